@@ -62,6 +62,7 @@ impl From<&PyStoreConfig> for RepositoryConfig {
                 .virtual_ref_config
                 .as_ref()
                 .map(ObjectStoreVirtualChunkResolverConfig::from),
+            content_hash: None,
         }
     }
 }
@@ -71,7 +72,7 @@ impl From<&PyStoreConfig> for StoreOptions {
         if let Some(get_partial_values_concurrency) =
             config.get_partial_values_concurrency
         {
-            StoreOptions { get_partial_values_concurrency }
+            StoreOptions { get_partial_values_concurrency, ..StoreOptions::default() }
         } else {
             StoreOptions::default()
         }
@@ -1002,6 +1003,7 @@ async fn do_set_virtual_ref(
         location: VirtualChunkLocation::Absolute(location),
         offset,
         length,
+        slice: None,
     };
     let mut store = store.write().await;
     store.set_virtual_ref(&key, virtual_ref).await.map_err(PyIcechunkStoreError::from)?;
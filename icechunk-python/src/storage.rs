@@ -100,6 +100,7 @@ impl PyStorageConfig {
             endpoint: endpoint_url,
             allow_http,
             credentials: mk_credentials(None, false),
+            ..Default::default()
         };
         Self(StorageConfig::S3ObjectStore { bucket, prefix, config: Some(config) })
     }
@@ -127,6 +128,7 @@ impl PyStorageConfig {
             endpoint: endpoint_url,
             allow_http,
             credentials: credentials.into(),
+            ..Default::default()
         };
         Self(StorageConfig::S3ObjectStore { bucket, prefix, config: Some(config) })
     }
@@ -152,6 +154,7 @@ impl PyStorageConfig {
             endpoint: endpoint_url,
             allow_http,
             credentials: mk_credentials(None, true),
+            ..Default::default()
         };
         Self(StorageConfig::S3ObjectStore { bucket, prefix, config: Some(config) })
     }
@@ -272,6 +275,7 @@ impl From<&PyVirtualRefConfig> for ObjectStoreVirtualChunkResolverConfig {
                 endpoint: endpoint_url.clone(),
                 credentials: mk_credentials(credentials.as_ref(), *anon),
                 allow_http: allow_http.unwrap_or(false),
+                ..Default::default()
             }),
         }
     }
@@ -0,0 +1,246 @@
+//! Timings of the main repository operations, on synthetic repositories
+//!
+//! ```sh
+//! cargo bench --bench repository
+//! # record the timings, and fail later runs that are much slower
+//! ICECHUNK_BENCH_SAVE=baseline.json cargo bench --bench repository
+//! ICECHUNK_BENCH_BASELINE=baseline.json cargo bench --bench repository
+//! ```
+//!
+//! `ICECHUNK_BENCH_SCALE` multiplies the number of arrays, chunks and commits of every
+//! repository, to size deployments. With a baseline, the run fails if the median time of a
+//! measurement grows more than `ICECHUNK_BENCH_TOLERANCE` times, 1.5 by default.
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    future::Future,
+    num::NonZeroU64,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use chrono::{TimeDelta, Utc};
+use futures::{StreamExt, TryStreamExt};
+use icechunk::{
+    format::{ChunkIndices, Path},
+    metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+    ops::read_plan::ReadPlan,
+    repository::ZarrArrayMetadata,
+    ObjectStorage, Repository, Storage,
+};
+
+type BenchResult<A> = Result<A, Box<dyn Error>>;
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+const ITERATIONS: usize = 10;
+
+/// The size of a synthetic repository
+#[derive(Debug, Clone, Copy)]
+struct Shape {
+    name: &'static str,
+    arrays: u64,
+    chunks_per_array: u64,
+    commits: u64,
+}
+
+impl Shape {
+    fn scaled(self, scale: f64) -> Self {
+        let scale = |n: u64| ((n as f64 * scale).round() as u64).max(1);
+        Self {
+            arrays: scale(self.arrays),
+            chunks_per_array: scale(self.chunks_per_array),
+            commits: scale(self.commits),
+            ..self
+        }
+    }
+}
+
+const SHAPES: [Shape; 2] = [
+    Shape { name: "wide", arrays: 100, chunks_per_array: 100, commits: 1 },
+    Shape { name: "deep", arrays: 2, chunks_per_array: 10, commits: 200 },
+];
+
+fn array_path(array: u64) -> BenchResult<Path> {
+    Ok(format!("/array{array}").try_into()?)
+}
+
+async fn write_chunk(
+    repo: &mut Repository,
+    array: u64,
+    chunk: u64,
+    value: u64,
+) -> BenchResult<()> {
+    let payload =
+        repo.get_chunk_writer()(Bytes::from(value.to_le_bytes().to_vec())).await?;
+    repo.set_chunk_ref(array_path(array)?, ChunkIndices(vec![chunk]), Some(payload))
+        .await?;
+    Ok(())
+}
+
+/// Create a repository with `shape.arrays` arrays of `shape.chunks_per_array` chunks, in
+/// the first commit, and rewrite one chunk in each of the other commits
+async fn generate(shape: Shape) -> BenchResult<DynStorage> {
+    let storage: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+    let mut repo = Repository::init(Arc::clone(&storage), false)
+        .await?
+        .with_inline_threshold_bytes(0)
+        .build();
+    repo.add_group(Path::root()).await?;
+    for array in 0..shape.arrays {
+        let metadata = ZarrArrayMetadata {
+            shape: vec![shape.chunks_per_array],
+            data_type: DataType::UInt64,
+            chunk_shape: ChunkShape(vec![NonZeroU64::MIN]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt64(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        repo.add_array(array_path(array)?, metadata).await?;
+        for chunk in 0..shape.chunks_per_array {
+            write_chunk(&mut repo, array, chunk, chunk).await?;
+        }
+    }
+    repo.commit("main", "initial", None).await?;
+    for commit in 1..shape.commits {
+        let chunk = commit % shape.chunks_per_array;
+        write_chunk(&mut repo, commit % shape.arrays, chunk, commit).await?;
+        repo.commit("main", "update", None).await?;
+    }
+    Ok(storage)
+}
+
+async fn open(storage: &DynStorage) -> BenchResult<Repository> {
+    Ok(Repository::from_branch_tip(Arc::clone(storage), "main").await?.build())
+}
+
+/// The median time of `ITERATIONS` runs of `f`
+async fn measure<F, Fut>(mut f: F) -> BenchResult<Duration>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = BenchResult<()>>,
+{
+    let mut times = Vec::with_capacity(ITERATIONS);
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        f().await?;
+        times.push(start.elapsed());
+    }
+    times.sort();
+    Ok(times[ITERATIONS / 2])
+}
+
+async fn bench_shape(shape: Shape) -> BenchResult<Vec<(String, Duration)>> {
+    let start = Instant::now();
+    let storage = generate(shape).await?;
+    println!(
+        "{}: {} arrays x {} chunks, {} commits, generated in {:?}",
+        shape.name,
+        shape.arrays,
+        shape.chunks_per_array,
+        shape.commits,
+        start.elapsed()
+    );
+    let repo = open(&storage).await?;
+    let expires_at = Utc::now() + TimeDelta::hours(1);
+    let mut timings = Vec::new();
+    let mut record = |name: &str, time: Duration| {
+        timings.push((format!("{}/{}", shape.name, name), time));
+    };
+
+    record(
+        "open",
+        measure(|| async {
+            open(&storage).await?.list_nodes().await?.count();
+            Ok(())
+        })
+        .await?,
+    );
+    record(
+        "list_chunks",
+        measure(|| async {
+            repo.all_chunks().await?.try_collect::<Vec<_>>().await?;
+            Ok(())
+        })
+        .await?,
+    );
+    record(
+        "read_plan",
+        measure(|| async {
+            ReadPlan::new(&repo, &Path::root(), expires_at).await?;
+            Ok(())
+        })
+        .await?,
+    );
+    record(
+        "ancestry",
+        measure(|| async {
+            repo.ancestry().await?.count().await;
+            Ok(())
+        })
+        .await?,
+    );
+    record(
+        "commit",
+        measure(|| async {
+            let mut repo = open(&storage).await?;
+            write_chunk(&mut repo, 0, 0, 42).await?;
+            repo.commit("main", "bench", None).await?;
+            Ok(())
+        })
+        .await?,
+    );
+    Ok(timings)
+}
+
+fn env_f64(name: &str, default: f64) -> BenchResult<f64> {
+    match env::var(name) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+#[tokio::main]
+async fn main() -> BenchResult<()> {
+    let scale = env_f64("ICECHUNK_BENCH_SCALE", 1.0)?;
+    let tolerance = env_f64("ICECHUNK_BENCH_TOLERANCE", 1.5)?;
+
+    let mut medians = BTreeMap::new();
+    for shape in SHAPES {
+        for (name, time) in bench_shape(shape.scaled(scale)).await? {
+            println!("  {name:<24} {time:>12.2?}");
+            medians.insert(name, time.as_secs_f64() * 1_000_000.0);
+        }
+    }
+
+    if let Ok(path) = env::var("ICECHUNK_BENCH_SAVE") {
+        std::fs::write(&path, serde_json::to_string_pretty(&medians)?)?;
+        println!("timings saved to {path}");
+    }
+    if let Ok(path) = env::var("ICECHUNK_BENCH_BASELINE") {
+        let baseline: BTreeMap<String, f64> =
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        let regressions: Vec<String> = medians
+            .iter()
+            .filter_map(|(name, micros)| {
+                let before = baseline.get(name)?;
+                (*micros > before * tolerance)
+                    .then(|| format!("{name}: {before:.0}us -> {micros:.0}us"))
+            })
+            .collect();
+        if !regressions.is_empty() {
+            return Err(format!(
+                "slower than the baseline in {path}:\n{}",
+                regressions.join("\n")
+            )
+            .into());
+        }
+        println!("no regressions against {path}");
+    }
+    Ok(())
+}
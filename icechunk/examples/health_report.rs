@@ -0,0 +1,63 @@
+//! Print the health report of a repository in the local filesystem
+//!
+//! ```sh
+//! cargo run --example health_report -- <repository directory> [branch] [--json]
+//! ```
+use std::{env, path::PathBuf, sync::Arc};
+
+use icechunk::{
+    ops::health::HealthConfig, storage::ObjectStorage, MemCachingStorage, Repository,
+    Storage,
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let Some(dir) = args.first() else {
+        return Err(
+            "usage: health_report <repository directory> [branch] [--json]".into()
+        );
+    };
+    let branch = args.get(1).map(String::as_str).unwrap_or("main");
+
+    let backend: Arc<dyn Storage + Send + Sync> =
+        Arc::new(ObjectStorage::new_local_store(&PathBuf::from(dir))?);
+    let storage: Arc<dyn Storage + Send + Sync> =
+        Arc::new(MemCachingStorage::new(backend, 2, 100, 0, 0, 0));
+    let repo = Repository::from_branch_tip(storage, branch).await?.build();
+    let report = repo.health_report(&HealthConfig::default()).await?;
+
+    if flags.iter().any(|flag| flag == "--json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!("snapshot {} of branch {}", report.snapshot, branch);
+    println!("  {} arrays, {} groups", report.arrays, report.groups);
+    println!(
+        "  {} manifests, {:.2} per array",
+        report.manifests, report.manifests_per_array
+    );
+    println!("  history depth {}", report.history_depth);
+    println!(
+        "  chunks: {} inline, {} native, {} virtual, {} missing",
+        report.chunks.inline,
+        report.chunks.native,
+        report.chunks.r#virtual,
+        report.chunks.missing
+    );
+    println!("  tiny chunk ratio {:.2}", report.tiny_chunk_ratio);
+    println!(
+        "  orphans: {} chunks, {} manifests, {} snapshots",
+        report.orphans.chunks, report.orphans.manifests, report.orphans.snapshots
+    );
+    if report.recommendations.is_empty() {
+        println!("no recommendations");
+    } else {
+        println!("recommendations:");
+        for recommendation in report.recommendations.iter() {
+            println!("  - {}", recommendation);
+        }
+    }
+    Ok(())
+}
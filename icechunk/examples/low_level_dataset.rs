@@ -90,6 +90,8 @@ let zarr_meta1 = ZarrArrayMetadata {{
         Some("y".to_string()),
         Some("t".to_string()),
     ]),
+    chunk_transformers: None,
+    grid_origin: None,
 }};
 
 let array1_path: Path = "/group1/array1".into();
@@ -128,6 +130,8 @@ ds.add_array(array1_path.clone(), zarr_meta1).await?;
             Some("y".to_string()),
             Some("t".to_string()),
         ]),
+        chunk_transformers: None,
+        grid_origin: None,
     };
     let array1_path: Path = "/group1/array1".try_into().unwrap();
     ds.add_array(array1_path.clone(), zarr_meta1).await?;
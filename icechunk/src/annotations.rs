@@ -0,0 +1,224 @@
+//! Labels attached to snapshots after they are committed
+//!
+//! Snapshots are immutable, but teams need to record what they learn about them later, a
+//! QA status, review notes, a quality score. Annotations are stored in a namespace of the
+//! refs, one per snapshot, and versioned like branches: every change writes a new version
+//! that is only created if it doesn't exist, so concurrent writers never lose each other's
+//! updates, and the previous versions stay as the history of the annotation.
+//!
+//! [`crate::Repository::annotated_ancestry`] lists the history of a session with the
+//! current annotations of each snapshot.
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    format::SnapshotId,
+    refs::{RefError, RefResult},
+    storage::REF_PREFIX,
+    Storage, StorageError,
+};
+
+pub(crate) const ANNOTATION_REF_PREFIX: &str = "annotation.";
+
+/// Annotation labels, by name
+pub type AnnotationLabels = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotAnnotations {
+    pub snapshot: SnapshotId,
+    /// Starts at 1, incremented by every change
+    pub version: u64,
+    pub labels: AnnotationLabels,
+    pub updated_at: DateTime<Utc>,
+}
+
+fn annotation_root(snapshot: &SnapshotId) -> String {
+    format!("{}{}", ANNOTATION_REF_PREFIX, snapshot)
+}
+
+fn annotation_key(snapshot: &SnapshotId, version: u64) -> String {
+    // padded, so versions sort as strings
+    format!("{}/{:020}.json", annotation_root(snapshot), version)
+}
+
+async fn version_keys(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &SnapshotId,
+) -> RefResult<Vec<String>> {
+    let root = annotation_root(snapshot);
+    let mut keys: Vec<String> = storage
+        .ref_versions(root.as_str())
+        .await?
+        .map_ok(|version| format!("{}/{}", root, version))
+        .try_collect()
+        .await?;
+    keys.sort();
+    Ok(keys)
+}
+
+async fn fetch_version(
+    storage: &(dyn Storage + Send + Sync),
+    key: &str,
+) -> RefResult<SnapshotAnnotations> {
+    match storage.get_ref(key).await {
+        Ok(data) => Ok(serde_json::from_slice(data.as_ref())?),
+        Err(StorageError::RefNotFound(..)) => Err(RefError::RefNotFound(key.to_string())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The current annotations of `snapshot`, `None` if it was never annotated
+pub async fn fetch_annotations(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &SnapshotId,
+) -> RefResult<Option<SnapshotAnnotations>> {
+    match version_keys(storage, snapshot).await?.last() {
+        Some(key) => Ok(Some(fetch_version(storage, key).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Every version of the annotations of `snapshot`, oldest first
+pub async fn annotation_history(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &SnapshotId,
+) -> RefResult<Vec<SnapshotAnnotations>> {
+    let mut res = Vec::new();
+    for key in version_keys(storage, snapshot).await? {
+        res.push(fetch_version(storage, key.as_str()).await?);
+    }
+    Ok(res)
+}
+
+/// Change the labels of `snapshot` with `f`, writing a new version of its annotations
+///
+/// `f` gets the current labels, and runs again on the new ones if another writer changed
+/// them in between. Labels set to [`Value::Null`] are removed.
+pub async fn annotate(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &SnapshotId,
+    now: DateTime<Utc>,
+    mut f: impl FnMut(&mut AnnotationLabels),
+) -> RefResult<SnapshotAnnotations> {
+    loop {
+        let current = fetch_annotations(storage, snapshot).await?;
+        let (version, mut labels) =
+            current.map_or((0, AnnotationLabels::new()), |a| (a.version, a.labels));
+        f(&mut labels);
+        labels.retain(|_, value| !value.is_null());
+        let annotations = SnapshotAnnotations {
+            snapshot: snapshot.clone(),
+            version: version + 1,
+            labels,
+            updated_at: now,
+        };
+        let key = annotation_key(snapshot, annotations.version);
+        let content = serde_json::to_vec(&annotations)?;
+        match storage.write_ref(key.as_str(), false, Bytes::from(content)).await {
+            Ok(()) => return Ok(annotations),
+            // another writer took this version, apply the change to theirs
+            Err(StorageError::RefAlreadyExists(_)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The current annotations of every annotated snapshot
+pub async fn list_annotations(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<HashMap<SnapshotId, SnapshotAnnotations>> {
+    let mut res = HashMap::new();
+    for name in storage.ref_names().await? {
+        let Some(id) = name.strip_prefix(ANNOTATION_REF_PREFIX) else { continue };
+        let snapshot = SnapshotId::try_from(id)
+            .map_err(|_| RefError::InvalidRefName(name.clone()))?;
+        if let Some(annotations) = fetch_annotations(storage, &snapshot).await? {
+            res.insert(snapshot, annotations);
+        }
+    }
+    Ok(res)
+}
+
+/// Delete every version of the annotations of `snapshot`
+pub async fn delete_annotations(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &SnapshotId,
+) -> RefResult<usize> {
+    let keys = version_keys(storage, snapshot).await?;
+    Ok(storage.delete_objects(REF_PREFIX, stream::iter(keys).boxed()).await?)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use chrono::TimeDelta;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        refs::{list_refs, Ref},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_annotate_snapshots() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit("main", "first", None).await?;
+        ds.add_group("/group".try_into()?).await?;
+        let second = ds.commit("main", "second", None).await?;
+
+        let now = Utc::now();
+        let annotations = annotate(storage.as_ref(), &first, now, |labels| {
+            labels.insert("qa".to_string(), json!("pending"));
+            labels.insert("score".to_string(), json!(0.5));
+        })
+        .await?;
+        assert_eq!(annotations.version, 1);
+        let later = now + TimeDelta::hours(1);
+        let annotations = annotate(storage.as_ref(), &first, later, |labels| {
+            labels.insert("qa".to_string(), json!("passed"));
+            labels.insert("score".to_string(), Value::Null);
+        })
+        .await?;
+        assert_eq!(annotations.version, 2);
+        assert_eq!(
+            annotations.labels,
+            AnnotationLabels::from([("qa".to_string(), json!("passed"))])
+        );
+        assert_eq!(fetch_annotations(storage.as_ref(), &first).await?, Some(annotations));
+        assert_eq!(fetch_annotations(storage.as_ref(), &second).await?, None);
+        let history = annotation_history(storage.as_ref(), &first).await?;
+        assert_eq!(
+            history.iter().map(|a| a.updated_at).collect::<Vec<_>>(),
+            [now, later]
+        );
+
+        // annotations are not refs, and don't create commits
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string())]
+        );
+        let history: Vec<_> = ds.annotated_ancestry().await?.try_collect().await?;
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].0.id, second);
+        assert_eq!(history[0].1, None);
+        assert_eq!(history[1].0.id, first);
+        assert_eq!(history[1].1.as_ref().map(|a| a.version), Some(2));
+
+        assert_eq!(delete_annotations(storage.as_ref(), &first).await?, 2);
+        assert_eq!(list_annotations(storage.as_ref()).await?, HashMap::new());
+        Ok(())
+    }
+}
@@ -0,0 +1,1183 @@
+use std::{num::NonZeroU64, ops::Range};
+
+use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use ndarray::{ArrayD, ArrayViewD, Dimension, IxDyn, Slice};
+use thiserror::Error;
+
+use crate::{
+    format::{snapshot::NodeData, ByteRange, ChunkIndices, Path},
+    metadata::{
+        vlen::{decode_vlen, encode_vlen, VlenError},
+        ArrayShape, Codec, DataType, FillValue,
+    },
+    private,
+    repository::{get_chunk, RepositoryError, ZarrArrayMetadata},
+    Repository,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ArrayError {
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("array at `{path}` has data type `{found}`, requested `{expected}`")]
+    DataTypeMismatch { path: Path, expected: DataType, found: DataType },
+    #[error("unsupported codec `{0}`, only uncompressed `bytes` chunks can be decoded")]
+    UnsupportedCodec(String),
+    #[error("invalid region `{region:?}` for array of shape `{shape:?}`")]
+    InvalidRegion { region: Vec<Range<u64>>, shape: ArrayShape },
+    #[error("chunk `{coords:?}` has {found} bytes, expected {expected}")]
+    InvalidChunkLength { coords: ChunkIndices, expected: usize, found: usize },
+    #[error("fill value `{0:?}` doesn't match the requested element type")]
+    InvalidFillValue(FillValue),
+    #[error("chunk index {0} is out of the supported range")]
+    ChunkIndexOverflow(u64),
+    #[error("array at `{path}` has shape `{shape:?}`, it's not a scalar")]
+    NotAScalar { path: Path, shape: ArrayShape },
+    #[error("invalid variable length chunk `{coords:?}`: {source}")]
+    InvalidVlenChunk { coords: ChunkIndices, source: VlenError },
+}
+
+pub type ArrayResult<A> = Result<A, ArrayError>;
+
+/// Number of chunks fetched concurrently by [`read_region`]
+const READ_CONCURRENCY: usize = 10;
+
+pub use crate::metadata::Endianness;
+
+impl Endianness {
+    /// Find the endianness of the chunks encoded with the given codec pipeline.
+    ///
+    /// Only a single `bytes` codec is supported, any other codec, like compressors, will fail.
+    pub fn from_codecs(codecs: &[Codec]) -> ArrayResult<Self> {
+        let mut res = Endianness::Little;
+        for codec in codecs {
+            if codec.name != "bytes" {
+                return Err(ArrayError::UnsupportedCodec(codec.name.clone()));
+            }
+            let endian = codec.configuration.as_ref().and_then(|conf| conf.get("endian"));
+            res = match endian.and_then(|e| e.as_str()) {
+                None => Endianness::Little,
+                Some(endian) => Endianness::try_from(endian).map_err(|_| {
+                    ArrayError::UnsupportedCodec(format!("bytes with endian {endian}"))
+                })?,
+            };
+        }
+        Ok(res)
+    }
+}
+
+/// Rust types that can be read from, and written to, Icechunk arrays
+pub trait Element: private::Sealed + Copy + Send + Sync + 'static {
+    const DATA_TYPE: DataType;
+    const SIZE: usize;
+
+    fn from_fill_value(fill_value: &FillValue) -> Option<Self>;
+    /// `bytes` has exactly `Self::SIZE` elements
+    fn decode(bytes: &[u8], endianness: Endianness) -> Self;
+    fn encode(self, endianness: Endianness, out: &mut Vec<u8>);
+}
+
+macro_rules! impl_numeric_element {
+    ($t:ty, $variant:ident) => {
+        impl private::Sealed for $t {}
+
+        impl Element for $t {
+            const DATA_TYPE: DataType = DataType::$variant;
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn from_fill_value(fill_value: &FillValue) -> Option<Self> {
+                match fill_value {
+                    FillValue::$variant(v) => Some(*v),
+                    _ => None,
+                }
+            }
+
+            fn decode(bytes: &[u8], endianness: Endianness) -> Self {
+                let mut buf = [0u8; std::mem::size_of::<$t>()];
+                buf.copy_from_slice(bytes);
+                match endianness {
+                    Endianness::Little => <$t>::from_le_bytes(buf),
+                    Endianness::Big => <$t>::from_be_bytes(buf),
+                }
+            }
+
+            fn encode(self, endianness: Endianness, out: &mut Vec<u8>) {
+                match endianness {
+                    Endianness::Little => out.extend_from_slice(&self.to_le_bytes()),
+                    Endianness::Big => out.extend_from_slice(&self.to_be_bytes()),
+                }
+            }
+        }
+    };
+}
+
+impl_numeric_element!(i8, Int8);
+impl_numeric_element!(i16, Int16);
+impl_numeric_element!(i32, Int32);
+impl_numeric_element!(i64, Int64);
+impl_numeric_element!(u8, UInt8);
+impl_numeric_element!(u16, UInt16);
+impl_numeric_element!(u32, UInt32);
+impl_numeric_element!(u64, UInt64);
+impl_numeric_element!(f32, Float32);
+impl_numeric_element!(f64, Float64);
+
+impl private::Sealed for bool {}
+
+impl Element for bool {
+    const DATA_TYPE: DataType = DataType::Bool;
+    const SIZE: usize = 1;
+
+    fn from_fill_value(fill_value: &FillValue) -> Option<Self> {
+        match fill_value {
+            FillValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn decode(bytes: &[u8], _endianness: Endianness) -> Self {
+        bytes[0] != 0
+    }
+
+    fn encode(self, _endianness: Endianness, out: &mut Vec<u8>) {
+        out.push(self as u8)
+    }
+}
+
+/// Rust types that can be read from, and written to, arrays of variable length types
+pub trait VlenElement: private::Sealed + Clone + Send + Sync + 'static {
+    const DATA_TYPE: DataType;
+
+    fn from_fill_value(fill_value: &FillValue) -> Option<Self>;
+    /// `None` if `bytes` is not a valid element
+    fn decode(bytes: &[u8]) -> Option<Self>;
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl private::Sealed for String {}
+
+impl VlenElement for String {
+    const DATA_TYPE: DataType = DataType::String;
+
+    fn from_fill_value(fill_value: &FillValue) -> Option<Self> {
+        match fill_value {
+            FillValue::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        std::str::from_utf8(bytes).ok().map(String::from)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl private::Sealed for Vec<u8> {}
+
+impl VlenElement for Vec<u8> {
+    const DATA_TYPE: DataType = DataType::Bytes;
+
+    fn from_fill_value(fill_value: &FillValue) -> Option<Self> {
+        match fill_value {
+            FillValue::Bytes(b) => Some(b.clone()),
+            _ => None,
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+/// The intersection between a region of the array and one of its chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChunkOverlap {
+    coords: ChunkIndices,
+    /// Intersection in coordinates relative to the chunk origin
+    in_chunk: Vec<Range<usize>>,
+    /// Intersection in coordinates relative to the region origin
+    in_region: Vec<Range<usize>>,
+    /// The region includes every element of the chunk
+    covers_chunk: bool,
+}
+
+/// A strided selection along one dimension: every `step` elements, from `start`
+/// (included) to `stop` (excluded)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DimSelection {
+    pub start: u64,
+    pub stop: u64,
+    pub step: NonZeroU64,
+}
+
+impl DimSelection {
+    pub fn strided(range: Range<u64>, step: NonZeroU64) -> Self {
+        Self { start: range.start, stop: range.end, step }
+    }
+
+    /// Number of selected elements
+    pub fn len(&self) -> u64 {
+        self.stop.saturating_sub(self.start).div_ceil(self.step.get())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<Range<u64>> for DimSelection {
+    fn from(range: Range<u64>) -> Self {
+        Self::strided(range, NonZeroU64::MIN)
+    }
+}
+
+/// The elements of a chunk selected along one dimension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkDimSelection {
+    /// Index of the first selected element, relative to the chunk origin
+    pub first: usize,
+    /// Number of selected elements
+    pub count: usize,
+    /// Distance between consecutive selected elements
+    pub step: usize,
+    /// Index of the first selected element in the output array
+    pub output_first: usize,
+}
+
+/// What needs to be fetched from a single chunk to satisfy a selection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkReadPlan {
+    pub coords: ChunkIndices,
+    /// The smallest byte range of the encoded chunk that contains every selected element
+    pub byte_range: ByteRange,
+    /// One element per array dimension
+    pub dimensions: Vec<ChunkDimSelection>,
+}
+
+/// Read a region of the array at `path` into an [`ArrayD`].
+///
+/// `region` has one half open range per dimension. This is the same as [`read_selection`]
+/// with a step of 1 in every dimension.
+///
+/// Array indexes are also coordinates on the chunk grid, if the array has a
+/// [`ZarrArrayMetadata::grid_origin`], chunks start at it instead of at zero.
+pub async fn read_region<T: Element>(
+    repo: &Repository,
+    path: &Path,
+    region: &[Range<u64>],
+) -> ArrayResult<ArrayD<T>> {
+    let selection = region.iter().cloned().map(DimSelection::from).collect::<Vec<_>>();
+    read_selection(repo, path, &selection).await
+}
+
+/// Read a strided selection of the array at `path` into an [`ArrayD`].
+///
+/// `selection` has one element per dimension. Only the chunks that contain selected
+/// elements are fetched, concurrently, and for each one of them only the byte range
+/// computed by [`plan_selection`]. Chunks that were never written are filled with the array
+/// fill value.
+pub async fn read_selection<T: Element>(
+    repo: &Repository,
+    path: &Path,
+    selection: &[DimSelection],
+) -> ArrayResult<ArrayD<T>> {
+    let meta = typed_array_metadata::<T>(repo, path).await?;
+    let endianness = Endianness::from_codecs(&meta.codecs)?;
+    let fill_value = fill_value::<T>(&meta)?;
+    let plans = plan_selection::<T>(&meta, selection)?;
+
+    let shape = selection.iter().map(|sel| sel.len() as usize).collect::<Vec<_>>();
+    let mut res = ArrayD::from_elem(IxDyn(&shape), fill_value);
+    let strides = chunk_strides(&chunk_shape(&meta));
+
+    let mut readers = Vec::with_capacity(plans.len());
+    for plan in plans {
+        let reader = repo.get_chunk_reader(path, &plan.coords, &plan.byte_range).await?;
+        readers.push(async move { get_chunk(reader).await.map(|bytes| (plan, bytes)) });
+    }
+
+    let mut chunks = stream::iter(readers).buffer_unordered(READ_CONCURRENCY);
+    while let Some((plan, bytes)) = chunks.try_next().await? {
+        if let Some(bytes) = bytes {
+            copy_selected(&plan, &strides, endianness, &bytes, &mut res)?;
+        }
+    }
+    Ok(res)
+}
+
+/// Compute the chunks, and byte ranges within them, needed to read `selection`.
+///
+/// Chunks that don't contain any selected elements, for example when the step is larger
+/// than the chunk, are not included.
+pub fn plan_selection<T: Element>(
+    meta: &ZarrArrayMetadata,
+    selection: &[DimSelection],
+) -> ArrayResult<Vec<ChunkReadPlan>> {
+    let valid = selection.len() == meta.shape.len()
+        && meta.chunk_shape.0.len() == meta.shape.len()
+        && selection
+            .iter()
+            .zip(meta.shape.iter())
+            .all(|(sel, dim)| sel.start <= sel.stop && sel.stop <= *dim);
+    if !valid {
+        return Err(ArrayError::InvalidRegion {
+            region: selection.iter().map(|sel| sel.start..sel.stop).collect(),
+            shape: meta.shape.clone(),
+        });
+    }
+    if selection.iter().any(|sel| sel.is_empty()) {
+        return Ok(vec![]);
+    }
+    let ranges = selection.iter().map(|sel| sel.start..sel.stop).collect::<Vec<_>>();
+    let selection = from_grid_origin(meta, &ranges)
+        .ok_or_else(|| ArrayError::InvalidRegion {
+            region: ranges.clone(),
+            shape: meta.shape.clone(),
+        })?
+        .into_iter()
+        .zip(selection)
+        .map(|(range, sel)| DimSelection::strided(range, sel.step))
+        .collect::<Vec<_>>();
+
+    let strides = chunk_strides(&chunk_shape(meta));
+    let per_dimension = selection
+        .iter()
+        .zip(meta.chunk_shape.0.iter())
+        .map(|(sel, chunk_len)| dimension_chunks(sel, chunk_len.get()))
+        .collect::<Vec<_>>();
+
+    let mk_plan = |dims: Vec<(u64, ChunkDimSelection)>| {
+        let (coords, dimensions): (Vec<_>, Vec<_>) = dims.into_iter().unzip();
+        let offset = |pos: fn(&ChunkDimSelection) -> usize| {
+            dimensions.iter().zip(strides.iter()).map(|(d, s)| pos(d) * s).sum::<usize>()
+                as u64
+        };
+        let first = offset(|d| d.first) * T::SIZE as u64;
+        let last = offset(|d| d.first + (d.count - 1) * d.step) * T::SIZE as u64;
+        Ok(ChunkReadPlan {
+            coords: ChunkIndices(coords),
+            byte_range: ByteRange::bounded(first, last + T::SIZE as u64),
+            dimensions,
+        })
+    };
+
+    if per_dimension.is_empty() {
+        // a zero dimensional array has a single chunk
+        return Ok(vec![mk_plan(vec![])?]);
+    }
+    per_dimension.into_iter().multi_cartesian_product().map(mk_plan).collect()
+}
+
+/// The chunks along one dimension that contain selected elements
+fn dimension_chunks(
+    sel: &DimSelection,
+    chunk_len: u64,
+) -> impl Iterator<Item = (u64, ChunkDimSelection)> + Clone {
+    let step = sel.step.get();
+    let last_selected = sel.start + (sel.len() - 1) * step;
+    let sel = sel.clone();
+    (sel.start / chunk_len..=last_selected / chunk_len).filter_map(move |index| {
+        let chunk_start = index * chunk_len;
+        let first = if sel.start >= chunk_start {
+            sel.start
+        } else {
+            sel.start + (chunk_start - sel.start).div_ceil(step) * step
+        };
+        let end = (chunk_start + chunk_len).min(last_selected + 1);
+        (first < end).then(|| {
+            let dim = ChunkDimSelection {
+                first: (first - chunk_start) as usize,
+                count: (end - first).div_ceil(step) as usize,
+                step: step as usize,
+                output_first: ((first - sel.start) / step) as usize,
+            };
+            (index, dim)
+        })
+    })
+}
+
+/// Number of elements between consecutive indexes in each dimension, in row major order
+fn chunk_strides(chunk_shape: &[usize]) -> Vec<usize> {
+    let mut res = vec![1; chunk_shape.len()];
+    for dim in (0..chunk_shape.len().saturating_sub(1)).rev() {
+        res[dim] = res[dim + 1] * chunk_shape[dim + 1];
+    }
+    res
+}
+
+/// Decode the elements of `bytes`, fetched according to `plan`, into their place in `res`
+fn copy_selected<T: Element>(
+    plan: &ChunkReadPlan,
+    strides: &[usize],
+    endianness: Endianness,
+    bytes: &[u8],
+    res: &mut ArrayD<T>,
+) -> ArrayResult<()> {
+    let (range_start, expected) = match &plan.byte_range {
+        ByteRange::Bounded(range) => (range.start, (range.end - range.start) as usize),
+        _ => (0, bytes.len()),
+    };
+    if bytes.len() != expected {
+        return Err(ArrayError::InvalidChunkLength {
+            coords: plan.coords.clone(),
+            expected,
+            found: bytes.len(),
+        });
+    }
+
+    let counts = plan.dimensions.iter().map(|d| d.count).collect::<Vec<_>>();
+    let mut output_index = Vec::with_capacity(counts.len());
+    for index in ndarray::indices(IxDyn(&counts)) {
+        output_index.clear();
+        let mut offset = 0;
+        for ((dim, i), stride) in plan.dimensions.iter().zip(index.slice()).zip(strides) {
+            offset += (dim.first + i * dim.step) * stride;
+            output_index.push(dim.output_first + i);
+        }
+        let offset = offset * T::SIZE - range_start as usize;
+        res[IxDyn(&output_index)] =
+            T::decode(&bytes[offset..offset + T::SIZE], endianness);
+    }
+    Ok(())
+}
+
+/// Write `data` into the array at `path`, with its first element at `origin`.
+///
+/// Chunks only partially covered by `data` are read first, and their remaining elements
+/// preserved, or set to the fill value if the chunk didn't exist. Chunks are encoded in the
+/// endianness declared by the array `bytes` codec.
+///
+/// As with other [`Repository`] writes, this is recorded in the current session, nothing is
+/// visible to other sessions until commit.
+pub async fn write_region<T: Element>(
+    repo: &mut Repository,
+    path: &Path,
+    origin: &[u64],
+    data: ArrayViewD<'_, T>,
+) -> ArrayResult<()> {
+    let meta = typed_array_metadata::<T>(repo, path).await?;
+    let endianness = Endianness::from_codecs(&meta.codecs)?;
+    let fill_value = fill_value::<T>(&meta)?;
+    let region = origin
+        .iter()
+        .zip(data.shape())
+        .map(|(start, len)| *start..start + *len as u64)
+        .collect::<Vec<_>>();
+    if origin.len() != data.ndim() {
+        return Err(ArrayError::InvalidRegion { region, shape: meta.shape.clone() });
+    }
+    validate_region(&meta, &region)?;
+
+    let chunk_shape = chunk_shape(&meta);
+    for overlap in overlapping_chunks(&meta, &region)? {
+        let existing = if overlap.covers_chunk {
+            None
+        } else {
+            read_chunk::<T>(repo, path, &overlap.coords, &chunk_shape, endianness).await?
+        };
+        let mut chunk = existing
+            .unwrap_or_else(|| ArrayD::from_elem(IxDyn(&chunk_shape), fill_value));
+        chunk
+            .slice_each_axis_mut(|ax| {
+                Slice::from(overlap.in_chunk[ax.axis.index()].clone())
+            })
+            .assign(&data.slice_each_axis(|ax| {
+                Slice::from(overlap.in_region[ax.axis.index()].clone())
+            }));
+
+        let writer = repo.get_array_chunk_writer(path).await?;
+        let payload = writer(encode_chunk(&chunk, endianness)).await?;
+        repo.set_chunk_ref(path.clone(), overlap.coords, Some(payload)).await?;
+    }
+    Ok(())
+}
+
+/// Read a region of the array of strings, or bytes, at `path` into an [`ArrayD`]
+///
+/// Chunks must be stored as written by the vlen codec of the data type, without
+/// compression. Every overlapping chunk is fetched and decoded whole, the encoding has no
+/// fixed offsets.
+pub async fn read_vlen_region<T: VlenElement>(
+    repo: &Repository,
+    path: &Path,
+    region: &[Range<u64>],
+) -> ArrayResult<ArrayD<T>> {
+    let meta = vlen_array_metadata::<T>(repo, path).await?;
+    validate_region(&meta, region)?;
+    let fill_value = vlen_fill_value::<T>(&meta)?;
+    let shape =
+        region.iter().map(|range| (range.end - range.start) as usize).collect_vec();
+    let mut res = ArrayD::from_elem(IxDyn(&shape), fill_value);
+
+    let chunk_shape = chunk_shape(&meta);
+    for overlap in overlapping_chunks(&meta, region)? {
+        if let Some(chunk) =
+            read_vlen_chunk::<T>(repo, path, &overlap.coords, &chunk_shape).await?
+        {
+            res.slice_each_axis_mut(|ax| {
+                Slice::from(overlap.in_region[ax.axis.index()].clone())
+            })
+            .assign(&chunk.slice_each_axis(|ax| {
+                Slice::from(overlap.in_chunk[ax.axis.index()].clone())
+            }));
+        }
+    }
+    Ok(res)
+}
+
+/// Write `data` into the array of strings, or bytes, at `path`, starting at `origin`
+///
+/// Like [`write_region`], chunks only partially covered by `data` are read and rewritten.
+pub async fn write_vlen_region<T: VlenElement>(
+    repo: &mut Repository,
+    path: &Path,
+    origin: &[u64],
+    data: ArrayViewD<'_, T>,
+) -> ArrayResult<()> {
+    let meta = vlen_array_metadata::<T>(repo, path).await?;
+    let fill_value = vlen_fill_value::<T>(&meta)?;
+    let region = origin
+        .iter()
+        .zip(data.shape())
+        .map(|(start, len)| *start..start + *len as u64)
+        .collect::<Vec<_>>();
+    if origin.len() != data.ndim() {
+        return Err(ArrayError::InvalidRegion { region, shape: meta.shape.clone() });
+    }
+    validate_region(&meta, &region)?;
+
+    let chunk_shape = chunk_shape(&meta);
+    for overlap in overlapping_chunks(&meta, &region)? {
+        let existing = if overlap.covers_chunk {
+            None
+        } else {
+            read_vlen_chunk::<T>(repo, path, &overlap.coords, &chunk_shape).await?
+        };
+        let mut chunk = existing.unwrap_or_else(|| {
+            ArrayD::from_elem(IxDyn(&chunk_shape), fill_value.clone())
+        });
+        chunk
+            .slice_each_axis_mut(|ax| {
+                Slice::from(overlap.in_chunk[ax.axis.index()].clone())
+            })
+            .assign(&data.slice_each_axis(|ax| {
+                Slice::from(overlap.in_region[ax.axis.index()].clone())
+            }));
+
+        let writer = repo.get_array_chunk_writer(path).await?;
+        let payload = writer(encode_vlen(chunk.iter().map(T::as_bytes))).await?;
+        repo.set_chunk_ref(path.clone(), overlap.coords, Some(payload)).await?;
+    }
+    Ok(())
+}
+
+/// Read the value of the zero dimensional array at `path`
+///
+/// Returns the fill value if the chunk was never written.
+pub async fn read_scalar<T: Element>(repo: &Repository, path: &Path) -> ArrayResult<T> {
+    let meta = typed_array_metadata::<T>(repo, path).await?;
+    if !meta.is_scalar() {
+        return Err(ArrayError::NotAScalar { path: path.clone(), shape: meta.shape });
+    }
+    let endianness = Endianness::from_codecs(&meta.codecs)?;
+    let chunk =
+        read_chunk::<T>(repo, path, &ChunkIndices(vec![]), &[], endianness).await?;
+    match chunk.and_then(|chunk| chunk.iter().next().copied()) {
+        Some(value) => Ok(value),
+        None => fill_value(&meta),
+    }
+}
+
+/// Set the value of the zero dimensional array at `path`
+pub async fn write_scalar<T: Element>(
+    repo: &mut Repository,
+    path: &Path,
+    value: T,
+) -> ArrayResult<()> {
+    let meta = typed_array_metadata::<T>(repo, path).await?;
+    if !meta.is_scalar() {
+        return Err(ArrayError::NotAScalar { path: path.clone(), shape: meta.shape });
+    }
+    let endianness = Endianness::from_codecs(&meta.codecs)?;
+    let mut data = Vec::with_capacity(T::SIZE);
+    value.encode(endianness, &mut data);
+    let payload = repo.get_array_chunk_writer(path).await?(data.into()).await?;
+    repo.set_chunk_ref(path.clone(), ChunkIndices(vec![]), Some(payload)).await?;
+    Ok(())
+}
+
+async fn typed_array_metadata<T: Element>(
+    repo: &Repository,
+    path: &Path,
+) -> ArrayResult<ZarrArrayMetadata> {
+    array_metadata_of_type(repo, path, T::DATA_TYPE).await
+}
+
+/// Only arrays without codecs, or with just the vlen codec of the data type, are supported
+async fn vlen_array_metadata<T: VlenElement>(
+    repo: &Repository,
+    path: &Path,
+) -> ArrayResult<ZarrArrayMetadata> {
+    let meta = array_metadata_of_type(repo, path, T::DATA_TYPE).await?;
+    match meta.codecs.as_slice() {
+        [] => Ok(meta),
+        _ if meta.is_uncompressed_vlen() => Ok(meta),
+        [codec, ..] => Err(ArrayError::UnsupportedCodec(codec.name.clone())),
+    }
+}
+
+async fn array_metadata_of_type(
+    repo: &Repository,
+    path: &Path,
+    expected: DataType,
+) -> ArrayResult<ZarrArrayMetadata> {
+    let node = repo.get_array(path).await?;
+    match node.node_data.clone() {
+        NodeData::Array(meta, _) if meta.data_type == expected => Ok(meta),
+        NodeData::Array(meta, _) => Err(ArrayError::DataTypeMismatch {
+            path: path.clone(),
+            expected,
+            found: meta.data_type,
+        }),
+        NodeData::Group | NodeData::Custom(_) => Err(RepositoryError::NotAnArray {
+            node,
+            message: "getting typed array".to_string(),
+        }
+        .into()),
+    }
+}
+
+fn fill_value<T: Element>(meta: &ZarrArrayMetadata) -> ArrayResult<T> {
+    T::from_fill_value(&meta.fill_value)
+        .ok_or_else(|| ArrayError::InvalidFillValue(meta.fill_value.clone()))
+}
+
+fn vlen_fill_value<T: VlenElement>(meta: &ZarrArrayMetadata) -> ArrayResult<T> {
+    T::from_fill_value(&meta.fill_value)
+        .ok_or_else(|| ArrayError::InvalidFillValue(meta.fill_value.clone()))
+}
+
+fn chunk_shape(meta: &ZarrArrayMetadata) -> Vec<usize> {
+    meta.chunk_shape.0.iter().map(|n| n.get() as usize).collect()
+}
+
+fn validate_region(meta: &ZarrArrayMetadata, region: &[Range<u64>]) -> ArrayResult<()> {
+    let valid = region.len() == meta.shape.len()
+        && meta.chunk_shape.0.len() == meta.shape.len()
+        && region
+            .iter()
+            .zip(meta.shape.iter())
+            .all(|(range, dim)| range.start <= range.end && range.end <= *dim);
+    if valid {
+        Ok(())
+    } else {
+        Err(ArrayError::InvalidRegion {
+            region: region.to_vec(),
+            shape: meta.shape.clone(),
+        })
+    }
+}
+
+/// `region` relative to the origin of the chunk grid, chunks start every chunk length from
+/// it. `None` if the region starts before the origin, those indexes can't be stored.
+fn from_grid_origin(
+    meta: &ZarrArrayMetadata,
+    region: &[Range<u64>],
+) -> Option<Vec<Range<u64>>> {
+    let origin = meta.chunk_grid().origin;
+    if origin.len() != region.len() {
+        return None;
+    }
+    let shift = |index: u64, origin: i64| {
+        u64::try_from(i128::from(index) - i128::from(origin)).ok()
+    };
+    region
+        .iter()
+        .zip(origin)
+        .map(|(range, origin)| {
+            Some(shift(range.start, origin)?..shift(range.end, origin)?)
+        })
+        .collect()
+}
+
+fn overlapping_chunks(
+    meta: &ZarrArrayMetadata,
+    region: &[Range<u64>],
+) -> ArrayResult<Vec<ChunkOverlap>> {
+    if region.iter().any(|range| range.is_empty()) {
+        return Ok(vec![]);
+    }
+    let region = from_grid_origin(meta, region).ok_or_else(|| {
+        ArrayError::InvalidRegion { region: region.to_vec(), shape: meta.shape.clone() }
+    })?;
+
+    let per_dimension = region
+        .iter()
+        .zip(meta.chunk_shape.0.iter())
+        .map(|(range, chunk_len)| {
+            let chunk_len = chunk_len.get();
+            (range.start / chunk_len..=(range.end - 1) / chunk_len).map(move |index| {
+                let chunk_start = index * chunk_len;
+                let start = range.start.max(chunk_start);
+                let end = range.end.min(chunk_start + chunk_len);
+                let in_chunk =
+                    (start - chunk_start) as usize..(end - chunk_start) as usize;
+                let in_region =
+                    (start - range.start) as usize..(end - range.start) as usize;
+                (index, in_chunk, in_region, end - start == chunk_len)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if per_dimension.is_empty() {
+        // a zero dimensional array has a single chunk
+        return Ok(vec![ChunkOverlap {
+            coords: ChunkIndices(vec![]),
+            in_chunk: vec![],
+            in_region: vec![],
+            covers_chunk: true,
+        }]);
+    }
+
+    per_dimension
+        .into_iter()
+        .multi_cartesian_product()
+        .map(|dims| {
+            let coords = dims.iter().map(|(index, ..)| *index).collect();
+            Ok(ChunkOverlap {
+                coords: ChunkIndices(coords),
+                covers_chunk: dims.iter().all(|(.., covers)| *covers),
+                in_chunk: dims.iter().map(|(_, in_chunk, ..)| in_chunk.clone()).collect(),
+                in_region: dims
+                    .into_iter()
+                    .map(|(_, _, in_region, _)| in_region)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+async fn read_chunk<T: Element>(
+    repo: &Repository,
+    path: &Path,
+    coords: &ChunkIndices,
+    chunk_shape: &[usize],
+    endianness: Endianness,
+) -> ArrayResult<Option<ArrayD<T>>> {
+    let reader = repo.get_chunk_reader(path, coords, &ByteRange::ALL).await?;
+    match get_chunk(reader).await? {
+        Some(bytes) => {
+            Ok(Some(decode_chunk(coords, chunk_shape, endianness, bytes.as_ref())?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn decode_chunk<T: Element>(
+    coords: &ChunkIndices,
+    chunk_shape: &[usize],
+    endianness: Endianness,
+    bytes: &[u8],
+) -> ArrayResult<ArrayD<T>> {
+    let expected = chunk_shape.iter().product::<usize>() * T::SIZE;
+    if bytes.len() != expected {
+        return Err(ArrayError::InvalidChunkLength {
+            coords: coords.clone(),
+            expected,
+            found: bytes.len(),
+        });
+    }
+    let elements =
+        bytes.chunks_exact(T::SIZE).map(|elem| T::decode(elem, endianness)).collect();
+    // we verified the number of elements above
+    #[allow(clippy::expect_used)]
+    let res = ArrayD::from_shape_vec(IxDyn(chunk_shape), elements)
+        .expect("bug in decode_chunk, bad number of elements");
+    Ok(res)
+}
+
+async fn read_vlen_chunk<T: VlenElement>(
+    repo: &Repository,
+    path: &Path,
+    coords: &ChunkIndices,
+    chunk_shape: &[usize],
+) -> ArrayResult<Option<ArrayD<T>>> {
+    let reader = repo.get_chunk_reader(path, coords, &ByteRange::ALL).await?;
+    match get_chunk(reader).await? {
+        Some(bytes) => Ok(Some(decode_vlen_chunk(coords, chunk_shape, bytes.as_ref())?)),
+        None => Ok(None),
+    }
+}
+
+fn decode_vlen_chunk<T: VlenElement>(
+    coords: &ChunkIndices,
+    chunk_shape: &[usize],
+    bytes: &[u8],
+) -> ArrayResult<ArrayD<T>> {
+    let invalid =
+        |source| ArrayError::InvalidVlenChunk { coords: coords.clone(), source };
+    let decoded = decode_vlen(bytes).map_err(invalid)?;
+    let expected = chunk_shape.iter().product::<usize>();
+    if decoded.len() != expected {
+        return Err(invalid(VlenError::ElementCount {
+            expected: expected as u64,
+            found: decoded.len() as u64,
+        }));
+    }
+    let elements = decoded
+        .into_iter()
+        .enumerate()
+        .map(|(index, elem)| T::decode(elem).ok_or(VlenError::InvalidUtf8(index)))
+        .try_collect()
+        .map_err(invalid)?;
+    // we verified the number of elements above
+    #[allow(clippy::expect_used)]
+    let res = ArrayD::from_shape_vec(IxDyn(chunk_shape), elements)
+        .expect("bug in decode_vlen_chunk, bad number of elements");
+    Ok(res)
+}
+
+fn encode_chunk<T: Element>(chunk: &ArrayD<T>, endianness: Endianness) -> Bytes {
+    let mut res = Vec::with_capacity(chunk.len() * T::SIZE);
+    // iter goes through the elements in logical, row major, order
+    for elem in chunk.iter() {
+        elem.encode(endianness, &mut res);
+    }
+    res.into()
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashMap, error::Error, num::NonZeroU64, sync::Arc};
+
+    use ndarray::{s, Array};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape},
+        ObjectStorage,
+    };
+
+    fn metadata(endian: &str) -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![5, 7],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(3).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(-1),
+            codecs: vec![Codec {
+                name: "bytes".to_string(),
+                configuration: Some(HashMap::from([(
+                    "endian".to_string(),
+                    serde_json::Value::from(endian),
+                )])),
+            }],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        }
+    }
+
+    async fn repo_with_array(endian: &str) -> Result<(Repository, Path), Box<dyn Error>> {
+        let storage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(storage, false).await?.build();
+        let path: Path = "/array".try_into().unwrap();
+        repo.add_array(path.clone(), metadata(endian)).await?;
+        Ok((repo, path))
+    }
+
+    #[tokio::test]
+    async fn test_write_and_read_regions() -> Result<(), Box<dyn Error>> {
+        let (mut repo, path) = repo_with_array("little").await?;
+        let data = Array::from_shape_vec((5, 7), (0..35).collect())?.into_dyn();
+        write_region(&mut repo, &path, &[0, 0], data.view()).await?;
+
+        let all = read_region::<i32>(&repo, &path, &[0..5, 0..7]).await?;
+        assert_eq!(all, data);
+
+        let region = read_region::<i32>(&repo, &path, &[1..4, 2..6]).await?;
+        assert_eq!(region, data.slice(s![1..4, 2..6]).into_dyn());
+
+        // partial chunk writes preserve the rest of the chunk
+        let patch = Array::from_elem((1, 2), 100).into_dyn();
+        write_region(&mut repo, &path, &[3, 4], patch.view()).await?;
+        let mut expected = data.clone();
+        expected.slice_mut(s![3..4, 4..6]).fill(100);
+        assert_eq!(read_region::<i32>(&repo, &path, &[0..5, 0..7]).await?, expected);
+
+        // the changes survive a commit
+        repo.commit("main", "write", None).await?;
+        assert_eq!(read_region::<i32>(&repo, &path, &[0..5, 0..7]).await?, expected);
+
+        let empty = read_region::<i32>(&repo, &path, &[2..2, 0..7]).await?;
+        assert_eq!(empty.shape(), &[0, 7]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_strided_selections() -> Result<(), Box<dyn Error>> {
+        let (mut repo, path) = repo_with_array("little").await?;
+        let data = Array::from_shape_vec((5, 7), (0..35).collect())?.into_dyn();
+        write_region(&mut repo, &path, &[0, 0], data.view()).await?;
+
+        let step = |n| NonZeroU64::new(n).unwrap();
+        let selection =
+            [DimSelection::strided(0..5, step(2)), DimSelection::strided(1..7, step(3))];
+        let res = read_selection::<i32>(&repo, &path, &selection).await?;
+        assert_eq!(res, data.slice(s![0..5;2, 1..7;3]).into_dyn());
+
+        let selection =
+            [DimSelection::strided(1..5, step(3)), DimSelection::strided(0..7, step(4))];
+        let res = read_selection::<i32>(&repo, &path, &selection).await?;
+        assert_eq!(res, data.slice(s![1..5;3, 0..7;4]).into_dyn());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_regions_follow_the_grid_origin() -> Result<(), Box<dyn Error>> {
+        let storage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(storage, false).await?.build();
+        let path: Path = "/array".try_into().unwrap();
+        let meta =
+            ZarrArrayMetadata { grid_origin: Some(vec![-1, -2]), ..metadata("little") };
+        repo.add_array(path.clone(), meta.clone()).await?;
+
+        // index 1 is the second element of the second chunk, 0 is the last of the first
+        write_region(
+            &mut repo,
+            &path,
+            &[1, 0],
+            Array::from_elem((1, 1), 7).view().into_dyn(),
+        )
+        .await?;
+        assert!(repo.get_chunk_ref(&path, &ChunkIndices(vec![1, 0])).await?.is_some());
+        assert!(repo.get_chunk_ref(&path, &ChunkIndices(vec![0, 0])).await?.is_none());
+        let chunk = read_chunk::<i32>(
+            &repo,
+            &path,
+            &ChunkIndices(vec![1, 0]),
+            &[2, 3],
+            Endianness::Little,
+        )
+        .await?
+        .unwrap();
+        assert_eq!(
+            chunk,
+            Array::from_shape_vec((2, 3), vec![-1, -1, 7, -1, -1, -1])?.into_dyn()
+        );
+
+        let data = Array::from_shape_vec((5, 7), (0..35).collect())?.into_dyn();
+        write_region(&mut repo, &path, &[0, 0], data.view()).await?;
+        assert_eq!(read_region::<i32>(&repo, &path, &[0..5, 0..7]).await?, data);
+        let selection =
+            [DimSelection::strided(0..5, NonZeroU64::new(2).unwrap()), (1..6).into()];
+        let res = read_selection::<i32>(&repo, &path, &selection).await?;
+        assert_eq!(res, data.slice(s![0..5;2, 1..6]).into_dyn());
+        let plans = plan_selection::<i32>(&meta, &[(0..1).into(), (0..1).into()])?;
+        assert_eq!(plans[0].coords, ChunkIndices(vec![0, 0]));
+        assert_eq!(plans[0].byte_range, ByteRange::bounded(20, 24));
+
+        // with a positive origin, the indexes before it have no chunk
+        let meta =
+            ZarrArrayMetadata { grid_origin: Some(vec![1, 0]), ..metadata("little") };
+        assert!(matches!(
+            plan_selection::<i32>(&meta, &[(0..2).into(), (0..1).into()]),
+            Err(ArrayError::InvalidRegion { .. })
+        ));
+        assert!(matches!(
+            overlapping_chunks(&meta, &[1..2, 0..1])?.as_slice(),
+            [ChunkOverlap { coords: ChunkIndices(c), .. }] if c == &vec![0, 0]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_selection() {
+        let meta = metadata("little");
+        let plans =
+            plan_selection::<i32>(&meta, &[(1..2).into(), (3..5).into()]).unwrap();
+        assert_eq!(
+            plans,
+            vec![ChunkReadPlan {
+                coords: ChunkIndices(vec![0, 1]),
+                // elements 3 and 4 of the chunk
+                byte_range: ByteRange::bounded(12, 20),
+                dimensions: vec![
+                    ChunkDimSelection { first: 1, count: 1, step: 1, output_first: 0 },
+                    ChunkDimSelection { first: 0, count: 2, step: 1, output_first: 0 },
+                ],
+            }]
+        );
+
+        // the step skips the last chunk entirely
+        let selection =
+            [(0..2).into(), DimSelection::strided(0..7, NonZeroU64::new(4).unwrap())];
+        let plans = plan_selection::<i32>(&meta, &selection).unwrap();
+        assert_eq!(
+            plans.iter().map(|plan| plan.coords.clone()).collect::<Vec<_>>(),
+            vec![ChunkIndices(vec![0, 0]), ChunkIndices(vec![0, 1])]
+        );
+        assert_eq!(plans[1].byte_range, ByteRange::bounded(4, 20));
+
+        assert!(plan_selection::<i32>(&meta, &[(0..0).into(), (0..7).into()])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scalar_arrays() -> Result<(), Box<dyn Error>> {
+        let (mut repo, array) = repo_with_array("little").await?;
+        let path: Path = "/scalar".try_into()?;
+        let meta = ZarrArrayMetadata::scalar(DataType::Float64, FillValue::Float64(-1.0));
+        repo.add_array(path.clone(), meta.clone()).await?;
+        assert_eq!(read_scalar::<f64>(&repo, &path).await?, -1.0);
+
+        write_scalar(&mut repo, &path, 42.5f64).await?;
+        repo.commit("main", "scalar", None).await?;
+        assert_eq!(read_scalar::<f64>(&repo, &path).await?, 42.5);
+        assert_eq!(
+            read_region::<f64>(&repo, &path, &[]).await?,
+            ArrayD::from_elem(IxDyn(&[]), 42.5)
+        );
+        let chunk = repo.get_chunk_ref(&path, &ChunkIndices(vec![])).await?;
+        assert!(chunk.is_some());
+
+        assert!(matches!(
+            read_scalar::<i32>(&repo, &array).await,
+            Err(ArrayError::NotAScalar { shape, .. }) if shape == vec![5, 7]
+        ));
+        let mismatched = ZarrArrayMetadata { shape: vec![3], ..meta };
+        assert!(repo.add_array("/bad".try_into()?, mismatched).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_chunks_use_fill_value() -> Result<(), Box<dyn Error>> {
+        let (mut repo, path) = repo_with_array("little").await?;
+        let patch = Array::from_elem((1, 1), 42).into_dyn();
+        write_region(&mut repo, &path, &[4, 6], patch.view()).await?;
+
+        let res = read_region::<i32>(&repo, &path, &[3..5, 5..7]).await?;
+        assert_eq!(res, Array::from_shape_vec((2, 2), vec![-1, -1, -1, 42])?.into_dyn());
+
+        // only the touched chunk was written
+        assert_eq!(
+            repo.changes().chunk_changes().map(|(_, c)| c.len()).sum::<usize>(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_big_endian_chunks() -> Result<(), Box<dyn Error>> {
+        let (mut repo, path) = repo_with_array("big").await?;
+        let data = Array::from_shape_vec((2, 3), (1..7).collect())?.into_dyn();
+        write_region(&mut repo, &path, &[0, 0], data.view()).await?;
+
+        let raw = get_chunk(
+            repo.get_chunk_reader(&path, &ChunkIndices(vec![0, 0]), &ByteRange::ALL)
+                .await?,
+        )
+        .await?
+        .unwrap();
+        assert_eq!(&raw[0..8], &[0, 0, 0, 1, 0, 0, 0, 2]);
+        assert_eq!(read_region::<i32>(&repo, &path, &[0..2, 0..3]).await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vlen_arrays() -> Result<(), Box<dyn Error>> {
+        let (mut repo, _) = repo_with_array("little").await?;
+        let path: Path = "/names".try_into()?;
+        let meta = ZarrArrayMetadata {
+            shape: vec![5],
+            data_type: DataType::String,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap()]),
+            fill_value: FillValue::String("?".to_string()),
+            codecs: vec![Codec { name: "vlen-utf8".to_string(), configuration: None }],
+            ..metadata("little")
+        };
+        let bad_codec =
+            ZarrArrayMetadata { codecs: metadata("little").codecs, ..meta.clone() };
+        assert!(repo.add_array(path.clone(), bad_codec).await.is_err());
+        repo.add_array(path.clone(), meta).await?;
+
+        let names = ["ab", "", "ñandú"].map(String::from);
+        let data = Array::from_vec(names.to_vec()).into_dyn();
+        write_vlen_region(&mut repo, &path, &[1], data.view()).await?;
+        repo.commit("main", "names", None).await?;
+        let res = read_vlen_region::<String>(&repo, &path, std::slice::from_ref(&(0..5)))
+            .await?;
+        assert_eq!(
+            res.iter().cloned().collect_vec(),
+            vec!["?", "ab", "", "ñandú", "?"].into_iter().map(String::from).collect_vec()
+        );
+
+        // chunks with the wrong number of elements, or bad UTF-8, are rejected
+        let writer = repo.get_array_chunk_writer(&path).await?;
+        let short = writer(encode_vlen(["a"].iter())).await?;
+        assert!(matches!(
+            repo.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(short)).await,
+            Err(RepositoryError::InvalidVlenChunk {
+                source: VlenError::ElementCount { expected: 2, found: 1 },
+                ..
+            })
+        ));
+        let writer = repo.get_array_chunk_writer(&path).await?;
+        let binary = writer(encode_vlen([&[0xff][..], b"a"].iter())).await?;
+        assert!(matches!(
+            repo.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(binary)).await,
+            Err(RepositoryError::InvalidVlenChunk {
+                source: VlenError::InvalidUtf8(0),
+                ..
+            })
+        ));
+        assert!(matches!(
+            read_vlen_region::<Vec<u8>>(&repo, &path, std::slice::from_ref(&(0..1)))
+                .await,
+            Err(ArrayError::DataTypeMismatch { expected: DataType::Bytes, .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_reads() -> Result<(), Box<dyn Error>> {
+        let (repo, path) = repo_with_array("little").await?;
+        assert!(matches!(
+            read_region::<f64>(&repo, &path, &[0..1, 0..1]).await,
+            Err(ArrayError::DataTypeMismatch { expected: DataType::Float64, .. })
+        ));
+        assert!(matches!(
+            read_region::<i32>(&repo, &path, &[0..6, 0..1]).await,
+            Err(ArrayError::InvalidRegion { .. })
+        ));
+        assert!(matches!(
+            read_region::<i32>(&repo, &path, std::slice::from_ref(&(0..1))).await,
+            Err(ArrayError::InvalidRegion { .. })
+        ));
+        assert!(matches!(
+            Endianness::from_codecs(&[Codec {
+                name: "zstd".to_string(),
+                configuration: None
+            }]),
+            Err(ArrayError::UnsupportedCodec(name)) if name == "zstd"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+//! A checked way to describe new arrays
+//!
+//! [`ArrayBuilder`] checks the chunk shape, the fill value, the dimension names and the codec
+//! pipeline before the array is created, a [`ZarrArrayMetadata`] built by hand is only
+//! partially checked by [`crate::Repository::add_array`], and mistakes can show up much
+//! later, when the first chunk is written or read.
+use std::{collections::HashMap, num::NonZeroU64};
+
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    array::Element,
+    format::{IcechunkFormatError, Path},
+    metadata::{
+        vlen::{VLEN_BYTES_CODEC, VLEN_UTF8_CODEC},
+        ArrayShape, ChunkKeyEncoding, ChunkShape, Codec, DataType, DimensionName,
+        FillValue,
+    },
+    repository::{RepositoryResult, ZarrArrayMetadata},
+    Repository,
+};
+
+#[derive(Debug, Error, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ArrayBuilderError {
+    #[error("chunk shape has {chunk_ndim} dimensions, the array has {ndim}")]
+    ChunkDimensions { ndim: usize, chunk_ndim: usize },
+    #[error("chunk size of dimension {0} is zero")]
+    ZeroChunkSize(usize),
+    #[error("{names} dimension names for an array with {ndim} dimensions")]
+    DimensionNames { ndim: usize, names: usize },
+    #[error("dimension name `{0}` is used more than once")]
+    DuplicateDimensionName(String),
+    #[error("fill value `{fill_value:?}` is not valid for data type `{data_type}`")]
+    FillValue { data_type: DataType, fill_value: FillValue },
+    #[error("the codecs have no array to bytes codec, like `bytes`")]
+    MissingBytesCodec,
+    #[error("the codecs have more than one array to bytes codec: `{0}` and `{1}`")]
+    MultipleBytesCodecs(String, String),
+    #[error("codec `{0}` works on arrays, it must come before the array to bytes codec")]
+    CodecOrder(String),
+    #[error("invalid configuration for codec `{codec}`: {message}")]
+    CodecConfiguration { codec: String, message: String },
+    #[error("invalid codecs for the data type: {0}")]
+    Format(#[from] IcechunkFormatError),
+}
+
+pub type ArrayBuilderResult<A> = Result<A, ArrayBuilderError>;
+
+/// Codecs that turn elements into bytes, an array has exactly one
+const ARRAY_TO_BYTES_CODECS: [&str; 4] =
+    ["bytes", VLEN_UTF8_CODEC, VLEN_BYTES_CODEC, "sharding_indexed"];
+/// Codecs that go from arrays to arrays, before the array to bytes codec
+const ARRAY_TO_ARRAY_CODECS: [&str; 1] = ["transpose"];
+
+/// A new array, checked by [`ArrayBuilder::build`]
+///
+/// With no chunk shape, the whole array is one chunk. With no codecs, chunks are stored
+/// uncompressed, in little endian, or with the vlen codec of variable length types. The
+/// fill value defaults to the zero of the data type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArrayBuilder {
+    shape: ArrayShape,
+    data_type: DataType,
+    chunk_shape: Option<Vec<u64>>,
+    fill_value: Option<FillValue>,
+    codecs: Option<Vec<Codec>>,
+    dimension_names: Option<Vec<DimensionName>>,
+    chunk_key_encoding: ChunkKeyEncoding,
+    chunk_transformers: Option<Vec<String>>,
+    grid_origin: Option<Vec<i64>>,
+}
+
+impl ArrayBuilder {
+    pub fn new(shape: ArrayShape, data_type: DataType) -> Self {
+        Self {
+            shape,
+            data_type,
+            chunk_shape: None,
+            fill_value: None,
+            codecs: None,
+            dimension_names: None,
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            chunk_transformers: None,
+            grid_origin: None,
+        }
+    }
+
+    /// An array of the data type of `T`
+    pub fn of<T: Element>(shape: ArrayShape) -> Self {
+        Self::new(shape, T::DATA_TYPE)
+    }
+
+    pub fn with_chunk_shape(mut self, chunk_shape: Vec<u64>) -> Self {
+        self.chunk_shape = Some(chunk_shape);
+        self
+    }
+
+    pub fn with_fill_value(mut self, fill_value: FillValue) -> Self {
+        self.fill_value = Some(fill_value);
+        self
+    }
+
+    /// The whole codec pipeline, including the array to bytes codec
+    pub fn with_codecs(mut self, codecs: Vec<Codec>) -> Self {
+        self.codecs = Some(codecs);
+        self
+    }
+
+    pub fn with_dimension_names<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = Option<S>>,
+    ) -> Self {
+        self.dimension_names =
+            Some(names.into_iter().map(|name| name.map(Into::into)).collect());
+        self
+    }
+
+    pub fn with_chunk_key_encoding(mut self, encoding: ChunkKeyEncoding) -> Self {
+        self.chunk_key_encoding = encoding;
+        self
+    }
+
+    /// See [`ZarrArrayMetadata::chunk_transformers`]
+    pub fn with_chunk_transformers(mut self, ids: Vec<String>) -> Self {
+        self.chunk_transformers = Some(ids);
+        self
+    }
+
+    /// See [`ZarrArrayMetadata::grid_origin`]
+    pub fn with_grid_origin(mut self, origin: Vec<i64>) -> Self {
+        self.grid_origin = Some(origin);
+        self
+    }
+
+    pub fn build(self) -> ArrayBuilderResult<ZarrArrayMetadata> {
+        let ndim = self.shape.len();
+        let chunk_shape = match self.chunk_shape {
+            Some(chunk_shape) => chunk_shape,
+            // at least one element, zero length dimensions have no chunks anyway
+            None => self.shape.iter().map(|n| (*n).max(1)).collect(),
+        };
+        if chunk_shape.len() != ndim {
+            return Err(ArrayBuilderError::ChunkDimensions {
+                ndim,
+                chunk_ndim: chunk_shape.len(),
+            });
+        }
+        let chunk_shape = chunk_shape
+            .iter()
+            .enumerate()
+            .map(|(dim, n)| {
+                NonZeroU64::new(*n).ok_or(ArrayBuilderError::ZeroChunkSize(dim))
+            })
+            .collect::<ArrayBuilderResult<Vec<_>>>()?;
+
+        if let Some(names) = self.dimension_names.as_ref() {
+            if names.len() != ndim {
+                return Err(ArrayBuilderError::DimensionNames {
+                    ndim,
+                    names: names.len(),
+                });
+            }
+            let mut named: Vec<&String> = names.iter().flatten().collect();
+            named.sort();
+            if let Some(window) = named.windows(2).find(|pair| pair[0] == pair[1]) {
+                return Err(ArrayBuilderError::DuplicateDimensionName(window[0].clone()));
+            }
+        }
+
+        let fill_value =
+            self.fill_value.unwrap_or_else(|| default_fill_value(&self.data_type));
+        if !fill_value.is_valid_for(&self.data_type) {
+            return Err(ArrayBuilderError::FillValue {
+                data_type: self.data_type,
+                fill_value,
+            });
+        }
+
+        let codecs = self.codecs.unwrap_or_else(|| default_codecs(&self.data_type));
+        check_codecs(&codecs, ndim)?;
+
+        let meta = ZarrArrayMetadata {
+            shape: self.shape,
+            data_type: self.data_type,
+            chunk_shape: ChunkShape(chunk_shape),
+            chunk_key_encoding: self.chunk_key_encoding,
+            fill_value,
+            codecs,
+            storage_transformers: None,
+            dimension_names: self.dimension_names,
+            chunk_transformers: self.chunk_transformers,
+            grid_origin: self.grid_origin,
+        };
+        meta.validate()?;
+        Ok(meta)
+    }
+
+    /// Build the metadata and add the array at `path`
+    pub async fn create(
+        self,
+        repo: &mut Repository,
+        path: Path,
+    ) -> RepositoryResult<ZarrArrayMetadata> {
+        let meta = self.build()?;
+        repo.add_array(path, meta.clone()).await?;
+        Ok(meta)
+    }
+}
+
+fn default_fill_value(data_type: &DataType) -> FillValue {
+    match data_type {
+        DataType::Bool => FillValue::Bool(false),
+        DataType::Int8 => FillValue::Int8(0),
+        DataType::Int16 => FillValue::Int16(0),
+        DataType::Int32 => FillValue::Int32(0),
+        DataType::Int64 | DataType::DateTime64(_) => FillValue::Int64(0),
+        DataType::UInt8 => FillValue::UInt8(0),
+        DataType::UInt16 => FillValue::UInt16(0),
+        DataType::UInt32 => FillValue::UInt32(0),
+        DataType::UInt64 => FillValue::UInt64(0),
+        DataType::Float16 => FillValue::Float16(0.0),
+        DataType::Float32 => FillValue::Float32(0.0),
+        DataType::Float64 => FillValue::Float64(0.0),
+        DataType::Complex64 => FillValue::Complex64(0.0, 0.0),
+        DataType::Complex128 => FillValue::Complex128(0.0, 0.0),
+        DataType::String | DataType::FixedLengthString(_) => {
+            FillValue::String(String::new())
+        }
+        DataType::Bytes => FillValue::Bytes(Vec::new()),
+    }
+}
+
+fn default_codecs(data_type: &DataType) -> Vec<Codec> {
+    let codec = match data_type.vlen_codec() {
+        Some(vlen) => Codec { name: vlen.to_string(), configuration: None },
+        None => Codec {
+            name: "bytes".to_string(),
+            configuration: Some(HashMap::from([(
+                "endian".to_string(),
+                Value::from("little"),
+            )])),
+        },
+    };
+    vec![codec]
+}
+
+/// One array to bytes codec, with the array to array codecs before it, and the
+/// configurations of the known codecs are valid
+fn check_codecs(codecs: &[Codec], ndim: usize) -> ArrayBuilderResult<()> {
+    let mut array_to_bytes: Option<&str> = None;
+    for codec in codecs {
+        let name = codec.name.as_str();
+        if ARRAY_TO_BYTES_CODECS.contains(&name) {
+            if let Some(first) = array_to_bytes {
+                return Err(ArrayBuilderError::MultipleBytesCodecs(
+                    first.to_string(),
+                    name.to_string(),
+                ));
+            }
+            array_to_bytes = Some(name);
+        } else if ARRAY_TO_ARRAY_CODECS.contains(&name) && array_to_bytes.is_some() {
+            return Err(ArrayBuilderError::CodecOrder(name.to_string()));
+        }
+        check_configuration(codec, ndim).map_err(|message| {
+            ArrayBuilderError::CodecConfiguration { codec: name.to_string(), message }
+        })?;
+    }
+    match array_to_bytes {
+        Some(_) => Ok(()),
+        None => Err(ArrayBuilderError::MissingBytesCodec),
+    }
+}
+
+fn check_configuration(codec: &Codec, ndim: usize) -> Result<(), String> {
+    let conf = |key: &str| codec.configuration.as_ref().and_then(|conf| conf.get(key));
+    match codec.name.as_str() {
+        "transpose" => {
+            let order = conf("order")
+                .and_then(Value::as_array)
+                .ok_or("`order` must be a list of dimensions")?;
+            let mut dims: Vec<u64> = order.iter().filter_map(Value::as_u64).collect();
+            dims.sort_unstable();
+            if dims.len() != order.len() || dims != (0..ndim as u64).collect::<Vec<_>>() {
+                return Err(format!("`order` must be a permutation of 0..{ndim}"));
+            }
+        }
+        "gzip" => match conf("level").and_then(Value::as_u64) {
+            Some(0..=9) => {}
+            _ => return Err("`level` must be between 0 and 9".to_string()),
+        },
+        "zstd" => match conf("level").and_then(Value::as_i64) {
+            Some(-131072..=22) => {}
+            _ => return Err("`level` must be at most 22".to_string()),
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::snapshot::NodeData, repository::RepositoryError, test_utils};
+
+    fn codec(name: &str, configuration: Value) -> Codec {
+        let Value::Object(conf) = configuration else { panic!("not an object") };
+        Codec { name: name.to_string(), configuration: Some(conf.into_iter().collect()) }
+    }
+
+    #[tokio::test]
+    async fn test_array_builder() -> Result<(), Box<dyn Error>> {
+        let meta = ArrayBuilder::of::<f32>(vec![10, 20])
+            .with_chunk_shape(vec![5, 5])
+            .with_dimension_names([Some("y"), Some("x")])
+            .build()?;
+        assert_eq!(meta.fill_value, FillValue::Float32(0.0));
+        assert_eq!(meta.uncompressed_chunk_size_bytes(), Some(100));
+        assert_eq!(
+            ArrayBuilder::new(vec![3], DataType::String).build()?.codecs[0].name,
+            VLEN_UTF8_CODEC
+        );
+
+        let base = ArrayBuilder::new(vec![10, 20], DataType::Int16);
+        let errors = [
+            (
+                base.clone().with_chunk_shape(vec![5]),
+                ArrayBuilderError::ChunkDimensions { ndim: 2, chunk_ndim: 1 },
+            ),
+            (
+                base.clone().with_grid_origin(vec![-5]),
+                ArrayBuilderError::Format(IcechunkFormatError::GridOriginMismatch {
+                    ndim: 2,
+                    origin_ndim: 1,
+                }),
+            ),
+            (
+                base.clone().with_chunk_shape(vec![5, 0]),
+                ArrayBuilderError::ZeroChunkSize(1),
+            ),
+            (
+                base.clone().with_dimension_names([Some("x"), Some("x")]),
+                ArrayBuilderError::DuplicateDimensionName("x".to_string()),
+            ),
+            (
+                base.clone().with_fill_value(FillValue::Float64(1.5)),
+                ArrayBuilderError::FillValue {
+                    data_type: DataType::Int16,
+                    fill_value: FillValue::Float64(1.5),
+                },
+            ),
+            (base.clone().with_codecs(vec![]), ArrayBuilderError::MissingBytesCodec),
+            (
+                base.clone().with_codecs(vec![
+                    codec("bytes", serde_json::json!({})),
+                    codec("transpose", serde_json::json!({"order": [1, 0]})),
+                ]),
+                ArrayBuilderError::CodecOrder("transpose".to_string()),
+            ),
+            (
+                base.clone().with_codecs(vec![
+                    codec("bytes", serde_json::json!({})),
+                    codec("gzip", serde_json::json!({"level": 12})),
+                ]),
+                ArrayBuilderError::CodecConfiguration {
+                    codec: "gzip".to_string(),
+                    message: "`level` must be between 0 and 9".to_string(),
+                },
+            ),
+        ];
+        for (builder, expected) in errors {
+            assert_eq!(builder.build(), Err(expected));
+        }
+
+        let mut repo =
+            test_utils::new_repository(test_utils::new_in_memory_storage()).await?;
+        repo.add_group(Path::root()).await?;
+        let path: Path = "/array".try_into()?;
+        let meta = base.clone().create(&mut repo, path.clone()).await?;
+        let NodeData::Array(stored, _) = repo.get_array(&path).await?.node_data else {
+            panic!("not an array")
+        };
+        assert_eq!(stored, meta);
+        assert!(matches!(
+            base.with_codecs(vec![]).create(&mut repo, "/other".try_into()?).await,
+            Err(RepositoryError::InvalidArray(ArrayBuilderError::MissingBytesCodec))
+        ));
+        Ok(())
+    }
+}
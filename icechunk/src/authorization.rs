@@ -0,0 +1,92 @@
+use std::{fmt::Debug, sync::Arc};
+
+use crate::format::Path;
+
+/// An operation a session is about to do, see [`Authorizer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation<'a> {
+    /// Read the node at the path, its metadata or its chunks
+    Read(&'a Path),
+    /// Create, modify or delete the node at the path, or write its chunks
+    Write(&'a Path),
+    /// Commit to, create or delete a branch, or create a tag
+    UpdateRef(&'a str),
+}
+
+/// Decides which operations a session is allowed to do
+///
+/// Repositories consult their authorizer, set with
+/// [`crate::RepositoryBuilder::with_authorizer`], before each operation, and fail with
+/// [`crate::repository::RepositoryError::Unauthorized`] if it's denied. Embedders, like
+/// [`crate::server::RepoServer`], use it to implement access control per path. Writes check
+/// whether the node exists first, so they also need read access to the path.
+pub trait Authorizer: Debug + Send + Sync {
+    /// Returns the reason the operation is denied, if it is
+    fn authorize(&self, operation: Operation<'_>) -> Result<(), String>;
+}
+
+pub type DynAuthorizer = Arc<dyn Authorizer>;
+
+/// Allows every operation, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _operation: Operation<'_>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub fn default_authorizer() -> DynAuthorizer {
+    Arc::new(AllowAll)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::{repository::RepositoryError, ObjectStorage, Repository, Storage};
+
+    /// Allows writes only under a path, and commits only to `main`
+    #[derive(Debug)]
+    struct WritesUnder(Path);
+
+    impl Authorizer for WritesUnder {
+        fn authorize(&self, operation: Operation<'_>) -> Result<(), String> {
+            match operation {
+                Operation::Read(_) => Ok(()),
+                Operation::Write(path) if path.starts_with(&self.0) => Ok(()),
+                Operation::UpdateRef("main") => Ok(()),
+                op => Err(format!("{op:?} is not allowed")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authorizer() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.commit("main", "root", None).await?;
+
+        let mut ds = Repository::from_branch_tip(storage, "main")
+            .await?
+            .with_authorizer(Arc::new(WritesUnder("/public".try_into()?)))
+            .build();
+        ds.add_group("/public".try_into()?).await?;
+        assert!(matches!(
+            ds.add_group("/private".try_into()?).await,
+            Err(RepositoryError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            ds.new_branch("dev").await,
+            Err(RepositoryError::Unauthorized(_))
+        ));
+        ds.commit("main", "public", None).await?;
+        assert!(ds.get_group(&"/public".try_into()?).await.is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use std::{io, sync::Arc};
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::runtime::Runtime;
+
+use crate::{
+    format::{snapshot::NodeSnapshot, ByteRange, ChunkIndices, SnapshotId},
+    metadata::UserAttributes,
+    repository::{
+        get_chunk, NodeListing, NodePage, Path, RepositoryError, ZarrArrayMetadata,
+    },
+    storage::s3::S3Config,
+    Repository, Storage,
+};
+
+#[derive(Debug, Error)]
+pub enum BlockingError {
+    #[error("error creating the async runtime {0}")]
+    Runtime(#[from] io::Error),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+}
+
+pub type BlockingResult<A> = Result<A, BlockingError>;
+
+/// A [`Repository`] with synchronous methods
+///
+/// Every instance manages its own runtime, so it can be used by code that is not async, for
+/// example, command line tools. The methods block the calling thread, calling them from
+/// async code panics; use [`Repository`] directly instead.
+#[derive(Debug)]
+pub struct BlockingRepository {
+    runtime: Runtime,
+    repo: Repository,
+}
+
+impl BlockingRepository {
+    pub fn new(repo: Repository) -> BlockingResult<Self> {
+        Ok(Self { runtime: Runtime::new()?, repo })
+    }
+
+    /// Initialize a new repository in `storage`, see [`Repository::init`]
+    pub fn init(storage: Arc<dyn Storage + Send + Sync>) -> BlockingResult<Self> {
+        let runtime = Runtime::new()?;
+        let repo = runtime.block_on(Repository::init(storage, false))?.build();
+        Ok(Self { runtime, repo })
+    }
+
+    /// Open the tip of `branch_name` in `storage`
+    pub fn open(
+        storage: Arc<dyn Storage + Send + Sync>,
+        branch_name: &str,
+    ) -> BlockingResult<Self> {
+        let runtime = Runtime::new()?;
+        let repo =
+            runtime.block_on(Repository::from_branch_tip(storage, branch_name))?.build();
+        Ok(Self { runtime, repo })
+    }
+
+    /// Open the default branch of the repository at `url`, see [`Repository::from_url`]
+    pub fn from_url(url: &str, s3_config: Option<S3Config>) -> BlockingResult<Self> {
+        let runtime = Runtime::new()?;
+        let repo = runtime.block_on(Repository::from_url(url, s3_config))?.build();
+        Ok(Self { runtime, repo })
+    }
+
+    pub fn repository(&self) -> &Repository {
+        &self.repo
+    }
+
+    pub fn into_repository(self) -> Repository {
+        self.repo
+    }
+
+    pub fn snapshot_id(&self) -> &SnapshotId {
+        self.repo.snapshot_id()
+    }
+
+    pub fn has_uncommitted_changes(&self) -> bool {
+        self.repo.has_uncommitted_changes()
+    }
+
+    pub fn get_node(&self, path: &Path) -> BlockingResult<NodeSnapshot> {
+        Ok(self.runtime.block_on(self.repo.get_node(path))?)
+    }
+
+    pub fn list_nodes(&self) -> BlockingResult<Vec<NodeSnapshot>> {
+        Ok(self
+            .runtime
+            .block_on(async { self.repo.list_nodes().await.map(Vec::from_iter) })?)
+    }
+
+    pub fn list_nodes_page(
+        &self,
+        listing: &NodeListing,
+        page_size: usize,
+    ) -> BlockingResult<NodePage> {
+        Ok(self.runtime.block_on(self.repo.list_nodes_page(listing, page_size))?)
+    }
+
+    /// Read `byte_range` of a chunk, `None` if the chunk is not set
+    pub fn get_chunk(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        byte_range: &ByteRange,
+    ) -> BlockingResult<Option<Bytes>> {
+        Ok(self.runtime.block_on(async {
+            get_chunk(self.repo.get_chunk_reader(path, coords, byte_range).await?).await
+        })?)
+    }
+
+    pub fn add_group(&mut self, path: Path) -> BlockingResult<()> {
+        Ok(self.runtime.block_on(self.repo.add_group(path))?)
+    }
+
+    pub fn add_array(
+        &mut self,
+        path: Path,
+        metadata: ZarrArrayMetadata,
+    ) -> BlockingResult<()> {
+        Ok(self.runtime.block_on(self.repo.add_array(path, metadata))?)
+    }
+
+    pub fn set_user_attributes(
+        &mut self,
+        path: Path,
+        atts: Option<UserAttributes>,
+    ) -> BlockingResult<()> {
+        Ok(self.runtime.block_on(self.repo.set_user_attributes(path, atts))?)
+    }
+
+    /// Write the chunk and set it at `coords`, `None` deletes the chunk
+    pub fn set_chunk(
+        &mut self,
+        path: Path,
+        coords: ChunkIndices,
+        data: Option<Bytes>,
+    ) -> BlockingResult<()> {
+        let repo = &mut self.repo;
+        Ok(self.runtime.block_on(async {
+            let payload = match data {
+                Some(data) => Some(repo.get_chunk_writer()(data).await?),
+                None => None,
+            };
+            repo.set_chunk_ref(path, coords, payload).await
+        })?)
+    }
+
+    pub fn commit(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+    ) -> BlockingResult<SnapshotId> {
+        Ok(self.runtime.block_on(self.repo.commit(update_branch_name, message, None))?)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::snapshot::NodeType,
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        refs::Ref,
+        ObjectStorage,
+    };
+
+    #[test]
+    fn test_blocking_repository() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = BlockingRepository::init(Arc::clone(&storage))?;
+        let meta = ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::UInt8,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into()?;
+        repo.add_group(Path::root())?;
+        repo.add_array(path.clone(), meta)?;
+        let data = Bytes::from(vec![42; 1_000]);
+        repo.set_chunk(path.clone(), ChunkIndices(vec![0]), Some(data.clone()))?;
+        assert!(repo.has_uncommitted_changes());
+        let snapshot = repo.commit(Ref::DEFAULT_BRANCH, "first")?;
+
+        let repo = BlockingRepository::open(storage, Ref::DEFAULT_BRANCH)?;
+        assert_eq!(repo.snapshot_id(), &snapshot);
+        assert_eq!(repo.list_nodes()?.len(), 2);
+        assert_eq!(repo.get_node(&path)?.node_type(), NodeType::Array);
+        assert_eq!(
+            repo.get_chunk(&path, &ChunkIndices(vec![0]), &ByteRange::ALL)?,
+            Some(data)
+        );
+        assert_eq!(repo.get_chunk(&path, &ChunkIndices(vec![1]), &ByteRange::ALL)?, None);
+        Ok(())
+    }
+}
@@ -10,10 +10,11 @@ use serde::{Deserialize, Serialize};
 use crate::{
     format::{
         manifest::{ChunkInfo, ManifestExtents, ManifestRef},
-        snapshot::{NodeData, NodeSnapshot, UserAttributesSnapshot},
+        snapshot::{CustomNodeData, NodeData, NodeSnapshot, UserAttributesSnapshot},
         ManifestId, NodeId,
     },
     metadata::UserAttributes,
+    ops::documents::DocumentRef,
     repository::{ChunkIndices, ChunkPayload, Path, RepositoryResult, ZarrArrayMetadata},
 };
 
@@ -29,6 +30,15 @@ pub struct ChangeSet {
     set_chunks: HashMap<NodeId, HashMap<ChunkIndices, Option<ChunkPayload>>>,
     deleted_groups: HashSet<Path>,
     deleted_arrays: HashSet<Path>,
+    #[serde(default)]
+    new_custom_nodes: HashMap<Path, (NodeId, CustomNodeData)>,
+    #[serde(default)]
+    updated_custom_nodes: HashMap<NodeId, CustomNodeData>,
+    #[serde(default)]
+    deleted_custom_nodes: HashSet<Path>,
+    // Documents written or deleted, by group path and name
+    #[serde(default)]
+    documents: HashMap<Path, HashMap<String, Option<DocumentRef>>>,
 }
 
 impl ChangeSet {
@@ -44,6 +54,14 @@ impl ChangeSet {
         self.deleted_groups.iter()
     }
 
+    pub fn custom_updated_nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.updated_custom_nodes.keys()
+    }
+
+    pub fn deleted_custom_nodes(&self) -> impl Iterator<Item = &Path> {
+        self.deleted_custom_nodes.iter()
+    }
+
     pub fn user_attributes_updated_nodes(&self) -> impl Iterator<Item = &NodeId> {
         self.updated_attributes.keys()
     }
@@ -63,6 +81,41 @@ impl ChangeSet {
         self == &ChangeSet::default()
     }
 
+    /// Only attributes and metadata of existing nodes change
+    pub fn is_metadata_only(&self) -> bool {
+        self.new_groups.is_empty()
+            && self.new_arrays.is_empty()
+            && self.new_custom_nodes.is_empty()
+            && self.deleted_groups.is_empty()
+            && self.deleted_arrays.is_empty()
+            && self.deleted_custom_nodes.is_empty()
+            && self.set_chunks.is_empty()
+            && self.documents.is_empty()
+    }
+
+    /// Record the write, with `Some`, or the delete of the document `name` of group `path`
+    pub fn set_document(
+        &mut self,
+        path: Path,
+        name: String,
+        document: Option<DocumentRef>,
+    ) {
+        self.documents.entry(path).or_default().insert(name, document);
+    }
+
+    /// `Some(None)` if the document was deleted, `None` if it didn't change
+    pub fn get_document(&self, path: &Path, name: &str) -> Option<&Option<DocumentRef>> {
+        self.documents.get(path).and_then(|documents| documents.get(name))
+    }
+
+    pub fn document_changes(
+        &self,
+    ) -> impl Iterator<Item = (&Path, &String, &Option<DocumentRef>)> {
+        self.documents.iter().flat_map(|(path, documents)| {
+            documents.iter().map(move |(name, document)| (path, name, document))
+        })
+    }
+
     pub fn add_group(&mut self, path: Path, node_id: NodeId) {
         // when overwriting a group that exists in the base snapshot,
         // zarr will delete the group first, and then add a new group
@@ -118,6 +171,49 @@ impl ChangeSet {
         for (path, node) in arrays_to_delete {
             self.delete_array(path, &node);
         }
+
+        let custom_nodes_to_delete: Vec<_> = self
+            .new_custom_nodes
+            .iter()
+            .filter(|(child_path, _)| child_path.starts_with(path))
+            .map(|(k, (node, _))| (k.clone(), node.clone()))
+            .collect();
+
+        for (path, node) in custom_nodes_to_delete {
+            self.delete_custom_node(path, &node);
+        }
+    }
+
+    pub fn add_custom_node(&mut self, path: Path, node_id: NodeId, data: CustomNodeData) {
+        // same as for groups and arrays, the node may be replacing a deleted one
+        self.deleted_custom_nodes.remove(&path);
+        self.new_custom_nodes.insert(path, (node_id, data));
+    }
+
+    pub fn get_custom_node(&self, path: &Path) -> Option<&(NodeId, CustomNodeData)> {
+        self.new_custom_nodes.get(path)
+    }
+
+    pub fn update_custom_node(&mut self, node_id: NodeId, data: CustomNodeData) {
+        self.updated_custom_nodes.insert(node_id, data);
+    }
+
+    pub fn get_updated_custom_node(&self, node_id: &NodeId) -> Option<&CustomNodeData> {
+        self.updated_custom_nodes.get(node_id)
+    }
+
+    pub fn delete_custom_node(&mut self, path: Path, node_id: &NodeId) {
+        self.updated_custom_nodes.remove(node_id);
+        self.updated_attributes.remove(node_id);
+        match self.new_custom_nodes.remove(&path) {
+            Some(deleted) => {
+                debug_assert!(&deleted.0 == node_id);
+                self.delete_children(&path);
+            }
+            None => {
+                self.deleted_custom_nodes.insert(path);
+            }
+        }
     }
 
     pub fn add_array(
@@ -158,6 +254,7 @@ impl ChangeSet {
     pub fn is_deleted(&self, path: &Path) -> bool {
         self.deleted_groups.contains(path)
             || self.deleted_arrays.contains(path)
+            || self.deleted_custom_nodes.contains(path)
             || path.ancestors().skip(1).any(|parent| self.is_deleted(&parent))
     }
 
@@ -257,7 +354,7 @@ impl ChangeSet {
     }
 
     pub fn new_nodes(&self) -> impl Iterator<Item = (&Path, &NodeId)> {
-        self.new_groups().chain(self.new_arrays())
+        self.new_groups().chain(self.new_arrays()).chain(self.new_custom_nodes())
     }
 
     pub fn new_groups(&self) -> impl Iterator<Item = (&Path, &NodeId)> {
@@ -268,6 +365,10 @@ impl ChangeSet {
         self.new_arrays.iter().map(|(path, (node_id, _))| (path, node_id))
     }
 
+    pub fn new_custom_nodes(&self) -> impl Iterator<Item = (&Path, &NodeId)> {
+        self.new_custom_nodes.iter().map(|(path, (node_id, _))| (path, node_id))
+    }
+
     pub fn take_chunks(
         &mut self,
     ) -> HashMap<NodeId, HashMap<ChunkIndices, Option<ChunkPayload>>> {
@@ -295,6 +396,12 @@ impl ChangeSet {
         self.updated_attributes.extend(other.updated_attributes);
         self.deleted_groups.extend(other.deleted_groups);
         self.deleted_arrays.extend(other.deleted_arrays);
+        self.new_custom_nodes.extend(other.new_custom_nodes);
+        self.updated_custom_nodes.extend(other.updated_custom_nodes);
+        self.deleted_custom_nodes.extend(other.deleted_custom_nodes);
+        for (path, documents) in other.documents {
+            self.documents.entry(path).or_default().extend(documents);
+        }
 
         for (node, other_chunks) in other.set_chunks.into_iter() {
             match self.set_chunks.remove(&node) {
@@ -316,6 +423,53 @@ impl ChangeSet {
         });
     }
 
+    /// Move the changes to nodes under `root` into a new `ChangeSet`
+    ///
+    /// Nodes under `root` must have been created by this change set, changes to nodes of the
+    /// base snapshot are kept in `self`.
+    pub fn split_off(&mut self, root: &Path) -> ChangeSet {
+        let mut res = ChangeSet::default();
+        let (moved, kept) = take(&mut self.new_groups)
+            .into_iter()
+            .partition(|(path, _)| path.starts_with(root));
+        (res.new_groups, self.new_groups) = (moved, kept);
+        let (moved, kept) = take(&mut self.new_arrays)
+            .into_iter()
+            .partition(|(path, _)| path.starts_with(root));
+        (res.new_arrays, self.new_arrays) = (moved, kept);
+        let (moved, kept) = take(&mut self.new_custom_nodes)
+            .into_iter()
+            .partition(|(path, _)| path.starts_with(root));
+        (res.new_custom_nodes, self.new_custom_nodes) = (moved, kept);
+        let (moved, kept) = take(&mut self.documents)
+            .into_iter()
+            .partition(|(path, _)| path.starts_with(root));
+        (res.documents, self.documents) = (moved, kept);
+
+        let ids: HashSet<NodeId> = res
+            .new_groups
+            .values()
+            .chain(res.new_arrays.values().map(|(id, _)| id))
+            .chain(res.new_custom_nodes.values().map(|(id, _)| id))
+            .cloned()
+            .collect();
+        for id in ids.iter() {
+            if let Some(meta) = self.updated_arrays.remove(id) {
+                res.updated_arrays.insert(id.clone(), meta);
+            }
+            if let Some(atts) = self.updated_attributes.remove(id) {
+                res.updated_attributes.insert(id.clone(), atts);
+            }
+            if let Some(chunks) = self.set_chunks.remove(id) {
+                res.set_chunks.insert(id.clone(), chunks);
+            }
+            if let Some(data) = self.updated_custom_nodes.remove(id) {
+                res.updated_custom_nodes.insert(id.clone(), data);
+            }
+        }
+        res
+    }
+
     /// Serialize this ChangeSet
     ///
     /// This is intended to help with marshalling distributed writers back to the coordinator
@@ -344,7 +498,9 @@ impl ChangeSet {
     }
 
     pub fn get_new_node(&self, path: &Path) -> Option<NodeSnapshot> {
-        self.get_new_array(path).or(self.get_new_group(path))
+        self.get_new_array(path)
+            .or_else(|| self.get_new_group(path))
+            .or_else(|| self.get_new_custom_node(path))
     }
 
     pub fn get_new_array(&self, path: &Path) -> Option<NodeSnapshot> {
@@ -374,6 +530,19 @@ impl ChangeSet {
         })
     }
 
+    pub fn get_new_custom_node(&self, path: &Path) -> Option<NodeSnapshot> {
+        self.get_custom_node(path).map(|(id, data)| {
+            let data = self.get_updated_custom_node(id).unwrap_or(data).clone();
+            let atts = self.get_user_attributes(id).cloned();
+            NodeSnapshot {
+                id: id.clone(),
+                path: path.clone(),
+                user_attributes: atts.flatten().map(UserAttributesSnapshot::Inline),
+                node_data: NodeData::Custom(data),
+            }
+        })
+    }
+
     pub fn new_nodes_iterator<'a>(
         &'a self,
         manifest_id: Option<&'a ManifestId>,
@@ -387,7 +556,7 @@ impl ChangeSet {
             #[allow(clippy::expect_used)]
             let node = self.get_new_node(path).expect("Bug in new_nodes implementation");
             match node.node_data {
-                NodeData::Group => Some(node),
+                NodeData::Group | NodeData::Custom(_) => Some(node),
                 NodeData::Array(meta, _no_manifests_yet) => {
                     let new_manifests = manifest_id
                         .map(|mid| {
@@ -422,6 +591,15 @@ impl ChangeSet {
         let new_atts = session_atts.unwrap_or(node.user_attributes);
         match node.node_data {
             NodeData::Group => Some(NodeSnapshot { user_attributes: new_atts, ..node }),
+            NodeData::Custom(old_data) => {
+                let new_data =
+                    self.get_updated_custom_node(&node.id).cloned().unwrap_or(old_data);
+                Some(NodeSnapshot {
+                    node_data: NodeData::Custom(new_data),
+                    user_attributes: new_atts,
+                    ..node
+                })
+            }
             NodeData::Array(old_zarr_meta, _) => {
                 let new_zarr_meta = self
                     .get_updated_zarr_metadata(&node.id)
@@ -0,0 +1,297 @@
+//! Compression of chunks with dictionaries trained on the chunks of an array
+//!
+//! Small chunks with similar content compress poorly one at a time, compressors like zstd
+//! do much better with a dictionary trained on a sample of them.
+//! [`crate::Repository::train_chunk_dictionary`] trains one with a [`DictionaryCompressor`]
+//! configured with [`crate::RepositoryBuilder::with_dictionary_compressor`], stores it in the
+//! repository, and records the [`DictionaryTransformer`] that uses it in the chunk
+//! transformers of the array, see [`crate::chunk_transformer`]. The id of the transformer
+//! names the compressor and the dictionary, so any session with the compressor configured
+//! decodes the chunks, whatever dictionary they were written with.
+//!
+//! Icechunk doesn't ship compressors, and doesn't depend on zstd, a zstd one wraps
+//! `zstd::dict::from_samples` and the `zstd::bulk` functions that take a dictionary.
+//!
+//! Dictionaries are stored as chunks, with the id of the dictionary as chunk id.
+//! [`crate::ops::gc::garbage_collect`] keeps them while a live snapshot has an array
+//! configured with them, or a live manifest has chunks encoded with them.
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use bytes::Bytes;
+use tokio::sync::Mutex;
+
+use crate::{
+    chunk_transformer::{ChunkTransformError, ChunkTransformer, DynChunkTransformer},
+    format::{ByteRange, ChunkId},
+    repository::RepositoryResult,
+    Storage,
+};
+
+const ID_SEPARATOR: &str = "+dict.";
+
+pub trait DictionaryCompressor: Debug + Send + Sync {
+    /// Identifies the compression, `zstd` for example
+    fn id(&self) -> &str;
+
+    /// A dictionary of at most `max_size_bytes` for chunks like `samples`, or a description
+    /// of the problem
+    fn train(&self, samples: &[Bytes], max_size_bytes: usize) -> Result<Bytes, String>;
+
+    fn compress(&self, dictionary: &[u8], data: Bytes) -> Result<Bytes, String>;
+
+    /// The inverse of [`DictionaryCompressor::compress`] with the same dictionary
+    fn decompress(&self, dictionary: &[u8], data: Bytes) -> Result<Bytes, String>;
+}
+
+pub type DynDictionaryCompressor = Arc<dyn DictionaryCompressor>;
+
+/// The id of the [`DictionaryTransformer`] of `compressor` with dictionary `dictionary`
+pub fn dictionary_transformer_id(compressor: &str, dictionary: &str) -> String {
+    format!("{compressor}{ID_SEPARATOR}{dictionary}")
+}
+
+/// The compressor and dictionary of a [`DictionaryTransformer`] id
+pub fn parse_dictionary_transformer_id(id: &str) -> Option<(&str, &str)> {
+    id.split_once(ID_SEPARATOR)
+}
+
+/// A [`ChunkTransformer`] that compresses with a trained dictionary
+#[derive(Debug)]
+pub struct DictionaryTransformer {
+    id: String,
+    compressor: DynDictionaryCompressor,
+    dictionary: Bytes,
+}
+
+impl DictionaryTransformer {
+    pub fn new(
+        compressor: DynDictionaryCompressor,
+        dictionary_id: &str,
+        dictionary: Bytes,
+    ) -> Self {
+        let id = dictionary_transformer_id(compressor.id(), dictionary_id);
+        Self { id, compressor, dictionary }
+    }
+}
+
+impl ChunkTransformer for DictionaryTransformer {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn encode(&self, data: Bytes) -> Result<Bytes, String> {
+        self.compressor.compress(&self.dictionary, data)
+    }
+
+    fn decode(&self, data: Bytes) -> Result<Bytes, String> {
+        self.compressor.decompress(&self.dictionary, data)
+    }
+}
+
+/// The dictionaries used by the transformers with ids `ids`
+pub(crate) fn dictionary_chunks<'a>(
+    ids: impl IntoIterator<Item = &'a String> + 'a,
+) -> impl Iterator<Item = ChunkId> + 'a {
+    ids.into_iter()
+        .filter_map(|id| parse_dictionary_transformer_id(id))
+        .filter_map(|(_, dictionary)| ChunkId::try_from(dictionary).ok())
+}
+
+fn dictionary_chunk(id: &str) -> Result<ChunkId, ChunkTransformError> {
+    ChunkId::try_from(id)
+        .map_err(|_| ChunkTransformError::UnknownTransformer(id.to_string()))
+}
+
+pub(crate) async fn write_dictionary(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+    dictionary: Bytes,
+) -> RepositoryResult<()> {
+    Ok(storage.write_chunk(dictionary_chunk(id)?, dictionary).await?)
+}
+
+pub async fn fetch_dictionary(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+) -> RepositoryResult<Bytes> {
+    Ok(storage.fetch_chunk(&dictionary_chunk(id)?, &ByteRange::ALL).await?)
+}
+
+/// The dictionary transformers loaded by a session, by id
+#[derive(Debug, Default)]
+pub(crate) struct DictionaryCache(Mutex<HashMap<String, DynChunkTransformer>>);
+
+impl DictionaryCache {
+    /// Like [`crate::chunk_transformer::resolve_transformers`], also finding the
+    /// dictionary transformers of the `compressors`, with their dictionaries fetched once
+    pub(crate) async fn resolve(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        configured: &[DynChunkTransformer],
+        compressors: &[DynDictionaryCompressor],
+        ids: &[String],
+    ) -> RepositoryResult<Vec<DynChunkTransformer>> {
+        let mut res = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(transformer) = configured.iter().find(|t| t.id() == id) {
+                res.push(Arc::clone(transformer));
+                continue;
+            }
+            let compressor =
+                parse_dictionary_transformer_id(id).and_then(|(name, dict)| {
+                    compressors.iter().find(|c| c.id() == name).map(|c| (c, dict))
+                });
+            let Some((compressor, dictionary_id)) = compressor else {
+                return Err(ChunkTransformError::UnknownTransformer(id.clone()).into());
+            };
+            let cached = self.0.lock().await.get(id).cloned();
+            let transformer = match cached {
+                Some(transformer) => transformer,
+                None => {
+                    let dictionary = fetch_dictionary(storage, dictionary_id).await?;
+                    let transformer: DynChunkTransformer =
+                        Arc::new(DictionaryTransformer::new(
+                            Arc::clone(compressor),
+                            dictionary_id,
+                            dictionary,
+                        ));
+                    self.0.lock().await.insert(id.clone(), Arc::clone(&transformer));
+                    transformer
+                }
+            };
+            res.push(transformer);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use chrono::{TimeDelta, Utc};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{manifest::ChunkPayload, ChunkIndices, Path},
+        ops::gc::{garbage_collect, GCConfig},
+        repository::{get_chunk, RepositoryError},
+        test_utils::{array_metadata, new_in_memory_storage},
+        Repository,
+    };
+
+    /// Trains the longest prefix common to the samples, and strips it from the chunks
+    #[derive(Debug)]
+    struct CommonPrefix;
+
+    impl DictionaryCompressor for CommonPrefix {
+        fn id(&self) -> &str {
+            "prefix"
+        }
+
+        fn train(
+            &self,
+            samples: &[Bytes],
+            max_size_bytes: usize,
+        ) -> Result<Bytes, String> {
+            let first = samples.first().ok_or("no samples")?;
+            let len = samples
+                .iter()
+                .map(|s| first.iter().zip(s.iter()).take_while(|(a, b)| a == b).count())
+                .min()
+                .unwrap_or_default();
+            Ok(first.slice(..len.min(max_size_bytes)))
+        }
+
+        fn compress(&self, dictionary: &[u8], data: Bytes) -> Result<Bytes, String> {
+            let mut res = vec![u8::from(data.starts_with(dictionary))];
+            let rest = if res[0] == 1 { &data[dictionary.len()..] } else { &data[..] };
+            res.extend_from_slice(rest);
+            Ok(res.into())
+        }
+
+        fn decompress(&self, dictionary: &[u8], data: Bytes) -> Result<Bytes, String> {
+            match data.first() {
+                Some(1) => Ok([dictionary, &data[1..]].concat().into()),
+                Some(0) => Ok(data.slice(1..)),
+                _ => Err("bad chunk".to_string()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_train_chunk_dictionary() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> = new_in_memory_storage();
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .with_dictionary_compressor(CommonPrefix)
+            .build();
+        let path: Path = "/array".try_into()?;
+        repo.add_array(path.clone(), array_metadata(vec![4])).await?;
+        let chunk = |i: usize| Bytes::from(format!("station=42;sensor=temp;value={i}"));
+        for i in 0..2 {
+            let payload = repo.get_chunk_writer()(chunk(i)).await?;
+            repo.set_chunk_ref(path.clone(), ChunkIndices(vec![i as u64]), Some(payload))
+                .await?;
+        }
+        repo.commit("main", "plain chunks", None).await?;
+
+        let id = repo.train_chunk_dictionary(&path, "prefix", 10, 1024).await?;
+        let (compressor, dictionary) = parse_dictionary_transformer_id(&id).unwrap();
+        assert_eq!(compressor, "prefix");
+        assert_eq!(
+            fetch_dictionary(storage.as_ref(), dictionary).await?,
+            "station=42;sensor=temp;value="
+        );
+        let writer = repo.get_array_chunk_writer(&path).await?;
+        let ChunkPayload::Ref(stored) = writer(chunk(2)).await? else {
+            panic!("chunk was inlined")
+        };
+        assert_eq!(stored.length, 2);
+        repo.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![2]),
+            Some(ChunkPayload::Ref(stored)),
+        )
+        .await?;
+        repo.commit("main", "compressed chunks", None).await?;
+
+        // a new session loads the dictionary to decode, old chunks are still plain
+        let reader = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_dictionary_compressor(CommonPrefix)
+            .build();
+        for i in 0..3 {
+            let coords = ChunkIndices(vec![i as u64]);
+            let read = reader.get_chunk_reader(&path, &coords, &ByteRange::ALL).await?;
+            assert_eq!(get_chunk(read).await?, Some(chunk(i)));
+        }
+        let plain =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        let coords = ChunkIndices(vec![2]);
+        assert!(matches!(
+            plain.get_chunk_reader(&path, &coords, &ByteRange::ALL).await,
+            Err(RepositoryError::ChunkTransform(ChunkTransformError::UnknownTransformer(unknown)))
+                if unknown == id
+        ));
+
+        // a dictionary no snapshot uses is collected, the committed one is kept
+        let mut unused = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_dictionary_compressor(CommonPrefix)
+            .build();
+        let unused_id = unused.train_chunk_dictionary(&path, "prefix", 10, 1024).await?;
+        let (_, unused_dictionary) = parse_dictionary_transformer_id(&unused_id).unwrap();
+        let later = Utc::now() + TimeDelta::hours(1);
+        for refcounts in [true, false] {
+            let gc = GCConfig::clean_all(later, later, None).with_refcounts(refcounts);
+            garbage_collect(storage.as_ref(), &gc).await?;
+            fetch_dictionary(storage.as_ref(), dictionary).await?;
+        }
+        assert!(fetch_dictionary(storage.as_ref(), unused_dictionary).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use bytes::{Bytes, BytesMut};
+use tokio::sync::Mutex;
+
+use crate::{
+    format::{manifest::ChunkRef, ChunkId},
+    sources::Sources,
+};
+
+#[derive(Debug)]
+struct OpenPack {
+    id: ChunkId,
+    data: BytesMut,
+}
+
+#[derive(Debug, Default)]
+struct PackerState {
+    open: Option<OpenPack>,
+    /// Packs that are complete but haven't been uploaded yet
+    sealed: HashMap<ChunkId, Bytes>,
+}
+
+/// Packs many small chunks into larger chunk objects
+///
+/// Every chunk appended gets a [`ChunkRef`] pointing to a byte range in the current pack.
+/// Once the pack reaches `pack_size_bytes` it's sealed, and a new pack is started. Sealed
+/// packs need to be uploaded by the caller, who then calls [`ChunkPacker::remove`].
+#[derive(Debug)]
+pub struct ChunkPacker {
+    pack_size_bytes: u64,
+    state: Mutex<PackerState>,
+    sources: Sources,
+}
+
+impl ChunkPacker {
+    pub fn new(pack_size_bytes: u64) -> Self {
+        Self {
+            pack_size_bytes,
+            state: Mutex::new(PackerState::default()),
+            sources: Sources::default(),
+        }
+    }
+
+    /// Generate the ids of the packs with `sources`
+    pub fn with_sources(mut self, sources: Sources) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Add a chunk to the current pack
+    ///
+    /// Returns the reference to the chunk, and the id of the pack, if appending the chunk
+    /// sealed it.
+    pub async fn append(&self, data: &Bytes) -> (ChunkRef, Option<ChunkId>) {
+        let mut state = self.state.lock().await;
+        let pack = state.open.get_or_insert_with(|| OpenPack {
+            id: self.sources.new_id(),
+            data: BytesMut::new(),
+        });
+        let chunk_ref = ChunkRef {
+            id: pack.id.clone(),
+            offset: pack.data.len() as u64,
+            length: data.len() as u64,
+        };
+        pack.data.extend_from_slice(data);
+        let sealed = if pack.data.len() as u64 >= self.pack_size_bytes {
+            Self::seal(&mut state)
+        } else {
+            None
+        };
+        (chunk_ref, sealed)
+    }
+
+    /// Seal the current pack, even if it's not full
+    pub async fn seal_open(&self) -> Option<ChunkId> {
+        Self::seal(&mut *self.state.lock().await)
+    }
+
+    /// The ids of the sealed packs still waiting for upload
+    pub async fn sealed_packs(&self) -> Vec<ChunkId> {
+        self.state.lock().await.sealed.keys().cloned().collect()
+    }
+
+    /// The bytes of a pack that hasn't been uploaded, either open or sealed
+    pub async fn get(&self, id: &ChunkId) -> Option<Bytes> {
+        let state = self.state.lock().await;
+        match &state.open {
+            Some(pack) if &pack.id == id => Some(Bytes::copy_from_slice(&pack.data)),
+            _ => state.sealed.get(id).cloned(),
+        }
+    }
+
+    pub async fn is_open(&self, id: &ChunkId) -> bool {
+        self.state.lock().await.open.as_ref().is_some_and(|pack| &pack.id == id)
+    }
+
+    /// Forget a pack, after it's uploaded or when none of its chunks are used
+    ///
+    /// Returns `false` if the pack was not in the packer.
+    pub async fn remove(&self, id: &ChunkId) -> bool {
+        let mut state = self.state.lock().await;
+        if state.open.as_ref().is_some_and(|pack| &pack.id == id) {
+            state.open = None;
+            true
+        } else {
+            state.sealed.remove(id).is_some()
+        }
+    }
+
+    /// Forget every chunk, uploaded or not
+    pub async fn clear(&self) {
+        *self.state.lock().await = PackerState::default();
+    }
+
+    fn seal(state: &mut PackerState) -> Option<ChunkId> {
+        let pack = state.open.take()?;
+        state.sealed.insert(pack.id.clone(), pack.data.freeze());
+        Some(pack.id)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chunks_are_packed() {
+        let packer = ChunkPacker::new(8);
+        let (first, sealed) = packer.append(&Bytes::from_static(b"abc")).await;
+        assert_eq!((first.offset, first.length, sealed), (0, 3, None));
+        let (second, _) = packer.append(&Bytes::from_static(b"de")).await;
+        assert_eq!(
+            (second.id.clone(), second.offset, second.length),
+            (first.id.clone(), 3, 2)
+        );
+        assert_eq!(packer.get(&first.id).await, Some(Bytes::from_static(b"abcde")));
+
+        let (third, sealed) = packer.append(&Bytes::from_static(b"fghi")).await;
+        assert_eq!(third.id, first.id);
+        assert_eq!(sealed, Some(first.id.clone()));
+        assert_eq!(packer.sealed_packs().await, vec![first.id.clone()]);
+
+        // a new pack is started
+        let (fourth, _) = packer.append(&Bytes::from_static(b"j")).await;
+        assert_ne!(fourth.id, first.id);
+        assert_eq!(packer.seal_open().await, Some(fourth.id.clone()));
+        assert_eq!(packer.seal_open().await, None);
+
+        assert!(packer.remove(&first.id).await);
+        assert!(!packer.remove(&first.id).await);
+        assert_eq!(packer.get(&first.id).await, None);
+        assert_eq!(packer.sealed_packs().await, vec![fourth.id]);
+    }
+}
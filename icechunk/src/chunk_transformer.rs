@@ -0,0 +1,458 @@
+//! User defined transformations of the chunk bytes, like encryption or compression
+//!
+//! The [`ChunkTransformer`]s configured with
+//! [`crate::RepositoryBuilder::with_chunk_transformer`] are applied, in order, to every chunk
+//! written by the session, and reversed on read. The manifest records the ids of the
+//! transformers applied to each chunk, so chunks written with a different configuration are
+//! still decoded correctly, or fail with [`ChunkTransformError::UnknownTransformer`] if their
+//! transformers are not configured.
+//!
+//! Transformed chunks are never stored inline, and virtual chunks are not transformed.
+//! Chunks are decoded by [`crate::Repository::get_chunk_reader`], read plans and snapshot
+//! views deliver them as stored. Copies of chunk references, to other arrays, sessions or
+//! repositories, must carry the transformers with them, as a
+//! [`crate::format::manifest::TransformedPayload`], see
+//! [`crate::Repository::get_transformed_chunk_ref`].
+//!
+//! Encoding and decoding run on [`CodecWorkers`], in blocking threads of the runtime of the
+//! session, so heavy compression doesn't stall the tasks fetching and uploading chunks.
+use std::{fmt::Debug, num::NonZeroUsize, sync::Arc, thread};
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::runtime::{spawn_blocking, DynRuntime};
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChunkTransformError {
+    #[error("chunk transformer `{id}` failed: {message}")]
+    Failed { id: String, message: String },
+    #[error("chunk was written with transformer `{0}`, which is not configured")]
+    UnknownTransformer(String),
+    #[error("chunk codec worker stopped before finishing")]
+    WorkerStopped,
+}
+
+pub type ChunkTransformResult<A> = Result<A, ChunkTransformError>;
+
+pub trait ChunkTransformer: Debug + Send + Sync {
+    /// Identifies the encoding in the manifests, it must change if the encoding changes
+    fn id(&self) -> &str;
+
+    /// Returns a description of the problem if `data` cannot be encoded
+    fn encode(&self, data: Bytes) -> Result<Bytes, String>;
+
+    /// The inverse of [`ChunkTransformer::encode`]
+    fn decode(&self, data: Bytes) -> Result<Bytes, String>;
+}
+
+pub type DynChunkTransformer = Arc<dyn ChunkTransformer>;
+
+/// Apply `transformers` to `data`, in order
+pub fn encode_chunk(
+    transformers: &[DynChunkTransformer],
+    data: Bytes,
+) -> ChunkTransformResult<Bytes> {
+    transformers.iter().try_fold(data, |data, transformer| {
+        transformer.encode(data).map_err(|message| ChunkTransformError::Failed {
+            id: transformer.id().to_string(),
+            message,
+        })
+    })
+}
+
+/// Find the transformers with the recorded `ids` between the `configured` ones
+pub fn resolve_transformers(
+    configured: &[DynChunkTransformer],
+    ids: &[String],
+) -> ChunkTransformResult<Vec<DynChunkTransformer>> {
+    ids.iter()
+        .map(|id| {
+            configured
+                .iter()
+                .find(|transformer| transformer.id() == id)
+                .cloned()
+                .ok_or_else(|| ChunkTransformError::UnknownTransformer(id.clone()))
+        })
+        .collect()
+}
+
+/// Undo `transformers`, applied in order to `data`
+pub fn decode_chunk(
+    transformers: &[DynChunkTransformer],
+    data: Bytes,
+) -> ChunkTransformResult<Bytes> {
+    transformers.iter().rev().try_fold(data, |data, transformer| {
+        transformer.decode(data).map_err(|message| ChunkTransformError::Failed {
+            id: transformer.id().to_string(),
+            message,
+        })
+    })
+}
+
+/// Runs chunk transformers in at most `threads` blocking threads at a time
+///
+/// Sessions built with [`crate::RepositoryBuilder::with_codec_threads`] get their own
+/// workers; clones share the thread limit. Chunks without transformers don't use them.
+#[derive(Debug, Clone)]
+pub struct CodecWorkers {
+    runtime: DynRuntime,
+    permits: Arc<Semaphore>,
+    threads: usize,
+}
+
+impl CodecWorkers {
+    /// Workers for `threads` threads, zero means one per CPU
+    pub fn new(runtime: DynRuntime, threads: usize) -> Self {
+        let threads = if threads == 0 {
+            thread::available_parallelism().map_or(1, NonZeroUsize::get)
+        } else {
+            threads
+        };
+        Self { runtime, permits: Arc::new(Semaphore::new(threads)), threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// [`encode_chunk`] in a worker thread
+    pub async fn encode(
+        &self,
+        transformers: &[DynChunkTransformer],
+        data: Bytes,
+    ) -> ChunkTransformResult<Bytes> {
+        if transformers.is_empty() {
+            return Ok(data);
+        }
+        let transformers = transformers.to_vec();
+        self.run(move || encode_chunk(&transformers, data)).await
+    }
+
+    /// [`decode_chunk`] in a worker thread
+    pub async fn decode(
+        &self,
+        transformers: &[DynChunkTransformer],
+        data: Bytes,
+    ) -> ChunkTransformResult<Bytes> {
+        if transformers.is_empty() {
+            return Ok(data);
+        }
+        let transformers = transformers.to_vec();
+        self.run(move || decode_chunk(&transformers, data)).await
+    }
+
+    async fn run(
+        &self,
+        f: impl FnOnce() -> ChunkTransformResult<Bytes> + Send + 'static,
+    ) -> ChunkTransformResult<Bytes> {
+        // the semaphore is never closed
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| ChunkTransformError::WorkerStopped)?;
+        spawn_blocking(self.runtime.as_ref(), f)
+            .await
+            .map_err(|_| ChunkTransformError::WorkerStopped)?
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{
+            manifest::ChunkPayload, snapshot::NodeData, ByteRange, ChunkIndices, Path,
+        },
+        repository::{get_chunk, RepositoryError},
+        ObjectStorage, Repository, Storage,
+    };
+
+    #[derive(Debug)]
+    struct Xor(u8);
+
+    impl ChunkTransformer for Xor {
+        fn id(&self) -> &str {
+            "xor"
+        }
+
+        fn encode(&self, data: Bytes) -> Result<Bytes, String> {
+            Ok(data.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decode(&self, data: Bytes) -> Result<Bytes, String> {
+            self.encode(data)
+        }
+    }
+
+    /// Prefixes the length, so decoding in the wrong order fails
+    #[derive(Debug)]
+    struct Framed;
+
+    impl ChunkTransformer for Framed {
+        fn id(&self) -> &str {
+            "framed"
+        }
+
+        fn encode(&self, data: Bytes) -> Result<Bytes, String> {
+            let mut res = vec![data.len() as u8];
+            res.extend_from_slice(&data);
+            Ok(res.into())
+        }
+
+        fn decode(&self, data: Bytes) -> Result<Bytes, String> {
+            match data.first() {
+                Some(len) if *len as usize == data.len() - 1 => Ok(data.slice(1..)),
+                _ => Err("bad frame".to_string()),
+            }
+        }
+    }
+
+    async fn read(
+        repo: &Repository,
+        path: &Path,
+        coords: &ChunkIndices,
+        range: ByteRange,
+    ) -> Result<Option<Bytes>, RepositoryError> {
+        get_chunk(repo.get_chunk_reader(path, coords, &range).await?).await
+    }
+
+    #[tokio::test]
+    async fn test_chunks_are_transformed() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_chunk_transformer(Framed)
+            .with_chunk_transformer(Xor(42))
+            .build();
+        let path: Path = "/array".try_into()?;
+        repo.add_array(
+            path.clone(),
+            crate::repository::ZarrArrayMetadata::scalar(
+                crate::metadata::DataType::UInt8,
+                crate::metadata::FillValue::UInt8(0),
+            ),
+        )
+        .await?;
+        let coords = ChunkIndices(vec![]);
+
+        let payload = repo.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        let ChunkPayload::Ref(chunk) = payload.clone() else {
+            panic!("chunk was inlined")
+        };
+        repo.set_chunk_ref(path.clone(), coords.clone(), Some(payload)).await?;
+        assert_eq!(
+            read(&repo, &path, &coords, ByteRange::ALL).await?,
+            Some(Bytes::from_static(b"hello"))
+        );
+        repo.commit("main", "transformed", None).await?;
+
+        let stored = storage.fetch_chunk(&chunk.id, &ByteRange::ALL).await?;
+        assert_eq!(stored, Xor(42).encode(Framed.encode(Bytes::from_static(b"hello"))?)?);
+        assert_eq!(
+            read(&repo, &path, &coords, ByteRange::bounded(1, 3)).await?,
+            Some(Bytes::from_static(b"el"))
+        );
+
+        // the manifest records the transformers of the chunk
+        let snapshot = storage.fetch_snapshot(repo.snapshot_id()).await?;
+        let manifest = storage.fetch_manifests(&snapshot.manifest_files[0].id).await?;
+        let node = repo.get_array(&path).await?;
+        assert_eq!(manifest.chunk_transformers(&node.id, &coords), ["framed", "xor"]);
+
+        let plain =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert!(matches!(
+            read(&plain, &path, &coords, ByteRange::ALL).await,
+            Err(RepositoryError::ChunkTransform(ChunkTransformError::UnknownTransformer(id)))
+                if id == "framed"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_array_chunk_transformers() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .with_chunk_transformer(Framed)
+            .with_chunk_transformer(Xor(42))
+            .build();
+        let meta = crate::repository::ZarrArrayMetadata::scalar(
+            crate::metadata::DataType::UInt8,
+            crate::metadata::FillValue::UInt8(0),
+        );
+        let (coords, data, other): (Path, Path, Path) =
+            ("/coords".try_into()?, "/data".try_into()?, "/other".try_into()?);
+        repo.add_array(
+            coords.clone(),
+            crate::repository::ZarrArrayMetadata {
+                chunk_transformers: Some(vec![]),
+                ..meta.clone()
+            },
+        )
+        .await?;
+        repo.add_array(data.clone(), meta.clone()).await?;
+        repo.add_array(other.clone(), meta).await?;
+        repo.set_array_chunk_transformers(&data, Some(vec!["xor".to_string()])).await?;
+        assert!(matches!(
+            repo.set_array_chunk_transformers(&other, Some(vec!["zstd".to_string()])).await,
+            Err(RepositoryError::ChunkTransform(ChunkTransformError::UnknownTransformer(id)))
+                if id == "zstd"
+        ));
+
+        let index = ChunkIndices(vec![]);
+        let mut stored_ids = Vec::new();
+        for path in [&coords, &data, &other] {
+            let writer = repo.get_array_chunk_writer(path).await?;
+            let ChunkPayload::Ref(chunk) = writer(Bytes::from_static(b"7")).await? else {
+                panic!("chunk was inlined")
+            };
+            stored_ids.push(chunk.id.clone());
+            repo.set_chunk_ref(
+                path.clone(),
+                index.clone(),
+                Some(ChunkPayload::Ref(chunk)),
+            )
+            .await?;
+        }
+        repo.commit("main", "per array", None).await?;
+
+        let stored = |i: usize| storage.fetch_chunk(&stored_ids[i], &ByteRange::ALL);
+        assert_eq!(stored(0).await?, Bytes::from_static(b"7"));
+        assert_eq!(stored(1).await?, Xor(42).encode(Bytes::from_static(b"7"))?);
+        assert_eq!(
+            stored(2).await?,
+            Xor(42).encode(Framed.encode(Bytes::from_static(b"7"))?)?
+        );
+        let snapshot = storage.fetch_snapshot(repo.snapshot_id()).await?;
+        let manifest = storage.fetch_manifests(&snapshot.manifest_files[0].id).await?;
+        let data_node = repo.get_array(&data).await?;
+        assert_eq!(manifest.chunk_transformers(&data_node.id, &index), ["xor"]);
+        for path in [&coords, &data, &other] {
+            assert_eq!(
+                read(&repo, path, &index, ByteRange::ALL).await?,
+                Some(Bytes::from_static(b"7"))
+            );
+        }
+        // the setting is recorded in the array metadata
+        let node = repo.get_array(&data).await?;
+        let NodeData::Array(meta, _) = node.node_data else { panic!("not an array") };
+        assert_eq!(meta.chunk_transformers, Some(vec!["xor".to_string()]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copied_chunk_refs_keep_their_transformers() -> Result<(), Box<dyn Error>>
+    {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_chunk_transformer(Framed)
+            .with_chunk_transformer(Xor(42))
+            .build();
+        let meta = crate::repository::ZarrArrayMetadata::scalar(
+            crate::metadata::DataType::UInt8,
+            crate::metadata::FillValue::UInt8(0),
+        );
+        let (src, dst): (Path, Path) = ("/src".try_into()?, "/dst".try_into()?);
+        let coords = ChunkIndices(vec![]);
+        repo.add_array(src.clone(), meta.clone()).await?;
+        let payload = repo.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        repo.set_chunk_ref(src.clone(), coords.clone(), Some(payload)).await?;
+        repo.commit("main", "source", None).await?;
+
+        // `dst` doesn't transform its chunks, the copy keeps the transformers of `src`
+        let mut repo = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_chunk_transformer(Framed)
+            .with_chunk_transformer(Xor(42))
+            .build();
+        repo.add_array(
+            dst.clone(),
+            crate::repository::ZarrArrayMetadata {
+                chunk_transformers: Some(vec![]),
+                ..meta
+            },
+        )
+        .await?;
+        let chunk = repo.get_transformed_chunk_ref(&src, &coords).await?.unwrap();
+        assert_eq!(chunk.transformers, ["framed", "xor"]);
+        repo.set_transformed_chunk_ref(dst.clone(), coords.clone(), Some(chunk)).await?;
+        assert_eq!(
+            read(&repo, &dst, &coords, ByteRange::ALL).await?,
+            Some(Bytes::from_static(b"hello"))
+        );
+        repo.commit("main", "copy", None).await?;
+        assert_eq!(
+            read(&repo, &dst, &coords, ByteRange::ALL).await?,
+            Some(Bytes::from_static(b"hello"))
+        );
+        assert_eq!(
+            repo.get_transformed_chunk_ref(&dst, &coords).await?.unwrap().transformers,
+            ["framed", "xor"]
+        );
+        Ok(())
+    }
+
+    /// Records how many encodings run at the same time, and in which threads
+    #[derive(Debug, Default)]
+    struct Slow {
+        running: std::sync::atomic::AtomicUsize,
+        max_running: std::sync::atomic::AtomicUsize,
+        threads: std::sync::Mutex<Vec<thread::ThreadId>>,
+    }
+
+    impl ChunkTransformer for Arc<Slow> {
+        fn id(&self) -> &str {
+            "slow"
+        }
+
+        fn encode(&self, data: Bytes) -> Result<Bytes, String> {
+            use std::sync::atomic::Ordering;
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            self.threads.lock().unwrap().push(thread::current().id());
+            thread::sleep(std::time::Duration::from_millis(20));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(data)
+        }
+
+        fn decode(&self, data: Bytes) -> Result<Bytes, String> {
+            Ok(data)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_codec_threads() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let slow = Arc::new(Slow::default());
+        let repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_chunk_transformer(Arc::clone(&slow))
+            .with_codec_threads(2)
+            .build();
+        let writes = (0..8u8).map(|i| repo.get_chunk_writer()(Bytes::from(vec![i; 10])));
+        futures::future::try_join_all(writes).await?;
+
+        assert_eq!(slow.max_running.load(std::sync::atomic::Ordering::SeqCst), 2);
+        let threads = slow.threads.lock().unwrap();
+        assert_eq!(threads.len(), 8);
+        // the reactor thread only waits for the workers
+        assert!(!threads.contains(&thread::current().id()));
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+//! Coordination of distributed writers
+//!
+//! Before writing, each worker in a job claims disjoint ranges of chunk coordinates. The
+//! claims are recorded in the refs of the repository, and overlapping claims are rejected.
+//! The coordinator merges the change sets of the workers with
+//! [`crate::Repository::merge_claimed`], which fails if a worker wrote outside its claim, or
+//! if two workers wrote the same chunk, instead of silently keeping the last write.
+use std::ops::Range;
+
+use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{ChunkIndices, Path},
+    refs::RefError,
+    storage::REF_PREFIX,
+    Storage, StorageError,
+};
+
+pub(crate) const CLAIM_REF_PREFIX: &str = "claim.";
+
+#[derive(Debug, Error)]
+pub enum ClaimError {
+    #[error("storage error `{0:?}`")]
+    Storage(#[from] StorageError),
+    #[error("ref error `{0}`")]
+    Ref(#[from] RefError),
+    #[error("cannot serialize claim json: `{0}`")]
+    Serialization(#[from] serde_json::Error),
+    #[error("worker `{worker}` already has a claim in this job")]
+    AlreadyClaimed { worker: String },
+    #[error(
+        "the claim of worker `{worker}` overlaps the claim of `{other}` on `{path}`"
+    )]
+    Overlap { worker: String, other: String, path: Path },
+    #[error("worker `{worker}` has no claim in job `{job}`")]
+    NoClaim { job: String, worker: String },
+    #[error("worker `{worker}` wrote chunk `{coords:?}` of `{path}` outside its claim")]
+    UnclaimedWrite { worker: String, path: Path, coords: ChunkIndices },
+    #[error("chunk `{coords:?}` of `{path}` was written by more than one worker")]
+    OverlappingWrite { path: Path, coords: ChunkIndices },
+}
+
+pub type ClaimResult<A> = Result<A, ClaimError>;
+
+/// A box of chunk coordinates in one array, one half-open range per dimension
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRange {
+    pub path: Path,
+    pub ranges: Vec<Range<u64>>,
+}
+
+impl ChunkRange {
+    pub fn new(path: Path, ranges: Vec<Range<u64>>) -> Self {
+        Self { path, ranges }
+    }
+
+    pub fn contains(&self, path: &Path, coords: &ChunkIndices) -> bool {
+        &self.path == path
+            && coords.0.len() == self.ranges.len()
+            && coords.0.iter().zip(self.ranges.iter()).all(|(c, r)| r.contains(c))
+    }
+
+    /// Ranges of different dimensionality on the same array are considered overlapping
+    pub fn overlaps(&self, other: &ChunkRange) -> bool {
+        self.path == other.path
+            && (self.ranges.len() != other.ranges.len()
+                || self
+                    .ranges
+                    .iter()
+                    .zip(other.ranges.iter())
+                    .all(|(a, b)| a.start < b.end && b.start < a.end))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkClaim {
+    pub job: String,
+    pub worker: String,
+    pub ranges: Vec<ChunkRange>,
+}
+
+impl ChunkClaim {
+    pub fn contains(&self, path: &Path, coords: &ChunkIndices) -> bool {
+        self.ranges.iter().any(|range| range.contains(path, coords))
+    }
+
+    /// The first array where the two claims overlap
+    pub fn overlap(&self, other: &ChunkClaim) -> Option<&Path> {
+        self.ranges.iter().find_map(|range| {
+            other.ranges.iter().any(|o| range.overlaps(o)).then_some(&range.path)
+        })
+    }
+}
+
+fn job_root(job: &str) -> ClaimResult<String> {
+    if job.contains('/') {
+        return Err(RefError::InvalidRefName(job.to_string()).into());
+    }
+    Ok(format!("{}{}", CLAIM_REF_PREFIX, job))
+}
+
+fn claim_key(job: &str, worker: &str) -> ClaimResult<String> {
+    if worker.contains('/') {
+        return Err(RefError::InvalidRefName(worker.to_string()).into());
+    }
+    job_root(job).map(|root| format!("{}/{}.json", root, worker))
+}
+
+/// All the claims in `job`
+pub async fn list_claims(
+    storage: &(dyn Storage + Send + Sync),
+    job: &str,
+) -> ClaimResult<Vec<ChunkClaim>> {
+    let root = job_root(job)?;
+    let names: Vec<String> =
+        storage.ref_versions(root.as_str()).await?.try_collect().await?;
+    let mut res = Vec::with_capacity(names.len());
+    for name in names {
+        let data = storage.get_ref(format!("{}/{}", root, name).as_str()).await?;
+        res.push(serde_json::from_slice(data.as_ref())?);
+    }
+    Ok(res)
+}
+
+/// Record the chunk ranges `worker` will write in `job`
+///
+/// Fails if the ranges overlap the claim of another worker. Claims from different workers
+/// created concurrently are checked again after writing, if they overlap, both may fail.
+pub async fn claim_chunks(
+    storage: &(dyn Storage + Send + Sync),
+    job: &str,
+    worker: &str,
+    ranges: Vec<ChunkRange>,
+) -> ClaimResult<ChunkClaim> {
+    let claim = ChunkClaim { job: job.to_string(), worker: worker.to_string(), ranges };
+    check_overlaps(&claim, &list_claims(storage, job).await?)?;
+
+    let key = claim_key(job, worker)?;
+    let content = serde_json::to_vec(&claim)?;
+    storage.write_ref(key.as_str(), false, Bytes::from(content)).await.map_err(
+        |err| match err {
+            StorageError::RefAlreadyExists(_) => {
+                ClaimError::AlreadyClaimed { worker: worker.to_string() }
+            }
+            err => err.into(),
+        },
+    )?;
+
+    if let Err(err) = check_overlaps(&claim, &list_claims(storage, job).await?) {
+        storage.delete_objects(REF_PREFIX, stream::iter([key]).boxed()).await?;
+        return Err(err);
+    }
+    Ok(claim)
+}
+
+fn check_overlaps(claim: &ChunkClaim, existing: &[ChunkClaim]) -> ClaimResult<()> {
+    for other in existing.iter().filter(|other| other.worker != claim.worker) {
+        if let Some(path) = claim.overlap(other) {
+            return Err(ClaimError::Overlap {
+                worker: claim.worker.clone(),
+                other: other.worker.clone(),
+                path: path.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Delete all the claims in `job`, once its writes are committed
+pub async fn release_claims(
+    storage: &(dyn Storage + Send + Sync),
+    job: &str,
+) -> ClaimResult<usize> {
+    let keys: Vec<String> = list_claims(storage, job)
+        .await?
+        .iter()
+        .map(|claim| claim_key(job, &claim.worker))
+        .collect::<ClaimResult<_>>()?;
+    Ok(storage.delete_objects(REF_PREFIX, stream::iter(keys).boxed()).await?)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        change_set::ChangeSet,
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        refs::list_refs,
+        repository::{ChunkPayload, RepositoryError, ZarrArrayMetadata},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_claimed_writes() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let path: Path = "/array".try_into()?;
+        let mut coordinator =
+            Repository::init(Arc::clone(&storage), false).await?.build();
+        coordinator.add_group(Path::root()).await?;
+        coordinator
+            .add_array(
+                path.clone(),
+                ZarrArrayMetadata {
+                    shape: vec![4],
+                    data_type: DataType::UInt8,
+                    chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                    chunk_key_encoding: ChunkKeyEncoding::Slash,
+                    fill_value: FillValue::UInt8(0),
+                    codecs: vec![],
+                    storage_transformers: None,
+                    dimension_names: None,
+                    chunk_transformers: None,
+                    grid_origin: None,
+                },
+            )
+            .await?;
+        let base = coordinator.commit("main", "create", None).await?;
+
+        let range = |r: Range<u64>| vec![ChunkRange::new(path.clone(), vec![r])];
+        claim_chunks(storage.as_ref(), "job", "w1", range(0..2)).await?;
+        claim_chunks(storage.as_ref(), "job", "w2", range(2..4)).await?;
+        assert!(matches!(
+            claim_chunks(storage.as_ref(), "job", "w3", range(1..3)).await,
+            Err(ClaimError::Overlap { worker, other, .. }) if worker == "w3" && other == "w1"
+        ));
+        assert!(matches!(
+            claim_chunks(storage.as_ref(), "job", "w1", range(0..1)).await,
+            Err(ClaimError::AlreadyClaimed { worker }) if worker == "w1"
+        ));
+        assert_eq!(list_claims(storage.as_ref(), "job").await?.len(), 2);
+        // claims are not branches or tags
+        assert_eq!(list_refs(storage.as_ref()).await?.len(), 1);
+
+        let write = |coords: Vec<u64>| {
+            let storage = Arc::clone(&storage);
+            let base = base.clone();
+            let path = path.clone();
+            async move {
+                let mut ds = Repository::update(storage, base).build();
+                for c in coords {
+                    ds.set_chunk_ref(
+                        path.clone(),
+                        ChunkIndices(vec![c]),
+                        Some(ChunkPayload::Inline(vec![c as u8].into())),
+                    )
+                    .await?;
+                }
+                Ok::<_, RepositoryError>(ChangeSet::from(ds))
+            }
+        };
+
+        coordinator.merge_claimed("job", "w1", write(vec![0, 1]).await?).await?;
+        assert!(matches!(
+            coordinator.merge_claimed("job", "w2", write(vec![1, 2]).await?).await,
+            Err(RepositoryError::Claim(ClaimError::UnclaimedWrite { worker, coords, .. }))
+                if worker == "w2" && coords == ChunkIndices(vec![1])
+        ));
+        assert!(matches!(
+            coordinator.merge_claimed("job", "w1", write(vec![1]).await?).await,
+            Err(RepositoryError::Claim(ClaimError::OverlappingWrite { coords, .. }))
+                if coords == ChunkIndices(vec![1])
+        ));
+        assert!(matches!(
+            coordinator.merge_claimed("job", "w3", write(vec![3]).await?).await,
+            Err(RepositoryError::Claim(ClaimError::NoClaim { .. }))
+        ));
+        coordinator.merge_claimed("job", "w2", write(vec![2, 3]).await?).await?;
+        coordinator.commit("main", "job", None).await?;
+        for c in 0..4 {
+            assert_eq!(
+                coordinator.get_chunk_ref(&path, &ChunkIndices(vec![c])).await?,
+                Some(ChunkPayload::Inline(vec![c as u8].into()))
+            );
+        }
+
+        assert_eq!(release_claims(storage.as_ref(), "job").await?, 2);
+        assert_eq!(list_claims(storage.as_ref(), "job").await?, vec![]);
+        Ok(())
+    }
+}
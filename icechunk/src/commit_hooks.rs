@@ -0,0 +1,292 @@
+//! Work that runs after a commit moves its branch, like refreshing derived metadata
+//!
+//! Hooks run at least once per commit. Before the branch is updated, the commit records
+//! the hooks it must run in a namespace of the refs, and each hook is removed from the
+//! record once it succeeds. Hooks that fail, or didn't run because the process died, stay
+//! pending until [`run_pending_hooks`] runs them again. Hooks must tolerate running more
+//! than once for the same commit.
+use std::{error::Error, fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::SnapshotId,
+    refs::{fetch_branch_tip, RefError, RefResult},
+    storage::REF_PREFIX,
+    Storage, StorageError,
+};
+
+pub(crate) const HOOK_REF_PREFIX: &str = "hook.";
+const HOOK_KEY_NAME: &str = "ref.json";
+
+#[async_trait]
+pub trait CommitHook: Debug + Send + Sync {
+    /// Identifies the hook in the pending records, it must be the same across processes
+    fn name(&self) -> &str;
+
+    async fn on_commit(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        commit: &PendingHooks,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+pub type DynCommitHook = Arc<dyn CommitHook>;
+
+/// The hooks a commit still has to run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingHooks {
+    pub branch: String,
+    pub snapshot: SnapshotId,
+    pub parent: Option<SnapshotId>,
+    pub hooks: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookFailure {
+    pub hook: String,
+    pub snapshot: SnapshotId,
+    pub message: String,
+}
+
+fn hook_key(snapshot: &SnapshotId) -> String {
+    format!("{}{}/{}", HOOK_REF_PREFIX, snapshot, HOOK_KEY_NAME)
+}
+
+async fn write_pending_hooks(
+    storage: &(dyn Storage + Send + Sync),
+    pending: &PendingHooks,
+) -> RefResult<()> {
+    let content = serde_json::to_vec(pending)?;
+    storage
+        .write_ref(hook_key(&pending.snapshot).as_str(), true, Bytes::from(content))
+        .await?;
+    Ok(())
+}
+
+/// Record the `hooks` of a commit of `snapshot`, before the branch is updated
+pub(crate) async fn record_pending_hooks(
+    storage: &(dyn Storage + Send + Sync),
+    hooks: &[DynCommitHook],
+    branch: &str,
+    snapshot: &SnapshotId,
+    parent: Option<&SnapshotId>,
+    now: DateTime<Utc>,
+) -> RefResult<Option<PendingHooks>> {
+    if hooks.is_empty() {
+        return Ok(None);
+    }
+    let pending = PendingHooks {
+        branch: branch.to_string(),
+        snapshot: snapshot.clone(),
+        parent: parent.cloned(),
+        hooks: hooks.iter().map(|hook| hook.name().to_string()).collect(),
+        created_at: now,
+    };
+    write_pending_hooks(storage, &pending).await?;
+    Ok(Some(pending))
+}
+
+/// Drop the record of `snapshot`, its commit didn't update the branch
+pub(crate) async fn discard_pending_hooks(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &SnapshotId,
+) -> RefResult<()> {
+    storage
+        .delete_objects(REF_PREFIX, stream::iter([hook_key(snapshot)]).boxed())
+        .await?;
+    Ok(())
+}
+
+/// Run the pending hooks of a committed snapshot, in order, and record the ones that failed
+///
+/// Hooks missing from `hooks` stay pending, another process may know them.
+pub async fn run_hooks(
+    storage: &(dyn Storage + Send + Sync),
+    hooks: &[DynCommitHook],
+    mut pending: PendingHooks,
+) -> RefResult<Vec<HookFailure>> {
+    let mut failures = Vec::new();
+    let mut remaining = Vec::new();
+    for name in pending.hooks.iter() {
+        let Some(hook) = hooks.iter().find(|hook| hook.name() == name) else {
+            remaining.push(name.clone());
+            continue;
+        };
+        if let Err(err) = hook.on_commit(storage, &pending).await {
+            failures.push(HookFailure {
+                hook: name.clone(),
+                snapshot: pending.snapshot.clone(),
+                message: err.to_string(),
+            });
+            remaining.push(name.clone());
+        }
+    }
+    if remaining.is_empty() {
+        discard_pending_hooks(storage, &pending.snapshot).await?;
+    } else if remaining != pending.hooks {
+        pending.hooks = remaining;
+        write_pending_hooks(storage, &pending).await?;
+    }
+    Ok(failures)
+}
+
+pub async fn list_pending_hooks(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Vec<PendingHooks>> {
+    let mut res = Vec::new();
+    for name in storage.ref_names().await? {
+        if name.starts_with(HOOK_REF_PREFIX) {
+            let key = format!("{}/{}", name, HOOK_KEY_NAME);
+            match storage.get_ref(key.as_str()).await {
+                Ok(data) => res.push(serde_json::from_slice(data.as_ref())?),
+                // a concurrent run finished it
+                Err(StorageError::RefNotFound(..)) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Run the hooks left pending by failed or interrupted runs
+///
+/// Records of snapshots that are not in the history of their branch are from commits that
+/// didn't update the branch, they are dropped once older than `grace`, younger ones may
+/// be of a commit in progress.
+pub async fn run_pending_hooks(
+    storage: &(dyn Storage + Send + Sync),
+    hooks: &[DynCommitHook],
+    now: DateTime<Utc>,
+    grace: TimeDelta,
+) -> RefResult<Vec<HookFailure>> {
+    let mut failures = Vec::new();
+    for pending in list_pending_hooks(storage).await? {
+        if is_committed(storage, &pending).await? {
+            failures.extend(run_hooks(storage, hooks, pending).await?);
+        } else if now - pending.created_at > grace {
+            discard_pending_hooks(storage, &pending.snapshot).await?;
+        }
+    }
+    Ok(failures)
+}
+
+async fn is_committed(
+    storage: &(dyn Storage + Send + Sync),
+    pending: &PendingHooks,
+) -> RefResult<bool> {
+    let tip = match fetch_branch_tip(storage, &pending.branch).await {
+        Ok(tip) => tip.snapshot,
+        Err(RefError::RefNotFound(_)) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if tip == pending.snapshot {
+        return Ok(true);
+    }
+    Ok(storage
+        .fetch_snapshot(&tip)
+        .await?
+        .local_ancestry()
+        .any(|ancestor| ancestor.id == pending.snapshot))
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::sync::{Mutex, PoisonError};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        refs::{list_refs, Ref},
+        test_utils::new_in_memory_storage,
+        Repository,
+    };
+
+    #[derive(Debug, Default)]
+    struct Summary {
+        runs: Mutex<Vec<SnapshotId>>,
+        failures: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl CommitHook for Summary {
+        fn name(&self) -> &str {
+            "summary"
+        }
+
+        async fn on_commit(
+            &self,
+            _storage: &(dyn Storage + Send + Sync),
+            commit: &PendingHooks,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.runs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(commit.snapshot.clone());
+            let mut failures =
+                self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+            if *failures > 0 {
+                *failures -= 1;
+                return Err("summary store unavailable".into());
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_commit_hooks() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let summary = Arc::new(Summary::default());
+        let hooks: Vec<DynCommitHook> = vec![summary.clone()];
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_commit_hook(summary.clone())
+            .build();
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit("main", "first", None).await?;
+        assert_eq!(*summary.runs.lock().unwrap(), vec![first.clone()]);
+        assert_eq!(list_pending_hooks(storage.as_ref()).await?, vec![]);
+
+        // a failed hook doesn't fail the commit, it stays pending until it succeeds
+        *summary.failures.lock().unwrap() = 1;
+        ds.add_group("/a".try_into()?).await?;
+        let second = ds.commit("main", "second", None).await?;
+        let pending = list_pending_hooks(storage.as_ref()).await?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].snapshot, second);
+        assert_eq!(pending[0].hooks, vec!["summary".to_string()]);
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string())]
+        );
+        let now = Utc::now();
+        let failures =
+            run_pending_hooks(storage.as_ref(), &hooks, now, TimeDelta::hours(1)).await?;
+        assert_eq!(failures, vec![]);
+        assert_eq!(
+            *summary.runs.lock().unwrap(),
+            vec![first.clone(), second.clone(), second]
+        );
+        assert_eq!(list_pending_hooks(storage.as_ref()).await?, vec![]);
+
+        // the record of a commit that never updated its branch is dropped after the grace
+        let lost = SnapshotId::random();
+        record_pending_hooks(storage.as_ref(), &hooks, "main", &lost, Some(&first), now)
+            .await?;
+        run_pending_hooks(storage.as_ref(), &hooks, now, TimeDelta::hours(1)).await?;
+        assert_eq!(list_pending_hooks(storage.as_ref()).await?.len(), 1);
+        let later = now + TimeDelta::hours(2);
+        run_pending_hooks(storage.as_ref(), &hooks, later, TimeDelta::hours(1)).await?;
+        assert_eq!(list_pending_hooks(storage.as_ref()).await?, vec![]);
+        assert_eq!(summary.runs.lock().unwrap().len(), 3);
+        Ok(())
+    }
+}
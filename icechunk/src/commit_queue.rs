@@ -0,0 +1,293 @@
+//! Fair ordering of the commits to a branch
+//!
+//! Committers racing on the branch ref with compare-and-swap retries favor the fast ones: a
+//! session with a large change set can keep losing to small commits. With
+//! [`crate::Repository::commit_queued`], committers first take a ticket in the queue of the
+//! branch, a namespace of the refs, and then commit in ticket order. Tickets are leases,
+//! renewed while their holder waits and commits, so a crashed committer only blocks the
+//! queue until its ticket expires.
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    refs::RefResult,
+    runtime::{default_runtime, DynRuntime},
+    storage::REF_PREFIX,
+    Storage, StorageError,
+};
+
+pub(crate) const QUEUE_REF_PREFIX: &str = "queue.";
+
+#[derive(Debug, Clone)]
+pub struct CommitQueueConfig {
+    /// Delay before checking the queue again, it doubles on each check up to
+    /// `max_poll_interval`
+    pub poll_interval: Duration,
+    pub max_poll_interval: Duration,
+    /// How long a ticket lives without being renewed
+    pub lease: TimeDelta,
+    /// Give up waiting for the turn after this long
+    pub timeout: Option<TimeDelta>,
+    /// Where to wait between checks
+    pub runtime: DynRuntime,
+}
+
+impl Default for CommitQueueConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(50),
+            max_poll_interval: Duration::from_secs(2),
+            lease: TimeDelta::seconds(30),
+            timeout: None,
+            runtime: default_runtime(),
+        }
+    }
+}
+
+impl CommitQueueConfig {
+    pub fn with_poll_interval(mut self, initial: Duration, max: Duration) -> Self {
+        self.poll_interval = initial;
+        self.max_poll_interval = max.max(initial);
+        self
+    }
+
+    pub fn with_lease(mut self, lease: TimeDelta) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: TimeDelta) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+}
+
+/// A place in the commit queue of a branch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueTicket {
+    pub id: String,
+    pub branch: String,
+    pub enqueued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl QueueTicket {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn queue_name(branch: &str) -> String {
+    format!("{}{}", QUEUE_REF_PREFIX, branch)
+}
+
+fn ticket_key(branch: &str, id: &str) -> String {
+    format!("{}/{}.json", queue_name(branch), id)
+}
+
+async fn write_ticket(
+    storage: &(dyn Storage + Send + Sync),
+    ticket: &QueueTicket,
+    overwrite: bool,
+) -> RefResult<()> {
+    let content = serde_json::to_vec(ticket)?;
+    storage
+        .write_ref(
+            ticket_key(&ticket.branch, &ticket.id).as_str(),
+            overwrite,
+            Bytes::from(content),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Take a ticket at the end of the queue of `branch`, valid until `now + lease`
+pub async fn enqueue(
+    storage: &(dyn Storage + Send + Sync),
+    branch: &str,
+    now: DateTime<Utc>,
+    lease: TimeDelta,
+) -> RefResult<QueueTicket> {
+    // ids sort by arrival time
+    let ticket = QueueTicket {
+        id: format!(
+            "{}-{}",
+            now.format("%Y%m%dT%H%M%S%6f"),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        ),
+        branch: branch.to_string(),
+        enqueued_at: now,
+        expires_at: now + lease,
+    };
+    write_ticket(storage, &ticket, false).await?;
+    Ok(ticket)
+}
+
+/// The tickets in the queue of `branch`, first in line first, including the expired ones
+pub async fn list_queue(
+    storage: &(dyn Storage + Send + Sync),
+    branch: &str,
+) -> RefResult<Vec<QueueTicket>> {
+    let name = queue_name(branch);
+    let versions: Vec<String> =
+        storage.ref_versions(name.as_str()).await?.try_collect().await?;
+    let mut res = Vec::with_capacity(versions.len());
+    for version in versions {
+        match storage.get_ref(format!("{}/{}", name, version).as_str()).await {
+            Ok(data) => res.push(serde_json::from_slice::<QueueTicket>(data.as_ref())?),
+            // the holder left the queue after we listed it
+            Err(StorageError::RefNotFound(..)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    res.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(res)
+}
+
+/// Extend the ticket to `now + lease`
+///
+/// A ticket that expired and was purged is written again, keeping its place in the queue.
+pub async fn renew_ticket(
+    storage: &(dyn Storage + Send + Sync),
+    ticket: &mut QueueTicket,
+    now: DateTime<Utc>,
+    lease: TimeDelta,
+) -> RefResult<()> {
+    ticket.expires_at = now + lease;
+    write_ticket(storage, ticket, true).await
+}
+
+/// Whether `ticket` is the first ticket of its queue that hasn't expired at `now`
+pub async fn has_turn(
+    storage: &(dyn Storage + Send + Sync),
+    ticket: &QueueTicket,
+    now: DateTime<Utc>,
+) -> RefResult<bool> {
+    Ok(list_queue(storage, &ticket.branch)
+        .await?
+        .iter()
+        .find(|other| !other.is_expired(now))
+        .is_none_or(|first| first.id == ticket.id))
+}
+
+pub async fn leave_queue(
+    storage: &(dyn Storage + Send + Sync),
+    ticket: &QueueTicket,
+) -> RefResult<()> {
+    storage
+        .delete_objects(
+            REF_PREFIX,
+            stream::iter([ticket_key(&ticket.branch, &ticket.id)]).boxed(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Delete the tickets of the queue of `branch` expired at `now`, returning how many were
+/// deleted
+pub async fn purge_queue(
+    storage: &(dyn Storage + Send + Sync),
+    branch: &str,
+    now: DateTime<Utc>,
+) -> RefResult<usize> {
+    let expired: Vec<String> = list_queue(storage, branch)
+        .await?
+        .into_iter()
+        .filter(|ticket| ticket.is_expired(now))
+        .map(|ticket| ticket_key(&ticket.branch, &ticket.id))
+        .collect();
+    Ok(storage.delete_objects(REF_PREFIX, stream::iter(expired).boxed()).await?)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        conflicts::basic_solver::BasicConflictSolver,
+        format::Path,
+        refs::{fetch_branch_tip, list_refs, Ref},
+        repository::RepositoryError,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_commits_wait_their_turn() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let root = ds.commit("main", "root", None).await?;
+        let mut slow = Repository::update(Arc::clone(&storage), root.clone()).build();
+        slow.add_group("/slow".try_into()?).await?;
+        ds.add_group("/fast".try_into()?).await?;
+
+        let solver = BasicConflictSolver::default();
+        let config = CommitQueueConfig::default()
+            .with_poll_interval(Duration::from_millis(1), Duration::from_millis(5));
+        let ahead =
+            enqueue(storage.as_ref(), "main", Utc::now(), TimeDelta::hours(1)).await?;
+        let (queued, _) = tokio::join!(
+            slow.commit_queued("main", "slow", None, &solver, &config),
+            async {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                assert_eq!(
+                    fetch_branch_tip(storage.as_ref(), "main").await?.snapshot,
+                    root
+                );
+                // the fast commit goes first, so the queued one is rebased
+                ds.commit("main", "fast", None).await?;
+                leave_queue(storage.as_ref(), &ahead).await?;
+                Ok::<_, Box<dyn Error>>(())
+            }
+        );
+        let queued = queued?;
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, queued);
+        assert_eq!(slow.ancestry().await?.count().await, 4);
+        assert_eq!(list_queue(storage.as_ref(), "main").await?, vec![]);
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string())]
+        );
+
+        // expired tickets don't hold the queue, and can be purged
+        let now = Utc::now();
+        let crashed = enqueue(storage.as_ref(), "main", now, TimeDelta::zero()).await?;
+        slow.add_group("/after-crash".try_into()?).await?;
+        slow.commit_queued("main", "after crash", None, &solver, &config).await?;
+        assert_eq!(list_queue(storage.as_ref(), "main").await?, vec![crashed.clone()]);
+        assert_eq!(purge_queue(storage.as_ref(), "main", now).await?, 1);
+
+        let ahead =
+            enqueue(storage.as_ref(), "main", Utc::now(), TimeDelta::hours(1)).await?;
+        slow.add_group("/timeout".try_into()?).await?;
+        let res = slow
+            .commit_queued(
+                "main",
+                "timeout",
+                None,
+                &solver,
+                &config.with_timeout(TimeDelta::milliseconds(20)),
+            )
+            .await;
+        assert!(
+            matches!(res, Err(RepositoryError::CommitQueueTimeout(branch)) if branch == "main")
+        );
+        assert_eq!(list_queue(storage.as_ref(), "main").await?, vec![ahead]);
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//! Declarative configuration, loaded from files and environment variables
+//!
+//! Configuration files are JSON documents with the serialized form of the config type.
+//! Fields the type doesn't know are rejected, so typos don't go unnoticed.
+//!
+//! Environment variables are named after the path of the field they set, upper cased,
+//! with `__` between levels, after a prefix: with the prefix `ICECHUNK_`,
+//! `ICECHUNK_STORAGE__BUCKET=data` sets `storage.bucket`. Values are parsed as JSON when
+//! possible and used as strings otherwise, so strings that look like numbers or booleans
+//! must be quoted: `ICECHUNK_STORAGE__PREFIX='"2024"'`.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+/// The prefix of the environment variables read by the `from_env` constructors
+pub const ENV_PREFIX: &str = "ICECHUNK_";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("cannot read config file `{path}`: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+
+    #[error("unsupported format for config file `{0}`, use a `.json` file")]
+    UnsupportedFormat(PathBuf),
+
+    #[error("invalid config in {origin}: {message}")]
+    Invalid { origin: String, message: String },
+
+    #[error("unknown config field `{field}` in {origin}")]
+    UnknownField { origin: String, field: String },
+}
+
+pub type ConfigResult<A> = Result<A, ConfigError>;
+
+/// Load a config from a JSON file
+pub fn from_file<T: Serialize + DeserializeOwned>(path: &Path) -> ConfigResult<T> {
+    if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        return Err(ConfigError::UnsupportedFormat(path.to_path_buf()));
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|source| ConfigError::Io { path: path.to_path_buf(), source })?;
+    let origin = format!("`{}`", path.display());
+    let invalid = |err: serde_json::Error| ConfigError::Invalid {
+        origin: origin.clone(),
+        message: err.to_string(),
+    };
+    // parsing the file directly, instead of its json value, reports lines and columns
+    let config = serde_json::from_str(&contents).map_err(invalid)?;
+    let value = serde_json::from_str(&contents).map_err(invalid)?;
+    check_known_fields(&origin, &config, &value)?;
+    Ok(config)
+}
+
+/// Load a config from the environment variables that start with `prefix`
+pub fn from_env<T: Serialize + DeserializeOwned>(prefix: &str) -> ConfigResult<T> {
+    from_env_vars(prefix, std::env::vars())
+}
+
+/// Load a config from the variables in `vars` that start with `prefix`, see [`from_env`]
+pub fn from_env_vars<T: Serialize + DeserializeOwned>(
+    prefix: &str,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> ConfigResult<T> {
+    let origin = format!("`{prefix}*` environment variables");
+    let mut root = Map::new();
+    for (name, value) in vars {
+        let Some(field) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        let path: Vec<String> = field.split("__").map(str::to_lowercase).collect();
+        let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+        insert(&mut root, &path, value).map_err(|message| ConfigError::Invalid {
+            origin: origin.clone(),
+            message: format!("`{name}` {message}"),
+        })?;
+    }
+    let value = Value::Object(root);
+    let config = serde_json::from_value(value.clone()).map_err(|err| {
+        ConfigError::Invalid { origin: origin.clone(), message: err.to_string() }
+    })?;
+    check_known_fields(&origin, &config, &value)?;
+    Ok(config)
+}
+
+fn insert(
+    map: &mut Map<String, Value>,
+    path: &[String],
+    value: Value,
+) -> Result<(), String> {
+    match path {
+        [] => Err("doesn't name a field".to_string()),
+        [key] => match map.insert(key.clone(), value) {
+            Some(Value::Object(_)) => {
+                Err("conflicts with the variables of its fields".to_string())
+            }
+            _ => Ok(()),
+        },
+        [key, rest @ ..] => {
+            match map.entry(key.clone()).or_insert_with(|| Value::Object(Map::new())) {
+                Value::Object(fields) => insert(fields, rest, value),
+                _ => Err(format!("sets a field of `{key}`, which already has a value")),
+            }
+        }
+    }
+}
+
+/// Fail for the first field set in `input` that doesn't survive a round trip through `config`
+fn check_known_fields<T: Serialize>(
+    origin: &str,
+    config: &T,
+    input: &Value,
+) -> ConfigResult<()> {
+    let parsed = serde_json::to_value(config).map_err(|err| ConfigError::Invalid {
+        origin: origin.to_string(),
+        message: err.to_string(),
+    })?;
+    match unknown_field(input, &parsed) {
+        Some(field) => {
+            Err(ConfigError::UnknownField { origin: origin.to_string(), field })
+        }
+        None => Ok(()),
+    }
+}
+
+fn unknown_field(input: &Value, parsed: &Value) -> Option<String> {
+    let (Value::Object(input), Value::Object(parsed)) = (input, parsed) else {
+        return None;
+    };
+    input.iter().filter(|(_, value)| !value.is_null()).find_map(|(key, value)| {
+        match parsed.get(key) {
+            None => Some(key.clone()),
+            Some(parsed) => {
+                unknown_field(value, parsed).map(|field| format!("{key}.{field}"))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    use crate::{
+        storage::CacheConfig,
+        zarr::{ConsolidatedStore, RepositoryConfig, StorageConfig, StoreOptions},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_load_config() -> Result<(), Box<dyn std::error::Error>> {
+        let expected = ConsolidatedStore {
+            storage: StorageConfig::InMemory { prefix: Some("2024".to_string()) },
+            repository: RepositoryConfig::new().with_inline_chunk_threshold_bytes(64),
+            config: Some(StoreOptions {
+                get_partial_values_concurrency: 10,
+                cache: CacheConfig { num_chunks: 100, ..CacheConfig::default() },
+            }),
+        };
+
+        let dir = tempdir()?;
+        let path = dir.path().join("icechunk.json");
+        fs::write(
+            &path,
+            r#"{"storage": {"type": "in_memory", "prefix": "2024"},
+                "repository": {"inline_chunk_threshold_bytes": 64},
+                "config": {"cache": {"num_chunks": 100}}}"#,
+        )?;
+        assert_eq!(ConsolidatedStore::from_file(&path)?, expected);
+
+        fs::write(
+            &path,
+            r#"{"storage": {"type": "in_memory"},
+                "repository": {"inline_chunk_threshold": 64}}"#,
+        )?;
+        assert!(matches!(
+            ConsolidatedStore::from_file(&path),
+            Err(ConfigError::UnknownField { field, .. })
+                if field == "repository.inline_chunk_threshold"
+        ));
+        fs::write(&path, r#"{"storage": {"type": "in_memory"}, "repository": {"#)?;
+        assert!(matches!(
+            ConsolidatedStore::from_file(&path),
+            Err(ConfigError::Invalid { message, .. }) if message.contains("line 1")
+        ));
+        assert!(matches!(
+            ConsolidatedStore::from_file(dir.path().join("icechunk.toml")),
+            Err(ConfigError::UnsupportedFormat(_))
+        ));
+
+        let vars = [
+            ("ICECHUNK_STORAGE__TYPE", "in_memory"),
+            ("ICECHUNK_STORAGE__PREFIX", r#""2024""#),
+            ("ICECHUNK_REPOSITORY__INLINE_CHUNK_THRESHOLD_BYTES", "64"),
+            ("ICECHUNK_CONFIG__CACHE__NUM_CHUNKS", "100"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let loaded: ConsolidatedStore = from_env_vars(ENV_PREFIX, vars.clone())?;
+        assert_eq!(loaded, expected);
+        let repository: RepositoryConfig =
+            from_env_vars("ICECHUNK_REPOSITORY__", vars.clone())?;
+        assert_eq!(repository, expected.repository);
+
+        let typo = vars.into_iter().chain([(
+            "ICECHUNK_CONFIG__CACHE__NUM_CHUNK".to_string(),
+            "100".to_string(),
+        )]);
+        assert!(matches!(
+            from_env_vars::<ConsolidatedStore>(ENV_PREFIX, typo),
+            Err(ConfigError::UnknownField { field, .. })
+                if field == "config.cache.num_chunk"
+        ));
+        Ok(())
+    }
+}
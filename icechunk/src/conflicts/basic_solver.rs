@@ -81,7 +81,10 @@ impl BasicConflictSolver {
                     ZarrMetadataUpdateOfDeletedArray(_) |
                     UserAttributesUpdateOfDeletedNode(_) |
                     ChunksUpdatedInDeletedArray{..} |
-                    ChunksUpdatedInUpdatedArray{..}
+                    ChunksUpdatedInUpdatedArray{..} |
+                    CustomNodeDoubleUpdate(_) |
+                    CustomNodeUpdateOfDeletedNode(_) |
+                    DeleteOfUpdatedCustomNode(_)
                 ) ||
                 matches!(conflict,
                     UserAttributesDoubleUpdate{..} if self.on_user_attributes_conflict == VersionSelection::Fail
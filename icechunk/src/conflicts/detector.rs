@@ -98,6 +98,7 @@ impl ConflictSolver for ConflictDetector {
             .filter(|node_id| {
                 previous_change.deleted_arrays.contains(node_id)
                     || previous_change.deleted_groups.contains(node_id)
+                    || previous_change.deleted_custom_nodes.contains(node_id)
             })
             .map(Ok);
 
@@ -107,6 +108,28 @@ impl ConflictSolver for ConflictDetector {
                 Ok(Conflict::UserAttributesUpdateOfDeletedNode(path))
             });
 
+        let custom_nodes_already_updated = current_changes
+            .custom_updated_nodes()
+            .filter(|node_id| previous_change.updated_custom_nodes.contains(node_id))
+            .map(Ok);
+
+        let custom_nodes_already_updated = stream::iter(custom_nodes_already_updated)
+            .and_then(|node_id| async {
+                let path = path_finder.find(node_id)?;
+                Ok(Conflict::CustomNodeDoubleUpdate(path))
+            });
+
+        let updated_custom_nodes_were_deleted = current_changes
+            .custom_updated_nodes()
+            .filter(|node_id| previous_change.deleted_custom_nodes.contains(node_id))
+            .map(Ok);
+
+        let updated_custom_nodes_were_deleted =
+            stream::iter(updated_custom_nodes_were_deleted).and_then(|node_id| async {
+                let path = path_finder.find(node_id)?;
+                Ok(Conflict::CustomNodeUpdateOfDeletedNode(path))
+            });
+
         let chunks_updated_in_deleted_array = current_changes
             .arrays_with_chunk_changes()
             .filter(|node_id| previous_change.deleted_arrays.contains(node_id))
@@ -211,17 +234,41 @@ impl ConflictSolver for ConflictDetector {
             }
         });
 
+        let deletes_of_updated_custom_nodes = stream::iter(
+            current_changes.deleted_custom_nodes().map(Ok),
+        )
+        .try_filter_map(|path| async {
+            let id = match previous_repo.get_node(path).await {
+                Ok(node) => Some(node.id),
+                Err(RepositoryError::NodeNotFound { .. }) => None,
+                Err(err) => Err(err)?,
+            };
+
+            match id {
+                Some(node_id)
+                    if previous_change.updated_custom_nodes.contains(&node_id)
+                        || previous_change.updated_user_attributes.contains(&node_id) =>
+                {
+                    Ok(Some(Conflict::DeleteOfUpdatedCustomNode(path.clone())))
+                }
+                _ => Ok(None),
+            }
+        });
+
         let all_conflicts: Vec<_> = new_nodes_explicit_conflicts
             .chain(new_nodes_implicit_conflicts)
             .chain(updated_arrays_already_updated)
             .chain(updated_arrays_were_deleted)
             .chain(updated_attributes_already_updated)
             .chain(updated_attributes_on_deleted_node)
+            .chain(custom_nodes_already_updated)
+            .chain(updated_custom_nodes_were_deleted)
             .chain(chunks_updated_in_deleted_array)
             .chain(chunks_updated_in_updated_array)
             .chain(chunks_double_updated)
             .chain(deletes_of_updated_arrays)
             .chain(deletes_of_updated_groups)
+            .chain(deletes_of_updated_custom_nodes)
             .try_collect()
             .await?;
 
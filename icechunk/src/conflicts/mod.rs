@@ -4,7 +4,7 @@ use async_trait::async_trait;
 
 use crate::{
     change_set::ChangeSet,
-    format::{transaction_log::TransactionLog, ChunkIndices, NodeId, Path},
+    format::{transaction_log::TransactionLog, ChunkIndices, NodeId, Path, SnapshotId},
     repository::RepositoryResult,
     Repository,
 };
@@ -38,10 +38,21 @@ pub enum Conflict {
     },
     DeleteOfUpdatedArray(Path),
     DeleteOfUpdatedGroup(Path),
+    CustomNodeDoubleUpdate(Path),
+    CustomNodeUpdateOfDeletedNode(Path),
+    DeleteOfUpdatedCustomNode(Path),
     // FIXME: we are missing the case of current change deleting a group and previous change
     // creating something new under it
 }
 
+/// The conflicts of a session with one of the commits made after its base snapshot, see
+/// [`Repository::detect_conflicts`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommitConflicts {
+    pub snapshot: SnapshotId,
+    pub conflicts: Vec<Conflict>,
+}
+
 #[derive(Debug)]
 pub enum ConflictResolution {
     Patched(ChangeSet),
@@ -0,0 +1,302 @@
+//! The xarray and CF conventions for array attributes
+//!
+//! xarray reads the dimensions of an array from its `_ARRAY_DIMENSIONS` attribute, the
+//! coordinate variables of a data variable from its space separated `coordinates` and the
+//! unit of its values from `units`. [`ArrayConventions`] reads and writes them without
+//! touching the other attributes, and [`check_conventions`] finds the arrays xarray would
+//! reject. Enable [`crate::schema::SchemaRule::XarrayConventions`] to check them on commit.
+use std::collections::{BTreeSet, HashMap};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    format::Path,
+    metadata::{ArrayShape, UserAttributes},
+};
+
+pub const ARRAY_DIMENSIONS: &str = "_ARRAY_DIMENSIONS";
+pub const COORDINATES: &str = "coordinates";
+pub const UNITS: &str = "units";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConventionViolation {
+    /// The attribute doesn't have the JSON type of the convention
+    MalformedAttribute { path: Path, attribute: String },
+    /// `_ARRAY_DIMENSIONS` doesn't name every dimension of the shape
+    DimensionCountMismatch { path: Path, dimensions: usize, shape: usize },
+    /// A name in `coordinates` is not an array of the same group
+    MissingCoordinate { path: Path, coordinate: String },
+    /// Arrays of the same group give the dimension different sizes
+    DimensionSizeMismatch { path: Path, dimension: String, size: u64, other: u64 },
+}
+
+/// The attributes of an array that follow the conventions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArrayConventions {
+    pub dimensions: Option<Vec<String>>,
+    pub coordinates: Vec<String>,
+    pub units: Option<String>,
+}
+
+impl ArrayConventions {
+    pub fn with_dimensions(
+        mut self,
+        dimensions: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.dimensions = Some(dimensions.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn with_coordinates(
+        mut self,
+        coordinates: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.coordinates = coordinates.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_units(mut self, units: impl Into<String>) -> Self {
+        self.units = Some(units.into());
+        self
+    }
+
+    /// Read the conventions from the attributes of the array at `path`
+    pub fn parse(
+        path: &Path,
+        atts: Option<&UserAttributes>,
+    ) -> Result<Self, ConventionViolation> {
+        let Some(map) = atts.and_then(|atts| atts.parsed.as_object()) else {
+            return Ok(Self::default());
+        };
+        let malformed = |attribute: &str| ConventionViolation::MalformedAttribute {
+            path: path.clone(),
+            attribute: attribute.to_string(),
+        };
+        let dimensions = match map.get(ARRAY_DIMENSIONS) {
+            None => None,
+            Some(Value::Array(names)) => Some(
+                names
+                    .iter()
+                    .map(|name| name.as_str().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| malformed(ARRAY_DIMENSIONS))?,
+            ),
+            Some(_) => return Err(malformed(ARRAY_DIMENSIONS)),
+        };
+        let coordinates = match map.get(COORDINATES) {
+            None => Vec::new(),
+            Some(Value::String(names)) => {
+                names.split_whitespace().map(str::to_string).collect()
+            }
+            Some(_) => return Err(malformed(COORDINATES)),
+        };
+        let units = match map.get(UNITS) {
+            None => None,
+            Some(Value::String(units)) => Some(units.clone()),
+            Some(_) => return Err(malformed(UNITS)),
+        };
+        Ok(Self { dimensions, coordinates, units })
+    }
+
+    /// `atts` with the convention attributes replaced by these ones, unset conventions are
+    /// removed and the other attributes kept
+    pub fn apply(&self, atts: Option<UserAttributes>) -> UserAttributes {
+        let mut map = match atts.map(|atts| atts.parsed) {
+            Some(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        match &self.dimensions {
+            Some(names) => {
+                map.insert(ARRAY_DIMENSIONS.to_string(), names.clone().into());
+            }
+            None => {
+                map.remove(ARRAY_DIMENSIONS);
+            }
+        }
+        if self.coordinates.is_empty() {
+            map.remove(COORDINATES);
+        } else {
+            map.insert(COORDINATES.to_string(), self.coordinates.join(" ").into());
+        }
+        match &self.units {
+            Some(units) => {
+                map.insert(UNITS.to_string(), units.clone().into());
+            }
+            None => {
+                map.remove(UNITS);
+            }
+        }
+        UserAttributes { parsed: Value::Object(map) }
+    }
+}
+
+/// The violations of the conventions by the `arrays` of a hierarchy
+///
+/// Coordinates must be arrays of the same group, and a dimension has the same size in all
+/// the arrays of a group that name it.
+pub fn check_conventions<'a>(
+    arrays: impl IntoIterator<Item = (&'a Path, &'a ArrayShape, Option<&'a UserAttributes>)>,
+) -> Vec<ConventionViolation> {
+    let mut res = Vec::new();
+    let mut parsed = Vec::new();
+    for (path, shape, atts) in arrays {
+        match ArrayConventions::parse(path, atts) {
+            Ok(conventions) => parsed.push((path, shape, conventions)),
+            Err(violation) => res.push(violation),
+        }
+    }
+    let array_paths: BTreeSet<&Path> = parsed.iter().map(|(path, _, _)| *path).collect();
+    let mut sizes: HashMap<(Option<Path>, &str), u64> = HashMap::new();
+    for (path, shape, conventions) in parsed.iter() {
+        let group = path.parent();
+        if let Some(dimensions) = &conventions.dimensions {
+            if dimensions.len() != shape.len() {
+                res.push(ConventionViolation::DimensionCountMismatch {
+                    path: (*path).clone(),
+                    dimensions: dimensions.len(),
+                    shape: shape.len(),
+                });
+            } else {
+                for (dimension, size) in dimensions.iter().zip(shape.iter()) {
+                    let other = *sizes
+                        .entry((group.clone(), dimension.as_str()))
+                        .or_insert(*size);
+                    if other != *size {
+                        res.push(ConventionViolation::DimensionSizeMismatch {
+                            path: (*path).clone(),
+                            dimension: dimension.clone(),
+                            size: *size,
+                            other,
+                        });
+                    }
+                }
+            }
+        }
+        for coordinate in conventions.coordinates.iter() {
+            let found = group
+                .as_ref()
+                .and_then(|group| group.child(coordinate).ok())
+                .is_some_and(|coordinate| array_paths.contains(&coordinate));
+            if !found {
+                res.push(ConventionViolation::MissingCoordinate {
+                    path: (*path).clone(),
+                    coordinate: coordinate.clone(),
+                });
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::{RepositoryError, ZarrArrayMetadata},
+        schema::{SchemaConstraint, SchemaRule, SchemaViolation},
+        ObjectStorage, Repository, Storage,
+    };
+
+    fn array_metadata(shape: ArrayShape) -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap(); shape.len()]),
+            shape,
+            data_type: DataType::Float64,
+            fill_value: FillValue::Float64(0.0),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_xarray_conventions() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_schema_constraint(SchemaConstraint::all_branches(
+                SchemaRule::XarrayConventions,
+            ))
+            .build();
+        let (time, temp): (Path, Path) = ("/time".try_into()?, "/temp".try_into()?);
+        ds.add_group(Path::root()).await?;
+        ds.add_array(time.clone(), array_metadata(vec![10])).await?;
+        ds.add_array(temp.clone(), array_metadata(vec![10, 5])).await?;
+        ds.set_user_attributes(
+            temp.clone(),
+            Some(UserAttributes { parsed: json!({"long_name": "temperature"}) }),
+        )
+        .await?;
+
+        let conventions = ArrayConventions::default()
+            .with_dimensions(["time", "station"])
+            .with_coordinates(["time"])
+            .with_units("K");
+        ds.set_array_conventions(temp.clone(), &conventions).await?;
+        ds.set_array_conventions(
+            time.clone(),
+            &ArrayConventions::default().with_dimensions(["time"]),
+        )
+        .await?;
+        assert_eq!(ds.array_conventions(&temp).await?, conventions);
+        assert_eq!(
+            ds.get_user_attributes(&temp).await?.unwrap().parsed,
+            json!({"long_name": "temperature", "_ARRAY_DIMENSIONS": ["time", "station"],
+                   "coordinates": "time", "units": "K"})
+        );
+        ds.commit("main", "dataset", None).await?;
+
+        // the time dimension has another size in temp, and pressure doesn't exist
+        ds.set_array_conventions(
+            time.clone(),
+            &ArrayConventions::default().with_dimensions(["time"]).with_units("s"),
+        )
+        .await?;
+        ds.update_array(time.clone(), array_metadata(vec![12])).await?;
+        ds.set_array_conventions(
+            temp.clone(),
+            &conventions.clone().with_coordinates(["time", "pressure"]),
+        )
+        .await?;
+        let res = ds.commit("main", "broken", None).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::SchemaViolations(violations)) if violations == vec![
+                SchemaViolation::Conventions(ConventionViolation::MissingCoordinate {
+                    path: temp.clone(),
+                    coordinate: "pressure".to_string(),
+                }),
+                SchemaViolation::Conventions(ConventionViolation::DimensionSizeMismatch {
+                    path: time.clone(),
+                    dimension: "time".to_string(),
+                    size: 12,
+                    other: 10,
+                }),
+            ]
+        ));
+
+        ds.set_user_attributes(
+            time.clone(),
+            Some(UserAttributes { parsed: json!({"units": 3}) }),
+        )
+        .await?;
+        assert!(matches!(
+            ds.array_conventions(&time).await,
+            Err(RepositoryError::InvalidConventions(
+                ConventionViolation::MalformedAttribute { attribute, .. }
+            )) if attribute == UNITS
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! Structured events of repository operations, for log ingestion
+//!
+//! Commits and conflicts are reported by [`crate::Repository`], collections by
+//! [`crate::ops::gc::garbage_collect`] and object reads by
+//! [`crate::storage::AuditedStorage`], to the same [`EventObserver`].
+//! [`JsonEventLogger`] writes them as JSON lines, one object per event, for tools like
+//! Elasticsearch or Datadog.
+use std::{
+    fmt::{self, Debug},
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    format::SnapshotId,
+    storage::{AccessLogger, AccessRecord, ObjectKind},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The branch was moved to a new snapshot
+    Commit { branch: String, snapshot: SnapshotId, parent: Option<SnapshotId> },
+    /// A commit failed because the branch moved since the session started
+    Conflict {
+        branch: String,
+        expected_parent: Option<SnapshotId>,
+        actual_parent: Option<SnapshotId>,
+    },
+    /// An object was read from the storage
+    Fetch { principal: String, kind: ObjectKind, id: String },
+    /// Garbage collection kept the reachable objects of a kind and deleted the rest
+    GarbageCollected { kind: ObjectKind, reachable: usize, deleted: usize },
+    /// A flush wrote the chunks of its snapshot in several manifests, because the manifest
+    /// was too large, or the backend rejected it for its size
+    ManifestSplit { snapshot: SnapshotId, manifests: usize, reason: String },
+    /// A commit hook failed, it stays pending, see [`crate::commit_hooks`]
+    HookFailed { hook: String, snapshot: SnapshotId, message: String },
+    /// The refs were updated but the ref advertisement could not be written, it's behind
+    /// until the next successful write, see [`crate::refs::advertise_refs`]
+    AdvertisementFailed { message: String },
+    /// A flush could not delete the chunks overwritten in its session, they stay until
+    /// the next garbage collection
+    ChunkCleanupFailed { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EventRecord {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl EventRecord {
+    pub fn new(at: DateTime<Utc>, event: Event) -> Self {
+        Self { at, event }
+    }
+}
+
+/// Receives the events of the operations it's set on
+///
+/// Set it with [`crate::RepositoryBuilder::with_event_observer`] or
+/// [`crate::ops::gc::GCConfig::with_event_observer`]. Observers are called from the task
+/// doing the work, they should return quickly.
+pub trait EventObserver: Debug + Send + Sync {
+    fn on_event(&self, record: &EventRecord);
+}
+
+pub type DynEventObserver = Arc<dyn EventObserver>;
+
+/// Ignores all events, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoEvents;
+
+impl EventObserver for NoEvents {
+    fn on_event(&self, _record: &EventRecord) {}
+}
+
+pub fn default_event_observer() -> DynEventObserver {
+    Arc::new(NoEvents)
+}
+
+/// Writes every event as a line of JSON
+///
+/// It's also an [`AccessLogger`], the reads of an [`crate::storage::AuditedStorage`] are
+/// written as [`Event::Fetch`]. Write errors are ignored, logging never fails an operation.
+pub struct JsonEventLogger {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonEventLogger {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self { writer: Mutex::new(Box::new(writer)) }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+
+    fn write(&self, records: impl IntoIterator<Item = EventRecord>) {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        for record in records {
+            if let Ok(line) = serde_json::to_string(&record) {
+                let _ = writeln!(writer, "{}", line);
+            }
+        }
+        let _ = writer.flush();
+    }
+}
+
+impl Debug for JsonEventLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonEventLogger").finish_non_exhaustive()
+    }
+}
+
+impl EventObserver for JsonEventLogger {
+    fn on_event(&self, record: &EventRecord) {
+        self.write([record.clone()]);
+    }
+}
+
+impl AccessLogger for JsonEventLogger {
+    fn log(&self, records: Vec<AccessRecord>) {
+        self.write(records.into_iter().map(|record| {
+            EventRecord::new(
+                record.at,
+                Event::Fetch {
+                    principal: record.principal,
+                    kind: record.kind,
+                    id: record.id,
+                },
+            )
+        }));
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use itertools::Itertools;
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        format::Path,
+        ops::gc::{garbage_collect, GCConfig},
+        refs::fetch_branch_tip,
+        repository::RepositoryError,
+        storage::faulty::FaultyStorage,
+        ObjectStorage, Repository, Storage,
+    };
+
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<Value> {
+            let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+            String::from_utf8(bytes)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_event_log() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let buffer = SharedBuffer::default();
+        let logger = Arc::new(JsonEventLogger::new(buffer.clone()));
+        let initial = Repository::init(Arc::clone(&storage), false).await?.build();
+        let parent = initial.snapshot_id().clone();
+        let session = || {
+            Repository::update(Arc::clone(&storage), parent.clone())
+                .with_event_observer(logger.clone())
+                .build()
+        };
+
+        let (mut first, mut second) = (session(), session());
+        first.add_group(Path::root()).await?;
+        let snapshot = first.commit("main", "first", None).await?;
+        second.add_group(Path::root()).await?;
+        assert!(matches!(
+            second.commit("main", "second", None).await,
+            Err(RepositoryError::Conflict { .. })
+        ));
+
+        let now = Utc::now();
+        let config =
+            GCConfig::clean_all(now, now, None).with_event_observer(logger.clone());
+        garbage_collect(storage.as_ref(), &config).await?;
+        logger.log(vec![AccessRecord {
+            principal: "reader".to_string(),
+            at: now,
+            kind: ObjectKind::Snapshot,
+            id: snapshot.to_string(),
+        }]);
+
+        let lines = buffer.lines();
+        assert!(lines.iter().all(|line| line["at"].is_string()));
+        let events = lines
+            .into_iter()
+            .map(|mut line| {
+                line.as_object_mut().unwrap().remove("at");
+                line
+            })
+            .collect_vec();
+        assert_eq!(
+            events,
+            vec![
+                json!({"event": "commit", "branch": "main",
+                       "snapshot": snapshot, "parent": parent}),
+                json!({"event": "conflict", "branch": "main",
+                       "expected_parent": parent, "actual_parent": snapshot}),
+                json!({"event": "garbage_collected", "kind": "snapshot",
+                       "reachable": 2, "deleted": 0}),
+                json!({"event": "garbage_collected", "kind": "manifest",
+                       "reachable": 0, "deleted": 0}),
+                json!({"event": "garbage_collected", "kind": "chunk",
+                       "reachable": 0, "deleted": 0}),
+                json!({"event": "fetch", "principal": "reader",
+                       "kind": "snapshot", "id": snapshot}),
+            ]
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_advertisement_failure() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        Repository::init(Arc::clone(&storage), false).await?;
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&storage)));
+        let buffer = SharedBuffer::default();
+        let mut ds = Repository::from_branch_tip(faulty.clone(), "main")
+            .await?
+            .with_ref_advertisement(true)
+            .with_event_observer(Arc::new(JsonEventLogger::new(buffer.clone())))
+            .build();
+        ds.add_group(Path::root()).await?;
+        // the branch moves, the advertisement after it fails
+        faulty.fail("write_ref", 1, 1);
+        let snapshot = ds.commit("main", "first", None).await?;
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, snapshot);
+        let events = buffer.lines();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "commit");
+        assert_eq!(events[1]["event"], "advertisement_failed");
+        Ok(())
+    }
+}
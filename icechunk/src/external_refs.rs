@@ -0,0 +1,181 @@
+//! A registry of objects the repository references but didn't write
+//!
+//! Manifests can point to objects written by other tools in the bucket and prefix of the
+//! repository, like virtual chunks, or chunks shared with another repository. Garbage
+//! collection would delete them as orphans, because no snapshot wrote them. Registered
+//! objects are kept by [`crate::ops::gc::garbage_collect`] whatever their age, until they
+//! are unregistered. References are recorded in a namespace of the refs.
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::hashing::HashAlgorithm,
+    refs::{RefError, RefResult},
+    storage::{ObjectKind, REF_PREFIX},
+    Storage, StorageError,
+};
+
+pub(crate) const EXTERNAL_REF_PREFIX: &str = "external.";
+const EXTERNAL_KEY_NAME: &str = "ref.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalReference {
+    pub kind: ObjectKind,
+    /// The id of the object, its key under the prefix of its kind
+    pub id: String,
+    /// Who registered the reference, for operators listing them
+    pub holder: String,
+    pub registered_at: DateTime<Utc>,
+}
+
+/// Object ids can have any character, the registry entry is named after their hash
+fn external_ref_name(kind: ObjectKind, id: &str) -> String {
+    let digest = HashAlgorithm::Sha256.digest(format!("{kind:?}/{id}"));
+    format!("{}{:02x}", EXTERNAL_REF_PREFIX, digest[..16].iter().format(""))
+}
+
+fn external_ref_key(kind: ObjectKind, id: &str) -> String {
+    format!("{}/{}", external_ref_name(kind, id), EXTERNAL_KEY_NAME)
+}
+
+/// Protect the `kind` object `id` from garbage collection
+///
+/// Registering an object again replaces its holder and registration time.
+pub async fn register_external_reference(
+    storage: &(dyn Storage + Send + Sync),
+    kind: ObjectKind,
+    id: &str,
+    holder: &str,
+    now: DateTime<Utc>,
+) -> RefResult<ExternalReference> {
+    let reference = ExternalReference {
+        kind,
+        id: id.to_string(),
+        holder: holder.to_string(),
+        registered_at: now,
+    };
+    let content = serde_json::to_vec(&reference)?;
+    storage
+        .write_ref(external_ref_key(kind, id).as_str(), true, Bytes::from(content))
+        .await?;
+    Ok(reference)
+}
+
+/// Stop protecting the object, the next garbage collection deletes it if it's an orphan
+pub async fn unregister_external_reference(
+    storage: &(dyn Storage + Send + Sync),
+    kind: ObjectKind,
+    id: &str,
+) -> RefResult<()> {
+    let key = external_ref_key(kind, id);
+    storage.delete_objects(REF_PREFIX, stream::iter([key]).boxed()).await?;
+    Ok(())
+}
+
+pub async fn list_external_references(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Vec<ExternalReference>> {
+    let mut res = Vec::new();
+    for name in storage.ref_names().await? {
+        if name.starts_with(EXTERNAL_REF_PREFIX) {
+            let key = format!("{}/{}", name, EXTERNAL_KEY_NAME);
+            match storage.get_ref(key.as_str()).await {
+                Ok(data) => res.push(serde_json::from_slice(data.as_ref())?),
+                Err(StorageError::RefNotFound(..)) => {
+                    return Err(RefError::RefNotFound(name))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// The registered references whose object no longer exists in `storage`
+pub async fn validate_external_references(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Vec<ExternalReference>> {
+    let mut missing = Vec::new();
+    for reference in list_external_references(storage).await? {
+        let found = storage
+            .list_objects_of_kind(reference.kind, &reference.id)
+            .await?
+            .try_any(|info| futures::future::ready(info.id == reference.id))
+            .await?;
+        if !found {
+            missing.push(reference);
+        }
+    }
+    Ok(missing)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use chrono::TimeDelta;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkId, Path},
+        ops::gc::{garbage_collect, GCConfig},
+        refs::{list_refs, Ref},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_external_references_survive_gc() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.commit("main", "root", None).await?;
+
+        // chunks written by another tool, only one of them registered
+        let (shared, orphan) = (ChunkId::random(), ChunkId::random());
+        for id in [&shared, &orphan] {
+            storage.write_chunk(id.clone(), Bytes::from_static(b"external")).await?;
+        }
+        let now = Utc::now();
+        let reference = register_external_reference(
+            storage.as_ref(),
+            ObjectKind::Chunk,
+            &shared.to_string(),
+            "ingest-tool",
+            now,
+        )
+        .await?;
+        assert_eq!(list_external_references(storage.as_ref()).await?, vec![reference]);
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string())]
+        );
+        assert_eq!(validate_external_references(storage.as_ref()).await?, vec![]);
+
+        let later = now + TimeDelta::hours(1);
+        let gc = GCConfig::clean_all(later, later, None);
+        assert_eq!(garbage_collect(storage.as_ref(), &gc).await?.chunks_deleted, 1);
+        storage.fetch_chunk(&shared, &ByteRange::ALL).await?;
+        assert!(storage.fetch_chunk(&orphan, &ByteRange::ALL).await.is_err());
+
+        // a reference to a deleted object doesn't validate
+        storage.delete_chunks(stream::iter([shared.clone()]).boxed()).await?;
+        let missing = validate_external_references(storage.as_ref()).await?;
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].id, shared.to_string());
+
+        unregister_external_reference(
+            storage.as_ref(),
+            ObjectKind::Chunk,
+            &missing[0].id,
+        )
+        .await?;
+        assert_eq!(list_external_references(storage.as_ref()).await?, vec![]);
+        Ok(())
+    }
+}
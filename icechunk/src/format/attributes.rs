@@ -1,2 +1,38 @@
-#[derive(Debug, PartialEq)]
-pub struct AttributesTable {}
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::UserAttributes;
+
+use super::{format_constants, IcechunkFormatVersion, TableOffset};
+
+/// The user attributes of many nodes, stored outside the snapshot
+///
+/// Snapshots point to the attributes of a node with a
+/// [`UserAttributesRef`](super::snapshot::UserAttributesRef), its location is the position
+/// in the table.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AttributesTable {
+    pub icechunk_attributes_format_version: IcechunkFormatVersion,
+    attributes: Vec<UserAttributes>,
+}
+
+impl AttributesTable {
+    pub fn new(attributes: Vec<UserAttributes>) -> Self {
+        Self {
+            icechunk_attributes_format_version:
+                format_constants::LATEST_ICECHUNK_ATTRIBUTES_FORMAT,
+            attributes,
+        }
+    }
+
+    pub fn get(&self, location: TableOffset) -> Option<&UserAttributes> {
+        self.attributes.get(location as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.attributes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty()
+    }
+}
@@ -0,0 +1,200 @@
+//! A checksummed container for serialized metadata objects
+//!
+//! A frame is a fixed header followed by the serialized object:
+//!
+//! | bytes | content                                      |
+//! |-------|----------------------------------------------|
+//! | 4     | magic, `ICEF`                                |
+//! | 1     | framing version                              |
+//! | 1     | [`FrameKind`] of the object                  |
+//! | 2     | format version of the object, little endian  |
+//! | 8     | length of the payload, little endian         |
+//! | 4     | CRC-32 of the payload, little endian         |
+//!
+//! Serialized objects never start with the magic bytes, so [`unframe`] passes unframed
+//! objects through, and repositories can mix objects written with and without framing.
+use thiserror::Error;
+
+use super::IcechunkFormatVersion;
+
+pub const MAGIC: &[u8; 4] = b"ICEF";
+pub const FRAMING_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum FrameKind {
+    Snapshot = 1,
+    Manifest = 2,
+    TransactionLog = 3,
+    Attributes = 4,
+}
+
+impl FrameKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(FrameKind::Snapshot),
+            2 => Some(FrameKind::Manifest),
+            3 => Some(FrameKind::TransactionLog),
+            4 => Some(FrameKind::Attributes),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum FramingError {
+    #[error("{kind:?} object is truncated: expected {expected} bytes, found {found}")]
+    Truncated { kind: FrameKind, expected: u64, found: u64 },
+    #[error("{kind:?} object is corrupted: checksum is {found:#010x}, expected {expected:#010x}")]
+    ChecksumMismatch { kind: FrameKind, expected: u32, found: u32 },
+    #[error("{kind:?} object has {trailing} unexpected bytes after the payload")]
+    TrailingBytes { kind: FrameKind, trailing: u64 },
+    #[error("expected a {expected:?} object, found a frame of kind {found}")]
+    WrongKind { expected: FrameKind, found: u8 },
+    #[error("{kind:?} object uses unknown framing version {version}")]
+    UnknownVersion { kind: FrameKind, version: u8 },
+}
+
+pub type FramingResult<A> = Result<A, FramingError>;
+
+/// A frame around a serialized object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub kind: FrameKind,
+    pub format_version: IcechunkFormatVersion,
+    pub payload: &'a [u8],
+}
+
+pub fn is_framed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Wrap the serialized object in `payload` in a frame
+pub fn frame(
+    kind: FrameKind,
+    format_version: IcechunkFormatVersion,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut res = Vec::with_capacity(HEADER_LEN + payload.len());
+    res.extend_from_slice(MAGIC);
+    res.push(FRAMING_VERSION);
+    res.push(kind as u8);
+    res.extend_from_slice(&format_version.to_le_bytes());
+    res.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    res.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    res.extend_from_slice(payload);
+    res
+}
+
+/// Check the frame around a `kind` object and return it
+pub fn read_frame(kind: FrameKind, bytes: &[u8]) -> FramingResult<Frame<'_>> {
+    let truncated = |expected: u64| FramingError::Truncated {
+        kind,
+        expected,
+        found: bytes.len() as u64,
+    };
+    if bytes.len() < HEADER_LEN {
+        return Err(truncated(HEADER_LEN as u64));
+    }
+    let (header, payload) = bytes.split_at(HEADER_LEN);
+    if header[4] != FRAMING_VERSION {
+        return Err(FramingError::UnknownVersion { kind, version: header[4] });
+    }
+    if FrameKind::from_byte(header[5]) != Some(kind) {
+        return Err(FramingError::WrongKind { expected: kind, found: header[5] });
+    }
+    let format_version = u16::from_le_bytes([header[6], header[7]]);
+    let mut len = [0; 8];
+    len.copy_from_slice(&header[8..16]);
+    let len = u64::from_le_bytes(len);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&header[16..20]);
+    let expected_crc = u32::from_le_bytes(crc);
+
+    let found = payload.len() as u64;
+    if found < len {
+        // the length comes from the header, it may be corrupted
+        return Err(truncated((HEADER_LEN as u64).saturating_add(len)));
+    }
+    if found > len {
+        return Err(FramingError::TrailingBytes { kind, trailing: found - len });
+    }
+    let crc = crc32fast::hash(payload);
+    if crc != expected_crc {
+        return Err(FramingError::ChecksumMismatch {
+            kind,
+            expected: expected_crc,
+            found: crc,
+        });
+    }
+    Ok(Frame { kind, format_version, payload })
+}
+
+/// The serialized `kind` object in `bytes`, checking its frame if it has one
+pub fn unframe(kind: FrameKind, bytes: &[u8]) -> FramingResult<&[u8]> {
+    if is_framed(bytes) {
+        read_frame(kind, bytes).map(|frame| frame.payload)
+    } else {
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_framing() -> Result<(), FramingError> {
+        let framed = frame(FrameKind::Manifest, 3, b"payload");
+        assert!(is_framed(&framed));
+        assert_eq!(
+            read_frame(FrameKind::Manifest, &framed)?,
+            Frame { kind: FrameKind::Manifest, format_version: 3, payload: b"payload" }
+        );
+        assert_eq!(unframe(FrameKind::Manifest, b"\x93plain")?, b"\x93plain");
+
+        assert_eq!(
+            unframe(FrameKind::Manifest, &framed[..framed.len() - 2]),
+            Err(FramingError::Truncated {
+                kind: FrameKind::Manifest,
+                expected: framed.len() as u64,
+                found: framed.len() as u64 - 2
+            })
+        );
+        assert!(matches!(
+            unframe(FrameKind::Manifest, &framed[..10]),
+            Err(FramingError::Truncated { expected: 20, found: 10, .. })
+        ));
+        let mut corrupted = framed.clone();
+        corrupted[HEADER_LEN + 1] ^= 1;
+        assert!(matches!(
+            unframe(FrameKind::Manifest, &corrupted),
+            Err(FramingError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(
+            unframe(FrameKind::Snapshot, &framed),
+            Err(FramingError::WrongKind { expected: FrameKind::Snapshot, found: 2 })
+        );
+        let mut huge = framed.clone();
+        huge[8..16].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        assert_eq!(
+            unframe(FrameKind::Manifest, &huge),
+            Err(FramingError::Truncated {
+                kind: FrameKind::Manifest,
+                expected: u64::MAX,
+                found: framed.len() as u64
+            })
+        );
+        let mut longer = framed;
+        longer.push(0);
+        assert!(matches!(
+            unframe(FrameKind::Manifest, &longer),
+            Err(FramingError::TrailingBytes { trailing: 1, .. })
+        ));
+        Ok(())
+    }
+}
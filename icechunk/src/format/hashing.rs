@@ -0,0 +1,379 @@
+//! The hash algorithms used for content addressing
+//!
+//! SHA-256 is the default, it's FIPS approved. BLAKE3 is for repositories that don't need
+//! FIPS. The BLAKE3 implementation is the portable reference one, it hashes inputs of any
+//! size but doesn't use SIMD or threads.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => f.write_str("sha256"),
+            HashAlgorithm::Blake3 => f.write_str("blake3"),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> ContentHasher {
+        match self {
+            HashAlgorithm::Sha256 => ContentHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => ContentHasher::Blake3(Blake3::new()),
+        }
+    }
+
+    /// The 32 bytes digest of `input`
+    pub fn digest(self, input: impl AsRef<[u8]>) -> [u8; 32] {
+        let mut hasher = self.hasher();
+        hasher.update(input);
+        hasher.finalize()
+    }
+}
+
+/// An incremental hash with one of the [`HashAlgorithm`]s
+#[derive(Debug, Clone)]
+pub enum ContentHasher {
+    Sha256(Sha256),
+    Blake3(Blake3),
+}
+
+impl ContentHasher {
+    pub fn update(&mut self, input: impl AsRef<[u8]>) {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.update(input),
+            ContentHasher::Blake3(hasher) => hasher.update(input.as_ref()),
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.finalize().into(),
+            ContentHasher::Blake3(hasher) => hasher.finalize(),
+        }
+    }
+}
+
+const BLAKE3_IV: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB,
+    0x5BE0CD19,
+];
+const MSG_PERMUTATION: [usize; 16] =
+    [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+type ChainingValue = [u32; 8];
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // columns
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    // diagonals
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn compress(
+    cv: &ChainingValue,
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        BLAKE3_IV[0],
+        BLAKE3_IV[1],
+        BLAKE3_IV[2],
+        BLAKE3_IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut block = *block;
+    for i in 0..7 {
+        round(&mut state, &block);
+        if i < 6 {
+            block = MSG_PERMUTATION.map(|j| block[j]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+fn block_words(block: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    words
+}
+
+fn first_8(words: [u32; 16]) -> ChainingValue {
+    let mut cv = [0; 8];
+    cv.copy_from_slice(&words[..8]);
+    cv
+}
+
+/// A compression not done yet, it's the root one if nothing else is compressed after it
+struct Output {
+    cv: ChainingValue,
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> ChainingValue {
+        first_8(compress(&self.cv, &self.block, self.counter, self.block_len, self.flags))
+    }
+
+    fn root_hash(&self) -> [u8; 32] {
+        let words = compress(&self.cv, &self.block, 0, self.block_len, self.flags | ROOT);
+        let mut res = [0; 32];
+        for (bytes, word) in res.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        res
+    }
+}
+
+fn parent_output(left: &ChainingValue, right: &ChainingValue) -> Output {
+    let mut block = [0; 16];
+    block[..8].copy_from_slice(left);
+    block[8..].copy_from_slice(right);
+    Output {
+        cv: BLAKE3_IV,
+        block,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ChunkState {
+    cv: ChainingValue,
+    chunk_counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(chunk_counter: u64) -> Self {
+        Self {
+            cv: BLAKE3_IV,
+            chunk_counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // the last block of the chunk is compressed by `output`, with `CHUNK_END`
+            if self.block_len == BLOCK_LEN {
+                let words = compress(
+                    &self.cv,
+                    &block_words(&self.block),
+                    self.chunk_counter,
+                    BLOCK_LEN as u32,
+                    self.start_flag(),
+                );
+                self.cv = first_8(words);
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let take = (BLOCK_LEN - self.block_len).min(input.len());
+            self.block[self.block_len..self.block_len + take]
+                .copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            cv: self.cv,
+            block: block_words(&self.block),
+            counter: self.chunk_counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+/// The BLAKE3 hash, with 32 bytes of output
+#[derive(Debug, Clone)]
+pub struct Blake3 {
+    chunk: ChunkState,
+    /// The chaining values of the complete subtrees on the left of the current chunk
+    cv_stack: Vec<ChainingValue>,
+}
+
+impl Default for Blake3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Blake3 {
+    pub fn new() -> Self {
+        Self { chunk: ChunkState::new(0), cv_stack: Vec::new() }
+    }
+
+    /// Merge the subtrees completed by a new chunk, `total_chunks` counts it
+    fn push_chunk(&mut self, mut cv: ChainingValue, mut total_chunks: u64) {
+        while total_chunks & 1 == 0 {
+            if let Some(left) = self.cv_stack.pop() {
+                cv = parent_output(&left, &cv).chaining_value();
+            }
+            total_chunks >>= 1;
+        }
+        self.cv_stack.push(cv);
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // a full chunk is only finished when more input comes, it may be the root
+            if self.chunk.len() == CHUNK_LEN {
+                let cv = self.chunk.output().chaining_value();
+                let total_chunks = self.chunk.chunk_counter + 1;
+                self.push_chunk(cv, total_chunks);
+                self.chunk = ChunkState::new(total_chunks);
+            }
+            let take = (CHUNK_LEN - self.chunk.len()).min(input.len());
+            self.chunk.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    pub fn finalize(&self) -> [u8; 32] {
+        let mut output = self.chunk.output();
+        for left in self.cv_stack.iter().rev() {
+            output = parent_output(left, &output.chaining_value());
+        }
+        output.root_hash()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use itertools::Itertools;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn hex(digest: [u8; 32]) -> String {
+        format!("{:02x}", digest.iter().format(""))
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        assert_eq!(
+            hex(HashAlgorithm::Blake3.digest(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            hex(HashAlgorithm::Blake3.digest(b"abc")),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(
+            hex(HashAlgorithm::Sha256.digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        // the official vectors, the input repeats 0..251, lengths past one chunk go through
+        // the tree of chaining values
+        let input: Vec<u8> = (0..102400).map(|i| (i % 251) as u8).collect();
+        for (len, expected) in [
+            (1, "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"),
+            (1023, "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11"),
+            (1024, "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+            (1025, "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+            (2048, "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"),
+            (2049, "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030"),
+            (3072, "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2"),
+            (8192, "aae792484c8efe4f19e2ca7d371d8c467ffb10748d8a5a1ae579948f718a2a63"),
+            (102400, "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"),
+        ] {
+            assert_eq!(
+                hex(HashAlgorithm::Blake3.digest(&input[..len])),
+                expected,
+                "{len}"
+            );
+        }
+
+        // incremental updates across block and chunk boundaries hash like a single one
+        let input: Vec<u8> = (0..5 * CHUNK_LEN + 7).map(|i| (i % 251) as u8).collect();
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            let mut hasher = algorithm.hasher();
+            for part in input.chunks(100) {
+                hasher.update(part);
+            }
+            assert_eq!(hasher.finalize(), algorithm.digest(&input));
+        }
+        assert_ne!(
+            HashAlgorithm::Blake3.digest(&input[..CHUNK_LEN]),
+            HashAlgorithm::Blake3.digest(&input[..CHUNK_LEN + 1])
+        );
+    }
+}
@@ -1,6 +1,10 @@
 use futures::{pin_mut, Stream, TryStreamExt};
 use itertools::Itertools;
-use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, Range},
+    sync::Arc,
+};
 use thiserror::Error;
 
 use bytes::Bytes;
@@ -9,11 +13,53 @@ use serde::{Deserialize, Serialize};
 use super::{
     format_constants, ChunkId, ChunkIndices, ChunkLength, ChunkOffset,
     IcechunkFormatError, IcechunkFormatVersion, IcechunkResult, ManifestId, NodeId,
+    SnapshotId,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestExtents(pub Vec<ChunkIndices>);
 
+impl ManifestExtents {
+    /// The chunks from `from`, inclusive, to `to`, exclusive, in every dimension
+    pub fn new(from: ChunkIndices, to: ChunkIndices) -> Self {
+        Self(vec![from, to])
+    }
+
+    /// Whether the manifest can have the chunk at `coords`, empty extents have any chunk
+    pub fn contains(&self, coords: &ChunkIndices) -> bool {
+        match self.0.as_slice() {
+            [from, to] => {
+                coords.0.len() == from.0.len()
+                    && coords
+                        .0
+                        .iter()
+                        .zip(from.0.iter().zip(to.0.iter()))
+                        .all(|(coord, (from, to))| from <= coord && coord < to)
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether the manifest can have chunks in `other`
+    pub fn overlaps(&self, other: &ManifestExtents) -> bool {
+        match (self.0.as_slice(), other.0.as_slice()) {
+            ([from, to], [other_from, other_to]) => {
+                from.0.len() == other_from.0.len()
+                    && from.0.len() == other_to.0.len()
+                    && from
+                        .0
+                        .iter()
+                        .zip(to.0.iter())
+                        .zip(other_from.0.iter().zip(other_to.0.iter()))
+                        .all(|((from, to), (other_from, other_to))| {
+                            from < other_to && other_from < to
+                        })
+            }
+            _ => true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestRef {
     pub object_id: ManifestId,
@@ -35,6 +81,8 @@ pub enum VirtualReferenceError {
     FetchError(Box<dyn std::error::Error + Send + Sync>),
     #[error("error parsing virtual reference {0}")]
     OtherError(#[from] Box<dyn std::error::Error + Send + Sync>),
+    #[error("invalid virtual chunk slice: {0}")]
+    InvalidSlice(String),
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -78,6 +126,140 @@ pub struct VirtualChunkRef {
     pub location: VirtualChunkLocation,
     pub offset: ChunkOffset,
     pub length: ChunkLength,
+    /// The chunk is a region of the external chunk at `offset` and `length`, instead of
+    /// the whole of it
+    #[serde(default)]
+    pub slice: Option<ChunkSlice>,
+}
+
+impl VirtualChunkRef {
+    /// The size of the chunk, the size of its region if it's a slice
+    pub fn chunk_length(&self) -> ChunkLength {
+        self.slice.as_ref().map_or(self.length, ChunkSlice::len)
+    }
+}
+
+/// A region of a larger external chunk, for exposing files with giant chunks through a
+/// finer chunk grid
+///
+/// The external chunk must be stored uncompressed, with its elements in C order. Reads
+/// fetch the bytes from the first to the last element of the region, and gather the
+/// region from them.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkSlice {
+    /// The shape of the external chunk, in elements
+    pub source_shape: Vec<u64>,
+    /// The first element of the region in each dimension
+    pub origin: Vec<u64>,
+    /// The shape of the region, it's the shape of the chunk
+    pub shape: Vec<u64>,
+    pub element_size: u64,
+}
+
+impl ChunkSlice {
+    /// Returns a description of the problem if the slice doesn't fit in an external chunk
+    /// of `source_length` bytes
+    pub fn validate(&self, source_length: u64) -> Result<(), String> {
+        let dims = self.source_shape.len();
+        if self.origin.len() != dims || self.shape.len() != dims {
+            return Err(format!(
+                "the source has {dims} dimensions, the origin {} and the shape {}",
+                self.origin.len(),
+                self.shape.len()
+            ));
+        }
+        if self.element_size == 0 {
+            return Err("the element size is zero".to_string());
+        }
+        let fits = self
+            .origin
+            .iter()
+            .zip(self.shape.iter())
+            .zip(self.source_shape.iter())
+            .all(|((origin, shape), source)| origin + shape <= *source);
+        if !fits {
+            return Err(format!(
+                "the region at {:?} of shape {:?} is outside the source of shape {:?}",
+                self.origin, self.shape, self.source_shape
+            ));
+        }
+        let expected = self.source_shape.iter().product::<u64>() * self.element_size;
+        if expected != source_length {
+            return Err(format!(
+                "the source of shape {:?} has {expected} bytes, the reference {source_length}",
+                self.source_shape
+            ));
+        }
+        Ok(())
+    }
+
+    /// The size of the region in bytes
+    pub fn len(&self) -> u64 {
+        self.shape.iter().product::<u64>() * self.element_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The byte offset of the element at `index` in the source
+    fn source_offset(&self, index: &[u64]) -> u64 {
+        let element = index
+            .iter()
+            .zip(self.source_shape.iter())
+            .fold(0, |offset, (index, dim)| offset * dim + index);
+        element * self.element_size
+    }
+
+    /// The bytes of the source from the first to the last element of the region
+    pub fn byte_span(&self) -> Range<u64> {
+        if self.is_empty() {
+            return 0..0;
+        }
+        let last: Vec<u64> =
+            self.origin.iter().zip(self.shape.iter()).map(|(o, s)| o + s - 1).collect();
+        self.source_offset(&self.origin)..self.source_offset(&last) + self.element_size
+    }
+
+    /// Gather the region from `span`, the bytes of [`ChunkSlice::byte_span`]
+    pub fn extract(&self, span: &[u8]) -> Result<Bytes, String> {
+        let byte_span = self.byte_span();
+        let dims = self.source_shape.len();
+        if self.origin.len() != dims || self.shape.len() != dims {
+            return Err("the slice dimensions don't match".to_string());
+        }
+        if span.len() as u64 != byte_span.end - byte_span.start {
+            return Err(format!(
+                "expected {} bytes, got {}",
+                byte_span.end - byte_span.start,
+                span.len()
+            ));
+        }
+        let Some((row_len, outer)) = self.shape.split_last() else {
+            // zero dimensional, a single element
+            return Ok(Bytes::copy_from_slice(span));
+        };
+        let row_bytes = (row_len * self.element_size) as usize;
+        let mut res = Vec::with_capacity(self.len() as usize);
+        if self.is_empty() {
+            return Ok(res.into());
+        }
+        // the rows of the region, contiguous along the last dimension
+        let mut index = self.origin.clone();
+        'rows: loop {
+            let row_start = (self.source_offset(&index) - byte_span.start) as usize;
+            res.extend_from_slice(&span[row_start..row_start + row_bytes]);
+            for dim in (0..outer.len()).rev() {
+                index[dim] += 1;
+                if index[dim] < self.origin[dim] + self.shape[dim] {
+                    continue 'rows;
+                }
+                index[dim] = self.origin[dim];
+            }
+            break;
+        }
+        Ok(res.into())
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -95,6 +277,32 @@ pub enum ChunkPayload {
     Ref(ChunkRef),
 }
 
+/// A chunk payload with the ids of the transformers its bytes were encoded with
+///
+/// Transformed chunks can only be decoded with their transformers, so copies of chunk
+/// references, to other arrays, sessions or patches, carry this and not the bare payload.
+/// Inline and virtual chunks are never transformed. See [`crate::chunk_transformer`].
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransformedPayload {
+    pub payload: ChunkPayload,
+    pub transformers: Vec<String>,
+}
+
+impl TransformedPayload {
+    pub fn new(payload: ChunkPayload, transformers: Vec<String>) -> Self {
+        Self { payload, transformers }
+    }
+
+    /// A payload stored as written, with no transformers
+    pub fn plain(payload: ChunkPayload) -> Self {
+        Self::new(payload, Vec::new())
+    }
+
+    pub fn is_transformed(&self) -> bool {
+        !self.transformers.is_empty()
+    }
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ChunkInfo {
     pub node: NodeId,
@@ -102,11 +310,20 @@ pub struct ChunkInfo {
     pub payload: ChunkPayload,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+/// Serialized in the layout of its format version, see [`super::manifest_columns`]
+#[derive(Debug, PartialEq, Default)]
 pub struct Manifest {
     pub icechunk_manifest_format_version: IcechunkFormatVersion,
     pub icechunk_manifest_format_flags: BTreeMap<String, rmpv::Value>,
     chunks: BTreeMap<(NodeId, ChunkIndices), ChunkPayload>,
+    /// The snapshot that wrote each chunk, missing for chunks written by older versions
+    origins: BTreeMap<(NodeId, ChunkIndices), SnapshotId>,
+    /// The ids of the transformers applied to each chunk, in order, missing for chunks
+    /// stored as written
+    transformers: BTreeMap<(NodeId, ChunkIndices), Vec<String>>,
+    /// The shard of a [`crate::storage::ShardedStorage`] that stores each chunk, missing
+    /// for chunks written to a single bucket
+    placements: BTreeMap<(NodeId, ChunkIndices), String>,
 }
 
 impl Manifest {
@@ -134,9 +351,75 @@ impl Manifest {
             icechunk_manifest_format_version:
                 format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
             icechunk_manifest_format_flags: Default::default(),
+            origins: Default::default(),
+            transformers: Default::default(),
+            placements: Default::default(),
         }
     }
 
+    pub fn with_format_version(mut self, version: IcechunkFormatVersion) -> Self {
+        self.icechunk_manifest_format_version = version;
+        self
+    }
+
+    pub fn with_origins(
+        mut self,
+        origins: BTreeMap<(NodeId, ChunkIndices), SnapshotId>,
+    ) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    /// The snapshot that wrote the chunk, if it was recorded
+    pub fn chunk_origin(
+        &self,
+        node: &NodeId,
+        coord: &ChunkIndices,
+    ) -> Option<&SnapshotId> {
+        self.origins.get(&(node.clone(), coord.clone()))
+    }
+
+    pub fn origins(&self) -> &BTreeMap<(NodeId, ChunkIndices), SnapshotId> {
+        &self.origins
+    }
+
+    pub fn with_transformers(
+        mut self,
+        transformers: BTreeMap<(NodeId, ChunkIndices), Vec<String>>,
+    ) -> Self {
+        self.transformers = transformers;
+        self
+    }
+
+    /// The ids of the [`crate::chunk_transformer::ChunkTransformer`]s applied to the chunk
+    pub fn chunk_transformers(&self, node: &NodeId, coord: &ChunkIndices) -> &[String] {
+        self.transformers
+            .get(&(node.clone(), coord.clone()))
+            .map(|ids| ids.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn transformers(&self) -> &BTreeMap<(NodeId, ChunkIndices), Vec<String>> {
+        &self.transformers
+    }
+
+    pub fn with_placements(
+        mut self,
+        placements: BTreeMap<(NodeId, ChunkIndices), String>,
+    ) -> Self {
+        self.placements = placements;
+        self
+    }
+
+    /// The shard the chunk was written to, if it was recorded
+    pub fn chunk_placement(&self, node: &NodeId, coord: &ChunkIndices) -> Option<&str> {
+        self.placements.get(&(node.clone(), coord.clone())).map(|shard| shard.as_str())
+    }
+
+    pub fn placements(&self) -> &BTreeMap<(NodeId, ChunkIndices), String> {
+        &self.placements
+    }
+
     pub async fn from_stream<E>(
         chunks: impl Stream<Item = Result<ChunkInfo, E>>,
     ) -> Result<Self, E> {
@@ -152,16 +435,154 @@ impl Manifest {
         &self.chunks
     }
 
+    /// The chunks of `node` with coordinates in `extents`, in coordinate order
+    pub fn chunks_in_region<'a>(
+        &'a self,
+        node: &NodeId,
+        extents: &'a ManifestExtents,
+    ) -> impl Iterator<Item = (&'a ChunkIndices, &'a ChunkPayload)> + 'a {
+        // coordinates within the extents sort between its corners, zero dimensional
+        // extents have the single chunk of their corners
+        let range = match extents.0.as_slice() {
+            [from, to] if !from.0.is_empty() => (
+                Bound::Included((node.clone(), from.clone())),
+                Bound::Excluded((node.clone(), to.clone())),
+            ),
+            _ => {
+                (Bound::Included((node.clone(), ChunkIndices(vec![]))), Bound::Unbounded)
+            }
+        };
+        let node = node.clone();
+        self.chunks
+            .range(range)
+            .take_while(move |((n, _), _)| n == &node)
+            .filter(|((_, coord), _)| extents.contains(coord))
+            .map(|((_, coord), payload)| (coord, payload))
+    }
+
     pub fn len(&self) -> usize {
         self.chunks.len()
     }
 
+    /// An upper bound of the size of the serialized manifest, without serializing it
+    ///
+    /// Counts every integer at its largest MessagePack encoding, and the keys of the records
+    /// once per record.
+    pub fn estimated_size(&self) -> u64 {
+        // a node id and the coordinates, in a key
+        let key_size = |coords: &ChunkIndices| 2 + 10 + 9 * coords.0.len() as u64;
+        let chunks: u64 = self
+            .chunks
+            .iter()
+            .map(|((_, coords), payload)| {
+                key_size(coords)
+                    + match payload {
+                        ChunkPayload::Inline(bytes) => 5 + bytes.len() as u64,
+                        ChunkPayload::Ref(_) => 4 + 14 + 2 * 9,
+                        ChunkPayload::Virtual(VirtualChunkRef {
+                            location,
+                            slice,
+                            ..
+                        }) => {
+                            let VirtualChunkLocation::Absolute(url) = location;
+                            let slice = slice.as_ref().map_or(1, |slice| {
+                                let dims = slice.source_shape.len()
+                                    + slice.origin.len()
+                                    + slice.shape.len();
+                                4 + 3 * 5 + 9 * (dims as u64 + 1)
+                            });
+                            4 + 5 + url.len() as u64 + 2 * 9 + slice
+                        }
+                    }
+            })
+            .sum();
+        let origins: u64 =
+            self.origins.keys().map(|(_, coords)| key_size(coords) + 14).sum();
+        let transformers: u64 = self
+            .transformers
+            .iter()
+            .map(|((_, coords), ids)| {
+                key_size(coords)
+                    + 5
+                    + ids.iter().map(|id| 5 + id.len() as u64).sum::<u64>()
+            })
+            .sum();
+        let placements: u64 = self
+            .placements
+            .iter()
+            .map(|((_, coords), shard)| key_size(coords) + 5 + shard.len() as u64)
+            .sum();
+        64 + chunks + origins + transformers + placements
+    }
+
+    /// The chunks of the manifest in at most `pieces` manifests of consecutive chunks, with
+    /// their records
+    pub fn split(&self, pieces: usize) -> Vec<Manifest> {
+        let per_piece = self.len().div_ceil(pieces.max(1)).max(1);
+        self.chunks
+            .iter()
+            .chunks(per_piece)
+            .into_iter()
+            .map(|piece| {
+                let chunks: BTreeMap<_, _> =
+                    piece.map(|(key, payload)| (key.clone(), payload.clone())).collect();
+                Manifest {
+                    icechunk_manifest_format_version: self
+                        .icechunk_manifest_format_version,
+                    icechunk_manifest_format_flags: self
+                        .icechunk_manifest_format_flags
+                        .clone(),
+                    origins: records(&self.origins, &chunks),
+                    transformers: records(&self.transformers, &chunks),
+                    placements: records(&self.placements, &chunks),
+                    chunks,
+                }
+            })
+            .collect()
+    }
+
+    /// The smallest extents with all the chunks of each node
+    pub fn node_extents(&self) -> BTreeMap<NodeId, ManifestExtents> {
+        let mut res: BTreeMap<NodeId, ManifestExtents> = BTreeMap::new();
+        for (node, coords) in self.chunks.keys() {
+            let end =
+                ChunkIndices(coords.0.iter().map(|c| c.saturating_add(1)).collect());
+            match res.get_mut(node).map(|extents| extents.0.as_mut_slice()) {
+                Some([from, to]) => {
+                    for (i, coord) in coords.0.iter().enumerate() {
+                        if let (Some(from), Some(to), Some(end)) =
+                            (from.0.get_mut(i), to.0.get_mut(i), end.0.get(i))
+                        {
+                            *from = (*from).min(*coord);
+                            *to = (*to).max(*end);
+                        }
+                    }
+                }
+                _ => {
+                    res.insert(node.clone(), ManifestExtents::new(coords.clone(), end));
+                }
+            }
+        }
+        res
+    }
+
     #[must_use]
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 }
 
+/// The entries of `map` for the chunks in `chunks`
+fn records<V: Clone>(
+    map: &BTreeMap<(NodeId, ChunkIndices), V>,
+    chunks: &BTreeMap<(NodeId, ChunkIndices), ChunkPayload>,
+) -> BTreeMap<(NodeId, ChunkIndices), V> {
+    map.iter()
+        .filter(|(key, _)| chunks.contains_key(*key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
 impl FromIterator<ChunkInfo> for Manifest {
     fn from_iter<T: IntoIterator<Item = ChunkInfo>>(iter: T) -> Self {
         let chunks = iter
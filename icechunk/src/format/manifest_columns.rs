@@ -0,0 +1,517 @@
+//! The columnar layout of manifests
+//!
+//! Manifests are serialized with the chunks of each array in columns: one column of
+//! coordinates per dimension, the kind of each payload, and one column per field of each
+//! kind of payload, holding only the payloads of that kind. Chunks are in coordinate order.
+//! The origins, transformers and placements of the chunks are columns too, empty when no
+//! chunk has them.
+//!
+//! [`ColumnarManifest::select`] filters the chunks of an array by coordinate range one
+//! column at a time, and builds only the payloads of the chunks it keeps, so planning the
+//! read of a region, with [`crate::Storage::fetch_manifest_region`], doesn't materialize
+//! the whole manifest.
+//!
+//! Manifests are serialized in the layout of their format version. Readers older than
+//! [`COLUMNAR_MANIFEST_FORMAT`] can't read the columnar layout, so repositories opt in to
+//! write it with [`crate::repository::RepositoryConfig::columnar_manifests`], and new
+//! manifests keep the row layout by default. Both layouts are read.
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use itertools::Itertools;
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::{
+    manifest::{ChunkPayload, ChunkRef, Manifest, ManifestExtents, VirtualChunkRef},
+    ChunkId, ChunkIndices, IcechunkFormatVersion, NodeId, SnapshotId,
+};
+
+/// The first manifest format with the columnar layout
+pub const COLUMNAR_MANIFEST_FORMAT: IcechunkFormatVersion = 1;
+
+const REF_PAYLOAD: u8 = 0;
+const INLINE_PAYLOAD: u8 = 1;
+const VIRTUAL_PAYLOAD: u8 = 2;
+
+/// The chunks of an array with the same number of dimensions, in coordinate order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArrayColumns {
+    node: NodeId,
+    /// One column per dimension
+    coords: Vec<Vec<u64>>,
+    /// The kind of the payload of each chunk, it's the number of chunks
+    kinds: Vec<u8>,
+    ref_ids: Vec<ChunkId>,
+    ref_offsets: Vec<u64>,
+    ref_lengths: Vec<u64>,
+    inline: Vec<Bytes>,
+    virtual_refs: Vec<VirtualChunkRef>,
+    origins: Vec<Option<SnapshotId>>,
+    transformers: Vec<Vec<String>>,
+    placements: Vec<Option<String>>,
+}
+
+impl ArrayColumns {
+    pub fn node(&self) -> &NodeId {
+        &self.node
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.coords.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    fn push(&mut self, manifest: &Manifest, key: &(NodeId, ChunkIndices)) {
+        let row = self.len();
+        let (node, coord) = key;
+        for (column, c) in self.coords.iter_mut().zip(coord.0.iter()) {
+            column.push(*c);
+        }
+        match &manifest.chunks()[key] {
+            ChunkPayload::Ref(chunk) => {
+                self.kinds.push(REF_PAYLOAD);
+                self.ref_ids.push(chunk.id.clone());
+                self.ref_offsets.push(chunk.offset);
+                self.ref_lengths.push(chunk.length);
+            }
+            ChunkPayload::Inline(bytes) => {
+                self.kinds.push(INLINE_PAYLOAD);
+                self.inline.push(bytes.clone());
+            }
+            ChunkPayload::Virtual(reference) => {
+                self.kinds.push(VIRTUAL_PAYLOAD);
+                self.virtual_refs.push(reference.clone());
+            }
+        }
+        // optional columns are filled up to the row once a chunk has them
+        if let Some(origin) = manifest.chunk_origin(node, coord) {
+            self.origins.resize(row, None);
+            self.origins.push(Some(origin.clone()));
+        }
+        let transformers = manifest.chunk_transformers(node, coord);
+        if !transformers.is_empty() {
+            self.transformers.resize(row, Vec::new());
+            self.transformers.push(transformers.to_vec());
+        }
+        if let Some(shard) = manifest.chunk_placement(node, coord) {
+            self.placements.resize(row, None);
+            self.placements.push(Some(shard.to_string()));
+        }
+    }
+
+    /// Fill the optional columns up to the last chunk
+    fn finish(&mut self) {
+        let len = self.len();
+        if !self.origins.is_empty() {
+            self.origins.resize(len, None);
+        }
+        if !self.transformers.is_empty() {
+            self.transformers.resize(len, Vec::new());
+        }
+        if !self.placements.is_empty() {
+            self.placements.resize(len, None);
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let len = self.len();
+        let count = |kind| self.kinds.iter().filter(|k| **k == kind).count();
+        let refs = count(REF_PAYLOAD);
+        let valid = self.coords.iter().all(|column| column.len() == len)
+            && self.kinds.iter().all(|kind| *kind <= VIRTUAL_PAYLOAD)
+            && self.ref_ids.len() == refs
+            && self.ref_offsets.len() == refs
+            && self.ref_lengths.len() == refs
+            && self.inline.len() == count(INLINE_PAYLOAD)
+            && self.virtual_refs.len() == count(VIRTUAL_PAYLOAD)
+            && [self.origins.len(), self.transformers.len(), self.placements.len()]
+                .iter()
+                .all(|column| *column == 0 || *column == len);
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("inconsistent manifest columns for node {}", self.node))
+        }
+    }
+
+    /// The rows with coordinates in `extents`, in order
+    ///
+    /// Each coordinate column is scanned once, narrowing a mask of the rows.
+    pub fn select(&self, extents: &ManifestExtents) -> Vec<usize> {
+        let [from, to] = extents.0.as_slice() else { return (0..self.len()).collect() };
+        if from.0.len() != self.coords.len() || to.0.len() != self.coords.len() {
+            return Vec::new();
+        }
+        let mut mask = vec![true; self.len()];
+        for ((column, from), to) in self.coords.iter().zip(from.0.iter()).zip(to.0.iter())
+        {
+            for (keep, coord) in mask.iter_mut().zip(column.iter()) {
+                *keep &= from <= coord && coord < to;
+            }
+        }
+        mask.iter().positions(|keep| *keep).collect()
+    }
+
+    fn coord(&self, row: usize) -> ChunkIndices {
+        ChunkIndices(self.coords.iter().map(|column| column[row]).collect())
+    }
+
+    /// The coordinates and payloads of `rows`, in increasing order
+    ///
+    /// The position of a payload in the column of its kind is the number of chunks of the
+    /// same kind before it, counted in a single pass over the chunks up to the last row.
+    pub fn payloads(&self, rows: &[usize]) -> Vec<(ChunkIndices, ChunkPayload)> {
+        let mut res = Vec::with_capacity(rows.len());
+        let mut seen = [0usize; 3];
+        let mut rows = rows.iter().peekable();
+        for (row, kind) in self.kinds.iter().enumerate() {
+            let Some(next) = rows.peek() else { break };
+            let index = seen[*kind as usize];
+            seen[*kind as usize] += 1;
+            if **next != row {
+                continue;
+            }
+            rows.next();
+            let payload = match *kind {
+                REF_PAYLOAD => ChunkPayload::Ref(ChunkRef {
+                    id: self.ref_ids[index].clone(),
+                    offset: self.ref_offsets[index],
+                    length: self.ref_lengths[index],
+                }),
+                INLINE_PAYLOAD => ChunkPayload::Inline(self.inline[index].clone()),
+                _ => ChunkPayload::Virtual(self.virtual_refs[index].clone()),
+            };
+            res.push((self.coord(row), payload));
+        }
+        res
+    }
+}
+
+/// A manifest in the columnar layout, see the [module documentation](self)
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ColumnarManifest {
+    pub icechunk_manifest_format_version: IcechunkFormatVersion,
+    pub icechunk_manifest_format_flags: BTreeMap<String, rmpv::Value>,
+    arrays: Vec<ArrayColumns>,
+}
+
+impl ColumnarManifest {
+    /// Decode a serialized manifest, in either layout
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+        let columns = match rmp_serde::from_slice(bytes)? {
+            Layout::Columnar(columns) => columns,
+            Layout::Rows(manifest) => return Ok(Self::from(&manifest)),
+        };
+        for array in columns.arrays.iter() {
+            array.validate().map_err(rmp_serde::decode::Error::Syntax)?;
+        }
+        Ok(columns)
+    }
+
+    /// The chunks of `node` with coordinates in `extents`, in coordinate order
+    pub fn select(
+        &self,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> Vec<(ChunkIndices, ChunkPayload)> {
+        self.arrays
+            .iter()
+            .filter(|array| &array.node == node)
+            .flat_map(|array| array.payloads(&array.select(extents)))
+            .collect()
+    }
+
+    pub fn arrays(&self) -> &[ArrayColumns] {
+        &self.arrays
+    }
+
+    pub fn len(&self) -> usize {
+        self.arrays.iter().map(ArrayColumns::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_manifest(self) -> Result<Manifest, String> {
+        let mut chunks = BTreeMap::new();
+        let mut origins = BTreeMap::new();
+        let mut transformers = BTreeMap::new();
+        let mut placements = BTreeMap::new();
+        for array in self.arrays {
+            array.validate()?;
+            let all: Vec<usize> = (0..array.len()).collect();
+            for (row, (coord, payload)) in array.payloads(&all).into_iter().enumerate() {
+                let key = (array.node.clone(), coord);
+                if let Some(Some(origin)) = array.origins.get(row) {
+                    origins.insert(key.clone(), origin.clone());
+                }
+                if let Some(ids) =
+                    array.transformers.get(row).filter(|ids| !ids.is_empty())
+                {
+                    transformers.insert(key.clone(), ids.clone());
+                }
+                if let Some(Some(shard)) = array.placements.get(row) {
+                    placements.insert(key.clone(), shard.clone());
+                }
+                chunks.insert(key, payload);
+            }
+        }
+        let mut manifest = Manifest::new(chunks)
+            .with_origins(origins)
+            .with_transformers(transformers)
+            .with_placements(placements);
+        manifest.icechunk_manifest_format_version = self.icechunk_manifest_format_version;
+        manifest.icechunk_manifest_format_flags = self.icechunk_manifest_format_flags;
+        Ok(manifest)
+    }
+}
+
+impl From<&Manifest> for ColumnarManifest {
+    fn from(manifest: &Manifest) -> Self {
+        let mut arrays: Vec<ArrayColumns> = Vec::new();
+        for key @ (node, coord) in manifest.chunks().keys() {
+            let ndim = coord.0.len();
+            match arrays.last_mut() {
+                Some(array) if &array.node == node && array.coords.len() == ndim => {
+                    array.push(manifest, key)
+                }
+                _ => {
+                    let mut array = ArrayColumns {
+                        node: node.clone(),
+                        coords: vec![Vec::new(); ndim],
+                        kinds: Vec::new(),
+                        ref_ids: Vec::new(),
+                        ref_offsets: Vec::new(),
+                        ref_lengths: Vec::new(),
+                        inline: Vec::new(),
+                        virtual_refs: Vec::new(),
+                        origins: Vec::new(),
+                        transformers: Vec::new(),
+                        placements: Vec::new(),
+                    };
+                    array.push(manifest, key);
+                    arrays.push(array);
+                }
+            }
+        }
+        arrays.iter_mut().for_each(ArrayColumns::finish);
+        Self {
+            icechunk_manifest_format_version: manifest
+                .icechunk_manifest_format_version
+                .max(COLUMNAR_MANIFEST_FORMAT),
+            icechunk_manifest_format_flags: manifest
+                .icechunk_manifest_format_flags
+                .clone(),
+            arrays,
+        }
+    }
+}
+
+/// A serialized manifest, told apart by its format version
+enum Layout {
+    Columnar(ColumnarManifest),
+    Rows(Manifest),
+}
+
+impl<'de> Deserialize<'de> for Layout {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_seq(LayoutVisitor)
+    }
+}
+
+struct LayoutVisitor;
+
+impl<'de> Visitor<'de> for LayoutVisitor {
+    type Value = Layout;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a manifest")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Layout, A::Error> {
+        let version: IcechunkFormatVersion =
+            seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let flags =
+            seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        if version >= COLUMNAR_MANIFEST_FORMAT {
+            let arrays =
+                seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+            return Ok(Layout::Columnar(ColumnarManifest {
+                icechunk_manifest_format_version: version,
+                icechunk_manifest_format_flags: flags,
+                arrays,
+            }));
+        }
+        let chunks =
+            seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        // records added after the first row layout
+        let origins = seq.next_element()?.unwrap_or_default();
+        let transformers = seq.next_element()?.unwrap_or_default();
+        let placements = seq.next_element()?.unwrap_or_default();
+        let mut manifest = Manifest::new(chunks)
+            .with_origins(origins)
+            .with_transformers(transformers)
+            .with_placements(placements);
+        manifest.icechunk_manifest_format_version = version;
+        manifest.icechunk_manifest_format_flags = flags;
+        Ok(Layout::Rows(manifest))
+    }
+}
+
+impl Serialize for Manifest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.icechunk_manifest_format_version >= COLUMNAR_MANIFEST_FORMAT {
+            return ColumnarManifest::from(self).serialize(serializer);
+        }
+        (
+            self.icechunk_manifest_format_version,
+            &self.icechunk_manifest_format_flags,
+            self.chunks(),
+            self.origins(),
+            self.transformers(),
+            self.placements(),
+        )
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Manifest {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        match Layout::deserialize(d)? {
+            Layout::Columnar(columns) => {
+                columns.into_manifest().map_err(de::Error::custom)
+            }
+            Layout::Rows(manifest) => Ok(manifest),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::format::manifest::VirtualChunkLocation;
+
+    /// A columnar manifest with a 4x4 array, the node of the array, and a zero dimensional
+    /// array
+    fn sample() -> Result<(Manifest, NodeId), Box<dyn Error>> {
+        let node = NodeId::random();
+        let other = NodeId::random();
+        let mut chunks = BTreeMap::new();
+        for x in 0..4u64 {
+            for y in 0..4u64 {
+                let payload = match (x + y) % 3 {
+                    0 => ChunkPayload::Ref(ChunkRef {
+                        id: ChunkId::random(),
+                        offset: x,
+                        length: y,
+                    }),
+                    1 => ChunkPayload::Inline(Bytes::from(vec![x as u8, y as u8])),
+                    _ => ChunkPayload::Virtual(VirtualChunkRef {
+                        location: VirtualChunkLocation::from_absolute_path(
+                            "s3://bucket/file",
+                        )?,
+                        offset: x,
+                        length: y,
+                        slice: None,
+                    }),
+                };
+                chunks.insert((node.clone(), ChunkIndices(vec![x, y])), payload);
+            }
+        }
+        chunks.insert(
+            (other.clone(), ChunkIndices(vec![])),
+            ChunkPayload::Inline(Bytes::new()),
+        );
+        let origin = SnapshotId::random();
+        let key = (node.clone(), ChunkIndices(vec![1, 2]));
+        let manifest = Manifest::new(chunks)
+            .with_origins(BTreeMap::from([(key.clone(), origin)]))
+            .with_transformers(BTreeMap::from([(key.clone(), vec!["zstd".to_string()])]))
+            .with_placements(BTreeMap::from([(key, "east".to_string())]))
+            .with_format_version(COLUMNAR_MANIFEST_FORMAT);
+        Ok((manifest, node))
+    }
+
+    #[test]
+    fn test_columnar_roundtrip() -> Result<(), Box<dyn Error>> {
+        let (manifest, _) = sample()?;
+        let bytes = rmp_serde::to_vec(&manifest)?;
+        let decoded: Manifest = rmp_serde::from_slice(&bytes)?;
+        assert_eq!(decoded.chunks(), manifest.chunks());
+        assert_eq!(decoded.origins(), manifest.origins());
+        assert_eq!(decoded.transformers(), manifest.transformers());
+        assert_eq!(decoded.placements(), manifest.placements());
+        assert_eq!(decoded.icechunk_manifest_format_version, COLUMNAR_MANIFEST_FORMAT);
+        let columns = ColumnarManifest::from_slice(&bytes)?;
+        assert_eq!(columns.arrays().len(), 2);
+        assert_eq!(columns.len(), 17);
+
+        // manifests keep the row layout unless they opt in
+        let manifest = manifest.with_format_version(0);
+        let rows = rmp_serde::to_vec(&manifest)?;
+        assert!(rows.len() > bytes.len());
+        let decoded: Manifest = rmp_serde::from_slice(&rows)?;
+        assert_eq!(decoded, manifest);
+        assert_eq!(ColumnarManifest::from_slice(&rows)?.len(), 17);
+
+        // manifests written before the transformers and placements records
+        let rows = rmp_serde::to_vec(&(
+            0 as IcechunkFormatVersion,
+            BTreeMap::<String, rmpv::Value>::new(),
+            manifest.chunks(),
+            manifest.origins(),
+        ))?;
+        let decoded: Manifest = rmp_serde::from_slice(&rows)?;
+        assert_eq!(decoded.chunks(), manifest.chunks());
+        assert_eq!(decoded.origins(), manifest.origins());
+        assert!(decoded.transformers().is_empty());
+        assert_eq!(decoded.icechunk_manifest_format_version, 0);
+        assert_eq!(ColumnarManifest::from_slice(&rows)?.len(), 17);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_by_coordinates() -> Result<(), Box<dyn Error>> {
+        let (manifest, node) = sample()?;
+        let columns = ColumnarManifest::from_slice(&rmp_serde::to_vec(&manifest)?)?;
+        let extents =
+            ManifestExtents::new(ChunkIndices(vec![1, 2]), ChunkIndices(vec![3, 4]));
+        let selected = columns.select(&node, &extents);
+        let expected: Vec<_> = manifest
+            .chunks()
+            .iter()
+            .filter(|((n, coord), _)| n == &node && extents.contains(coord))
+            .map(|((_, coord), payload)| (coord.clone(), payload.clone()))
+            .collect();
+        assert_eq!(selected.len(), 4);
+        assert_eq!(selected, expected);
+        assert_eq!(
+            manifest
+                .chunks_in_region(&node, &extents)
+                .map(|(coord, payload)| (coord.clone(), payload.clone()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(columns.select(&node, &ManifestExtents(vec![])).len(), 16);
+        // a region with other dimensions has no chunks
+        let flat = ManifestExtents::new(ChunkIndices(vec![0]), ChunkIndices(vec![4]));
+        assert_eq!(columns.select(&node, &flat), vec![]);
+        Ok(())
+    }
+}
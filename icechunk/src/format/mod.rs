@@ -3,6 +3,7 @@ use std::{
     fmt::{Debug, Display},
     hash::Hash,
     marker::PhantomData,
+    num::NonZeroU64,
     ops::Range,
 };
 
@@ -17,8 +18,12 @@ use typed_path::Utf8UnixPathBuf;
 use crate::{metadata::DataType, private};
 
 pub mod attributes;
+pub mod framing;
+pub mod hashing;
 pub mod manifest;
+pub mod manifest_columns;
 pub mod snapshot;
+pub mod snapshot_segments;
 pub mod transaction_log;
 
 #[serde_as]
@@ -29,6 +34,15 @@ pub struct Path(#[serde_as(as = "TryFromInto<String>")] Utf8UnixPathBuf);
 pub trait FileTypeTag: private::Sealed {}
 
 /// The id of a file in object store
+///
+/// The tag makes ids of different kinds distinct types, passing a [`ChunkId`] where a
+/// [`SnapshotId`] is expected doesn't compile:
+///
+/// ```compile_fail
+/// use icechunk::format::{ChunkId, SnapshotId};
+///
+/// let id: SnapshotId = ChunkId::random();
+/// ```
 #[derive(Hash, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ObjectId<const SIZE: usize, T: FileTypeTag>(pub [u8; SIZE], PhantomData<T>);
 
@@ -139,7 +153,74 @@ impl<'de, const SIZE: usize, T: FileTypeTag> Deserialize<'de> for ObjectId<SIZE,
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 /// An ND index to an element in a chunk grid.
-pub struct ChunkIndices(pub Vec<u32>);
+pub struct ChunkIndices(pub Vec<u64>);
+
+/// A regular grid of chunks, with the first chunk starting at `origin`
+///
+/// Coordinates on the grid are signed, so the origin can be negative, while elements and
+/// chunks are indexed from the origin, over the whole `u64` range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkGrid {
+    pub chunk_shape: Vec<NonZeroU64>,
+    pub origin: Vec<i64>,
+}
+
+impl ChunkGrid {
+    /// A grid with its origin at zero
+    pub fn new(chunk_shape: Vec<NonZeroU64>) -> Self {
+        let origin = vec![0; chunk_shape.len()];
+        Self { chunk_shape, origin }
+    }
+
+    pub fn with_origin(mut self, origin: Vec<i64>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// The indices of the element at grid `coords`, `None` if it's before the origin, or
+    /// `coords` has another number of dimensions
+    pub fn element_indices(&self, coords: &[i64]) -> Option<Vec<u64>> {
+        if coords.len() != self.origin.len() {
+            return None;
+        }
+        coords
+            .iter()
+            .zip(self.origin.iter())
+            .map(|(coord, origin)| {
+                u64::try_from(i128::from(*coord) - i128::from(*origin)).ok()
+            })
+            .collect()
+    }
+
+    /// The chunk of the element at grid `coords`
+    pub fn chunk_of(&self, coords: &[i64]) -> Option<ChunkIndices> {
+        let indices = self.element_indices(coords)?;
+        Some(ChunkIndices(
+            indices
+                .iter()
+                .zip(self.chunk_shape.iter())
+                .map(|(i, size)| i / size.get())
+                .collect(),
+        ))
+    }
+
+    /// The grid coordinates where `chunk` starts, `None` if they don't fit in an `i64`
+    pub fn chunk_start(&self, chunk: &ChunkIndices) -> Option<Vec<i64>> {
+        if chunk.0.len() != self.origin.len() {
+            return None;
+        }
+        chunk
+            .0
+            .iter()
+            .zip(self.chunk_shape.iter().zip(self.origin.iter()))
+            .map(|(index, (size, origin))| {
+                let start =
+                    i128::from(*index) * i128::from(size.get()) + i128::from(*origin);
+                i64::try_from(start).ok()
+            })
+            .collect()
+    }
+}
 
 pub type ChunkOffset = u64;
 pub type ChunkLength = u64;
@@ -210,10 +291,24 @@ pub enum IcechunkFormatError {
     FillValueDecodeError { found_size: usize, target_size: usize, target_type: DataType },
     #[error("error decoding fill_value from json")]
     FillValueParse { data_type: DataType, value: serde_json::Value },
+    #[error("fill value `{fill_value}` is not valid for data type `{data_type}`")]
+    FillValueMismatch { data_type: DataType, fill_value: String },
+    #[error("invalid endianness for the bytes codec `{0}`, it must be little or big")]
+    InvalidEndianness(serde_json::Value),
+    #[error("codec `{codec}` cannot encode elements of data type `{data_type}`")]
+    CodecMismatch { data_type: DataType, codec: String },
+    #[error("chunk shape with {chunk_ndim} dimensions for an array with {ndim}")]
+    ChunkShapeMismatch { ndim: usize, chunk_ndim: usize },
+    #[error("grid origin with {origin_ndim} dimensions for an array with {ndim}")]
+    GridOriginMismatch { ndim: usize, origin_ndim: usize },
     #[error("node not found at `{path:?}`")]
     NodeNotFound { path: Path },
     #[error("chunk coordinates not found `{coords:?}`")]
     ChunkCoordinatesNotFound { coords: ChunkIndices },
+    #[error(
+        "user attributes not found at location {location} of attributes file `{id}`"
+    )]
+    UserAttributesNotFound { id: AttributesId, location: TableOffset },
 }
 
 pub type IcechunkResult<T> = Result<T, IcechunkFormatError>;
@@ -223,6 +318,7 @@ pub type IcechunkFormatVersion = u16;
 pub mod format_constants {
     use super::IcechunkFormatVersion;
 
+    /// The row layout, the columnar layout of [`crate::format::manifest_columns`] is opt-in
     pub const LATEST_ICECHUNK_MANIFEST_FORMAT: IcechunkFormatVersion = 0;
     pub const LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE: &str = "application/msgpack";
     pub const LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY: &str = "ic-man-fmt-ver";
@@ -235,6 +331,10 @@ pub mod format_constants {
     pub const LATEST_ICECHUNK_TRANSACTION_LOG_CONTENT_TYPE: &str = "application/msgpack";
     pub const LATEST_ICECHUNK_TRANSACTION_LOG_VERSION_METADATA_KEY: &str =
         "ic-tx-fmt-ver";
+
+    pub const LATEST_ICECHUNK_ATTRIBUTES_FORMAT: IcechunkFormatVersion = 0;
+    pub const LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE: &str = "application/msgpack";
+    pub const LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY: &str = "ic-att-fmt-ver";
 }
 
 impl Display for Path {
@@ -250,13 +350,42 @@ pub enum PathError {
     NotAbsolute,
     #[error(r#"path must be cannonic, cannot include "." or "..""#)]
     NotCanonic,
+    #[error("path cannot include the character {0:?}")]
+    ForbiddenCharacter(char),
+    #[error("path has {depth} components, the maximum is {}", Path::MAX_DEPTH)]
+    TooDeep { depth: usize },
+    #[error("path has {length} bytes, the maximum is {}", Path::MAX_LENGTH)]
+    TooLong { length: usize },
+}
+
+/// Characters that would let two paths that look the same name different nodes
+fn is_forbidden(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            // zero width spaces and joiners, direction marks and overrides, byte order mark
+            '\u{200B}'..='\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}'
+                | '\u{FEFF}'
+        )
 }
 
 impl Path {
+    /// Maximum number of components of a path
+    pub const MAX_DEPTH: usize = 128;
+    /// Maximum length of a path, in bytes
+    pub const MAX_LENGTH: usize = 4096;
+
     pub fn root() -> Path {
         Path(Utf8UnixPathBuf::from("/".to_string()))
     }
 
+    /// Validate a canonic absolute path, like `/a/b`
+    ///
+    /// Names are compared by code point, they are not Unicode normalized, but invisible
+    /// characters, like control characters or zero width spaces, are rejected.
     pub fn new(path: &str) -> Result<Path, PathError> {
         let buf = Utf8UnixPathBuf::from(path);
         if !buf.is_absolute() {
@@ -266,9 +395,29 @@ impl Path {
         if buf.normalize() != buf {
             return Err(PathError::NotCanonic);
         }
+        if let Some(c) = path.chars().find(|c| is_forbidden(*c)) {
+            return Err(PathError::ForbiddenCharacter(c));
+        }
+        if path.len() > Path::MAX_LENGTH {
+            return Err(PathError::TooLong { length: path.len() });
+        }
+        let depth = buf.components().count() - 1;
+        if depth > Path::MAX_DEPTH {
+            return Err(PathError::TooDeep { depth });
+        }
         Ok(Path(buf))
     }
 
+    /// Validate a path from user input, accepting every spelling of the same node: without
+    /// the leading `/`, with repeated or trailing `/`, or with `.` components
+    pub fn normalized(path: &str) -> Result<Path, PathError> {
+        let mut components = path.split('/').filter(|c| !c.is_empty() && *c != ".");
+        if components.clone().any(|c| c == "..") {
+            return Err(PathError::NotCanonic);
+        }
+        Path::new(format!("/{}", components.join("/")).as_str())
+    }
+
     pub fn starts_with(&self, other: &Path) -> bool {
         self.0.starts_with(&other.0)
     }
@@ -276,6 +425,21 @@ impl Path {
     pub fn ancestors(&self) -> impl Iterator<Item = Path> + '_ {
         self.0.ancestors().map(|p| Path(p.to_owned()))
     }
+
+    /// The path without its last component, `None` for the root
+    pub fn parent(&self) -> Option<Path> {
+        self.0.parent().map(|p| Path(p.to_owned()))
+    }
+
+    /// The last component of the path, `None` for the root
+    pub fn name(&self) -> Option<&str> {
+        self.0.file_name()
+    }
+
+    /// Append a component to the path
+    pub fn child(&self, name: &str) -> Result<Path, PathError> {
+        Path::new(self.0.join(name).as_str())
+    }
 }
 
 impl TryFrom<&str> for Path {
@@ -308,6 +472,66 @@ mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn test_path_validation() {
+        let expected = Path::new("/a/b").unwrap();
+        for spelling in ["a/b", "/a/b/", "//a//b", "./a/./b"] {
+            assert_eq!(Path::normalized(spelling), Ok(expected.clone()));
+        }
+        assert_eq!(Path::normalized(""), Ok(Path::root()));
+        assert_eq!(Path::new("a/b"), Err(PathError::NotAbsolute));
+        assert_eq!(Path::new("/a/../b"), Err(PathError::NotCanonic));
+        assert_eq!(Path::normalized("a/../b"), Err(PathError::NotCanonic));
+
+        assert_eq!(Path::new("/caf\u{e9}/\u{1F30A}").unwrap().name(), Some("\u{1F30A}"));
+        assert_eq!(
+            Path::new("/a\u{200B}"),
+            Err(PathError::ForbiddenCharacter('\u{200B}'))
+        );
+        assert_eq!(Path::new("/a\nb"), Err(PathError::ForbiddenCharacter('\n')));
+
+        let deep = "/a".repeat(Path::MAX_DEPTH);
+        assert!(Path::new(&deep).is_ok());
+        assert_eq!(
+            Path::new(&format!("{deep}/a")),
+            Err(PathError::TooDeep { depth: Path::MAX_DEPTH + 1 })
+        );
+        let long = format!("/{}", "a".repeat(Path::MAX_LENGTH));
+        assert_eq!(
+            Path::new(&long),
+            Err(PathError::TooLong { length: Path::MAX_LENGTH + 1 })
+        );
+    }
+
+    #[test]
+    fn test_chunk_grid() {
+        let grid = ChunkGrid::new(vec![NonZeroU64::new(10).unwrap(); 2])
+            .with_origin(vec![-25, 0]);
+        assert_eq!(grid.chunk_of(&[-25, 0]), Some(ChunkIndices(vec![0, 0])));
+        assert_eq!(grid.chunk_of(&[-16, 9]), Some(ChunkIndices(vec![0, 0])));
+        assert_eq!(grid.chunk_of(&[-15, 10]), Some(ChunkIndices(vec![1, 1])));
+        assert_eq!(grid.chunk_of(&[-26, 0]), None);
+        assert_eq!(grid.chunk_of(&[0]), None);
+        assert_eq!(grid.chunk_start(&ChunkIndices(vec![3, 1])), Some(vec![5, 10]));
+
+        // the whole range of chunk indices
+        let grid = ChunkGrid::new(vec![NonZeroU64::MIN]).with_origin(vec![i64::MIN]);
+        let last = ChunkIndices(vec![u64::MAX]);
+        assert_eq!(grid.chunk_of(&[i64::MAX]), Some(last.clone()));
+        assert_eq!(grid.chunk_start(&last), Some(vec![i64::MAX]));
+        let grid = ChunkGrid::new(vec![NonZeroU64::new(2).unwrap()]);
+        assert_eq!(grid.chunk_start(&last), None);
+
+        let serialized = rmp_serde::to_vec(&last).unwrap();
+        assert_eq!(rmp_serde::from_slice::<ChunkIndices>(&serialized).unwrap(), last);
+        // indices written when they were `u32`
+        let old = rmp_serde::to_vec(&vec![1u32, u32::MAX]).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<ChunkIndices>(&old).unwrap(),
+            ChunkIndices(vec![1, u32::MAX as u64])
+        );
+    }
+
     #[test]
     fn test_object_id_serialization() {
         let sid = SnapshotId::new([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
@@ -4,19 +4,21 @@ use std::{
     sync::Arc,
 };
 
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::metadata::{
-    ArrayShape, ChunkKeyEncoding, ChunkShape, Codec, DataType, DimensionNames, FillValue,
-    StorageTransformer, UserAttributes,
+    vlen::{VLEN_BYTES_CODEC, VLEN_UTF8_CODEC},
+    ArrayShape, ChunkKeyEncoding, ChunkShape, Codec, DataType, DimensionNames,
+    Endianness, FillValue, StorageTransformer, UserAttributes,
 };
 
 use super::{
-    format_constants, manifest::ManifestRef, AttributesId, IcechunkFormatError,
-    IcechunkFormatVersion, IcechunkResult, ManifestId, NodeId, ObjectId, Path,
-    SnapshotId, TableOffset,
+    format_constants, manifest::ManifestRef, AttributesId, ChunkGrid,
+    IcechunkFormatError, IcechunkFormatVersion, IcechunkResult, ManifestId, NodeId,
+    ObjectId, Path, SnapshotId, TableOffset,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -35,6 +37,7 @@ pub enum UserAttributesSnapshot {
 pub enum NodeType {
     Group,
     Array,
+    Custom,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -47,12 +50,155 @@ pub struct ZarrArrayMetadata {
     pub codecs: Vec<Codec>,
     pub storage_transformers: Option<Vec<StorageTransformer>>,
     pub dimension_names: Option<DimensionNames>,
+    /// The ids of the [`crate::chunk_transformer::ChunkTransformer`]s applied to new chunks
+    /// of the array, in order. `None` uses the ones configured in the session. It's not part
+    /// of the Zarr metadata.
+    #[serde(default)]
+    pub chunk_transformers: Option<Vec<String>>,
+    /// Where the chunk grid starts, in signed coordinates, `None` is at zero. Array indexes
+    /// are grid coordinates, so with a negative origin the first chunk starts before the
+    /// array, and with a positive one the indexes before it can't be written. It's not part
+    /// of the Zarr metadata.
+    #[serde(default)]
+    pub grid_origin: Option<Vec<i64>>,
+}
+
+impl ZarrArrayMetadata {
+    /// A zero dimensional array, holding a single value in a single chunk, stored
+    /// uncompressed in little endian
+    pub fn scalar(data_type: DataType, fill_value: FillValue) -> Self {
+        Self {
+            shape: vec![],
+            data_type,
+            chunk_shape: ChunkShape(vec![]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value,
+            codecs: vec![Codec {
+                name: "bytes".to_string(),
+                configuration: Some(
+                    [("endian".to_string(), Value::from("little"))].into_iter().collect(),
+                ),
+            }],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        }
+    }
+
+    pub fn chunk_grid(&self) -> ChunkGrid {
+        let grid = ChunkGrid::new(self.chunk_shape.0.clone());
+        match self.grid_origin.as_ref() {
+            Some(origin) => grid.with_origin(origin.clone()),
+            None => grid,
+        }
+    }
+
+    /// Zero dimensional arrays have a single chunk, at the empty coordinates
+    pub fn is_scalar(&self) -> bool {
+        self.shape.is_empty()
+    }
+
+    /// Check only zero dimensional arrays have an empty chunk shape, the fill value matches
+    /// the data type, the codec that turns elements into bytes matches the data type, and
+    /// the `bytes` codec has a valid endianness
+    pub fn validate(&self) -> IcechunkResult<()> {
+        if self.chunk_shape.0.is_empty() != self.shape.is_empty() {
+            return Err(IcechunkFormatError::ChunkShapeMismatch {
+                ndim: self.shape.len(),
+                chunk_ndim: self.chunk_shape.0.len(),
+            });
+        }
+        if let Some(origin) = self.grid_origin.as_ref() {
+            if origin.len() != self.shape.len() {
+                return Err(IcechunkFormatError::GridOriginMismatch {
+                    ndim: self.shape.len(),
+                    origin_ndim: origin.len(),
+                });
+            }
+        }
+        if !self.fill_value.is_valid_for(&self.data_type) {
+            return Err(IcechunkFormatError::FillValueMismatch {
+                data_type: self.data_type.clone(),
+                fill_value: format!("{:?}", self.fill_value),
+            });
+        }
+        let bytes_codec = self.data_type.vlen_codec().unwrap_or("bytes");
+        if let Some(codec) = self.codecs.iter().find(|codec| {
+            matches!(codec.name.as_str(), "bytes" | VLEN_UTF8_CODEC | VLEN_BYTES_CODEC)
+                && codec.name != bytes_codec
+        }) {
+            return Err(IcechunkFormatError::CodecMismatch {
+                data_type: self.data_type.clone(),
+                codec: codec.name.clone(),
+            });
+        }
+        for codec in self.codecs.iter().filter(|codec| codec.name == "bytes") {
+            let endian = codec.configuration.as_ref().and_then(|conf| conf.get("endian"));
+            match endian {
+                None => {}
+                Some(Value::String(endian))
+                    if Endianness::try_from(endian.as_str()).is_ok() => {}
+                Some(other) => {
+                    return Err(IcechunkFormatError::InvalidEndianness(other.clone()))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The size in bytes of every chunk, if chunks are stored without compression
+    ///
+    /// Returns `None` for variable length data types, or if there are codecs other than
+    /// `bytes`.
+    pub fn uncompressed_chunk_size_bytes(&self) -> Option<u64> {
+        if self.codecs.is_empty() || self.codecs.iter().any(|codec| codec.name != "bytes")
+        {
+            return None;
+        }
+        Some(self.chunk_elements() * self.data_type.size_bytes()?)
+    }
+
+    /// The number of elements of every chunk, including the padding of edge chunks
+    pub fn chunk_elements(&self) -> u64 {
+        self.chunk_shape.0.iter().map(|n| n.get()).product()
+    }
+
+    /// Chunks are stored as written by the vlen codec of the data type, with no other codec
+    pub fn is_uncompressed_vlen(&self) -> bool {
+        match (self.data_type.vlen_codec(), self.codecs.as_slice()) {
+            (Some(vlen), [codec]) => codec.name == vlen,
+            _ => false,
+        }
+    }
+
+    /// The position in the shape of the dimension called `name`
+    pub fn dimension_index(&self, name: &str) -> Option<usize> {
+        self.dimension_names.as_ref()?.iter().position(|dim| dim.as_deref() == Some(name))
+    }
+
+    /// An array is a coordinate for `dimension` if it's one dimensional, and its only
+    /// dimension is `dimension`, as in the xarray data model
+    pub fn is_coordinate_for(&self, dimension: &str) -> bool {
+        self.shape.len() == 1 && self.dimension_index(dimension) == Some(0)
+    }
+}
+
+/// A node of a user defined kind, like "table" or "mesh"
+///
+/// Icechunk doesn't interpret `metadata`, it's only validated, on write, by the
+/// [`crate::repository::NodeKindSchema`] registered for `kind`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CustomNodeData {
+    pub kind: String,
+    pub metadata: Bytes,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NodeData {
     Array(ZarrArrayMetadata, Vec<ManifestRef>),
     Group,
+    Custom(CustomNodeData),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -68,6 +214,7 @@ impl NodeSnapshot {
         match &self.node_data {
             NodeData::Group => NodeType::Group,
             NodeData::Array(_, _) => NodeType::Array,
+            NodeData::Custom(_) => NodeType::Custom,
         }
     }
 }
@@ -181,11 +328,54 @@ impl Snapshot {
     }
 
     pub fn empty() -> Self {
-        let metadata =
-            SnapshotMetadata::with_message(Self::INITIAL_COMMIT_MESSAGE.to_string());
+        Self::initial(Self::INITIAL_COMMIT_MESSAGE.to_string(), None, vec![])
+    }
+
+    /// The first snapshot of a repository, with no parents, starting with `nodes`
+    pub fn initial<T: IntoIterator<Item = NodeSnapshot>>(
+        message: String,
+        properties: Option<SnapshotProperties>,
+        iter: T,
+    ) -> Self {
+        let nodes = iter.into_iter().map(|node| (node.path.clone(), node)).collect();
+        let metadata = SnapshotMetadata::with_message(message);
         Self {
             metadata,
-            ..Self::new(VecDeque::new(), 0, None, Default::default(), vec![], vec![])
+            ..Self::new(VecDeque::new(), 0, properties, nodes, vec![], vec![])
+        }
+    }
+
+    /// The same snapshot, with the history of `replaced`, to take its place in a branch
+    pub fn with_history_of(&self, replaced: &Snapshot) -> Self {
+        Self {
+            icechunk_snapshot_format_version: self.icechunk_snapshot_format_version,
+            icechunk_snapshot_format_flags: self.icechunk_snapshot_format_flags.clone(),
+            manifest_files: self.manifest_files.clone(),
+            attribute_files: self.attribute_files.clone(),
+            total_parents: replaced.total_parents,
+            short_term_parents: replaced.short_term_parents,
+            short_term_history: replaced.short_term_history.clone(),
+            metadata: self.metadata.clone(),
+            started_at: self.started_at,
+            properties: self.properties.clone(),
+            nodes: self.nodes.clone(),
+        }
+    }
+
+    /// The same snapshot, with `nodes` instead of its nodes
+    pub fn with_nodes<T: IntoIterator<Item = NodeSnapshot>>(&self, nodes: T) -> Self {
+        Self {
+            icechunk_snapshot_format_version: self.icechunk_snapshot_format_version,
+            icechunk_snapshot_format_flags: self.icechunk_snapshot_format_flags.clone(),
+            manifest_files: self.manifest_files.clone(),
+            attribute_files: self.attribute_files.clone(),
+            total_parents: self.total_parents,
+            short_term_parents: self.short_term_parents,
+            short_term_history: self.short_term_history.clone(),
+            metadata: self.metadata.clone(),
+            started_at: self.started_at,
+            properties: self.properties.clone(),
+            nodes: nodes.into_iter().map(|node| (node.path.clone(), node)).collect(),
         }
     }
 
@@ -200,7 +390,25 @@ impl Snapshot {
     }
 
     pub fn iter_arc(self: Arc<Self>) -> impl Iterator<Item = NodeSnapshot> {
-        NodeIterator { table: self, last_key: None }
+        self.iter_arc_from(Bound::Unbounded)
+    }
+
+    /// The nodes from `start` on, in path order
+    pub fn iter_arc_from(
+        self: Arc<Self>,
+        start: Bound<Path>,
+    ) -> impl Iterator<Item = NodeSnapshot> {
+        match start {
+            Bound::Included(start) => {
+                NodeIterator { table: self, first_key: Some(start), last_key: None }
+            }
+            Bound::Excluded(after) => {
+                NodeIterator { table: self, first_key: None, last_key: Some(after) }
+            }
+            Bound::Unbounded => {
+                NodeIterator { table: self, first_key: None, last_key: None }
+            }
+        }
     }
 
     pub fn local_ancestry(self: Arc<Self>) -> impl Iterator<Item = SnapshotMetadata> {
@@ -222,6 +430,7 @@ impl Snapshot {
 // reference to it (in the iterator) in a single self-referential struct
 struct NodeIterator {
     table: Arc<Snapshot>,
+    first_key: Option<Path>,
     last_key: Option<Path>,
 }
 
@@ -231,7 +440,15 @@ impl Iterator for NodeIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match &self.last_key {
             None => {
-                if let Some((k, v)) = self.table.nodes.first_key_value() {
+                let first = match &self.first_key {
+                    Some(first_key) => self
+                        .table
+                        .nodes
+                        .range::<Path, _>((Bound::Included(first_key), Bound::Unbounded))
+                        .next(),
+                    None => self.table.nodes.first_key_value(),
+                };
+                if let Some((k, v)) = first {
                     self.last_key = Some(k.clone());
                     Some(v.clone())
                 } else {
@@ -300,6 +517,8 @@ mod tests {
                 Some("y".to_string()),
                 Some("t".to_string()),
             ]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
         let zarr_meta2 = ZarrArrayMetadata {
             storage_transformers: None,
@@ -0,0 +1,148 @@
+//! A layout of the nodes of a snapshot that can be read one segment at a time
+//!
+//! The segmented layout is an additional object with the id of its snapshot, the snapshot
+//! itself is still written whole. The object starts with the length of the index, as a
+//! little endian `u64`, then comes the index, and then the segments. Each segment is a run
+//! of consecutive nodes in path order, so the index is enough to find the only segment that
+//! can contain a path. The index and the segments are serialized with MessagePack.
+use bytes::{BufMut, Bytes, BytesMut};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    snapshot::{NodeSnapshot, Snapshot},
+    ByteRange, Path, SnapshotId,
+};
+
+/// The bytes before the index
+pub const INDEX_LENGTH_BYTES: u64 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentInfo {
+    pub first: Path,
+    pub last: Path,
+    pub nodes: u32,
+    /// From the end of the index
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentIndex {
+    pub snapshot: SnapshotId,
+    /// The length of the serialized index, filled in by [`decode_index`]
+    #[serde(skip)]
+    pub length: u64,
+    pub nodes: u64,
+    pub segments: Vec<SegmentInfo>,
+}
+
+impl SegmentIndex {
+    /// The position of the segment that would contain the node at `path`, if any
+    pub fn find(&self, path: &Path) -> Option<usize> {
+        let index = self.segments.partition_point(|segment| &segment.last < path);
+        self.segments.get(index).filter(|segment| &segment.first <= path).map(|_| index)
+    }
+
+    /// The bytes of the segment at `index` in the segmented object
+    pub fn segment_range(&self, index: usize) -> Option<ByteRange> {
+        let segment = self.segments.get(index)?;
+        let start = INDEX_LENGTH_BYTES + self.length + segment.offset;
+        Some(ByteRange::from_offset_with_length(start, segment.length))
+    }
+}
+
+/// Serialize the nodes of `snapshot` in segments of `nodes_per_segment` nodes
+pub fn encode_segments(
+    snapshot: &Snapshot,
+    nodes_per_segment: usize,
+) -> Result<Bytes, rmp_serde::encode::Error> {
+    let mut data = BytesMut::new();
+    let mut segments = Vec::new();
+    for chunk in snapshot.iter().chunks(nodes_per_segment.max(1)).into_iter() {
+        let nodes = chunk.collect::<Vec<_>>();
+        let (Some(first), Some(last)) = (nodes.first(), nodes.last()) else { continue };
+        let bytes = rmp_serde::to_vec(&nodes)?;
+        segments.push(SegmentInfo {
+            first: first.path.clone(),
+            last: last.path.clone(),
+            nodes: nodes.len() as u32,
+            offset: data.len() as u64,
+            length: bytes.len() as u64,
+        });
+        data.put_slice(&bytes);
+    }
+    let index = SegmentIndex {
+        snapshot: snapshot.metadata.id.clone(),
+        length: 0,
+        nodes: snapshot.len() as u64,
+        segments,
+    };
+    let index = rmp_serde::to_vec(&index)?;
+    let mut res = BytesMut::with_capacity(8 + index.len() + data.len());
+    res.put_u64_le(index.len() as u64);
+    res.put_slice(&index);
+    res.put_slice(&data);
+    Ok(res.freeze())
+}
+
+/// The length of the index, from the first [`INDEX_LENGTH_BYTES`] of the object
+pub fn decode_index_length(bytes: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+pub fn decode_index(bytes: &[u8]) -> Result<SegmentIndex, rmp_serde::decode::Error> {
+    let index: SegmentIndex = rmp_serde::from_slice(bytes)?;
+    Ok(SegmentIndex { length: bytes.len() as u64, ..index })
+}
+
+/// The nodes of a segment, in path order
+pub fn decode_segment(
+    bytes: &[u8],
+) -> Result<Vec<NodeSnapshot>, rmp_serde::decode::Error> {
+    rmp_serde::from_slice(bytes)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::format::{snapshot::NodeData, ObjectId};
+
+    #[test]
+    fn test_segment_layout() {
+        let paths = ["/", "/a", "/a/b", "/c", "/d"];
+        let nodes = paths.iter().map(|path| NodeSnapshot {
+            id: ObjectId::random(),
+            path: (*path).try_into().unwrap(),
+            user_attributes: None,
+            node_data: NodeData::Group,
+        });
+        let snapshot =
+            Snapshot::from_iter(&Snapshot::empty(), None, vec![], vec![], nodes);
+        let bytes = encode_segments(&snapshot, 2).unwrap();
+
+        let length = decode_index_length(&bytes[..8]).unwrap() as usize;
+        let index = decode_index(&bytes[8..8 + length]).unwrap();
+        assert_eq!(index.snapshot, snapshot.metadata.id);
+        assert_eq!(index.nodes, 5);
+        assert_eq!(index.segments.iter().map(|s| s.nodes).collect::<Vec<_>>(), [2, 2, 1]);
+
+        for path in paths {
+            let path: Path = path.try_into().unwrap();
+            let segment = index.find(&path).unwrap();
+            let range = index.segment_range(segment).unwrap();
+            let nodes = decode_segment(&range.slice(bytes.clone())).unwrap();
+            assert_eq!(
+                nodes.iter().find(|node| node.path == path),
+                Some(snapshot.get_node(&path).unwrap())
+            );
+        }
+        // between two segments, or after the last one
+        assert_eq!(index.find(&"/a/a".try_into().unwrap()), None);
+        assert_eq!(index.find(&"/e".try_into().unwrap()), None);
+    }
+}
@@ -21,6 +21,12 @@ pub struct TransactionLog {
     pub updated_user_attributes: HashSet<NodeId>,
     pub updated_zarr_metadata: HashSet<NodeId>,
     pub updated_chunks: HashMap<NodeId, HashSet<ChunkIndices>>,
+    #[serde(default)]
+    pub new_custom_nodes: HashSet<NodeId>,
+    #[serde(default)]
+    pub deleted_custom_nodes: HashSet<NodeId>,
+    #[serde(default)]
+    pub updated_custom_nodes: HashSet<NodeId>,
 }
 
 impl TransactionLog {
@@ -31,12 +37,15 @@ impl TransactionLog {
     ) -> Self {
         let new_groups = cs.new_groups().map(|(_, node_id)| node_id).cloned().collect();
         let new_arrays = cs.new_arrays().map(|(_, node_id)| node_id).cloned().collect();
+        let new_custom_nodes =
+            cs.new_custom_nodes().map(|(_, node_id)| node_id).cloned().collect();
         let parent_nodes =
             parent_nodes.map(|n| (n.id.clone(), n.node_type())).collect::<HashSet<_>>();
         let child_nodes =
             child_nodes.map(|n| (n.id.clone(), n.node_type())).collect::<HashSet<_>>();
         let mut deleted_groups = HashSet::new();
         let mut deleted_arrays = HashSet::new();
+        let mut deleted_custom_nodes = HashSet::new();
 
         for (node_id, node_type) in parent_nodes.difference(&child_nodes) {
             // TODO: we shouldn't need the following clones
@@ -47,12 +56,16 @@ impl TransactionLog {
                 NodeType::Array => {
                     deleted_arrays.insert(node_id.clone());
                 }
+                NodeType::Custom => {
+                    deleted_custom_nodes.insert(node_id.clone());
+                }
             }
         }
 
         let updated_user_attributes =
             cs.user_attributes_updated_nodes().cloned().collect();
         let updated_zarr_metadata = cs.zarr_updated_arrays().cloned().collect();
+        let updated_custom_nodes = cs.custom_updated_nodes().cloned().collect();
         let updated_chunks = cs
             .chunk_changes()
             .map(|(k, v)| (k.clone(), v.keys().cloned().collect()))
@@ -66,8 +79,27 @@ impl TransactionLog {
             updated_user_attributes,
             updated_zarr_metadata,
             updated_chunks,
+            new_custom_nodes,
+            deleted_custom_nodes,
+            updated_custom_nodes,
             icechunk_transaction_log_format_version:
                 format_constants::LATEST_ICECHUNK_TRANSACTION_LOG_FORMAT,
         }
     }
+
+    /// Add the changes of `other`, made on top of the changes of `self`
+    pub fn merge(&mut self, other: TransactionLog) {
+        self.new_groups.extend(other.new_groups);
+        self.new_arrays.extend(other.new_arrays);
+        self.deleted_groups.extend(other.deleted_groups);
+        self.deleted_arrays.extend(other.deleted_arrays);
+        self.updated_user_attributes.extend(other.updated_user_attributes);
+        self.updated_zarr_metadata.extend(other.updated_zarr_metadata);
+        for (node, chunks) in other.updated_chunks {
+            self.updated_chunks.entry(node).or_default().extend(chunks);
+        }
+        self.new_custom_nodes.extend(other.new_custom_nodes);
+        self.deleted_custom_nodes.extend(other.deleted_custom_nodes);
+        self.updated_custom_nodes.extend(other.updated_custom_nodes);
+    }
 }
@@ -0,0 +1,442 @@
+//! Generation markers, fencing the destructive maintenance of a repository
+//!
+//! [`crate::ops::gc::garbage_collect`] and [`crate::ops::retention::apply_retention`]
+//! delete or rewrite objects other clients may be reading. Before starting, they take the
+//! next generation of the repository, a namespace of the refs, and they refuse to run if
+//! another operation holds it. When they finish, they take the one after it. A generation
+//! is created only if it doesn't exist, so two operators cannot start at the same time,
+//! even when they list the same current generation.
+//!
+//! Readers note the [`generation_number`] before reading, and [`check_generation`] after:
+//! if it changed, maintenance started or finished in between, and what they read may have
+//! been deleted. Running generations expire after [`MAINTENANCE_TIMEOUT`] without being
+//! renewed, so a crashed operation only blocks maintenance for that long. Operations run
+//! with [`run_fenced`] renew their generation every [`RENEWAL_INTERVAL`], and stop if
+//! another operation took it over.
+use std::future::Future;
+
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{future, pin_mut, stream, StreamExt, TryStreamExt};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    refs::{RefError, RefResult},
+    runtime::Runtime,
+    Storage, StorageError,
+};
+
+const GENERATION_NAME: &str = "generation.repo";
+
+/// A running generation not renewed for this long can be taken over by another operation
+pub const MAINTENANCE_TIMEOUT: TimeDelta = TimeDelta::hours(6);
+
+/// How often [`run_fenced`] renews its generation, well within [`MAINTENANCE_TIMEOUT`]
+pub const RENEWAL_INTERVAL: TimeDelta = TimeDelta::minutes(30);
+
+/// Reads of the current generation retry when it's replaced while they read it
+const GENERATION_READ_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    pub number: u64,
+    pub operation: String,
+    /// Random, identifies the run of the operation
+    pub holder: String,
+    pub started_at: DateTime<Utc>,
+    /// The last renewal, `None` if it was never renewed
+    #[serde(default)]
+    pub renewed_at: Option<DateTime<Utc>>,
+    /// `None` while the operation runs
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Generation {
+    pub fn is_running(&self, now: DateTime<Utc>) -> bool {
+        let alive_at = self.renewed_at.unwrap_or(self.started_at);
+        self.finished_at.is_none() && alive_at + MAINTENANCE_TIMEOUT > now
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum GenerationError {
+    #[error("ref error {0}")]
+    Ref(#[from] RefError),
+    #[error("{operation} started at {started_at} is still running")]
+    InProgress { operation: String, started_at: DateTime<Utc> },
+    #[error("the repository was maintained while reading, generation {observed} is now {current}")]
+    Crossed { observed: u64, current: u64 },
+}
+
+pub type GenerationResult<A> = Result<A, GenerationError>;
+
+fn generation_key(number: u64) -> String {
+    // padded, so generations sort as strings
+    format!("{}/{:020}.json", GENERATION_NAME, number)
+}
+
+/// The last generation of the repository, `None` if it was never maintained
+///
+/// Finishing an operation deletes the generations before it, so the last generation
+/// listed can be gone when it's read. Then there is a newer one, and it's listed again.
+pub async fn current_generation(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Option<Generation>> {
+    let mut attempts = 0;
+    loop {
+        let versions: Vec<String> =
+            storage.ref_versions(GENERATION_NAME).await?.try_collect().await?;
+        let Some(last) = versions.iter().max() else { return Ok(None) };
+        let key = format!("{}/{}", GENERATION_NAME, last);
+        match storage.get_ref(key.as_str()).await {
+            Ok(data) => return Ok(Some(serde_json::from_slice(data.as_ref())?)),
+            Err(StorageError::RefNotFound(_))
+                if attempts + 1 < GENERATION_READ_ATTEMPTS =>
+            {
+                attempts += 1;
+            }
+            Err(StorageError::RefNotFound(_)) => return Err(RefError::RefNotFound(key)),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The number of the last generation, 0 if the repository was never maintained
+pub async fn generation_number(storage: &(dyn Storage + Send + Sync)) -> RefResult<u64> {
+    Ok(current_generation(storage).await?.map_or(0, |g| g.number))
+}
+
+/// Fails if maintenance started or finished since the generation was `observed`
+pub async fn check_generation(
+    storage: &(dyn Storage + Send + Sync),
+    observed: u64,
+) -> GenerationResult<()> {
+    let current = generation_number(storage).await?;
+    if current != observed {
+        return Err(GenerationError::Crossed { observed, current });
+    }
+    Ok(())
+}
+
+async fn write_generation(
+    storage: &(dyn Storage + Send + Sync),
+    generation: &Generation,
+) -> GenerationResult<()> {
+    let content = serde_json::to_vec(generation).map_err(RefError::from)?;
+    match storage
+        .write_ref(
+            generation_key(generation.number).as_str(),
+            false,
+            Bytes::from(content),
+        )
+        .await
+    {
+        Ok(()) => Ok(()),
+        // another operation took it first
+        Err(StorageError::RefAlreadyExists(_)) => {
+            let current = generation_number(storage).await?;
+            Err(GenerationError::Crossed { observed: generation.number - 1, current })
+        }
+        Err(err) => Err(RefError::from(err).into()),
+    }
+}
+
+/// Take a new generation for `operation`, fails if another one is running at `now`
+pub async fn begin_maintenance(
+    storage: &(dyn Storage + Send + Sync),
+    operation: &str,
+    now: DateTime<Utc>,
+) -> GenerationResult<Generation> {
+    let current = current_generation(storage).await?;
+    if let Some(current) = current.as_ref().filter(|c| c.is_running(now)) {
+        return Err(GenerationError::InProgress {
+            operation: current.operation.clone(),
+            started_at: current.started_at,
+        });
+    }
+    let generation = Generation {
+        number: current.map_or(0, |c| c.number) + 1,
+        operation: operation.to_string(),
+        holder: Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+        started_at: now,
+        renewed_at: None,
+        finished_at: None,
+    };
+    write_generation(storage, &generation).await?;
+    Ok(generation)
+}
+
+/// Keep `running` for another [`MAINTENANCE_TIMEOUT`] from `now`
+///
+/// Fails with [`GenerationError::Crossed`] if another operation took over `running` after
+/// it timed out, then the operation must stop.
+pub async fn renew_maintenance(
+    storage: &(dyn Storage + Send + Sync),
+    running: &Generation,
+    now: DateTime<Utc>,
+) -> GenerationResult<Generation> {
+    let current = current_generation(storage).await?;
+    let number = current.as_ref().map_or(0, |c| c.number);
+    if current
+        .as_ref()
+        .is_none_or(|c| c.number != running.number || c.holder != running.holder)
+    {
+        return Err(GenerationError::Crossed {
+            observed: running.number,
+            current: number,
+        });
+    }
+    let renewed = Generation { renewed_at: Some(now), ..running.clone() };
+    let content = serde_json::to_vec(&renewed).map_err(RefError::from)?;
+    storage
+        .write_ref(generation_key(renewed.number).as_str(), true, Bytes::from(content))
+        .await
+        .map_err(RefError::from)?;
+    Ok(renewed)
+}
+
+/// Run `work` fenced by a new generation for `operation`
+///
+/// The generation is renewed every [`RENEWAL_INTERVAL`] while `work` runs, waiting with
+/// `runtime`. If a renewal fails, another operation may be running, so `work` is dropped
+/// and the error returned. The generation is ended with `now`, like it began, whether
+/// `work` succeeds or not.
+pub async fn run_fenced<T, E: From<GenerationError>>(
+    storage: &(dyn Storage + Send + Sync),
+    operation: &str,
+    now: DateTime<Utc>,
+    runtime: &dyn Runtime,
+    work: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let generation = begin_maintenance(storage, operation, now).await?;
+    let keep_generation = async {
+        let every = RENEWAL_INTERVAL.to_std().unwrap_or_default();
+        let mut running = generation.clone();
+        loop {
+            runtime.sleep(every).await;
+            match renew_maintenance(storage, &running, Utc::now()).await {
+                Ok(renewed) => running = renewed,
+                Err(err) => return err,
+            }
+        }
+    };
+    pin_mut!(keep_generation, work);
+    let res = match future::select(work, keep_generation).await {
+        future::Either::Left((res, _)) => res,
+        future::Either::Right((err, _)) => return Err(err.into()),
+    };
+    end_maintenance(storage, &generation, now).await?;
+    res
+}
+
+/// Take the generation after `running`, and delete the older ones
+///
+/// Fails if another operation took over `running` after it timed out.
+pub async fn end_maintenance(
+    storage: &(dyn Storage + Send + Sync),
+    running: &Generation,
+    now: DateTime<Utc>,
+) -> GenerationResult<()> {
+    let finished = Generation {
+        number: running.number + 1,
+        finished_at: Some(now),
+        ..running.clone()
+    };
+    write_generation(storage, &finished).await?;
+    delete_generations_before(storage, finished.number).await
+}
+
+/// Delete the generations before `number`
+///
+/// Only older ones, another operation may have begun a newer generation since `number` was
+/// written, and it's the fence of that operation.
+async fn delete_generations_before(
+    storage: &(dyn Storage + Send + Sync),
+    number: u64,
+) -> GenerationResult<()> {
+    let current_key = generation_key(number);
+    let old: Vec<String> = storage
+        .ref_versions(GENERATION_NAME)
+        .await
+        .map_err(RefError::from)?
+        .map_ok(|version| format!("{}/{}", GENERATION_NAME, version))
+        .try_filter(|key| future::ready(*key < current_key))
+        .try_collect()
+        .await
+        .map_err(RefError::from)?;
+    storage
+        .delete_objects(crate::storage::REF_PREFIX, stream::iter(old).boxed())
+        .await
+        .map_err(RefError::from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc, time::Duration};
+
+    use futures::future::BoxFuture;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        ops::gc::{garbage_collect, GCConfig, GCError},
+        refs::{list_refs, Ref},
+        runtime::TokioRuntime,
+        test_utils::new_in_memory_storage,
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn test_generation_markers() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.commit("main", "first", None).await?;
+        let now = Utc::now();
+        let observed = generation_number(storage.as_ref()).await?;
+        assert_eq!(observed, 0);
+
+        // a running operation blocks the others
+        let running = begin_maintenance(storage.as_ref(), "compaction", now).await?;
+        assert_eq!(running.number, 1);
+        let config = GCConfig::clean_all(now, now, None).with_now(now);
+        assert!(matches!(
+            garbage_collect(storage.as_ref(), &config).await,
+            Err(GCError::Generation(GenerationError::InProgress { .. }))
+        ));
+        assert!(matches!(
+            check_generation(storage.as_ref(), observed).await,
+            Err(GenerationError::Crossed { observed: 0, current: 1 })
+        ));
+
+        // readers notice the end of the operation too
+        end_maintenance(storage.as_ref(), &running, now).await?;
+        assert!(check_generation(storage.as_ref(), 1).await.is_err());
+        garbage_collect(storage.as_ref(), &config).await?;
+        let current = current_generation(storage.as_ref()).await?.unwrap();
+        assert_eq!((current.number, current.operation.as_str()), (4, "gc"));
+        assert!(!current.is_running(now));
+        assert_eq!(storage.ref_versions(GENERATION_NAME).await?.count().await, 1);
+
+        // an operation that never finished times out
+        let crashed = begin_maintenance(storage.as_ref(), "gc", now).await?;
+        let later = now + MAINTENANCE_TIMEOUT;
+        let taken = begin_maintenance(storage.as_ref(), "gc", later).await?;
+        assert!(matches!(
+            end_maintenance(storage.as_ref(), &crashed, later).await,
+            Err(GenerationError::Crossed { .. })
+        ));
+        end_maintenance(storage.as_ref(), &taken, later).await?;
+        assert_eq!(generation_number(storage.as_ref()).await?, 7);
+        assert_eq!(list_refs(storage.as_ref()).await?, vec![Ref::Branch("main".into())]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_end_keeps_newer_generations() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let now = Utc::now();
+        let first = begin_maintenance(storage.as_ref(), "gc", now).await?;
+        let finished = Generation {
+            number: first.number + 1,
+            finished_at: Some(now),
+            ..first.clone()
+        };
+        write_generation(storage.as_ref(), &finished).await?;
+
+        // another operation begins before the first one deletes the old generations
+        let second = begin_maintenance(storage.as_ref(), "retention", now).await?;
+        delete_generations_before(storage.as_ref(), finished.number).await?;
+        assert_eq!(storage.ref_versions(GENERATION_NAME).await?.count().await, 2);
+        assert_eq!(current_generation(storage.as_ref()).await?, Some(second.clone()));
+        assert!(matches!(
+            begin_maintenance(storage.as_ref(), "gc", now).await,
+            Err(GenerationError::InProgress { operation, .. }) if operation == "retention"
+        ));
+
+        end_maintenance(storage.as_ref(), &second, now).await?;
+        assert_eq!(storage.ref_versions(GENERATION_NAME).await?.count().await, 1);
+        assert_eq!(generation_number(storage.as_ref()).await?, 4);
+        Ok(())
+    }
+
+    /// Waits no time, so renewals run back to back
+    #[derive(Debug)]
+    struct Eager;
+
+    impl Runtime for Eager {
+        fn spawn(&self, task: BoxFuture<'static, ()>) {
+            TokioRuntime.spawn(task)
+        }
+
+        fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+            TokioRuntime.spawn_blocking(task)
+        }
+
+        fn sleep(&self, _duration: Duration) -> BoxFuture<'static, ()> {
+            TokioRuntime.sleep(Duration::ZERO)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_renewed_generations_keep_running() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let now = Utc::now();
+        let running = begin_maintenance(storage.as_ref(), "gc", now).await?;
+        let later = now + MAINTENANCE_TIMEOUT - TimeDelta::hours(1);
+        let renewed = renew_maintenance(storage.as_ref(), &running, later).await?;
+        assert_eq!(renewed.renewed_at, Some(later));
+        assert_eq!(current_generation(storage.as_ref()).await?, Some(renewed.clone()));
+
+        // without the renewal it would have timed out
+        let timed_out = now + MAINTENANCE_TIMEOUT + TimeDelta::hours(1);
+        assert!(!running.is_running(timed_out));
+        assert!(matches!(
+            begin_maintenance(storage.as_ref(), "retention", timed_out).await,
+            Err(GenerationError::InProgress { .. })
+        ));
+
+        // once taken over, renewals fail
+        let taken_at = later + MAINTENANCE_TIMEOUT;
+        let taken = begin_maintenance(storage.as_ref(), "retention", taken_at).await?;
+        assert!(matches!(
+            renew_maintenance(storage.as_ref(), &renewed, taken_at).await,
+            Err(GenerationError::Crossed { observed: 1, current: 2 })
+        ));
+        end_maintenance(storage.as_ref(), &taken, taken_at).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lost_generation_stops_the_work() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let now = Utc::now();
+        let work = async {
+            // another operation takes over, as if this one had timed out
+            let later = Utc::now() + MAINTENANCE_TIMEOUT + TimeDelta::hours(1);
+            begin_maintenance(storage.as_ref(), "retention", later).await?;
+            future::pending::<GenerationResult<()>>().await
+        };
+        assert!(matches!(
+            run_fenced(storage.as_ref(), "gc", now, &Eager, work).await,
+            Err(GenerationError::Crossed { observed: 1, current: 2 })
+        ));
+        let current = current_generation(storage.as_ref()).await?.unwrap();
+        assert_eq!((current.number, current.operation.as_str()), (2, "retention"));
+
+        // work that completes ends its generation
+        let after = now + MAINTENANCE_TIMEOUT * 3;
+        let res = run_fenced(storage.as_ref(), "gc", after, &Eager, async {
+            Ok::<_, GenerationError>(42)
+        })
+        .await?;
+        assert_eq!(res, 42);
+        assert!(!current_generation(storage.as_ref()).await?.unwrap().is_running(after));
+        Ok(())
+    }
+}
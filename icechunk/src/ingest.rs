@@ -0,0 +1,164 @@
+//! Bulk writes of chunks from a stream, see [`crate::Repository::ingest`], and streaming
+//! writes committed as they arrive, see [`crate::Repository::ingest_streaming`]
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{
+    format::{ChunkIndices, Path, SnapshotId},
+    runtime::{default_runtime, DynRuntime},
+};
+
+/// One chunk to write, with its bytes already encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkWrite {
+    pub path: Path,
+    pub coords: ChunkIndices,
+    pub data: Bytes,
+}
+
+impl ChunkWrite {
+    pub fn new(path: Path, coords: ChunkIndices, data: Bytes) -> Self {
+        Self { path, coords, data }
+    }
+}
+
+/// Commit to `branch` every `chunks` chunks written, so a failed ingest can resume from
+/// the last checkpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub branch: String,
+    pub chunks: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct IngestConfig {
+    /// Maximum number of chunks uploading at the same time
+    pub concurrency: usize,
+    /// Retries of an upload that failed with a retryable error, see
+    /// [`crate::repository::RepositoryError::is_retryable`]
+    pub max_retries: u32,
+    /// Delay before the first retry, it doubles on each retry
+    pub retry_delay: Duration,
+    pub checkpoint: Option<Checkpoint>,
+    /// Where to wait between retries
+    pub runtime: DynRuntime,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 16,
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+            checkpoint: None,
+            runtime: default_runtime(),
+        }
+    }
+}
+
+impl IngestConfig {
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn with_checkpoint(
+        mut self,
+        branch: impl Into<String>,
+        chunks: usize,
+        message: impl Into<String>,
+    ) -> Self {
+        self.checkpoint = Some(Checkpoint {
+            branch: branch.into(),
+            chunks: chunks.max(1),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+}
+
+/// The state of an ingest, reported after every chunk written
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IngestProgress {
+    pub chunks: usize,
+    pub bytes: u64,
+    pub retries: usize,
+    /// The snapshot of the last checkpoint, if any
+    pub checkpoint: Option<SnapshotId>,
+    /// Chunks written since the last checkpoint, not committed yet
+    pub pending_chunks: usize,
+}
+
+/// When [`crate::Repository::ingest_streaming`] commits the chunks written
+///
+/// The chunks are committed `interval` after the first chunk not committed yet, or as soon
+/// as `max_pending_bytes` are pending, whichever comes first, and at the end of the stream.
+#[derive(Debug, Clone)]
+pub struct CoalescingConfig {
+    pub branch: String,
+    pub message: String,
+    /// `None` to only commit on the bytes threshold
+    pub interval: Option<Duration>,
+    /// `None` to only commit on the interval
+    pub max_pending_bytes: Option<u64>,
+    /// Where the interval is timed
+    pub runtime: DynRuntime,
+}
+
+impl CoalescingConfig {
+    pub fn new(branch: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            branch: branch.into(),
+            message: message.into(),
+            interval: Some(Duration::from_secs(60)),
+            max_pending_bytes: None,
+            runtime: default_runtime(),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Option<Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_max_pending_bytes(mut self, max_pending_bytes: Option<u64>) -> Self {
+        self.max_pending_bytes = max_pending_bytes.map(|bytes| bytes.max(1));
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+}
+
+/// Why [`crate::Repository::ingest_streaming`] committed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitTrigger {
+    Interval,
+    PendingBytes,
+    EndOfStream,
+}
+
+/// A commit of [`crate::Repository::ingest_streaming`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamingCommit {
+    pub snapshot: SnapshotId,
+    /// The chunks, and their bytes, in this commit
+    pub chunks: usize,
+    pub bytes: u64,
+    pub trigger: CommitTrigger,
+}
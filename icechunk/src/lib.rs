@@ -17,16 +17,50 @@
 //!     - a caching wrapper implementation
 //! - The datastructures are represented by concrete types in the [`mod@format`] modules.
 //!   These datastructures use Arrow RecordBatches for representation.
+pub mod annotations;
+pub mod array;
+pub mod array_builder;
+pub mod authorization;
+pub mod blocking;
 pub mod change_set;
+pub mod chunk_dictionary;
+pub mod chunk_packer;
+pub mod chunk_transformer;
+pub mod claims;
+pub mod commit_hooks;
+pub mod commit_queue;
+pub mod config;
 pub mod conflicts;
+pub mod conventions;
+pub mod events;
+pub mod external_refs;
 pub mod format;
+pub mod generation;
+pub mod ingest;
+pub mod maintenance;
+pub mod memory;
 pub mod metadata;
+pub mod metrics;
+pub mod mount;
 pub mod ops;
+pub mod pins;
+pub mod progress;
 pub mod refs;
 pub mod repository;
+pub mod runtime;
+pub mod schema;
+pub mod server;
+pub mod sources;
 pub mod storage;
-#[cfg(test)]
+#[cfg(any(test, feature = "test_utils"))]
 pub mod strategies;
+pub mod template;
+#[cfg(any(test, feature = "test_utils"))]
+pub mod test_utils;
+pub mod trash;
+pub mod tunables;
+pub mod view;
+pub mod write_buffer;
 pub mod zarr;
 
 pub use repository::{Repository, RepositoryBuilder, RepositoryConfig, SnapshotMetadata};
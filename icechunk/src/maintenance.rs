@@ -0,0 +1,679 @@
+//! Periodic maintenance of a repository, embedded in the application
+//!
+//! A [`MaintenanceScheduler`] runs [`MaintenanceTask`]s, like [`GarbageCollectTask`],
+//! [`RetentionTask`] or [`PurgeTrashTask`], each on its own interval, with at most
+//! [`MaintenanceConfig::max_concurrent`] tasks running at the same time. Other maintenance,
+//! like compacting manifests or pruning a cache, can be scheduled by implementing
+//! [`MaintenanceTask`].
+//!
+//! Many processes can run schedulers for the same repository: before running a task, the
+//! scheduler takes its lease, a namespace of the refs, and the task is skipped if another
+//! scheduler holds it. Leases are renewed while the task runs, so a crashed scheduler only
+//! blocks the task until its lease expires. If a renewal fails, or another scheduler took
+//! the expired lease, the task is abandoned, so it never runs twice at the same time.
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{
+    future::{self, RemoteHandle},
+    pin_mut, stream, StreamExt, TryStreamExt,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ops::{
+        gc::{garbage_collect, GCConfig},
+        retention::{apply_retention, RetentionRule},
+    },
+    refs::{RefError, RefResult},
+    runtime::{self, default_runtime, DynRuntime},
+    trash::purge_trash,
+    Storage, StorageError,
+};
+
+pub(crate) const LOCK_REF_PREFIX: &str = "lock.";
+
+/// A maintenance operation the scheduler runs periodically
+#[async_trait]
+pub trait MaintenanceTask: Debug + Send + Sync {
+    /// Identifies the task and its lease, schedulers sharing a repository must use the same
+    /// name for the same task
+    fn name(&self) -> &str;
+
+    /// Returns a description of what was done, or of the problem
+    async fn run(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        now: DateTime<Utc>,
+    ) -> Result<String, String>;
+}
+
+pub type DynMaintenanceTask = Arc<dyn MaintenanceTask>;
+
+/// Delete the objects nothing references, that are older than the given ages
+#[derive(Debug, Clone)]
+pub struct GarbageCollectTask {
+    pub chunks_age: TimeDelta,
+    pub metadata_age: TimeDelta,
+}
+
+#[async_trait]
+impl MaintenanceTask for GarbageCollectTask {
+    fn name(&self) -> &str {
+        "gc"
+    }
+
+    async fn run(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        now: DateTime<Utc>,
+    ) -> Result<String, String> {
+        let config =
+            GCConfig::clean_all(now - self.chunks_age, now - self.metadata_age, None)
+                .with_now(now);
+        let summary =
+            garbage_collect(storage, &config).await.map_err(|err| err.to_string())?;
+        Ok(format!("{:?}", summary))
+    }
+}
+
+/// Expire the snapshots none of the `rules` keep, see [`apply_retention`]
+#[derive(Debug, Clone)]
+pub struct RetentionTask {
+    pub rules: Vec<RetentionRule>,
+}
+
+#[async_trait]
+impl MaintenanceTask for RetentionTask {
+    fn name(&self) -> &str {
+        "retention"
+    }
+
+    async fn run(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        now: DateTime<Utc>,
+    ) -> Result<String, String> {
+        let summary = apply_retention(storage, &self.rules, now)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(format!("{:?}", summary))
+    }
+}
+
+/// Delete the expired trash entries
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PurgeTrashTask;
+
+#[async_trait]
+impl MaintenanceTask for PurgeTrashTask {
+    fn name(&self) -> &str {
+        "purge-trash"
+    }
+
+    async fn run(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        now: DateTime<Utc>,
+    ) -> Result<String, String> {
+        let deleted = purge_trash(storage, now).await.map_err(|err| err.to_string())?;
+        Ok(format!("{} trash entries deleted", deleted))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskOutcome {
+    Completed(String),
+    Failed(String),
+    /// Another scheduler holds the lease of the task
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskReport {
+    pub task: String,
+    pub started_at: DateTime<Utc>,
+    pub outcome: TaskOutcome,
+}
+
+/// Receives the report of every task run by [`MaintenanceScheduler::run`]
+pub trait MaintenanceObserver: Debug + Send + Sync {
+    fn on_report(&self, report: &TaskReport);
+}
+
+pub type DynMaintenanceObserver = Arc<dyn MaintenanceObserver>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMaintenanceObserver;
+
+impl MaintenanceObserver for NoMaintenanceObserver {
+    fn on_report(&self, _report: &TaskReport) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    pub max_concurrent: usize,
+    /// How long a task lease lives without being renewed
+    pub lease: TimeDelta,
+    /// Identifies the scheduler in the leases, random by default
+    pub holder: String,
+    pub observer: DynMaintenanceObserver,
+    /// Where to wait between runs
+    pub runtime: DynRuntime,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 1,
+            lease: TimeDelta::minutes(5),
+            holder: Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+            observer: Arc::new(NoMaintenanceObserver),
+            runtime: default_runtime(),
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = max_concurrent.max(1);
+        self
+    }
+
+    pub fn with_lease(mut self, lease: TimeDelta) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    pub fn with_holder(mut self, holder: impl Into<String>) -> Self {
+        self.holder = holder.into();
+        self
+    }
+
+    pub fn with_observer(mut self, observer: DynMaintenanceObserver) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct ScheduledTask {
+    task: DynMaintenanceTask,
+    interval: TimeDelta,
+    next_run: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub struct MaintenanceScheduler {
+    storage: Arc<dyn Storage + Send + Sync>,
+    config: MaintenanceConfig,
+    tasks: Vec<ScheduledTask>,
+}
+
+impl MaintenanceScheduler {
+    pub fn new(
+        storage: Arc<dyn Storage + Send + Sync>,
+        config: MaintenanceConfig,
+    ) -> Self {
+        Self { storage, config, tasks: Vec::new() }
+    }
+
+    /// Run `task` every `interval`, starting with the first call to
+    /// [`MaintenanceScheduler::run_pending`]
+    pub fn with_task(
+        mut self,
+        task: impl MaintenanceTask + 'static,
+        interval: TimeDelta,
+    ) -> Self {
+        self.tasks.push(ScheduledTask { task: Arc::new(task), interval, next_run: None });
+        self
+    }
+
+    /// When the next task is due, `None` if there are no tasks
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        self.tasks.iter().map(|task| task.next_run.unwrap_or(DateTime::UNIX_EPOCH)).min()
+    }
+
+    /// Run the tasks due at `now`, and schedule their next run
+    pub async fn run_pending(&mut self, now: DateTime<Utc>) -> Vec<TaskReport> {
+        let due: Vec<DynMaintenanceTask> = self
+            .tasks
+            .iter_mut()
+            .filter(|task| task.next_run.is_none_or(|next| next <= now))
+            .map(|task| {
+                task.next_run = Some(now + task.interval);
+                Arc::clone(&task.task)
+            })
+            .collect();
+        let storage = self.storage.as_ref();
+        let config = &self.config;
+        let runs: Vec<_> = due
+            .into_iter()
+            .map(|task| async move {
+                let outcome = run_task(storage, config, task.as_ref(), now).await;
+                TaskReport { task: task.name().to_string(), started_at: now, outcome }
+            })
+            .collect();
+        stream::iter(runs).buffer_unordered(self.config.max_concurrent).collect().await
+    }
+
+    /// Run the tasks as they are due, forever
+    pub async fn run(mut self) {
+        loop {
+            for report in self.run_pending(Utc::now()).await {
+                self.config.observer.on_report(&report);
+            }
+            let Some(next) = self.next_run() else { return };
+            let wait = (next - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+            self.config.runtime.sleep(wait).await;
+        }
+    }
+
+    /// Run the tasks in the background, until the returned handle is dropped
+    pub fn spawn(self) -> RemoteHandle<()> {
+        let runtime = Arc::clone(&self.config.runtime);
+        runtime::spawn(runtime.as_ref(), self.run())
+    }
+}
+
+async fn run_task(
+    storage: &(dyn Storage + Send + Sync),
+    config: &MaintenanceConfig,
+    task: &dyn MaintenanceTask,
+    now: DateTime<Utc>,
+) -> TaskOutcome {
+    let lease = match acquire_lease(
+        storage,
+        task.name(),
+        &config.holder,
+        now,
+        config.lease,
+    )
+    .await
+    {
+        Ok(Some(lease)) => lease,
+        Ok(None) => return TaskOutcome::Skipped,
+        Err(err) => return TaskOutcome::Failed(err.to_string()),
+    };
+
+    // the latest generation, renewing writes a new one
+    let current = Mutex::new(lease);
+    let keep_lease = async {
+        let every = (config.lease / 3).to_std().unwrap_or(Duration::ZERO);
+        loop {
+            config.runtime.sleep(every).await;
+            let mut lease = current.lock().unwrap_or_else(|err| err.into_inner()).clone();
+            match renew_lease(storage, &mut lease, Utc::now(), config.lease).await {
+                Ok(true) => {
+                    *current.lock().unwrap_or_else(|err| err.into_inner()) = lease;
+                }
+                Ok(false) => return "lease lost to another scheduler".to_string(),
+                Err(err) => return format!("cannot renew lease: {}", err),
+            }
+        }
+    };
+    let work = task.run(storage, now);
+    pin_mut!(keep_lease, work);
+    // without the lease, another scheduler can start the task, the work is dropped
+    let res = match future::select(work, keep_lease).await {
+        future::Either::Left((res, _)) => res,
+        future::Either::Right((lost, _)) => return TaskOutcome::Failed(lost),
+    };
+
+    let lease = current.lock().unwrap_or_else(|err| err.into_inner()).clone();
+    let released = release_lease(storage, &lease, Utc::now()).await;
+    match (res, released) {
+        (Ok(summary), Ok(())) => TaskOutcome::Completed(summary),
+        (Ok(_), Err(err)) => {
+            TaskOutcome::Failed(format!("cannot release lease: {}", err))
+        }
+        (Err(message), _) => TaskOutcome::Failed(message),
+    }
+}
+
+/// The right to run a maintenance task, until `expires_at`
+///
+/// Each lease of a task is written as a new generation, created only if it doesn't exist,
+/// so two schedulers cannot take the same expired lease.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskLease {
+    pub task: String,
+    pub holder: String,
+    pub generation: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl TaskLease {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn lock_name(task: &str) -> String {
+    format!("{}{}", LOCK_REF_PREFIX, task)
+}
+
+fn lease_key(task: &str, generation: u64) -> String {
+    // padded, so generations sort as strings
+    format!("{}/{:020}.json", lock_name(task), generation)
+}
+
+async fn write_lease(
+    storage: &(dyn Storage + Send + Sync),
+    lease: &TaskLease,
+    overwrite: bool,
+) -> RefResult<()> {
+    let content = serde_json::to_vec(lease)?;
+    storage
+        .write_ref(
+            lease_key(&lease.task, lease.generation).as_str(),
+            overwrite,
+            Bytes::from(content),
+        )
+        .await?;
+    Ok(())
+}
+
+/// The last lease of `task`, expired or not
+pub async fn current_lease(
+    storage: &(dyn Storage + Send + Sync),
+    task: &str,
+) -> RefResult<Option<TaskLease>> {
+    let name = lock_name(task);
+    let versions: Vec<String> =
+        storage.ref_versions(name.as_str()).await?.try_collect().await?;
+    let Some(last) = versions.iter().max() else { return Ok(None) };
+    let data = storage.get_ref(format!("{}/{}", name, last).as_str()).await?;
+    Ok(Some(serde_json::from_slice(data.as_ref())?))
+}
+
+/// Take the lease of `task` until `now + lease`, `None` if another holder has it
+pub async fn acquire_lease(
+    storage: &(dyn Storage + Send + Sync),
+    task: &str,
+    holder: &str,
+    now: DateTime<Utc>,
+    lease: TimeDelta,
+) -> RefResult<Option<TaskLease>> {
+    let current = current_lease(storage, task).await?;
+    if current.as_ref().is_some_and(|c| !c.is_expired(now) && c.holder != holder) {
+        return Ok(None);
+    }
+    let new = TaskLease {
+        task: task.to_string(),
+        holder: holder.to_string(),
+        generation: current.as_ref().map_or(0, |c| c.generation + 1),
+        expires_at: now + lease,
+    };
+    match write_lease(storage, &new, false).await {
+        Ok(()) => {}
+        // another scheduler took it first
+        Err(RefError::Storage(StorageError::RefAlreadyExists(_))) => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let current_key = lease_key(task, new.generation);
+    let name = lock_name(task);
+    let old: Vec<String> = storage
+        .ref_versions(name.as_str())
+        .await?
+        .map_ok(|version| format!("{}/{}", name, version))
+        .try_filter(|key| future::ready(*key != current_key))
+        .try_collect()
+        .await?;
+    storage.delete_objects(crate::storage::REF_PREFIX, stream::iter(old).boxed()).await?;
+    Ok(Some(new))
+}
+
+/// Extend the lease to `now + lease`, `false` if it was lost
+///
+/// The renewed lease is written as the next generation, only if it doesn't exist: if
+/// another holder took the task after the lease expired, the renewal fails, and `lease` is
+/// unchanged.
+pub async fn renew_lease(
+    storage: &(dyn Storage + Send + Sync),
+    lease: &mut TaskLease,
+    now: DateTime<Utc>,
+    duration: TimeDelta,
+) -> RefResult<bool> {
+    let renewed = TaskLease {
+        generation: lease.generation + 1,
+        expires_at: now + duration,
+        ..lease.clone()
+    };
+    match write_lease(storage, &renewed, false).await {
+        Ok(()) => {}
+        Err(RefError::Storage(StorageError::RefAlreadyExists(_))) => return Ok(false),
+        Err(err) => return Err(err),
+    }
+    let old = lease_key(&lease.task, lease.generation);
+    *lease = renewed;
+    // a leftover generation is deleted by the next acquisition
+    let _ = storage
+        .delete_objects(crate::storage::REF_PREFIX, stream::iter([old]).boxed())
+        .await;
+    Ok(true)
+}
+
+/// Let other schedulers run the task
+///
+/// The lease is kept, expired, so the next one gets a new generation.
+pub async fn release_lease(
+    storage: &(dyn Storage + Send + Sync),
+    lease: &TaskLease,
+    now: DateTime<Utc>,
+) -> RefResult<()> {
+    let released = TaskLease { expires_at: now, ..lease.clone() };
+    write_lease(storage, &released, true).await
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        error::Error,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        refs::{list_refs, Ref},
+        ObjectStorage, Repository,
+    };
+
+    /// Counts its runs, and the counted tasks running at the same time
+    #[derive(Debug, Default)]
+    struct Counting {
+        name: String,
+        runs: AtomicUsize,
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl MaintenanceTask for Arc<Counting> {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        async fn run(
+            &self,
+            _storage: &(dyn Storage + Send + Sync),
+            _now: DateTime<Utc>,
+        ) -> Result<String, String> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            let runs = self.runs.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(format!("run {}", runs))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_maintenance() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.commit("main", "first", None).await?;
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let counters: Vec<Arc<Counting>> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                Arc::new(Counting {
+                    name: name.to_string(),
+                    runs: AtomicUsize::new(0),
+                    running: Arc::clone(&running),
+                    max_running: Arc::clone(&max_running),
+                })
+            })
+            .collect();
+        let config =
+            MaintenanceConfig::default().with_holder("one").with_max_concurrent(2);
+        let mut scheduler = MaintenanceScheduler::new(Arc::clone(&storage), config)
+            .with_task(PurgeTrashTask, TimeDelta::hours(1));
+        for counter in counters.iter() {
+            scheduler = scheduler.with_task(Arc::clone(counter), TimeDelta::minutes(10));
+        }
+
+        let now = Utc::now();
+        let mut reports = scheduler.run_pending(now).await;
+        reports.sort_by(|a, b| a.task.cmp(&b.task));
+        let outcomes: Vec<_> = reports.into_iter().map(|r| (r.task, r.outcome)).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("a".to_string(), TaskOutcome::Completed("run 1".to_string())),
+                ("b".to_string(), TaskOutcome::Completed("run 1".to_string())),
+                ("c".to_string(), TaskOutcome::Completed("run 1".to_string())),
+                (
+                    "purge-trash".to_string(),
+                    TaskOutcome::Completed("0 trash entries deleted".to_string())
+                ),
+            ]
+        );
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+        assert_eq!(scheduler.next_run(), Some(now + TimeDelta::minutes(10)));
+
+        // only the tasks that are due run again
+        let reports = scheduler.run_pending(now + TimeDelta::minutes(10)).await;
+        assert_eq!(reports.len(), 3);
+        assert!(scheduler.run_pending(now + TimeDelta::minutes(11)).await.is_empty());
+
+        // tasks leased by another scheduler are skipped
+        let later = now + TimeDelta::minutes(20);
+        let taken =
+            acquire_lease(storage.as_ref(), "a", "two", later, TimeDelta::minutes(5))
+                .await?;
+        assert!(taken.is_some());
+        let reports = scheduler.run_pending(later).await;
+        let skipped: Vec<_> = reports
+            .iter()
+            .filter(|r| r.outcome == TaskOutcome::Skipped)
+            .map(|r| r.task.as_str())
+            .collect();
+        assert_eq!(skipped, vec!["a"]);
+        assert_eq!(counters[0].runs.load(Ordering::SeqCst), 2);
+
+        // expired leases can be taken, and only the last generation is kept
+        let much_later = later + TimeDelta::minutes(30);
+        let lease =
+            acquire_lease(storage.as_ref(), "a", "one", much_later, TimeDelta::zero())
+                .await?
+                .unwrap();
+        assert_eq!(lease.generation, taken.unwrap().generation + 1);
+        assert_eq!(storage.ref_versions("lock.a").await?.count().await, 1);
+        assert_eq!(current_lease(storage.as_ref(), "a").await?, Some(lease));
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string())]
+        );
+        Ok(())
+    }
+
+    /// Steals its own lease, then never ends
+    #[derive(Debug)]
+    struct Stolen;
+
+    #[async_trait]
+    impl MaintenanceTask for Stolen {
+        fn name(&self) -> &str {
+            "stolen"
+        }
+
+        async fn run(
+            &self,
+            storage: &(dyn Storage + Send + Sync),
+            now: DateTime<Utc>,
+        ) -> Result<String, String> {
+            let later = now + TimeDelta::hours(1);
+            acquire_lease(storage, "stolen", "thief", later, TimeDelta::hours(1))
+                .await
+                .map_err(|err| err.to_string())?;
+            future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lost_lease_abandons_the_task() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let now = Utc::now();
+        let mut lease =
+            acquire_lease(storage.as_ref(), "a", "one", now, TimeDelta::zero())
+                .await?
+                .unwrap();
+        assert!(
+            renew_lease(storage.as_ref(), &mut lease, now, TimeDelta::minutes(5)).await?
+        );
+        assert_eq!(current_lease(storage.as_ref(), "a").await?, Some(lease.clone()));
+        assert_eq!(storage.ref_versions("lock.a").await?.count().await, 1);
+
+        // once expired, another holder can take it, and the renewal fails
+        let later = now + TimeDelta::minutes(10);
+        let taken =
+            acquire_lease(storage.as_ref(), "a", "two", later, TimeDelta::minutes(5))
+                .await?;
+        let stale = lease.clone();
+        assert!(
+            !renew_lease(storage.as_ref(), &mut lease, later, TimeDelta::minutes(5))
+                .await?
+        );
+        assert_eq!(lease, stale);
+        assert_eq!(current_lease(storage.as_ref(), "a").await?, taken);
+
+        let config = MaintenanceConfig::default()
+            .with_holder("one")
+            .with_lease(TimeDelta::milliseconds(30));
+        let mut scheduler = MaintenanceScheduler::new(Arc::clone(&storage), config)
+            .with_task(Stolen, TimeDelta::hours(1));
+        let reports = scheduler.run_pending(Utc::now()).await;
+        assert_eq!(
+            reports[0].outcome,
+            TaskOutcome::Failed("lease lost to another scheduler".to_string())
+        );
+        assert_eq!(
+            current_lease(storage.as_ref(), "stolen").await?.map(|lease| lease.holder),
+            Some("thief".to_string())
+        );
+        Ok(())
+    }
+}
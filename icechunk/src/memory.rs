@@ -0,0 +1,433 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, Weak,
+    },
+};
+
+use bytes::Bytes;
+use quick_cache::{sync::Cache, DefaultHashBuilder, Lifecycle, Weighter};
+
+use crate::format::manifest::Manifest;
+
+/// A component holding memory that it can free on request, like a cache
+pub trait Reclaim: Debug + Send + Sync {
+    /// Free up to `bytes` of memory, returns the number of bytes freed
+    fn reclaim(&self, bytes: u64) -> u64;
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    limit_bytes: u64,
+    used_bytes: AtomicU64,
+    /// Incremented on every reservation, to find the least recently used pools
+    clock: AtomicU64,
+    pools: Mutex<Vec<Weak<PoolState>>>,
+}
+
+impl BudgetState {
+    fn try_add(&self, bytes: u64) -> bool {
+        self.used_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + bytes <= self.limit_bytes).then_some(used + bytes)
+            })
+            .is_ok()
+    }
+
+    fn pools(&self) -> Vec<Arc<PoolState>> {
+        let mut pools = self.pools.lock().unwrap_or_else(PoisonError::into_inner);
+        pools.retain(|pool| pool.strong_count() > 0);
+        pools.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Ask the pools other than `requester` to free `bytes`, least recently used first
+    fn reclaim(&self, requester: &PoolState, bytes: u64) {
+        let mut pools: Vec<_> = self
+            .pools()
+            .into_iter()
+            .filter(|pool| !std::ptr::eq(pool.as_ref(), requester))
+            .collect();
+        pools.sort_by_key(|pool| pool.last_used.load(Ordering::SeqCst));
+        let mut missing = bytes;
+        for pool in pools {
+            if missing == 0 {
+                break;
+            }
+            let reclaimer = pool
+                .reclaimer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_ref()
+                .and_then(Weak::upgrade);
+            if let Some(reclaimer) = reclaimer {
+                missing = missing.saturating_sub(reclaimer.reclaim(missing));
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PoolState {
+    name: String,
+    cap_bytes: Option<u64>,
+    used_bytes: AtomicU64,
+    last_used: AtomicU64,
+    reclaimer: Mutex<Option<Weak<dyn Reclaim>>>,
+    budget: Arc<BudgetState>,
+}
+
+impl Drop for PoolState {
+    fn drop(&mut self) {
+        let used = *self.used_bytes.get_mut();
+        saturating_sub(&self.budget.used_bytes, used);
+    }
+}
+
+fn saturating_sub(value: &AtomicU64, bytes: u64) {
+    let _always_ok = value.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+        Some(current.saturating_sub(bytes))
+    });
+}
+
+/// A memory limit shared by caches and buffers
+///
+/// Components get a [`MemoryPool`] from the budget and reserve memory from it before
+/// holding data. When the budget is exhausted, the least recently used pools that can free
+/// memory, like caches, are asked to make room. Accounting is approximate, it counts the
+/// data held, not the bookkeeping overhead.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    state: Arc<BudgetState>,
+}
+
+/// The memory used by a [`MemoryBudget`] and each of its pools
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub limit_bytes: u64,
+    pub used_bytes: u64,
+    pub pools: BTreeMap<String, u64>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit_bytes: u64) -> Self {
+        Self {
+            state: Arc::new(BudgetState {
+                limit_bytes,
+                used_bytes: AtomicU64::new(0),
+                clock: AtomicU64::new(0),
+                pools: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Create a pool called `name`, that can use up to `cap_bytes` of the budget
+    pub fn pool(&self, name: &str, cap_bytes: Option<u64>) -> MemoryPool {
+        let pool = Arc::new(PoolState {
+            name: name.to_string(),
+            cap_bytes,
+            used_bytes: AtomicU64::new(0),
+            last_used: AtomicU64::new(0),
+            reclaimer: Mutex::new(None),
+            budget: Arc::clone(&self.state),
+        });
+        self.state
+            .pools
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::downgrade(&pool));
+        MemoryPool { state: pool }
+    }
+
+    pub fn limit_bytes(&self) -> u64 {
+        self.state.limit_bytes
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.state.used_bytes.load(Ordering::SeqCst)
+    }
+
+    /// The current usage, pools with the same name are added together
+    pub fn usage(&self) -> MemoryUsage {
+        let mut pools = BTreeMap::new();
+        for pool in self.state.pools() {
+            *pools.entry(pool.name.clone()).or_default() +=
+                pool.used_bytes.load(Ordering::SeqCst);
+        }
+        MemoryUsage {
+            limit_bytes: self.limit_bytes(),
+            used_bytes: self.used_bytes(),
+            pools,
+        }
+    }
+}
+
+/// A share of a [`MemoryBudget`] used by one component
+///
+/// The memory still reserved when the last clone of the pool is dropped returns to the
+/// budget.
+#[derive(Debug, Clone)]
+pub struct MemoryPool {
+    state: Arc<PoolState>,
+}
+
+impl MemoryPool {
+    pub fn name(&self) -> &str {
+        &self.state.name
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.state.used_bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn cap_bytes(&self) -> Option<u64> {
+        self.state.cap_bytes
+    }
+
+    /// Register the component that can free this pool's memory when other pools need it
+    pub fn set_reclaimer(&self, reclaimer: Weak<dyn Reclaim>) {
+        *self.state.reclaimer.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(reclaimer);
+    }
+
+    /// Reserve `bytes`, returns `false` if they don't fit in the pool cap or the budget
+    ///
+    /// If the budget is exhausted, other pools are asked to free memory first.
+    pub fn try_reserve(&self, bytes: u64) -> bool {
+        let pool = self.state.as_ref();
+        if pool.cap_bytes.is_some_and(|cap| self.used_bytes() + bytes > cap) {
+            return false;
+        }
+        let budget = pool.budget.as_ref();
+        pool.last_used
+            .store(budget.clock.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+        if !budget.try_add(bytes) {
+            let available = budget
+                .limit_bytes
+                .saturating_sub(budget.used_bytes.load(Ordering::SeqCst));
+            budget.reclaim(pool, bytes.saturating_sub(available));
+            if !budget.try_add(bytes) {
+                return false;
+            }
+        }
+        pool.used_bytes.fetch_add(bytes, Ordering::SeqCst);
+        true
+    }
+
+    /// Return `bytes` previously reserved
+    pub fn release(&self, bytes: u64) {
+        saturating_sub(&self.state.used_bytes, bytes);
+        saturating_sub(&self.state.budget.used_bytes, bytes);
+    }
+}
+
+/// The approximate number of bytes needed to keep a value in memory
+pub trait MemorySize {
+    fn memory_size(&self) -> u64;
+}
+
+impl MemorySize for Bytes {
+    fn memory_size(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+/// Rough size of a manifest entry: ids, coordinates and the chunk reference
+const MANIFEST_ENTRY_SIZE_BYTES: u64 = 100;
+
+impl MemorySize for Manifest {
+    fn memory_size(&self) -> u64 {
+        self.len() as u64 * MANIFEST_ENTRY_SIZE_BYTES
+    }
+}
+
+impl<T: MemorySize> MemorySize for Arc<T> {
+    fn memory_size(&self) -> u64 {
+        self.as_ref().memory_size()
+    }
+}
+
+/// The weight of a cache entry, zero weight entries are never evicted by `quick_cache`
+fn entry_weight<V: MemorySize>(value: &V) -> u64 {
+    value.memory_size().max(1)
+}
+
+#[derive(Debug, Clone)]
+struct SizeWeighter;
+
+impl<K, V: MemorySize> Weighter<K, V> for SizeWeighter {
+    fn weight(&self, _key: &K, value: &V) -> u64 {
+        entry_weight(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PoolLifecycle {
+    pool: MemoryPool,
+}
+
+impl<K, V: MemorySize> Lifecycle<K, V> for PoolLifecycle {
+    type RequestState = ();
+
+    fn begin_request(&self) -> Self::RequestState {}
+
+    fn on_evict(&self, _state: &mut Self::RequestState, _key: K, value: V) {
+        self.pool.release(entry_weight(&value));
+    }
+}
+
+/// A cache whose entries are accounted in a [`MemoryPool`]
+///
+/// Entries that don't fit in the budget are not cached. When other pools need memory, the
+/// cache is emptied.
+#[derive(Debug)]
+pub struct BudgetedCache<K, V> {
+    cache: Cache<K, V, SizeWeighter, DefaultHashBuilder, PoolLifecycle>,
+    pool: MemoryPool,
+}
+
+impl<K, V> BudgetedCache<K, V>
+where
+    K: Eq + Hash + Clone + Debug + Send + Sync + 'static,
+    V: MemorySize + Clone + Debug + Send + Sync + 'static,
+{
+    pub fn new(pool: MemoryPool, estimated_items: usize) -> Arc<Self> {
+        let capacity = pool.cap_bytes().unwrap_or(pool.state.budget.limit_bytes).max(1);
+        let cache = Cache::with(
+            estimated_items.max(1),
+            capacity,
+            SizeWeighter,
+            DefaultHashBuilder::default(),
+            PoolLifecycle { pool: pool.clone() },
+        );
+        let res = Arc::new(Self { cache, pool });
+        let reclaimer: Arc<dyn Reclaim> = res.clone();
+        res.pool.set_reclaimer(Arc::downgrade(&reclaimer));
+        res
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.cache.get(key)
+    }
+
+    /// Get the cached value, or compute it with `f` and try to cache it
+    pub async fn get_or_insert_async<E>(
+        &self,
+        key: &K,
+        f: impl Future<Output = Result<V, E>>,
+    ) -> Result<V, E> {
+        match self.cache.get_value_or_guard_async(key).await {
+            Ok(value) => Ok(value),
+            Err(guard) => {
+                let value = f.await?;
+                let weight = entry_weight(&value);
+                if self.pool.try_reserve(weight) && guard.insert(value.clone()).is_err() {
+                    self.pool.release(weight);
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        if let Some((_, old)) = self.cache.remove(&key) {
+            self.pool.release(entry_weight(&old));
+        }
+        let weight = entry_weight(&value);
+        if self.pool.try_reserve(weight) {
+            self.cache.insert(key, value);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+}
+
+impl<K, V> Reclaim for BudgetedCache<K, V>
+where
+    K: Eq + Hash + Clone + Debug + Send + Sync,
+    V: MemorySize + Clone + Debug + Send + Sync,
+{
+    fn reclaim(&self, _bytes: u64) -> u64 {
+        // the cache can't evict a given number of bytes, so we drop everything
+        self.cache.clear();
+        let used = self.pool.used_bytes();
+        self.pool.release(used);
+        used
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::convert::Infallible;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_pools_share_the_budget() {
+        let budget = MemoryBudget::new(100);
+        let buffer = budget.pool("buffer", Some(60));
+        let other = budget.pool("other", None);
+        assert!(buffer.try_reserve(50));
+        assert!(!buffer.try_reserve(20));
+        assert!(other.try_reserve(50));
+        assert!(!other.try_reserve(1));
+        buffer.release(30);
+        assert_eq!(
+            budget.usage(),
+            MemoryUsage {
+                limit_bytes: 100,
+                used_bytes: 70,
+                pools: BTreeMap::from([
+                    ("buffer".to_string(), 20),
+                    ("other".to_string(), 50)
+                ])
+            }
+        );
+        drop(other);
+        assert_eq!(budget.used_bytes(), 20);
+        assert_eq!(budget.usage().pools.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_caches_are_reclaimed() {
+        let budget = MemoryBudget::new(100);
+        let cache: Arc<BudgetedCache<u32, Bytes>> =
+            BudgetedCache::new(budget.pool("cache", None), 10);
+        for key in 0..4 {
+            let value = cache
+                .get_or_insert_async(&key, async {
+                    Ok::<_, Infallible>(Bytes::from(vec![0; 20]))
+                })
+                .await
+                .unwrap();
+            assert_eq!(value.len(), 20);
+        }
+        assert_eq!(cache.len(), 4);
+        assert_eq!(budget.used_bytes(), 80);
+
+        // another pool needing memory empties the cache
+        let buffer = budget.pool("buffer", None);
+        assert!(buffer.try_reserve(50));
+        assert!(cache.is_empty());
+        assert_eq!(budget.usage().pools["cache"], 0);
+        assert_eq!(budget.used_bytes(), 50);
+
+        // values that don't fit are returned but not cached
+        cache.insert(0, Bytes::from(vec![0; 60]));
+        assert_eq!(cache.get(&0), None);
+        cache.insert(0, Bytes::from(vec![0; 10]));
+        assert_eq!(cache.get(&0).map(|b| b.len()), Some(10));
+    }
+}
@@ -1,10 +1,12 @@
-use std::fmt::Display;
+use std::{fmt::Display, num::NonZeroU32, str::FromStr};
 
-use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use test_strategy::Arbitrary;
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+use super::vlen::{VLEN_BYTES_CODEC, VLEN_UTF8_CODEC};
+
+#[derive(Clone, Debug, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
 #[non_exhaustive]
-#[serde(rename_all = "lowercase")]
 pub enum DataType {
     Bool,
     Int8,
@@ -22,6 +24,33 @@ pub enum DataType {
     Complex128,
     String,
     Bytes,
+    /// UTF-8 strings of up to the given number of bytes
+    FixedLengthString(NonZeroU32),
+    /// Number of time units since the UNIX epoch, as in numpy
+    DateTime64(TimeUnit),
+}
+
+#[derive(Arbitrary, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimeUnit {
+    Year,
+    Month,
+    Week,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Millisecond,
+    Microsecond,
+    Nanosecond,
+}
+
+/// The byte order of the array elements
+///
+/// In Zarr, it's part of the configuration of the `bytes` codec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Endianness {
+    Little,
+    Big,
 }
 
 impl DataType {
@@ -46,6 +75,96 @@ impl DataType {
             _ => false,
         }
     }
+
+    /// The size of each element in bytes, `None` for variable length types
+    pub fn size_bytes(&self) -> Option<u64> {
+        use DataType::*;
+        match self {
+            Bool | Int8 | UInt8 => Some(1),
+            Int16 | UInt16 | Float16 => Some(2),
+            Int32 | UInt32 | Float32 => Some(4),
+            Int64 | UInt64 | Float64 | Complex64 | DateTime64(_) => Some(8),
+            Complex128 => Some(16),
+            FixedLengthString(n) => Some(n.get() as u64),
+            String | Bytes => None,
+        }
+    }
+
+    /// Elements of this type have different sizes, they are not encoded by `bytes`
+    pub fn is_variable_length(&self) -> bool {
+        matches!(self, DataType::String | DataType::Bytes)
+    }
+
+    /// The codec that encodes arrays of this type into bytes, `None` for fixed size types
+    pub fn vlen_codec(&self) -> Option<&'static str> {
+        match self {
+            DataType::String => Some(VLEN_UTF8_CODEC),
+            DataType::Bytes => Some(VLEN_BYTES_CODEC),
+            _ => None,
+        }
+    }
+
+    /// Elements of this type have more than one byte, that can be stored in any order
+    pub fn needs_endianness(&self) -> bool {
+        use DataType::*;
+        match self {
+            Bool | Int8 | UInt8 | String | Bytes | FixedLengthString(_) => false,
+            Int16 | Int32 | Int64 | UInt16 | UInt32 | UInt64 | Float16 | Float32
+            | Float64 | Complex64 | Complex128 | DateTime64(_) => true,
+        }
+    }
+}
+
+impl TimeUnit {
+    fn code(&self) -> &'static str {
+        use TimeUnit::*;
+        match self {
+            Year => "Y",
+            Month => "M",
+            Week => "W",
+            Day => "D",
+            Hour => "h",
+            Minute => "m",
+            Second => "s",
+            Millisecond => "ms",
+            Microsecond => "us",
+            Nanosecond => "ns",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        use TimeUnit::*;
+        match code {
+            "Y" => Some(Year),
+            "M" => Some(Month),
+            "W" => Some(Week),
+            "D" => Some(Day),
+            "h" => Some(Hour),
+            "m" => Some(Minute),
+            "s" => Some(Second),
+            "ms" => Some(Millisecond),
+            "us" => Some(Microsecond),
+            "ns" => Some(Nanosecond),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<&str> for Endianness {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "little" => Ok(Endianness::Little),
+            "big" => Ok(Endianness::Big),
+            _ => Err("Unknown endianness, it must be little or big"),
+        }
+    }
+}
+
+/// Parse the argument of a parametrized type name, like `datetime64[ns]`
+fn parametrized<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.strip_prefix(name)?.strip_prefix('[')?.strip_suffix(']')
 }
 
 impl TryFrom<&str> for DataType {
@@ -69,11 +188,32 @@ impl TryFrom<&str> for DataType {
             "complex128" => Ok(DataType::Complex128),
             "string" => Ok(DataType::String),
             "bytes" => Ok(DataType::Bytes),
-            _ => Err("Unknown data type, cannot parse"),
+            _ => {
+                if let Some(length) = parametrized(value, "fixed_length_string") {
+                    length
+                        .parse()
+                        .map(DataType::FixedLengthString)
+                        .map_err(|_| "Invalid length for fixed length string")
+                } else if let Some(unit) = parametrized(value, "datetime64") {
+                    TimeUnit::from_code(unit)
+                        .map(DataType::DateTime64)
+                        .ok_or("Unknown datetime64 unit")
+                } else {
+                    Err("Unknown data type, cannot parse")
+                }
+            }
         }
     }
 }
 
+impl FromStr for DataType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DataType::try_from(s).map_err(|err| format!("{err}: `{s}`"))
+    }
+}
+
 impl Display for DataType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use DataType::*;
@@ -94,6 +234,57 @@ impl Display for DataType {
             Complex128 => f.write_str("complex128"),
             String => f.write_str("string"),
             Bytes => f.write_str("bytes"),
+            FixedLengthString(n) => write!(f, "fixed_length_string[{n}]"),
+            DateTime64(unit) => write!(f, "datetime64[{}]", unit.code()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_data_type_names() {
+        for name in [
+            "int32",
+            "bytes",
+            "fixed_length_string[16]",
+            "datetime64[ns]",
+            "datetime64[D]",
+        ] {
+            let dt: DataType = name.parse().unwrap();
+            assert_eq!(dt.to_string(), name);
+            let json = serde_json::to_string(&dt).unwrap();
+            assert_eq!(json, format!("\"{name}\""));
+            assert_eq!(serde_json::from_str::<DataType>(&json).unwrap(), dt);
         }
+        assert_eq!(
+            "datetime64[ms]".parse::<DataType>(),
+            Ok(DataType::DateTime64(TimeUnit::Millisecond))
+        );
+        assert!("fixed_length_string[0]".parse::<DataType>().is_err());
+        assert!("datetime64[parsec]".parse::<DataType>().is_err());
+        assert!("datetime64".parse::<DataType>().is_err());
+        assert!(serde_json::from_str::<DataType>("\"int7\"").is_err());
+    }
+
+    #[test]
+    fn test_data_type_sizes() {
+        assert_eq!(DataType::Complex64.size_bytes(), Some(8));
+        assert_eq!(DataType::DateTime64(TimeUnit::Second).size_bytes(), Some(8));
+        assert_eq!(
+            DataType::FixedLengthString(NonZeroU32::new(10).unwrap()).size_bytes(),
+            Some(10)
+        );
+        assert_eq!(DataType::String.size_bytes(), None);
+        assert!(DataType::Bytes.is_variable_length());
+        assert_eq!(DataType::String.vlen_codec(), Some("vlen-utf8"));
+        assert_eq!(DataType::Int8.vlen_codec(), None);
+        assert!(DataType::Int16.needs_endianness());
+        assert!(!DataType::UInt8.needs_endianness());
     }
 }
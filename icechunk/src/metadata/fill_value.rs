@@ -1,3 +1,4 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use test_strategy::Arbitrary;
@@ -31,6 +32,9 @@ impl FillValue {
     pub const NAN_STR: &'static str = "NaN";
     pub const INF_STR: &'static str = "Infinity";
     pub const NEG_INF_STR: &'static str = "-Infinity";
+    /// The "not a time" value of `datetime64` arrays
+    pub const NAT_STR: &'static str = "NaT";
+    pub const NAT: i64 = i64::MIN;
 
     pub fn from_data_type_and_json(
         dt: &DataType,
@@ -186,6 +190,39 @@ impl FillValue {
             (DataType::String, serde_json::Value::String(s)) => {
                 Ok(FillValue::String(s.clone()))
             }
+            (DataType::FixedLengthString(n), serde_json::Value::String(s))
+                if s.len() <= n.get() as usize =>
+            {
+                Ok(FillValue::String(s.clone()))
+            }
+
+            // datetimes use the same representation as numpy, an int64
+            (DataType::DateTime64(_), serde_json::Value::Number(n))
+                if n.as_i64().is_some() =>
+            {
+                Ok(FillValue::Int64(n.as_i64().expect("bug in from_data_type_and_json")))
+            }
+            (DataType::DateTime64(_), serde_json::Value::String(s))
+                if s.as_str() == FillValue::NAT_STR =>
+            {
+                Ok(FillValue::Int64(FillValue::NAT))
+            }
+
+            // zarr-python stores the fill value of bytes arrays in base64
+            (DataType::Bytes, serde_json::Value::String(s)) => STANDARD
+                .decode(s)
+                .map(FillValue::Bytes)
+                .map_err(|_| IcechunkFormatError::FillValueParse {
+                    data_type: dt.clone(),
+                    value: value.clone(),
+                }),
+            // and Zarr version 2 uses null for variable length types
+            (DataType::String, serde_json::Value::Null) => {
+                Ok(FillValue::String(String::new()))
+            }
+            (DataType::Bytes, serde_json::Value::Null) => {
+                Ok(FillValue::Bytes(Vec::new()))
+            }
 
             (DataType::Bytes, serde_json::Value::Array(arr)) => {
                 let bytes = arr
@@ -233,6 +270,17 @@ impl FillValue {
             FillValue::Bytes(_) => DataType::Bytes,
         }
     }
+
+    /// The fill value can be used in arrays of type `dt`
+    pub fn is_valid_for(&self, dt: &DataType) -> bool {
+        match (self, dt) {
+            (FillValue::Int64(_), DataType::DateTime64(_)) => true,
+            (FillValue::String(s), DataType::FixedLengthString(n)) => {
+                s.len() <= n.get() as usize
+            }
+            _ => &self.get_data_type() == dt,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -240,6 +288,50 @@ impl FillValue {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_typed_fill_values() {
+        use std::num::NonZeroU32;
+
+        use serde_json::Value;
+
+        use crate::metadata::data_type::TimeUnit;
+
+        let dt = DataType::DateTime64(TimeUnit::Second);
+        assert_eq!(
+            FillValue::from_data_type_and_json(&dt, &"NaT".into()).unwrap(),
+            FillValue::Int64(FillValue::NAT)
+        );
+        assert_eq!(
+            FillValue::from_data_type_and_json(&dt, &42.into()).unwrap(),
+            FillValue::Int64(42)
+        );
+        assert!(FillValue::from_data_type_and_json(&dt, &1.5.into()).is_err());
+        assert!(FillValue::Int64(0).is_valid_for(&dt));
+
+        let dt = DataType::FixedLengthString(NonZeroU32::new(3).unwrap());
+        assert_eq!(
+            FillValue::from_data_type_and_json(&dt, &"abc".into()).unwrap(),
+            FillValue::String("abc".to_string())
+        );
+        assert!(FillValue::from_data_type_and_json(&dt, &"abcd".into()).is_err());
+        assert!(!FillValue::String("abcd".to_string()).is_valid_for(&dt));
+
+        assert_eq!(
+            FillValue::from_data_type_and_json(&DataType::Bytes, &"AAE=".into()).unwrap(),
+            FillValue::Bytes(vec![0, 1])
+        );
+        assert_eq!(
+            FillValue::from_data_type_and_json(&DataType::String, &Value::Null).unwrap(),
+            FillValue::String(String::new())
+        );
+        assert!(
+            FillValue::from_data_type_and_json(&DataType::Bytes, &"%".into()).is_err()
+        );
+
+        assert!(FillValue::Int32(0).is_valid_for(&DataType::Int32));
+        assert!(!FillValue::Int32(0).is_valid_for(&DataType::Float32));
+    }
+
     #[test]
     fn test_nan_inf_parsing() {
         assert_eq!(
@@ -6,8 +6,9 @@ use test_strategy::Arbitrary;
 
 pub mod data_type;
 pub mod fill_value;
+pub mod vlen;
 
-pub use data_type::DataType;
+pub use data_type::{DataType, Endianness, TimeUnit};
 pub use fill_value::FillValue;
 
 /// The shape of an array.
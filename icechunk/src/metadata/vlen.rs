@@ -0,0 +1,129 @@
+//! The `vlen-utf8` and `vlen-bytes` codecs, used by arrays of variable length types
+//!
+//! An encoded chunk starts with its number of elements, and then has the length and the
+//! bytes of each element, in row major order. Numbers are little endian `u32`, as written
+//! by numcodecs.
+use bytes::{BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+pub const VLEN_UTF8_CODEC: &str = "vlen-utf8";
+pub const VLEN_BYTES_CODEC: &str = "vlen-bytes";
+
+const HEADER_BYTES: u64 = 4;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum VlenError {
+    #[error("variable length chunk is truncated, it has {0} bytes")]
+    Truncated(usize),
+    #[error("variable length chunk has {found} elements, expected {expected}")]
+    ElementCount { expected: u64, found: u64 },
+    #[error("element {0} of the variable length chunk is not valid UTF-8")]
+    InvalidUtf8(usize),
+    #[error("variable length chunk has {0} bytes after its last element")]
+    TrailingBytes(usize),
+}
+
+/// The size of the encoded elements of a chunk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VlenSize {
+    pub elements: u64,
+    /// Without the lengths
+    pub bytes: u64,
+}
+
+/// The smallest encoded chunk of `elements` elements, all of them empty
+pub fn min_vlen_chunk_size(elements: u64) -> u64 {
+    HEADER_BYTES * (elements + 1)
+}
+
+pub fn encode_vlen<I: AsRef<[u8]>>(elements: impl ExactSizeIterator<Item = I>) -> Bytes {
+    let mut res = BytesMut::new();
+    res.put_u32_le(elements.len() as u32);
+    for element in elements {
+        let element = element.as_ref();
+        res.put_u32_le(element.len() as u32);
+        res.put_slice(element);
+    }
+    res.freeze()
+}
+
+pub fn decode_vlen(chunk: &[u8]) -> Result<Vec<&[u8]>, VlenError> {
+    let truncated = || VlenError::Truncated(chunk.len());
+    let read_u32 = |at: usize| -> Result<usize, VlenError> {
+        let bytes = chunk.get(at..at + 4).ok_or_else(truncated)?;
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(bytes);
+        Ok(u32::from_le_bytes(buf) as usize)
+    };
+    let count = read_u32(0)?;
+    // every element takes at least its length, don't trust the count for the allocation
+    let mut res = Vec::with_capacity(count.min(chunk.len() / 4));
+    let mut at = 4;
+    for _ in 0..count {
+        let len = read_u32(at)?;
+        let start = at + 4;
+        res.push(chunk.get(start..start + len).ok_or_else(truncated)?);
+        at = start + len;
+    }
+    if at < chunk.len() {
+        return Err(VlenError::TrailingBytes(chunk.len() - at));
+    }
+    Ok(res)
+}
+
+/// Check `chunk` encodes `elements` elements, of valid UTF-8 if `utf8`
+pub fn validate_vlen(
+    chunk: &[u8],
+    elements: u64,
+    utf8: bool,
+) -> Result<VlenSize, VlenError> {
+    let decoded = decode_vlen(chunk)?;
+    if decoded.len() as u64 != elements {
+        return Err(VlenError::ElementCount {
+            expected: elements,
+            found: decoded.len() as u64,
+        });
+    }
+    if utf8 {
+        if let Some(index) =
+            decoded.iter().position(|element| std::str::from_utf8(element).is_err())
+        {
+            return Err(VlenError::InvalidUtf8(index));
+        }
+    }
+    Ok(VlenSize {
+        elements,
+        bytes: decoded.iter().map(|element| element.len() as u64).sum(),
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_vlen_encoding() {
+        let chunk = encode_vlen(["ab", "", "ñ"].iter());
+        assert_eq!(chunk.len() as u64, min_vlen_chunk_size(3) + 4);
+        assert_eq!(decode_vlen(&chunk).unwrap(), vec![&b"ab"[..], b"", "ñ".as_bytes()]);
+        assert_eq!(
+            validate_vlen(&chunk, 3, true),
+            Ok(VlenSize { elements: 3, bytes: 4 })
+        );
+        assert_eq!(
+            validate_vlen(&chunk, 4, true),
+            Err(VlenError::ElementCount { expected: 4, found: 3 })
+        );
+        assert_eq!(decode_vlen(&chunk[..chunk.len() - 1]), Err(VlenError::Truncated(19)));
+        let mut padded = chunk.to_vec();
+        padded.push(0);
+        assert_eq!(decode_vlen(&padded), Err(VlenError::TrailingBytes(1)));
+
+        let binary = encode_vlen([vec![0xff, 0xfe]].iter());
+        assert!(validate_vlen(&binary, 1, false).is_ok());
+        assert_eq!(validate_vlen(&binary, 1, true), Err(VlenError::InvalidUtf8(0)));
+    }
+}
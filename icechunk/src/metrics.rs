@@ -0,0 +1,420 @@
+//! What a session cost, see [`crate::Repository::metrics`]
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use serde::Serialize;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+    storage::{
+        CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+        StorageCapabilities, StorageResult,
+    },
+};
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+/// The counters of a session since it was built
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SessionMetrics {
+    /// Chunk objects uploaded, packed chunks count once per pack
+    pub chunks_written: u64,
+    /// Bytes of the chunk objects uploaded. The size of metadata objects is only known to
+    /// the storage, they are not included.
+    pub bytes_uploaded: u64,
+    /// Snapshots, manifests, attributes and transaction logs requested from the storage,
+    /// including the ones served from a cache
+    pub metadata_fetches: u64,
+    /// Lookups in the caches of the storage since the session was built. Caches are shared
+    /// by the sessions built on the same storage, concurrent sessions count each other's
+    /// lookups.
+    pub cache: CachingStats,
+    /// Chunk uploads retried by [`crate::Repository::ingest`]
+    pub retries: u64,
+    /// Rebases over commits made to the branch since the session started
+    pub conflict_retries: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SessionCounters {
+    chunks_written: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    metadata_fetches: AtomicU64,
+    retries: AtomicU64,
+    conflict_retries: AtomicU64,
+    cache_baseline: Option<CachingStats>,
+}
+
+impl SessionCounters {
+    pub(crate) fn new(storage: &DynStorage) -> Self {
+        Self { cache_baseline: storage.cache_stats(), ..Self::default() }
+    }
+
+    pub(crate) fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn conflict_retry(&self) {
+        self.conflict_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn metadata_fetch(&self) {
+        self.metadata_fetches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn metrics(&self, storage: &DynStorage) -> SessionMetrics {
+        let cache = match (storage.cache_stats(), self.cache_baseline) {
+            (Some(current), Some(baseline)) => current.since(&baseline),
+            (current, _) => current.unwrap_or_default(),
+        };
+        SessionMetrics {
+            chunks_written: self.chunks_written.load(Ordering::Relaxed),
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            metadata_fetches: self.metadata_fetches.load(Ordering::Relaxed),
+            cache,
+            retries: self.retries.load(Ordering::Relaxed),
+            conflict_retries: self.conflict_retries.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The storage of a session, it counts the requests into the [`SessionCounters`]
+#[derive(Debug)]
+pub(crate) struct MeteredStorage {
+    backend: DynStorage,
+    counters: Arc<SessionCounters>,
+}
+
+impl MeteredStorage {
+    pub(crate) fn new(backend: DynStorage, counters: Arc<SessionCounters>) -> Self {
+        Self { backend, counters }
+    }
+}
+
+impl private::Sealed for MeteredStorage {}
+
+#[async_trait]
+impl Storage for MeteredStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        let len = bytes.len() as u64;
+        self.backend.write_chunk(id, bytes).await?;
+        self.counters.chunks_written.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_uploaded.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.counters.metadata_fetch();
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        conflicts::basic_solver::BasicConflictSolver,
+        format::{ChunkIndices, Path},
+        metadata::{
+            ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, StorageTransformer,
+        },
+        repository::{ChunkPayload, ZarrArrayMetadata},
+        storage::MemCachingStorage,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_session_metrics() -> Result<(), Box<dyn Error>> {
+        let backend: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage: DynStorage =
+            Arc::new(MemCachingStorage::new(backend, 2, 2, 0, 2, 0));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let root = ds.commit("main", "root", None).await?;
+
+        let mut ds = Repository::update(Arc::clone(&storage), root.clone())
+            .with_inline_threshold_bytes(0)
+            .build();
+        let mut other = Repository::update(Arc::clone(&storage), root).build();
+        assert_eq!(ds.metrics(), SessionMetrics::default());
+
+        let path: Path = "/array".try_into()?;
+        let meta = ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![1.try_into()?]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![Codec { name: "mycodec".to_string(), configuration: None }],
+            storage_transformers: Some(vec![StorageTransformer {
+                name: "mytransformer".to_string(),
+                configuration: None,
+            }]),
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        ds.add_array(path.clone(), meta).await?;
+        let data = Bytes::from_static(b"hello world");
+        let payload = ds.get_chunk_writer()(data.clone()).await?;
+        assert!(matches!(payload, ChunkPayload::Ref(_)));
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+
+        other.add_group("/other".try_into()?).await?;
+        other.commit("main", "other", None).await?;
+        ds.rebase(&BasicConflictSolver::default(), "main").await?;
+        ds.commit("main", "array", None).await?;
+
+        let metrics = ds.metrics();
+        assert_eq!(metrics.chunks_written, 1);
+        assert_eq!(metrics.bytes_uploaded, data.len() as u64);
+        assert_eq!(metrics.conflict_retries, 1);
+        assert_eq!(metrics.retries, 0);
+        assert!(metrics.metadata_fetches > 0);
+        assert!(metrics.cache.snapshots.hits > 0);
+        assert_eq!(other.metrics().chunks_written, 0);
+
+        let fetches = ds.metrics().metadata_fetches;
+        ds.unmetered_storage().fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(ds.metrics().metadata_fetches, fetches);
+        ds.storage().fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(ds.metrics().metadata_fetches, fetches + 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+//! A snapshot as a read-only tree of files, for mounting as a filesystem
+//!
+//! [`SnapshotFs`] presents the keys of the zarr store of a snapshot as files: every group
+//! and array is a directory with its `zarr.json`, and the chunks are files under the `c`
+//! directory of their array, like in a zarr store on disk. Tools that only read files can
+//! consume the data without exporting it.
+//!
+//! The operations are the ones of a FUSE filesystem, [`SnapshotFs::lookup`],
+//! [`SnapshotFs::getattr`], [`SnapshotFs::readdir`] and [`SnapshotFs::read`], so an adapter
+//! for a FUSE library only translates them. This crate doesn't mount anything itself, it
+//! doesn't depend on a FUSE library, the adapter and the mount entry point belong to the
+//! application that does. The snapshot never changes, inodes are stable and nothing needs
+//! to be invalidated.
+//!
+//! The sizes of the chunk files come from their references in the manifests, read once when
+//! the filesystem is created. Only the `zarr.json` files, and the chunks whose stored bytes
+//! are transformed, are read whole to get their size, the first time it's needed.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::{
+    format::{manifest::ChunkPayload, snapshot::NodeData, ByteRange, SnapshotId},
+    repository::RepositoryError,
+    zarr::{AccessMode, Key, Store, StoreError},
+    Repository,
+};
+
+pub type Inode = u64;
+
+/// The inode of the root directory, by FUSE convention
+pub const ROOT_INODE: Inode = 1;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum MountError {
+    #[error("no such inode {0}")]
+    NotFound(Inode),
+    #[error("no entry `{name}` in directory {parent}")]
+    NameNotFound { parent: Inode, name: String },
+    #[error("inode {0} is not a directory")]
+    NotADirectory(Inode),
+    #[error("inode {0} is a directory")]
+    IsADirectory(Inode),
+    #[error("the session has uncommitted changes, only snapshots can be mounted")]
+    UncommittedChanges,
+    #[error("store error {0}")]
+    Store(#[from] StoreError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+}
+
+pub type MountResult<A> = Result<A, MountError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Directory,
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileAttr {
+    pub inode: Inode,
+    pub kind: FileKind,
+    /// Zero for directories
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub inode: Inode,
+    pub name: String,
+    pub kind: FileKind,
+}
+
+#[derive(Debug)]
+enum Entry {
+    Directory {
+        children: BTreeMap<String, Inode>,
+    },
+    /// The key of the file in the zarr store
+    File {
+        key: String,
+    },
+}
+
+/// The files of a snapshot, see the [module documentation](self)
+#[derive(Debug)]
+pub struct SnapshotFs {
+    store: Store,
+    snapshot: SnapshotId,
+    /// The entry of inode `i` is at `i - ROOT_INODE`
+    entries: Vec<Entry>,
+    sizes: Mutex<HashMap<Inode, u64>>,
+}
+
+impl SnapshotFs {
+    /// The files of the snapshot of `repository`, which must not have uncommitted changes
+    pub async fn new(repository: Repository) -> MountResult<Self> {
+        if repository.has_uncommitted_changes() {
+            return Err(MountError::UncommittedChanges);
+        }
+        let snapshot = repository.snapshot_id().clone();
+        let known_sizes = chunk_sizes(&repository).await?;
+        let store = Store::from_repository(repository, AccessMode::ReadOnly, None, None);
+        let keys: Vec<String> = store.list().await?.try_collect().await?;
+
+        let mut entries = vec![Entry::Directory { children: BTreeMap::new() }];
+        let mut sizes = HashMap::new();
+        for key in keys {
+            let mut parent = ROOT_INODE;
+            let components: Vec<&str> =
+                key.split('/').filter(|name| !name.is_empty()).collect();
+            for (depth, name) in components.iter().enumerate() {
+                let is_file = depth == components.len() - 1;
+                let next = entries.len() as Inode + ROOT_INODE;
+                let Entry::Directory { children } = &mut entries[index(parent)] else {
+                    // a key can't be both a file and a directory in a zarr store
+                    break;
+                };
+                let inode = *children.entry(name.to_string()).or_insert(next);
+                if inode == next {
+                    entries.push(if is_file {
+                        if let Some(size) = known_sizes.get(&key) {
+                            sizes.insert(inode, *size);
+                        }
+                        Entry::File { key: key.clone() }
+                    } else {
+                        Entry::Directory { children: BTreeMap::new() }
+                    });
+                }
+                parent = inode;
+            }
+        }
+        Ok(Self { store, snapshot, entries, sizes: Mutex::new(sizes) })
+    }
+
+    /// The mounted snapshot
+    pub fn snapshot_id(&self) -> &SnapshotId {
+        &self.snapshot
+    }
+
+    /// The entry `name` of directory `parent`
+    pub async fn lookup(&self, parent: Inode, name: &str) -> MountResult<FileAttr> {
+        let Entry::Directory { children } = self.entry(parent)? else {
+            return Err(MountError::NotADirectory(parent));
+        };
+        let inode = children
+            .get(name)
+            .ok_or_else(|| MountError::NameNotFound { parent, name: name.to_string() })?;
+        self.getattr(*inode).await
+    }
+
+    pub async fn getattr(&self, inode: Inode) -> MountResult<FileAttr> {
+        match self.entry(inode)? {
+            Entry::Directory { .. } => {
+                Ok(FileAttr { inode, kind: FileKind::Directory, size: 0 })
+            }
+            Entry::File { key } => {
+                let size = self.size(inode, key).await?;
+                Ok(FileAttr { inode, kind: FileKind::File, size })
+            }
+        }
+    }
+
+    /// The entries of directory `inode`, by name, without `.` and `..`
+    pub fn readdir(&self, inode: Inode) -> MountResult<Vec<DirEntry>> {
+        let Entry::Directory { children } = self.entry(inode)? else {
+            return Err(MountError::NotADirectory(inode));
+        };
+        children
+            .iter()
+            .map(|(name, child)| {
+                let kind = match self.entry(*child)? {
+                    Entry::Directory { .. } => FileKind::Directory,
+                    Entry::File { .. } => FileKind::File,
+                };
+                Ok(DirEntry { inode: *child, name: name.clone(), kind })
+            })
+            .collect()
+    }
+
+    /// Up to `size` bytes of file `inode` from `offset`, fewer at the end of the file
+    pub async fn read(&self, inode: Inode, offset: u64, size: u64) -> MountResult<Bytes> {
+        let Entry::File { key } = self.entry(inode)? else {
+            return Err(MountError::IsADirectory(inode));
+        };
+        let len = self.size(inode, key).await?;
+        let end = offset.saturating_add(size).min(len);
+        if offset >= end {
+            return Ok(Bytes::new());
+        }
+        Ok(self.store.get(key, &ByteRange::bounded(offset, end)).await?)
+    }
+
+    fn entry(&self, inode: Inode) -> MountResult<&Entry> {
+        inode
+            .checked_sub(ROOT_INODE)
+            .and_then(|i| self.entries.get(i as usize))
+            .ok_or(MountError::NotFound(inode))
+    }
+
+    async fn size(&self, inode: Inode, key: &str) -> MountResult<u64> {
+        if let Some(size) = self.sizes.lock().await.get(&inode) {
+            return Ok(*size);
+        }
+        let size = self.store.get(key, &ByteRange::ALL).await?.len() as u64;
+        self.sizes.lock().await.insert(inode, size);
+        Ok(size)
+    }
+}
+
+/// The sizes of the chunk files given by their references, by key
+///
+/// Transformed chunks decode to a different size than the one stored, they are left out.
+async fn chunk_sizes(repository: &Repository) -> MountResult<HashMap<String, u64>> {
+    let mut res = HashMap::new();
+    // the nodes listed don't carry their manifests
+    let arrays: Vec<_> = repository
+        .list_nodes()
+        .await?
+        .filter(|node| matches!(node.node_data, NodeData::Array(..)))
+        .map(|node| node.path)
+        .collect();
+    for path in arrays {
+        let node = repository.get_node(&path).await?;
+        let NodeData::Array(_, manifests) = &node.node_data else { continue };
+        for manifest in manifests {
+            let manifest = repository
+                .storage()
+                .fetch_manifests(&manifest.object_id)
+                .await
+                .map_err(RepositoryError::from)?;
+            for (coords, payload) in Arc::clone(&manifest).iter(node.id.clone()) {
+                if !manifest.chunk_transformers(&node.id, &coords).is_empty() {
+                    continue;
+                }
+                let size = match payload {
+                    ChunkPayload::Inline(bytes) => bytes.len() as u64,
+                    ChunkPayload::Ref(chunk_ref) => chunk_ref.length,
+                    ChunkPayload::Virtual(virtual_ref) => virtual_ref.chunk_length(),
+                };
+                let key = Key::Chunk { node_path: node.path.clone(), coords };
+                res.insert(key.to_string(), size);
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn index(inode: Inode) -> usize {
+    (inode - ROOT_INODE) as usize
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ChunkIndices, Path},
+        storage::logging::LoggingStorage,
+        test_utils::{array_metadata, new_in_memory_storage},
+        Storage,
+    };
+
+    #[tokio::test]
+    async fn test_snapshot_fs() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> = new_in_memory_storage();
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let array: Path = "/group/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_group("/group".try_into()?).await?;
+        repo.add_array(array.clone(), array_metadata(vec![2])).await?;
+        for i in 0..2 {
+            let writer = repo.get_chunk_writer();
+            let payload = writer(Bytes::from(format!("chunk {i}"))).await?;
+            repo.set_chunk_ref(array.clone(), ChunkIndices(vec![i]), Some(payload))
+                .await?;
+        }
+        repo.commit("main", "data", None).await?;
+        let logging = Arc::new(LoggingStorage::new(storage));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let fs = SnapshotFs::new(
+            Repository::from_branch_tip(logging_c, "main").await?.build(),
+        )
+        .await?;
+        let fetched_chunks = || {
+            logging
+                .fetch_operations()
+                .iter()
+                .filter(|(op, _)| op == "fetch_chunk")
+                .count()
+        };
+
+        let names = |entries: Vec<DirEntry>| {
+            entries.into_iter().map(|e| (e.name, e.kind)).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(fs.readdir(ROOT_INODE)?),
+            vec![
+                ("group".to_string(), FileKind::Directory),
+                ("zarr.json".to_string(), FileKind::File)
+            ]
+        );
+        let group = fs.lookup(ROOT_INODE, "group").await?;
+        let array = fs.lookup(group.inode, "array").await?;
+        assert_eq!(
+            names(fs.readdir(array.inode)?),
+            vec![
+                ("c".to_string(), FileKind::Directory),
+                ("zarr.json".to_string(), FileKind::File)
+            ]
+        );
+        let chunks = fs.lookup(array.inode, "c").await?;
+        let chunk = fs.lookup(chunks.inode, "1").await?;
+        assert_eq!((chunk.kind, chunk.size), (FileKind::File, 7));
+        // the size is the one of the chunk reference
+        assert_eq!(fetched_chunks(), 0);
+        assert_eq!(fs.read(chunk.inode, 0, 4096).await?, "chunk 1");
+        assert_eq!(fs.read(chunk.inode, 2, 3).await?, "unk");
+        assert!(fs.read(chunk.inode, 7, 10).await?.is_empty());
+
+        let meta = fs.lookup(array.inode, "zarr.json").await?;
+        let json: serde_json::Value =
+            serde_json::from_slice(&fs.read(meta.inode, 0, meta.size).await?)?;
+        assert_eq!(json["node_type"], "array");
+        assert_eq!(json["shape"], serde_json::json!([2]));
+
+        assert!(matches!(
+            fs.lookup(chunks.inode, "5").await,
+            Err(MountError::NameNotFound { .. })
+        ));
+        assert!(matches!(fs.readdir(chunk.inode), Err(MountError::NotADirectory(_))));
+        assert!(matches!(
+            fs.read(array.inode, 0, 1).await,
+            Err(MountError::IsADirectory(_))
+        ));
+        assert!(matches!(fs.getattr(1000).await, Err(MountError::NotFound(1000))));
+        Ok(())
+    }
+}
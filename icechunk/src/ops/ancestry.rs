@@ -0,0 +1,204 @@
+//! An index of snapshot ancestors, to find common ancestors without walking the history
+//!
+//! [`AncestryIndex`] is a skip list over the parent links: every indexed snapshot points to
+//! its ancestors 1, 2, 4, 8... commits back. Reaching the `n`th ancestor of a snapshot takes
+//! `log2(n)` jumps, and so does finding the common ancestor of two snapshots, however long
+//! their branches diverged. The histories of several branches share their ancestors in the
+//! index.
+use std::collections::HashMap;
+
+use crate::format::{snapshot::Snapshot, SnapshotId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IndexEntry {
+    /// Number of ancestors of the snapshot, zero for the first commit
+    depth: u32,
+    /// The ancestor `2^k` commits back at position `k`, as far as the index goes
+    jumps: Vec<SnapshotId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommonAncestor {
+    Found(SnapshotId),
+    /// The snapshots don't share any ancestor
+    Unrelated,
+    /// The common ancestor is older than the indexed history of the snapshots
+    BeyondIndex,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AncestryIndex {
+    entries: HashMap<SnapshotId, IndexEntry>,
+}
+
+impl AncestryIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index `snapshot` and up to `max_depth` of its ancestors, zero indexes all of them
+    ///
+    /// Only the history stored in the snapshot is used, no snapshots are fetched.
+    pub fn insert(&mut self, snapshot: &Snapshot, max_depth: usize) {
+        let limit = if max_depth == 0 { usize::MAX } else { max_depth };
+        let ancestors = snapshot.short_term_history.iter().map(|meta| &meta.id);
+        let mut chain: Vec<&SnapshotId> =
+            std::iter::once(&snapshot.metadata.id).chain(ancestors.take(limit)).collect();
+        // oldest first, so the ancestors are indexed before their descendants
+        chain.reverse();
+        let tip_depth = snapshot.total_parents;
+        let oldest_depth = tip_depth.saturating_sub(chain.len() as u32 - 1);
+        for (position, id) in chain.iter().enumerate() {
+            if self.entries.contains_key(*id) {
+                continue;
+            }
+            let mut jumps: Vec<SnapshotId> = Vec::new();
+            if let Some(parent) = position.checked_sub(1).map(|p| chain[p]) {
+                jumps.push(parent.clone());
+                let mut k = 0;
+                while let Some(next) =
+                    self.entries.get(&jumps[k]).and_then(|e| e.jumps.get(k))
+                {
+                    let next = next.clone();
+                    jumps.push(next);
+                    k += 1;
+                }
+            }
+            let depth = oldest_depth + position as u32;
+            self.entries.insert((*id).clone(), IndexEntry { depth, jumps });
+        }
+    }
+
+    pub fn contains(&self, id: &SnapshotId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Number of ancestors of `id`, if it's indexed
+    pub fn depth(&self, id: &SnapshotId) -> Option<u32> {
+        self.entries.get(id).map(|entry| entry.depth)
+    }
+
+    /// The ancestor `n` commits back from `id`, `id` itself for zero, if it's indexed
+    pub fn ancestor(&self, id: &SnapshotId, n: u32) -> Option<&SnapshotId> {
+        let (mut current, _) = self.entries.get_key_value(id)?;
+        let mut remaining = n;
+        while remaining > 0 {
+            let k = remaining.trailing_zeros();
+            current = self.entries.get(current)?.jumps.get(k as usize)?;
+            remaining &= remaining - 1;
+        }
+        Some(current)
+    }
+
+    /// The most recent snapshot that is `a` or `b`, or an ancestor of both
+    ///
+    /// Both must be indexed, otherwise the result is [`CommonAncestor::BeyondIndex`].
+    pub fn common_ancestor(&self, a: &SnapshotId, b: &SnapshotId) -> CommonAncestor {
+        let (Some(depth_a), Some(depth_b)) = (self.depth(a), self.depth(b)) else {
+            return CommonAncestor::BeyondIndex;
+        };
+        let depth = depth_a.min(depth_b);
+        let (Some(mut a), Some(mut b)) =
+            (self.ancestor(a, depth_a - depth), self.ancestor(b, depth_b - depth))
+        else {
+            return CommonAncestor::BeyondIndex;
+        };
+        if a == b {
+            return CommonAncestor::Found(a.clone());
+        }
+        // a and b are at the same depth, jump as far as they stay different
+        let mut k = self.entries[a].jumps.len().min(self.entries[b].jumps.len());
+        while k > 0 {
+            k -= 1;
+            let (jumps_a, jumps_b) = (&self.entries[a].jumps, &self.entries[b].jumps);
+            if let (Some(next_a), Some(next_b)) = (jumps_a.get(k), jumps_b.get(k)) {
+                if next_a != next_b {
+                    (a, b) = (next_a, next_b);
+                }
+            }
+        }
+        match (self.entries[a].jumps.first(), self.entries[b].jumps.first()) {
+            (Some(parent_a), Some(parent_b)) if parent_a == parent_b => {
+                CommonAncestor::Found(parent_a.clone())
+            }
+            (Some(_), Some(_)) => CommonAncestor::BeyondIndex,
+            _ if depth == 0 => CommonAncestor::Unrelated,
+            _ => CommonAncestor::BeyondIndex,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path, repository::RepositoryError, test_utils::new_in_memory_storage,
+        Repository, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_common_ancestor() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> = new_in_memory_storage();
+        let mut main = Repository::init(Arc::clone(&storage), false).await?.build();
+        let mut trunk = vec![main.snapshot_id().clone()];
+        for i in 0..5 {
+            main.add_group(Path::try_from(format!("/main-{i}").as_str())?).await?;
+            trunk.push(main.commit("main", "trunk", None).await?);
+        }
+        main.new_branch("feature").await?;
+        let mut feature =
+            Repository::from_branch_tip(Arc::clone(&storage), "feature").await?.build();
+        let mut feature_tip = trunk[5].clone();
+        for i in 0..7 {
+            feature.add_group(Path::try_from(format!("/feature-{i}").as_str())?).await?;
+            feature_tip = feature.commit("feature", "feature", None).await?;
+        }
+        for i in 5..8 {
+            main.add_group(Path::try_from(format!("/main-{i}").as_str())?).await?;
+            trunk.push(main.commit("main", "trunk", None).await?);
+        }
+
+        let mut index = AncestryIndex::new();
+        index.insert(storage.fetch_snapshot(&trunk[8]).await?.as_ref(), 0);
+        index.insert(storage.fetch_snapshot(&feature_tip).await?.as_ref(), 0);
+        assert_eq!(index.depth(&trunk[8]), Some(8));
+        assert_eq!(index.depth(&feature_tip), Some(12));
+        assert_eq!(index.ancestor(&trunk[8], 6), Some(&trunk[2]));
+        assert_eq!(index.ancestor(&feature_tip, 7), Some(&trunk[5]));
+        assert_eq!(
+            index.common_ancestor(&trunk[8], &feature_tip),
+            CommonAncestor::Found(trunk[5].clone())
+        );
+        assert_eq!(
+            index.common_ancestor(&trunk[3], &feature_tip),
+            CommonAncestor::Found(trunk[3].clone())
+        );
+
+        assert_eq!(
+            main.common_ancestor(&feature_tip, &trunk[8]).await?,
+            Some(trunk[5].clone())
+        );
+        // the common ancestor is 7 commits back from the feature branch
+        let mut limited = AncestryIndex::new();
+        limited.insert(storage.fetch_snapshot(&trunk[8]).await?.as_ref(), 6);
+        limited.insert(storage.fetch_snapshot(&feature_tip).await?.as_ref(), 6);
+        assert_eq!(
+            limited.common_ancestor(&trunk[8], &feature_tip),
+            CommonAncestor::BeyondIndex
+        );
+        let limited_repo = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_max_history_depth(6)
+            .build();
+        assert!(matches!(
+            limited_repo.common_ancestor(&feature_tip, &trunk[8]).await,
+            Err(RepositoryError::HistoryTooDeep { limit: 6, .. })
+        ));
+        Ok(())
+    }
+}
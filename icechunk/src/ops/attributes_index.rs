@@ -0,0 +1,181 @@
+//! An index of user attributes, to find nodes by attribute value
+//!
+//! Repositories configured with [`crate::RepositoryBuilder::with_indexed_attribute`] record,
+//! in each snapshot they commit, the values of the indexed top level attribute keys for every
+//! node. [`crate::Repository::find_by_attribute`] answers queries from the index, instead of
+//! going through the attributes of every node. The index of a commit is derived from the
+//! index of its parent, only the nodes changed by the session are read.
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    change_set::ChangeSet,
+    format::{
+        snapshot::{NodeSnapshot, Snapshot, UserAttributesSnapshot},
+        Path,
+    },
+    repository::{RepositoryError, RepositoryResult},
+};
+
+/// The snapshot property that stores the [`AttributesIndex`] of the snapshot
+pub const ATTRIBUTES_INDEX_PROPERTY: &str = "icechunk.attributes_index";
+
+/// The value of each indexed key for the nodes that have it, by key and path
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AttributesIndex {
+    keys: BTreeMap<String, BTreeMap<Path, Value>>,
+}
+
+impl AttributesIndex {
+    /// Index the attributes `keys` of all nodes in `snapshot`
+    pub fn build(snapshot: &Snapshot, keys: &[String]) -> Self {
+        let mut res = Self {
+            keys: keys.iter().map(|key| (key.clone(), BTreeMap::new())).collect(),
+        };
+        for node in snapshot.iter() {
+            res.insert(node);
+        }
+        res
+    }
+
+    /// The index of `new_snapshot`, committed by `change_set` on top of a parent with index
+    /// `parent`
+    ///
+    /// Falls back to [`AttributesIndex::build`] if the parent indexes different keys.
+    pub fn update(
+        parent: Option<AttributesIndex>,
+        new_snapshot: &Snapshot,
+        change_set: &ChangeSet,
+        keys: &[String],
+    ) -> Self {
+        let wanted: BTreeSet<&String> = keys.iter().collect();
+        let Some(mut res) =
+            parent.filter(|index| index.keys.keys().collect::<BTreeSet<_>>() == wanted)
+        else {
+            return Self::build(new_snapshot, keys);
+        };
+        let changed: HashSet<_> = change_set
+            .user_attributes_updated_nodes()
+            .chain(change_set.new_nodes().map(|(_, id)| id))
+            .collect();
+        for paths in res.keys.values_mut() {
+            paths.retain(|path, _| match new_snapshot.get_node(path) {
+                Ok(node) => !changed.contains(&node.id),
+                Err(_) => false,
+            });
+        }
+        for node in new_snapshot.iter().filter(|node| changed.contains(&node.id)) {
+            res.insert(node);
+        }
+        res
+    }
+
+    fn insert(&mut self, node: &NodeSnapshot) {
+        // attributes stored outside the snapshot are not indexed
+        let Some(UserAttributesSnapshot::Inline(attributes)) = &node.user_attributes
+        else {
+            return;
+        };
+        for (key, paths) in self.keys.iter_mut() {
+            if let Some(value) = attributes.parsed.get(key) {
+                paths.insert(node.path.clone(), value.clone());
+            }
+        }
+    }
+
+    pub fn is_indexed(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    /// The nodes where the attribute `key` is `value`, `None` if the key is not indexed
+    pub fn find(&self, key: &str, value: &Value) -> Option<Vec<Path>> {
+        self.keys.get(key).map(|paths| {
+            paths
+                .iter()
+                .filter(|(_, v)| *v == value)
+                .map(|(path, _)| path.clone())
+                .collect()
+        })
+    }
+
+    pub fn to_property(&self) -> Value {
+        // serializing these types to a json value cannot fail
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// The index recorded in `snapshot`, if any
+    pub fn from_snapshot(snapshot: &Snapshot) -> RepositoryResult<Option<Self>> {
+        snapshot
+            .properties
+            .get(ATTRIBUTES_INDEX_PROPERTY)
+            .map(|value| {
+                serde_json::from_value(value.clone()).map_err(|err| {
+                    RepositoryError::InvalidAttributesIndex {
+                        snapshot: snapshot.metadata.id.clone(),
+                        message: err.to_string(),
+                    }
+                })
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{metadata::UserAttributes, ObjectStorage, Repository, Storage};
+
+    #[tokio::test]
+    async fn test_find_by_attribute() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_indexed_attribute("standard_name")
+            .build();
+        let sst = json!("sea_surface_temperature");
+        let attrs = |name: &Value| {
+            UserAttributes::try_new(json!({"standard_name": name}).to_string().as_bytes())
+        };
+        ds.add_group(Path::root()).await?;
+        for name in ["a", "b", "c"] {
+            let path = Path::root().child(name)?;
+            ds.add_group(path.clone()).await?;
+            ds.set_user_attributes(path, Some(attrs(&sst)?)).await?;
+        }
+        ds.commit("main", "first", None).await?;
+        assert_eq!(
+            ds.find_by_attribute("standard_name", &sst).await?,
+            vec!["/a".try_into()?, "/b".try_into()?, "/c".try_into()?]
+        );
+
+        // the next commit only reads the changed nodes
+        ds.set_user_attributes("/b".try_into()?, Some(attrs(&json!("salinity"))?))
+            .await?;
+        ds.delete_group("/c".try_into()?).await?;
+        ds.add_group("/d".try_into()?).await?;
+        ds.set_user_attributes("/d".try_into()?, Some(attrs(&sst)?)).await?;
+        ds.commit("main", "second", None).await?;
+        let expected: Vec<Path> = vec!["/a".try_into()?, "/d".try_into()?];
+        assert_eq!(ds.find_by_attribute("standard_name", &sst).await?, expected);
+
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        let index = AttributesIndex::from_snapshot(&snapshot)?.unwrap();
+        let keys = ["standard_name".to_string()];
+        assert_eq!(index, AttributesIndex::build(&snapshot, &keys));
+
+        // keys that are not indexed are found by reading every node
+        let ds = Repository::from_branch_tip(storage, "main").await?.build();
+        assert_eq!(ds.find_by_attribute("standard_name", &sst).await?, expected);
+        assert_eq!(ds.find_by_attribute("units", &json!("K")).await?, vec![]);
+        Ok(())
+    }
+}
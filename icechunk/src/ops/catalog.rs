@@ -0,0 +1,512 @@
+//! STAC catalog records of snapshots
+//!
+//! [`stac_item`] and [`stac_collection`] describe the hierarchy of a snapshot with the STAC
+//! datacube extension: arrays are the variables, and their named dimensions the dimensions
+//! of the cube. The title, description and extent come from the ACDD attributes of the
+//! root group, like `geospatial_lon_min` or `time_coverage_start`, unless the
+//! [`CatalogConfig`] sets them. Coordinate arrays, of a single dimension with its name, tell
+//! the kind of their dimension with the CF `axis` or `standard_name` attributes.
+//!
+//! Attributes stored outside the snapshot are not read.
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::format::snapshot::{NodeData, NodeSnapshot, Snapshot, UserAttributesSnapshot};
+
+pub const STAC_VERSION: &str = "1.0.0";
+pub const DATACUBE_EXTENSION: &str =
+    "https://stac-extensions.github.io/datacube/v2.2.0/schema.json";
+pub const ZARR_MEDIA_TYPE: &str = "application/vnd+zarr";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CatalogError {
+    #[error("invalid attribute `{key}`: {message}")]
+    InvalidAttribute { key: String, message: String },
+}
+
+pub type CatalogResult<A> = Result<A, CatalogError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogConfig {
+    pub id: String,
+    /// Where clients can open the dataset, the `data` asset of the record
+    pub href: Option<String>,
+    /// West, south, east and north bounds, in degrees
+    pub bbox: Option<[f64; 4]>,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub license: String,
+}
+
+impl CatalogConfig {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            href: None,
+            bbox: None,
+            start: None,
+            end: None,
+            license: "proprietary".to_string(),
+        }
+    }
+
+    pub fn with_href(mut self, href: impl Into<String>) -> Self {
+        self.href = Some(href.into());
+        self
+    }
+
+    pub fn with_bbox(mut self, bbox: [f64; 4]) -> Self {
+        self.bbox = Some(bbox);
+        self
+    }
+
+    pub fn with_interval(
+        mut self,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Self {
+        self.start = start;
+        self.end = end;
+        self
+    }
+
+    pub fn with_license(mut self, license: impl Into<String>) -> Self {
+        self.license = license.into();
+        self
+    }
+}
+
+/// What the record says about the snapshot, before it's laid out as an item or collection
+struct Description {
+    title: Option<String>,
+    description: Option<String>,
+    keywords: Vec<String>,
+    bbox: Option<[f64; 4]>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    dimensions: Map<String, Value>,
+    variables: Map<String, Value>,
+}
+
+fn attributes(node: &NodeSnapshot) -> Option<&Map<String, Value>> {
+    match &node.user_attributes {
+        Some(UserAttributesSnapshot::Inline(atts)) => atts.parsed.as_object(),
+        _ => None,
+    }
+}
+
+fn string_attribute(atts: Option<&Map<String, Value>>, key: &str) -> Option<String> {
+    atts?.get(key)?.as_str().map(str::to_string)
+}
+
+fn number_attribute(
+    atts: Option<&Map<String, Value>>,
+    key: &str,
+) -> CatalogResult<Option<f64>> {
+    let Some(value) = atts.and_then(|atts| atts.get(key)) else { return Ok(None) };
+    let number = match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+    number.map(Some).ok_or_else(|| CatalogError::InvalidAttribute {
+        key: key.to_string(),
+        message: format!("expected a number, got {}", value),
+    })
+}
+
+fn time_attribute(
+    atts: Option<&Map<String, Value>>,
+    key: &str,
+) -> CatalogResult<Option<DateTime<Utc>>> {
+    let Some(value) = atts.and_then(|atts| atts.get(key)) else { return Ok(None) };
+    value
+        .as_str()
+        .and_then(|s| DateTime::parse_from_rfc3339(s.trim()).ok())
+        .map(|time| Some(time.with_timezone(&Utc)))
+        .ok_or_else(|| CatalogError::InvalidAttribute {
+            key: key.to_string(),
+            message: format!("expected an RFC 3339 time, got {}", value),
+        })
+}
+
+/// The datacube `type` and `axis` of the dimension a coordinate array is for
+fn dimension_kind(
+    name: &str,
+    coordinate: Option<&NodeSnapshot>,
+) -> (&'static str, Option<&'static str>) {
+    let atts = coordinate.and_then(attributes);
+    let axis = string_attribute(atts, "axis").map(|axis| axis.to_lowercase());
+    let standard_name = string_attribute(atts, "standard_name");
+    let hint = axis.as_deref().or(standard_name.as_deref()).unwrap_or(name);
+    match hint.to_lowercase().as_str() {
+        "x" | "lon" | "longitude" | "projection_x_coordinate" => ("spatial", Some("x")),
+        "y" | "lat" | "latitude" | "projection_y_coordinate" => ("spatial", Some("y")),
+        "z" | "depth" | "height" | "altitude" => ("spatial", Some("z")),
+        "t" | "time" => ("temporal", None),
+        _ => ("other", None),
+    }
+}
+
+fn describe(snapshot: &Snapshot, config: &CatalogConfig) -> CatalogResult<Description> {
+    let root = snapshot.iter().find(|node| node.path.to_string() == "/");
+    let root_atts = root.and_then(attributes);
+
+    let bbox = match config.bbox {
+        Some(bbox) => Some(bbox),
+        None => {
+            let bounds = [
+                number_attribute(root_atts, "geospatial_lon_min")?,
+                number_attribute(root_atts, "geospatial_lat_min")?,
+                number_attribute(root_atts, "geospatial_lon_max")?,
+                number_attribute(root_atts, "geospatial_lat_max")?,
+            ];
+            match bounds {
+                [Some(west), Some(south), Some(east), Some(north)] => {
+                    Some([west, south, east, north])
+                }
+                _ => None,
+            }
+        }
+    };
+    let start = match config.start {
+        Some(start) => Some(start),
+        None => time_attribute(root_atts, "time_coverage_start")?,
+    };
+    let end = match config.end {
+        Some(end) => Some(end),
+        None => time_attribute(root_atts, "time_coverage_end")?,
+    };
+    let keywords = match root_atts.and_then(|atts| atts.get("keywords")) {
+        Some(Value::String(keywords)) => keywords
+            .split(',')
+            .map(|keyword| keyword.trim().to_string())
+            .filter(|keyword| !keyword.is_empty())
+            .collect(),
+        Some(Value::Array(keywords)) => {
+            keywords.iter().filter_map(|k| k.as_str().map(str::to_string)).collect()
+        }
+        _ => Vec::new(),
+    };
+
+    let arrays: Vec<_> = snapshot
+        .iter()
+        .filter_map(|node| match &node.node_data {
+            NodeData::Array(meta, _) => Some((node, meta)),
+            _ => None,
+        })
+        .collect();
+    let mut dimension_names = BTreeMap::new();
+    for (node, meta) in arrays.iter() {
+        for name in meta.dimension_names.iter().flatten().flatten() {
+            let coordinate = meta.is_coordinate_for(name).then_some(*node);
+            let entry = dimension_names.entry(name.clone()).or_insert(None);
+            if coordinate.is_some() {
+                *entry = coordinate;
+            }
+        }
+    }
+    let iso = |time: Option<DateTime<Utc>>| {
+        time.map_or(Value::Null, |time| Value::String(time.to_rfc3339()))
+    };
+    let mut dimensions = Map::new();
+    for (name, coordinate) in dimension_names {
+        let (kind, axis) = dimension_kind(&name, coordinate);
+        let mut dimension = Map::new();
+        dimension.insert("type".to_string(), json!(kind));
+        match (axis, bbox) {
+            (Some("x"), Some([west, _, east, _])) => {
+                dimension.insert("axis".to_string(), json!("x"));
+                dimension.insert("extent".to_string(), json!([west, east]));
+            }
+            (Some("y"), Some([_, south, _, north])) => {
+                dimension.insert("axis".to_string(), json!("y"));
+                dimension.insert("extent".to_string(), json!([south, north]));
+            }
+            (Some(axis), _) => {
+                dimension.insert("axis".to_string(), json!(axis));
+            }
+            (None, _) if kind == "temporal" => {
+                dimension.insert("extent".to_string(), json!([iso(start), iso(end)]));
+            }
+            (None, _) => {}
+        }
+        if let Some(description) =
+            string_attribute(coordinate.and_then(attributes), "long_name")
+        {
+            dimension.insert("description".to_string(), json!(description));
+        }
+        dimensions.insert(name, Value::Object(dimension));
+    }
+
+    let mut variables = Map::new();
+    for (node, meta) in arrays {
+        let Some(names) = &meta.dimension_names else { continue };
+        let names: Option<Vec<&String>> = names.iter().map(Option::as_ref).collect();
+        let Some(names) = names else { continue };
+        let coordinate = names.len() == 1 && meta.is_coordinate_for(names[0]);
+        let atts = attributes(node);
+        let mut variable = Map::new();
+        variable.insert("dimensions".to_string(), json!(names));
+        variable.insert(
+            "type".to_string(),
+            json!(if coordinate { "auxiliary" } else { "data" }),
+        );
+        if let Some(description) = string_attribute(atts, "long_name") {
+            variable.insert("description".to_string(), json!(description));
+        }
+        if let Some(unit) = string_attribute(atts, "units") {
+            variable.insert("unit".to_string(), json!(unit));
+        }
+        let name = node.path.to_string().trim_start_matches('/').to_string();
+        variables.insert(name, Value::Object(variable));
+    }
+
+    Ok(Description {
+        title: string_attribute(root_atts, "title"),
+        description: string_attribute(root_atts, "summary")
+            .or_else(|| string_attribute(root_atts, "description")),
+        keywords,
+        bbox,
+        start,
+        end,
+        dimensions,
+        variables,
+    })
+}
+
+fn assets(config: &CatalogConfig, title: Option<&String>) -> Value {
+    match &config.href {
+        Some(href) => json!({
+            "data": {
+                "href": href,
+                "type": ZARR_MEDIA_TYPE,
+                "roles": ["data"],
+                "title": title,
+            }
+        }),
+        None => json!({}),
+    }
+}
+
+/// A STAC item for `snapshot`
+///
+/// The snapshot time is the datetime of the item when the record has no temporal extent.
+pub fn stac_item(snapshot: &Snapshot, config: &CatalogConfig) -> CatalogResult<Value> {
+    let desc = describe(snapshot, config)?;
+    let geometry = desc.bbox.map_or(Value::Null, |[west, south, east, north]| {
+        json!({
+            "type": "Polygon",
+            "coordinates": [[
+                [west, south], [east, south], [east, north], [west, north], [west, south]
+            ]],
+        })
+    });
+
+    let mut properties = Map::new();
+    match (desc.start, desc.end) {
+        (Some(start), Some(end)) if start != end => {
+            properties.insert("datetime".to_string(), Value::Null);
+            properties.insert("start_datetime".to_string(), json!(start.to_rfc3339()));
+            properties.insert("end_datetime".to_string(), json!(end.to_rfc3339()));
+        }
+        (Some(time), _) | (None, Some(time)) => {
+            properties.insert("datetime".to_string(), json!(time.to_rfc3339()));
+        }
+        (None, None) => {
+            let written_at = snapshot.metadata.written_at.to_rfc3339();
+            properties.insert("datetime".to_string(), json!(written_at));
+        }
+    }
+    if let Some(title) = &desc.title {
+        properties.insert("title".to_string(), json!(title));
+    }
+    if let Some(description) = &desc.description {
+        properties.insert("description".to_string(), json!(description));
+    }
+    if !desc.keywords.is_empty() {
+        properties.insert("keywords".to_string(), json!(desc.keywords));
+    }
+    properties.insert("license".to_string(), json!(config.license));
+    properties
+        .insert("icechunk:snapshot".to_string(), json!(snapshot.metadata.id.to_string()));
+    properties.insert("cube:dimensions".to_string(), Value::Object(desc.dimensions));
+    properties.insert("cube:variables".to_string(), Value::Object(desc.variables));
+
+    let mut item = json!({
+        "type": "Feature",
+        "stac_version": STAC_VERSION,
+        "stac_extensions": [DATACUBE_EXTENSION],
+        "id": config.id,
+        "geometry": geometry,
+        "properties": properties,
+        "links": [],
+        "assets": assets(config, desc.title.as_ref()),
+    });
+    if let (Some(bbox), Some(item)) = (desc.bbox, item.as_object_mut()) {
+        item.insert("bbox".to_string(), json!(bbox));
+    }
+    Ok(item)
+}
+
+/// A STAC collection for `snapshot`
+///
+/// The spatial extent is the whole globe if the record has none.
+pub fn stac_collection(
+    snapshot: &Snapshot,
+    config: &CatalogConfig,
+) -> CatalogResult<Value> {
+    let desc = describe(snapshot, config)?;
+    let bbox = desc.bbox.unwrap_or([-180.0, -90.0, 180.0, 90.0]);
+    let iso = |time: Option<DateTime<Utc>>| time.map(|time| time.to_rfc3339());
+    Ok(json!({
+        "type": "Collection",
+        "stac_version": STAC_VERSION,
+        "stac_extensions": [DATACUBE_EXTENSION],
+        "id": config.id,
+        "title": desc.title,
+        "description": desc.description.or(desc.title.clone()).unwrap_or_else(|| config.id.clone()),
+        "keywords": desc.keywords,
+        "license": config.license,
+        "extent": {
+            "spatial": { "bbox": [bbox] },
+            "temporal": { "interval": [[iso(desc.start), iso(desc.end)]] },
+        },
+        "cube:dimensions": desc.dimensions,
+        "cube:variables": desc.variables,
+        "links": [],
+        "assets": assets(config, desc.title.as_ref()),
+    }))
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue, UserAttributes},
+        repository::ZarrArrayMetadata,
+        ObjectStorage, Repository, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_stac_records() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let attrs = |value: Value| UserAttributes::try_new(value.to_string().as_bytes());
+        ds.add_group(Path::root()).await?;
+        ds.set_user_attributes(
+            Path::root(),
+            Some(attrs(json!({
+                "title": "Sea surface temperature",
+                "summary": "Daily SST",
+                "keywords": "ocean, temperature",
+                "geospatial_lon_min": -10,
+                "geospatial_lon_max": "10.5",
+                "geospatial_lat_min": 40,
+                "geospatial_lat_max": 50,
+                "time_coverage_start": "2024-01-01T00:00:00Z",
+                "time_coverage_end": "2024-12-31T00:00:00Z",
+            }))?),
+        )
+        .await?;
+        let meta = |shape: Vec<u64>, dims: Vec<&str>| ZarrArrayMetadata {
+            chunk_shape: ChunkShape(
+                shape.iter().filter_map(|n| NonZeroU64::new(*n)).collect(),
+            ),
+            shape,
+            data_type: DataType::Float32,
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Float32(0.0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: Some(
+                dims.into_iter().map(|d| Some(d.to_string())).collect(),
+            ),
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        for (name, axis) in [("time", "T"), ("lat", "Y"), ("lon", "X")] {
+            let path = Path::root().child(name)?;
+            ds.add_array(path.clone(), meta(vec![2], vec![name])).await?;
+            ds.set_user_attributes(path, Some(attrs(json!({"axis": axis}))?)).await?;
+        }
+        let sst: Path = "/sst".try_into()?;
+        ds.add_array(sst.clone(), meta(vec![2, 2, 2], vec!["time", "lat", "lon"]))
+            .await?;
+        ds.set_user_attributes(
+            sst,
+            Some(attrs(json!({"units": "K", "long_name": "SST"}))?),
+        )
+        .await?;
+        let snapshot_id = ds.commit("main", "first", None).await?;
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+
+        let config = CatalogConfig::new("sst").with_href("s3://bucket/sst");
+        let item = stac_item(&snapshot, &config)?;
+        assert_eq!(item["type"], "Feature");
+        assert_eq!(item["bbox"], json!([-10.0, 40.0, 10.5, 50.0]));
+        assert_eq!(item["geometry"]["coordinates"][0][2], json!([10.5, 50.0]));
+        let properties = &item["properties"];
+        assert_eq!(properties["datetime"], Value::Null);
+        assert_eq!(properties["start_datetime"], "2024-01-01T00:00:00+00:00");
+        assert_eq!(properties["keywords"], json!(["ocean", "temperature"]));
+        assert_eq!(properties["icechunk:snapshot"], json!(snapshot_id.to_string()));
+        assert_eq!(
+            properties["cube:dimensions"],
+            json!({
+                "lat": {"type": "spatial", "axis": "y", "extent": [40.0, 50.0]},
+                "lon": {"type": "spatial", "axis": "x", "extent": [-10.0, 10.5]},
+                "time": {
+                    "type": "temporal",
+                    "extent": ["2024-01-01T00:00:00+00:00", "2024-12-31T00:00:00+00:00"]
+                },
+            })
+        );
+        assert_eq!(
+            properties["cube:variables"]["sst"],
+            json!({
+                "dimensions": ["time", "lat", "lon"],
+                "type": "data",
+                "description": "SST",
+                "unit": "K",
+            })
+        );
+        assert_eq!(properties["cube:variables"]["lat"]["type"], "auxiliary");
+        assert_eq!(item["assets"]["data"]["type"], ZARR_MEDIA_TYPE);
+
+        let collection =
+            stac_collection(&snapshot, &config.with_bbox([0.0, 0.0, 1.0, 1.0]))?;
+        assert_eq!(collection["description"], "Daily SST");
+        assert_eq!(
+            collection["extent"]["spatial"]["bbox"],
+            json!([[0.0, 0.0, 1.0, 1.0]])
+        );
+        assert_eq!(
+            collection["extent"]["temporal"]["interval"],
+            json!([["2024-01-01T00:00:00+00:00", "2024-12-31T00:00:00+00:00"]])
+        );
+
+        ds.set_user_attributes(
+            Path::root(),
+            Some(attrs(json!({"time_coverage_start": "yesterday"}))?),
+        )
+        .await?;
+        let snapshot =
+            storage.fetch_snapshot(&ds.commit("main", "bad", None).await?).await?;
+        assert!(matches!(
+            stac_item(&snapshot, &CatalogConfig::new("sst")),
+            Err(CatalogError::InvalidAttribute { key, .. }) if key == "time_coverage_start"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,566 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use serde_json::Value;
+
+use crate::{
+    format::{
+        hashing::{ContentHasher, HashAlgorithm},
+        manifest::{ChunkPayload, ChunkRef, VirtualChunkRef},
+        snapshot::{NodeData, NodeSnapshot, Snapshot, SnapshotMetadata},
+        ByteRange, ChunkIndices, Path, SnapshotId,
+    },
+    repository::{
+        chunk_ref_byte_range, load_user_attributes, RepositoryResult, ZarrArrayMetadata,
+    },
+    Storage,
+};
+
+/// The snapshot property that stores the [`ContentHash`] of the snapshot, when the
+/// repository is configured to record it
+pub const CONTENT_HASH_PROPERTY: &str = "icechunk.content_hash";
+/// The snapshot property that stores the [`HashAlgorithm`] of its [`CONTENT_HASH_PROPERTY`]
+pub const CONTENT_HASH_ALGORITHM_PROPERTY: &str = "icechunk.content_hash_algorithm";
+
+const FETCH_CONCURRENCY: usize = 10;
+
+/// A digest of the contents of a snapshot, or one of its nodes, with a [`HashAlgorithm`]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContentHash(pub [u8; 32]);
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}", self.0.iter().format(""))
+    }
+}
+
+impl fmt::Debug for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Parse the hexadecimal form of the hash, as it's displayed
+impl FromStr for ContentHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid content hash `{s}`");
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut res = [0; 32];
+        for (byte, hex) in res.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        }
+        Ok(ContentHash(res))
+    }
+}
+
+/// How two snapshots differ, see [`verify_reproducibility`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentDifference {
+    OnlyInFirst(Path),
+    OnlyInSecond(Path),
+    Different(Path),
+}
+
+/// The content hash of every node in `snapshot`
+///
+/// The hash covers the path, type, metadata and user attributes of the node, and for
+/// arrays, the coordinates and bytes of every chunk. It doesn't depend on object ids, or on
+/// how the chunks are stored: the same bytes materialized, packed or inline, produce the
+/// same hash. Virtual chunks are hashed by location, their bytes are not fetched.
+///
+/// Materialized chunks are fetched from `storage`, this reads the whole snapshot.
+pub async fn node_content_hashes(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &Snapshot,
+    algorithm: HashAlgorithm,
+) -> RepositoryResult<BTreeMap<Path, ContentHash>> {
+    let mut res = BTreeMap::new();
+    for node in snapshot.iter() {
+        let hash = node_content_hash(storage, node, algorithm, true).await?;
+        res.insert(node.path.clone(), hash);
+    }
+    Ok(res)
+}
+
+/// The content hash of `snapshot`, see [`node_content_hashes`] for what it covers
+pub async fn snapshot_content_hash(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &Snapshot,
+    algorithm: HashAlgorithm,
+) -> RepositoryResult<ContentHash> {
+    let mut hasher = algorithm.hasher();
+    for (path, hash) in node_content_hashes(storage, snapshot, algorithm).await? {
+        update_str(&mut hasher, &path.to_string());
+        hasher.update(hash.0);
+    }
+    Ok(ContentHash(hasher.finalize()))
+}
+
+/// The root hash of `snapshot`, to pin the exact snapshot a reader expects
+///
+/// Unlike [`snapshot_content_hash`], it depends on how the data is stored, and it's computed
+/// without reading any chunk: it covers the id, metadata and properties of the snapshot,
+/// the ancestors in its history, and the nodes like [`node_content_hashes`], with
+/// materialized chunks hashed by id and byte range instead of by content. Manifests and
+/// attributes files are fetched from `storage`. See
+/// [`crate::repository::OpenOptions::expect_root_hash`] to verify it when opening a session.
+pub async fn snapshot_root_hash(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &Snapshot,
+    algorithm: HashAlgorithm,
+) -> RepositoryResult<ContentHash> {
+    let mut hasher = algorithm.hasher();
+    update_snapshot_metadata(&mut hasher, &snapshot.metadata);
+    update_json_object(&mut hasher, snapshot.properties.iter());
+    update_len(&mut hasher, snapshot.short_term_history.len());
+    for parent in snapshot.short_term_history.iter() {
+        update_snapshot_metadata(&mut hasher, parent);
+    }
+    update_len(&mut hasher, snapshot.len());
+    for node in snapshot.iter() {
+        hasher.update(node_content_hash(storage, node, algorithm, false).await?.0);
+    }
+    Ok(ContentHash(hasher.finalize()))
+}
+
+/// Compare the contents of two snapshots, maybe in different repositories
+///
+/// Returns the nodes that differ, an empty result means the snapshots are data-identical,
+/// even if they were written by different sessions, with different object ids. Both
+/// snapshots are hashed with `algorithm`.
+pub async fn verify_reproducibility(
+    storage_a: &(dyn Storage + Send + Sync),
+    a: &SnapshotId,
+    storage_b: &(dyn Storage + Send + Sync),
+    b: &SnapshotId,
+    algorithm: HashAlgorithm,
+) -> RepositoryResult<Vec<ContentDifference>> {
+    let snapshot_a = storage_a.fetch_snapshot(a).await?;
+    let hashes_a = node_content_hashes(storage_a, &snapshot_a, algorithm).await?;
+    let snapshot_b = storage_b.fetch_snapshot(b).await?;
+    let mut hashes_b = node_content_hashes(storage_b, &snapshot_b, algorithm).await?;
+    let mut res = Vec::new();
+    for (path, hash_a) in hashes_a {
+        match hashes_b.remove(&path) {
+            None => res.push(ContentDifference::OnlyInFirst(path)),
+            Some(hash_b) if hash_b != hash_a => {
+                res.push(ContentDifference::Different(path))
+            }
+            Some(_) => {}
+        }
+    }
+    res.extend(hashes_b.into_keys().map(ContentDifference::OnlyInSecond));
+    Ok(res)
+}
+
+/// With `fetch_chunks` false, materialized chunks are hashed by reference
+async fn node_content_hash(
+    storage: &(dyn Storage + Send + Sync),
+    node: &NodeSnapshot,
+    algorithm: HashAlgorithm,
+    fetch_chunks: bool,
+) -> RepositoryResult<ContentHash> {
+    let mut hasher = algorithm.hasher();
+    update_str(&mut hasher, &node.path.to_string());
+    // attributes hash the same inline or in an attributes file
+    match load_user_attributes(storage, node.user_attributes.clone()).await? {
+        None => hasher.update([0]),
+        Some(atts) => {
+            hasher.update([1]);
+            update_json(&mut hasher, &atts.parsed);
+        }
+    }
+    match &node.node_data {
+        NodeData::Group => hasher.update([0]),
+        NodeData::Custom(custom) => {
+            hasher.update([1]);
+            update_str(&mut hasher, &custom.kind);
+            update_bytes(&mut hasher, &custom.metadata);
+        }
+        NodeData::Array(meta, manifests) => {
+            hasher.update([2]);
+            update_array_metadata(&mut hasher, meta);
+            let mut chunks = BTreeMap::new();
+            for manifest in manifests {
+                let manifest = storage.fetch_manifests(&manifest.object_id).await?;
+                for ((node_id, coords), payload) in manifest.chunks() {
+                    if node_id == &node.id {
+                        chunks.entry(coords.clone()).or_insert_with(|| payload.clone());
+                    }
+                }
+            }
+            let digests: Vec<(ChunkIndices, [u8; 32])> = stream::iter(chunks)
+                .map(|(coords, payload)| async move {
+                    let digest =
+                        chunk_digest(storage, payload, algorithm, fetch_chunks).await;
+                    digest.map(|digest| (coords, digest))
+                })
+                .buffered(FETCH_CONCURRENCY)
+                .try_collect()
+                .await?;
+            update_len(&mut hasher, digests.len());
+            for (coords, digest) in digests {
+                update_len(&mut hasher, coords.0.len());
+                for idx in coords.0 {
+                    hasher.update(idx.to_le_bytes());
+                }
+                hasher.update(digest);
+            }
+        }
+    }
+    Ok(ContentHash(hasher.finalize()))
+}
+
+async fn chunk_digest(
+    storage: &(dyn Storage + Send + Sync),
+    payload: ChunkPayload,
+    algorithm: HashAlgorithm,
+    fetch_chunks: bool,
+) -> RepositoryResult<[u8; 32]> {
+    let mut hasher = algorithm.hasher();
+    match payload {
+        ChunkPayload::Inline(bytes) => {
+            hasher.update([0]);
+            hasher.update(bytes);
+        }
+        ChunkPayload::Ref(ChunkRef { id, offset, length }) if !fetch_chunks => {
+            hasher.update([2]);
+            hasher.update(id.0);
+            hasher.update(offset.to_le_bytes());
+            hasher.update(length.to_le_bytes());
+        }
+        ChunkPayload::Ref(ChunkRef { id, offset, length }) => {
+            let range = chunk_ref_byte_range(&ByteRange::ALL, offset, length);
+            hasher.update([0]);
+            hasher.update(storage.fetch_chunk(&id, &range).await?);
+        }
+        ChunkPayload::Virtual(VirtualChunkRef { location, offset, length, slice }) => {
+            hasher.update([1]);
+            update_str(&mut hasher, &format!("{:?}", location));
+            hasher.update(offset.to_le_bytes());
+            hasher.update(length.to_le_bytes());
+            // unsliced references hash like before slices existed
+            if let Some(slice) = slice {
+                update_str(&mut hasher, &format!("{:?}", slice));
+            }
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+fn update_array_metadata(hasher: &mut ContentHasher, meta: &ZarrArrayMetadata) {
+    update_len(hasher, meta.shape.len());
+    for dim in meta.shape.iter() {
+        hasher.update(dim.to_le_bytes());
+    }
+    update_str(hasher, &meta.data_type.to_string());
+    update_len(hasher, meta.chunk_shape.0.len());
+    for dim in meta.chunk_shape.0.iter() {
+        hasher.update(dim.get().to_le_bytes());
+    }
+    update_str(hasher, &format!("{:?}", meta.chunk_key_encoding));
+    update_str(hasher, &format!("{:?}", meta.fill_value));
+    update_len(hasher, meta.codecs.len());
+    for codec in meta.codecs.iter() {
+        update_named_config(hasher, &codec.name, codec.configuration.as_ref());
+    }
+    let transformers = meta.storage_transformers.as_deref().unwrap_or_default();
+    update_len(hasher, transformers.len());
+    for transformer in transformers {
+        update_named_config(
+            hasher,
+            &transformer.name,
+            transformer.configuration.as_ref(),
+        );
+    }
+    let dimension_names = meta.dimension_names.as_deref().unwrap_or_default();
+    update_len(hasher, dimension_names.len());
+    for name in dimension_names {
+        match name {
+            None => hasher.update([0]),
+            Some(name) => {
+                hasher.update([1]);
+                update_str(hasher, name);
+            }
+        }
+    }
+}
+
+fn update_named_config(
+    hasher: &mut ContentHasher,
+    name: &str,
+    configuration: Option<&std::collections::HashMap<String, Value>>,
+) {
+    update_str(hasher, name);
+    match configuration {
+        None => hasher.update([0]),
+        Some(conf) => {
+            hasher.update([1]);
+            update_json_object(hasher, conf.iter());
+        }
+    }
+}
+
+fn update_snapshot_metadata(hasher: &mut ContentHasher, metadata: &SnapshotMetadata) {
+    hasher.update(metadata.id.0);
+    update_str(hasher, &metadata.written_at.to_rfc3339());
+    update_str(hasher, &metadata.message);
+}
+
+fn update_len(hasher: &mut ContentHasher, len: usize) {
+    hasher.update((len as u64).to_le_bytes());
+}
+
+fn update_bytes(hasher: &mut ContentHasher, bytes: &[u8]) {
+    update_len(hasher, bytes.len());
+    hasher.update(bytes);
+}
+
+fn update_str(hasher: &mut ContentHasher, s: &str) {
+    update_bytes(hasher, s.as_bytes());
+}
+
+/// Hash `value` independently of the order of the keys in its objects
+fn update_json(hasher: &mut ContentHasher, value: &Value) {
+    match value {
+        Value::Null => hasher.update([0]),
+        Value::Bool(b) => hasher.update([1, *b as u8]),
+        Value::Number(n) => {
+            hasher.update([2]);
+            update_str(hasher, &n.to_string());
+        }
+        Value::String(s) => {
+            hasher.update([3]);
+            update_str(hasher, s);
+        }
+        Value::Array(values) => {
+            hasher.update([4]);
+            update_len(hasher, values.len());
+            for value in values {
+                update_json(hasher, value);
+            }
+        }
+        Value::Object(map) => {
+            hasher.update([5]);
+            update_json_object(hasher, map.iter());
+        }
+    }
+}
+
+fn update_json_object<'a>(
+    hasher: &mut ContentHasher,
+    entries: impl Iterator<Item = (&'a String, &'a Value)>,
+) {
+    let entries: Vec<_> = entries.sorted_by_key(|(key, _)| *key).collect();
+    update_len(hasher, entries.len());
+    for (key, value) in entries {
+        update_str(hasher, key);
+        update_json(hasher, value);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue, UserAttributes},
+        repository::{OpenOptions, RepositoryError},
+        storage::logging::LoggingStorage,
+        ObjectStorage, Repository,
+    };
+
+    async fn write_dataset(
+        storage: Arc<dyn Storage + Send + Sync>,
+        inline_threshold: u16,
+        chunk: &'static [u8],
+        algorithm: HashAlgorithm,
+    ) -> Result<SnapshotId, Box<dyn Error>> {
+        let mut ds = Repository::init(storage, false)
+            .await?
+            .with_inline_threshold_bytes(inline_threshold)
+            .with_record_content_hash(true)
+            .with_hash_algorithm(algorithm)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"a": 1, "b": [true]}"#)?),
+        )
+        .await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        for (idx, data) in [b"hello".as_slice(), chunk].into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(Bytes::from_static(data)).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx as u64]), Some(payload))
+                .await?;
+        }
+        Ok(ds.commit("main", "write", None).await?)
+    }
+
+    #[tokio::test]
+    async fn test_verify_reproducibility() -> Result<(), Box<dyn Error>> {
+        let storage_a: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage_b: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage_c: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        // the same data, inline in one repo and materialized in the other
+        let sha256 = HashAlgorithm::Sha256;
+        let a = write_dataset(Arc::clone(&storage_a), 512, b"world", sha256).await?;
+        let b = write_dataset(Arc::clone(&storage_b), 0, b"world", sha256).await?;
+        let c = write_dataset(Arc::clone(&storage_c), 512, b"earth", sha256).await?;
+
+        assert!(verify_reproducibility(
+            storage_a.as_ref(),
+            &a,
+            storage_b.as_ref(),
+            &b,
+            sha256
+        )
+        .await?
+        .is_empty());
+        assert_eq!(
+            verify_reproducibility(
+                storage_a.as_ref(),
+                &a,
+                storage_c.as_ref(),
+                &c,
+                sha256
+            )
+            .await?,
+            vec![ContentDifference::Different("/array".try_into()?)]
+        );
+
+        let snapshot_a = storage_a.fetch_snapshot(&a).await?;
+        let snapshot_b = storage_b.fetch_snapshot(&b).await?;
+        let hash = snapshot_content_hash(storage_a.as_ref(), &snapshot_a, sha256).await?;
+        assert_eq!(hash.to_string().len(), 64);
+        assert_eq!(
+            snapshot_a.properties.get(CONTENT_HASH_PROPERTY),
+            Some(&Value::from(hash.to_string()))
+        );
+        assert_eq!(
+            snapshot_a.properties.get(CONTENT_HASH_PROPERTY),
+            snapshot_b.properties.get(CONTENT_HASH_PROPERTY)
+        );
+        assert_eq!(
+            snapshot_a.properties.get(CONTENT_HASH_ALGORITHM_PROPERTY),
+            Some(&Value::from("sha256"))
+        );
+
+        // the algorithm is recorded with the hash, the same data hashes the same with it
+        let storage_d: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let blake3 = HashAlgorithm::Blake3;
+        let d = write_dataset(Arc::clone(&storage_d), 0, b"world", blake3).await?;
+        let snapshot_d = storage_d.fetch_snapshot(&d).await?;
+        let hash_d =
+            snapshot_content_hash(storage_a.as_ref(), &snapshot_a, blake3).await?;
+        assert_ne!(hash_d, hash);
+        assert_eq!(
+            snapshot_d.properties.get(CONTENT_HASH_PROPERTY),
+            Some(&Value::from(hash_d.to_string()))
+        );
+        assert_eq!(
+            snapshot_d.properties.get(CONTENT_HASH_ALGORITHM_PROPERTY),
+            Some(&Value::from("blake3"))
+        );
+
+        let root_id = snapshot_a.short_term_history[0].id.clone();
+        assert_eq!(
+            verify_reproducibility(
+                storage_a.as_ref(),
+                &root_id,
+                storage_b.as_ref(),
+                &b,
+                sha256
+            )
+            .await?,
+            vec![
+                ContentDifference::OnlyInSecond(Path::root()),
+                ContentDifference::OnlyInSecond("/array".try_into()?),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_root_hash() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let sha256 = HashAlgorithm::Sha256;
+        let id = write_dataset(Arc::clone(&storage), 0, b"world", sha256).await?;
+        Repository::update(Arc::clone(&storage), id.clone())
+            .build()
+            .tag("v1", &id)
+            .await?;
+        let snapshot = storage.fetch_snapshot(&id).await?;
+        let hash = snapshot_root_hash(storage.as_ref(), &snapshot, sha256).await?;
+        assert_eq!(hash.to_string().parse::<ContentHash>()?, hash);
+        assert!("0f".parse::<ContentHash>().is_err());
+
+        // verified without reading any chunk
+        let before = logging.fetch_operations().len();
+        let options = OpenOptions::new().expect_root_hash(hash, sha256);
+        Repository::open_tag(Arc::clone(&storage), "v1", &options).await?;
+        Repository::open_snapshot(Arc::clone(&storage), id.clone(), &options).await?;
+        assert!(logging.fetch_operations()[before..]
+            .iter()
+            .all(|(op, _)| op != "fetch_chunk"));
+
+        // the chunk references are covered, unlike in the content hash
+        let other_storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let other =
+            write_dataset(Arc::clone(&other_storage), 0, b"world", sha256).await?;
+        let other = other_storage.fetch_snapshot(&other).await?;
+        assert_eq!(
+            snapshot_content_hash(other_storage.as_ref(), &other, sha256).await?,
+            snapshot_content_hash(storage.as_ref(), &snapshot, sha256).await?
+        );
+        assert_ne!(
+            snapshot_root_hash(other_storage.as_ref(), &other, sha256).await?,
+            hash
+        );
+
+        // a tampered snapshot is detected
+        let tampered = snapshot.with_nodes(snapshot.iter().skip(1).cloned());
+        backend.write_snapshot(id.clone(), Arc::new(tampered)).await?;
+        assert!(matches!(
+            Repository::open_tag(Arc::clone(&backend), "v1", &options).await,
+            Err(RepositoryError::RootHashMismatch { snapshot, expected, .. })
+                if snapshot == id && expected == hash
+        ));
+        Ok(())
+    }
+}
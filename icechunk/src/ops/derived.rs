@@ -0,0 +1,405 @@
+//! Arrays computed from other arrays, kept up to date incrementally
+//!
+//! A [`DerivedArrays`] registry pairs source arrays with derived arrays, like the levels of a
+//! downsampled pyramid, and the [`Derivation`] that computes them. On refresh, only the
+//! derived chunks that depend on source chunks changed since the last refresh are
+//! recomputed, using [`Repository::chunks_changed_since`]. Commits made with
+//! [`DerivedArrays::commit`] record the refresh in the [`DERIVED_PROPERTY`] of the snapshot,
+//! the next refresh starts from there.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    marker::PhantomData,
+    num::NonZeroU64,
+    ops::Range,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    array::{read_selection, write_region, ArrayError, DimSelection, Element},
+    format::{
+        snapshot::{NodeData, SnapshotProperties, ZarrArrayMetadata},
+        ChunkIndices, Path, SnapshotId,
+    },
+    ops::retention::expired_snapshots,
+    repository::RepositoryError,
+    Repository, StorageError,
+};
+
+/// The snapshot property that lists the derived arrays refreshed by the snapshot
+pub const DERIVED_PROPERTY: &str = "icechunk.derived_arrays";
+
+#[derive(Debug, Error)]
+pub enum DerivedError {
+    #[error("storage error `{0}`")]
+    Storage(#[from] StorageError),
+    #[error("repository error `{0}`")]
+    Repository(#[from] RepositoryError),
+    #[error("array error `{0}`")]
+    Array(#[from] ArrayError),
+    #[error("cannot derive `{target}`: {message}")]
+    Derivation { target: Path, message: String },
+}
+
+pub type DerivedResult<A> = Result<A, DerivedError>;
+
+/// Computes the chunks of a derived array from its source array
+#[async_trait]
+pub trait Derivation: Debug + Send + Sync {
+    /// The chunks of the derived array that depend on the source chunk at `coords`
+    fn affected_chunks(
+        &self,
+        source: &ZarrArrayMetadata,
+        target: &ZarrArrayMetadata,
+        coords: &ChunkIndices,
+    ) -> Vec<ChunkIndices>;
+
+    /// Write the `chunks` of the array at `target` to the session
+    async fn recompute(
+        &self,
+        repository: &mut Repository,
+        source: &Path,
+        target: &Path,
+        chunks: &BTreeSet<ChunkIndices>,
+    ) -> DerivedResult<()>;
+}
+
+#[derive(Debug, Clone)]
+pub struct DerivedArray {
+    pub source: Path,
+    pub target: Path,
+    pub derivation: Arc<dyn Derivation>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DerivedArrays {
+    arrays: Vec<DerivedArray>,
+}
+
+impl DerivedArrays {
+    pub fn with_derived(
+        mut self,
+        source: Path,
+        target: Path,
+        derivation: Arc<dyn Derivation>,
+    ) -> Self {
+        self.arrays.push(DerivedArray { source, target, derivation });
+        self
+    }
+
+    /// Recompute, in the session, the derived chunks affected by the source changes since
+    /// the last refresh, including the uncommitted changes
+    ///
+    /// Returns the number of chunks recomputed for each derived array. Finding the last
+    /// refresh reads the snapshots of the history, from the newest, until one recorded it.
+    pub async fn refresh(
+        &self,
+        repository: &mut Repository,
+    ) -> DerivedResult<BTreeMap<Path, usize>> {
+        let mut res = BTreeMap::new();
+        for derived in self.arrays.iter() {
+            let since = last_refresh(repository, &derived.target).await?;
+            let changed =
+                repository.chunks_changed_since(&derived.source, &since).await?;
+            let source = array_metadata(repository, &derived.source).await?;
+            let target = array_metadata(repository, &derived.target).await?;
+            let affected: BTreeSet<ChunkIndices> = changed
+                .written
+                .iter()
+                .chain(changed.deleted.iter())
+                .flat_map(|coords| {
+                    derived.derivation.affected_chunks(&source, &target, coords)
+                })
+                .collect();
+            if !affected.is_empty() {
+                derived
+                    .derivation
+                    .recompute(repository, &derived.source, &derived.target, &affected)
+                    .await?;
+            }
+            res.insert(derived.target.clone(), affected.len());
+        }
+        Ok(res)
+    }
+
+    /// [`DerivedArrays::refresh`] and commit, in the same transaction
+    ///
+    /// Call it in a follow-up session, with no other changes, to catch up with source
+    /// changes committed without refreshing.
+    pub async fn commit(
+        &self,
+        repository: &mut Repository,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> DerivedResult<SnapshotId> {
+        self.refresh(repository).await?;
+        let mut properties = properties.unwrap_or_default();
+        let targets =
+            self.arrays.iter().map(|derived| derived.target.to_string()).collect_vec();
+        properties.insert(DERIVED_PROPERTY.to_string(), targets.into());
+        Ok(repository.commit(update_branch_name, message, Some(properties)).await?)
+    }
+}
+
+/// The newest snapshot, in the history of the session, that refreshed `target`, or the
+/// first snapshot of the repository if none did
+async fn last_refresh(
+    repository: &Repository,
+    target: &Path,
+) -> DerivedResult<SnapshotId> {
+    let storage = repository.storage();
+    let current = storage.fetch_snapshot(repository.snapshot_id()).await?;
+    let target = serde_json::Value::from(target.to_string());
+    let mut oldest = repository.snapshot_id().clone();
+    let expired =
+        expired_snapshots(storage.as_ref()).await.map_err(RepositoryError::from)?;
+    let history = std::iter::once(oldest.clone())
+        .chain(Arc::clone(&current).local_ancestry().map(|meta| meta.id))
+        .filter(|id| !expired.contains(id));
+    for id in history {
+        let snapshot = storage.fetch_snapshot(&id).await?;
+        let refreshed = snapshot
+            .properties
+            .get(DERIVED_PROPERTY)
+            .and_then(|targets| targets.as_array())
+            .is_some_and(|targets| targets.contains(&target));
+        if refreshed {
+            return Ok(id);
+        }
+        oldest = id;
+    }
+    Ok(oldest)
+}
+
+async fn array_metadata(
+    repository: &Repository,
+    path: &Path,
+) -> DerivedResult<ZarrArrayMetadata> {
+    match repository.get_array(path).await?.node_data {
+        NodeData::Array(meta, _) => Ok(meta),
+        _ => Err(DerivedError::Derivation {
+            target: path.clone(),
+            message: "not an array".to_string(),
+        }),
+    }
+}
+
+/// Keep every `factors[dim]` element of the source, along each dimension
+///
+/// Element `i` of the derived array is element `i * factor` of the source, as in the
+/// levels of an overview pyramid computed by decimation. Chunks are read and written as
+/// uncompressed elements of type `T`, see [`crate::array`].
+#[derive(Debug)]
+pub struct Downsample<T> {
+    factors: Vec<NonZeroU64>,
+    element: PhantomData<fn() -> T>,
+}
+
+impl<T> Downsample<T> {
+    pub fn new(factors: Vec<NonZeroU64>) -> Self {
+        Self { factors, element: PhantomData }
+    }
+}
+
+/// The chunks of `meta` that intersect the element `region`
+fn chunks_in(meta: &ZarrArrayMetadata, region: &[Range<u64>]) -> Vec<ChunkIndices> {
+    // indexes before the grid origin have no chunk
+    let from_origin = |index: u64, origin: i64| {
+        u64::try_from(i128::from(index) - i128::from(origin)).unwrap_or(0)
+    };
+    region
+        .iter()
+        .zip(meta.chunk_shape.0.iter())
+        .zip(meta.chunk_grid().origin)
+        .map(|((range, size), origin)| {
+            let size = size.get();
+            let first = from_origin(range.start, origin) / size;
+            let last = from_origin(range.end, origin).div_ceil(size);
+            first..last
+        })
+        .multi_cartesian_product()
+        .map(ChunkIndices)
+        .collect()
+}
+
+/// The elements of the array in its chunk at `coords`
+fn chunk_region(meta: &ZarrArrayMetadata, coords: &ChunkIndices) -> Vec<Range<u64>> {
+    coords
+        .0
+        .iter()
+        .zip(meta.chunk_shape.0.iter())
+        .zip(meta.chunk_grid().origin)
+        .zip(meta.shape.iter())
+        .map(|(((c, size), origin), len)| {
+            let start = i128::from(*c) * i128::from(size.get()) + i128::from(origin);
+            let clamp = |index: i128| index.clamp(0, i128::from(*len)) as u64;
+            clamp(start)..clamp(start + i128::from(size.get()))
+        })
+        .collect_vec()
+}
+
+#[async_trait]
+impl<T: Element + Debug> Derivation for Downsample<T> {
+    fn affected_chunks(
+        &self,
+        source: &ZarrArrayMetadata,
+        target: &ZarrArrayMetadata,
+        coords: &ChunkIndices,
+    ) -> Vec<ChunkIndices> {
+        // the derived elements sampled from inside the source chunk
+        let region = chunk_region(source, coords)
+            .into_iter()
+            .zip(self.factors.iter())
+            .zip(target.shape.iter())
+            .map(|((range, factor), len)| {
+                let factor = factor.get();
+                range.start.div_ceil(factor).min(*len)
+                    ..range.end.div_ceil(factor).min(*len)
+            })
+            .collect_vec();
+        if region.iter().any(|range| range.is_empty()) {
+            return vec![];
+        }
+        chunks_in(target, &region)
+    }
+
+    async fn recompute(
+        &self,
+        repository: &mut Repository,
+        source: &Path,
+        target: &Path,
+        chunks: &BTreeSet<ChunkIndices>,
+    ) -> DerivedResult<()> {
+        let meta = array_metadata(repository, target).await?;
+        if self.factors.len() != meta.shape.len() {
+            return Err(DerivedError::Derivation {
+                target: target.clone(),
+                message: "one factor per dimension is needed".to_string(),
+            });
+        }
+        for coords in chunks {
+            let region = chunk_region(&meta, coords);
+            let selection = region
+                .iter()
+                .zip(self.factors.iter())
+                .map(|(range, factor)| {
+                    DimSelection::strided(
+                        range.start * factor.get()..range.end * factor.get(),
+                        *factor,
+                    )
+                })
+                .collect_vec();
+            let data = read_selection::<T>(repository, source, &selection).await?;
+            let origin = region.iter().map(|range| range.start).collect_vec();
+            write_region(repository, target, &origin, data.view()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use ndarray::{ArrayD, IxDyn};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        ObjectStorage, Storage,
+    };
+
+    fn metadata(len: u64) -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![len],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_downsampled_pyramid() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let source: Path = "/data".try_into()?;
+        let target: Path = "/data_2x".try_into()?;
+        let derived = DerivedArrays::default().with_derived(
+            source.clone(),
+            target.clone(),
+            Arc::new(Downsample::<i32>::new(vec![NonZeroU64::new(2).unwrap()])),
+        );
+
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(source.clone(), metadata(8)).await?;
+        ds.add_array(target.clone(), metadata(4)).await?;
+        let data = ArrayD::from_shape_vec(IxDyn(&[8]), (0..8).collect())?;
+        write_region(&mut ds, &source, &[0], data.view()).await?;
+        assert_eq!(
+            derived.refresh(&mut ds).await?,
+            BTreeMap::from([(target.clone(), 2)])
+        );
+        derived.commit(&mut ds, "main", "first", None).await?;
+        assert_eq!(
+            read_selection::<i32>(&ds, &target, &[DimSelection::from(0..4)])
+                .await?
+                .iter()
+                .copied()
+                .collect_vec(),
+            vec![0, 2, 4, 6]
+        );
+
+        // a source change committed without refreshing
+        let data = ArrayD::from_shape_vec(IxDyn(&[1]), vec![100])?;
+        write_region(&mut ds, &source, &[4], data.view()).await?;
+        ds.commit("main", "update", None).await?;
+
+        // the follow-up refresh only recomputes the affected chunk
+        assert_eq!(
+            derived.refresh(&mut ds).await?,
+            BTreeMap::from([(target.clone(), 1)])
+        );
+        derived.commit(&mut ds, "main", "refresh", None).await?;
+        assert_eq!(
+            read_selection::<i32>(&ds, &target, &[DimSelection::from(0..4)])
+                .await?
+                .iter()
+                .copied()
+                .collect_vec(),
+            vec![0, 2, 100, 6]
+        );
+        assert_eq!(derived.refresh(&mut ds).await?, BTreeMap::from([(target, 0)]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunks_follow_the_grid_origin() {
+        let meta = ZarrArrayMetadata { grid_origin: Some(vec![-1]), ..metadata(8) };
+        // the first chunk holds the index before the array, and index 0
+        assert_eq!(chunk_region(&meta, &ChunkIndices(vec![0])), vec![0..1]);
+        assert_eq!(chunk_region(&meta, &ChunkIndices(vec![4])), vec![7..8]);
+        assert_eq!(
+            chunks_in(&meta, std::slice::from_ref(&(1..4))),
+            vec![ChunkIndices(vec![1]), ChunkIndices(vec![2])]
+        );
+        let downsample = Downsample::<i32>::new(vec![NonZeroU64::new(2).unwrap()]);
+        assert_eq!(
+            downsample.affected_chunks(&meta, &metadata(4), &ChunkIndices(vec![2])),
+            vec![ChunkIndices(vec![1])]
+        );
+    }
+}
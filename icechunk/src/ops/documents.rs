@@ -0,0 +1,157 @@
+//! Documents attached to groups, like READMEs and licenses, versioned with the data
+//!
+//! [`crate::Repository::put_document`] stores the content of a document as an object of its
+//! own, of any size, and the snapshots committed after it reference it by name, in the
+//! [`DOCUMENTS_PROPERTY`] of their properties. Checking out an old version gets the
+//! documentation of that version. Deleting a group deletes its documents.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    change_set::ChangeSet,
+    format::{
+        snapshot::{NodeData, Snapshot},
+        ChunkId, Path,
+    },
+    repository::{RepositoryError, RepositoryResult},
+};
+
+/// The snapshot property that stores the [`DocumentTable`] of the snapshot
+pub const DOCUMENTS_PROPERTY: &str = "icechunk.documents";
+
+/// A document stored in the repository
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentRef {
+    /// The object with the content of the document, stored with the chunks
+    pub id: ChunkId,
+    pub size_bytes: u64,
+    /// `text/markdown` for example
+    pub media_type: String,
+}
+
+/// The documents of every group, by group path and name
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DocumentTable {
+    groups: BTreeMap<Path, BTreeMap<String, DocumentRef>>,
+}
+
+impl DocumentTable {
+    /// The documents referenced by `snapshot`, empty if it has none
+    pub fn from_snapshot(snapshot: &Snapshot) -> RepositoryResult<Self> {
+        let Some(value) = snapshot.properties.get(DOCUMENTS_PROPERTY) else {
+            return Ok(Self::default());
+        };
+        serde_json::from_value(value.clone()).map_err(|err| {
+            RepositoryError::InvalidDocuments {
+                snapshot: snapshot.metadata.id.clone(),
+                message: err.to_string(),
+            }
+        })
+    }
+
+    pub fn to_property(&self) -> serde_json::Value {
+        // serializing these types to a json value cannot fail
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// The table of `new_snapshot`, committed by `change_set` on top of a parent with table
+    /// `self`
+    pub fn update(mut self, change_set: &ChangeSet, new_snapshot: &Snapshot) -> Self {
+        for (path, name, document) in change_set.document_changes() {
+            let documents = self.groups.entry(path.clone()).or_default();
+            match document {
+                Some(document) => documents.insert(name.clone(), document.clone()),
+                None => documents.remove(name),
+            };
+        }
+        self.groups.retain(|path, documents| {
+            !documents.is_empty()
+                && new_snapshot
+                    .get_node(path)
+                    .is_ok_and(|node| matches!(node.node_data, NodeData::Group))
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn get(&self, path: &Path, name: &str) -> Option<&DocumentRef> {
+        self.groups.get(path).and_then(|documents| documents.get(name))
+    }
+
+    /// The documents of the group at `path`, by name
+    pub fn documents(
+        &self,
+        path: &Path,
+    ) -> impl Iterator<Item = (&String, &DocumentRef)> {
+        self.groups.get(path).into_iter().flatten()
+    }
+
+    /// The objects of all documents
+    pub fn objects(&self) -> impl Iterator<Item = &ChunkId> {
+        self.groups.values().flat_map(|documents| documents.values().map(|doc| &doc.id))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        ops::gc::{garbage_collect, GCConfig},
+        test_utils::new_in_memory_storage,
+        Repository, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_group_documents() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> = new_in_memory_storage();
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let root = Path::root();
+        let group: Path = "/group".try_into()?;
+        ds.add_group(root.clone()).await?;
+        ds.add_group(group.clone()).await?;
+        let readme = Bytes::from_static(b"# Dataset\n\nVersion one");
+        ds.put_document(root.clone(), "README.md", "text/markdown", readme.clone())
+            .await?;
+        ds.put_document(group.clone(), "LICENSE", "text/plain", "CC-BY".into()).await?;
+        ds.put_document(root.clone(), "NOTES", "text/plain", "draft".into()).await?;
+        ds.delete_document(&root, "NOTES").await?;
+        // visible before the commit
+        assert_eq!(ds.get_document(&root, "README.md").await?, readme);
+        let first = ds.commit("main", "first", None).await?;
+
+        ds.put_document(root.clone(), "README.md", "text/markdown", "# v2".into())
+            .await?;
+        ds.delete_group(group.clone()).await?;
+        ds.commit("main", "second", None).await?;
+        let names: Vec<_> = ds.list_documents(&root).await?.into_keys().collect();
+        assert_eq!(names, vec!["README.md".to_string()]);
+        assert_eq!(ds.get_document(&root, "README.md").await?, "# v2");
+        assert!(ds.list_documents(&group).await.is_err());
+
+        // the documentation travels with the version
+        let old = Repository::update(Arc::clone(&storage), first).build();
+        assert_eq!(old.get_document(&root, "README.md").await?, readme);
+        let license = old.list_documents(&group).await?.remove("LICENSE").unwrap();
+        assert_eq!((license.size_bytes, license.media_type.as_str()), (5, "text/plain"));
+        assert!(matches!(
+            old.get_document(&root, "NOTES").await,
+            Err(RepositoryError::DocumentNotFound { .. })
+        ));
+
+        // the documents of live snapshots are kept
+        let now = chrono::Utc::now() + chrono::TimeDelta::seconds(10);
+        garbage_collect(storage.as_ref(), &GCConfig::clean_all(now, now, None)).await?;
+        assert_eq!(ds.get_document(&root, "README.md").await?, "# v2");
+        Ok(())
+    }
+}
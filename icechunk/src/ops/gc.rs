@@ -1,14 +1,33 @@
-use std::{collections::HashSet, future::ready, iter};
+use std::{
+    collections::HashSet,
+    future::ready,
+    hash::{BuildHasher, RandomState},
+    iter,
+    sync::Arc,
+};
 
 use chrono::{DateTime, Utc};
 use futures::{stream, Stream, StreamExt, TryStreamExt};
 use tokio::pin;
 
 use crate::{
-    format::{ChunkId, ManifestId, SnapshotId},
-    refs::{list_refs, RefError},
+    chunk_dictionary::dictionary_chunks,
+    events::{default_event_observer, DynEventObserver, Event, EventRecord},
+    external_refs::list_external_references,
+    format::{snapshot::NodeData, ChunkId, ManifestId, SnapshotId},
+    generation::{run_fenced, GenerationError},
+    ops::{
+        documents::DocumentTable,
+        refcount::{fetch_refcounts, update_refcounts, Reconciliation},
+        retention::expired_snapshots,
+    },
+    pins::pinned_snapshots,
+    progress::{default_progress_observer, DynProgressObserver, Phase, Progress},
+    refs::{list_ref_tips, RefError},
     repository::ChunkPayload,
-    storage::ListInfo,
+    runtime::{default_runtime, DynRuntime},
+    storage::{ListInfo, ObjectKind},
+    trash::list_trash,
     Storage, StorageError,
 };
 
@@ -18,14 +37,40 @@ pub enum Action {
     DeleteIfCreatedBefore(DateTime<Utc>),
 }
 
+/// How the chunks reachable from the roots are recorded during the mark phase
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChunkMark {
+    /// Every reachable chunk id is kept in memory
+    #[default]
+    Exact,
+    /// The reachable chunks are recorded in a Bloom filter, sized for `expected_chunks`
+    /// with `false_positive_rate`. It takes about 10 bits per chunk for a 1% rate, instead
+    /// of tens of bytes.
+    ///
+    /// A false positive only keeps an unreachable chunk, reachable chunks are never
+    /// deleted. The filter hashes with new keys on every collection, the chunks kept by
+    /// one are likely collected by the next. If the repository has more chunks than
+    /// expected the rate degrades, but the collection stays safe.
+    Bloom { expected_chunks: u64, false_positive_rate: f64 },
+}
+
+/// Manifests fetched at the same time during the mark phase
+const MANIFEST_FETCH_CONCURRENCY: usize = 8;
+
 #[derive(Debug)]
 pub struct GCConfig {
     extra_roots: HashSet<SnapshotId>,
+    chunk_mark: ChunkMark,
+    refcounts: bool,
     dangling_chunks: Action,
     dangling_manifests: Action,
     dangling_attributes: Action,
     dangling_transaction_logs: Action,
     dangling_snapshots: Action,
+    progress: DynProgressObserver,
+    events: DynEventObserver,
+    now: DateTime<Utc>,
+    runtime: DynRuntime,
 }
 
 impl GCConfig {
@@ -39,13 +84,61 @@ impl GCConfig {
     ) -> Self {
         GCConfig {
             extra_roots,
+            chunk_mark: ChunkMark::default(),
+            refcounts: false,
             dangling_chunks,
             dangling_manifests,
             dangling_attributes,
             dangling_transaction_logs,
             dangling_snapshots,
+            progress: default_progress_observer(),
+            events: default_event_observer(),
+            now: Utc::now(),
+            runtime: default_runtime(),
         }
     }
+
+    /// Report the progress of the collection to `observer`
+    pub fn with_progress_observer(mut self, observer: DynProgressObserver) -> Self {
+        self.progress = observer;
+        self
+    }
+
+    /// Report how many objects of each kind were kept and deleted to `observer`
+    pub fn with_event_observer(mut self, observer: DynEventObserver) -> Self {
+        self.events = observer;
+        self
+    }
+
+    /// Record the reachable chunks with `mark`, see [`ChunkMark::Bloom`] for repositories
+    /// with more chunk ids than fit in memory
+    pub fn with_chunk_mark(mut self, mark: ChunkMark) -> Self {
+        self.chunk_mark = mark;
+        self
+    }
+
+    /// Find the reachable chunks in the table of [`crate::ops::refcount`], instead of
+    /// reading the manifests of every snapshot. The table is reconciled with the live
+    /// snapshots first, and created if the repository has none.
+    pub fn with_refcounts(mut self, refcounts: bool) -> Self {
+        self.refcounts = refcounts;
+        self
+    }
+
+    /// The time pins are checked against, pins expired by then don't protect their
+    /// snapshots. The current time by default.
+    pub fn with_now(mut self, now: DateTime<Utc>) -> Self {
+        self.now = now;
+        self
+    }
+
+    /// Where the collection waits between renewals of its generation, see
+    /// [`crate::generation::run_fenced`]
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
     pub fn clean_all(
         chunks_age: DateTime<Utc>,
         metadata_age: DateTime<Utc>,
@@ -131,6 +224,10 @@ pub enum GCError {
     Ref(#[from] RefError),
     #[error("storage error {0}")]
     Storage(#[from] StorageError),
+    #[error("generation error {0}")]
+    Generation(#[from] GenerationError),
+    #[error("invalid documents table {0}")]
+    InvalidDocuments(String),
 }
 
 pub type GCResult<A> = Result<A, GCError>;
@@ -139,54 +236,134 @@ pub async fn garbage_collect(
     storage: &(dyn Storage + Send + Sync),
     config: &GCConfig,
 ) -> GCResult<GCSummary> {
-    // TODO: this function could have much more parallelism
     if !config.action_needed() {
         return Ok(GCSummary::default());
     }
+    // the collection is fenced by a new generation, see [`crate::generation`]
+    let work = collect(storage, config);
+    run_fenced(storage, "gc", config.now, config.runtime.as_ref(), work).await
+}
 
-    let all_snaps = pointed_snapshots(storage, &config.extra_roots).await?;
+async fn collect(
+    storage: &(dyn Storage + Send + Sync),
+    config: &GCConfig,
+) -> GCResult<GCSummary> {
+    // TODO: this function could have much more parallelism
+    let all_snaps = pointed_snapshots(storage, &config.extra_roots, config.now).await?;
 
     // FIXME: add attribute files
     // FIXME: add transaction log files
-    let mut keep_chunks = HashSet::new();
+    let mut keep_chunks = ReachableChunks::new(config.chunk_mark);
     let mut keep_manifests = HashSet::new();
     let mut keep_snapshots = HashSet::new();
+    let mut live_snapshots = HashSet::new();
 
+    let report =
+        |phase, done| config.progress.on_progress(&Progress::new(phase, done, None));
+    let collected = |kind, reachable, deleted| {
+        let event = Event::GarbageCollected { kind, reachable, deleted };
+        config.events.on_event(&EventRecord::new(config.now, event));
+    };
+    report(Phase::FindingReachable, 0);
+    let mut visited = 0;
     pin!(all_snaps);
     while let Some(snap_id) = all_snaps.try_next().await? {
         let snap = storage.fetch_snapshot(&snap_id).await?;
+        visited += 1;
+        report(Phase::FindingReachable, visited);
+        if config.refcounts {
+            live_snapshots.insert(snap_id.clone());
+        }
         if config.deletes_snapshots() {
             keep_snapshots.insert(snap_id);
         }
+        // manifests are shared by many snapshots, they are only read once
+        keep_manifests.extend(snap.manifest_files.iter().map(|mf| mf.id.clone()));
+        if config.deletes_chunks() {
+            // documents are not in the manifests, nor in the reference counts
+            let documents = DocumentTable::from_snapshot(&snap)
+                .map_err(|err| GCError::InvalidDocuments(err.to_string()))?;
+            for id in documents.objects() {
+                keep_chunks.insert(id);
+            }
+            // the dictionaries of the arrays, before they encode any chunk
+            for node in snap.iter() {
+                if let NodeData::Array(meta, _) = &node.node_data {
+                    let ids = meta.chunk_transformers.iter().flatten();
+                    for id in dictionary_chunks(ids) {
+                        keep_chunks.insert(&id);
+                    }
+                }
+            }
+        }
+    }
 
-        if config.deletes_manifests() {
-            keep_manifests.extend(snap.manifest_files.iter().map(|mf| mf.id.clone()));
+    if config.deletes_chunks() && config.refcounts {
+        let live_snapshots = &live_snapshots;
+        update_refcounts(storage, true, |mut table| async move {
+            let changes = table.reconcile(storage, live_snapshots).await?;
+            Ok((table, changes != Reconciliation::default()))
+        })
+        .await?;
+        // the update only read the shards it changed, commits since then only add chunks
+        let table = fetch_refcounts(storage).await?.unwrap_or_default();
+        for id in table.chunks() {
+            keep_chunks.insert(id);
         }
+    } else if config.deletes_chunks() {
+        // only a few manifests are in memory at a time
+        let mut manifests = stream::iter(keep_manifests.iter().cloned())
+            .map(|id| async move { storage.fetch_manifests(&id).await })
+            .buffer_unordered(MANIFEST_FETCH_CONCURRENCY);
+        while let Some(manifest) = manifests.try_next().await? {
+            for payload in manifest.chunks().values() {
+                if let ChunkPayload::Ref(chunk_ref) = payload {
+                    keep_chunks.insert(&chunk_ref.id);
+                }
+            }
+            for id in dictionary_chunks(manifest.transformers().values().flatten()) {
+                keep_chunks.insert(&id);
+            }
+        }
+    }
 
-        if config.deletes_chunks() {
-            for manifest_file in snap.manifest_files.iter() {
-                let manifest_id = &manifest_file.id;
-                let manifest = storage.fetch_manifests(manifest_id).await?;
-                let chunk_ids =
-                    manifest.chunks().values().filter_map(|payload| match payload {
-                        ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
-                        _ => None,
-                    });
-                keep_chunks.extend(chunk_ids);
+    // registered external objects are kept, even if nothing references them
+    for reference in list_external_references(storage).await? {
+        let id = reference.id.as_str();
+        match reference.kind {
+            ObjectKind::Snapshot => keep_snapshots.extend(SnapshotId::try_from(id).ok()),
+            ObjectKind::Manifest => keep_manifests.extend(ManifestId::try_from(id).ok()),
+            ObjectKind::Chunk => {
+                if let Ok(id) = ChunkId::try_from(id) {
+                    keep_chunks.insert(&id);
+                }
             }
+            ObjectKind::TransactionLog | ObjectKind::Ref => {}
         }
     }
 
     let mut summary = GCSummary::default();
 
     if config.deletes_snapshots() {
+        report(Phase::DeletingSnapshots, 0);
+        let reachable = keep_snapshots.len();
         summary.snapshots_deleted = gc_snapshots(storage, config, keep_snapshots).await?;
+        report(Phase::DeletingSnapshots, summary.snapshots_deleted as u64);
+        collected(ObjectKind::Snapshot, reachable, summary.snapshots_deleted);
     }
     if config.deletes_manifests() {
+        report(Phase::DeletingManifests, 0);
+        let reachable = keep_manifests.len();
         summary.manifests_deleted = gc_manifests(storage, config, keep_manifests).await?;
+        report(Phase::DeletingManifests, summary.manifests_deleted as u64);
+        collected(ObjectKind::Manifest, reachable, summary.manifests_deleted);
     }
     if config.deletes_chunks() {
+        report(Phase::DeletingChunks, 0);
+        let reachable = keep_chunks.len;
         summary.chunks_deleted = gc_chunks(storage, config, keep_chunks).await?;
+        report(Phase::DeletingChunks, summary.chunks_deleted as u64);
+        collected(ObjectKind::Chunk, reachable, summary.chunks_deleted);
     }
 
     Ok(summary)
@@ -195,40 +372,133 @@ pub async fn garbage_collect(
 async fn all_roots<'a>(
     storage: &'a (dyn Storage + Send + Sync),
     extra_roots: &'a HashSet<SnapshotId>,
+    now: DateTime<Utc>,
 ) -> GCResult<impl Stream<Item = GCResult<SnapshotId>> + 'a> {
-    let all_refs = list_refs(storage).await?;
+    let all_refs = list_ref_tips(storage).await?;
+    // trashed snapshots are kept until the entry is purged, pinned ones until it expires
+    let trashed = list_trash(storage).await?.into_iter().map(|entry| Ok(entry.snapshot));
+    let pinned = pinned_snapshots(storage, now).await?.into_iter().map(Ok);
     // TODO: this could be optimized by not following the ancestry of snapshots that we have
     // already seen
     let roots =
-        stream::iter(all_refs)
-            .then(move |r| async move {
-                r.fetch(storage).await.map(|ref_data| ref_data.snapshot)
-            })
-            .err_into()
+        stream::iter(all_refs.into_iter().map(|(_, ref_data)| Ok(ref_data.snapshot)))
+            .chain(stream::iter(trashed))
+            .chain(stream::iter(pinned))
             .chain(stream::iter(extra_roots.iter().cloned()).map(Ok));
     Ok(roots)
 }
 
-async fn pointed_snapshots<'a>(
+/// The snapshots in the history of the refs, the trash, the pins live at `now`, and
+/// `extra_roots`, without the ancestors expired by retention, see [`super::retention`]
+pub(crate) async fn pointed_snapshots<'a>(
     storage: &'a (dyn Storage + Send + Sync),
     extra_roots: &'a HashSet<SnapshotId>,
+    now: DateTime<Utc>,
 ) -> GCResult<impl Stream<Item = GCResult<SnapshotId>> + 'a> {
-    let roots = all_roots(storage, extra_roots).await?;
+    let roots = all_roots(storage, extra_roots, now).await?;
+    let expired = Arc::new(expired_snapshots(storage).await?);
     Ok(roots
-        .and_then(move |snap_id| async move {
-            let snap = storage.fetch_snapshot(&snap_id).await?;
-            // FIXME: this should be global ancestry, not local
-            let parents = snap.local_ancestry().map(|parent| parent.id);
-            Ok(stream::iter(iter::once(snap_id).chain(parents))
-                .map(Ok::<SnapshotId, GCError>))
+        .and_then(move |snap_id| {
+            let expired = Arc::clone(&expired);
+            async move {
+                let snap = storage.fetch_snapshot(&snap_id).await?;
+                // FIXME: this should be global ancestry, not local
+                let parents = snap
+                    .local_ancestry()
+                    .map(|parent| parent.id)
+                    .filter(move |id| !expired.contains(id));
+                Ok(stream::iter(iter::once(snap_id).chain(parents))
+                    .map(Ok::<SnapshotId, GCError>))
+            }
         })
         .try_flatten())
 }
 
+/// The chunks found in the mark phase, exactly or in a Bloom filter
+struct ReachableChunks {
+    exact: HashSet<ChunkId>,
+    bloom: Option<BloomFilter>,
+    /// Distinct chunks inserted, approximate for a Bloom filter
+    len: usize,
+}
+
+impl ReachableChunks {
+    fn new(mark: ChunkMark) -> Self {
+        let bloom = match mark {
+            ChunkMark::Exact => None,
+            ChunkMark::Bloom { expected_chunks, false_positive_rate } => {
+                Some(BloomFilter::new(expected_chunks, false_positive_rate))
+            }
+        };
+        Self { exact: HashSet::new(), bloom, len: 0 }
+    }
+
+    fn insert(&mut self, id: &ChunkId) {
+        let new = match &mut self.bloom {
+            Some(bloom) => bloom.insert(id),
+            None => self.exact.insert(id.clone()),
+        };
+        if new {
+            self.len += 1;
+        }
+    }
+
+    fn may_contain(&self, id: &ChunkId) -> bool {
+        match &self.bloom {
+            Some(bloom) => bloom.may_contain(id),
+            None => self.exact.contains(id),
+        }
+    }
+}
+
+struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    keys: (RandomState, RandomState),
+}
+
+impl BloomFilter {
+    fn new(expected_items: u64, false_positive_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let bits = (-n * rate.ln() / std::f64::consts::LN_2.powi(2)).ceil().max(64.0);
+        let hashes = ((bits / n) * std::f64::consts::LN_2).round().clamp(1.0, 32.0);
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes: hashes as u32,
+            keys: (RandomState::new(), RandomState::new()),
+        }
+    }
+
+    /// The bit of every hash function, with double hashing
+    fn positions(&self, id: &ChunkId) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let h1 = self.keys.0.hash_one(id);
+        let h2 = self.keys.1.hash_one(id) | 1;
+        (0..self.hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// Returns true if the id was not in the filter before
+    fn insert(&mut self, id: &ChunkId) -> bool {
+        let mut new = false;
+        for bit in self.positions(id).collect::<Vec<_>>() {
+            let (word, mask) = (bit / 64, 1 << (bit % 64));
+            new |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        new
+    }
+
+    fn may_contain(&self, id: &ChunkId) -> bool {
+        self.positions(id).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
 async fn gc_chunks(
     storage: &(dyn Storage + Send + Sync),
     config: &GCConfig,
-    keep_ids: HashSet<ChunkId>,
+    keep_ids: ReachableChunks,
 ) -> GCResult<usize> {
     let to_delete = storage
         .list_chunks()
@@ -236,7 +506,7 @@ async fn gc_chunks(
         // TODO: don't skip over errors
         .filter_map(move |chunk| {
             ready(chunk.ok().and_then(|chunk| {
-                if config.must_delete_chunk(&chunk) && !keep_ids.contains(&chunk.id) {
+                if config.must_delete_chunk(&chunk) && !keep_ids.may_contain(&chunk.id) {
                     Some(chunk.id.clone())
                 } else {
                     None
@@ -294,3 +564,77 @@ async fn gc_snapshots(
         .boxed();
     Ok(storage.delete_snapshots(to_delete).await?)
 }
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkIndices, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_bloom_chunk_mark() -> Result<(), Box<dyn Error>> {
+        let mut bloom = BloomFilter::new(10_000, 0.01);
+        let ids: Vec<ChunkId> = (0..10_000).map(|_| ChunkId::random()).collect();
+        // an insert of a new id is only missed on a false positive
+        assert!(ids.iter().filter(|id| bloom.insert(id)).count() > 9_900);
+        assert!(ids.iter().all(|id| bloom.may_contain(id)));
+        let false_positives =
+            (0..10_000).filter(|_| bloom.may_contain(&ChunkId::random())).count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![1.try_into()?]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        for idx in 0..2 {
+            let payload = ds.get_chunk_writer()(Bytes::from_static(b"1")).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx]), Some(payload))
+                .await?;
+        }
+        ds.commit("main", "chunks", None).await?;
+        let dangling = ChunkId::random();
+        storage.write_chunk(dangling.clone(), Bytes::from_static(b"1")).await?;
+
+        let now = Utc::now();
+        let config =
+            GCConfig::clean_all(now, now, None).with_chunk_mark(ChunkMark::Bloom {
+                expected_chunks: 1000,
+                false_positive_rate: 1e-9,
+            });
+        let summary = garbage_collect(storage.as_ref(), &config).await?;
+        assert_eq!(summary.chunks_deleted, 1);
+        assert!(storage.fetch_chunk(&dangling, &ByteRange::ALL).await.is_err());
+        assert_eq!(storage.list_chunks().await?.count().await, 2);
+        Ok(())
+    }
+}
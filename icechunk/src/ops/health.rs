@@ -0,0 +1,462 @@
+//! A summary of the state of a snapshot and its storage, with recommended maintenance
+//!
+//! [`health_report`] reads every manifest of the snapshot, and for the orphan estimate, the
+//! snapshots and manifests reachable from the refs, like [`super::gc::garbage_collect`]
+//! does. The orphans are an estimate: objects written by sessions that didn't commit yet
+//! are counted as orphans.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+};
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::pin;
+
+use crate::{
+    chunk_dictionary::dictionary_chunks,
+    format::{
+        manifest::ChunkPayload, snapshot::NodeData, ChunkId, ManifestId, SnapshotId,
+    },
+    ops::gc::{pointed_snapshots, GCError},
+    storage::CachingStats,
+    Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum HealthError {
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("gc error {0}")]
+    GC(#[from] GCError),
+}
+
+pub type HealthResult<A> = Result<A, HealthError>;
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Chunk objects smaller than this are tiny
+    pub tiny_chunk_bytes: u64,
+    /// Thresholds of the recommendations
+    pub max_history_depth: usize,
+    pub max_tiny_chunk_ratio: f64,
+    pub min_cache_hit_rate: f64,
+    /// The stats of the cache the repository reads through, see
+    /// [`crate::MemCachingStorage::stats`]
+    pub cache_stats: Option<CachingStats>,
+    /// The time pins are checked against
+    pub now: DateTime<Utc>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            tiny_chunk_bytes: 64 * 1024,
+            max_history_depth: 1000,
+            max_tiny_chunk_ratio: 0.5,
+            min_cache_hit_rate: 0.5,
+            cache_stats: None,
+            now: Utc::now(),
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn with_tiny_chunk_bytes(mut self, bytes: u64) -> Self {
+        self.tiny_chunk_bytes = bytes;
+        self
+    }
+
+    pub fn with_max_history_depth(mut self, depth: usize) -> Self {
+        self.max_history_depth = depth;
+        self
+    }
+
+    pub fn with_max_tiny_chunk_ratio(mut self, ratio: f64) -> Self {
+        self.max_tiny_chunk_ratio = ratio;
+        self
+    }
+
+    pub fn with_min_cache_hit_rate(mut self, rate: f64) -> Self {
+        self.min_cache_hit_rate = rate;
+        self
+    }
+
+    pub fn with_cache_stats(mut self, stats: CachingStats) -> Self {
+        self.cache_stats = Some(stats);
+        self
+    }
+
+    pub fn with_now(mut self, now: DateTime<Utc>) -> Self {
+        self.now = now;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkCounts {
+    pub inline: usize,
+    pub native: usize,
+    pub r#virtual: usize,
+    /// Native chunks stored alone, in an object smaller than
+    /// [`HealthConfig::tiny_chunk_bytes`]
+    pub tiny: usize,
+    /// Native chunks the snapshot references, that are not in the storage
+    pub missing: usize,
+}
+
+/// Objects in the storage that no snapshot reachable from the refs, the trash or the live
+/// pins references
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct OrphanEstimate {
+    pub chunks: usize,
+    pub manifests: usize,
+    pub snapshots: usize,
+}
+
+impl OrphanEstimate {
+    pub fn total(&self) -> usize {
+        self.chunks + self.manifests + self.snapshots
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Recommendation {
+    RestoreMissingChunks { missing: usize },
+    GarbageCollect { orphans: usize },
+    ApplyRetention { history_depth: usize },
+    PackChunks { tiny_chunk_ratio: f64 },
+    IncreaseCacheSize { cache: String, hit_rate: f64 },
+}
+
+impl Display for Recommendation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recommendation::RestoreMissingChunks { missing } => write!(
+                f,
+                "{} chunks of the snapshot are missing from the storage, restore them from a replica or a backup",
+                missing
+            ),
+            Recommendation::GarbageCollect { orphans } => write!(
+                f,
+                "{} objects are not referenced by any snapshot, run the garbage collection to delete them",
+                orphans
+            ),
+            Recommendation::ApplyRetention { history_depth } => write!(
+                f,
+                "the history is {} snapshots deep, configure retention rules and apply them",
+                history_depth
+            ),
+            Recommendation::PackChunks { tiny_chunk_ratio } => write!(
+                f,
+                "{:.0}% of the chunk objects are tiny, enable chunk packing or use larger chunks",
+                tiny_chunk_ratio * 100.0
+            ),
+            Recommendation::IncreaseCacheSize { cache, hit_rate } => write!(
+                f,
+                "the {} cache serves {:.0}% of the lookups, increase its size",
+                cache,
+                hit_rate * 100.0
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub snapshot: SnapshotId,
+    pub arrays: usize,
+    pub groups: usize,
+    pub manifests: usize,
+    /// How many manifests the chunks of an array are spread over, on average
+    pub manifests_per_array: f64,
+    /// The number of snapshots in the history, including this one
+    pub history_depth: usize,
+    pub chunks: ChunkCounts,
+    /// The fraction of the native chunk objects that are tiny
+    pub tiny_chunk_ratio: f64,
+    pub orphans: OrphanEstimate,
+    pub cache: Option<CachingStats>,
+    pub recommendations: Vec<Recommendation>,
+}
+
+/// Analyze the snapshot `snapshot_id` and the objects in `storage`
+pub async fn health_report(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+    config: &HealthConfig,
+) -> HealthResult<HealthReport> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let (mut arrays, mut groups, mut array_manifests) = (0, 0, 0);
+    for node in snapshot.iter() {
+        match &node.node_data {
+            NodeData::Array(_, manifests) => {
+                arrays += 1;
+                array_manifests += manifests.len();
+            }
+            NodeData::Group => groups += 1,
+            NodeData::Custom(_) => {}
+        }
+    }
+
+    let mut chunks = ChunkCounts::default();
+    // the length of each native chunk ref, by chunk object
+    let mut objects: HashMap<ChunkId, Vec<u64>> = HashMap::new();
+    for manifest_file in snapshot.manifest_files.iter() {
+        let manifest = storage.fetch_manifests(&manifest_file.id).await?;
+        for payload in manifest.chunks().values() {
+            match payload {
+                ChunkPayload::Inline(_) => chunks.inline += 1,
+                ChunkPayload::Virtual(_) => chunks.r#virtual += 1,
+                ChunkPayload::Ref(chunk_ref) => {
+                    chunks.native += 1;
+                    objects
+                        .entry(chunk_ref.id.clone())
+                        .or_default()
+                        .push(chunk_ref.length);
+                }
+            }
+        }
+    }
+    chunks.tiny = objects
+        .values()
+        .filter(|lengths| lengths.len() == 1 && lengths[0] < config.tiny_chunk_bytes)
+        .count();
+    let tiny_chunk_ratio = ratio(chunks.tiny, objects.len());
+
+    let (reachable_snapshots, reachable_manifests, reachable_chunks) =
+        reachable_objects(storage, config.now).await?;
+    let listed_chunks: HashSet<ChunkId> =
+        storage.list_chunks().await?.map_ok(|info| info.id).try_collect().await?;
+    chunks.missing = objects.keys().filter(|id| !listed_chunks.contains(id)).count();
+    let orphans = OrphanEstimate {
+        chunks: listed_chunks.difference(&reachable_chunks).count(),
+        manifests: storage
+            .list_manifests()
+            .await?
+            .try_filter(|info| {
+                futures::future::ready(!reachable_manifests.contains(&info.id))
+            })
+            .try_fold(0, |n, _| futures::future::ready(Ok(n + 1)))
+            .await?,
+        snapshots: storage
+            .list_snapshots()
+            .await?
+            .try_filter(|info| {
+                futures::future::ready(!reachable_snapshots.contains(&info.id))
+            })
+            .try_fold(0, |n, _| futures::future::ready(Ok(n + 1)))
+            .await?,
+    };
+
+    let mut report = HealthReport {
+        snapshot: snapshot_id.clone(),
+        arrays,
+        groups,
+        manifests: snapshot.manifest_files.len(),
+        manifests_per_array: ratio(array_manifests, arrays),
+        history_depth: snapshot.local_ancestry().count() + 1,
+        chunks,
+        tiny_chunk_ratio,
+        orphans,
+        cache: config.cache_stats,
+        recommendations: Vec::new(),
+    };
+    report.recommendations = recommendations(&report, config);
+    Ok(report)
+}
+
+fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+async fn reachable_objects(
+    storage: &(dyn Storage + Send + Sync),
+    now: DateTime<Utc>,
+) -> HealthResult<(HashSet<SnapshotId>, HashSet<ManifestId>, HashSet<ChunkId>)> {
+    let (mut snapshots, mut manifests, mut chunks) =
+        (HashSet::new(), HashSet::new(), HashSet::new());
+    let extra_roots = HashSet::new();
+    let pointed = pointed_snapshots(storage, &extra_roots, now).await?;
+    pin!(pointed);
+    while let Some(snapshot_id) = pointed.try_next().await? {
+        if !snapshots.insert(snapshot_id.clone()) {
+            continue;
+        }
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+        for manifest_file in snapshot.manifest_files.iter() {
+            if !manifests.insert(manifest_file.id.clone()) {
+                continue;
+            }
+            let manifest = storage.fetch_manifests(&manifest_file.id).await?;
+            chunks.extend(manifest.chunks().values().filter_map(
+                |payload| match payload {
+                    ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+                    _ => None,
+                },
+            ));
+            chunks.extend(dictionary_chunks(manifest.transformers().values().flatten()));
+        }
+    }
+    Ok((snapshots, manifests, chunks))
+}
+
+fn recommendations(report: &HealthReport, config: &HealthConfig) -> Vec<Recommendation> {
+    let mut res = Vec::new();
+    if report.chunks.missing > 0 {
+        res.push(Recommendation::RestoreMissingChunks { missing: report.chunks.missing });
+    }
+    if report.orphans.total() > 0 {
+        res.push(Recommendation::GarbageCollect { orphans: report.orphans.total() });
+    }
+    if report.history_depth > config.max_history_depth {
+        res.push(Recommendation::ApplyRetention { history_depth: report.history_depth });
+    }
+    if report.tiny_chunk_ratio > config.max_tiny_chunk_ratio {
+        res.push(Recommendation::PackChunks {
+            tiny_chunk_ratio: report.tiny_chunk_ratio,
+        });
+    }
+    if let Some(stats) = &report.cache {
+        for (cache, stats) in [
+            ("snapshot", stats.snapshots),
+            ("manifest", stats.manifests),
+            ("chunk", stats.chunks),
+        ] {
+            match stats.hit_rate() {
+                Some(hit_rate) if hit_rate < config.min_cache_hit_rate => {
+                    res.push(Recommendation::IncreaseCacheSize {
+                        cache: cache.to_string(),
+                        hit_rate,
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkIndices, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::{get_chunk, ZarrArrayMetadata},
+        storage::CacheStats,
+        MemCachingStorage, ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_health_report() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let caching =
+            Arc::new(MemCachingStorage::new(Arc::clone(&backend), 2, 2, 0, 0, 0));
+        let storage: Arc<dyn Storage + Send + Sync> = caching.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        ds.add_group(Path::root()).await?;
+        let path: Path = "/array".try_into()?;
+        let meta = ZarrArrayMetadata {
+            shape: vec![4],
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            data_type: DataType::UInt8,
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        ds.add_array(path.clone(), meta).await?;
+        for i in 0..4 {
+            let payload = ds.get_chunk_writer()(Bytes::from(vec![i as u8])).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![i]), Some(payload)).await?;
+        }
+        ds.commit("main", "first", None).await?;
+        // overwritten chunks are orphans, but still in the history
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"x")).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        // chunks written by a session that never commits are orphans
+        ds.get_chunk_writer()(Bytes::from_static(b"y")).await?;
+        let snapshot = ds.commit("main", "second", None).await?;
+        let coords = ChunkIndices(vec![1]);
+        get_chunk(ds.get_chunk_reader(&path, &coords, &ByteRange::ALL).await?).await?;
+
+        let config = HealthConfig::default()
+            .with_max_history_depth(2)
+            .with_cache_stats(caching.stats());
+        let report = health_report(storage.as_ref(), &snapshot, &config).await?;
+        assert_eq!((report.arrays, report.groups, report.manifests), (1, 1, 1));
+        assert_eq!(report.manifests_per_array, 1.0);
+        assert_eq!(report.history_depth, 3);
+        assert_eq!(
+            report.chunks,
+            ChunkCounts { inline: 0, native: 4, r#virtual: 0, tiny: 4, missing: 0 }
+        );
+        assert_eq!(
+            report.orphans,
+            OrphanEstimate { chunks: 1, manifests: 0, snapshots: 0 }
+        );
+        assert_eq!(report.cache.unwrap().chunks, CacheStats { hits: 0, misses: 1 });
+        assert_eq!(
+            report.recommendations,
+            vec![
+                Recommendation::GarbageCollect { orphans: 1 },
+                Recommendation::ApplyRetention { history_depth: 3 },
+                Recommendation::PackChunks { tiny_chunk_ratio: 1.0 },
+                Recommendation::IncreaseCacheSize {
+                    cache: "chunk".to_string(),
+                    hit_rate: 0.0
+                },
+            ]
+        );
+        assert!(
+            serde_json::to_value(&report)?["recommendations"][0]["kind"]
+                == "garbage_collect"
+        );
+
+        // a chunk deleted from the storage is missing
+        let manifest = storage
+            .fetch_manifests(
+                &storage.fetch_snapshot(&snapshot).await?.manifest_files[0].id,
+            )
+            .await?;
+        let lost = manifest
+            .chunks()
+            .values()
+            .find_map(|payload| match payload {
+                ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+                _ => None,
+            })
+            .unwrap();
+        backend.delete_chunks(futures::stream::iter([lost]).boxed()).await?;
+        let report =
+            health_report(storage.as_ref(), &snapshot, &HealthConfig::default()).await?;
+        assert_eq!(report.chunks.missing, 1);
+        assert_eq!(
+            report.recommendations[0],
+            Recommendation::RestoreMissingChunks { missing: 1 }
+        );
+        Ok(())
+    }
+}
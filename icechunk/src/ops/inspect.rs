@@ -0,0 +1,231 @@
+//! Decoding of the raw objects of a repository by id, for debugging
+//!
+//! [`inspect_object`] fetches every object stored with an id, a snapshot and its
+//! transaction log share theirs, and decodes it. Objects that exist but can't be fetched or
+//! decoded are reported as [`ObjectContent::Unreadable`], instead of failing, so corrupted
+//! objects can be found. Sizes come from listing the objects of each kind, which can be slow
+//! in large repositories.
+use std::{future::Future, sync::Arc};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        framing::{is_framed, HEADER_LEN},
+        manifest::Manifest,
+        snapshot::Snapshot,
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, IcechunkFormatVersion, ManifestId, SnapshotId,
+    },
+    repository::{RepositoryError, RepositoryResult},
+    storage::{ObjectKind, StorageResult, ATTRIBUTES_PREFIX},
+    Storage,
+};
+
+#[derive(Debug, Clone)]
+pub enum ObjectContent {
+    Snapshot(Arc<Snapshot>),
+    TransactionLog(Arc<TransactionLog>),
+    Manifest(Arc<Manifest>),
+    Attributes(Arc<AttributesTable>),
+    Chunk {
+        /// The first bytes of the chunk
+        header: Bytes,
+        /// Whether the chunk starts with a metadata frame, see [`crate::format::framing`]
+        framed: bool,
+    },
+    /// The object exists, but fetching or decoding it failed
+    Unreadable(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct InspectedObject {
+    /// The prefix of the objects of its kind, `snapshots/` for example
+    pub prefix: &'static str,
+    pub id: String,
+    /// `None` for chunks and unreadable objects
+    pub format_version: Option<IcechunkFormatVersion>,
+    /// `None` if the object was not found in the listing
+    pub size_bytes: Option<u64>,
+    pub content: ObjectContent,
+}
+
+/// Fetch and decode the objects stored with id `id`
+///
+/// Returns an empty list if there is none, fails if `id` is not an object id.
+pub async fn inspect_object(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+) -> RepositoryResult<Vec<InspectedObject>> {
+    let invalid = |_| RepositoryError::InvalidObjectId(id.to_string());
+    let snapshot_id = SnapshotId::try_from(id).map_err(invalid)?;
+    let manifest_id = ManifestId::try_from(id).map_err(invalid)?;
+    let attributes_id = AttributesId::try_from(id).map_err(invalid)?;
+    let chunk_id = ChunkId::try_from(id).map_err(invalid)?;
+
+    let mut res = Vec::new();
+    let snapshot = storage.fetch_snapshot(&snapshot_id);
+    inspect(storage, &mut res, ObjectKind::Snapshot.prefix(), id, snapshot, |s| {
+        (Some(s.icechunk_snapshot_format_version), ObjectContent::Snapshot(s))
+    })
+    .await?;
+    let log = storage.fetch_transaction_log(&snapshot_id);
+    inspect(storage, &mut res, ObjectKind::TransactionLog.prefix(), id, log, |log| {
+        (
+            Some(log.icechunk_transaction_log_format_version),
+            ObjectContent::TransactionLog(log),
+        )
+    })
+    .await?;
+    let manifest = storage.fetch_manifests(&manifest_id);
+    inspect(storage, &mut res, ObjectKind::Manifest.prefix(), id, manifest, |m| {
+        (Some(m.icechunk_manifest_format_version), ObjectContent::Manifest(m))
+    })
+    .await?;
+    let attributes = storage.fetch_attributes(&attributes_id);
+    inspect(storage, &mut res, ATTRIBUTES_PREFIX, id, attributes, |table| {
+        (Some(table.icechunk_attributes_format_version), ObjectContent::Attributes(table))
+    })
+    .await?;
+
+    match storage.fetch_chunk(&chunk_id, &ByteRange::ALL).await {
+        Ok(bytes) => {
+            let header = bytes.slice(..bytes.len().min(HEADER_LEN));
+            res.push(InspectedObject {
+                prefix: ObjectKind::Chunk.prefix(),
+                id: id.to_string(),
+                format_version: None,
+                size_bytes: Some(bytes.len() as u64),
+                content: ObjectContent::Chunk { framed: is_framed(&header), header },
+            });
+        }
+        Err(err) if err.is_not_found() => {}
+        Err(err) => res.push(InspectedObject {
+            prefix: ObjectKind::Chunk.prefix(),
+            id: id.to_string(),
+            format_version: None,
+            size_bytes: listed_size(storage, ObjectKind::Chunk.prefix(), id).await?,
+            content: ObjectContent::Unreadable(err.to_string()),
+        }),
+    }
+    Ok(res)
+}
+
+/// Add the object fetched by `fetch` to `res`, if it exists
+async fn inspect<T>(
+    storage: &(dyn Storage + Send + Sync),
+    res: &mut Vec<InspectedObject>,
+    prefix: &'static str,
+    id: &str,
+    fetch: impl Future<Output = StorageResult<T>>,
+    decoded: impl FnOnce(T) -> (Option<IcechunkFormatVersion>, ObjectContent),
+) -> RepositoryResult<()> {
+    let size_bytes = listed_size(storage, prefix, id).await?;
+    let (format_version, content) = match fetch.await {
+        Ok(object) => decoded(object),
+        Err(err) if err.is_not_found() && size_bytes.is_none() => return Ok(()),
+        Err(err) => (None, ObjectContent::Unreadable(err.to_string())),
+    };
+    res.push(InspectedObject {
+        prefix,
+        id: id.to_string(),
+        format_version,
+        size_bytes,
+        content,
+    });
+    Ok(())
+}
+
+async fn listed_size(
+    storage: &(dyn Storage + Send + Sync),
+    prefix: &str,
+    id: &str,
+) -> RepositoryResult<Option<u64>> {
+    let listed = storage
+        .list_objects(prefix)
+        .await?
+        .try_filter(|info| futures::future::ready(info.id == id))
+        .try_next()
+        .await?;
+    Ok(listed.map(|info| info.size_bytes))
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{manifest::ChunkPayload, ChunkIndices, Path},
+        storage::faulty::FaultyStorage,
+        test_utils::{array_metadata, new_in_memory_storage},
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn test_inspect_object() -> Result<(), Box<dyn Error>> {
+        let faulty = Arc::new(FaultyStorage::new(new_in_memory_storage()));
+        let storage: Arc<dyn Storage + Send + Sync> = faulty.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), array_metadata(vec![1])).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"data")).await?;
+        let ChunkPayload::Ref(chunk) = &payload else { panic!("chunks are not inlined") };
+        let chunk = chunk.id.to_string();
+        ds.set_chunk_ref(path, ChunkIndices(vec![0]), Some(payload)).await?;
+        let snapshot = ds.commit("main", "first", None).await?;
+
+        let objects = ds.inspect_object(&snapshot.to_string()).await?;
+        assert_eq!(
+            objects.iter().map(|o| o.prefix).collect::<Vec<_>>(),
+            vec!["snapshots/", "transactions/"]
+        );
+        let ObjectContent::Snapshot(decoded) = &objects[0].content else {
+            panic!("must be a snapshot")
+        };
+        assert_eq!(decoded.metadata.message, "first");
+        assert!(objects.iter().all(|o| o.format_version.is_some()));
+        assert!(objects.iter().all(|o| o.size_bytes.unwrap() > 0));
+
+        let manifest = decoded.manifest_files[0].id.to_string();
+        let objects = ds.inspect_object(&manifest).await?;
+        assert!(matches!(objects.as_slice(), [InspectedObject {
+            content: ObjectContent::Manifest(m), ..
+        }] if m.len() == 1));
+        // a manifest that can't be read is still listed
+        faulty.fail("fetch_manifests", 0, 1);
+        let objects = ds.inspect_object(&manifest).await?;
+        assert!(matches!(
+            objects.as_slice(),
+            [InspectedObject {
+                content: ObjectContent::Unreadable(_),
+                size_bytes: Some(_),
+                ..
+            }]
+        ));
+
+        let objects = ds.inspect_object(&chunk).await?;
+        let [InspectedObject { content: ObjectContent::Chunk { header, framed }, .. }] =
+            objects.as_slice()
+        else {
+            panic!("must be a chunk")
+        };
+        assert_eq!((header.as_ref(), *framed), (&b"data"[..], false));
+        assert_eq!(objects[0].size_bytes, Some(4));
+
+        assert!(ds.inspect_object(&SnapshotId::random().to_string()).await?.is_empty());
+        assert!(matches!(
+            ds.inspect_object("not an id").await,
+            Err(RepositoryError::InvalidObjectId(_))
+        ));
+        Ok(())
+    }
+}
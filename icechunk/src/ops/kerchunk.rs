@@ -0,0 +1,521 @@
+//! Ingestion of existing files, like HDF5 or NetCDF, as virtual datasets
+//!
+//! A [`VirtualDataset`] describes groups and arrays whose chunks are byte ranges of
+//! existing files. It can be parsed from [kerchunk](https://fsspec.github.io/kerchunk/)
+//! reference JSON, as produced by `kerchunk.hdf.SingleHdf5ToZarr`, or built from any other
+//! index of the files with [`VirtualDataset::with_array`] and [`VirtualDataset::with_chunk`].
+//! [`ingest`] adds the dataset to a session, the files are not read.
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::NonZeroU64,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    format::{
+        manifest::{
+            ChunkPayload, VirtualChunkLocation, VirtualChunkRef, VirtualReferenceError,
+        },
+        snapshot::ZarrArrayMetadata,
+        ChunkIndices, IcechunkFormatError, Path, PathError,
+    },
+    metadata::{
+        ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, UserAttributes,
+    },
+    repository::RepositoryError,
+    Repository,
+};
+
+#[derive(Debug, Error)]
+pub enum KerchunkError {
+    #[error("invalid reference json `{0}`")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid metadata in `{key}`: {message}")]
+    InvalidMetadata { key: String, message: String },
+    #[error("invalid reference for `{key}`: {message}")]
+    InvalidReference { key: String, message: String },
+    #[error("unsupported references: {0}")]
+    Unsupported(String),
+    #[error("invalid path `{0}`")]
+    InvalidPath(#[from] PathError),
+    #[error("array not found at `{0}`")]
+    ArrayNotFound(Path),
+    #[error("virtual reference error `{0}`")]
+    VirtualReference(#[from] VirtualReferenceError),
+    #[error("repository error `{0}`")]
+    Repository(#[from] RepositoryError),
+}
+
+pub type KerchunkResult<A> = Result<A, KerchunkError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualArray {
+    pub metadata: ZarrArrayMetadata,
+    pub attributes: Option<UserAttributes>,
+    pub chunks: BTreeMap<ChunkIndices, ChunkPayload>,
+}
+
+/// Groups and arrays, by their path relative to the root of the dataset
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VirtualDataset {
+    pub groups: BTreeMap<Path, Option<UserAttributes>>,
+    pub arrays: BTreeMap<Path, VirtualArray>,
+}
+
+impl VirtualDataset {
+    pub fn with_group(mut self, path: Path, attributes: Option<UserAttributes>) -> Self {
+        self.groups.insert(path, attributes);
+        self
+    }
+
+    pub fn with_array(mut self, path: Path, metadata: ZarrArrayMetadata) -> Self {
+        self.arrays.insert(
+            path,
+            VirtualArray { metadata, attributes: None, chunks: BTreeMap::new() },
+        );
+        self
+    }
+
+    /// Point the chunk at `coords` to a byte range of an existing file
+    pub fn with_chunk(
+        mut self,
+        path: &Path,
+        coords: ChunkIndices,
+        reference: VirtualChunkRef,
+    ) -> KerchunkResult<Self> {
+        self.arrays
+            .get_mut(path)
+            .ok_or_else(|| KerchunkError::ArrayNotFound(path.clone()))?
+            .chunks
+            .insert(coords, ChunkPayload::Virtual(reference));
+        Ok(self)
+    }
+
+    /// Parse kerchunk reference JSON, version 0 or 1
+    ///
+    /// Templates are expanded, generators are not supported. Whole file references need a
+    /// length, which kerchunk doesn't record, so they are rejected.
+    pub fn from_kerchunk(json: &[u8]) -> KerchunkResult<Self> {
+        let value: Value = serde_json::from_slice(json)?;
+        let (refs, templates) = match value.get("version") {
+            Some(version) if version == 1 => {
+                let refs: KerchunkV1 = serde_json::from_value(value)?;
+                if !refs.gen.is_empty() {
+                    return Err(KerchunkError::Unsupported(
+                        "reference generators".to_string(),
+                    ));
+                }
+                (refs.refs, refs.templates)
+            }
+            Some(version) => {
+                return Err(KerchunkError::Unsupported(format!("version {version}")))
+            }
+            None => (serde_json::from_value(value)?, HashMap::new()),
+        };
+
+        let mut res = VirtualDataset::default();
+        let mut separators = HashMap::new();
+        for (key, value) in refs.iter() {
+            let (prefix, name) = key.rsplit_once('/').unwrap_or(("", key.as_str()));
+            let path = Path::root().child(prefix)?;
+            match name {
+                ".zgroup" => {
+                    res.groups.entry(path).or_default();
+                }
+                ".zarray" => {
+                    let meta: ZArray =
+                        serde_json::from_slice(&inline_bytes(key, value)?)?;
+                    separators
+                        .insert(prefix.to_string(), meta.dimension_separator.clone());
+                    let metadata = meta.into_metadata().map_err(|message| {
+                        KerchunkError::InvalidMetadata { key: key.clone(), message }
+                    })?;
+                    res = res.with_array(path, metadata);
+                }
+                _ => {}
+            }
+        }
+
+        for (key, value) in refs.iter() {
+            let (prefix, name) = key.rsplit_once('/').unwrap_or(("", key.as_str()));
+            if name == ".zattrs" {
+                let path = Path::root().child(prefix)?;
+                let attributes = Some(
+                    UserAttributes::try_new(&inline_bytes(key, value)?)
+                        .map_err(KerchunkError::Json)?,
+                );
+                if let Some(array) = res.arrays.get_mut(&path) {
+                    array.attributes = attributes;
+                } else {
+                    res.groups.insert(path, attributes);
+                }
+                continue;
+            }
+            if name.starts_with(".z") {
+                continue;
+            }
+            // the array is the longest prefix of the key that has metadata
+            let Some((prefix, chunk_key)) = separators
+                .iter()
+                .filter_map(|(prefix, sep)| {
+                    let chunk_key = if prefix.is_empty() {
+                        key.as_str()
+                    } else {
+                        key.strip_prefix(prefix.as_str())?.strip_prefix('/')?
+                    };
+                    Some((prefix, chunk_key, sep))
+                })
+                .filter(|(_, chunk_key, sep)| {
+                    sep.as_str() == "/" || !chunk_key.contains('/')
+                })
+                .max_by_key(|(prefix, _, _)| prefix.len())
+                .map(|(prefix, chunk_key, sep)| (prefix, parse_coords(chunk_key, sep)))
+            else {
+                return Err(KerchunkError::InvalidReference {
+                    key: key.clone(),
+                    message: "the key is not in any array".to_string(),
+                });
+            };
+            let coords = chunk_key.ok_or_else(|| KerchunkError::InvalidReference {
+                key: key.clone(),
+                message: "invalid chunk coordinates".to_string(),
+            })?;
+            let payload = chunk_payload(key, value, &templates)?;
+            let path = Path::root().child(prefix)?;
+            if let Some(array) = res.arrays.get_mut(&path) {
+                array.chunks.insert(coords, payload);
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Add the groups and arrays of `dataset` to the session, under `root`
+///
+/// Existing groups are kept, their attributes are replaced if the dataset has any. Either
+/// the whole dataset is added, or, on failure, the session is left as it was. Commit
+/// the session to publish the dataset atomically.
+pub async fn ingest(
+    repository: &mut Repository,
+    root: &Path,
+    dataset: &VirtualDataset,
+) -> KerchunkResult<()> {
+    let previous = repository.change_set().clone();
+    let res = do_ingest(repository, root, dataset).await;
+    if res.is_err() {
+        repository.discard_changes();
+        repository.merge(previous).await;
+    }
+    res
+}
+
+async fn do_ingest(
+    repository: &mut Repository,
+    root: &Path,
+    dataset: &VirtualDataset,
+) -> KerchunkResult<()> {
+    let under_root = |path: &Path| {
+        let relative = path.to_string();
+        match relative.trim_start_matches('/') {
+            "" => Ok(root.clone()),
+            relative => root.child(relative),
+        }
+    };
+    for (path, attributes) in dataset.groups.iter() {
+        let path = under_root(path)?;
+        match repository.get_group(&path).await {
+            Ok(_) => {}
+            Err(RepositoryError::NodeNotFound { .. }) => {
+                repository.add_group(path.clone()).await?
+            }
+            Err(err) => return Err(err.into()),
+        }
+        if attributes.is_some() {
+            repository.set_user_attributes(path, attributes.clone()).await?;
+        }
+    }
+    for (path, array) in dataset.arrays.iter() {
+        let path = under_root(path)?;
+        repository.add_array(path.clone(), array.metadata.clone()).await?;
+        if array.attributes.is_some() {
+            repository
+                .set_user_attributes(path.clone(), array.attributes.clone())
+                .await?;
+        }
+        for (coords, payload) in array.chunks.iter() {
+            repository
+                .set_chunk_ref(path.clone(), coords.clone(), Some(payload.clone()))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct KerchunkV1 {
+    refs: BTreeMap<String, Value>,
+    #[serde(default)]
+    templates: HashMap<String, String>,
+    #[serde(default)]
+    gen: Vec<Value>,
+}
+
+/// Zarr version 2 array metadata
+#[derive(Deserialize)]
+struct ZArray {
+    shape: Vec<u64>,
+    chunks: Vec<u64>,
+    dtype: String,
+    compressor: Option<HashMap<String, Value>>,
+    fill_value: Value,
+    #[serde(default)]
+    order: Option<String>,
+    #[serde(default)]
+    filters: Option<Vec<HashMap<String, Value>>>,
+    #[serde(default = "default_dimension_separator")]
+    dimension_separator: String,
+}
+
+fn default_dimension_separator() -> String {
+    ".".to_string()
+}
+
+impl ZArray {
+    fn into_metadata(self) -> Result<ZarrArrayMetadata, String> {
+        let (data_type, endian) = parse_numpy_dtype(&self.dtype)?;
+        let fill_value = match &self.fill_value {
+            Value::Null => zero_fill_value(&data_type)?,
+            value => FillValue::from_data_type_and_json(&data_type, value)
+                .map_err(|err: IcechunkFormatError| err.to_string())?,
+        };
+        let chunk_shape = self
+            .chunks
+            .iter()
+            .map(|n| NonZeroU64::new(*n).ok_or("chunk sizes cannot be zero"))
+            .collect::<Result<_, _>>()?;
+
+        let mut codecs = Vec::new();
+        if self.order.as_deref() == Some("F") {
+            let order: Vec<usize> = (0..self.shape.len()).rev().collect();
+            codecs.push(Codec {
+                name: "transpose".to_string(),
+                configuration: Some(HashMap::from([("order".to_string(), order.into())])),
+            });
+        }
+        codecs.push(Codec {
+            name: "bytes".to_string(),
+            configuration: endian.map(|endian| {
+                HashMap::from([("endian".to_string(), Value::from(endian))])
+            }),
+        });
+        for numcodec in self.filters.into_iter().flatten().chain(self.compressor) {
+            codecs.push(numcodecs_codec(numcodec)?);
+        }
+
+        Ok(ZarrArrayMetadata {
+            shape: self.shape,
+            data_type,
+            chunk_shape: ChunkShape(chunk_shape),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value,
+            codecs,
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        })
+    }
+}
+
+/// A numcodecs codec, named as in the `numcodecs.zarr3` package
+fn numcodecs_codec(mut config: HashMap<String, Value>) -> Result<Codec, String> {
+    match config.remove("id") {
+        Some(Value::String(id)) => {
+            Ok(Codec { name: format!("numcodecs.{id}"), configuration: Some(config) })
+        }
+        _ => Err("codec without id".to_string()),
+    }
+}
+
+/// The data type and, for multi byte types, the endianness of a numpy type string
+fn parse_numpy_dtype(dtype: &str) -> Result<(DataType, Option<&'static str>), String> {
+    let (order, code) = match dtype.split_at_checked(1) {
+        Some((order @ ("<" | ">" | "|" | "="), code)) => (order, code),
+        _ => ("|", dtype),
+    };
+    let data_type = match code {
+        "b1" => DataType::Bool,
+        "i1" => DataType::Int8,
+        "i2" => DataType::Int16,
+        "i4" => DataType::Int32,
+        "i8" => DataType::Int64,
+        "u1" => DataType::UInt8,
+        "u2" => DataType::UInt16,
+        "u4" => DataType::UInt32,
+        "u8" => DataType::UInt64,
+        "f2" => DataType::Float16,
+        "f4" => DataType::Float32,
+        "f8" => DataType::Float64,
+        "c8" => DataType::Complex64,
+        "c16" => DataType::Complex128,
+        _ => return Err(format!("unsupported data type `{dtype}`")),
+    };
+    let endian = match (data_type.needs_endianness(), order) {
+        (false, _) => None,
+        (true, ">") => Some("big"),
+        (true, _) => Some("little"),
+    };
+    Ok((data_type, endian))
+}
+
+fn zero_fill_value(data_type: &DataType) -> Result<FillValue, String> {
+    let zero = match data_type {
+        DataType::Bool => Value::from(false),
+        DataType::Complex64 | DataType::Complex128 => Value::from(vec![0, 0]),
+        _ => Value::from(0),
+    };
+    FillValue::from_data_type_and_json(data_type, &zero).map_err(|err| err.to_string())
+}
+
+fn parse_coords(chunk_key: &str, separator: &str) -> Option<ChunkIndices> {
+    chunk_key
+        .split(separator)
+        .map(|c| c.parse().ok())
+        .collect::<Option<_>>()
+        .map(ChunkIndices)
+}
+
+/// The bytes of an inline reference, kerchunk stores binary data in base64
+fn inline_bytes(key: &str, value: &Value) -> KerchunkResult<Bytes> {
+    let Value::String(data) = value else {
+        return Err(KerchunkError::InvalidReference {
+            key: key.to_string(),
+            message: "expected inline data".to_string(),
+        });
+    };
+    match data.strip_prefix("base64:") {
+        Some(encoded) => STANDARD.decode(encoded).map(Bytes::from).map_err(|err| {
+            KerchunkError::InvalidReference {
+                key: key.to_string(),
+                message: err.to_string(),
+            }
+        }),
+        None => Ok(Bytes::copy_from_slice(data.as_bytes())),
+    }
+}
+
+fn chunk_payload(
+    key: &str,
+    value: &Value,
+    templates: &HashMap<String, String>,
+) -> KerchunkResult<ChunkPayload> {
+    let invalid = |message: &str| KerchunkError::InvalidReference {
+        key: key.to_string(),
+        message: message.to_string(),
+    };
+    match value {
+        Value::String(_) => Ok(ChunkPayload::Inline(inline_bytes(key, value)?)),
+        Value::Array(parts) => match parts.as_slice() {
+            [Value::String(url), offset, length] => {
+                let mut url = url.clone();
+                for (name, value) in templates {
+                    url = url.replace(&format!("{{{{{name}}}}}"), value);
+                }
+                let offset = offset.as_u64().ok_or_else(|| invalid("invalid offset"))?;
+                let length = length.as_u64().ok_or_else(|| invalid("invalid length"))?;
+                Ok(ChunkPayload::Virtual(VirtualChunkRef {
+                    location: VirtualChunkLocation::from_absolute_path(&url)?,
+                    offset,
+                    length,
+                    slice: None,
+                }))
+            }
+            [Value::String(_)] => Err(invalid("whole file references are not supported")),
+            _ => Err(invalid("expected [url, offset, length]")),
+        },
+        _ => Err(invalid("expected inline data or [url, offset, length]")),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{ObjectStorage, Storage};
+
+    const REFS: &str = r#"{
+        "version": 1,
+        "templates": {"u": "s3://bucket/data.h5"},
+        "refs": {
+            ".zgroup": "{\"zarr_format\": 2}",
+            ".zattrs": "{\"title\": \"test\"}",
+            "temp/.zarray": "{\"shape\": [4, 4], \"chunks\": [2, 4], \"dtype\": \"<f4\", \"compressor\": {\"id\": \"zlib\", \"level\": 5}, \"fill_value\": \"NaN\", \"order\": \"C\", \"filters\": null, \"zarr_format\": 2}",
+            "temp/.zattrs": "{\"_ARRAY_DIMENSIONS\": [\"x\", \"y\"]}",
+            "temp/0.0": ["{{u}}", 100, 32],
+            "temp/1.0": ["s3://bucket/data.h5", 132, 32],
+            "flag/.zarray": "{\"shape\": [2], \"chunks\": [2], \"dtype\": \"|b1\", \"compressor\": null, \"fill_value\": null, \"zarr_format\": 2}",
+            "flag/0": "base64:AQA="
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn test_ingest_kerchunk_references() -> Result<(), Box<dyn Error>> {
+        let dataset = VirtualDataset::from_kerchunk(REFS.as_bytes())?;
+        let temp: Path = "/temp".try_into()?;
+        let flag: Path = "/flag".try_into()?;
+        assert_eq!(dataset.groups.keys().collect::<Vec<_>>(), vec![&Path::root()]);
+
+        let array = &dataset.arrays[&temp];
+        assert_eq!(array.metadata.data_type, DataType::Float32);
+        assert_eq!(array.metadata.codecs[1].name, "numcodecs.zlib");
+        assert_eq!(
+            array.chunks.get(&ChunkIndices(vec![0, 0])),
+            Some(&ChunkPayload::Virtual(VirtualChunkRef {
+                location: VirtualChunkLocation::from_absolute_path(
+                    "s3://bucket/data.h5"
+                )?,
+                offset: 100,
+                length: 32,
+                slice: None,
+            }))
+        );
+        assert_eq!(array.chunks.len(), 2);
+        assert_eq!(
+            dataset.arrays[&flag].chunks.get(&ChunkIndices(vec![0])),
+            Some(&ChunkPayload::Inline(Bytes::from_static(&[1, 0])))
+        );
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let root: Path = "/ingested".try_into()?;
+        ingest(&mut ds, &root, &dataset).await?;
+        ds.commit("main", "ingest", None).await?;
+        assert_eq!(
+            ds.get_chunk_ref(&root.child("temp")?, &ChunkIndices(vec![1, 0])).await?,
+            array.chunks.get(&ChunkIndices(vec![1, 0])).cloned()
+        );
+
+        // a failed ingestion leaves the session unchanged
+        assert!(ingest(&mut ds, &root, &dataset).await.is_err());
+        assert!(!ds.has_uncommitted_changes());
+
+        assert!(matches!(
+            VirtualDataset::from_kerchunk(
+                br#"{"a/.zarray": "{\"shape\": [1], \"chunks\": [1], \"dtype\": \"<f8\", \"compressor\": null, \"fill_value\": 0}", "a/0": ["s3://bucket/file"]}"#
+            ),
+            Err(KerchunkError::InvalidReference { key, .. }) if key == "a/0"
+        ));
+        Ok(())
+    }
+}
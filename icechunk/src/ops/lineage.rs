@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    format::{snapshot::Snapshot, SnapshotId},
+    repository::{RepositoryError, RepositoryResult},
+    Storage,
+};
+
+/// The snapshot property that stores the [`Lineage`] of the snapshot
+pub const LINEAGE_PROPERTY: &str = "icechunk.lineage";
+
+/// A snapshot in some repository
+///
+/// Repositories are identified by a name chosen by the user, usually their URL. The same
+/// name must be used when recording and when tracing lineage.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SnapshotLink {
+    pub repository: String,
+    pub snapshot_id: SnapshotId,
+}
+
+impl SnapshotLink {
+    pub fn new(repository: impl Into<String>, snapshot_id: SnapshotId) -> Self {
+        Self { repository: repository.into(), snapshot_id }
+    }
+}
+
+/// The provenance of a snapshot: the snapshots it was computed from, and how
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Lineage {
+    pub inputs: Vec<SnapshotLink>,
+    pub code_version: Option<String>,
+    pub parameters: BTreeMap<String, Value>,
+}
+
+impl Lineage {
+    pub fn with_input(mut self, input: SnapshotLink) -> Self {
+        self.inputs.push(input);
+        self
+    }
+
+    pub fn with_code_version(mut self, version: impl Into<String>) -> Self {
+        self.code_version = Some(version.into());
+        self
+    }
+
+    pub fn with_parameter(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Self {
+        self.parameters.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn to_property(&self) -> Value {
+        // serializing these types to a json value cannot fail
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// The lineage recorded in `snapshot`, if any
+    pub fn from_snapshot(snapshot: &Snapshot) -> RepositoryResult<Option<Lineage>> {
+        snapshot
+            .properties
+            .get(LINEAGE_PROPERTY)
+            .map(|value| {
+                serde_json::from_value(value.clone()).map_err(|err| {
+                    RepositoryError::InvalidLineage {
+                        snapshot: snapshot.metadata.id.clone(),
+                        message: err.to_string(),
+                    }
+                })
+            })
+            .transpose()
+    }
+}
+
+/// The lineage graph reachable from a snapshot, see [`trace_lineage`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineageGraph {
+    /// Every snapshot found, with the lineage it recorded, `None` if it recorded none
+    pub nodes: BTreeMap<SnapshotLink, Option<Lineage>>,
+    /// Inputs in repositories that were not provided, they were not followed
+    pub unresolved: Vec<SnapshotLink>,
+}
+
+impl LineageGraph {
+    /// The snapshots that were not computed from any other snapshot
+    pub fn sources(&self) -> impl Iterator<Item = &SnapshotLink> {
+        self.nodes
+            .iter()
+            .filter(|(_, lineage)| lineage.as_ref().is_none_or(|l| l.inputs.is_empty()))
+            .map(|(link, _)| link)
+    }
+}
+
+/// Follow the inputs recorded in the lineage of `start`, across repositories
+///
+/// `repositories` maps repository names, as used in [`SnapshotLink`], to their storage.
+/// Inputs in other repositories are reported as unresolved.
+pub async fn trace_lineage(
+    repositories: &HashMap<String, Arc<dyn Storage + Send + Sync>>,
+    start: SnapshotLink,
+) -> RepositoryResult<LineageGraph> {
+    let mut res = LineageGraph::default();
+    let mut seen = HashSet::from([start.clone()]);
+    let mut pending = VecDeque::from([start]);
+    while let Some(link) = pending.pop_front() {
+        let Some(storage) = repositories.get(&link.repository) else {
+            res.unresolved.push(link);
+            continue;
+        };
+        let snapshot = storage.fetch_snapshot(&link.snapshot_id).await?;
+        let lineage = Lineage::from_snapshot(&snapshot)?;
+        for input in lineage.iter().flat_map(|l| l.inputs.iter()) {
+            if seen.insert(input.clone()) {
+                pending.push_back(input.clone());
+            }
+        }
+        res.nodes.insert(link, lineage);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, ObjectStorage, Repository};
+
+    #[tokio::test]
+    async fn test_trace_lineage() -> Result<(), Box<dyn Error>> {
+        let raw: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let derived: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+
+        let mut ds = Repository::init(Arc::clone(&raw), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let raw_id = ds.commit("main", "raw data", None).await?;
+        assert_eq!(ds.lineage().await?, None);
+
+        let external = SnapshotLink::new("s3://other", raw_id.clone());
+        let mut ds = Repository::init(Arc::clone(&derived), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let lineage = Lineage::default()
+            .with_input(SnapshotLink::new("raw", raw_id.clone()))
+            .with_input(external.clone())
+            .with_code_version("v1.2.3")
+            .with_parameter("threshold", 0.5);
+        let derived_id =
+            ds.commit_with_lineage("main", "derived", None, &lineage).await?;
+        assert_eq!(ds.lineage().await?, Some(lineage.clone()));
+
+        let repositories = HashMap::from([
+            ("raw".to_string(), Arc::clone(&raw)),
+            ("derived".to_string(), Arc::clone(&derived)),
+        ]);
+        let graph = trace_lineage(
+            &repositories,
+            SnapshotLink::new("derived", derived_id.clone()),
+        )
+        .await?;
+        assert_eq!(
+            graph.nodes,
+            BTreeMap::from([
+                (SnapshotLink::new("derived", derived_id), Some(lineage)),
+                (SnapshotLink::new("raw", raw_id.clone()), None),
+            ])
+        );
+        assert_eq!(graph.unresolved, vec![external]);
+        assert_eq!(
+            graph.sources().collect::<Vec<_>>(),
+            vec![&SnapshotLink::new("raw", raw_id)]
+        );
+        Ok(())
+    }
+}
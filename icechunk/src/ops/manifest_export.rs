@@ -0,0 +1,878 @@
+//! Export of the chunk references of a snapshot as a Parquet file
+//!
+//! The file has one row per chunk, with the columns:
+//!
+//! * `array`: the path of the array
+//! * `coords`: the chunk coordinates, a list of integers
+//! * `kind`: `inline`, `native`, `virtual`, or `virtual_slice` for a region of the virtual
+//!   chunk, see [`crate::format::manifest::ChunkSlice`]
+//! * `object_key`: the key of the chunk object relative to the repository prefix, or the
+//!   location of the virtual chunk, null for inline chunks
+//! * `offset` and `length`: the byte range of the chunk in the object, null for inline chunks
+//! * `size`: the size of the chunk in bytes
+//!
+//! The file is written uncompressed, in a single row group of data pages of about
+//! [`PAGE_SIZE_BYTES`], so it can be read by any Parquet reader.
+use std::{fs, io, path::Path as FsPath};
+
+use futures::{pin_mut, TryStreamExt};
+use thiserror::Error;
+
+use crate::{
+    change_set::ChangeSet,
+    format::{
+        manifest::{ChunkPayload, ChunkRef, VirtualChunkLocation, VirtualChunkRef},
+        SnapshotId,
+    },
+    repository::{all_chunks, RepositoryError},
+    runtime::{self, default_runtime},
+    storage::CHUNK_PREFIX,
+    Storage,
+};
+
+#[derive(Debug, Error)]
+pub enum ManifestExportError {
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("error writing the export {0}")]
+    Io(#[from] std::io::Error),
+    #[error("the export is too large for the Parquet format: {0}")]
+    TooLarge(String),
+}
+
+pub type ManifestExportResult<A> = Result<A, ManifestExportError>;
+
+/// Write the chunk references of `snapshot_id` to a Parquet file at `path`
+///
+/// Returns the number of chunks written. The whole file is built in memory before it's
+/// written, in a blocking task of the default [`crate::runtime::Runtime`].
+pub async fn export_manifest_parquet(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+    path: &FsPath,
+) -> ManifestExportResult<usize> {
+    let change_set = ChangeSet::default();
+    let column = |name, physical_type, levels| {
+        Column::new(name, physical_type, levels, PAGE_SIZE_BYTES)
+    };
+    let mut array = column("array", PhysicalType::ByteArray, Levels::Required);
+    let mut coords = column("element", PhysicalType::Int64, Levels::List);
+    let mut kind = column("kind", PhysicalType::ByteArray, Levels::Required);
+    let mut object_key = column("object_key", PhysicalType::ByteArray, Levels::Optional);
+    let mut offset = column("offset", PhysicalType::Int64, Levels::Optional);
+    let mut length = column("length", PhysicalType::Int64, Levels::Optional);
+    let mut size = column("size", PhysicalType::Int64, Levels::Required);
+
+    let mut rows = 0;
+    let chunks = all_chunks(storage, &change_set, snapshot_id).await?;
+    pin_mut!(chunks);
+    while let Some((array_path, chunk)) = chunks.try_next().await? {
+        rows += 1;
+        array.push_str(Some(&array_path.to_string()));
+        coords.push_list(chunk.coord.0.iter().map(|c| *c as i64));
+        let (kind_name, key, range) = match chunk.payload {
+            ChunkPayload::Inline(bytes) => {
+                size.push_i64(Some(bytes.len() as i64));
+                ("inline", None, None)
+            }
+            ChunkPayload::Ref(ChunkRef { id, offset, length }) => {
+                size.push_i64(Some(length as i64));
+                ("native", Some(format!("{CHUNK_PREFIX}{id}")), Some((offset, length)))
+            }
+            ChunkPayload::Virtual(VirtualChunkRef {
+                location: VirtualChunkLocation::Absolute(location),
+                offset,
+                length,
+                slice,
+            }) => match slice {
+                None => {
+                    size.push_i64(Some(length as i64));
+                    ("virtual", Some(location), Some((offset, length)))
+                }
+                // the range is the whole external chunk, readers must know it's a region
+                Some(slice) => {
+                    size.push_i64(Some(slice.len() as i64));
+                    ("virtual_slice", Some(location), Some((offset, length)))
+                }
+            },
+        };
+        kind.push_str(Some(kind_name));
+        object_key.push_str(key.as_deref());
+        offset.push_i64(range.map(|(offset, _)| offset as i64));
+        length.push_i64(range.map(|(_, length)| length as i64));
+    }
+
+    let columns = [array, coords, kind, object_key, offset, length, size];
+    let file = encode_file(&columns, rows).map_err(ManifestExportError::TooLarge)?;
+    let path = path.to_path_buf();
+    runtime::spawn_blocking(default_runtime().as_ref(), move || fs::write(path, file))
+        .await
+        .map_err(|_| io::Error::other("the write of the export was cancelled"))??;
+    Ok(rows)
+}
+
+// A minimal Parquet encoder, for the flat schema above: plain encoded values, RLE encoded
+// levels, no compression, data pages that start at row boundaries. Metadata is Thrift, in
+// the compact protocol, see https://github.com/apache/parquet-format
+
+const MAGIC: &[u8] = b"PAR1";
+
+/// Data pages are closed once their values reach this size, readers load a page at a time
+pub const PAGE_SIZE_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PhysicalType {
+    Int64 = 2,
+    ByteArray = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Levels {
+    Required,
+    Optional,
+    /// The element of a required list: `coords.list.element`
+    List,
+}
+
+#[derive(Debug, Default)]
+struct Page {
+    repetition: Vec<u8>,
+    definition: Vec<u8>,
+    values: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Column {
+    name: &'static str,
+    physical_type: PhysicalType,
+    levels: Levels,
+    page_size: usize,
+    /// The last one is open, the values of a row are always in a single page
+    pages: Vec<Page>,
+}
+
+impl Column {
+    fn new(
+        name: &'static str,
+        physical_type: PhysicalType,
+        levels: Levels,
+        page_size: usize,
+    ) -> Self {
+        Self { name, physical_type, levels, page_size, pages: vec![] }
+    }
+
+    /// The page the next row goes to
+    ///
+    /// Levels count towards the size, so pages of nulls are bounded too.
+    fn next_row(&mut self) -> &mut Page {
+        let full = self.pages.last().is_none_or(|page| {
+            page.values.len() + page.definition.len() >= self.page_size
+        });
+        if full {
+            self.pages.push(Page::default());
+        }
+        let last = self.pages.len() - 1;
+        &mut self.pages[last]
+    }
+
+    fn push_str(&mut self, value: Option<&str>) {
+        self.next_row().push(value.map(|value| {
+            let mut bytes = (value.len() as u32).to_le_bytes().to_vec();
+            bytes.extend(value.as_bytes());
+            bytes
+        }));
+    }
+
+    fn push_i64(&mut self, value: Option<i64>) {
+        self.next_row().push(value.map(|value| value.to_le_bytes().to_vec()));
+    }
+
+    fn push_list(&mut self, values: impl Iterator<Item = i64>) {
+        let page = self.next_row();
+        let before = page.definition.len();
+        for (i, value) in values.enumerate() {
+            page.repetition.push((i > 0) as u8);
+            page.push(Some(value.to_le_bytes().to_vec()));
+        }
+        // an empty list is a single entry, one level short of an element
+        if page.definition.len() == before {
+            page.repetition.push(0);
+            page.definition.push(0);
+        }
+    }
+
+    fn num_values(&self) -> usize {
+        self.pages.iter().map(|page| page.definition.len()).sum()
+    }
+
+    fn path(&self) -> Vec<&'static str> {
+        match self.levels {
+            Levels::List => vec!["coords", "list", self.name],
+            _ => vec![self.name],
+        }
+    }
+}
+
+impl Page {
+    fn push(&mut self, value: Option<Vec<u8>>) {
+        self.definition.push(value.is_some() as u8);
+        if let Some(value) = value {
+            self.values.extend(value);
+        }
+    }
+
+    fn encode(&self, levels: Levels) -> Vec<u8> {
+        let mut page = vec![];
+        if levels == Levels::List {
+            encode_levels(&mut page, &self.repetition);
+        }
+        if levels != Levels::Required {
+            encode_levels(&mut page, &self.definition);
+        }
+        page.extend(&self.values);
+        page
+    }
+}
+
+/// The value of an `i32` field of the metadata, the error describes the overflow
+fn thrift_i32(field: &str, value: usize) -> Result<i32, String> {
+    i32::try_from(value).map_err(|_| format!("{field} of {value} is larger than 2 GiB"))
+}
+
+/// Levels of at most 1, as runs of the RLE hybrid encoding, prefixed with their length
+fn encode_levels(out: &mut Vec<u8>, levels: &[u8]) {
+    let mut encoded = vec![];
+    for run in levels.chunk_by(|a, b| a == b) {
+        write_varint(&mut encoded, (run.len() as u64) << 1);
+        encoded.push(run[0]);
+    }
+    out.extend((encoded.len() as u32).to_le_bytes());
+    out.extend(encoded);
+}
+
+/// The file with `columns`, the error describes a field too large for the format
+fn encode_file(columns: &[Column], rows: usize) -> Result<Vec<u8>, String> {
+    let mut file = MAGIC.to_vec();
+    let mut chunks = vec![];
+    for column in columns {
+        let offset = file.len();
+        // columns without rows have a single empty page
+        let empty = [Page::default()];
+        let pages = if column.pages.is_empty() { &empty[..] } else { &column.pages };
+        for page in pages {
+            let encoded = page.encode(column.levels);
+            let page_size = thrift_i32("page size", encoded.len())?;
+            let num_values = thrift_i32("number of values", page.definition.len())?;
+            let mut header = Thrift::default();
+            header.write_struct(|t| {
+                // DATA_PAGE
+                t.i32(1, 0);
+                t.i32(2, page_size);
+                t.i32(3, page_size);
+                t.struct_field(5, |t| {
+                    t.i32(1, num_values);
+                    // PLAIN values, RLE levels
+                    t.i32(2, 0);
+                    t.i32(3, 3);
+                    t.i32(4, 3);
+                });
+            });
+            file.extend(&header.buf);
+            file.extend(&encoded);
+        }
+        chunks.push((offset, file.len() - offset));
+    }
+
+    let mut meta = Thrift::default();
+    meta.write_struct(|t| {
+        t.i32(1, 1);
+        t.list_field(2, Thrift::STRUCT, columns.len() + 3);
+        t.write_struct(|t| {
+            t.binary(4, b"schema");
+            t.i32(5, columns.len() as i32);
+        });
+        for column in columns {
+            if column.levels == Levels::List {
+                // required group coords (LIST) { repeated group list { required element } }
+                t.write_struct(|t| {
+                    t.i32(3, 0);
+                    t.binary(4, b"coords");
+                    t.i32(5, 1);
+                    t.i32(6, 3);
+                });
+                t.write_struct(|t| {
+                    t.i32(3, 2);
+                    t.binary(4, b"list");
+                    t.i32(5, 1);
+                });
+            }
+            t.write_struct(|t| {
+                t.i32(1, column.physical_type as i32);
+                t.i32(3, (column.levels == Levels::Optional) as i32);
+                t.binary(4, column.name.as_bytes());
+                if column.physical_type == PhysicalType::ByteArray {
+                    // UTF8
+                    t.i32(6, 0);
+                }
+            });
+        }
+        t.i64(3, rows as i64);
+        t.list_field(4, Thrift::STRUCT, 1);
+        t.write_struct(|t| {
+            t.list_field(1, Thrift::STRUCT, columns.len());
+            for (column, (offset, size)) in columns.iter().zip(chunks.iter()) {
+                t.write_struct(|t| {
+                    t.i64(2, *offset as i64);
+                    t.struct_field(3, |t| {
+                        t.i32(1, column.physical_type as i32);
+                        t.list_field(2, Thrift::I32, 2);
+                        t.i32_element(0);
+                        t.i32_element(3);
+                        let path = column.path();
+                        t.list_field(3, Thrift::BINARY, path.len());
+                        for name in path {
+                            t.binary_element(name.as_bytes());
+                        }
+                        // UNCOMPRESSED
+                        t.i32(4, 0);
+                        t.i64(5, column.num_values() as i64);
+                        t.i64(6, *size as i64);
+                        t.i64(7, *size as i64);
+                        t.i64(9, *offset as i64);
+                    });
+                });
+            }
+            t.i64(2, chunks.iter().map(|(_, size)| *size as i64).sum());
+            t.i64(3, rows as i64);
+        });
+        t.binary(6, b"icechunk");
+    });
+    file.extend(&meta.buf);
+    file.extend(thrift_i32("footer size", meta.buf.len())?.to_le_bytes());
+    file.extend(MAGIC);
+    Ok(file)
+}
+
+/// A writer of the Thrift compact protocol, for the types Parquet metadata uses
+#[derive(Debug, Default)]
+struct Thrift {
+    buf: Vec<u8>,
+    last_field: i16,
+}
+
+impl Thrift {
+    const I32: u8 = 5;
+    const I64: u8 = 6;
+    const BINARY: u8 = 8;
+    const LIST: u8 = 9;
+    const STRUCT: u8 = 12;
+
+    fn field(&mut self, id: i16, field_type: u8) {
+        let delta = id - self.last_field;
+        if (1..=15).contains(&delta) {
+            self.buf.push(((delta as u8) << 4) | field_type);
+        } else {
+            self.buf.push(field_type);
+            write_varint(&mut self.buf, zigzag(id as i64));
+        }
+        self.last_field = id;
+    }
+
+    /// A struct as a top level value or a list element, `fields` writes its fields
+    fn write_struct(&mut self, fields: impl FnOnce(&mut Self)) {
+        let outer = std::mem::replace(&mut self.last_field, 0);
+        fields(self);
+        self.buf.push(0);
+        self.last_field = outer;
+    }
+
+    fn struct_field(&mut self, id: i16, fields: impl FnOnce(&mut Self)) {
+        self.field(id, Self::STRUCT);
+        self.write_struct(fields);
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, Self::I32);
+        self.i32_element(value);
+    }
+
+    fn i32_element(&mut self, value: i32) {
+        write_varint(&mut self.buf, zigzag(value as i64));
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, Self::I64);
+        write_varint(&mut self.buf, zigzag(value));
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, Self::BINARY);
+        self.binary_element(value);
+    }
+
+    fn binary_element(&mut self, value: &[u8]) {
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend(value);
+    }
+
+    /// The header of a list, the caller writes the `len` elements next
+    fn list_field(&mut self, id: i16, element_type: u8, len: usize) {
+        self.field(id, Self::LIST);
+        if len < 15 {
+            self.buf.push(((len as u8) << 4) | element_type);
+        } else {
+            self.buf.push(0xf0 | element_type);
+            write_varint(&mut self.buf, len as u64);
+        }
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashMap, error::Error, num::NonZeroU64, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ChunkIndices, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        ObjectStorage, Repository,
+    };
+
+    // A reader of the files written above, following the Parquet and Thrift specs rather
+    // than the encoder, so a mistake in one doesn't hide in the other
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Thrifty {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Thrifty>),
+        Struct(HashMap<i16, Thrifty>),
+    }
+
+    impl Thrifty {
+        fn int(&self, id: i16) -> i64 {
+            match self.field(id) {
+                Thrifty::Int(value) => *value,
+                other => panic!("field {id} is not an integer: {other:?}"),
+            }
+        }
+
+        fn str(&self, id: i16) -> String {
+            match self.field(id) {
+                Thrifty::Binary(value) => String::from_utf8(value.clone()).unwrap(),
+                other => panic!("field {id} is not binary: {other:?}"),
+            }
+        }
+
+        fn list(&self, id: i16) -> &[Thrifty] {
+            match self.field(id) {
+                Thrifty::List(values) => values,
+                other => panic!("field {id} is not a list: {other:?}"),
+            }
+        }
+
+        fn field(&self, id: i16) -> &Thrifty {
+            self.get(id).unwrap_or_else(|| panic!("missing field {id}"))
+        }
+
+        fn get(&self, id: i16) -> Option<&Thrifty> {
+            match self {
+                Thrifty::Struct(fields) => fields.get(&id),
+                other => panic!("not a struct: {other:?}"),
+            }
+        }
+    }
+
+    struct Reader<'a> {
+        buf: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        fn new(buf: &'a [u8]) -> Self {
+            Self { buf, pos: 0 }
+        }
+
+        fn bytes(&mut self, len: usize) -> &'a [u8] {
+            let res = &self.buf[self.pos..self.pos + len];
+            self.pos += len;
+            res
+        }
+
+        fn byte(&mut self) -> u8 {
+            self.bytes(1)[0]
+        }
+
+        fn u32_le(&mut self) -> u32 {
+            u32::from_le_bytes(self.bytes(4).try_into().unwrap())
+        }
+
+        fn varint(&mut self) -> u64 {
+            let mut res = 0;
+            for shift in (0..).step_by(7) {
+                let byte = self.byte();
+                res |= ((byte & 0x7f) as u64) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            res
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let n = self.varint();
+            (n >> 1) as i64 ^ -((n & 1) as i64)
+        }
+
+        fn value(&mut self, compact_type: u8) -> Thrifty {
+            match compact_type {
+                // booleans in fields carry their value in the type
+                1 => Thrifty::Int(1),
+                2 => Thrifty::Int(0),
+                3 => Thrifty::Int(self.byte() as i8 as i64),
+                4..=6 => Thrifty::Int(self.zigzag()),
+                8 => {
+                    let len = self.varint() as usize;
+                    Thrifty::Binary(self.bytes(len).to_vec())
+                }
+                9 => {
+                    let header = self.byte();
+                    let mut len = (header >> 4) as usize;
+                    if len == 15 {
+                        len = self.varint() as usize;
+                    }
+                    Thrifty::List((0..len).map(|_| self.value(header & 0x0f)).collect())
+                }
+                12 => {
+                    let mut fields = HashMap::new();
+                    let mut last = 0i16;
+                    loop {
+                        let header = self.byte();
+                        if header == 0 {
+                            break Thrifty::Struct(fields);
+                        }
+                        let id = match header >> 4 {
+                            0 => self.zigzag() as i16,
+                            delta => last + delta as i16,
+                        };
+                        last = id;
+                        fields.insert(id, self.value(header & 0x0f));
+                    }
+                }
+                other => panic!("unexpected compact type {other}"),
+            }
+        }
+
+        /// Levels of bit width 1 in the RLE/bit-packing hybrid, prefixed with their length
+        fn levels(&mut self, count: usize) -> Vec<u8> {
+            let len = self.u32_le() as usize;
+            let mut runs = Reader::new(self.bytes(len));
+            let mut res = Vec::with_capacity(count);
+            while runs.pos < runs.buf.len() {
+                let header = runs.varint();
+                if header & 1 == 0 {
+                    let value = runs.byte();
+                    res.extend(std::iter::repeat_n(value, (header >> 1) as usize));
+                } else {
+                    for byte in runs.bytes((header >> 1) as usize) {
+                        res.extend((0..8).map(|bit| (byte >> bit) & 1));
+                    }
+                }
+            }
+            res.truncate(count);
+            res
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    enum Cell {
+        Null,
+        Int(i64),
+        Str(String),
+        List(Vec<i64>),
+    }
+
+    /// The leaf columns of `schema` with their path, max repetition and definition levels
+    fn leaves(schema: &[Thrifty]) -> Vec<(Vec<String>, u8, u8)> {
+        fn walk(
+            schema: &[Thrifty],
+            pos: &mut usize,
+            path: &mut Vec<String>,
+            (rep, def): (u8, u8),
+            res: &mut Vec<(Vec<String>, u8, u8)>,
+        ) {
+            let element = &schema[*pos];
+            *pos += 1;
+            for _ in 0..element.get(5).map_or(0, |_| element.int(5)) {
+                let child = &schema[*pos];
+                let levels = match child.int(3) {
+                    0 => (rep, def),
+                    1 => (rep, def + 1),
+                    _ => (rep + 1, def + 1),
+                };
+                path.push(child.str(4));
+                if child.get(5).is_some() {
+                    walk(schema, pos, path, levels, res);
+                } else {
+                    *pos += 1;
+                    res.push((path.clone(), levels.0, levels.1));
+                }
+                path.pop();
+            }
+        }
+        let mut res = vec![];
+        walk(schema, &mut 0, &mut vec![], (0, 0), &mut res);
+        res
+    }
+
+    /// The rows of a Parquet file with one row group of uncompressed data pages, and the
+    /// number of pages of each column
+    fn read_parquet(bytes: &[u8]) -> (Vec<Vec<String>>, Vec<Vec<Cell>>, Vec<usize>) {
+        assert_eq!(&bytes[..4], b"PAR1");
+        assert_eq!(&bytes[bytes.len() - 4..], b"PAR1");
+        let mut tail = Reader::new(&bytes[bytes.len() - 8..]);
+        let footer_start = bytes.len() - 8 - tail.u32_le() as usize;
+        let meta = Reader::new(&bytes[footer_start..]).value(12);
+        let num_rows = meta.int(3) as usize;
+        let leaves = leaves(meta.list(2));
+        let row_groups = meta.list(4);
+        assert_eq!(row_groups.len(), 1);
+        assert_eq!(row_groups[0].int(3) as usize, num_rows);
+        let chunks = row_groups[0].list(1);
+        assert_eq!(chunks.len(), leaves.len());
+
+        let mut rows = vec![vec![]; num_rows];
+        let mut page_counts = vec![];
+        for (chunk, (path, max_rep, max_def)) in chunks.iter().zip(leaves.iter()) {
+            let meta = chunk.field(3);
+            let chunk_path: Vec<String> = meta
+                .list(3)
+                .iter()
+                .map(|name| match name {
+                    Thrifty::Binary(name) => String::from_utf8(name.clone()).unwrap(),
+                    other => panic!("not a name: {other:?}"),
+                })
+                .collect();
+            assert_eq!(&chunk_path, path);
+            // uncompressed
+            assert_eq!(meta.int(4), 0);
+
+            let mut reader = Reader::new(bytes);
+            reader.pos = meta.int(9) as usize;
+            let end = reader.pos + meta.int(6) as usize;
+            let (mut values, mut pages, mut row) = (0, 0, 0);
+            while reader.pos < end {
+                let header = reader.value(12);
+                pages += 1;
+                // a data page, with plain values
+                assert_eq!(header.int(1), 0);
+                let page_header = header.field(5);
+                assert_eq!(page_header.int(2), 0);
+                let count = page_header.int(1) as usize;
+                let mut page = Reader::new(reader.bytes(header.int(3) as usize));
+                let reps = if *max_rep > 0 { page.levels(count) } else { vec![0; count] };
+                let defs = if *max_def > 0 { page.levels(count) } else { vec![0; count] };
+                // pages start at row boundaries
+                assert_eq!(reps.first().copied().unwrap_or(0), 0);
+
+                for (rep, def) in reps.iter().zip(defs.iter()) {
+                    let value = (def == max_def).then(|| match meta.int(1) {
+                        2 => Cell::Int(i64::from_le_bytes(
+                            page.bytes(8).try_into().unwrap(),
+                        )),
+                        6 => {
+                            let len = page.u32_le() as usize;
+                            Cell::Str(
+                                String::from_utf8(page.bytes(len).to_vec()).unwrap(),
+                            )
+                        }
+                        other => panic!("unexpected physical type {other}"),
+                    });
+                    if *max_rep == 0 {
+                        rows[row].push(value.unwrap_or(Cell::Null));
+                        row += 1;
+                        continue;
+                    }
+                    if *rep == 0 {
+                        rows[row].push(Cell::List(vec![]));
+                        row += 1;
+                    }
+                    if let (Some(Cell::Int(value)), Some(Cell::List(list))) =
+                        (value, rows[row - 1].last_mut())
+                    {
+                        list.push(value);
+                    }
+                }
+                assert_eq!(page.pos, page.buf.len());
+                values += count;
+            }
+            assert_eq!(reader.pos, end);
+            assert_eq!(values as i64, meta.int(5));
+            assert_eq!(row, num_rows);
+            page_counts.push(pages);
+        }
+        (leaves.into_iter().map(|(path, ..)| path).collect(), rows, page_counts)
+    }
+
+    #[tokio::test]
+    async fn test_export_manifest_parquet() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2, 2],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap(); 2]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        let id = crate::format::ChunkId::random();
+        let chunks = [
+            ChunkPayload::Inline(Bytes::from_static(b"ab")),
+            ChunkPayload::Ref(ChunkRef { id: id.clone(), offset: 0, length: 3 }),
+            ChunkPayload::Virtual(VirtualChunkRef {
+                location: VirtualChunkLocation::from_absolute_path("s3://bucket/file")?,
+                offset: 10,
+                length: 20,
+                slice: None,
+            }),
+        ];
+        for (i, payload) in chunks.into_iter().enumerate() {
+            ds.set_chunk_ref(
+                path.clone(),
+                ChunkIndices(vec![i as u64, 1]),
+                Some(payload),
+            )
+            .await?;
+        }
+        let snapshot = ds.commit("main", "commit", None).await?;
+
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("manifest.parquet");
+        assert_eq!(export_manifest_parquet(storage.as_ref(), &snapshot, &file).await?, 3);
+
+        let bytes = fs::read(&file)?;
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(&bytes[bytes.len() - 4..], MAGIC);
+        let footer_len =
+            u32::from_le_bytes(bytes[bytes.len() - 8..bytes.len() - 4].try_into()?)
+                as usize;
+        let footer = &bytes[bytes.len() - 8 - footer_len..bytes.len() - 8];
+        assert!(footer.windows(10).any(|w| w == b"object_key"));
+
+        // the object keys are plain encoded, after the definition levels of the inline chunk
+        let key = format!("{CHUNK_PREFIX}{id}");
+        let mut expected = (key.len() as u32).to_le_bytes().to_vec();
+        expected.extend(key.as_bytes());
+        assert!(bytes.windows(expected.len()).any(|w| w == expected));
+
+        // the levels of the coordinates: 3 lists of 2 elements
+        let mut levels = vec![];
+        encode_levels(&mut levels, &[0, 1, 0, 1, 0, 1]);
+        assert_eq!(levels, vec![12, 0, 0, 0, 2, 0, 2, 1, 2, 0, 2, 1, 2, 0, 2, 1]);
+
+        let (columns, mut rows, pages) = read_parquet(&bytes);
+        assert_eq!(pages, vec![1; 7]);
+        assert_eq!(
+            columns,
+            [
+                vec!["array"],
+                vec!["coords", "list", "element"],
+                vec!["kind"],
+                vec!["object_key"],
+                vec!["offset"],
+                vec!["length"],
+                vec!["size"]
+            ]
+        );
+        rows.sort();
+        let array = || Cell::Str("/array".to_string());
+        let str = |s: &str| Cell::Str(s.to_string());
+        assert_eq!(
+            rows,
+            vec![
+                vec![
+                    array(),
+                    Cell::List(vec![0, 1]),
+                    str("inline"),
+                    Cell::Null,
+                    Cell::Null,
+                    Cell::Null,
+                    Cell::Int(2)
+                ],
+                vec![
+                    array(),
+                    Cell::List(vec![1, 1]),
+                    str("native"),
+                    Cell::Str(key),
+                    Cell::Int(0),
+                    Cell::Int(3),
+                    Cell::Int(3)
+                ],
+                vec![
+                    array(),
+                    Cell::List(vec![2, 1]),
+                    str("virtual"),
+                    str("s3://bucket/file"),
+                    Cell::Int(10),
+                    Cell::Int(20),
+                    Cell::Int(20)
+                ],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pages_are_split_at_rows() -> Result<(), Box<dyn Error>> {
+        let mut name = Column::new("name", PhysicalType::ByteArray, Levels::Optional, 16);
+        let mut coords = Column::new("element", PhysicalType::Int64, Levels::List, 16);
+        let mut expected = vec![];
+        for row in 0..10i64 {
+            let value = (row % 3 != 0).then(|| format!("row-{row}"));
+            name.push_str(value.as_deref());
+            let list: Vec<i64> = (0..row % 4).map(|i| row * 10 + i).collect();
+            coords.push_list(list.iter().copied());
+            expected.push(vec![value.map_or(Cell::Null, Cell::Str), Cell::List(list)]);
+        }
+        assert!(name.pages.len() > 1);
+        assert!(coords.pages.len() > 1);
+        let bytes = encode_file(&[name, coords], 10)?;
+        let (_, rows, pages) = read_parquet(&bytes);
+        assert!(pages.iter().all(|pages| *pages > 1));
+        assert_eq!(rows, expected);
+
+        // fields of the metadata are 32 bits
+        assert!(thrift_i32("page size", 1 << 31).is_err());
+        assert_eq!(thrift_i32("page size", 1024)?, 1024);
+        Ok(())
+    }
+}
@@ -1 +1,23 @@
+pub mod ancestry;
+pub mod attributes_index;
+#[cfg(feature = "catalog")]
+pub mod catalog;
+pub mod content_hash;
+pub mod derived;
+pub mod documents;
 pub mod gc;
+pub mod health;
+pub mod inspect;
+pub mod kerchunk;
+pub mod lineage;
+pub mod manifest_export;
+pub mod patch;
+pub mod publish;
+pub mod read_plan;
+pub mod refcount;
+pub mod retention;
+pub mod spec;
+pub mod tiering;
+pub mod transaction;
+pub mod union;
+pub mod usage;
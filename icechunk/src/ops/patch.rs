@@ -0,0 +1,417 @@
+//! Portable patches, the uncommitted changes of a session as a file
+//!
+//! A [`Patch`] records the changes by path, not by node id, so it can be applied to another
+//! branch, or another repository, with [`apply_patch`]. It's reviewed with [`Patch::changes`]
+//! and transferred with [`Patch::to_bytes`].
+//!
+//! Chunks written by the session are kept as references with [`PatchChunks::References`],
+//! the patch is small but only valid in repositories that share the chunk objects, other
+//! branches of the same repository, for example. With [`PatchChunks::Data`] their bytes are
+//! copied into the patch, and written again when it's applied. Inline and virtual chunks
+//! are always kept as they are. References keep the transformers the chunks were encoded
+//! with, see [`TransformedPayload`].
+//!
+//! Serialized patches carry [`PATCH_FORMAT_VERSION`], patches of other versions are
+//! rejected by [`Patch::from_bytes`].
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, TransformedPayload},
+        snapshot::{CustomNodeData, NodeType, ZarrArrayMetadata},
+        ByteRange, ChunkIndices, NodeId, Path, SnapshotId,
+    },
+    metadata::UserAttributes,
+    repository::{get_chunk, RepositoryError, RepositoryResult},
+    Repository,
+};
+
+/// The version of the serialized [`Patch`], it changes with the layout of patches
+pub const PATCH_FORMAT_VERSION: u16 = 1;
+
+/// How the chunks written by the session are exported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchChunks {
+    References,
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PatchChunk {
+    Payload(TransformedPayload),
+    /// The bytes of a chunk, written again when the patch is applied
+    Data(Bytes),
+}
+
+/// A change of the patch, they are applied in order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PatchChange {
+    DeleteGroup(Path),
+    DeleteArray(Path),
+    DeleteCustomNode(Path),
+    AddGroup(Path),
+    AddArray(Path, ZarrArrayMetadata),
+    AddCustomNode(Path, CustomNodeData),
+    UpdateArray(Path, ZarrArrayMetadata),
+    UpdateCustomNode(Path, CustomNodeData),
+    SetUserAttributes(Path, Option<UserAttributes>),
+    /// `None` deletes the chunk
+    SetChunk(Path, ChunkIndices, Option<PatchChunk>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Patch {
+    version: u16,
+    /// The snapshot the changes were made on
+    pub base: SnapshotId,
+    changes: Vec<PatchChange>,
+}
+
+/// Only the version of a serialized [`Patch`], readable whatever the layout of the rest
+#[derive(Deserialize)]
+struct PatchHeader {
+    version: u16,
+}
+
+impl Patch {
+    pub fn changes(&self) -> &[PatchChange] {
+        &self.changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn to_bytes(&self) -> RepositoryResult<Vec<u8>> {
+        // with field names, so the version can be read before the rest
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+
+    /// Fails with [`RepositoryError::UnsupportedPatchVersion`] for patches of another
+    /// version of the format
+    pub fn from_bytes(bytes: &[u8]) -> RepositoryResult<Self> {
+        let PatchHeader { version } = rmp_serde::from_slice(bytes)?;
+        if version != PATCH_FORMAT_VERSION {
+            return Err(RepositoryError::UnsupportedPatchVersion {
+                found: version,
+                supported: PATCH_FORMAT_VERSION,
+            });
+        }
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// The uncommitted changes of `repo` as a patch, without the scratch area
+pub async fn export_patch(
+    repo: &Repository,
+    chunks: PatchChunks,
+) -> RepositoryResult<Patch> {
+    let mut change_set = repo.change_set().clone();
+    change_set.split_off(&Repository::scratch_path());
+    let paths: HashMap<NodeId, Path> =
+        repo.list_nodes().await?.map(|node| (node.id, node.path)).collect();
+    let base = repo.storage().fetch_snapshot(repo.snapshot_id()).await?;
+
+    let mut deleted: BTreeMap<Path, NodeType> = BTreeMap::new();
+    deleted.extend(change_set.deleted_groups().map(|p| (p.clone(), NodeType::Group)));
+    deleted.extend(change_set.deleted_arrays().map(|p| (p.clone(), NodeType::Array)));
+    deleted
+        .extend(change_set.deleted_custom_nodes().map(|p| (p.clone(), NodeType::Custom)));
+    // a new node replacing one of the base snapshot
+    for (path, _) in change_set.new_nodes() {
+        if let Ok(node) = base.get_node(path) {
+            deleted.entry(path.clone()).or_insert_with(|| node.node_type());
+        }
+    }
+    let mut changes: Vec<PatchChange> = deleted
+        .into_iter()
+        .map(|(path, node_type)| match node_type {
+            NodeType::Group => PatchChange::DeleteGroup(path),
+            NodeType::Array => PatchChange::DeleteArray(path),
+            NodeType::Custom => PatchChange::DeleteCustomNode(path),
+        })
+        .collect();
+
+    // parents are added before their children
+    let mut added: BTreeMap<&Path, PatchChange> = BTreeMap::new();
+    for (path, _) in change_set.new_groups() {
+        added.insert(path, PatchChange::AddGroup(path.clone()));
+    }
+    for (path, _) in change_set.new_arrays() {
+        if let Some((_, metadata)) = change_set.get_array(path) {
+            added.insert(path, PatchChange::AddArray(path.clone(), metadata.clone()));
+        }
+    }
+    for (path, _) in change_set.new_custom_nodes() {
+        if let Some((_, data)) = change_set.get_custom_node(path) {
+            added.insert(path, PatchChange::AddCustomNode(path.clone(), data.clone()));
+        }
+    }
+    changes.extend(added.into_values());
+
+    // changes to deleted nodes have no path, they are dropped
+    let mut updated: BTreeMap<&Path, Vec<PatchChange>> = BTreeMap::new();
+    for id in change_set.zarr_updated_arrays() {
+        if let (Some(path), Some(metadata)) =
+            (paths.get(id), change_set.get_updated_zarr_metadata(id))
+        {
+            updated
+                .entry(path)
+                .or_default()
+                .push(PatchChange::UpdateArray(path.clone(), metadata.clone()));
+        }
+    }
+    for id in change_set.custom_updated_nodes() {
+        if let (Some(path), Some(data)) =
+            (paths.get(id), change_set.get_updated_custom_node(id))
+        {
+            updated
+                .entry(path)
+                .or_default()
+                .push(PatchChange::UpdateCustomNode(path.clone(), data.clone()));
+        }
+    }
+    for id in change_set.user_attributes_updated_nodes() {
+        if let (Some(path), Some(atts)) =
+            (paths.get(id), change_set.get_user_attributes(id))
+        {
+            updated
+                .entry(path)
+                .or_default()
+                .push(PatchChange::SetUserAttributes(path.clone(), atts.clone()));
+        }
+    }
+    for (id, node_chunks) in change_set.chunk_changes() {
+        let Some(path) = paths.get(id) else { continue };
+        let mut node_chunks: Vec<_> = node_chunks.iter().collect();
+        node_chunks.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        for (coords, payload) in node_chunks {
+            let chunk = match payload {
+                Some(ChunkPayload::Ref(_)) if chunks == PatchChunks::Data => {
+                    let reader =
+                        repo.get_chunk_reader(path, coords, &ByteRange::ALL).await?;
+                    get_chunk(reader).await?.map(PatchChunk::Data)
+                }
+                Some(_) => repo
+                    .get_transformed_chunk_ref(path, coords)
+                    .await?
+                    .map(PatchChunk::Payload),
+                None => None,
+            };
+            updated.entry(path).or_default().push(PatchChange::SetChunk(
+                path.clone(),
+                coords.clone(),
+                chunk,
+            ));
+        }
+    }
+    changes.extend(updated.into_values().flatten());
+
+    Ok(Patch { version: PATCH_FORMAT_VERSION, base: repo.snapshot_id().clone(), changes })
+}
+
+/// Record the changes of `patch` in the session of `repo`
+///
+/// Nothing is committed. Fails, leaving the changes applied until then, if a change doesn't
+/// fit the session, like adding a node that already exists.
+pub async fn apply_patch(repo: &mut Repository, patch: &Patch) -> RepositoryResult<()> {
+    if patch.version != PATCH_FORMAT_VERSION {
+        return Err(RepositoryError::UnsupportedPatchVersion {
+            found: patch.version,
+            supported: PATCH_FORMAT_VERSION,
+        });
+    }
+    for change in patch.changes.iter() {
+        match change {
+            PatchChange::DeleteGroup(path) => repo.delete_group(path.clone()).await?,
+            PatchChange::DeleteArray(path) => repo.delete_array(path.clone()).await?,
+            PatchChange::DeleteCustomNode(path) => {
+                repo.delete_custom_node(path.clone()).await?
+            }
+            PatchChange::AddGroup(path) => repo.add_group(path.clone()).await?,
+            PatchChange::AddArray(path, metadata) => {
+                repo.add_array(path.clone(), metadata.clone()).await?
+            }
+            PatchChange::AddCustomNode(path, data) => {
+                repo.add_custom_node(path.clone(), data.clone()).await?
+            }
+            PatchChange::UpdateArray(path, metadata) => {
+                repo.update_array(path.clone(), metadata.clone()).await?
+            }
+            PatchChange::UpdateCustomNode(path, data) => {
+                repo.update_custom_node(path.clone(), data.clone()).await?
+            }
+            PatchChange::SetUserAttributes(path, atts) => {
+                repo.set_user_attributes(path.clone(), atts.clone()).await?
+            }
+            PatchChange::SetChunk(path, coords, Some(PatchChunk::Data(bytes))) => {
+                // written again, with the transformers of the array
+                let writer = repo.get_array_chunk_writer(path).await?;
+                let payload = writer(bytes.clone()).await?;
+                repo.set_chunk_ref(path.clone(), coords.clone(), Some(payload)).await?
+            }
+            PatchChange::SetChunk(path, coords, Some(PatchChunk::Payload(chunk))) => {
+                repo.set_transformed_chunk_ref(
+                    path.clone(),
+                    coords.clone(),
+                    Some(chunk.clone()),
+                )
+                .await?
+            }
+            PatchChange::SetChunk(path, coords, None) => {
+                repo.set_chunk_ref(path.clone(), coords.clone(), None).await?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        repository::RepositoryError,
+        test_utils::{array_metadata, new_in_memory_storage, XorTransformer},
+    };
+
+    async fn chunk(repo: &Repository, path: &Path, idx: u64) -> Option<Bytes> {
+        let reader = repo
+            .get_chunk_reader(path, &ChunkIndices(vec![idx]), &ByteRange::ALL)
+            .await
+            .unwrap();
+        get_chunk(reader).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_export_and_apply_patch() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .build();
+        let (old, array): (Path, Path) = ("/old".try_into()?, "/data/array".try_into()?);
+        ds.add_group(Path::root()).await?;
+        ds.add_group(old.clone()).await?;
+        let base = ds.commit("main", "base", None).await?;
+
+        ds.delete_group(old.clone()).await?;
+        ds.add_group("/data".try_into()?).await?;
+        ds.add_array(array.clone(), array_metadata(vec![3])).await?;
+        let atts = UserAttributes::try_new(br#"{"units":"K"}"#)?;
+        ds.set_user_attributes(array.clone(), Some(atts.clone())).await?;
+        for (idx, data) in [&b"a"[..], b"native"].into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(Bytes::from_static(data)).await?;
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![idx as u64]),
+                Some(payload),
+            )
+            .await?;
+        }
+        ds.add_group(Repository::scratch_path()).await?;
+
+        let patch = export_patch(&ds, PatchChunks::References).await?;
+        assert_eq!(patch.base, base);
+        assert_eq!(patch.changes()[0], PatchChange::DeleteGroup(old.clone()));
+        assert_eq!(patch.changes()[1], PatchChange::AddGroup("/data".try_into()?));
+        assert_eq!(patch.changes().len(), 6);
+
+        // to another branch of the repository
+        let patch = Patch::from_bytes(&patch.to_bytes()?)?;
+        let mut other = Repository::from_branch_tip(storage, "main").await?.build();
+        apply_patch(&mut other, &patch).await?;
+        assert!(other.get_node(&old).await.is_err());
+        assert!(other.get_node(&Repository::scratch_path()).await.is_err());
+        assert_eq!(other.get_user_attributes(&array).await?, Some(atts.clone()));
+        assert_eq!(chunk(&other, &array, 1).await, Some("native".into()));
+
+        // to another repository, with the chunk data
+        let patch = export_patch(&ds, PatchChunks::Data).await?;
+        let Some(PatchChange::SetChunk(_, _, Some(PatchChunk::Data(data)))) =
+            patch.changes().last()
+        else {
+            panic!("the native chunk is copied");
+        };
+        assert_eq!(data, "native");
+        let mut copy = Repository::init(new_in_memory_storage(), false).await?.build();
+        copy.add_group(Path::root()).await?;
+        copy.add_group(old.clone()).await?;
+        apply_patch(&mut copy, &patch).await?;
+        assert_eq!(chunk(&copy, &array, 0).await, Some("a".into()));
+        assert_eq!(chunk(&copy, &array, 1).await, Some("native".into()));
+        assert_eq!(chunk(&copy, &array, 2).await, None);
+        copy.commit("main", "patched", None).await?;
+
+        // the changes must fit the session
+        assert!(matches!(
+            apply_patch(&mut copy, &patch).await,
+            Err(RepositoryError::AlreadyExists { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_patch_keeps_chunk_transformers() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_chunk_transformer(XorTransformer(42))
+            .build();
+        let array: Path = "/array".try_into()?;
+        ds.add_array(array.clone(), array_metadata(vec![1])).await?;
+        ds.commit("main", "base", None).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+
+        let patch = export_patch(&ds, PatchChunks::References).await?;
+        let Some(PatchChange::SetChunk(_, _, Some(PatchChunk::Payload(referenced)))) =
+            patch.changes().last()
+        else {
+            panic!("the chunk is referenced");
+        };
+        assert_eq!(referenced.transformers, ["xor"]);
+
+        let patch = Patch::from_bytes(&patch.to_bytes()?)?;
+        let mut other = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_chunk_transformer(XorTransformer(42))
+            .build();
+        apply_patch(&mut other, &patch).await?;
+        assert_eq!(chunk(&other, &array, 0).await, Some("hello".into()));
+        other.commit("main", "patched", None).await?;
+        assert_eq!(chunk(&other, &array, 0).await, Some("hello".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_patch_version_is_checked() -> Result<(), Box<dyn Error>> {
+        let mut ds = Repository::init(new_in_memory_storage(), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let mut patch = export_patch(&ds, PatchChunks::References).await?;
+        assert_eq!(patch.version(), PATCH_FORMAT_VERSION);
+
+        patch.version = PATCH_FORMAT_VERSION + 1;
+        assert!(matches!(
+            Patch::from_bytes(&patch.to_bytes()?),
+            Err(RepositoryError::UnsupportedPatchVersion { found, .. })
+                if found == PATCH_FORMAT_VERSION + 1
+        ));
+        assert!(matches!(
+            apply_patch(&mut ds, &patch).await,
+            Err(RepositoryError::UnsupportedPatchVersion { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! A release workflow: check the tip of a branch and freeze it in a tag
+//!
+//! [`publish`] runs a [`health_report`] on the tip of the branch and refuses to publish a
+//! snapshot with missing chunks. It then records the publication, with the stats of the
+//! report, in a namespace of the refs, and creates the tag. The tag is the commit point:
+//! tags are immutable, publishing the same name twice fails, and records without a
+//! matching tag are ignored by [`fetch_publication`].
+use std::{collections::HashSet, sync::Arc};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{manifest::ChunkPayload, ByteRange, ChunkId, SnapshotId},
+    refs::{create_tag, fetch_branch_tip, fetch_tag, RefError},
+    Storage, StorageError,
+};
+
+use super::health::{health_report, ChunkCounts, HealthConfig, HealthError};
+
+pub(crate) const PUBLICATION_REF_PREFIX: &str = "publication.";
+const PUBLICATION_KEY_NAME: &str = "ref.json";
+
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("ref error {0}")]
+    Ref(#[from] RefError),
+    #[error("health check failed {0}")]
+    Health(#[from] HealthError),
+    #[error("cannot serialize the publication: `{0}`")]
+    Serialization(#[from] serde_json::Error),
+    #[error("snapshot {snapshot} is missing {missing} chunks, it cannot be published")]
+    MissingChunks { snapshot: SnapshotId, missing: usize },
+}
+
+pub type PublishResult<A> = Result<A, PublishError>;
+
+#[derive(Debug, Clone, Default)]
+pub struct PublishConfig {
+    pub health: HealthConfig,
+    /// The published chunks are copied to this storage, usually the cold tier of a
+    /// [`crate::storage::TieredStorage`], if it doesn't have them yet
+    pub cold_tier: Option<Arc<dyn Storage + Send + Sync>>,
+}
+
+impl PublishConfig {
+    pub fn with_health(mut self, health: HealthConfig) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn with_cold_tier(mut self, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        self.cold_tier = Some(storage);
+        self
+    }
+}
+
+/// The record of a published snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Publication {
+    pub tag: String,
+    pub branch: String,
+    pub snapshot: SnapshotId,
+    pub published_at: DateTime<Utc>,
+    pub arrays: usize,
+    pub groups: usize,
+    pub manifests: usize,
+    pub chunks: ChunkCounts,
+    pub history_depth: usize,
+    /// Chunks copied to [`PublishConfig::cold_tier`]
+    pub chunks_archived: usize,
+}
+
+fn publication_key(tag_name: &str) -> PublishResult<String> {
+    if tag_name.contains('/') {
+        return Err(RefError::InvalidRefName(tag_name.to_string()).into());
+    }
+    Ok(format!("{}{}/{}", PUBLICATION_REF_PREFIX, tag_name, PUBLICATION_KEY_NAME))
+}
+
+/// Check the tip of `branch`, record its stats and tag it as `tag_name`
+///
+/// Fails with [`RefError::TagAlreadyExists`] if the tag exists, and with
+/// [`PublishError::MissingChunks`] if the storage lost chunks of the snapshot. The chunks
+/// are archived before the tag is created, a failed publish can be run again.
+pub async fn publish(
+    storage: &(dyn Storage + Send + Sync),
+    branch: &str,
+    tag_name: &str,
+    config: &PublishConfig,
+) -> PublishResult<Publication> {
+    let key = publication_key(tag_name)?;
+    match fetch_tag(storage, tag_name).await {
+        Ok(_) => return Err(RefError::TagAlreadyExists(tag_name.to_string()).into()),
+        Err(RefError::RefNotFound(_)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    let snapshot = fetch_branch_tip(storage, branch).await?.snapshot;
+    let report = health_report(storage, &snapshot, &config.health).await?;
+    if report.chunks.missing > 0 {
+        return Err(PublishError::MissingChunks {
+            snapshot,
+            missing: report.chunks.missing,
+        });
+    }
+    let chunks_archived = match &config.cold_tier {
+        Some(cold) => archive_chunks(storage, cold.as_ref(), &snapshot).await?,
+        None => 0,
+    };
+
+    let publication = Publication {
+        tag: tag_name.to_string(),
+        branch: branch.to_string(),
+        snapshot: snapshot.clone(),
+        published_at: config.health.now,
+        arrays: report.arrays,
+        groups: report.groups,
+        manifests: report.manifests,
+        chunks: report.chunks,
+        history_depth: report.history_depth,
+        chunks_archived,
+    };
+    let content = serde_json::to_vec(&publication)?;
+    storage.write_ref(key.as_str(), true, Bytes::from(content)).await?;
+    create_tag(storage, tag_name, snapshot, false).await?;
+    Ok(publication)
+}
+
+/// The publication of the tag, `None` if the tag was not created by [`publish`]
+pub async fn fetch_publication(
+    storage: &(dyn Storage + Send + Sync),
+    tag_name: &str,
+) -> PublishResult<Option<Publication>> {
+    let tag = fetch_tag(storage, tag_name).await?;
+    let publication: Publication =
+        match storage.get_ref(publication_key(tag_name)?.as_str()).await {
+            Ok(data) => serde_json::from_slice(data.as_ref())?,
+            Err(StorageError::RefNotFound(..)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+    // a concurrent publish of the same name may have lost the race for the tag
+    Ok((publication.snapshot == tag.snapshot).then_some(publication))
+}
+
+/// Copy the native chunks of the snapshot that `cold` doesn't have
+async fn archive_chunks(
+    storage: &(dyn Storage + Send + Sync),
+    cold: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> PublishResult<usize> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let mut chunks: HashSet<ChunkId> = HashSet::new();
+    for manifest_file in snapshot.manifest_files.iter() {
+        let manifest = storage.fetch_manifests(&manifest_file.id).await?;
+        chunks.extend(manifest.chunks().values().filter_map(|payload| match payload {
+            ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+            _ => None,
+        }));
+    }
+    let archived: HashSet<ChunkId> =
+        cold.list_chunks().await?.map_ok(|info| info.id).try_collect().await?;
+    let mut copied = 0;
+    for id in chunks.difference(&archived) {
+        let bytes = storage.fetch_chunk(id, &ByteRange::ALL).await?;
+        cold.write_chunk(id.clone(), bytes).await?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use futures::{stream, StreamExt};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ChunkIndices, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        refs::{list_refs, Ref},
+        repository::ZarrArrayMetadata,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_publish() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let cold: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![1],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"1")).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        let snapshot = ds.commit("main", "release", None).await?;
+
+        let config = PublishConfig::default().with_cold_tier(Arc::clone(&cold));
+        let publication = publish(storage.as_ref(), "main", "v1", &config).await?;
+        assert_eq!(publication.snapshot, snapshot);
+        assert_eq!((publication.arrays, publication.groups), (1, 1));
+        assert_eq!(publication.chunks.native, 1);
+        assert_eq!(publication.chunks_archived, 1);
+        assert_eq!(cold.list_chunks().await?.count().await, 1);
+        assert_eq!(fetch_tag(storage.as_ref(), "v1").await?.snapshot, snapshot);
+        assert_eq!(fetch_publication(storage.as_ref(), "v1").await?, Some(publication));
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string()), Ref::Tag("v1".to_string())]
+        );
+
+        // published tags are frozen
+        assert!(matches!(
+            publish(storage.as_ref(), "main", "v1", &config).await,
+            Err(PublishError::Ref(RefError::TagAlreadyExists(name))) if name == "v1"
+        ));
+        // and snapshots with missing chunks are not published
+        let chunks: Vec<ChunkId> =
+            storage.list_chunks().await?.map_ok(|info| info.id).try_collect().await?;
+        storage.delete_chunks(stream::iter(chunks).boxed()).await?;
+        assert!(matches!(
+            publish(storage.as_ref(), "main", "v2", &config).await,
+            Err(PublishError::MissingChunks { missing: 1, .. })
+        ));
+        assert!(matches!(
+            fetch_tag(storage.as_ref(), "v2").await,
+            Err(RefError::RefNotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,831 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::{
+    format::{
+        manifest::{
+            ChunkInfo, ChunkRef, ChunkSlice, ManifestExtents, VirtualChunkRef,
+            VirtualReferenceError,
+        },
+        snapshot::{NodeData, NodeSnapshot},
+        ByteRange, ChunkId, ChunkIndices, SnapshotId,
+    },
+    repository::{ChunkPayload, Path, RepositoryError, VirtualChunkLocation},
+    storage::virtual_ref::VirtualChunkResolver,
+    Repository, Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum ReadPlanError {
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("error fetching virtual chunk {0}")]
+    VirtualReference(#[from] VirtualReferenceError),
+    #[error("error serializing read plan {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("read plans can only be created for committed snapshots")]
+    UncommittedChanges,
+    #[error("invalid read plan signature")]
+    InvalidSignature,
+    #[error("read plan expired at {0}")]
+    Expired(DateTime<Utc>),
+}
+
+pub type ReadPlanResult<A> = Result<A, ReadPlanError>;
+
+/// Where the bytes of a planned chunk can be found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkSource {
+    /// A chunk object in the repository storage
+    Stored { id: ChunkId, offset: u64, length: u64 },
+    /// The chunk bytes are included in the plan
+    Inline(Bytes),
+    /// A byte range of an object outside the repository
+    ///
+    /// With a `slice`, the range holds a region of a larger external chunk, from its first
+    /// to its last element. The chunk is gathered from it with [`ChunkSlice::extract`].
+    Virtual {
+        location: VirtualChunkLocation,
+        offset: u64,
+        length: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        slice: Option<ChunkSlice>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedChunk {
+    pub path: Path,
+    pub coords: ChunkIndices,
+    pub source: ChunkSource,
+}
+
+/// Everything needed to read a subtree of a snapshot: the nodes and where their chunks are
+///
+/// A read plan allows reading the subtree without access to the repository refs or
+/// snapshots. Combined with a [`ReadPlanSigner`], it can be handed to a client that
+/// shouldn't have access to the rest of the repository.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadPlan {
+    pub snapshot_id: SnapshotId,
+    /// The root of the subtree, every node and chunk in the plan is under this path
+    pub root: Path,
+    pub nodes: Vec<NodeSnapshot>,
+    pub chunks: Vec<PlannedChunk>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ReadPlan {
+    /// Create a plan for the subtree at `root` in the current snapshot of `repo`.
+    ///
+    /// The repository must not have uncommitted changes.
+    pub async fn new(
+        repo: &Repository,
+        root: &Path,
+        expires_at: DateTime<Utc>,
+    ) -> ReadPlanResult<Self> {
+        if repo.has_uncommitted_changes() {
+            return Err(ReadPlanError::UncommittedChanges);
+        }
+        let nodes: Vec<_> =
+            repo.list_nodes().await?.filter(|node| node.path.starts_with(root)).collect();
+        let chunks = repo
+            .all_chunks()
+            .await?
+            .try_filter(|(path, _)| futures::future::ready(path.starts_with(root)))
+            .map_ok(|(path, info)| PlannedChunk::new(path, info))
+            .try_collect()
+            .await?;
+
+        Ok(Self {
+            snapshot_id: repo.snapshot_id().clone(),
+            root: root.clone(),
+            nodes,
+            chunks,
+            expires_at,
+        })
+    }
+
+    /// Create a plan for the chunks of `request.arrays` its selection intersects, in the
+    /// current snapshot of `repo`
+    ///
+    /// Scheduling the plan coalesces and deduplicates the requests of all the arrays, instead
+    /// of each array on its own. The repository must not have uncommitted changes.
+    pub async fn for_arrays(
+        repo: &Repository,
+        request: &MultiArrayRequest,
+        expires_at: DateTime<Utc>,
+    ) -> ReadPlanResult<Self> {
+        if repo.has_uncommitted_changes() {
+            return Err(ReadPlanError::UncommittedChanges);
+        }
+        let mut nodes: Vec<NodeSnapshot> = Vec::new();
+        let mut chunks = Vec::new();
+        for path in request.arrays.iter() {
+            if nodes.iter().any(|node| &node.path == path) {
+                continue;
+            }
+            let node = repo.get_array(path).await?;
+            // only the chunks in the selection are built from the manifests
+            let (from, to) = request
+                .chunk_ranges(&node)
+                .into_iter()
+                .map(|range| {
+                    range.map_or((0, u64::MAX), |range| (range.start, range.end))
+                })
+                .unzip();
+            let region = ManifestExtents::new(ChunkIndices(from), ChunkIndices(to));
+            for (coord, payload) in repo.chunk_refs_in_region(path, &region).await? {
+                let info = ChunkInfo { node: node.id.clone(), coord, payload };
+                chunks.push(PlannedChunk::new(path.clone(), info));
+            }
+            nodes.push(node);
+        }
+
+        Ok(Self {
+            snapshot_id: repo.snapshot_id().clone(),
+            root: Path::root(),
+            nodes,
+            chunks,
+            expires_at,
+        })
+    }
+
+    pub fn get_chunk(&self, path: &Path, coords: &ChunkIndices) -> Option<&PlannedChunk> {
+        self.chunks.iter().find(|chunk| &chunk.path == path && &chunk.coords == coords)
+    }
+}
+
+/// Fetch the bytes of a chunk in a [`ReadPlan`].
+///
+/// `storage` only needs read access to the chunk objects, and `virtual_resolver` is used
+/// only for virtual chunks.
+pub async fn fetch_planned_chunk(
+    storage: &(dyn Storage + Send + Sync),
+    virtual_resolver: &(dyn VirtualChunkResolver + Send + Sync),
+    chunk: &PlannedChunk,
+) -> ReadPlanResult<Bytes> {
+    match &chunk.source {
+        ChunkSource::Inline(bytes) => Ok(bytes.clone()),
+        ChunkSource::Stored { id, offset, length } => Ok(storage
+            .fetch_chunk(id, &ByteRange::from_offset_with_length(*offset, *length))
+            .await?),
+        ChunkSource::Virtual { location, offset, length, slice } => {
+            let bytes = virtual_resolver
+                .fetch_chunk(
+                    location,
+                    &ByteRange::from_offset_with_length(*offset, *length),
+                )
+                .await?;
+            gather_slice(slice.as_ref(), bytes)
+        }
+    }
+}
+
+fn gather_slice(slice: Option<&ChunkSlice>, bytes: Bytes) -> ReadPlanResult<Bytes> {
+    match slice {
+        Some(slice) => {
+            Ok(slice.extract(&bytes).map_err(VirtualReferenceError::InvalidSlice)?)
+        }
+        None => Ok(bytes),
+    }
+}
+
+impl PlannedChunk {
+    fn new(path: Path, info: ChunkInfo) -> Self {
+        let source = match info.payload {
+            ChunkPayload::Inline(bytes) => ChunkSource::Inline(bytes),
+            ChunkPayload::Ref(ChunkRef { id, offset, length }) => {
+                ChunkSource::Stored { id, offset, length }
+            }
+            ChunkPayload::Virtual(VirtualChunkRef {
+                location,
+                offset,
+                length,
+                slice,
+            }) => {
+                // only the bytes of the region are planned
+                let (offset, length) = match &slice {
+                    Some(slice) => {
+                        let span = slice.byte_span();
+                        (offset + span.start, span.end - span.start)
+                    }
+                    None => (offset, length),
+                };
+                ChunkSource::Virtual { location, offset, length, slice }
+            }
+        };
+        PlannedChunk { path, coords: info.coord, source }
+    }
+
+    /// The size of the chunk in bytes, as recorded in the manifest
+    pub fn size(&self) -> u64 {
+        match &self.source {
+            ChunkSource::Inline(bytes) => bytes.len() as u64,
+            ChunkSource::Stored { length, .. } | ChunkSource::Virtual { length, .. } => {
+                *length
+            }
+        }
+    }
+}
+
+/// The same selection of many arrays, like the variables of an xarray dataset
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MultiArrayRequest {
+    pub arrays: Vec<Path>,
+    /// Element index ranges by dimension name, dimensions not selected are read whole
+    pub selection: BTreeMap<String, Range<u64>>,
+}
+
+impl MultiArrayRequest {
+    pub fn new(arrays: impl IntoIterator<Item = Path>) -> Self {
+        Self { arrays: arrays.into_iter().collect(), selection: BTreeMap::new() }
+    }
+
+    pub fn with_range(mut self, dimension: impl Into<String>, range: Range<u64>) -> Self {
+        self.selection.insert(dimension.into(), range);
+        self
+    }
+
+    /// The chunk indices the selection intersects in each dimension of `node`, `None` for
+    /// the dimensions read whole
+    fn chunk_ranges(&self, node: &NodeSnapshot) -> Vec<Option<Range<u64>>> {
+        let NodeData::Array(meta, _) = &node.node_data else { return Vec::new() };
+        meta.chunk_shape
+            .0
+            .iter()
+            .enumerate()
+            .map(|(dim, chunk_size)| {
+                let name = meta.dimension_names.as_ref()?.get(dim)?.as_ref()?;
+                let range = self.selection.get(name)?;
+                let size = chunk_size.get();
+                let start = range.start / size;
+                let end = range.end.div_ceil(size).max(start);
+                Some(start..end)
+            })
+            .collect()
+    }
+}
+
+/// How [`ReadPlan::schedule`] groups and orders fetches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleConfig {
+    /// Chunks smaller than this are coalesced with their neighbours in the same object
+    pub coalesce_below_bytes: u64,
+    /// Maximum number of unrequested bytes between two coalesced chunks
+    pub max_gap_bytes: u64,
+    pub max_coalesced_bytes: u64,
+    /// How many fetches run in parallel
+    pub concurrency: usize,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            coalesce_below_bytes: 1024 * 1024,
+            max_gap_bytes: 64 * 1024,
+            max_coalesced_bytes: 16 * 1024 * 1024,
+            concurrency: 10,
+        }
+    }
+}
+
+/// An object that a scheduled fetch reads from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FetchTarget {
+    Stored(ChunkId),
+    Virtual(VirtualChunkLocation),
+}
+
+/// A chunk delivered by a [`ScheduledFetch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchedChunk {
+    /// The index of the chunk in [`ReadPlan::chunks`]
+    pub chunk: usize,
+    /// Where the chunk starts, relative to the fetched range
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A single request, for one chunk or several coalesced chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledFetch {
+    pub target: FetchTarget,
+    pub offset: u64,
+    pub length: u64,
+    pub chunks: Vec<FetchedChunk>,
+}
+
+/// The fetches needed to read every chunk of a [`ReadPlan`], biggest first
+///
+/// Starting the biggest fetches first keeps the slowest requests from being the last to
+/// start. Inline chunks need no fetch, and chunks with the same bytes share a fetch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchSchedule {
+    pub fetches: Vec<ScheduledFetch>,
+    pub concurrency: usize,
+}
+
+impl ReadPlan {
+    /// Plan the requests needed to read all chunks, using the chunk sizes in the plan
+    pub fn schedule(&self, config: &ScheduleConfig) -> FetchSchedule {
+        let mut fetches: Vec<ScheduledFetch> = Vec::new();
+        let mut big: HashMap<(FetchTarget, u64, u64), usize> = HashMap::new();
+        let mut small: HashMap<FetchTarget, Vec<(usize, u64, u64)>> = HashMap::new();
+        for (idx, chunk) in self.chunks.iter().enumerate() {
+            let (target, offset, length) = match &chunk.source {
+                ChunkSource::Inline(_) => continue,
+                ChunkSource::Stored { id, offset, length } => {
+                    (FetchTarget::Stored(id.clone()), *offset, *length)
+                }
+                ChunkSource::Virtual { location, offset, length, .. } => {
+                    (FetchTarget::Virtual(location.clone()), *offset, *length)
+                }
+            };
+            if length >= config.coalesce_below_bytes {
+                let fetched = FetchedChunk { chunk: idx, offset: 0, length };
+                match big.get(&(target.clone(), offset, length)) {
+                    Some(existing) => fetches[*existing].chunks.push(fetched),
+                    None => {
+                        big.insert((target.clone(), offset, length), fetches.len());
+                        fetches.push(ScheduledFetch {
+                            target,
+                            offset,
+                            length,
+                            chunks: vec![fetched],
+                        });
+                    }
+                }
+            } else {
+                small.entry(target).or_default().push((idx, offset, length));
+            }
+        }
+
+        for (target, mut chunks) in small {
+            chunks.sort_by_key(|(_, offset, _)| *offset);
+            let mut current: Option<ScheduledFetch> = None;
+            for (idx, offset, length) in chunks {
+                if let Some(fetch) = current.as_mut() {
+                    let end = fetch.offset + fetch.length;
+                    let new_end = end.max(offset + length);
+                    if offset <= end + config.max_gap_bytes
+                        && new_end - fetch.offset <= config.max_coalesced_bytes
+                    {
+                        fetch.length = new_end - fetch.offset;
+                        fetch.chunks.push(FetchedChunk {
+                            chunk: idx,
+                            offset: offset - fetch.offset,
+                            length,
+                        });
+                        continue;
+                    }
+                }
+                fetches.extend(current.replace(ScheduledFetch {
+                    target: target.clone(),
+                    offset,
+                    length,
+                    chunks: vec![FetchedChunk { chunk: idx, offset: 0, length }],
+                }));
+            }
+            fetches.extend(current);
+        }
+
+        fetches.sort_by_key(|fetch| Reverse(fetch.length));
+        FetchSchedule { fetches, concurrency: config.concurrency.max(1) }
+    }
+}
+
+/// Fetch every chunk of `plan` following `schedule`
+///
+/// Returns the bytes of each chunk, in the order of [`ReadPlan::chunks`].
+pub async fn fetch_scheduled(
+    storage: &(dyn Storage + Send + Sync),
+    virtual_resolver: &(dyn VirtualChunkResolver + Send + Sync),
+    plan: &ReadPlan,
+    schedule: &FetchSchedule,
+) -> ReadPlanResult<Vec<Bytes>> {
+    let mut res: Vec<Bytes> = plan
+        .chunks
+        .iter()
+        .map(|chunk| match &chunk.source {
+            ChunkSource::Inline(bytes) => bytes.clone(),
+            _ => Bytes::new(),
+        })
+        .collect();
+    let mut fetched = futures::stream::iter(schedule.fetches.iter())
+        .map(|fetch| async move {
+            let range = ByteRange::from_offset_with_length(fetch.offset, fetch.length);
+            let bytes = match &fetch.target {
+                FetchTarget::Stored(id) => storage.fetch_chunk(id, &range).await?,
+                FetchTarget::Virtual(location) => {
+                    virtual_resolver.fetch_chunk(location, &range).await?
+                }
+            };
+            Ok::<_, ReadPlanError>((fetch, bytes))
+        })
+        .buffer_unordered(schedule.concurrency);
+    while let Some((fetch, bytes)) = fetched.try_next().await? {
+        for chunk in fetch.chunks.iter() {
+            let start = chunk.offset as usize;
+            let end = start + chunk.length as usize;
+            if end > bytes.len() {
+                return Err(StorageError::Other(format!(
+                    "expected {} bytes, got {}",
+                    fetch.length,
+                    bytes.len()
+                ))
+                .into());
+            }
+            let slice = match &plan.chunks[chunk.chunk].source {
+                ChunkSource::Virtual { slice, .. } => slice.as_ref(),
+                _ => None,
+            };
+            res[chunk.chunk] = gather_slice(slice, bytes.slice(start..end))?;
+        }
+    }
+    Ok(res)
+}
+
+/// A [`ReadPlan`] serialized to JSON, together with its signature
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedReadPlan {
+    pub payload: String,
+    #[serde_as(as = "Hex")]
+    pub signature: Vec<u8>,
+}
+
+/// Signs and verifies read plans with HMAC-SHA256, using a secret key
+pub struct ReadPlanSigner {
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for ReadPlanSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never print the secret key
+        f.debug_struct("ReadPlanSigner").finish_non_exhaustive()
+    }
+}
+
+impl ReadPlanSigner {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length
+        #[allow(clippy::expect_used)]
+        Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC can take keys of any size")
+    }
+
+    pub fn sign(&self, plan: &ReadPlan) -> ReadPlanResult<SignedReadPlan> {
+        let payload = serde_json::to_string(plan)?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        Ok(SignedReadPlan { payload, signature: mac.finalize().into_bytes().to_vec() })
+    }
+
+    /// Verify the signature and expiration time of the plan, and return it
+    pub fn verify(&self, signed: &SignedReadPlan) -> ReadPlanResult<ReadPlan> {
+        let mut mac = self.mac();
+        mac.update(signed.payload.as_bytes());
+        mac.verify_slice(&signed.signature)
+            .map_err(|_| ReadPlanError::InvalidSignature)?;
+
+        let plan: ReadPlan = serde_json::from_str(&signed.payload)?;
+        if plan.expires_at <= Utc::now() {
+            return Err(ReadPlanError::Expired(plan.expires_at));
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use chrono::TimeDelta;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::{get_chunk, ZarrArrayMetadata},
+        storage::virtual_ref::ObjectStoreVirtualChunkResolver,
+        ObjectStorage,
+    };
+
+    async fn mk_repo() -> Result<Repository, Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(storage, false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .build();
+        let meta = ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::UInt8,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        repo.add_group(Path::root()).await?;
+        for group in ["/a", "/b"] {
+            repo.add_group(group.try_into()?).await?;
+            let array: Path = format!("{group}/array").try_into()?;
+            repo.add_array(array.clone(), meta.clone()).await?;
+            for (idx, data) in ["x", "long chunk"].into_iter().enumerate() {
+                let payload = repo.get_chunk_writer()(data.into()).await?;
+                repo.set_chunk_ref(
+                    array.clone(),
+                    ChunkIndices(vec![idx as u64]),
+                    Some(payload),
+                )
+                .await?;
+            }
+        }
+        repo.commit("main", "create", None).await?;
+        Ok(repo)
+    }
+
+    #[tokio::test]
+    async fn test_read_plan_for_subtree() -> Result<(), Box<dyn Error>> {
+        let repo = mk_repo().await?;
+        let root: Path = "/a".try_into()?;
+        let plan = ReadPlan::new(&repo, &root, Utc::now() + TimeDelta::hours(1)).await?;
+
+        let mut paths: Vec<_> =
+            plan.nodes.iter().map(|node| node.path.to_string()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/a", "/a/array"]);
+        assert_eq!(plan.chunks.len(), 2);
+
+        let array: Path = "/a/array".try_into()?;
+        let resolver = ObjectStoreVirtualChunkResolver::new(None);
+        for idx in 0..2 {
+            let coords = ChunkIndices(vec![idx]);
+            let chunk = plan.get_chunk(&array, &coords).unwrap();
+            let expected =
+                get_chunk(repo.get_chunk_reader(&array, &coords, &ByteRange::ALL).await?)
+                    .await?
+                    .unwrap();
+            let actual =
+                fetch_planned_chunk(repo.storage().as_ref(), &resolver, chunk).await?;
+            assert_eq!(actual, expected);
+        }
+        assert!(
+            matches!(plan.chunks[0].source, ChunkSource::Stored { .. })
+                || matches!(plan.chunks[1].source, ChunkSource::Stored { .. })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_schedule() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let (packed, big) = (ChunkId::random(), ChunkId::random());
+        storage
+            .write_chunk(packed.clone(), Bytes::from_static(b"0123456789abcdef"))
+            .await?;
+        storage.write_chunk(big.clone(), Bytes::from(vec![1; 100])).await?;
+        let array: Path = "/array".try_into()?;
+        let chunk = |idx: u64, source: ChunkSource| PlannedChunk {
+            path: array.clone(),
+            coords: ChunkIndices(vec![idx]),
+            source,
+        };
+        let stored = |id: &ChunkId, offset, length| ChunkSource::Stored {
+            id: id.clone(),
+            offset,
+            length,
+        };
+        let plan = ReadPlan {
+            snapshot_id: SnapshotId::random(),
+            root: Path::root(),
+            nodes: vec![],
+            chunks: vec![
+                chunk(0, stored(&packed, 12, 4)),
+                chunk(1, stored(&packed, 0, 4)),
+                chunk(2, ChunkSource::Inline(Bytes::from_static(b"inline"))),
+                chunk(3, stored(&big, 0, 100)),
+                chunk(4, stored(&packed, 4, 4)),
+            ],
+            expires_at: Utc::now(),
+        };
+        assert_eq!(plan.chunks.iter().map(|c| c.size()).sum::<u64>(), 118);
+
+        let config = ScheduleConfig {
+            coalesce_below_bytes: 50,
+            max_gap_bytes: 4,
+            ..ScheduleConfig::default()
+        };
+        let schedule = plan.schedule(&config);
+        let ranges: Vec<_> = schedule
+            .fetches
+            .iter()
+            .map(|f| (f.target.clone(), f.offset, f.length))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (FetchTarget::Stored(big.clone()), 0, 100),
+                (FetchTarget::Stored(packed.clone()), 0, 16),
+            ]
+        );
+        assert_eq!(
+            schedule.fetches[1].chunks.iter().map(|c| c.chunk).collect::<Vec<_>>(),
+            vec![1, 4, 0]
+        );
+        // without a gap allowance, the last chunk is fetched separately
+        let schedule_no_gap =
+            plan.schedule(&ScheduleConfig { max_gap_bytes: 0, ..config });
+        assert_eq!(schedule_no_gap.fetches.len(), 3);
+
+        let resolver = ObjectStoreVirtualChunkResolver::new(None);
+        for schedule in [schedule, schedule_no_gap] {
+            let bytes =
+                fetch_scheduled(storage.as_ref(), &resolver, &plan, &schedule).await?;
+            assert_eq!(
+                bytes,
+                vec![
+                    Bytes::from_static(b"cdef"),
+                    Bytes::from_static(b"0123"),
+                    Bytes::from_static(b"inline"),
+                    Bytes::from(vec![1; 100]),
+                    Bytes::from_static(b"4567"),
+                ]
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multi_array_plan() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        repo.add_group(Path::root()).await?;
+        let meta =
+            |shape: Vec<u64>, chunks: Vec<u64>, dims: Vec<&str>| ZarrArrayMetadata {
+                shape,
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(
+                    chunks.into_iter().filter_map(NonZeroU64::new).collect(),
+                ),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: Some(
+                    dims.into_iter().map(|d| Some(d.to_string())).collect(),
+                ),
+                chunk_transformers: None,
+                grid_origin: None,
+            };
+        let (temperature, salinity, time): (Path, Path, Path) =
+            ("/temperature".try_into()?, "/salinity".try_into()?, "/time".try_into()?);
+        for path in [&temperature, &salinity] {
+            repo.add_array(path.clone(), meta(vec![4, 4], vec![1, 2], vec!["time", "x"]))
+                .await?;
+        }
+        repo.add_array(time.clone(), meta(vec![4], vec![2], vec!["time"])).await?;
+        for t in 0..4 {
+            for x in 0..2 {
+                let coords = ChunkIndices(vec![t, x]);
+                let payload =
+                    repo.get_chunk_writer()(Bytes::from(vec![t as u8; 2])).await?;
+                repo.set_chunk_ref(
+                    temperature.clone(),
+                    coords.clone(),
+                    Some(payload.clone()),
+                )
+                .await?;
+                // the same object, referenced by both arrays
+                repo.set_chunk_ref(salinity.clone(), coords, Some(payload)).await?;
+            }
+        }
+        for t in 0..2 {
+            let payload = repo.get_chunk_writer()(Bytes::from(vec![t as u8; 2])).await?;
+            repo.set_chunk_ref(time.clone(), ChunkIndices(vec![t]), Some(payload))
+                .await?;
+        }
+        repo.commit("main", "create", None).await?;
+
+        let request = MultiArrayRequest::new([
+            temperature.clone(),
+            salinity.clone(),
+            time.clone(),
+            temperature.clone(),
+        ])
+        .with_range("time", 1..3);
+        let plan =
+            ReadPlan::for_arrays(&repo, &request, Utc::now() + TimeDelta::hours(1))
+                .await?;
+        assert_eq!(plan.nodes.len(), 3);
+        let mut selected: Vec<_> = plan
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.path.to_string(), chunk.coords.0.clone()))
+            .collect();
+        selected.sort();
+        assert_eq!(
+            selected,
+            vec![
+                ("/salinity".to_string(), vec![1, 0]),
+                ("/salinity".to_string(), vec![1, 1]),
+                ("/salinity".to_string(), vec![2, 0]),
+                ("/salinity".to_string(), vec![2, 1]),
+                ("/temperature".to_string(), vec![1, 0]),
+                ("/temperature".to_string(), vec![1, 1]),
+                ("/temperature".to_string(), vec![2, 0]),
+                ("/temperature".to_string(), vec![2, 1]),
+                ("/time".to_string(), vec![0]),
+                ("/time".to_string(), vec![1]),
+            ]
+        );
+
+        // the objects shared by the arrays are fetched once
+        let config =
+            ScheduleConfig { coalesce_below_bytes: 0, ..ScheduleConfig::default() };
+        let schedule = plan.schedule(&config);
+        assert_eq!(schedule.fetches.len(), 6);
+        let resolver = ObjectStoreVirtualChunkResolver::new(None);
+        let bytes =
+            fetch_scheduled(storage.as_ref(), &resolver, &plan, &schedule).await?;
+        for (chunk, bytes) in plan.chunks.iter().zip(bytes) {
+            let expected = get_chunk(
+                repo.get_chunk_reader(&chunk.path, &chunk.coords, &ByteRange::ALL)
+                    .await?,
+            )
+            .await?;
+            assert_eq!(Some(bytes), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_plan_requires_committed_snapshot() -> Result<(), Box<dyn Error>> {
+        let mut repo = mk_repo().await?;
+        repo.add_group("/c".try_into()?).await?;
+        assert!(matches!(
+            ReadPlan::new(&repo, &Path::root(), Utc::now()).await,
+            Err(ReadPlanError::UncommittedChanges)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signed_read_plans() -> Result<(), Box<dyn Error>> {
+        let repo = mk_repo().await?;
+        let root: Path = "/b".try_into()?;
+        let plan = ReadPlan::new(&repo, &root, Utc::now() + TimeDelta::hours(1)).await?;
+        let signer = ReadPlanSigner::new("secret");
+        let signed = signer.sign(&plan)?;
+
+        // signed plans can travel as JSON
+        let signed: SignedReadPlan =
+            serde_json::from_str(&serde_json::to_string(&signed)?)?;
+        assert_eq!(signer.verify(&signed)?, plan);
+
+        assert!(matches!(
+            ReadPlanSigner::new("other secret").verify(&signed),
+            Err(ReadPlanError::InvalidSignature)
+        ));
+        let tampered = SignedReadPlan {
+            payload: signed.payload.replace("/b", "/a"),
+            ..signed.clone()
+        };
+        assert!(matches!(signer.verify(&tampered), Err(ReadPlanError::InvalidSignature)));
+
+        let expired = ReadPlan { expires_at: Utc::now() - TimeDelta::seconds(1), ..plan };
+        assert!(matches!(
+            signer.verify(&signer.sign(&expired)?),
+            Err(ReadPlanError::Expired(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,657 @@
+//! A table of how many live manifests reference each chunk, kept in a namespace of the refs
+//!
+//! With the table, [`super::gc::garbage_collect`] finds the unreferenced chunks from the
+//! table alone, instead of reading every manifest of every live snapshot. The table tracks
+//! a set of snapshots, it counts the tracked snapshots that use each manifest, and the
+//! manifests in use that reference each chunk. Manifests are only read when they start or
+//! stop being used.
+//!
+//! [`RefCountHook`] adds each new commit, and [`super::retention::apply_retention`] removes
+//! the snapshots it expires. Updates are not atomic with the commits, before collecting,
+//! the garbage collector reconciles the table with the live snapshots, so a missed commit
+//! only costs the manifests it has to read.
+//!
+//! Every update writes a new version of the table, only if no other writer took that
+//! version first, see [`update_refcounts`]. Concurrent hooks, retention and collections
+//! retry from the latest table instead of overwriting each other.
+//!
+//! The counts of the chunks are split in shards by the first byte of their id, each shard
+//! its own object. The versioned object only has the snapshots, the manifests, and the key
+//! of each shard. An update reads and writes the shards of the chunks it changes, a commit
+//! adding a manifest of a few chunks doesn't rewrite the counts of the whole repository.
+//! Shards are immutable, a new version of the table writes new shards, and the shards it
+//! replaces are deleted once it's written.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    error::Error,
+    future::Future,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk_dictionary::dictionary_chunks,
+    commit_hooks::{CommitHook, PendingHooks},
+    format::{ChunkId, ManifestId, SnapshotId},
+    refs::RefResult,
+    repository::ChunkPayload,
+    storage::REF_PREFIX,
+    Storage, StorageError,
+};
+
+const REFCOUNT_NAME: &str = "refcount.chunks";
+const SHARD_NAME_PREFIX: &str = "refcount.shard.";
+
+/// Writers losing the race for a version more times than this give up
+const MAX_UPDATE_ATTEMPTS: usize = 10;
+
+type Shard = HashMap<ChunkId, u32>;
+
+/// The versioned object of the table
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredTable {
+    snapshots: BTreeMap<SnapshotId, BTreeSet<ManifestId>>,
+    manifests: HashMap<ManifestId, u32>,
+    #[serde(default)]
+    shards: BTreeMap<u8, String>,
+    /// The counts of tables written before they were sharded
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    chunks: Shard,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefCountTable {
+    /// The tracked snapshots, with their manifests
+    snapshots: BTreeMap<SnapshotId, BTreeSet<ManifestId>>,
+    /// Tracked snapshots using each manifest
+    manifests: HashMap<ManifestId, u32>,
+    /// The key of each stored shard
+    shards: BTreeMap<u8, String>,
+    /// Manifests in use referencing each chunk, of the shards read so far
+    chunks: BTreeMap<u8, Shard>,
+    /// The shards changed since the table was read
+    changed: BTreeSet<u8>,
+}
+
+/// The changes of [`RefCountTable::reconcile`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    pub snapshots_added: usize,
+    pub snapshots_removed: usize,
+}
+
+fn shard_of(chunk: &ChunkId) -> u8 {
+    chunk.0[0]
+}
+
+impl RefCountTable {
+    fn from_stored(stored: StoredTable) -> Self {
+        let mut table = RefCountTable {
+            snapshots: stored.snapshots,
+            manifests: stored.manifests,
+            shards: stored.shards,
+            ..Default::default()
+        };
+        // the next update writes them in shards
+        for (chunk, count) in stored.chunks {
+            let shard = shard_of(&chunk);
+            table.chunks.entry(shard).or_default().insert(chunk, count);
+            table.changed.insert(shard);
+        }
+        table
+    }
+
+    pub fn is_tracked(&self, snapshot: &SnapshotId) -> bool {
+        self.snapshots.contains_key(snapshot)
+    }
+
+    pub fn snapshots(&self) -> impl Iterator<Item = &SnapshotId> {
+        self.snapshots.keys()
+    }
+
+    /// How many manifests in use reference the chunk
+    ///
+    /// Only the shards read are counted, [`fetch_refcounts`] reads all of them.
+    pub fn chunk_refs(&self, id: &ChunkId) -> u32 {
+        self.chunks
+            .get(&shard_of(id))
+            .and_then(|shard| shard.get(id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The chunks referenced by the tracked snapshots, of the shards read
+    pub fn chunks(&self) -> impl Iterator<Item = &ChunkId> {
+        self.chunks.values().flat_map(|shard| shard.keys())
+    }
+
+    async fn read_shard(
+        &mut self,
+        storage: &(dyn Storage + Send + Sync),
+        shard: u8,
+    ) -> Result<&mut Shard, StorageError> {
+        if !self.chunks.contains_key(&shard) {
+            let counts = match self.shards.get(&shard) {
+                Some(key) => rmp_serde::from_slice(storage.get_ref(key).await?.as_ref())?,
+                None => Shard::default(),
+            };
+            self.chunks.insert(shard, counts);
+        }
+        Ok(self.chunks.entry(shard).or_default())
+    }
+
+    /// Read the shards not read yet
+    async fn read_all_shards(
+        &mut self,
+        storage: &(dyn Storage + Send + Sync),
+    ) -> Result<(), StorageError> {
+        let shards: Vec<u8> = self.shards.keys().copied().collect();
+        for shard in shards {
+            self.read_shard(storage, shard).await?;
+        }
+        Ok(())
+    }
+
+    async fn change_count(
+        &mut self,
+        storage: &(dyn Storage + Send + Sync),
+        chunk: ChunkId,
+        increment: bool,
+    ) -> Result<(), StorageError> {
+        let shard = shard_of(&chunk);
+        let counts = self.read_shard(storage, shard).await?;
+        if increment {
+            *counts.entry(chunk).or_insert(0) += 1;
+        } else if let Some(count) = counts.get_mut(&chunk) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&chunk);
+            }
+        }
+        self.changed.insert(shard);
+        Ok(())
+    }
+
+    /// Start tracking `snapshot`, returns false if it was already tracked
+    pub async fn add_snapshot(
+        &mut self,
+        storage: &(dyn Storage + Send + Sync),
+        snapshot: &SnapshotId,
+    ) -> Result<bool, StorageError> {
+        if self.is_tracked(snapshot) {
+            return Ok(false);
+        }
+        let snap = storage.fetch_snapshot(snapshot).await?;
+        let manifests: BTreeSet<ManifestId> =
+            snap.manifest_files.iter().map(|mf| mf.id.clone()).collect();
+        for manifest in manifests.iter() {
+            let count = self.manifests.entry(manifest.clone()).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                for chunk in manifest_chunks(storage, manifest).await? {
+                    self.change_count(storage, chunk, true).await?;
+                }
+            }
+        }
+        self.snapshots.insert(snapshot.clone(), manifests);
+        Ok(true)
+    }
+
+    /// Stop tracking `snapshot`, returns false if it wasn't tracked
+    pub async fn remove_snapshot(
+        &mut self,
+        storage: &(dyn Storage + Send + Sync),
+        snapshot: &SnapshotId,
+    ) -> Result<bool, StorageError> {
+        let Some(manifests) = self.snapshots.remove(snapshot) else {
+            return Ok(false);
+        };
+        for manifest in manifests.iter() {
+            let Some(count) = self.manifests.get_mut(manifest) else { continue };
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            self.manifests.remove(manifest);
+            for chunk in manifest_chunks(storage, manifest).await? {
+                self.change_count(storage, chunk, false).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Track exactly the `live` snapshots
+    pub async fn reconcile(
+        &mut self,
+        storage: &(dyn Storage + Send + Sync),
+        live: &HashSet<SnapshotId>,
+    ) -> Result<Reconciliation, StorageError> {
+        let mut res = Reconciliation::default();
+        let stale: Vec<SnapshotId> =
+            self.snapshots().filter(|id| !live.contains(*id)).cloned().collect();
+        // removing first reads fewer manifests, if a new snapshot reuses them they are read
+        // again when added
+        for id in stale.iter() {
+            self.remove_snapshot(storage, id).await?;
+            res.snapshots_removed += 1;
+        }
+        for id in live.iter() {
+            if self.add_snapshot(storage, id).await? {
+                res.snapshots_added += 1;
+            }
+        }
+        Ok(res)
+    }
+
+    /// Write the changed shards for `version`, returning the keys written and replaced
+    async fn write_changed_shards(
+        &mut self,
+        storage: &(dyn Storage + Send + Sync),
+        version: u64,
+    ) -> Result<(Vec<String>, Vec<String>), StorageError> {
+        let (mut written, mut replaced) = (Vec::new(), Vec::new());
+        for shard in std::mem::take(&mut self.changed) {
+            let counts = self.chunks.get(&shard).filter(|counts| !counts.is_empty());
+            let key = match counts {
+                Some(counts) => {
+                    // writers racing for the same version don't write the same keys
+                    let key = format!(
+                        "{}{:02x}/{:020}-{}.msgpack",
+                        SHARD_NAME_PREFIX,
+                        shard,
+                        version,
+                        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+                    );
+                    let content = rmp_serde::to_vec(counts)?;
+                    storage.write_ref(&key, false, Bytes::from(content)).await?;
+                    written.push(key.clone());
+                    self.shards.insert(shard, key)
+                }
+                None => self.shards.remove(&shard),
+            };
+            replaced.extend(key);
+        }
+        Ok((written, replaced))
+    }
+
+    fn to_stored(&self) -> StoredTable {
+        StoredTable {
+            snapshots: self.snapshots.clone(),
+            manifests: self.manifests.clone(),
+            shards: self.shards.clone(),
+            chunks: Shard::default(),
+        }
+    }
+}
+
+/// The native chunks of a manifest, and the dictionaries they were compressed with, once
+/// each
+async fn manifest_chunks(
+    storage: &(dyn Storage + Send + Sync),
+    id: &ManifestId,
+) -> Result<HashSet<ChunkId>, StorageError> {
+    let manifest = storage.fetch_manifests(id).await?;
+    let dictionaries = dictionary_chunks(manifest.transformers().values().flatten());
+    Ok(manifest
+        .chunks()
+        .values()
+        .filter_map(|payload| match payload {
+            ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+            _ => None,
+        })
+        .chain(dictionaries)
+        .collect())
+}
+
+fn refcount_key(version: u64) -> String {
+    // padded, so versions sort as strings
+    format!("{}/{:020}.json", REFCOUNT_NAME, version)
+}
+
+/// The version of a key of the table, 0 for tables written before they were versioned
+fn key_version(name: &str) -> u64 {
+    name.strip_suffix(".json").and_then(|n| n.parse().ok()).unwrap_or(0)
+}
+
+/// The latest table of the repository with its version, if it maintains one
+///
+/// With `all_shards`, the counts of every chunk are read, otherwise none are.
+async fn fetch_versioned_refcounts(
+    storage: &(dyn Storage + Send + Sync),
+    all_shards: bool,
+) -> RefResult<Option<(u64, RefCountTable)>> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let versions: Vec<String> =
+            storage.ref_versions(REFCOUNT_NAME).await?.try_collect().await?;
+        let Some(latest) = versions.iter().max_by_key(|name| key_version(name)) else {
+            return Ok(None);
+        };
+        let key = format!("{}/{}", REFCOUNT_NAME, latest);
+        let res = match storage.get_ref(&key).await {
+            Ok(data) => {
+                let mut table =
+                    RefCountTable::from_stored(serde_json::from_slice(data.as_ref())?);
+                if all_shards {
+                    table.read_all_shards(storage).await.map(|_| table)
+                } else {
+                    Ok(table)
+                }
+            }
+            Err(err) => Err(err),
+        };
+        match res {
+            Ok(table) => return Ok(Some((key_version(latest), table))),
+            // a newer version replaced it, or its shards, since the listing
+            Err(StorageError::RefNotFound(..)) if attempt < MAX_UPDATE_ATTEMPTS => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The table of the repository with the counts of every chunk, if it maintains one
+pub async fn fetch_refcounts(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Option<RefCountTable>> {
+    Ok(fetch_versioned_refcounts(storage, true).await?.map(|(_, table)| table))
+}
+
+/// Apply `update` to the latest table and write it as the next version
+///
+/// `update` returns the table and whether it changed, unchanged tables are not written.
+/// Without a table, `update` gets an empty one if `create` is set, otherwise nothing is
+/// done. If another writer writes the next version first, `update` runs again on theirs.
+/// `update` gets the table without the counts of the chunks, they are read as it changes
+/// them. Returns the written table, with the counts read.
+pub async fn update_refcounts<F, Fut>(
+    storage: &(dyn Storage + Send + Sync),
+    create: bool,
+    update: F,
+) -> RefResult<Option<RefCountTable>>
+where
+    F: Fn(RefCountTable) -> Fut,
+    Fut: Future<Output = Result<(RefCountTable, bool), StorageError>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let (version, table) = match fetch_versioned_refcounts(storage, false).await? {
+            Some((version, table)) => (version, table),
+            None if create => (0, RefCountTable::default()),
+            None => return Ok(None),
+        };
+        let res = update(table).await;
+        // its shards were replaced by a newer version
+        if matches!(res, Err(StorageError::RefNotFound(..)))
+            && attempt < MAX_UPDATE_ATTEMPTS
+        {
+            continue;
+        }
+        let (mut table, changed) = res?;
+        if !changed && table.changed.is_empty() {
+            return Ok(Some(table));
+        }
+        let (written, replaced) =
+            table.write_changed_shards(storage, version + 1).await?;
+        let content = serde_json::to_vec(&table.to_stored())?;
+        let key = refcount_key(version + 1);
+        match storage.write_ref(&key, false, Bytes::from(content)).await {
+            Ok(()) => {
+                storage
+                    .delete_objects(REF_PREFIX, stream::iter(replaced).boxed())
+                    .await?;
+                delete_versions_before(storage, &key).await?;
+                return Ok(Some(table));
+            }
+            Err(StorageError::RefAlreadyExists(_)) if attempt < MAX_UPDATE_ATTEMPTS => {
+                storage.delete_objects(REF_PREFIX, stream::iter(written).boxed()).await?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+async fn delete_versions_before(
+    storage: &(dyn Storage + Send + Sync),
+    key: &str,
+) -> RefResult<()> {
+    let version = key_version(key.rsplit('/').next().unwrap_or_default());
+    let old: Vec<String> = storage
+        .ref_versions(REFCOUNT_NAME)
+        .await?
+        .try_filter(|name| futures::future::ready(key_version(name) < version))
+        .map_ok(|name| format!("{}/{}", REFCOUNT_NAME, name))
+        .try_collect()
+        .await?;
+    storage.delete_objects(REF_PREFIX, stream::iter(old).boxed()).await?;
+    Ok(())
+}
+
+/// Adds every commit to the table, creating it on the first commit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RefCountHook;
+
+#[async_trait]
+impl CommitHook for RefCountHook {
+    fn name(&self) -> &str {
+        "refcount"
+    }
+
+    async fn on_commit(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        commit: &PendingHooks,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        update_refcounts(storage, true, |mut table| async move {
+            let added = table.add_snapshot(storage, &commit.snapshot).await?;
+            Ok((table, added))
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        num::NonZeroU32,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkIndices, Path},
+        ops::{
+            gc::{garbage_collect, GCConfig},
+            retention::{apply_retention, RetentionRule},
+        },
+        refs::{list_refs, Ref},
+        repository::ManifestPartitioning,
+        test_utils::{array_metadata, new_in_memory_storage},
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn test_refcount_table() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .with_commit_hook(Arc::new(RefCountHook))
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), array_metadata(vec![1])).await?;
+        ds.commit("main", "array", None).await?;
+
+        let mut chunks = Vec::new();
+        for data in [b"1", b"2"] {
+            let payload = ds.get_chunk_writer()(Bytes::from_static(data)).await?;
+            let ChunkPayload::Ref(chunk_ref) = &payload else {
+                panic!("chunks are not inlined")
+            };
+            chunks.push(chunk_ref.id.clone());
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+            ds.commit("main", "chunk", None).await?;
+        }
+        let table = fetch_refcounts(storage.as_ref()).await?.unwrap();
+        assert_eq!(table.snapshots().count(), 3);
+        assert_eq!(table.chunk_refs(&chunks[0]), 1);
+        assert_eq!(table.chunk_refs(&chunks[1]), 1);
+        assert_eq!(list_refs(storage.as_ref()).await?, vec![Ref::Branch("main".into())]);
+
+        // the expired snapshots release their chunks
+        let now = Utc::now();
+        apply_retention(storage.as_ref(), &[RetentionRule::KeepLast(1)], now).await?;
+        let table = fetch_refcounts(storage.as_ref()).await?.unwrap();
+        assert_eq!(table.snapshots().count(), 1);
+        assert_eq!(table.chunk_refs(&chunks[0]), 0);
+        assert_eq!(table.chunk_refs(&chunks[1]), 1);
+
+        let config = GCConfig::clean_all(now, now, None).with_refcounts(true);
+        let summary = garbage_collect(storage.as_ref(), &config).await?;
+        assert_eq!(summary.chunks_deleted, 1);
+        assert!(storage.fetch_chunk(&chunks[0], &ByteRange::ALL).await.is_err());
+        assert!(storage.fetch_chunk(&chunks[1], &ByteRange::ALL).await.is_ok());
+        // the first snapshot of the repository was live but never committed with the hook
+        let table = fetch_refcounts(storage.as_ref()).await?.unwrap();
+        assert_eq!(table.snapshots().count(), 2);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_concurrent_updates() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_commit_hook(Arc::new(RefCountHook))
+            .build();
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit("main", "first", None).await?;
+        // committed without the hook
+        let mut other = Repository::update(Arc::clone(&storage), first.clone()).build();
+        other.add_group("/other".try_into()?).await?;
+        let second = other.commit("main", "second", None).await?;
+
+        // another writer adds the second snapshot while the first one is removed
+        let runs = AtomicUsize::new(0);
+        let storage = storage.as_ref();
+        let (runs, first) = (&runs, &first);
+        let second = &second;
+        let table = update_refcounts(storage, false, |mut table| async move {
+            if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                update_refcounts(storage, false, |mut table| async move {
+                    let added = table.add_snapshot(storage, second).await?;
+                    Ok((table, added))
+                })
+                .await
+                .unwrap();
+            }
+            let removed = table.remove_snapshot(storage, first).await?;
+            Ok((table, removed))
+        })
+        .await?
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(table.snapshots().collect::<Vec<_>>(), vec![second]);
+        assert_eq!(fetch_refcounts(storage).await?, Some(table));
+        // only the latest version is kept
+        assert_eq!(storage.ref_versions(REFCOUNT_NAME).await?.count().await, 1);
+        assert_eq!(key_version("ref.json"), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_updates_write_the_changed_shards() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let path: Path = "/array".try_into()?;
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .with_commit_hook(Arc::new(RefCountHook))
+            .with_manifest_partitioning(
+                path.clone(),
+                ManifestPartitioning::new(0, NonZeroU32::new(10).unwrap()),
+            )
+            .build();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), array_metadata(vec![100])).await?;
+        for index in 0..20 {
+            let payload = ds.get_chunk_writer()(Bytes::from(vec![index as u8])).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![index]), Some(payload))
+                .await?;
+        }
+        ds.commit("main", "many chunks", None).await?;
+        let shards = |storage: Arc<dyn Storage + Send + Sync>| async move {
+            let mut keys = BTreeSet::new();
+            for name in storage.ref_names().await? {
+                if name.starts_with(SHARD_NAME_PREFIX) {
+                    let versions: Vec<String> =
+                        storage.ref_versions(&name).await?.try_collect().await?;
+                    keys.extend(versions.into_iter().map(|v| format!("{name}/{v}")));
+                }
+            }
+            Ok::<_, StorageError>(keys)
+        };
+        let before = shards(Arc::clone(&storage)).await?;
+        let table = fetch_refcounts(storage.as_ref()).await?.unwrap();
+        assert_eq!(table.chunks().count(), 20);
+
+        // a commit of one chunk, in a new partition, replaces its shard and no other
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"new")).await?;
+        let ChunkPayload::Ref(chunk_ref) = &payload else {
+            panic!("chunks are not inlined")
+        };
+        let new_chunk = chunk_ref.id.clone();
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![50]), Some(payload)).await?;
+        ds.commit("main", "one chunk", None).await?;
+        let after = shards(Arc::clone(&storage)).await?;
+        assert!(before.difference(&after).count() <= 1);
+        assert_eq!(after.difference(&before).count(), 1);
+        let table = fetch_refcounts(storage.as_ref()).await?.unwrap();
+        assert_eq!(table.chunks().count(), 21);
+        assert_eq!(table.chunk_refs(&new_chunk), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unsharded_tables_are_sharded() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let chunk = ChunkId::random();
+        let stored = serde_json::json!({
+            "snapshots": {},
+            "manifests": {},
+            "chunks": {chunk.to_string(): 2},
+        });
+        storage
+            .write_ref(&refcount_key(1), false, Bytes::from(serde_json::to_vec(&stored)?))
+            .await?;
+        let table = fetch_refcounts(storage.as_ref()).await?.unwrap();
+        assert_eq!(table.chunk_refs(&chunk), 2);
+
+        // the next update writes its shard, even if it changes nothing else
+        update_refcounts(
+            storage.as_ref(),
+            false,
+            |table| async move { Ok((table, false)) },
+        )
+        .await?;
+        let stored: serde_json::Value =
+            serde_json::from_slice(storage.get_ref(&refcount_key(2)).await?.as_ref())?;
+        assert_eq!(stored.get("chunks"), None);
+        let table = fetch_refcounts(storage.as_ref()).await?.unwrap();
+        assert_eq!(table.chunk_refs(&chunk), 2);
+        Ok(())
+    }
+}
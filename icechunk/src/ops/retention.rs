@@ -0,0 +1,275 @@
+//! Expiration of old snapshots according to declarative rules
+//!
+//! [`apply_retention`] doesn't rewrite snapshots, their ids and contents never change. It
+//! records the expired snapshots in a namespace of the refs instead, see
+//! [`expired_snapshots`], and the history of a snapshot skips them: in
+//! [`crate::Repository::ancestry`], and in the snapshots [`super::gc::garbage_collect`]
+//! keeps. Once no ref, trash entry or pin points to them, garbage collection deletes the
+//! expired snapshots, and their manifests and chunks that are no longer used.
+//!
+//! The records are kept after the snapshots are deleted, they are small, and the history
+//! of the snapshots that are kept still lists the expired ones.
+use std::collections::{HashMap, HashSet};
+
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{snapshot::SnapshotMetadata, SnapshotId},
+    generation::{run_fenced, GenerationError},
+    ops::refcount::update_refcounts,
+    pins::pinned_snapshots,
+    refs::{list_ref_tips, Ref, RefError, RefResult},
+    runtime::default_runtime,
+    Storage, StorageError,
+};
+
+pub(crate) const EXPIRED_REF_PREFIX: &str = "expired.";
+const EXPIRED_KEY_NAME: &str = "ref.json";
+
+/// A reason to keep a snapshot, snapshots no rule keeps expire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionRule {
+    /// The last `n` snapshots of each branch, including the tip
+    KeepLast(usize),
+    /// The snapshots pointed to by tags
+    KeepTagged,
+    /// The newest snapshot of each day, for the last `days` days, per branch
+    KeepDaily { days: u32 },
+}
+
+#[derive(Debug, Error)]
+pub enum RetentionError {
+    #[error("ref error {0}")]
+    Ref(#[from] RefError),
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("generation error {0}")]
+    Generation(#[from] GenerationError),
+}
+
+pub type RetentionResult<A> = Result<A, RetentionError>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionSummary {
+    pub snapshots_expired: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ExpiredSnapshot {
+    expired_at: DateTime<Utc>,
+}
+
+fn expired_key(id: &SnapshotId) -> String {
+    format!("{}{}/{}", EXPIRED_REF_PREFIX, id, EXPIRED_KEY_NAME)
+}
+
+/// The snapshots expired by [`apply_retention`], histories skip them
+pub async fn expired_snapshots(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<HashSet<SnapshotId>> {
+    Ok(storage
+        .ref_names()
+        .await?
+        .iter()
+        .filter_map(|name| name.strip_prefix(EXPIRED_REF_PREFIX))
+        .filter_map(|id| SnapshotId::try_from(id).ok())
+        .collect())
+}
+
+async fn record_expired(
+    storage: &(dyn Storage + Send + Sync),
+    id: &SnapshotId,
+    now: DateTime<Utc>,
+) -> RefResult<()> {
+    let content = serde_json::to_vec(&ExpiredSnapshot { expired_at: now })?;
+    storage.write_ref(expired_key(id).as_str(), true, Bytes::from(content)).await?;
+    Ok(())
+}
+
+/// Expire the snapshots in the history of the branches that none of the `rules` keep
+///
+/// Branch tips, the first snapshot of the repository and the snapshots pinned at `now`, see
+/// [`crate::pins`], are always kept, and with no rules, nothing expires. Expired snapshots
+/// that a tag, the trash or a pin points to are kept until they no longer do, but they
+/// don't show in the history of other snapshots.
+///
+/// The expired snapshots are removed from the chunk reference counts, if the repository
+/// keeps them, see [`super::refcount`].
+///
+/// Sessions started from an expired snapshot can still commit, their new snapshot doesn't
+/// show the expired snapshot in its history either.
+///
+/// Like garbage collection, it takes a new generation, renewed while it runs, see
+/// [`crate::generation`], and fails if another maintenance operation is running.
+pub async fn apply_retention(
+    storage: &(dyn Storage + Send + Sync),
+    rules: &[RetentionRule],
+    now: DateTime<Utc>,
+) -> RetentionResult<RetentionSummary> {
+    if rules.is_empty() {
+        return Ok(RetentionSummary::default());
+    }
+    let work = expire(storage, rules, now);
+    run_fenced(storage, "retention", now, default_runtime().as_ref(), work).await
+}
+
+async fn expire(
+    storage: &(dyn Storage + Send + Sync),
+    rules: &[RetentionRule],
+    now: DateTime<Utc>,
+) -> RetentionResult<RetentionSummary> {
+    let mut candidates = HashSet::new();
+    let mut keep = HashSet::new();
+    let mut tagged = HashSet::new();
+    for (r, ref_data) in list_ref_tips(storage).await? {
+        let tip = ref_data.snapshot;
+        if let Ref::Tag(_) = r {
+            tagged.insert(tip);
+            continue;
+        }
+        let snapshot = storage.fetch_snapshot(&tip).await?;
+        let history: Vec<&SnapshotMetadata> = std::iter::once(&snapshot.metadata)
+            .chain(snapshot.short_term_history.iter())
+            .collect();
+        keep.insert(tip.clone());
+        if let Some(root) = history.last() {
+            keep.insert(root.id.clone());
+        }
+        for rule in rules {
+            keep.extend(kept_by(rule, &history, now));
+        }
+        candidates.extend(history.iter().map(|meta| meta.id.clone()));
+    }
+    if rules.contains(&RetentionRule::KeepTagged) {
+        keep.extend(tagged.iter().cloned());
+    }
+    keep.extend(pinned_snapshots(storage, now).await?);
+
+    let already_expired = expired_snapshots(storage).await?;
+    let expired: HashSet<SnapshotId> = candidates
+        .difference(&keep)
+        .filter(|id| !already_expired.contains(*id))
+        .cloned()
+        .collect();
+    let summary = RetentionSummary { snapshots_expired: expired.len() };
+    if expired.is_empty() {
+        return Ok(summary);
+    }
+    for id in expired.iter() {
+        record_expired(storage, id, now).await?;
+    }
+    let expired = &expired;
+    update_refcounts(storage, false, |mut table| async move {
+        let mut removed = false;
+        for id in expired.iter() {
+            removed |= table.remove_snapshot(storage, id).await?;
+        }
+        Ok((table, removed))
+    })
+    .await?;
+    Ok(summary)
+}
+
+/// The snapshots of a branch `rule` keeps, `history` goes from the tip to the oldest
+fn kept_by(
+    rule: &RetentionRule,
+    history: &[&SnapshotMetadata],
+    now: DateTime<Utc>,
+) -> Vec<SnapshotId> {
+    match rule {
+        RetentionRule::KeepLast(n) => {
+            history.iter().take(*n).map(|meta| meta.id.clone()).collect()
+        }
+        RetentionRule::KeepTagged => vec![],
+        RetentionRule::KeepDaily { days } => {
+            let since = now - chrono::TimeDelta::days(*days as i64);
+            let mut newest: HashMap<NaiveDate, SnapshotId> = HashMap::new();
+            for meta in history.iter().filter(|meta| meta.written_at > since) {
+                newest.entry(meta.written_at.date_naive()).or_insert(meta.id.clone());
+            }
+            newest.into_values().collect()
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use chrono::TimeDelta;
+    use futures::{StreamExt, TryStreamExt};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{snapshot::Snapshot, Path},
+        ops::gc::{garbage_collect, GCConfig},
+        sources::{Clock, Sources, VirtualClock},
+        storage::guarded::GuardedStorage,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_retention_rules() -> Result<(), Box<dyn Error>> {
+        // snapshots are never overwritten, retention doesn't rewrite them
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(GuardedStorage::new(
+            Arc::new(ObjectStorage::new_in_memory_store(None)),
+        ));
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")?.to_utc();
+        let clock = Arc::new(VirtualClock::new(start, TimeDelta::zero()));
+        let sources = Sources { clock: clock.clone(), ..Sources::default() };
+        let mut ds = Repository::init_with_sources(Arc::clone(&storage), false, sources)
+            .await?
+            .build();
+        let mut commits = vec![];
+        // three commits a day, the last one at midnight of the 4th
+        for i in 0..9 {
+            clock.advance(TimeDelta::hours(8));
+            ds.add_group(Path::root().child(&i.to_string())?).await?;
+            commits.push(ds.commit("main", &format!("commit {i}"), None).await?);
+        }
+        ds.tag("v1", &commits[1]).await?;
+
+        clock.advance(TimeDelta::hours(8));
+        let now = clock.now();
+        let rules = [
+            RetentionRule::KeepLast(2),
+            RetentionRule::KeepTagged,
+            RetentionRule::KeepDaily { days: 2 },
+        ];
+        let tip = storage.fetch_snapshot(&commits[8]).await?;
+        let summary = apply_retention(storage.as_ref(), &rules, now).await?;
+        assert_eq!(storage.fetch_snapshot(&commits[8]).await?, tip);
+        assert_eq!(expired_snapshots(storage.as_ref()).await?.len(), 5);
+
+        let ancestry: Vec<_> =
+            ds.ancestry().await?.map_ok(|meta| meta.message).try_collect().await?;
+        assert_eq!(
+            ancestry,
+            vec![
+                "commit 8",
+                "commit 7",
+                // the newest of the 2nd, in the last two days
+                "commit 4",
+                // tagged
+                "commit 1",
+                Snapshot::INITIAL_COMMIT_MESSAGE
+            ]
+        );
+        assert_eq!(summary, RetentionSummary { snapshots_expired: 5 });
+        // once expired, the snapshots can be collected
+        let gc = GCConfig::clean_all(Utc::now(), Utc::now(), None);
+        assert_eq!(garbage_collect(storage.as_ref(), &gc).await?.snapshots_deleted, 5);
+        assert_eq!(ds.ancestry().await?.count().await, 5);
+        // nothing else expires
+        assert_eq!(
+            apply_retention(storage.as_ref(), &rules, now).await?,
+            RetentionSummary::default()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+//! Create or update a whole hierarchy from a declarative document
+//!
+//! A [`HierarchySpec`] maps node paths to `zarr.json` like documents, with the node type,
+//! the user attributes and, for arrays, the Zarr metadata. [`apply_spec`] brings the session
+//! in line with it, so the whole hierarchy is written by the next commit, for example to
+//! create new repositories from a template. [`clone_structure`] does the same with the
+//! hierarchy of an existing snapshot, to start an empty dataset with the same schema.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, TryFromInto};
+use thiserror::Error;
+
+use crate::{
+    format::{
+        snapshot::{NodeData, NodeSnapshot, NodeType, Snapshot, UserAttributesSnapshot},
+        IcechunkFormatError, Path, PathError, SnapshotId,
+    },
+    metadata::UserAttributes,
+    repository::{load_user_attributes, RepositoryError, ZarrArrayMetadata},
+    zarr::ZarrArrayMetadataSerialzer,
+    Repository, Storage,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SpecError {
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("invalid spec document: {0}")]
+    InvalidDocument(#[from] serde_json::Error),
+    #[error("invalid path `{path}` in spec: {error}")]
+    InvalidPath { path: String, error: PathError },
+    #[error("path `{0}` is declared more than once")]
+    DuplicatePath(Path),
+    #[error("invalid array metadata for `{path}`: {error}")]
+    InvalidArray { path: Path, error: IcechunkFormatError },
+    #[error(
+        "`{path}` is a {existing:?} in the repository, the spec declares a {declared:?}"
+    )]
+    NodeTypeMismatch { path: Path, existing: NodeType, declared: NodeType },
+    #[error("the parent of `{0}` is not a group in the spec or the repository")]
+    MissingParent(Path),
+}
+
+pub type SpecResult<A> = Result<A, SpecError>;
+
+/// The desired state of one node
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "node_type", rename_all = "lowercase")]
+pub enum NodeSpec {
+    Group {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attributes: Option<UserAttributes>,
+    },
+    Array {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attributes: Option<UserAttributes>,
+        #[serde(flatten)]
+        #[serde_as(as = "TryFromInto<ZarrArrayMetadataSerialzer>")]
+        metadata: ZarrArrayMetadata,
+    },
+}
+
+impl NodeSpec {
+    pub fn node_type(&self) -> NodeType {
+        match self {
+            NodeSpec::Group { .. } => NodeType::Group,
+            NodeSpec::Array { .. } => NodeType::Array,
+        }
+    }
+}
+
+/// The nodes of a hierarchy, by path
+///
+/// Paths are normalized with [`Path::normalized`], `a/b` and `/a/b` are the same node.
+/// Other keys of the node documents, like `zarr_format`, are ignored, so `zarr.json`
+/// documents can be used as they are.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct HierarchySpec {
+    pub nodes: BTreeMap<String, NodeSpec>,
+}
+
+impl HierarchySpec {
+    pub fn from_json(json: &[u8]) -> SpecResult<Self> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    /// The groups and arrays of `snapshot`, with their attributes
+    ///
+    /// Attributes stored outside the snapshot are fetched from `storage`. Nodes of user
+    /// defined kinds are not included.
+    pub async fn from_snapshot(
+        storage: &(dyn Storage + Send + Sync),
+        snapshot: &Snapshot,
+    ) -> SpecResult<Self> {
+        let mut nodes = BTreeMap::new();
+        for node in snapshot.iter() {
+            let attributes =
+                load_user_attributes(storage, node.user_attributes.clone()).await?;
+            let spec = match &node.node_data {
+                NodeData::Group => NodeSpec::Group { attributes },
+                NodeData::Array(metadata, _) => {
+                    NodeSpec::Array { attributes, metadata: metadata.clone() }
+                }
+                NodeData::Custom(_) => continue,
+            };
+            nodes.insert(node.path.to_string(), spec);
+        }
+        Ok(Self { nodes })
+    }
+}
+
+/// The nodes [`apply_spec`] changed in the session
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SpecSummary {
+    pub created: Vec<Path>,
+    pub updated: Vec<Path>,
+}
+
+/// Create the nodes of `spec` missing from the session, and update the metadata and
+/// attributes of the others to match it
+///
+/// The whole spec is validated before the session is modified: paths, array metadata, node
+/// types of existing nodes, and that every parent is a group. Nodes that already match are
+/// not touched, and nodes not in the spec are left as they are. As with other
+/// [`Repository`] writes, nothing is visible to other sessions until commit.
+pub async fn apply_spec(
+    repo: &mut Repository,
+    spec: &HierarchySpec,
+) -> SpecResult<SpecSummary> {
+    let mut nodes = BTreeMap::new();
+    for (key, node) in spec.nodes.iter() {
+        let path = Path::normalized(key)
+            .map_err(|error| SpecError::InvalidPath { path: key.clone(), error })?;
+        if nodes.insert(path.clone(), node).is_some() {
+            return Err(SpecError::DuplicatePath(path));
+        }
+    }
+
+    let mut plan = Vec::with_capacity(nodes.len());
+    for (path, node) in nodes.iter() {
+        if let NodeSpec::Array { metadata, .. } = node {
+            metadata
+                .validate()
+                .map_err(|error| SpecError::InvalidArray { path: path.clone(), error })?;
+        }
+        let existing = existing_node(repo, path).await?;
+        if let Some(existing) = &existing {
+            if existing.node_type() != node.node_type() {
+                return Err(SpecError::NodeTypeMismatch {
+                    path: path.clone(),
+                    existing: existing.node_type(),
+                    declared: node.node_type(),
+                });
+            }
+        }
+        if let Some(parent) = path.parent() {
+            let parent_is_group = match nodes.get(&parent) {
+                Some(parent) => parent.node_type() == NodeType::Group,
+                None => existing_node(repo, &parent)
+                    .await?
+                    .is_some_and(|node| node.node_type() == NodeType::Group),
+            };
+            if !parent_is_group {
+                return Err(SpecError::MissingParent(path.clone()));
+            }
+        }
+        plan.push((path.clone(), *node, existing));
+    }
+
+    let mut res = SpecSummary::default();
+    // paths sort parents first
+    for (path, node, existing) in plan {
+        let (attributes, changed) = match (node, &existing) {
+            (NodeSpec::Group { attributes }, None) => {
+                repo.add_group(path.clone()).await?;
+                (attributes, true)
+            }
+            (NodeSpec::Array { attributes, metadata }, None) => {
+                repo.add_array(path.clone(), metadata.clone()).await?;
+                (attributes, true)
+            }
+            (NodeSpec::Group { attributes }, Some(_)) => (attributes, false),
+            (NodeSpec::Array { attributes, metadata }, Some(existing)) => {
+                let update = !matches!(
+                    &existing.node_data,
+                    NodeData::Array(current, _) if same_metadata(current, metadata)
+                );
+                if update {
+                    repo.update_array(path.clone(), metadata.clone()).await?;
+                }
+                (attributes, update)
+            }
+        };
+        let same_attributes = match existing.as_ref().map(|node| &node.user_attributes) {
+            // attributes stored outside the snapshot are always rewritten
+            Some(Some(UserAttributesSnapshot::Ref(_))) => false,
+            Some(Some(UserAttributesSnapshot::Inline(current))) => {
+                attributes.as_ref() == Some(current)
+            }
+            Some(None) | None => attributes.is_none(),
+        };
+        if !same_attributes {
+            repo.set_user_attributes(path.clone(), attributes.clone()).await?;
+        }
+        match existing {
+            None => res.created.push(path),
+            Some(_) if changed || !same_attributes => res.updated.push(path),
+            Some(_) => {}
+        }
+    }
+    Ok(res)
+}
+
+/// Create in `dst` the groups and arrays of `src_snapshot`, with their attributes, but
+/// none of their chunks
+///
+/// The snapshot can come from another repository, `storage` is where it's stored. This is
+/// [`apply_spec`] with the spec of the snapshot, see [`HierarchySpec::from_snapshot`].
+pub async fn clone_structure(
+    storage: &(dyn Storage + Send + Sync),
+    src_snapshot: &SnapshotId,
+    dst: &mut Repository,
+) -> SpecResult<SpecSummary> {
+    let snapshot =
+        storage.fetch_snapshot(src_snapshot).await.map_err(RepositoryError::from)?;
+    apply_spec(dst, &HierarchySpec::from_snapshot(storage, &snapshot).await?).await
+}
+
+/// Compares the Zarr representation, a NaN fill value is equal to itself
+fn same_metadata(a: &ZarrArrayMetadata, b: &ZarrArrayMetadata) -> bool {
+    let zarr = |meta: &ZarrArrayMetadata| {
+        serde_json::to_value(ZarrArrayMetadataSerialzer::from(meta.clone())).ok()
+    };
+    a == b || zarr(a).is_some_and(|a| Some(a) == zarr(b))
+}
+
+async fn existing_node(
+    repo: &Repository,
+    path: &Path,
+) -> SpecResult<Option<NodeSnapshot>> {
+    match repo.get_node(path).await {
+        Ok(node) => Ok(Some(node)),
+        Err(RepositoryError::NodeNotFound { .. }) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{format::ChunkIndices, metadata::DataType, ObjectStorage};
+
+    fn spec(value: serde_json::Value) -> HierarchySpec {
+        HierarchySpec::from_json(value.to_string().as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_spec() -> Result<(), Box<dyn Error>> {
+        let storage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo = Repository::init(storage, false).await?.build();
+        let sst = json!({
+            "zarr_format": 3,
+            "node_type": "array",
+            "attributes": {"units": "K"},
+            "shape": [720, 1440],
+            "data_type": "float32",
+            "chunk_grid": {"name": "regular", "configuration": {"chunk_shape": [90, 180]}},
+            "chunk_key_encoding": {"name": "default", "configuration": {"separator": "/"}},
+            "fill_value": "NaN",
+            "codecs": [{"name": "bytes", "configuration": {"endian": "little"}}],
+            "dimension_names": ["lat", "lon"]
+        });
+        let template = json!({"nodes": {
+            "/": {"node_type": "group", "attributes": {"title": "template"}},
+            "ocean": {"node_type": "group"},
+            "ocean/sst": sst,
+        }});
+        let summary = apply_spec(&mut repo, &spec(template.clone())).await?;
+        let paths = |paths: &[&str]| -> Vec<Path> {
+            paths.iter().map(|p| Path::try_from(*p).unwrap()).collect()
+        };
+        assert_eq!(summary.created, paths(&["/", "/ocean", "/ocean/sst"]));
+        repo.commit("main", "from template", None).await?;
+        let node = repo.get_array(&"/ocean/sst".try_into()?).await?;
+        let NodeData::Array(meta, _) = node.node_data else { panic!() };
+        assert_eq!((meta.shape, meta.data_type), (vec![720, 1440], DataType::Float32));
+
+        // applying it again changes nothing
+        assert_eq!(apply_spec(&mut repo, &spec(template)).await?, SpecSummary::default());
+        assert!(!repo.has_uncommitted_changes());
+
+        let mut resized = sst.clone();
+        resized["shape"] = json!([720, 2880]);
+        let summary = apply_spec(
+            &mut repo,
+            &spec(json!({"nodes": {"/ocean/sst": resized, "/ocean/sss": sst}})),
+        )
+        .await?;
+        assert_eq!(summary.created, paths(&["/ocean/sss"]));
+        assert_eq!(summary.updated, paths(&["/ocean/sst"]));
+
+        // invalid specs don't modify the session
+        repo.commit("main", "resize", None).await?;
+        let invalid = [
+            json!({"nodes": {"/ocean": sst}}),
+            json!({"nodes": {"/land/height": sst}}),
+            json!({"nodes": {"/ocean/sst/inner": {"node_type": "group"}}}),
+            json!({"nodes": {"ocean/new": {"node_type": "group"}, "/ocean/new": {"node_type": "group"}}}),
+        ];
+        for invalid in invalid {
+            assert!(apply_spec(&mut repo, &spec(invalid)).await.is_err());
+        }
+        assert!(!repo.has_uncommitted_changes());
+        assert!(matches!(
+            HierarchySpec::from_json(br#"{"nodes": {"/a": {"node_type": "table"}}}"#),
+            Err(SpecError::InvalidDocument(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_structure() -> Result<(), Box<dyn Error>> {
+        let src_storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        // the attributes are stored outside the snapshot
+        let mut src = Repository::init(Arc::clone(&src_storage), false)
+            .await?
+            .with_attributes_split_threshold_bytes(1)
+            .build();
+        let template = spec(json!({"nodes": {
+            "/": {"node_type": "group", "attributes": {"title": "source"}},
+            "/sst": {
+                "node_type": "array",
+                "shape": [4],
+                "data_type": "int32",
+                "chunk_grid": {"name": "regular", "configuration": {"chunk_shape": [2]}},
+                "chunk_key_encoding": {"name": "default", "configuration": {"separator": "/"}},
+                "fill_value": 0,
+                "codecs": [{"name": "bytes"}],
+                "dimension_names": ["time"]
+            }
+        }}));
+        apply_spec(&mut src, &template).await?;
+        let sst: Path = "/sst".try_into()?;
+        let data = src.get_chunk_writer()(bytes::Bytes::from_static(b"12345678")).await?;
+        src.set_chunk_ref(sst.clone(), ChunkIndices(vec![0]), Some(data)).await?;
+        let snapshot = src.commit("main", "data", None).await?;
+
+        let mut dst =
+            Repository::init(Arc::new(ObjectStorage::new_in_memory_store(None)), false)
+                .await?
+                .build();
+        let summary = clone_structure(src_storage.as_ref(), &snapshot, &mut dst).await?;
+        assert_eq!(summary.created, vec![Path::root(), sst.clone()]);
+        dst.commit("main", "structure", None).await?;
+        let metadata = |node: NodeSnapshot| match node.node_data {
+            NodeData::Array(metadata, manifests) => (metadata, manifests.len()),
+            _ => panic!("not an array"),
+        };
+        let (cloned, manifests) = metadata(dst.get_array(&sst).await?);
+        assert_eq!(cloned, metadata(src.get_array(&sst).await?).0);
+        assert_eq!(manifests, 0);
+        assert!(matches!(
+            src.get_node(&Path::root()).await?.user_attributes,
+            Some(UserAttributesSnapshot::Ref(_))
+        ));
+        assert_eq!(
+            dst.get_node(&Path::root()).await?.user_attributes,
+            Some(UserAttributesSnapshot::Inline(UserAttributes::try_new(
+                br#"{"title": "source"}"#
+            )?))
+        );
+        assert_eq!(dst.get_chunk_ref(&sst, &ChunkIndices(vec![0])).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use tokio::pin;
+
+use crate::{
+    format::ByteRange, repository::ChunkPayload, storage::TieredStorage, Storage,
+};
+
+use super::gc::{pointed_snapshots, GCError};
+
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct TieringSummary {
+    pub chunks_migrated: usize,
+    pub bytes_migrated: u64,
+}
+
+pub type TieringResult<A> = Result<A, GCError>;
+
+/// Move chunks not used by any recent snapshot to the cold tier of `storage`
+///
+/// A chunk is moved if it was created before `older_than`, and it's not referenced by any
+/// snapshot written after `older_than`, in the history of any ref. Every chunk is copied to
+/// the cold tier before removing it from the hot one, so readers never miss a chunk.
+pub async fn migrate_cold_chunks(
+    storage: &TieredStorage,
+    older_than: DateTime<Utc>,
+) -> TieringResult<TieringSummary> {
+    let no_extra_roots = HashSet::new();
+    let all_snaps = pointed_snapshots(storage, &no_extra_roots, Utc::now()).await?;
+
+    let mut hot_chunks = HashSet::new();
+    pin!(all_snaps);
+    while let Some(snap_id) = all_snaps.try_next().await? {
+        let snap = storage.fetch_snapshot(&snap_id).await?;
+        if snap.metadata.written_at < older_than {
+            continue;
+        }
+        for manifest_file in snap.manifest_files.iter() {
+            let manifest = storage.fetch_manifests(&manifest_file.id).await?;
+            hot_chunks.extend(manifest.chunks().values().filter_map(|payload| {
+                match payload {
+                    ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+                    _ => None,
+                }
+            }));
+        }
+    }
+
+    let to_migrate: Vec<_> = storage
+        .hot()
+        .list_chunks()
+        .await?
+        .try_filter(|chunk| {
+            futures::future::ready(
+                chunk.created_at < older_than && !hot_chunks.contains(&chunk.id),
+            )
+        })
+        .map_ok(|chunk| chunk.id)
+        .try_collect()
+        .await?;
+
+    let mut summary = TieringSummary::default();
+    for id in to_migrate.iter() {
+        let bytes = storage.hot().fetch_chunk(id, &ByteRange::ALL).await?;
+        summary.bytes_migrated += bytes.len() as u64;
+        storage.cold().write_chunk(id.clone(), bytes).await?;
+        summary.chunks_migrated += 1;
+    }
+    storage.hot().delete_chunks(stream::iter(to_migrate).boxed()).await?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::ChunkIndices,
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::{get_chunk, Path, ZarrArrayMetadata},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_migrate_cold_chunks() -> Result<(), Box<dyn Error>> {
+        let hot: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let cold: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = Arc::new(TieredStorage::new(Arc::clone(&hot), Arc::clone(&cold)));
+
+        let mut repo = Repository::init(Arc::clone(&storage) as Arc<_>, false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let meta = ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::UInt8,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(path.clone(), meta).await?;
+        for (idx, data) in ["old", "kept"].into_iter().enumerate() {
+            let payload = repo.get_chunk_writer()(Bytes::from(data)).await?;
+            repo.set_chunk_ref(
+                path.clone(),
+                ChunkIndices(vec![idx as u64]),
+                Some(payload),
+            )
+            .await?;
+        }
+        let old_snapshot = repo.commit("main", "first", None).await?;
+        let cutoff = Utc::now();
+
+        let payload = repo.get_chunk_writer()(Bytes::from_static(b"new")).await?;
+        repo.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        repo.commit("main", "second", None).await?;
+
+        let summary = migrate_cold_chunks(&storage, cutoff).await?;
+        assert_eq!(summary, TieringSummary { chunks_migrated: 1, bytes_migrated: 3 });
+        assert_eq!(hot.list_chunks().await?.count().await, 2);
+        assert_eq!(cold.list_chunks().await?.count().await, 1);
+
+        // the old version is still readable, from the cold tier
+        let old_repo =
+            Repository::update(Arc::clone(&storage) as Arc<_>, old_snapshot).build();
+        let coords = ChunkIndices(vec![0]);
+        let old =
+            get_chunk(old_repo.get_chunk_reader(&path, &coords, &ByteRange::ALL).await?)
+                .await?;
+        assert_eq!(old, Some(Bytes::from_static(b"old")));
+        let new =
+            get_chunk(repo.get_chunk_reader(&path, &coords, &ByteRange::ALL).await?)
+                .await?;
+        assert_eq!(new, Some(Bytes::from_static(b"new")));
+
+        // nothing else to migrate
+        let summary = migrate_cold_chunks(&storage, cutoff).await?;
+        assert_eq!(summary, TieringSummary::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! Best effort commits to several repositories together
+//!
+//! Storage cannot update refs of different repositories atomically, so
+//! [`commit_together`] commits in two phases. First it prepares every commit, see
+//! [`Repository::prepare_commit`]: the snapshots are written but no branch moves, so a
+//! failure here leaves every repository as it was. Then it finalizes the commits in order,
+//! and if one fails, it moves the branches already finalized back to their parents.
+//!
+//! Readers can see some repositories updated while the commits are finalized or rolled
+//! back, and a rollback fails if another writer committed on top of a finalized commit in
+//! between, [`TransactionError::Finalize`] lists the commits that stayed.
+use thiserror::Error;
+
+use crate::{
+    format::{snapshot::SnapshotProperties, SnapshotId},
+    repository::{PreparedCommit, RepositoryError},
+    Repository,
+};
+
+#[derive(Debug, Error)]
+pub enum TransactionError {
+    #[error("cannot prepare the commit to repository {index}, nothing was committed: {source}")]
+    Prepare {
+        index: usize,
+        #[source]
+        source: RepositoryError,
+    },
+    #[error("cannot finalize the commit to repository {index}, {} commits could not be rolled back: {source}", not_rolled_back.len())]
+    Finalize {
+        index: usize,
+        #[source]
+        source: RepositoryError,
+        /// The repositories moved back to their parent snapshot
+        rolled_back: Vec<usize>,
+        /// The repositories that kept their finalized commit, with the rollback error
+        not_rolled_back: Vec<(usize, RepositoryError)>,
+    },
+}
+
+pub type TransactionResult<A> = Result<A, TransactionError>;
+
+/// A commit of one of the repositories of a transaction
+#[derive(Debug)]
+pub struct TransactionCommit<'a> {
+    pub repository: &'a mut Repository,
+    pub branch: String,
+    pub message: String,
+    pub properties: Option<SnapshotProperties>,
+}
+
+impl<'a> TransactionCommit<'a> {
+    pub fn new(
+        repository: &'a mut Repository,
+        branch: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            repository,
+            branch: branch.into(),
+            message: message.into(),
+            properties: None,
+        }
+    }
+
+    pub fn with_properties(mut self, properties: SnapshotProperties) -> Self {
+        self.properties = Some(properties);
+        self
+    }
+}
+
+/// Commit the sessions of all `commits`, or none of them, as far as possible
+///
+/// Returns the new snapshots in the order of `commits`, see the
+/// [module documentation](self) for what happens on failures. After a failure, the
+/// sessions that were prepared continue from snapshots that are not in their branch, they
+/// must be reopened.
+pub async fn commit_together(
+    commits: Vec<TransactionCommit<'_>>,
+) -> TransactionResult<Vec<SnapshotId>> {
+    let mut prepared = Vec::with_capacity(commits.len());
+    for (index, commit) in commits.into_iter().enumerate() {
+        let TransactionCommit { repository, branch, message, properties } = commit;
+        match repository.prepare_commit(&branch, &message, properties).await {
+            Ok(commit) => prepared.push((repository, commit)),
+            // the prepared snapshots are not in any branch, garbage collection deletes them
+            Err(source) => return Err(TransactionError::Prepare { index, source }),
+        }
+    }
+
+    for index in 0..prepared.len() {
+        let (repository, commit) = &prepared[index];
+        if let Err(source) = repository.finalize_commit(commit).await {
+            // the commit hooks run after the branch moved, their failures leave it moved
+            let finalized =
+                if is_published(repository, commit).await { index + 1 } else { index };
+            let (rolled_back, not_rolled_back) = rollback(&prepared[..finalized]).await;
+            return Err(TransactionError::Finalize {
+                index,
+                source,
+                rolled_back,
+                not_rolled_back,
+            });
+        }
+    }
+    Ok(prepared.into_iter().map(|(_, commit)| commit.snapshot).collect())
+}
+
+/// The branch of `commit` points to its snapshot
+async fn is_published(repository: &Repository, commit: &PreparedCommit) -> bool {
+    matches!(
+        repository.branch_tip_versioned(&commit.branch).await,
+        Ok((_, tip)) if tip == commit.snapshot
+    )
+}
+
+/// Roll back the finalized `commits`, latest first
+async fn rollback(
+    commits: &[(&mut Repository, PreparedCommit)],
+) -> (Vec<usize>, Vec<(usize, RepositoryError)>) {
+    let mut rolled_back = Vec::new();
+    let mut not_rolled_back = Vec::new();
+    for (index, (repository, commit)) in commits.iter().enumerate().rev() {
+        match repository.rollback_commit(commit).await {
+            Ok(()) => rolled_back.push(index),
+            Err(err) => not_rolled_back.push((index, err)),
+        }
+    }
+    (rolled_back, not_rolled_back)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use async_trait::async_trait;
+
+    use crate::{
+        commit_hooks::{CommitHook, PendingHooks},
+        format::Path,
+        refs::fetch_branch_tip,
+        storage::faulty::FaultyStorage,
+        ObjectStorage, Storage,
+    };
+
+    async fn new_repository(
+    ) -> Result<(Arc<dyn Storage + Send + Sync>, Repository), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repository = Repository::init(Arc::clone(&storage), false).await?.build();
+        repository.add_group(Path::root()).await?;
+        Ok((storage, repository))
+    }
+
+    #[tokio::test]
+    async fn test_commit_together() -> Result<(), Box<dyn Error>> {
+        let (source_storage, mut source) = new_repository().await?;
+        let (pyramid_storage, mut pyramid) = new_repository().await?;
+        let snapshots = commit_together(vec![
+            TransactionCommit::new(&mut source, "main", "source"),
+            TransactionCommit::new(&mut pyramid, "main", "pyramid"),
+        ])
+        .await?;
+        assert_eq!(
+            fetch_branch_tip(source_storage.as_ref(), "main").await?.snapshot,
+            snapshots[0]
+        );
+        assert_eq!(
+            fetch_branch_tip(pyramid_storage.as_ref(), "main").await?.snapshot,
+            snapshots[1]
+        );
+
+        // a conflict while preparing commits nothing
+        source.add_group("/a".try_into()?).await?;
+        let mut stale = Repository::from_branch_tip(Arc::clone(&pyramid_storage), "main")
+            .await?
+            .build();
+        pyramid.add_group("/b".try_into()?).await?;
+        pyramid.commit("main", "concurrent", None).await?;
+        stale.add_group("/c".try_into()?).await?;
+        let res = commit_together(vec![
+            TransactionCommit::new(&mut source, "main", "source"),
+            TransactionCommit::new(&mut stale, "main", "pyramid"),
+        ])
+        .await;
+        assert!(matches!(
+            res,
+            Err(TransactionError::Prepare {
+                index: 1,
+                source: RepositoryError::Conflict { .. }
+            })
+        ));
+        assert_eq!(
+            fetch_branch_tip(source_storage.as_ref(), "main").await?.snapshot,
+            snapshots[0]
+        );
+
+        // a failure while finalizing rolls back the finalized commits, the source session
+        // moved to its prepared snapshot
+        let mut source = Repository::from_branch_tip(Arc::clone(&source_storage), "main")
+            .await?
+            .build();
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&pyramid_storage)));
+        let faulty_c: Arc<dyn Storage + Send + Sync> = faulty.clone();
+        let mut pyramid = Repository::from_branch_tip(faulty_c, "main").await?.build();
+        let pyramid_tip = pyramid.snapshot_id().clone();
+        source.add_group("/d".try_into()?).await?;
+        pyramid.add_group("/e".try_into()?).await?;
+        faulty.fail("write_ref", 0, 1);
+        let res = commit_together(vec![
+            TransactionCommit::new(&mut source, "main", "source"),
+            TransactionCommit::new(&mut pyramid, "main", "pyramid"),
+        ])
+        .await;
+        let Err(TransactionError::Finalize {
+            index, rolled_back, not_rolled_back, ..
+        }) = res
+        else {
+            panic!("the finalization should fail");
+        };
+        assert_eq!((index, rolled_back, not_rolled_back.len()), (1, vec![0], 0));
+        assert_eq!(
+            fetch_branch_tip(source_storage.as_ref(), "main").await?.snapshot,
+            snapshots[0]
+        );
+        assert_eq!(
+            fetch_branch_tip(pyramid_storage.as_ref(), "main").await?.snapshot,
+            pyramid_tip
+        );
+        Ok(())
+    }
+    #[derive(Debug)]
+    struct Noop;
+
+    #[async_trait]
+    impl CommitHook for Noop {
+        fn name(&self) -> &str {
+            "noop"
+        }
+
+        async fn on_commit(
+            &self,
+            _storage: &(dyn Storage + Send + Sync),
+            _commit: &PendingHooks,
+        ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rollback_after_publish() -> Result<(), Box<dyn Error>> {
+        let (source_storage, mut source) = new_repository().await?;
+        let (pyramid_storage, mut pyramid) = new_repository().await?;
+        let parents = commit_together(vec![
+            TransactionCommit::new(&mut source, "main", "source"),
+            TransactionCommit::new(&mut pyramid, "main", "pyramid"),
+        ])
+        .await?;
+
+        // the branch of the pyramid moves, then the hooks fail to clean up their record
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&pyramid_storage)));
+        let mut pyramid = Repository::from_branch_tip(faulty.clone(), "main")
+            .await?
+            .with_commit_hook(Arc::new(Noop))
+            .build();
+        source.add_group("/a".try_into()?).await?;
+        pyramid.add_group("/b".try_into()?).await?;
+        faulty.fail("delete_objects", 0, 1);
+        let res = commit_together(vec![
+            TransactionCommit::new(&mut source, "main", "source"),
+            TransactionCommit::new(&mut pyramid, "main", "pyramid"),
+        ])
+        .await;
+        let Err(TransactionError::Finalize {
+            index, rolled_back, not_rolled_back, ..
+        }) = res
+        else {
+            panic!("the finalization should fail");
+        };
+        assert_eq!((index, rolled_back, not_rolled_back.len()), (1, vec![1, 0], 0));
+        assert_eq!(
+            fetch_branch_tip(source_storage.as_ref(), "main").await?.snapshot,
+            parents[0]
+        );
+        assert_eq!(
+            fetch_branch_tip(pyramid_storage.as_ref(), "main").await?.snapshot,
+            parents[1]
+        );
+        Ok(())
+    }
+}
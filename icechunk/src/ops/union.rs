@@ -0,0 +1,437 @@
+//! Arrays that concatenate arrays of other repositories, without copying their chunks
+//!
+//! The chunks of the union point to the chunk objects of the source repositories, as
+//! virtual references, so the sources are only read. Each source is pinned to a snapshot:
+//! chunk objects are immutable, later commits to a source don't change the union. The
+//! snapshots are protected in the source repositories with a [`SnapshotPin`], so their
+//! garbage collection and retention keep the chunks while the pins are renewed.
+//!
+//! The locations of the chunks come from the storage of each source, see
+//! [`Storage::chunk_url`], sources whose storage can't locate its chunks are rejected.
+//! Virtual references are never decoded, so sources with chunks encoded by chunk
+//! transformers are rejected too.
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use thiserror::Error;
+
+use crate::{
+    format::{
+        manifest::{
+            ChunkPayload, ChunkRef, VirtualChunkLocation, VirtualChunkRef,
+            VirtualReferenceError,
+        },
+        snapshot::{NodeData, ZarrArrayMetadata},
+        IcechunkFormatError, Path, SnapshotId,
+    },
+    ops::lineage::{Lineage, SnapshotLink},
+    pins::{pin_snapshot, release_pin, SnapshotPin},
+    refs::RefError,
+    repository::RepositoryError,
+    Repository, Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum UnionError {
+    #[error("a union needs at least one source")]
+    NoSources,
+    #[error("source {index} cannot be concatenated: {message}")]
+    Incompatible { index: usize, message: String },
+    #[error("storage error `{0}`")]
+    Storage(#[from] StorageError),
+    #[error("error in icechunk file `{0}`")]
+    Format(#[from] IcechunkFormatError),
+    #[error("virtual reference error `{0}`")]
+    VirtualReference(#[from] VirtualReferenceError),
+    #[error("repository error `{0}`")]
+    Repository(#[from] RepositoryError),
+    #[error("ref error `{0}`")]
+    Ref(#[from] RefError),
+}
+
+pub type UnionResult<A> = Result<A, UnionError>;
+
+/// An array in another repository, as of `snapshot`
+#[derive(Debug, Clone)]
+pub struct UnionSource {
+    pub storage: Arc<dyn Storage + Send + Sync>,
+    /// The URL of the repository, it identifies the source in the lineage of the union
+    pub url: String,
+    pub snapshot: SnapshotId,
+    pub path: Path,
+}
+
+impl UnionSource {
+    pub fn new(
+        storage: Arc<dyn Storage + Send + Sync>,
+        url: impl Into<String>,
+        snapshot: SnapshotId,
+        path: Path,
+    ) -> Self {
+        Self { storage, url: url.into(), snapshot, path }
+    }
+}
+
+/// A union added to a session
+#[derive(Debug, Clone)]
+pub struct UnionArray {
+    /// Commit it with [`Repository::commit_with_lineage`] to record the pinned sources
+    pub lineage: Lineage,
+    /// The pin of each source snapshot, in the storage of the source, renew them with
+    /// [`crate::pins::renew_pin`] while the union exists
+    pub pins: Vec<SnapshotPin>,
+}
+
+/// Add an array at `path` that concatenates the `sources` along dimension `axis`
+///
+/// The sources must have the same data type, chunk shape, chunk key encoding, codecs,
+/// chunk transformers, grid origin and fill value, and the same shape except along `axis`.
+/// All but the last must have a whole number of chunks along `axis`. Each source snapshot
+/// is pinned until `now + pin_ttl`; if the union can't be added, the pins taken are
+/// released.
+pub async fn add_union_array(
+    repository: &mut Repository,
+    path: Path,
+    axis: usize,
+    sources: &[UnionSource],
+    now: DateTime<Utc>,
+    pin_ttl: TimeDelta,
+) -> UnionResult<UnionArray> {
+    let mut pins = Vec::with_capacity(sources.len());
+    let res =
+        add_pinned_union(repository, path, axis, sources, now, pin_ttl, &mut pins).await;
+    match res {
+        Ok(lineage) => Ok(UnionArray { lineage, pins }),
+        Err(err) => {
+            for (source, pin) in sources.iter().zip(pins) {
+                // the pins expire anyway, the error that matters is the first one
+                let _ = release_pin(source.storage.as_ref(), &pin.id).await;
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn add_pinned_union(
+    repository: &mut Repository,
+    path: Path,
+    axis: usize,
+    sources: &[UnionSource],
+    now: DateTime<Utc>,
+    pin_ttl: TimeDelta,
+    pins: &mut Vec<SnapshotPin>,
+) -> UnionResult<Lineage> {
+    let mut metadata: Option<ZarrArrayMetadata> = None;
+    let mut chunks = Vec::new();
+    let mut lineage = Lineage::default();
+    let holder = format!("union {path}");
+    for (index, source) in sources.iter().enumerate() {
+        let incompatible = |message: &str| UnionError::Incompatible {
+            index,
+            message: message.to_string(),
+        };
+        // pinned before reading, so the chunks can't be collected in between
+        let pin = pin_snapshot(
+            source.storage.as_ref(),
+            &source.snapshot,
+            holder.as_str(),
+            now,
+            pin_ttl,
+        )
+        .await?;
+        pins.push(pin);
+        let snapshot = source.storage.fetch_snapshot(&source.snapshot).await?;
+        let node = snapshot.get_node(&source.path)?;
+        let NodeData::Array(meta, manifests) = &node.node_data else {
+            return Err(incompatible("not an array"));
+        };
+        if axis >= meta.shape.len() {
+            return Err(incompatible("the array doesn't have the concatenation axis"));
+        }
+
+        let chunk_offset = match metadata.as_mut() {
+            None => {
+                metadata = Some(meta.clone());
+                0
+            }
+            Some(union) => {
+                if meta.data_type != union.data_type
+                    || meta.chunk_shape != union.chunk_shape
+                    || meta.chunk_key_encoding != union.chunk_key_encoding
+                    || meta.codecs != union.codecs
+                    || meta.chunk_transformers != union.chunk_transformers
+                    || meta.grid_origin != union.grid_origin
+                    || meta.fill_value != union.fill_value
+                {
+                    return Err(incompatible(
+                        "data type, chunk shape, chunk key encoding, codecs, chunk \
+                         transformers, grid origin or fill value differ",
+                    ));
+                }
+                let same_shape = meta.shape.len() == union.shape.len()
+                    && meta
+                        .shape
+                        .iter()
+                        .zip(union.shape.iter())
+                        .enumerate()
+                        .all(|(dim, (a, b))| dim == axis || a == b);
+                if !same_shape {
+                    return Err(incompatible("the shape differs outside the axis"));
+                }
+                let chunk_size = union.chunk_shape.0[axis].get();
+                if union.shape[axis] % chunk_size != 0 {
+                    return Err(UnionError::Incompatible {
+                        index: index - 1,
+                        message: "partial chunks along the axis".to_string(),
+                    });
+                }
+                let offset = union.shape[axis] / chunk_size;
+                union.shape[axis] += meta.shape[axis];
+                offset
+            }
+        };
+
+        for manifest_ref in manifests {
+            let manifest =
+                source.storage.fetch_manifests(&manifest_ref.object_id).await?;
+            for ((node_id, coords), payload) in manifest.chunks() {
+                if node_id != &node.id {
+                    continue;
+                }
+                if !manifest.chunk_transformers(node_id, coords).is_empty() {
+                    return Err(incompatible(
+                        "its chunks are encoded with chunk transformers",
+                    ));
+                }
+                let shard = manifest.chunk_placement(node_id, coords);
+                let payload =
+                    source_payload(source, shard, payload).ok_or_else(|| {
+                        incompatible("its storage can't locate its chunks")
+                    })??;
+                let mut coords = coords.clone();
+                coords.0[axis] += chunk_offset;
+                chunks.push((coords, payload));
+            }
+        }
+        lineage =
+            lineage.with_input(SnapshotLink::new(&source.url, source.snapshot.clone()));
+    }
+
+    let metadata = metadata.ok_or(UnionError::NoSources)?;
+    repository.add_array(path.clone(), metadata).await?;
+    for (coords, payload) in chunks {
+        repository.set_chunk_ref(path.clone(), coords, Some(payload)).await?;
+    }
+    Ok(lineage)
+}
+
+/// The payload of the union for a chunk of `source`, `None` if the storage of the source
+/// has no URL for it
+fn source_payload(
+    source: &UnionSource,
+    shard: Option<&str>,
+    payload: &ChunkPayload,
+) -> Option<UnionResult<ChunkPayload>> {
+    match payload {
+        ChunkPayload::Ref(ChunkRef { id, offset, length }) => {
+            let url = source.storage.chunk_url(shard, id)?;
+            Some(
+                VirtualChunkLocation::from_absolute_path(&url)
+                    .map(|location| {
+                        ChunkPayload::Virtual(VirtualChunkRef {
+                            location,
+                            offset: *offset,
+                            length: *length,
+                            slice: None,
+                        })
+                    })
+                    .map_err(UnionError::from),
+            )
+        }
+        inline_or_virtual => Some(Ok(inline_or_virtual.clone())),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use bytes::Bytes;
+    use chrono::TimeDelta;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkIndices},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        pins::list_pins,
+        repository::get_chunk,
+        test_utils::XorTransformer,
+        ObjectStorage,
+    };
+
+    fn metadata(len: u64) -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![len, 2],
+            data_type: DataType::UInt8,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap(); 2]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_union_array() -> Result<(), Box<dyn Error>> {
+        let path: Path = "/data".try_into()?;
+        let dirs = [tempfile::tempdir()?, tempfile::tempdir()?];
+        let mut sources = Vec::new();
+        let mut source_repos = Vec::new();
+        for (i, dir) in dirs.iter().enumerate() {
+            let storage: Arc<dyn Storage + Send + Sync> =
+                Arc::new(ObjectStorage::new_local_store(dir.path())?);
+            let mut ds = Repository::init(Arc::clone(&storage), false)
+                .await?
+                .with_inline_threshold_bytes(0)
+                .build();
+            ds.add_group(Path::root()).await?;
+            ds.add_array(path.clone(), metadata(2 + 2 * i as u64)).await?;
+            for c in 0..=i as u64 {
+                let payload =
+                    ds.get_chunk_writer()(Bytes::from(vec![i as u8; 4])).await?;
+                ds.set_chunk_ref(path.clone(), ChunkIndices(vec![c, 0]), Some(payload))
+                    .await?;
+            }
+            let snapshot = ds.commit("main", "data", None).await?;
+            let url = format!("file://{}", dir.path().display());
+            sources.push(UnionSource::new(storage, url, snapshot, path.clone()));
+            source_repos.push(ds);
+        }
+
+        let mut union =
+            Repository::init(Arc::new(ObjectStorage::new_in_memory_store(None)), false)
+                .await?
+                .build();
+        union.add_group(Path::root()).await?;
+        let union_path: Path = "/union".try_into()?;
+        let now = Utc::now();
+        let UnionArray { lineage, pins } = add_union_array(
+            &mut union,
+            union_path.clone(),
+            0,
+            &sources,
+            now,
+            TimeDelta::days(1),
+        )
+        .await?;
+        assert_eq!(lineage.inputs.len(), 2);
+        for (source, pin) in sources.iter().zip(pins.iter()) {
+            assert_eq!(pin.snapshot, source.snapshot);
+            assert_eq!(list_pins(source.storage.as_ref()).await?, vec![pin.clone()]);
+        }
+        union.commit_with_lineage("main", "union", None, &lineage).await?;
+        let NodeData::Array(meta, _) = union.get_array(&union_path).await?.node_data
+        else {
+            panic!("not an array");
+        };
+        assert_eq!(meta.shape, vec![6, 2]);
+
+        // the sources are pinned, later commits don't change the union
+        let payload = source_repos[1].get_chunk_writer()(Bytes::from(vec![9; 4])).await?;
+        source_repos[1]
+            .set_chunk_ref(path.clone(), ChunkIndices(vec![0, 0]), Some(payload))
+            .await?;
+        source_repos[1].commit("main", "overwrite", None).await?;
+
+        for (c, expected) in [(0, Some(0)), (1, Some(1)), (2, Some(1)), (3, None)] {
+            let chunk = get_chunk(
+                union
+                    .get_chunk_reader(
+                        &union_path,
+                        &ChunkIndices(vec![c, 0]),
+                        &ByteRange::ALL,
+                    )
+                    .await?,
+            )
+            .await?;
+            assert_eq!(chunk, expected.map(|v| Bytes::from(vec![v; 4])));
+        }
+
+        let mut odd = sources[0].clone();
+        let mut ds =
+            Repository::update(Arc::clone(&odd.storage), odd.snapshot.clone()).build();
+        ds.update_array(path.clone(), metadata(3)).await?;
+        odd.snapshot = ds.commit("main", "odd", None).await?;
+        assert!(matches!(
+            add_union_array(
+                &mut union,
+                "/odd".try_into()?,
+                0,
+                &[odd, sources[1].clone()],
+                now,
+                TimeDelta::days(1),
+            )
+            .await,
+            Err(UnionError::Incompatible { index: 0, .. })
+        ));
+        // the pins of a failed union are released
+        for (source, pin) in sources.iter().zip(pins) {
+            assert_eq!(list_pins(source.storage.as_ref()).await?, vec![pin]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_union_rejects_unlocatable_sources() -> Result<(), Box<dyn Error>> {
+        let path: Path = "/data".try_into()?;
+        async fn in_memory(
+            path: &Path,
+            transformed: bool,
+        ) -> Result<UnionSource, Box<dyn Error>> {
+            let storage: Arc<dyn Storage + Send + Sync> =
+                Arc::new(ObjectStorage::new_in_memory_store(None));
+            let mut builder = Repository::init(Arc::clone(&storage), false).await?;
+            builder.with_inline_threshold_bytes(0);
+            if transformed {
+                builder.with_chunk_transformer(XorTransformer(42));
+            }
+            let mut ds = builder.build();
+            ds.add_group(Path::root()).await?;
+            ds.add_array(path.clone(), metadata(2)).await?;
+            let payload = ds.get_chunk_writer()(Bytes::from(vec![1; 4])).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0, 0]), Some(payload))
+                .await?;
+            let snapshot = ds.commit("main", "data", None).await?;
+            Ok(UnionSource::new(storage, "memory://", snapshot, path.clone()))
+        }
+
+        for transformed in [false, true] {
+            let source = in_memory(&path, transformed).await?;
+            let mut union = Repository::init(
+                Arc::new(ObjectStorage::new_in_memory_store(None)),
+                false,
+            )
+            .await?
+            .build();
+            assert!(matches!(
+                add_union_array(
+                    &mut union,
+                    "/union".try_into()?,
+                    0,
+                    &[source.clone()],
+                    Utc::now(),
+                    TimeDelta::days(1),
+                )
+                .await,
+                Err(UnionError::Incompatible { index: 0, .. })
+            ));
+            assert_eq!(list_pins(source.storage.as_ref()).await?, vec![]);
+        }
+        Ok(())
+    }
+}
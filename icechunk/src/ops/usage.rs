@@ -0,0 +1,268 @@
+//! The storage used by each array, rolled up by group, and quotas on it
+//!
+//! [`usage_report`] adds up the chunks of every array of a snapshot. Given the report of
+//! the parent snapshot, it only reads the manifests of the arrays the commit changed,
+//! according to its transaction log, the usage of the other arrays is carried over.
+//! [`UsageReport::usage`] adds up the arrays under a group, to attribute the storage of a
+//! repository shared by many teams to the groups they own.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, Manifest},
+        snapshot::NodeData,
+        ManifestId, NodeId, Path, SnapshotId,
+    },
+    storage::StorageResult,
+    Storage,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub arrays: usize,
+    pub chunks: u64,
+    /// The size of the native and inline chunks, stored in the repository
+    pub bytes: u64,
+    /// The size of the virtual chunks, stored outside the repository
+    pub virtual_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.arrays += other.arrays;
+        self.chunks += other.chunks;
+        self.bytes += other.bytes;
+        self.virtual_bytes += other.virtual_bytes;
+    }
+
+    fn add_chunk(&mut self, payload: &ChunkPayload) {
+        self.chunks += 1;
+        match payload {
+            ChunkPayload::Inline(bytes) => self.bytes += bytes.len() as u64,
+            ChunkPayload::Ref(chunk_ref) => self.bytes += chunk_ref.length,
+            ChunkPayload::Virtual(virtual_ref) => {
+                self.virtual_bytes += virtual_ref.chunk_length()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArrayUsage {
+    pub node: NodeId,
+    pub usage: Usage,
+}
+
+/// Limits on the usage of a group and everything under it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_chunks: Option<u64>,
+}
+
+impl Quota {
+    pub fn with_max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    pub fn with_max_chunks(mut self, chunks: u64) -> Self {
+        self.max_chunks = Some(chunks);
+        self
+    }
+
+    fn is_exceeded_by(&self, usage: &Usage) -> bool {
+        self.max_bytes.is_some_and(|max| usage.bytes > max)
+            || self.max_chunks.is_some_and(|max| usage.chunks > max)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuotaViolation {
+    pub path: Path,
+    pub quota: Quota,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub snapshot: SnapshotId,
+    pub arrays: BTreeMap<Path, ArrayUsage>,
+    /// The arrays whose manifests were read, the others were carried over from the
+    /// report of the parent
+    pub recomputed: usize,
+}
+
+impl UsageReport {
+    /// The usage of the node at `path` and everything under it
+    pub fn usage(&self, path: &Path) -> Usage {
+        let mut res = Usage::default();
+        for (_, array) in self.arrays.iter().filter(|(array, _)| array.starts_with(path))
+        {
+            res.add(&array.usage);
+        }
+        res
+    }
+
+    /// The usage under every group that contains arrays
+    pub fn rollup(&self) -> BTreeMap<Path, Usage> {
+        let mut res: BTreeMap<Path, Usage> = BTreeMap::new();
+        for (path, array) in self.arrays.iter() {
+            for group in path.ancestors().skip(1) {
+                res.entry(group).or_default().add(&array.usage);
+            }
+        }
+        res
+    }
+
+    /// The groups that use more than their quota, by path
+    pub fn check_quotas(&self, quotas: &BTreeMap<Path, Quota>) -> Vec<QuotaViolation> {
+        quotas
+            .iter()
+            .filter_map(|(path, quota)| {
+                let usage = self.usage(path);
+                quota.is_exceeded_by(&usage).then(|| QuotaViolation {
+                    path: path.clone(),
+                    quota: *quota,
+                    usage,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The usage of every array of the snapshot `snapshot_id`
+///
+/// `previous` is only used if it's the report of the parent snapshot, otherwise every
+/// manifest is read.
+pub async fn usage_report(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+    previous: Option<&UsageReport>,
+) -> StorageResult<UsageReport> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let parent = Arc::clone(&snapshot).local_ancestry().next().map(|meta| meta.id);
+    let mut carried: HashMap<NodeId, Usage> = HashMap::new();
+    if let Some(previous) =
+        previous.filter(|prev| Some(&prev.snapshot) == parent.as_ref())
+    {
+        let log = storage.fetch_transaction_log(snapshot_id).await?;
+        carried.extend(
+            previous
+                .arrays
+                .values()
+                .filter(|array| {
+                    !log.updated_chunks.contains_key(&array.node)
+                        && !log.updated_zarr_metadata.contains(&array.node)
+                })
+                .map(|array| (array.node.clone(), array.usage)),
+        );
+    }
+
+    let mut manifests: HashMap<ManifestId, Arc<Manifest>> = HashMap::new();
+    let mut arrays = BTreeMap::new();
+    let mut recomputed = 0;
+    for node in snapshot.iter() {
+        let NodeData::Array(_, manifest_refs) = &node.node_data else { continue };
+        let usage = match carried.get(&node.id) {
+            Some(usage) => *usage,
+            None => {
+                recomputed += 1;
+                let mut usage = Usage { arrays: 1, ..Usage::default() };
+                let ids: BTreeSet<&ManifestId> =
+                    manifest_refs.iter().map(|manifest| &manifest.object_id).collect();
+                for id in ids {
+                    let manifest = match manifests.get(id) {
+                        Some(manifest) => Arc::clone(manifest),
+                        None => {
+                            let manifest = storage.fetch_manifests(id).await?;
+                            manifests.insert(id.clone(), Arc::clone(&manifest));
+                            manifest
+                        }
+                    };
+                    for (_, payload) in manifest.iter(node.id.clone()) {
+                        usage.add_chunk(&payload);
+                    }
+                }
+                usage
+            }
+        };
+        arrays.insert(node.path.clone(), ArrayUsage { node: node.id.clone(), usage });
+    }
+    Ok(UsageReport { snapshot: snapshot_id.clone(), arrays, recomputed })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::ChunkIndices, test_utils};
+
+    #[tokio::test]
+    async fn test_usage_report() -> Result<(), Box<dyn Error>> {
+        let storage = test_utils::new_in_memory_storage();
+        let mut ds = test_utils::new_repository(Arc::clone(&storage)).await?;
+        let (team_a, team_b): (Path, Path) =
+            ("/team-a".try_into()?, "/team-b".try_into()?);
+        for group in [Path::root(), team_a.clone(), team_b.clone()] {
+            ds.add_group(group).await?;
+        }
+        for (group, array, chunks) in
+            [(&team_a, "x", 2), (&team_a, "y", 1), (&team_b, "z", 3)]
+        {
+            let path = group.child(array)?;
+            ds.add_array(path.clone(), test_utils::array_metadata(vec![10])).await?;
+            for i in 0..chunks {
+                let payload = ds.get_chunk_writer()(Bytes::from_static(b"ab")).await?;
+                ds.set_chunk_ref(path.clone(), ChunkIndices(vec![i]), Some(payload))
+                    .await?;
+            }
+        }
+        let first = ds.commit("main", "first", None).await?;
+        let report = usage_report(storage.as_ref(), &first, None).await?;
+        assert_eq!(report.recomputed, 3);
+        assert_eq!(
+            report.usage(&team_a),
+            Usage { arrays: 2, chunks: 3, bytes: 6, virtual_bytes: 0 }
+        );
+        let rollup = report.rollup();
+        assert_eq!(rollup[&Path::root()].chunks, 6);
+        assert_eq!(rollup[&team_b].bytes, 6);
+        assert_eq!(rollup.len(), 3);
+
+        // only the arrays the commit changed are recomputed
+        let z = team_b.child("z")?;
+        ds.set_chunk_ref(z.clone(), ChunkIndices(vec![0]), None).await?;
+        let second = ds.commit("main", "second", None).await?;
+        let report = usage_report(storage.as_ref(), &second, Some(&report)).await?;
+        assert_eq!(report.recomputed, 1);
+        assert_eq!(report.arrays[&z].usage.chunks, 2);
+        assert_eq!(report.usage(&team_a).chunks, 3);
+        let full = usage_report(storage.as_ref(), &second, None).await?;
+        assert_eq!(full.arrays, report.arrays);
+
+        let quotas = BTreeMap::from([
+            (team_a.clone(), Quota::default().with_max_bytes(4)),
+            (team_b.clone(), Quota::default().with_max_chunks(2)),
+        ]);
+        assert_eq!(
+            report.check_quotas(&quotas),
+            vec![QuotaViolation {
+                path: team_a.clone(),
+                quota: quotas[&team_a],
+                usage: report.usage(&team_a),
+            }]
+        );
+        Ok(())
+    }
+}
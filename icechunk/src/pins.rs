@@ -0,0 +1,228 @@
+//! Leases that protect snapshots from garbage collection
+//!
+//! External systems, like a long running analysis, pin the snapshots they read. Pins are
+//! recorded in a namespace of the refs, and expire after their time to live unless renewed.
+//! Until then, [`crate::ops::gc::garbage_collect`] keeps the pinned snapshot and its history,
+//! and [`crate::ops::retention::apply_retention`] doesn't expire it.
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, StreamExt};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::SnapshotId,
+    refs::{RefError, RefResult},
+    storage::REF_PREFIX,
+    Storage, StorageError,
+};
+
+pub(crate) const PIN_REF_PREFIX: &str = "pin.";
+const PIN_KEY_NAME: &str = "ref.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotPin {
+    pub id: String,
+    pub snapshot: SnapshotId,
+    /// Who holds the pin, for operators listing them
+    pub holder: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl SnapshotPin {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn pin_key(id: &str) -> String {
+    format!("{}{}/{}", PIN_REF_PREFIX, id, PIN_KEY_NAME)
+}
+
+async fn write_pin(
+    storage: &(dyn Storage + Send + Sync),
+    pin: &SnapshotPin,
+) -> RefResult<()> {
+    let content = serde_json::to_vec(pin)?;
+    storage.write_ref(pin_key(&pin.id).as_str(), true, Bytes::from(content)).await?;
+    Ok(())
+}
+
+/// Protect `snapshot` from collection until `now + ttl`
+pub async fn pin_snapshot(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot: &SnapshotId,
+    holder: &str,
+    now: DateTime<Utc>,
+    ttl: TimeDelta,
+) -> RefResult<SnapshotPin> {
+    // pinning an already collected snapshot would silently protect nothing
+    storage.fetch_snapshot(snapshot).await?;
+    let pin = SnapshotPin {
+        id: Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+        snapshot: snapshot.clone(),
+        holder: holder.to_string(),
+        created_at: now,
+        expires_at: now + ttl,
+    };
+    write_pin(storage, &pin).await?;
+    Ok(pin)
+}
+
+pub async fn fetch_pin(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+) -> RefResult<SnapshotPin> {
+    match storage.get_ref(pin_key(id).as_str()).await {
+        Ok(data) => Ok(serde_json::from_slice(data.as_ref())?),
+        Err(StorageError::RefNotFound(..)) => Err(RefError::RefNotFound(id.to_string())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Extend the pin to `now + ttl`
+///
+/// Fails with [`RefError::RefNotFound`] if the pin was released or purged, the snapshot may
+/// have been collected since, so it must be pinned again, and checked.
+pub async fn renew_pin(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+    now: DateTime<Utc>,
+    ttl: TimeDelta,
+) -> RefResult<SnapshotPin> {
+    let mut pin = fetch_pin(storage, id).await?;
+    pin.expires_at = now + ttl;
+    write_pin(storage, &pin).await?;
+    Ok(pin)
+}
+
+/// All pins, including the expired ones that were not purged yet
+pub async fn list_pins(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Vec<SnapshotPin>> {
+    let mut res = Vec::new();
+    for name in storage.ref_names().await? {
+        if let Some(id) = name.strip_prefix(PIN_REF_PREFIX) {
+            // stores with directories can keep listing the name of a released pin
+            match fetch_pin(storage, id).await {
+                Ok(pin) => res.push(pin),
+                Err(RefError::RefNotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// The snapshots protected by pins that haven't expired at `now`
+pub async fn pinned_snapshots(
+    storage: &(dyn Storage + Send + Sync),
+    now: DateTime<Utc>,
+) -> RefResult<Vec<SnapshotId>> {
+    Ok(list_pins(storage)
+        .await?
+        .into_iter()
+        .filter(|pin| !pin.is_expired(now))
+        .map(|pin| pin.snapshot)
+        .collect())
+}
+
+pub async fn release_pin(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+) -> RefResult<()> {
+    storage.delete_objects(REF_PREFIX, stream::iter([pin_key(id)]).boxed()).await?;
+    Ok(())
+}
+
+/// Delete the pins expired at `now`, returning how many were deleted
+pub async fn purge_pins(
+    storage: &(dyn Storage + Send + Sync),
+    now: DateTime<Utc>,
+) -> RefResult<usize> {
+    let expired: Vec<String> = list_pins(storage)
+        .await?
+        .into_iter()
+        .filter(|pin| pin.is_expired(now))
+        .map(|pin| pin_key(&pin.id))
+        .collect();
+    Ok(storage.delete_objects(REF_PREFIX, stream::iter(expired).boxed()).await?)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        ops::gc::{garbage_collect, GCConfig},
+        refs::{delete_branch, list_refs, Ref},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_pins_protect_snapshots() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.commit("main", "root", None).await?;
+        ds.new_branch("scratch").await?;
+        ds.add_group("/scratch".try_into()?).await?;
+        let snapshot = ds.commit("scratch", "scratch", None).await?;
+
+        let pin = ds.pin("analysis", TimeDelta::hours(1)).await?;
+        assert_eq!(pin.snapshot, snapshot);
+        let now = pin.created_at;
+        delete_branch(storage.as_ref(), "scratch").await?;
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string())]
+        );
+        assert_eq!(list_pins(storage.as_ref()).await?, vec![pin.clone()]);
+
+        let gc = |at| GCConfig::clean_all(Utc::now(), Utc::now(), None).with_now(at);
+        assert_eq!(
+            garbage_collect(storage.as_ref(), &gc(now + TimeDelta::minutes(30)))
+                .await?
+                .snapshots_deleted,
+            0
+        );
+        let renewed = renew_pin(
+            storage.as_ref(),
+            &pin.id,
+            now + TimeDelta::minutes(30),
+            TimeDelta::hours(1),
+        )
+        .await?;
+        assert_eq!(renewed.expires_at, now + TimeDelta::minutes(90));
+        assert_eq!(
+            garbage_collect(storage.as_ref(), &gc(now + TimeDelta::minutes(80)))
+                .await?
+                .snapshots_deleted,
+            0
+        );
+        storage.fetch_snapshot(&snapshot).await?;
+
+        // once expired, the snapshot can be collected, and the pin purged
+        assert_eq!(
+            garbage_collect(storage.as_ref(), &gc(now + TimeDelta::hours(2)))
+                .await?
+                .snapshots_deleted,
+            1
+        );
+        assert!(storage.fetch_snapshot(&snapshot).await.is_err());
+        assert_eq!(purge_pins(storage.as_ref(), now + TimeDelta::hours(1)).await?, 0);
+        assert_eq!(purge_pins(storage.as_ref(), now + TimeDelta::hours(2)).await?, 1);
+        assert!(matches!(
+            renew_pin(storage.as_ref(), &pin.id, now, TimeDelta::hours(1)).await,
+            Err(RefError::RefNotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use std::{fmt::Debug, sync::Arc};
+
+/// A step of a long operation, reported in [`Progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Commit: uploading the packed and buffered chunks of the session
+    UploadingChunks,
+    /// Commit: writing the manifest, snapshot and transaction log
+    WritingMetadata,
+    /// Commit: moving the branch to the new snapshot
+    UpdatingRef,
+    /// Garbage collection: reading the snapshots and manifests reachable from the refs
+    FindingReachable,
+    /// Garbage collection: deleting the objects that are no longer reachable
+    DeletingSnapshots,
+    DeletingManifests,
+    DeletingChunks,
+}
+
+/// How far an operation got in its current phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Objects processed so far in the phase
+    pub done: u64,
+    /// Objects to process in the phase, if known in advance
+    pub total: Option<u64>,
+    /// Bytes transferred so far in the phase
+    pub bytes: u64,
+}
+
+impl Progress {
+    pub fn new(phase: Phase, done: u64, total: Option<u64>) -> Self {
+        Self { phase, done, total, bytes: 0 }
+    }
+
+    pub fn with_bytes(mut self, bytes: u64) -> Self {
+        self.bytes = bytes;
+        self
+    }
+}
+
+/// Receives progress reports of commits and garbage collection
+///
+/// Set it with [`crate::RepositoryBuilder::with_progress_observer`] or
+/// [`crate::ops::gc::GCConfig::with_progress_observer`]. Each phase is reported when it
+/// starts, with `done == 0`, as it advances, and when it finishes. Observers are called from
+/// the task doing the work, possibly from concurrent uploads, they should return quickly.
+pub trait ProgressObserver: Debug + Send + Sync {
+    fn on_progress(&self, progress: &Progress);
+}
+
+pub type DynProgressObserver = Arc<dyn ProgressObserver>;
+
+/// Ignores all reports, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {
+    fn on_progress(&self, _progress: &Progress) {}
+}
+
+pub fn default_progress_observer() -> DynProgressObserver {
+    Arc::new(NoProgress)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        error::Error,
+        num::NonZeroU64,
+        sync::{Mutex, PoisonError},
+    };
+
+    use chrono::Utc;
+    use itertools::Itertools;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ChunkIndices, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        ops::gc::{garbage_collect, GCConfig},
+        repository::ZarrArrayMetadata,
+        ObjectStorage, Repository, Storage,
+    };
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<Progress>>);
+
+    impl ProgressObserver for Recorder {
+        fn on_progress(&self, progress: &Progress) {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner).push(progress.clone());
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<Progress> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_progress_reports() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let recorder = Arc::new(Recorder::default());
+        let spill_dir = tempfile::tempdir()?;
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .with_chunk_write_buffer(spill_dir.path(), 1024)
+            .with_progress_observer(recorder.clone())
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        for c in 0..2 {
+            let payload = ds.get_chunk_writer()(vec![1; 3].into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![c]), Some(payload)).await?;
+        }
+        ds.commit("main", "commit", None).await?;
+
+        let mut reports = recorder.take();
+        // concurrent uploads can report in any order
+        reports[1..3].sort_by_key(|progress| progress.done);
+        use Phase::*;
+        assert_eq!(
+            reports,
+            vec![
+                Progress::new(UploadingChunks, 0, Some(2)),
+                Progress::new(UploadingChunks, 1, Some(2)).with_bytes(3),
+                Progress::new(UploadingChunks, 2, Some(2)).with_bytes(6),
+                Progress::new(WritingMetadata, 0, Some(1)),
+                Progress::new(WritingMetadata, 1, Some(1)),
+                Progress::new(UpdatingRef, 0, Some(1)),
+                Progress::new(UpdatingRef, 1, Some(1)),
+            ]
+        );
+
+        let now = Utc::now();
+        let config =
+            GCConfig::clean_all(now, now, None).with_progress_observer(recorder.clone());
+        garbage_collect(storage.as_ref(), &config).await?;
+        let phases = recorder.take().into_iter().map(|p| (p.phase, p.done)).collect_vec();
+        assert_eq!(
+            phases,
+            vec![
+                (FindingReachable, 0),
+                (FindingReachable, 1),
+                (FindingReachable, 2),
+                (DeletingSnapshots, 0),
+                (DeletingSnapshots, 0),
+                (DeletingManifests, 0),
+                (DeletingManifests, 0),
+                (DeletingChunks, 0),
+                (DeletingChunks, 0),
+            ]
+        );
+        Ok(())
+    }
+}
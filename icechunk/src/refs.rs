@@ -1,11 +1,17 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
 use async_recursion::async_recursion;
 use bytes::Bytes;
-use futures::{Stream, TryStreamExt};
+use futures::{future::ready, Stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{format::SnapshotId, Storage, StorageError};
+use crate::{format::SnapshotId, storage::REF_PREFIX, Storage, StorageError};
 
 fn crock_encode_int(n: u64) -> String {
     // skip the first 3 bytes (zeroes)
@@ -44,6 +50,16 @@ pub enum RefError {
 
     #[error("branch update conflict: `({expected_parent:?}) != ({actual_parent:?})`")]
     Conflict { expected_parent: Option<SnapshotId>, actual_parent: Option<SnapshotId> },
+
+    #[error("branch `{branch}` is no longer at version {expected:?}")]
+    VersionMismatch { branch: String, expected: Option<BranchVersion> },
+}
+
+impl RefError {
+    /// The operation that failed may succeed if it's retried
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, RefError::Storage(err) if err.is_retryable())
+    }
 }
 
 pub type RefResult<A> = Result<A, RefError>;
@@ -58,9 +74,9 @@ impl Ref {
     pub const DEFAULT_BRANCH: &'static str = "main";
 
     fn from_path(path: &str) -> RefResult<Self> {
-        match path.strip_prefix("tag.") {
+        match path.strip_prefix(TAG_REF_PREFIX) {
             Some(name) => Ok(Ref::Tag(name.to_string())),
-            None => match path.strip_prefix("branch.") {
+            None => match path.strip_prefix(BRANCH_REF_PREFIX) {
                 Some(name) => Ok(Ref::Branch(name.to_string())),
                 None => Err(RefError::InvalidRefType(path.to_string())),
             },
@@ -112,6 +128,8 @@ pub struct RefData {
     pub snapshot: SnapshotId,
 }
 
+const BRANCH_REF_PREFIX: &str = "branch.";
+const TAG_REF_PREFIX: &str = "tag.";
 const TAG_KEY_NAME: &str = "ref.json";
 
 fn tag_key(tag_name: &str) -> RefResult<String> {
@@ -119,14 +137,14 @@ fn tag_key(tag_name: &str) -> RefResult<String> {
         return Err(RefError::InvalidRefName(tag_name.to_string()));
     }
 
-    Ok(format!("tag.{}/{}", tag_name, TAG_KEY_NAME))
+    Ok(format!("{}{}/{}", TAG_REF_PREFIX, tag_name, TAG_KEY_NAME))
 }
 
 fn branch_root(branch_name: &str) -> RefResult<String> {
     if branch_name.contains('/') {
         return Err(RefError::InvalidRefName(branch_name.to_string()));
     }
-    Ok(format!("branch.{}", branch_name))
+    Ok(format!("{}{}", BRANCH_REF_PREFIX, branch_name))
 }
 
 fn branch_key(branch_name: &str, version_id: &str) -> RefResult<String> {
@@ -200,9 +218,76 @@ pub async fn update_branch(
     }
 }
 
+/// Point the branch to `new_snapshot` only if its tip is still at version `expected`
+///
+/// `expected` is the token returned by [`fetch_branch_tip_versioned`], or `None` to create
+/// the branch. Unlike [`update_branch`] nothing is retried, the caller gets
+/// [`RefError::VersionMismatch`] if the branch moved and can run its own optimistic loop.
+pub async fn update_branch_if_version(
+    storage: &(dyn Storage + Send + Sync),
+    name: &str,
+    new_snapshot: SnapshotId,
+    expected: Option<&BranchVersion>,
+) -> RefResult<BranchVersion> {
+    let mismatch = || RefError::VersionMismatch {
+        branch: name.to_string(),
+        expected: expected.cloned(),
+    };
+    let new_version = match expected {
+        Some(version) => {
+            match fetch_branch(storage, name, version).await {
+                Err(RefError::RefNotFound(_)) => return Err(mismatch()),
+                res => res?,
+            };
+            version.inc()
+        }
+        None => BranchVersion::initial(),
+    };
+
+    let key = new_version.to_path(name)?;
+    let content = serde_json::to_vec(&RefData { snapshot: new_snapshot })?;
+    match storage.write_ref(key.as_str(), false, Bytes::from(content)).await {
+        Ok(_) => Ok(new_version),
+        Err(StorageError::RefAlreadyExists(_)) => Err(mismatch()),
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub async fn list_refs(storage: &(dyn Storage + Send + Sync)) -> RefResult<Vec<Ref>> {
-    let all = storage.ref_names().await?;
-    all.iter().map(|path| Ref::from_path(path.as_str())).try_collect()
+    if !storage.capabilities().listing {
+        return Ok(fetch_ref_advertisement(storage).await?.refs());
+    }
+    let all = match storage.ref_names().await {
+        Ok(all) => all,
+        Err(StorageError::ListingNotSupported) => {
+            return Ok(fetch_ref_advertisement(storage).await?.refs())
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // the other namespaces of the refs are not branches or tags
+    all.iter()
+        .filter(|path| {
+            path.starts_with(BRANCH_REF_PREFIX) || path.starts_with(TAG_REF_PREFIX)
+        })
+        .map(|path| Ref::from_path(path.as_str()))
+        .try_collect()
+}
+
+/// Delete every version of the branch, returning its last value
+///
+/// This cannot be undone, prefer [`crate::trash::trash_branch`].
+pub async fn delete_branch(
+    storage: &(dyn Storage + Send + Sync),
+    name: &str,
+) -> RefResult<RefData> {
+    let tip = fetch_branch_tip(storage, name).await?;
+    let keys: Vec<String> = branch_history(storage, name)
+        .await?
+        .and_then(|version| ready(version.to_path(name)))
+        .try_collect()
+        .await?;
+    storage.delete_objects(REF_PREFIX, futures::stream::iter(keys).boxed()).await?;
+    Ok(tip)
 }
 
 async fn branch_history<'a, 'b>(
@@ -261,8 +346,71 @@ pub async fn fetch_branch_tip(
     storage: &(dyn Storage + Send + Sync),
     name: &str,
 ) -> RefResult<RefData> {
+    match last_branch_version(storage, name).await {
+        Ok(version) => fetch_branch(storage, name, &version).await,
+        Err(RefError::Storage(StorageError::ListingNotSupported)) => {
+            let advertisement = fetch_ref_advertisement(storage).await?;
+            match advertisement.branches.get(name) {
+                Some(snapshot) => Ok(RefData { snapshot: snapshot.clone() }),
+                None => Err(RefError::RefNotFound(name.to_string())),
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The tip of the branch, with the version token needed by [`update_branch_if_version`]
+pub async fn fetch_branch_tip_versioned(
+    storage: &(dyn Storage + Send + Sync),
+    name: &str,
+) -> RefResult<(BranchVersion, RefData)> {
     let version = last_branch_version(storage, name).await?;
-    fetch_branch(storage, name, &version).await
+    let data = fetch_branch(storage, name, &version).await?;
+    Ok((version, data))
+}
+
+/// Branch tips resolved recently, for sessions that tolerate stale reads
+///
+/// [`crate::storage::MemCachingStorage`] keeps one, shared by the sessions opened on it.
+/// Commits made through the storage update it, commits made by other processes are only
+/// seen once the tip is resolved again.
+#[derive(Debug, Default)]
+pub struct BranchTipCache {
+    tips: Mutex<HashMap<String, (Instant, SnapshotId)>>,
+}
+
+impl BranchTipCache {
+    /// The tip of the branch, from the cache if it was resolved less than `max_staleness`
+    /// ago
+    pub async fn fetch(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        name: &str,
+        max_staleness: Duration,
+    ) -> RefResult<SnapshotId> {
+        let cached =
+            self.tips.lock().unwrap_or_else(PoisonError::into_inner).get(name).and_then(
+                |(resolved_at, snapshot)| {
+                    (resolved_at.elapsed() < max_staleness).then(|| snapshot.clone())
+                },
+            );
+        match cached {
+            Some(snapshot) => Ok(snapshot),
+            None => {
+                let snapshot = fetch_branch_tip(storage, name).await?.snapshot;
+                self.insert(name, snapshot.clone());
+                Ok(snapshot)
+            }
+        }
+    }
+
+    /// Record `snapshot` as the tip of the branch, resolved now
+    pub fn insert(&self, name: &str, snapshot: SnapshotId) {
+        self.tips
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.to_string(), (Instant::now(), snapshot));
+    }
 }
 
 pub async fn fetch_ref(
@@ -279,6 +427,100 @@ pub async fn fetch_ref(
     }
 }
 
+const REF_FETCH_CONCURRENCY: usize = 16;
+
+/// Fetch `refs` concurrently, in order
+pub async fn fetch_refs(
+    storage: &(dyn Storage + Send + Sync),
+    refs: Vec<Ref>,
+) -> RefResult<Vec<(Ref, RefData)>> {
+    let fetches = refs.into_iter().map(|r| async move {
+        let data = r.fetch(storage).await?;
+        Ok((r, data))
+    });
+    futures::stream::iter(fetches).buffered(REF_FETCH_CONCURRENCY).try_collect().await
+}
+
+/// Every tag and branch, with the snapshot it points to
+pub async fn list_ref_tips(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Vec<(Ref, RefData)>> {
+    fetch_refs(storage, list_refs(storage).await?).await
+}
+
+/// The snapshot each tag or branch in `names` points to, fetched concurrently
+///
+/// Names are resolved as in [`fetch_ref`], tags first. Fails with
+/// [`RefError::RefNotFound`] if any of them doesn't exist.
+pub async fn resolve_refs(
+    storage: &(dyn Storage + Send + Sync),
+    names: impl IntoIterator<Item = impl Into<String>>,
+) -> RefResult<HashMap<String, SnapshotId>> {
+    let fetches = names.into_iter().map(Into::into).map(|name: String| async move {
+        let (_, data) = fetch_ref(storage, &name).await?;
+        Ok((name, data.snapshot))
+    });
+    futures::stream::iter(fetches)
+        .buffer_unordered(REF_FETCH_CONCURRENCY)
+        .try_collect()
+        .await
+}
+
+/// The key of the [`RefAdvertisement`], next to the refs
+pub const REF_ADVERTISEMENT_KEY: &str = "refs.json";
+
+/// Every branch and tag with the snapshot it points to, in a single object
+///
+/// Static hosting and CDNs serve objects but can't list keys. Readers of storages that
+/// fail with [`StorageError::ListingNotSupported`] resolve refs from the advertisement
+/// instead, it's as recent as its last write by [`advertise_refs`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefAdvertisement {
+    pub branches: BTreeMap<String, SnapshotId>,
+    pub tags: BTreeMap<String, SnapshotId>,
+}
+
+impl RefAdvertisement {
+    fn refs(&self) -> Vec<Ref> {
+        self.branches
+            .keys()
+            .map(|name| Ref::Branch(name.clone()))
+            .chain(self.tags.keys().map(|name| Ref::Tag(name.clone())))
+            .collect()
+    }
+}
+
+/// Write the [`RefAdvertisement`] of the current branches and tags
+///
+/// Concurrent writers don't coordinate, the last one wins. Writing it again after every ref
+/// update, as [`crate::RepositoryBuilder::with_ref_advertisement`] does, keeps it current.
+pub async fn advertise_refs(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<RefAdvertisement> {
+    let mut advertisement = RefAdvertisement::default();
+    for (r, data) in list_ref_tips(storage).await? {
+        match r {
+            Ref::Branch(name) => advertisement.branches.insert(name, data.snapshot),
+            Ref::Tag(name) => advertisement.tags.insert(name, data.snapshot),
+        };
+    }
+    let content = serde_json::to_vec(&advertisement)?;
+    storage.write_ref(REF_ADVERTISEMENT_KEY, true, Bytes::from(content)).await?;
+    Ok(advertisement)
+}
+
+pub async fn fetch_ref_advertisement(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<RefAdvertisement> {
+    match storage.get_ref(REF_ADVERTISEMENT_KEY).await {
+        Ok(data) => Ok(serde_json::from_slice(data.as_ref())?),
+        Err(StorageError::RefNotFound(..)) => {
+            Err(RefError::RefNotFound(REF_ADVERTISEMENT_KEY.to_string()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
@@ -289,7 +531,18 @@ mod tests {
     use rand::distributions::{Alphanumeric, DistString};
     use tempfile::{tempdir, TempDir};
 
-    use crate::ObjectStorage;
+    use futures::stream::BoxStream;
+
+    use crate::{
+        format::{
+            attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+            transaction_log::TransactionLog, AttributesId, ByteRange, ChunkId,
+            ManifestId, Path,
+        },
+        repository::OpenOptions,
+        storage::{ListInfo, ObjectKind, StorageCapabilities, StorageResult},
+        ObjectStorage, Repository,
+    };
 
     use super::*;
 
@@ -428,6 +681,9 @@ mod tests {
                     Ref::Tag("tag2".to_string())
                 ]
             );
+            // refs of other namespaces are not listed, even the ones of newer versions
+            storage.write_ref("future.name/ref.json", false, Bytes::from_static(b"{}")).await?;
+            assert_eq!(list_refs(storage.as_ref()).await?.len(), 3);
 
             // update a branch successfully
             update_branch(
@@ -503,4 +759,254 @@ mod tests {
         res2?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_resolve_refs() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let (s1, s2, s3) =
+            (SnapshotId::random(), SnapshotId::random(), SnapshotId::random());
+        update_branch(&storage, "main", s1.clone(), None, false).await?;
+        update_branch(&storage, "main", s2.clone(), Some(&s1), false).await?;
+        update_branch(&storage, "dev", s1.clone(), None, false).await?;
+        create_tag(&storage, "v1", s3.clone(), false).await?;
+
+        assert_eq!(
+            resolve_refs(&storage, ["main", "dev", "v1"]).await?,
+            HashMap::from([
+                ("main".to_string(), s2.clone()),
+                ("dev".to_string(), s1.clone()),
+                ("v1".to_string(), s3.clone())
+            ])
+        );
+        assert!(matches!(
+            resolve_refs(&storage, ["main", "missing"]).await,
+            Err(RefError::RefNotFound(name)) if name == "missing"
+        ));
+
+        let mut tips = list_ref_tips(&storage).await?;
+        tips.sort_by_key(|(r, _)| format!("{:?}", r));
+        assert_eq!(
+            tips,
+            vec![
+                (Ref::Branch("dev".to_string()), RefData { snapshot: s1 }),
+                (Ref::Branch("main".to_string()), RefData { snapshot: s2 }),
+                (Ref::Tag("v1".to_string()), RefData { snapshot: s3 }),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_branch_if_version() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let (s1, s2, s3) =
+            (SnapshotId::random(), SnapshotId::random(), SnapshotId::random());
+
+        let v0 = update_branch_if_version(&storage, "main", s1.clone(), None).await?;
+        assert!(matches!(
+            update_branch_if_version(&storage, "main", s2.clone(), None).await,
+            Err(RefError::VersionMismatch { expected: None, .. })
+        ));
+        let (token, data) = fetch_branch_tip_versioned(&storage, "main").await?;
+        assert_eq!((&token, &data.snapshot), (&v0, &s1));
+
+        let v1 =
+            update_branch_if_version(&storage, "main", s2.clone(), Some(&token)).await?;
+        assert_eq!(v1, v0.inc());
+        // the token is stale once the branch moved
+        assert!(matches!(
+            update_branch_if_version(&storage, "main", s3.clone(), Some(&token)).await,
+            Err(RefError::VersionMismatch { expected: Some(v), .. }) if v == v0
+        ));
+        // and tokens from the future are rejected
+        assert!(matches!(
+            update_branch_if_version(&storage, "main", s3.clone(), Some(&v1.inc())).await,
+            Err(RefError::VersionMismatch { .. })
+        ));
+        assert_eq!(fetch_branch_tip(&storage, "main").await?.snapshot, s2);
+        Ok(())
+    }
+
+    /// A storage that serves objects but can't list keys, like a static web server
+    #[derive(Debug)]
+    struct UnlistedStorage(Arc<dyn Storage + Send + Sync>);
+
+    impl crate::private::Sealed for UnlistedStorage {}
+
+    #[async_trait::async_trait]
+    impl Storage for UnlistedStorage {
+        async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+            self.0.fetch_snapshot(id).await
+        }
+
+        async fn fetch_attributes(
+            &self,
+            id: &AttributesId,
+        ) -> StorageResult<Arc<AttributesTable>> {
+            self.0.fetch_attributes(id).await
+        }
+
+        async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+            self.0.fetch_manifests(id).await
+        }
+
+        async fn fetch_chunk(
+            &self,
+            id: &ChunkId,
+            range: &ByteRange,
+        ) -> StorageResult<Bytes> {
+            self.0.fetch_chunk(id, range).await
+        }
+
+        async fn fetch_transaction_log(
+            &self,
+            id: &SnapshotId,
+        ) -> StorageResult<Arc<TransactionLog>> {
+            self.0.fetch_transaction_log(id).await
+        }
+
+        async fn write_snapshot(
+            &self,
+            id: SnapshotId,
+            table: Arc<Snapshot>,
+        ) -> StorageResult<()> {
+            self.0.write_snapshot(id, table).await
+        }
+
+        async fn write_snapshot_if_absent(
+            &self,
+            id: SnapshotId,
+            table: Arc<Snapshot>,
+        ) -> StorageResult<()> {
+            self.0.write_snapshot_if_absent(id, table).await
+        }
+
+        async fn write_attributes(
+            &self,
+            id: AttributesId,
+            table: Arc<AttributesTable>,
+        ) -> StorageResult<()> {
+            self.0.write_attributes(id, table).await
+        }
+
+        async fn write_manifests(
+            &self,
+            id: ManifestId,
+            table: Arc<Manifest>,
+        ) -> StorageResult<()> {
+            self.0.write_manifests(id, table).await
+        }
+
+        async fn write_manifests_if_absent(
+            &self,
+            id: ManifestId,
+            table: Arc<Manifest>,
+        ) -> StorageResult<()> {
+            self.0.write_manifests_if_absent(id, table).await
+        }
+
+        async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+            self.0.write_chunk(id, bytes).await
+        }
+
+        async fn write_transaction_log(
+            &self,
+            id: SnapshotId,
+            log: Arc<TransactionLog>,
+        ) -> StorageResult<()> {
+            self.0.write_transaction_log(id, log).await
+        }
+
+        async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+            self.0.get_ref(ref_key).await
+        }
+
+        async fn ref_names(&self) -> StorageResult<Vec<String>> {
+            Err(StorageError::ListingNotSupported)
+        }
+
+        async fn ref_versions(
+            &self,
+            _ref_name: &str,
+        ) -> StorageResult<BoxStream<StorageResult<String>>> {
+            Err(StorageError::ListingNotSupported)
+        }
+
+        async fn write_ref(
+            &self,
+            ref_key: &str,
+            overwrite_refs: bool,
+            bytes: Bytes,
+        ) -> StorageResult<()> {
+            self.0.write_ref(ref_key, overwrite_refs, bytes).await
+        }
+
+        async fn list_objects<'a>(
+            &'a self,
+            _prefix: &str,
+        ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+            Err(StorageError::ListingNotSupported)
+        }
+
+        async fn delete_objects(
+            &self,
+            prefix: &str,
+            ids: BoxStream<'_, String>,
+        ) -> StorageResult<usize> {
+            self.0.delete_objects(prefix, ids).await
+        }
+
+        async fn copy_object(
+            &self,
+            kind: ObjectKind,
+            from_id: &str,
+            to_id: &str,
+        ) -> StorageResult<()> {
+            self.0.copy_object(kind, from_id, to_id).await
+        }
+
+        fn capabilities(&self) -> StorageCapabilities {
+            StorageCapabilities { listing: false, ..self.0.capabilities() }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ref_advertisement() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_ref_advertisement(true)
+            .build();
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit("main", "first", None).await?;
+        ds.tag("v1", &first).await?;
+        ds.new_branch("dev").await?;
+        ds.add_group("/other".try_into()?).await?;
+        let second = ds.commit("main", "second", None).await?;
+        // the advertisement is not a ref
+        assert_eq!(list_refs(storage.as_ref()).await?.len(), 3);
+
+        let unlisted = UnlistedStorage(Arc::clone(&storage));
+        assert_eq!(
+            list_refs(&unlisted).await?,
+            vec![
+                Ref::Branch("dev".to_string()),
+                Ref::Branch("main".to_string()),
+                Ref::Tag("v1".to_string())
+            ]
+        );
+        assert_eq!(fetch_branch_tip(&unlisted, "main").await?.snapshot, second);
+        assert_eq!(fetch_ref(&unlisted, "v1").await?.1.snapshot, first);
+        assert!(matches!(
+            fetch_branch_tip(&unlisted, "missing").await,
+            Err(RefError::RefNotFound(name)) if name == "missing"
+        ));
+        let reader =
+            Repository::open_branch(Arc::new(unlisted), "dev", &OpenOptions::new())
+                .await?
+                .build();
+        assert_eq!(reader.snapshot_id(), &first);
+        Ok(())
+    }
 }
@@ -1,15 +1,46 @@
 use std::{
-    collections::HashSet,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Debug,
     iter::{self},
     mem::take,
-    pin::Pin,
-    sync::Arc,
+    num::NonZeroU32,
+    ops::{Bound, Range},
+    path::PathBuf,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+    time::Duration,
 };
 
+use crate::{
+    annotations::{annotate, list_annotations, AnnotationLabels, SnapshotAnnotations},
+    array_builder::ArrayBuilderError,
+    conflicts::{
+        detector::ConflictDetector, CommitConflicts, Conflict, ConflictResolution,
+        ConflictSolver,
+    },
+    format::{
+        manifest::VirtualReferenceError, snapshot::ManifestFileInfo,
+        transaction_log::TransactionLog, ManifestId, SnapshotId,
+    },
+    metadata::vlen::{min_vlen_chunk_size, validate_vlen, VlenError},
+    storage::virtual_ref::{
+        construct_valid_byte_range, fetch_virtual_chunk,
+        ObjectStoreVirtualChunkResolverConfig, VirtualChunkResolver,
+    },
+};
 pub use crate::{
     change_set::ChangeSet,
+    chunk_transformer::{
+        self, ChunkTransformError, ChunkTransformer, CodecWorkers, DynChunkTransformer,
+    },
+    commit_queue::{
+        enqueue, has_turn, leave_queue, renew_ticket, CommitQueueConfig, QueueTicket,
+    },
     format::{
-        manifest::{ChunkPayload, VirtualChunkLocation},
+        manifest::{ChunkPayload, TransformedPayload, VirtualChunkLocation},
         snapshot::{SnapshotMetadata, ZarrArrayMetadata},
         ChunkIndices, Path,
     },
@@ -18,39 +49,92 @@ pub use crate::{
         DimensionNames, FillValue, StorageTransformer, UserAttributes,
     },
 };
-use crate::{
-    conflicts::{Conflict, ConflictResolution, ConflictSolver},
-    format::{
-        manifest::VirtualReferenceError, snapshot::ManifestFileInfo,
-        transaction_log::TransactionLog, ManifestId, SnapshotId,
-    },
-    storage::virtual_ref::{
-        construct_valid_byte_range, ObjectStoreVirtualChunkResolverConfig,
-        VirtualChunkResolver,
-    },
-};
+use async_stream::try_stream;
 use bytes::Bytes;
-use chrono::Utc;
-use futures::{future::ready, Future, FutureExt, Stream, StreamExt, TryStreamExt};
-use itertools::Either;
+use chrono::TimeDelta;
+use futures::{
+    future::{self, ready, BoxFuture},
+    stream::FuturesOrdered,
+    Future, FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use itertools::{Either, Itertools};
 use thiserror::Error;
+use tokio::sync::Mutex;
 
 use crate::{
+    authorization::{default_authorizer, DynAuthorizer, Operation},
+    chunk_dictionary::{
+        dictionary_transformer_id, write_dictionary, DictionaryCache,
+        DictionaryCompressor, DynDictionaryCompressor,
+    },
+    chunk_packer::ChunkPacker,
+    claims::{list_claims, ClaimError},
+    commit_hooks::{
+        discard_pending_hooks, record_pending_hooks, run_hooks, run_pending_hooks,
+        DynCommitHook, HookFailure, PendingHooks,
+    },
+    conventions::{check_conventions, ArrayConventions, ConventionViolation},
+    events::{default_event_observer, DynEventObserver, Event, EventRecord},
     format::{
+        attributes::AttributesTable,
+        format_constants,
+        hashing::HashAlgorithm,
         manifest::{
             ChunkInfo, ChunkRef, Manifest, ManifestExtents, ManifestRef, VirtualChunkRef,
         },
+        manifest_columns::COLUMNAR_MANIFEST_FORMAT,
         snapshot::{
-            NodeData, NodeSnapshot, NodeType, Snapshot, SnapshotProperties,
-            UserAttributesSnapshot,
+            AttributeFileInfo, CustomNodeData, NodeData, NodeSnapshot, NodeType,
+            Snapshot, SnapshotProperties, UserAttributesRef, UserAttributesSnapshot,
+        },
+        AttributesId, ByteRange, ChunkId, IcechunkFormatError, IcechunkFormatVersion,
+        NodeId, TableOffset,
+    },
+    ingest::{
+        Checkpoint, ChunkWrite, CoalescingConfig, CommitTrigger, IngestConfig,
+        IngestProgress, StreamingCommit,
+    },
+    memory::MemoryBudget,
+    metrics::{MeteredStorage, SessionCounters, SessionMetrics},
+    ops::{
+        ancestry::{AncestryIndex, CommonAncestor},
+        attributes_index::{AttributesIndex, ATTRIBUTES_INDEX_PROPERTY},
+        content_hash::{
+            snapshot_content_hash, snapshot_root_hash, ContentHash,
+            CONTENT_HASH_ALGORITHM_PROPERTY, CONTENT_HASH_PROPERTY,
+        },
+        documents::{DocumentRef, DocumentTable, DOCUMENTS_PROPERTY},
+        health::{health_report, HealthConfig, HealthError, HealthReport},
+        inspect::{inspect_object, InspectedObject},
+        lineage::{Lineage, LINEAGE_PROPERTY},
+        retention::{
+            apply_retention, expired_snapshots, RetentionError, RetentionRule,
+            RetentionSummary,
         },
-        ByteRange, IcechunkFormatError, NodeId, ObjectId,
     },
+    pins::{pin_snapshot, SnapshotPin},
+    progress::{default_progress_observer, DynProgressObserver, Phase, Progress},
     refs::{
-        create_tag, fetch_branch_tip, fetch_tag, update_branch, BranchVersion, Ref,
-        RefError,
+        advertise_refs, create_tag, delete_branch, fetch_branch_tip,
+        fetch_branch_tip_versioned, fetch_tag, resolve_refs, update_branch,
+        update_branch_if_version, BranchVersion, Ref, RefError,
+    },
+    runtime::{default_runtime, spawn, DynRuntime, Runtime},
+    schema::{check_schema, SchemaConstraint, SchemaRule, SchemaViolation},
+    sources::Sources,
+    storage::{
+        s3::S3Config, virtual_ref::ObjectStoreVirtualChunkResolver, CacheConfig,
+        PresignedUrl, StorageErrorKind, StorageResult,
     },
-    storage::virtual_ref::ObjectStoreVirtualChunkResolver,
+    template::{default_branch, write_settings, RepositorySettings, RepositoryTemplate},
+    trash::{
+        delete_trash_entry, fetch_trash_entry, put_in_trash, trash_branch, TrashEntry,
+        TrashedItem,
+    },
+    tunables::TunablesHandle,
+    view::SnapshotView,
+    write_buffer::{ChunkWriteBuffer, WriteBufferError},
+    zarr::StorageConfig,
     MemCachingStorage, Storage, StorageError,
 };
 
@@ -63,23 +147,193 @@ pub struct RepositoryConfig {
     // the possibility of race conditions if this variable is set to true and there are concurrent
     // commit attempts.
     pub unsafe_overwrite_refs: bool,
+    // Chunks smaller than this, that are not stored inline, are packed together in chunk
+    // objects of around `chunk_pack_size_bytes`. Zero disables packing.
+    pub chunk_packing_threshold_bytes: u64,
+    pub chunk_pack_size_bytes: u64,
+    // Store the content hash of every new snapshot in its properties, under
+    // `CONTENT_HASH_PROPERTY`. Flushing reads back all chunks written by the snapshot.
+    pub record_content_hash: bool,
+    // The algorithm of the recorded content hashes
+    pub hash_algorithm: HashAlgorithm,
+    // Checked on every commit, commits that break them fail with `SchemaViolations`
+    pub schema_constraints: Vec<SchemaConstraint>,
+    // Deleted branches and arrays stay in the trash for this long before they can be purged
+    pub trash_retention: TimeDelta,
+    // Applied by `Repository::apply_retention`, with no rules snapshots never expire
+    pub retention_rules: Vec<RetentionRule>,
+    // Top level attribute keys recorded in the `ATTRIBUTES_INDEX_PROPERTY` of every new
+    // snapshot, empty disables the index
+    pub indexed_attributes: Vec<String>,
+    // Applied in order to the chunks written, and used to decode the chunks read
+    pub chunk_transformers: Vec<DynChunkTransformer>,
+    // Decode and train the chunk dictionaries of `crate::chunk_dictionary`
+    pub dictionary_compressors: Vec<DynDictionaryCompressor>,
+    // User attributes larger than this are stored in an attributes file, instead of the
+    // snapshot, and loaded on demand. Zero keeps all attributes in the snapshot.
+    pub attributes_split_threshold_bytes: u64,
+    // The chunk transformers run in at most this many threads at a time, zero means one per
+    // CPU
+    pub codec_threads: usize,
+    // Write the `refs::RefAdvertisement` after every ref update of the session, for readers
+    // of storages that can't list keys
+    pub advertise_refs: bool,
+    // The arrays whose manifest is split in partitions, see `ManifestPartitioning`
+    pub manifest_partitions: HashMap<Path, ManifestPartitioning>,
+    // The manifest of a commit is split in manifests of about this size, when it's larger.
+    // Zero disables it, manifests are still split if the backend rejects them for their size.
+    pub manifest_split_threshold_bytes: u64,
+    // Write new manifests in the columnar layout of `format::manifest_columns`. Readers
+    // older than that format can't read them, so it's opt-in.
+    pub columnar_manifests: bool,
+    // Operations that walk the history of a snapshot, like `ancestry`, `rebase` and
+    // `common_ancestor`, fail with `HistoryTooDeep` after this many ancestors. Zero means no
+    // limit.
+    pub max_history_depth: usize,
+    // Run in order after every commit updates its branch, see `commit_hooks`
+    pub commit_hooks: Vec<DynCommitHook>,
+}
+
+impl RepositoryConfig {
+    /// The format version of the manifests written with this config
+    pub fn manifest_format_version(&self) -> IcechunkFormatVersion {
+        if self.columnar_manifests {
+            COLUMNAR_MANIFEST_FORMAT
+        } else {
+            format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT
+        }
+    }
 }
 
 impl Default for RepositoryConfig {
     fn default() -> Self {
-        Self { inline_chunk_threshold_bytes: 512, unsafe_overwrite_refs: false }
+        Self {
+            inline_chunk_threshold_bytes: 512,
+            unsafe_overwrite_refs: false,
+            chunk_packing_threshold_bytes: 0,
+            chunk_pack_size_bytes: 8 * 1024 * 1024,
+            record_content_hash: false,
+            hash_algorithm: HashAlgorithm::default(),
+            schema_constraints: Vec::new(),
+            trash_retention: TimeDelta::days(7),
+            retention_rules: Vec::new(),
+            indexed_attributes: Vec::new(),
+            chunk_transformers: Vec::new(),
+            dictionary_compressors: Vec::new(),
+            attributes_split_threshold_bytes: 0,
+            codec_threads: 0,
+            advertise_refs: false,
+            manifest_partitions: HashMap::new(),
+            manifest_split_threshold_bytes: 0,
+            columnar_manifests: false,
+            max_history_depth: 0,
+            commit_hooks: Vec::new(),
+        }
+    }
+}
+
+/// Splits the manifest of an array in partitions of `chunks_per_partition` chunks along
+/// `dimension`, usually time
+///
+/// Commits only write the partitions with chunk changes, the rest keep the manifest of the
+/// parent, and reads only fetch the partition of the chunk. Appends roll over to a new
+/// partition when they cross the end of the last one. Arrays with fewer dimensions are not
+/// partitioned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ManifestPartitioning {
+    pub dimension: usize,
+    pub chunks_per_partition: NonZeroU32,
+}
+
+impl ManifestPartitioning {
+    pub fn new(dimension: usize, chunks_per_partition: NonZeroU32) -> Self {
+        Self { dimension, chunks_per_partition }
+    }
+
+    /// The partition of the chunk at `coords`
+    pub fn partition(&self, coords: &ChunkIndices) -> Option<u64> {
+        let size = u64::from(self.chunks_per_partition.get());
+        coords.0.get(self.dimension).map(|coord| *coord / size)
+    }
+
+    /// The extents of the manifest of `partition`, in an array with `ndim` dimensions
+    pub fn extents(&self, ndim: usize, partition: u64) -> ManifestExtents {
+        let size = u64::from(self.chunks_per_partition.get());
+        let mut from = vec![0; ndim];
+        let mut to = vec![u64::MAX; ndim];
+        if let (Some(from), Some(to)) =
+            (from.get_mut(self.dimension), to.get_mut(self.dimension))
+        {
+            *from = partition.saturating_mul(size);
+            *to = partition.saturating_add(1).saturating_mul(size);
+        }
+        ManifestExtents::new(ChunkIndices(from), ChunkIndices(to))
     }
 }
 
+/// Manifests and attributes files written at the same time by a flush
+const METADATA_WRITE_CONCURRENCY: usize = 8;
+/// Pending hooks of snapshots not in their branch are dropped after this long
+const PENDING_HOOKS_GRACE: TimeDelta = TimeDelta::hours(1);
+
+/// The snapshot property that stores the key passed to [`Repository::commit_idempotent`]
+pub const IDEMPOTENCY_KEY_PROPERTY: &str = "icechunk.idempotency_key";
+/// The snapshot properties Icechunk records start with it
+const RESERVED_PROPERTY_PREFIX: &str = "icechunk.";
+/// The reserved properties every flush computes from the new snapshot
+const FLUSHED_PROPERTIES: [&str; 4] = [
+    CONTENT_HASH_PROPERTY,
+    CONTENT_HASH_ALGORITHM_PROPERTY,
+    ATTRIBUTES_INDEX_PROPERTY,
+    DOCUMENTS_PROPERTY,
+];
+
+/// The root of the scratch area of sessions, see [`Repository::scratch_path`]
+pub const SCRATCH_PATH: &str = "/.scratch";
+
 #[derive(Debug)]
 pub struct Repository {
     config: RepositoryConfig,
+    /// Counts the requests of the session into `counters`
     storage: Arc<dyn Storage + Send + Sync>,
+    /// The storage the session was built with
+    backend: Arc<dyn Storage + Send + Sync>,
+    counters: Arc<SessionCounters>,
     snapshot_id: SnapshotId,
     change_set: ChangeSet,
     virtual_resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
+    /// Chunks uploaded by this session, with the ids of the transformers applied to them
+    written_chunks: Arc<Mutex<HashMap<ChunkId, Vec<String>>>>,
+    /// Transformed chunks referenced by this session but written before it, with the ids of
+    /// their transformers. They are never deleted as superseded.
+    copied_chunks: HashMap<ChunkId, Vec<String>>,
+    /// Chunks uploaded by this session, that were later replaced by a new write to the same
+    /// coordinates. They are deleted on flush.
+    superseded_chunks: HashSet<ChunkId>,
+    write_buffer: Option<Arc<ChunkWriteBuffer>>,
+    chunk_packer: Arc<ChunkPacker>,
+    codec_workers: CodecWorkers,
+    /// Where to wait before retrying writes
+    runtime: DynRuntime,
+    /// Shared with the sessions made by `read_only_at`
+    dictionaries: Arc<DictionaryCache>,
+    node_kinds: NodeKinds,
+    sources: Sources,
+    authorizer: DynAuthorizer,
+    progress: DynProgressObserver,
+    events: DynEventObserver,
+    tunables: TunablesHandle,
 }
 
+/// Validates the metadata of custom nodes of a given kind, see [`CustomNodeData`].
+pub trait NodeKindSchema: Debug {
+    /// Returns a description of the problem if `metadata` is invalid
+    fn validate(&self, metadata: &[u8]) -> Result<(), String>;
+}
+
+/// The custom node kinds allowed in a repository, with their schemas
+pub type NodeKinds = HashMap<String, Arc<dyn NodeKindSchema + Send + Sync>>;
+
 #[derive(Debug, Clone)]
 pub struct RepositoryBuilder {
     config: RepositoryConfig,
@@ -87,6 +341,15 @@ pub struct RepositoryBuilder {
     snapshot_id: SnapshotId,
     change_set: Option<ChangeSet>,
     virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+    node_kinds: NodeKinds,
+    write_buffer: Option<(PathBuf, u64)>,
+    runtime: DynRuntime,
+    memory_budget: Option<MemoryBudget>,
+    sources: Sources,
+    authorizer: DynAuthorizer,
+    progress: DynProgressObserver,
+    events: DynEventObserver,
+    tunables: TunablesHandle,
 }
 
 impl RepositoryBuilder {
@@ -97,6 +360,15 @@ impl RepositoryBuilder {
             storage,
             change_set: None,
             virtual_ref_config: None,
+            node_kinds: NodeKinds::new(),
+            write_buffer: None,
+            runtime: default_runtime(),
+            memory_budget: None,
+            sources: Sources::default(),
+            authorizer: default_authorizer(),
+            progress: default_progress_observer(),
+            events: default_event_observer(),
+            tunables: TunablesHandle::default(),
         }
     }
 
@@ -110,6 +382,130 @@ impl RepositoryBuilder {
         self
     }
 
+    /// Pack chunks smaller than `threshold_bytes` into chunk objects of `pack_size_bytes`
+    pub fn with_chunk_packing(
+        &mut self,
+        threshold_bytes: u64,
+        pack_size_bytes: u64,
+    ) -> &mut Self {
+        self.config.chunk_packing_threshold_bytes = threshold_bytes;
+        self.config.chunk_pack_size_bytes = pack_size_bytes;
+        self
+    }
+
+    /// Record the [`ContentHash`](crate::ops::content_hash::ContentHash) of every new snapshot in its properties
+    pub fn with_record_content_hash(&mut self, value: bool) -> &mut Self {
+        self.config.record_content_hash = value;
+        self
+    }
+
+    /// Hash the recorded content hashes with `algorithm`, SHA-256 by default
+    pub fn with_hash_algorithm(&mut self, algorithm: HashAlgorithm) -> &mut Self {
+        self.config.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Store user attributes larger than `threshold_bytes` outside the snapshot, see
+    /// [`Repository::get_user_attributes`]
+    pub fn with_attributes_split_threshold_bytes(
+        &mut self,
+        threshold_bytes: u64,
+    ) -> &mut Self {
+        self.config.attributes_split_threshold_bytes = threshold_bytes;
+        self
+    }
+
+    /// Index the attribute `key` of every node, see [`crate::ops::attributes_index`]
+    pub fn with_indexed_attribute(&mut self, key: impl Into<String>) -> &mut Self {
+        self.config.indexed_attributes.push(key.into());
+        self
+    }
+
+    /// Transform the chunks on write, after the transformers already added, see
+    /// [`crate::chunk_transformer`]
+    pub fn with_chunk_transformer(
+        &mut self,
+        transformer: impl ChunkTransformer + 'static,
+    ) -> &mut Self {
+        self.config.chunk_transformers.push(Arc::new(transformer));
+        self
+    }
+
+    /// Train, and decode chunks with, dictionaries of `compressor`, see
+    /// [`crate::chunk_dictionary`]
+    pub fn with_dictionary_compressor(
+        &mut self,
+        compressor: impl DictionaryCompressor + 'static,
+    ) -> &mut Self {
+        self.config.dictionary_compressors.push(Arc::new(compressor));
+        self
+    }
+
+    /// Run the chunk transformers in at most `threads` threads at a time, see
+    /// [`chunk_transformer::CodecWorkers`]. Zero, the default, means one per CPU.
+    pub fn with_codec_threads(&mut self, threads: usize) -> &mut Self {
+        self.config.codec_threads = threads;
+        self
+    }
+
+    /// Write the [`crate::refs::RefAdvertisement`] after every ref update, see
+    /// [`crate::refs::advertise_refs`]
+    pub fn with_ref_advertisement(&mut self, value: bool) -> &mut Self {
+        self.config.advertise_refs = value;
+        self
+    }
+
+    /// Split the manifest of the array at `path`, see [`ManifestPartitioning`]
+    pub fn with_manifest_partitioning(
+        &mut self,
+        path: Path,
+        partitioning: ManifestPartitioning,
+    ) -> &mut Self {
+        self.config.manifest_partitions.insert(path, partitioning);
+        self
+    }
+
+    /// Split the manifests of commits larger than `threshold_bytes`, see
+    /// [`RepositoryConfig::manifest_split_threshold_bytes`]
+    pub fn with_manifest_split_threshold_bytes(
+        &mut self,
+        threshold_bytes: u64,
+    ) -> &mut Self {
+        self.config.manifest_split_threshold_bytes = threshold_bytes;
+        self
+    }
+
+    /// Write new manifests in the columnar layout, see
+    /// [`RepositoryConfig::columnar_manifests`]
+    pub fn with_columnar_manifests(&mut self, enabled: bool) -> &mut Self {
+        self.config.columnar_manifests = enabled;
+        self
+    }
+
+    /// Fail history walks after `depth` ancestors, see
+    /// [`RepositoryConfig::max_history_depth`]
+    pub fn with_max_history_depth(&mut self, depth: usize) -> &mut Self {
+        self.config.max_history_depth = depth;
+        self
+    }
+
+    pub fn with_schema_constraint(&mut self, constraint: SchemaConstraint) -> &mut Self {
+        self.config.schema_constraints.push(constraint);
+        self
+    }
+
+    pub fn with_trash_retention(&mut self, retention: TimeDelta) -> &mut Self {
+        self.config.trash_retention = retention;
+        self
+    }
+
+    /// Keep the snapshots `rule` selects when applying retention, see
+    /// [`Repository::apply_retention`]
+    pub fn with_retention_rule(&mut self, rule: RetentionRule) -> &mut Self {
+        self.config.retention_rules.push(rule);
+        self
+    }
+
     pub fn with_config(&mut self, config: RepositoryConfig) -> &mut Self {
         self.config = config;
         self
@@ -128,14 +524,132 @@ impl RepositoryBuilder {
         self
     }
 
+    /// Allow adding custom nodes of type `kind`, their metadata is validated with `schema`
+    pub fn with_node_kind(
+        &mut self,
+        kind: impl Into<String>,
+        schema: Arc<dyn NodeKindSchema + Send + Sync>,
+    ) -> &mut Self {
+        self.node_kinds.insert(kind.into(), schema);
+        self
+    }
+
+    /// Hold the chunks written by the session until commit, instead of uploading them as
+    /// they are written
+    ///
+    /// Up to `memory_limit_bytes` of chunks are kept in memory, the rest are written to
+    /// files in `spill_dir`. See [`ChunkWriteBuffer`].
+    pub fn with_chunk_write_buffer(
+        &mut self,
+        spill_dir: impl Into<PathBuf>,
+        memory_limit_bytes: u64,
+    ) -> &mut Self {
+        self.write_buffer = Some((spill_dir.into(), memory_limit_bytes));
+        self
+    }
+
+    /// Account the memory used by the chunk write buffer in `budget`
+    ///
+    /// To share the budget with the caches, wrap the storage in a [`MemCachingStorage`]
+    /// created with [`MemCachingStorage::with_memory_budget`].
+    pub fn with_memory_budget(&mut self, budget: MemoryBudget) -> &mut Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// The clock and id source for the snapshots, nodes and chunks created by the session
+    ///
+    /// Use [`Sources::deterministic`] for reproducible tests. To also make the initial
+    /// snapshot reproducible, create the repository with [`Repository::init_with_sources`].
+    pub fn with_sources(&mut self, sources: Sources) -> &mut Self {
+        self.sources = sources;
+        self
+    }
+
+    /// The runtime for the background and blocking tasks of the session, and to wait before
+    /// retries, tokio by default
+    pub fn with_runtime(&mut self, runtime: DynRuntime) -> &mut Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Consult `authorizer` before the reads, writes and ref updates of the session
+    pub fn with_authorizer(&mut self, authorizer: DynAuthorizer) -> &mut Self {
+        self.authorizer = authorizer;
+        self
+    }
+
+    /// Report the progress of commits to `observer`
+    pub fn with_progress_observer(&mut self, observer: DynProgressObserver) -> &mut Self {
+        self.progress = observer;
+        self
+    }
+
+    /// Report commits and conflicts to `observer`
+    pub fn with_event_observer(&mut self, observer: DynEventObserver) -> &mut Self {
+        self.events = observer;
+        self
+    }
+
+    /// Follow the settings in `tunables`, which can change while the session is in use, see
+    /// [`crate::tunables`]
+    pub fn with_tunables(&mut self, tunables: TunablesHandle) -> &mut Self {
+        self.tunables = tunables;
+        self
+    }
+
+    /// Run `hook` after every commit of the session, see [`crate::commit_hooks`]
+    pub fn with_commit_hook(&mut self, hook: DynCommitHook) -> &mut Self {
+        self.config.commit_hooks.push(hook);
+        self
+    }
+
     pub fn build(&self) -> Repository {
-        Repository::new(
-            self.config.clone(),
-            self.storage.clone(),
-            self.snapshot_id.clone(),
-            self.change_set.clone(),
-            self.virtual_ref_config.clone(),
-        )
+        let write_buffer =
+            self.write_buffer.as_ref().map(|(spill_dir, memory_limit_bytes)| {
+                let buffer =
+                    ChunkWriteBuffer::new(spill_dir.clone(), *memory_limit_bytes)
+                        .with_runtime(Arc::clone(&self.runtime));
+                match &self.memory_budget {
+                    Some(budget) => Arc::new(buffer.with_memory_budget(budget)),
+                    None => Arc::new(buffer),
+                }
+            });
+        let counters = Arc::new(SessionCounters::new(&self.storage));
+        Repository {
+            config: self.config.clone(),
+            storage: Arc::new(MeteredStorage::new(
+                self.storage.clone(),
+                Arc::clone(&counters),
+            )),
+            backend: self.storage.clone(),
+            counters,
+            snapshot_id: self.snapshot_id.clone(),
+            change_set: self.change_set.clone().unwrap_or_default(),
+            virtual_resolver: Arc::new(ObjectStoreVirtualChunkResolver::new(
+                self.virtual_ref_config.clone(),
+            )),
+            written_chunks: Default::default(),
+            copied_chunks: Default::default(),
+            superseded_chunks: Default::default(),
+            write_buffer,
+            chunk_packer: Arc::new(
+                ChunkPacker::new(self.config.chunk_pack_size_bytes)
+                    .with_sources(self.sources.clone()),
+            ),
+            codec_workers: CodecWorkers::new(
+                Arc::clone(&self.runtime),
+                self.config.codec_threads,
+            ),
+            runtime: Arc::clone(&self.runtime),
+            dictionaries: Default::default(),
+            node_kinds: self.node_kinds.clone(),
+            sources: self.sources.clone(),
+            authorizer: Arc::clone(&self.authorizer),
+            progress: Arc::clone(&self.progress),
+            events: Arc::clone(&self.events),
+            tunables: self.tunables.clone(),
+        }
     }
 }
 
@@ -154,6 +668,12 @@ pub enum RepositoryError {
     NotAnArray { node: NodeSnapshot, message: String },
     #[error("there is not a group at `{node:?}`: {message}")]
     NotAGroup { node: NodeSnapshot, message: String },
+    #[error("there is not a custom node at `{node:?}`: {message}")]
+    NotACustomNode { node: NodeSnapshot, message: String },
+    #[error("unknown custom node kind `{0}`")]
+    UnknownNodeKind(String),
+    #[error("invalid metadata for custom node of kind `{kind}`: {message}")]
+    InvalidCustomNode { kind: String, message: String },
     #[error("node already exists at `{node:?}`: {message}")]
     AlreadyExists { node: NodeSnapshot, message: String },
     #[error("cannot commit, no changes made to the repository")]
@@ -170,6 +690,10 @@ pub enum RepositoryError {
     RebaseFailed { snapshot: SnapshotId, conflicts: Vec<Conflict> },
     #[error("the repository has been initialized already (default branch exists)")]
     AlreadyInitialized,
+    #[error("invalid repository template: {0}")]
+    InvalidTemplate(String),
+    #[error("invalid repository URL: {0}")]
+    InvalidUrl(String),
     #[error("error when handling virtual reference {0}")]
     VirtualReferenceError(#[from] VirtualReferenceError),
     #[error("error in repository serialization `{0}`")]
@@ -178,10 +702,276 @@ pub enum RepositoryError {
     DeserializationError(#[from] rmp_serde::decode::Error),
     #[error("error finding conflicting path for node `{0}`, this probably indicades a bug in `rebase`")]
     ConflictingPathNotFound(NodeId),
+    #[error(
+        "chunk `{coords:?}` of array `{path}` has {found} bytes, expected {expected}"
+    )]
+    InvalidChunkSize { path: Path, coords: ChunkIndices, expected: u64, found: u64 },
+    #[error("chunk `{coords:?}` of array `{path}` is not a valid variable length chunk: {source}")]
+    InvalidVlenChunk { path: Path, coords: ChunkIndices, source: VlenError },
+    #[error("error buffering chunks {0}")]
+    WriteBuffer(#[from] WriteBufferError),
+    #[error("chunk claim error: {0}")]
+    Claim(#[from] ClaimError),
+    #[error("retention error: {0}")]
+    Retention(#[from] RetentionError),
+    #[error("commit breaks the schema constraints: {0:?}")]
+    SchemaViolations(Vec<SchemaViolation>),
+    #[error("attributes don't follow the conventions: {0:?}")]
+    InvalidConventions(ConventionViolation),
+    #[error("invalid lineage in snapshot `{snapshot}`: {message}")]
+    InvalidLineage { snapshot: SnapshotId, message: String },
+    #[error("invalid attributes index in snapshot `{snapshot}`: {message}")]
+    InvalidAttributesIndex { snapshot: SnapshotId, message: String },
+    #[error("invalid documents table in snapshot `{snapshot}`: {message}")]
+    InvalidDocuments { snapshot: SnapshotId, message: String },
+    #[error("document `{name}` not found in group `{path}`")]
+    DocumentNotFound { path: Path, name: String },
+    #[error("operation not authorized: {0}")]
+    Unauthorized(String),
+    #[error("invalid page token `{0}`")]
+    InvalidPageToken(String),
+    #[error("timed out waiting in the commit queue of branch `{0}`")]
+    CommitQueueTimeout(String),
+    #[error("chunk transformation error: {0}")]
+    ChunkTransform(#[from] ChunkTransformError),
+    #[error("region `{region:?}` doesn't match the {dimensions} dimensions of `{path}`")]
+    InvalidChunkRegion { path: Path, region: Vec<Range<u64>>, dimensions: usize },
+    #[error("health report error: {0}")]
+    Health(#[from] HealthError),
+    #[error("invalid object id `{0}`")]
+    InvalidObjectId(String),
+    #[error("the history of snapshot `{snapshot}` is deeper than the limit of {limit} ancestors")]
+    HistoryTooDeep { snapshot: SnapshotId, limit: usize },
+    #[error("cannot amend the commit: {0}")]
+    InvalidAmend(String),
+    #[error("snapshot `{snapshot}` has root hash {actual}, expected {expected}")]
+    RootHashMismatch { snapshot: SnapshotId, expected: ContentHash, actual: ContentHash },
+    #[error("invalid array: {0}")]
+    InvalidArray(#[from] ArrayBuilderError),
+    #[error("patch format version {found} is not supported, the supported version is {supported}")]
+    UnsupportedPatchVersion { found: u16, supported: u16 },
+}
+
+impl RepositoryError {
+    /// The operation that failed may succeed if it's retried as is
+    ///
+    /// Conflicts are not retryable, the session must be rebased first.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RepositoryError::StorageError(err) => err.is_retryable(),
+            RepositoryError::Ref(err) => err.is_retryable(),
+            RepositoryError::WriteBuffer(WriteBufferError::Storage(err)) => {
+                err.is_retryable()
+            }
+            _ => false,
+        }
+    }
 }
 
 pub type RepositoryResult<T> = Result<T, RepositoryError>;
 
+/// How a client can fetch a chunk without repository credentials
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PresignedChunk {
+    Url(PresignedUrl),
+    /// Inline chunks are stored in the manifest, there is no object to presign
+    Inline(Bytes),
+}
+
+/// Why [`Repository::get_chunks_partial`] couldn't read a chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkReadErrorKind {
+    /// The manifest references an object that is not in storage
+    Missing,
+    /// The object doesn't decode, or doesn't match its checksum
+    Corrupted,
+    /// The storage asked to slow down, the chunk can be read again later
+    Throttled,
+    Other,
+}
+
+impl ChunkReadErrorKind {
+    pub fn of(err: &RepositoryError) -> Self {
+        match err {
+            RepositoryError::StorageError(err)
+            | RepositoryError::WriteBuffer(WriteBufferError::Storage(err)) => {
+                Self::of_storage(err)
+            }
+            RepositoryError::ChunkTransform(ChunkTransformError::Failed { .. }) => {
+                Self::Corrupted
+            }
+            _ => Self::Other,
+        }
+    }
+
+    fn of_storage(err: &StorageError) -> Self {
+        match err {
+            StorageError::Shared(err) => Self::of_storage(err),
+            StorageError::ChunkCorrupted(..) | StorageError::Framing(_) => {
+                Self::Corrupted
+            }
+            err => match err.kind() {
+                StorageErrorKind::NotFound => Self::Missing,
+                StorageErrorKind::Throttled => Self::Throttled,
+                _ => Self::Other,
+            },
+        }
+    }
+}
+
+/// A chunk of a partial read that failed
+#[derive(Debug)]
+pub struct ChunkReadError {
+    pub coords: ChunkIndices,
+    pub kind: ChunkReadErrorKind,
+    pub error: RepositoryError,
+}
+
+/// The result of [`Repository::get_chunks_partial`]
+#[derive(Debug, Default)]
+pub struct PartialChunks {
+    /// The chunks read, in the order requested, `None` for chunks that were never written
+    pub chunks: Vec<(ChunkIndices, Option<Bytes>)>,
+    /// The chunks that failed, in the order requested
+    pub errors: Vec<ChunkReadError>,
+}
+
+impl PartialChunks {
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A summary of the changes done in a session, and not yet committed
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SessionStatus {
+    pub new_groups: BTreeSet<Path>,
+    pub new_arrays: BTreeSet<Path>,
+    /// Arrays that had their Zarr metadata updated
+    pub updated_arrays: BTreeSet<Path>,
+    /// Groups or arrays that had their user attributes updated
+    pub updated_attributes: BTreeSet<Path>,
+    pub deleted_groups: BTreeSet<Path>,
+    pub deleted_arrays: BTreeSet<Path>,
+    /// Number of chunks written, per array
+    pub written_chunks: BTreeMap<Path, u64>,
+    /// Number of chunks deleted, per array
+    pub deleted_chunks: BTreeMap<Path, u64>,
+    /// Size of the materialized chunks written, these are already uploaded
+    pub uploaded_chunk_bytes: u64,
+    /// Size of the inline chunks written, they will be uploaded in manifests on commit
+    pub inline_chunk_bytes: u64,
+    /// Size of the virtual chunk references written
+    pub virtual_chunk_bytes: u64,
+}
+
+/// The chunks of an array that changed after a snapshot, see
+/// [`Repository::chunks_changed_since`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ChangedChunks {
+    pub written: BTreeSet<ChunkIndices>,
+    pub deleted: BTreeSet<ChunkIndices>,
+}
+
+/// A snapshot written, but not yet in its branch, see [`Repository::prepare_commit`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedCommit {
+    pub branch: String,
+    /// The tip of the branch when the commit was prepared, `None` for a new branch
+    pub parent: Option<SnapshotId>,
+    pub snapshot: SnapshotId,
+}
+
+/// Which nodes [`Repository::list_nodes_filtered`] returns
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeListing {
+    /// Only the node at this path and its descendants
+    pub prefix: Option<Path>,
+    /// Only the nodes at most this many levels below the prefix, or the root
+    pub max_depth: Option<usize>,
+    /// Only the nodes of these kinds, all kinds if empty
+    pub kinds: Vec<NodeType>,
+    /// Continue after the page that returned this token
+    pub page_token: Option<String>,
+}
+
+impl NodeListing {
+    pub fn with_prefix(mut self, prefix: Path) -> Self {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_kind(mut self, kind: NodeType) -> Self {
+        self.kinds.push(kind);
+        self
+    }
+
+    pub fn with_page_token(mut self, token: impl Into<String>) -> Self {
+        self.page_token = Some(token.into());
+        self
+    }
+}
+
+/// A page of nodes, see [`Repository::list_nodes_page`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodePage {
+    pub nodes: Vec<NodeSnapshot>,
+    /// Pass it to [`NodeListing::with_page_token`] to get the next page, `None` if this is
+    /// the last one
+    pub next_page_token: Option<String>,
+}
+
+/// How [`Repository::open_branch`], [`Repository::open_tag`] and
+/// [`Repository::open_snapshot`] open a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    max_staleness: Option<Duration>,
+    root_hash: Option<(ContentHash, HashAlgorithm)>,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept a branch tip resolved up to `window` ago instead of fetching the ref
+    ///
+    /// Read replicas opening many sessions save a ref request per session. The tips are
+    /// cached by the storage, see [`Storage::branch_tip_cache`]; storages without a cache
+    /// always fetch the ref. Commits made by other processes within the window are not
+    /// seen by the new sessions.
+    pub fn tolerate_staleness(mut self, window: Duration) -> Self {
+        self.max_staleness = Some(window);
+        self
+    }
+
+    /// Fail with [`RepositoryError::RootHashMismatch`] unless the snapshot opened has the
+    /// root hash `hash`, see [`snapshot_root_hash`]
+    ///
+    /// Consumers that pinned a tag or snapshot detect a tampered bucket before reading any
+    /// chunk. Opening verifies the snapshot and its manifests, the chunks read later are
+    /// not verified.
+    pub fn expect_root_hash(
+        mut self,
+        hash: ContentHash,
+        algorithm: HashAlgorithm,
+    ) -> Self {
+        self.root_hash = Some((hash, algorithm));
+        self
+    }
+}
+
+impl SessionStatus {
+    pub fn is_empty(&self) -> bool {
+        self == &SessionStatus::default()
+    }
+}
+
 // FIXME: what do we want to do with implicit groups?
 //
 impl Repository {
@@ -197,9 +987,75 @@ impl Repository {
         branch_name: &str,
     ) -> RepositoryResult<RepositoryBuilder> {
         let snapshot_id = fetch_branch_tip(storage.as_ref(), branch_name).await?.snapshot;
+        if let Some(tips) = storage.branch_tip_cache() {
+            tips.insert(branch_name, snapshot_id.clone());
+        }
+        Ok(Self::update(storage, snapshot_id))
+    }
+
+    /// Open the tip of a branch, like [`Repository::from_branch_tip`] with `options`
+    pub async fn open_branch(
+        storage: Arc<dyn Storage + Send + Sync>,
+        branch_name: &str,
+        options: &OpenOptions,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        let builder = match (options.max_staleness, storage.branch_tip_cache()) {
+            (Some(window), Some(tips)) => {
+                let snapshot_id =
+                    tips.fetch(storage.as_ref(), branch_name, window).await?;
+                Ok(Self::update(storage, snapshot_id))
+            }
+            _ => Self::from_branch_tip(storage, branch_name).await,
+        }?;
+        verify_root_hash(builder.storage.as_ref(), &builder.snapshot_id, options).await?;
+        Ok(builder)
+    }
+
+    /// Open the snapshot of a tag, like [`Repository::from_tag`] with `options`
+    pub async fn open_tag(
+        storage: Arc<dyn Storage + Send + Sync>,
+        tag_name: &str,
+        options: &OpenOptions,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        let builder = Self::from_tag(storage, tag_name).await?;
+        verify_root_hash(builder.storage.as_ref(), &builder.snapshot_id, options).await?;
+        Ok(builder)
+    }
+
+    /// Open a snapshot, like [`Repository::update`] with `options`
+    pub async fn open_snapshot(
+        storage: Arc<dyn Storage + Send + Sync>,
+        snapshot_id: SnapshotId,
+        options: &OpenOptions,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        verify_root_hash(storage.as_ref(), &snapshot_id, options).await?;
         Ok(Self::update(storage, snapshot_id))
     }
 
+    /// Open the default branch of the repository at `url`
+    ///
+    /// See [`StorageConfig::from_url`] for the supported URLs. The storage is wrapped in the
+    /// default in-memory cache.
+    pub async fn from_url(
+        url: &str,
+        s3_config: Option<S3Config>,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        let storage = StorageConfig::from_url(url, s3_config)
+            .map_err(RepositoryError::InvalidUrl)?
+            .make_cached_storage()
+            .await
+            .map_err(StorageError::Other)?;
+        Self::from_default_branch(storage).await
+    }
+
+    /// Open the tip of the default branch, see [`crate::template::default_branch`]
+    pub async fn from_default_branch(
+        storage: Arc<dyn Storage + Send + Sync>,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        let branch = default_branch(storage.as_ref()).await?;
+        Self::from_branch_tip(storage, &branch).await
+    }
+
     pub async fn from_tag(
         storage: Arc<dyn Storage + Send + Sync>,
         tag_name: &str,
@@ -216,28 +1072,124 @@ impl Repository {
         storage: Arc<dyn Storage + Send + Sync>,
         unsafe_overwrite_refs: bool,
     ) -> RepositoryResult<RepositoryBuilder> {
-        if Self::exists(storage.as_ref()).await? {
+        Self::init_with_sources(storage, unsafe_overwrite_refs, Sources::default()).await
+    }
+
+    /// Like [`Repository::init`], but the initial snapshot gets its id and timestamps from
+    /// `sources`, which are also used by the returned builder
+    pub async fn init_with_sources(
+        storage: Arc<dyn Storage + Send + Sync>,
+        unsafe_overwrite_refs: bool,
+        sources: Sources,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        Self::init_from_template(
+            storage,
+            unsafe_overwrite_refs,
+            &RepositoryTemplate::default(),
+            sources,
+        )
+        .await
+    }
+
+    /// Initialize a new repository as described by `template`, with its default branch,
+    /// first commit and seeded nodes
+    pub async fn init_with_template(
+        storage: Arc<dyn Storage + Send + Sync>,
+        unsafe_overwrite_refs: bool,
+        template: &RepositoryTemplate,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        Self::init_from_template(
+            storage,
+            unsafe_overwrite_refs,
+            template,
+            Sources::default(),
+        )
+        .await
+    }
+
+    async fn init_from_template(
+        storage: Arc<dyn Storage + Send + Sync>,
+        unsafe_overwrite_refs: bool,
+        template: &RepositoryTemplate,
+        sources: Sources,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        let new_snapshot = template.initial_snapshot(&sources)?;
+        if Self::exists(storage.as_ref()).await? {
             return Err(RepositoryError::AlreadyInitialized);
         }
-        let new_snapshot = Snapshot::empty();
         let new_snapshot_id = new_snapshot.metadata.id.clone();
-        storage.write_snapshot(new_snapshot_id.clone(), Arc::new(new_snapshot)).await?;
-        update_branch(
+        storage
+            .write_snapshot_if_absent(new_snapshot_id.clone(), Arc::new(new_snapshot))
+            .await?;
+        if template.default_branch() != Ref::DEFAULT_BRANCH {
+            let settings = RepositorySettings {
+                default_branch: template.default_branch().to_string(),
+            };
+            match write_settings(storage.as_ref(), &settings, unsafe_overwrite_refs).await
+            {
+                Ok(()) => {}
+                Err(RefError::Storage(StorageError::RefAlreadyExists(_))) => {
+                    return Err(RepositoryError::AlreadyInitialized)
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        match update_branch(
             storage.as_ref(),
-            Ref::DEFAULT_BRANCH,
+            template.default_branch(),
             new_snapshot_id.clone(),
             None,
             unsafe_overwrite_refs,
         )
-        .await?;
+        .await
+        {
+            Ok(_) => {}
+            // another process initialized the repository after we checked
+            Err(RefError::Conflict { .. }) => {
+                return Err(RepositoryError::AlreadyInitialized)
+            }
+            Err(err) => return Err(err.into()),
+        }
 
         debug_assert!(Self::exists(storage.as_ref()).await.unwrap_or(false));
 
-        Ok(RepositoryBuilder::new(storage, new_snapshot_id))
+        let mut builder = RepositoryBuilder::new(storage, new_snapshot_id);
+        builder.with_sources(sources);
+        Ok(builder)
+    }
+
+    /// Create a new repository, failing with [`RepositoryError::AlreadyInitialized`] if one
+    /// already exists in `storage`
+    ///
+    /// The default branch is created with a conditional write, so if multiple processes try
+    /// to create the repository concurrently, only one of them succeeds.
+    pub async fn create(
+        storage: Arc<dyn Storage + Send + Sync>,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        Self::init(storage, false).await
+    }
+
+    /// Open the default branch of the repository in `storage`, creating the repository if
+    /// it doesn't exist
+    ///
+    /// Safe to call concurrently, all callers end up with the same repository.
+    pub async fn open_or_create(
+        storage: Arc<dyn Storage + Send + Sync>,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        if Self::exists(storage.as_ref()).await? {
+            return Self::from_default_branch(storage).await;
+        }
+        match Self::create(Arc::clone(&storage)).await {
+            Err(RepositoryError::AlreadyInitialized) => {
+                Self::from_default_branch(storage).await
+            }
+            res => res,
+        }
     }
 
+    /// Whether the default branch exists, see [`crate::template::default_branch`]
     pub async fn exists(storage: &(dyn Storage + Send + Sync)) -> RepositoryResult<bool> {
-        match fetch_branch_tip(storage, Ref::DEFAULT_BRANCH).await {
+        match fetch_branch_tip(storage, &default_branch(storage).await?).await {
             Ok(_) => Ok(true),
             Err(RefError::RefNotFound(_)) => Ok(false),
             Err(err) => Err(err.into()),
@@ -249,26 +1201,7 @@ impl Repository {
     pub fn add_in_mem_asset_caching(
         storage: Arc<dyn Storage + Send + Sync>,
     ) -> Arc<dyn Storage + Send + Sync> {
-        // TODO: allow tuning once we experiment with different configurations
-        Arc::new(MemCachingStorage::new(storage, 2, 2, 0, 2, 0))
-    }
-
-    fn new(
-        config: RepositoryConfig,
-        storage: Arc<dyn Storage + Send + Sync>,
-        snapshot_id: SnapshotId,
-        change_set: Option<ChangeSet>,
-        virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
-    ) -> Self {
-        Repository {
-            snapshot_id,
-            config,
-            storage,
-            change_set: change_set.unwrap_or_default(),
-            virtual_resolver: Arc::new(ObjectStoreVirtualChunkResolver::new(
-                virtual_ref_config,
-            )),
-        }
+        Arc::new(MemCachingStorage::from_config(storage, &CacheConfig::default()))
     }
 
     pub fn config(&self) -> &RepositoryConfig {
@@ -298,26 +1231,91 @@ impl Repository {
     }
 
     /// Returns a pointer to the storage for the repository
+    ///
+    /// Requests through it count in the [`Repository::metrics`] of the session.
     pub fn storage(&self) -> &Arc<dyn Storage + Send + Sync> {
         &self.storage
     }
 
+    /// The storage the session was built with, its requests don't count in the metrics
+    ///
+    /// Sessions built on it count only their own requests.
+    pub fn unmetered_storage(&self) -> &Arc<dyn Storage + Send + Sync> {
+        &self.backend
+    }
+
+    /// What the session cost so far: uploads, metadata requests and retries
+    pub fn metrics(&self) -> SessionMetrics {
+        self.counters.metrics(&self.backend)
+    }
+
     /// Returns the head snapshot id of the repository, not including
     /// anm uncommitted changes
     pub fn snapshot_id(&self) -> &SnapshotId {
         &self.snapshot_id
     }
 
+    /// The settings the session follows, see [`RepositoryBuilder::with_tunables`]
+    pub fn tunables(&self) -> &TunablesHandle {
+        &self.tunables
+    }
+
     /// Indicates if the repository has pending changes
     pub fn has_uncommitted_changes(&self) -> bool {
         !self.change_set.is_empty()
     }
 
+    /// Fetch the snapshot of the session, up to `ancestry_depth` of its parents, and its
+    /// manifests and attribute files, concurrently
+    ///
+    /// Icechunk fetches files lazily, on first access. When the storage caches them, like
+    /// [`MemCachingStorage`], prefetching cuts the latency of the first reads, which helps
+    /// interactive use. Without a cache it only wastes requests.
+    pub async fn prefetch(&self, ancestry_depth: usize) -> RepositoryResult<()> {
+        let storage = self.storage.as_ref();
+        let snapshot = storage.fetch_snapshot(self.snapshot_id()).await?;
+        let mut fetches: Vec<Pin<Box<dyn Future<Output = StorageResult<()>> + Send>>> =
+            Vec::new();
+        let expired = expired_snapshots(storage).await?;
+        let parents = Arc::clone(&snapshot)
+            .local_ancestry()
+            .filter(|parent| !expired.contains(&parent.id))
+            .take(ancestry_depth);
+        for parent in parents {
+            fetches.push(
+                async move { storage.fetch_snapshot(&parent.id).await.map(|_| ()) }
+                    .boxed(),
+            );
+        }
+        for manifest in snapshot.manifest_files.iter() {
+            fetches.push(
+                async move { storage.fetch_manifests(&manifest.id).await.map(|_| ()) }
+                    .boxed(),
+            );
+        }
+        for attributes in snapshot.attribute_files.iter() {
+            fetches.push(
+                async move { storage.fetch_attributes(&attributes.id).await.map(|_| ()) }
+                    .boxed(),
+            );
+        }
+        futures::stream::iter(fetches)
+            .buffer_unordered(self.tunables.get().prefetch_concurrency.max(1))
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(())
+    }
+
     /// Returns the sequence of parents of the current session, in order of latest first.
+    ///
+    /// The parents expired by [`Repository::apply_retention`] are skipped. After
+    /// [`RepositoryConfig::max_history_depth`] parents, the stream ends with a
+    /// [`RepositoryError::HistoryTooDeep`].
     pub async fn ancestry(
         &self,
     ) -> RepositoryResult<impl Stream<Item = RepositoryResult<SnapshotMetadata>>> {
         let parent = self.storage.fetch_snapshot(self.snapshot_id()).await?;
+        let expired = expired_snapshots(self.storage.as_ref()).await?;
         let last = parent.metadata.clone();
         let it = if parent.short_term_history.len() < parent.total_parents as usize {
             // FIXME: implement splitting of snapshot history
@@ -328,17 +1326,83 @@ impl Repository {
         } else {
             Either::Right(parent.local_ancestry())
         };
+        let it = it.filter(move |meta| !expired.contains(&meta.id));
+        let limit = self.config.max_history_depth;
+        let snapshot = self.snapshot_id.clone();
+        let it = it.enumerate().map(move |(depth, meta)| {
+            if limit > 0 && depth >= limit {
+                Err(RepositoryError::HistoryTooDeep { snapshot: snapshot.clone(), limit })
+            } else {
+                Ok(meta)
+            }
+        });
+        // stop after the error
+        let it = it.scan(false, |failed, res| {
+            (!std::mem::replace(failed, res.is_err())).then_some(res)
+        });
+
+        Ok(futures::stream::iter(iter::once(Ok(last)).chain(it)))
+    }
+
+    /// [`Repository::ancestry`], with the current annotations of each snapshot, see
+    /// [`crate::annotations`]
+    pub async fn annotated_ancestry(
+        &self,
+    ) -> RepositoryResult<
+        impl Stream<Item = RepositoryResult<(SnapshotMetadata, Option<SnapshotAnnotations>)>>,
+    > {
+        let mut annotations = list_annotations(self.storage.as_ref()).await?;
+        Ok(self.ancestry().await?.map_ok(move |meta| {
+            let annotations = annotations.remove(&meta.id);
+            (meta, annotations)
+        }))
+    }
+
+    /// Change the annotations of the snapshot of the session, see
+    /// [`crate::annotations::annotate`]
+    pub async fn annotate(
+        &self,
+        f: impl FnMut(&mut AnnotationLabels),
+    ) -> RepositoryResult<SnapshotAnnotations> {
+        Ok(annotate(self.storage.as_ref(), self.snapshot_id(), self.sources.now(), f)
+            .await?)
+    }
 
-        Ok(futures::stream::iter(iter::once(Ok(last)).chain(it.map(Ok))))
+    /// The most recent common ancestor of snapshots `a` and `b`, one of them if it's an
+    /// ancestor of the other
+    ///
+    /// Uses an [`AncestryIndex`] of the two histories, so it doesn't fetch the snapshots in
+    /// between, however long ago the branches diverged. Fails with
+    /// [`RepositoryError::HistoryTooDeep`] if the ancestor is older than
+    /// [`RepositoryConfig::max_history_depth`] commits from either of them. Returns `None` if
+    /// they have no ancestor in common.
+    pub async fn common_ancestor(
+        &self,
+        a: &SnapshotId,
+        b: &SnapshotId,
+    ) -> RepositoryResult<Option<SnapshotId>> {
+        let limit = self.config.max_history_depth;
+        let mut index = AncestryIndex::new();
+        for id in [a, b] {
+            index.insert(self.storage.fetch_snapshot(id).await?.as_ref(), limit);
+        }
+        match index.common_ancestor(a, b) {
+            CommonAncestor::Found(id) => Ok(Some(id)),
+            CommonAncestor::Unrelated => Ok(None),
+            CommonAncestor::BeyondIndex => {
+                Err(RepositoryError::HistoryTooDeep { snapshot: a.clone(), limit })
+            }
+        }
     }
 
     /// Add a group to the store.
     ///
     /// Calling this only records the operation in memory, doesn't have any consequence on the storage
     pub async fn add_group(&mut self, path: Path) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
         match self.get_node(&path).await {
             Err(RepositoryError::NodeNotFound { .. }) => {
-                let id = NodeId::random();
+                let id = self.sources.new_id();
                 self.change_set.add_group(path.clone(), id);
                 Ok(())
             }
@@ -354,6 +1418,7 @@ impl Repository {
     ///
     /// Deletes of non existing groups will succeed.
     pub async fn delete_group(&mut self, path: Path) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
         match self.get_group(&path).await {
             Ok(node) => {
                 self.change_set.delete_group(node.path, &node.id);
@@ -372,9 +1437,11 @@ impl Repository {
         path: Path,
         metadata: ZarrArrayMetadata,
     ) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
+        metadata.validate()?;
         match self.get_node(&path).await {
             Err(RepositoryError::NodeNotFound { .. }) => {
-                let id = NodeId::random();
+                let id = self.sources.new_id();
                 self.change_set.add_array(path, id, metadata);
                 Ok(())
             }
@@ -394,15 +1461,73 @@ impl Repository {
         path: Path,
         metadata: ZarrArrayMetadata,
     ) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
+        metadata.validate()?;
         self.get_array(&path)
             .await
             .map(|node| self.change_set.update_array(node.id, metadata))
     }
 
+    /// Add a copy of the array at `src` at `dst`, with its metadata, attributes and chunks
+    ///
+    /// The copy references the same chunk objects, no chunk bytes are read or written, so
+    /// it's a metadata only operation whatever the size of the array. With a `region`, one
+    /// half-open range of chunk coordinates per dimension, only the chunks in the region are
+    /// copied, to the same coordinates. Returns the number of chunks copied.
+    ///
+    /// Calling this only records the operation in memory, doesn't have any consequence on the storage
+    pub async fn copy_array(
+        &mut self,
+        src: &Path,
+        dst: Path,
+        region: Option<&[Range<u64>]>,
+    ) -> RepositoryResult<u64> {
+        let node = self.get_array(src).await?;
+        let NodeData::Array(meta, _) = node.node_data.clone() else {
+            return Err(RepositoryError::NotAnArray {
+                node,
+                message: "copying an array".to_string(),
+            });
+        };
+        if let Some(region) = region {
+            if region.len() != meta.shape.len() {
+                return Err(RepositoryError::InvalidChunkRegion {
+                    path: src.clone(),
+                    region: region.to_vec(),
+                    dimensions: meta.shape.len(),
+                });
+            }
+        }
+        let attributes =
+            load_user_attributes(self.storage.as_ref(), node.user_attributes.clone())
+                .await?;
+        let chunks: Vec<(ChunkIndices, TransformedPayload)> = self
+            .transformed_chunks(node)
+            .await?
+            .into_iter()
+            .filter(|(coord, _)| region.is_none_or(|region| in_region(region, coord)))
+            .collect();
+
+        self.add_array(dst.clone(), meta).await?;
+        if attributes.is_some() {
+            self.set_user_attributes(dst.clone(), attributes).await?;
+        }
+        let dst_id = self.get_array(&dst).await?.id;
+        let copied = chunks.len() as u64;
+        // the payloads were validated when they were set on `src`. Chunks referenced by the
+        // change set are never deleted as superseded, rewriting `src` keeps them.
+        for (coord, chunk) in chunks {
+            self.record_copied_chunk(&chunk).await;
+            self.change_set.set_chunk_ref(dst_id.clone(), coord, Some(chunk.payload));
+        }
+        Ok(copied)
+    }
+
     /// Delete an array in the hierarchy
     ///
     /// Deletes of non existing array will succeed.
     pub async fn delete_array(&mut self, path: Path) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
         match self.get_array(&path).await {
             Ok(node) => {
                 self.change_set.delete_array(node.path, &node.id);
@@ -413,17 +1538,163 @@ impl Repository {
         Ok(())
     }
 
+    /// Add a node of a user defined kind to the store.
+    ///
+    /// The kind must have been registered with [`RepositoryBuilder::with_node_kind`], and
+    /// the metadata must pass its schema validation.
+    ///
+    /// Calling this only records the operation in memory, doesn't have any consequence on the storage
+    pub async fn add_custom_node(
+        &mut self,
+        path: Path,
+        data: CustomNodeData,
+    ) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
+        self.validate_custom_node(&data)?;
+        match self.get_node(&path).await {
+            Err(RepositoryError::NodeNotFound { .. }) => {
+                let id = self.sources.new_id();
+                self.change_set.add_custom_node(path, id, data);
+                Ok(())
+            }
+            Ok(node) => Err(RepositoryError::AlreadyExists {
+                node,
+                message: "trying to add custom node".to_string(),
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replace the data of an existing custom node, the new data is validated as in
+    /// [`Repository::add_custom_node`].
+    ///
+    /// Calling this only records the operation in memory, doesn't have any consequence on the storage
+    pub async fn update_custom_node(
+        &mut self,
+        path: Path,
+        data: CustomNodeData,
+    ) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
+        self.validate_custom_node(&data)?;
+        let node = self.get_custom_node(&path).await?;
+        self.change_set.update_custom_node(node.id, data);
+        Ok(())
+    }
+
+    /// Delete a custom node, and its children, from the hierarchy
+    ///
+    /// Deletes of non existing nodes will succeed.
+    pub async fn delete_custom_node(&mut self, path: Path) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
+        match self.get_custom_node(&path).await {
+            Ok(node) => {
+                self.change_set.delete_custom_node(node.path, &node.id);
+            }
+            Err(RepositoryError::NodeNotFound { .. }) => {}
+            Err(err) => Err(err)?,
+        }
+        Ok(())
+    }
+
+    fn authorize(&self, operation: Operation<'_>) -> RepositoryResult<()> {
+        self.authorizer.authorize(operation).map_err(RepositoryError::Unauthorized)
+    }
+
+    fn validate_custom_node(&self, data: &CustomNodeData) -> RepositoryResult<()> {
+        let schema = self
+            .node_kinds
+            .get(&data.kind)
+            .ok_or_else(|| RepositoryError::UnknownNodeKind(data.kind.clone()))?;
+        schema.validate(&data.metadata).map_err(|message| {
+            RepositoryError::InvalidCustomNode { kind: data.kind.clone(), message }
+        })
+    }
+
     /// Record the write or delete of user attributes to array or group
     pub async fn set_user_attributes(
         &mut self,
         path: Path,
         atts: Option<UserAttributes>,
     ) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
         let node = self.get_node(&path).await?;
         self.change_set.update_user_attributes(node.id, atts);
         Ok(())
     }
 
+    /// Attach the document `name` to the group at `path`, replacing the one with that name
+    ///
+    /// The content is uploaded right away, the document is part of the snapshots committed
+    /// from now on, see [`crate::ops::documents`].
+    pub async fn put_document(
+        &mut self,
+        path: Path,
+        name: impl Into<String>,
+        media_type: impl Into<String>,
+        content: Bytes,
+    ) -> RepositoryResult<DocumentRef> {
+        self.authorize(Operation::Write(&path))?;
+        self.get_group(&path).await?;
+        let id: ChunkId = self.sources.new_id();
+        let size_bytes = content.len() as u64;
+        self.storage.write_chunk(id.clone(), content).await?;
+        let document = DocumentRef { id, size_bytes, media_type: media_type.into() };
+        self.change_set.set_document(path, name.into(), Some(document.clone()));
+        Ok(document)
+    }
+
+    pub async fn delete_document(
+        &mut self,
+        path: &Path,
+        name: &str,
+    ) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(path))?;
+        if !self.list_documents(path).await?.contains_key(name) {
+            return Err(RepositoryError::DocumentNotFound {
+                path: path.clone(),
+                name: name.to_string(),
+            });
+        }
+        self.change_set.set_document(path.clone(), name.to_string(), None);
+        Ok(())
+    }
+
+    /// The documents of the group at `path`, by name, including the uncommitted changes
+    pub async fn list_documents(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<BTreeMap<String, DocumentRef>> {
+        self.authorize(Operation::Read(path))?;
+        self.get_group(path).await?;
+        let snapshot = self.storage.fetch_snapshot(&self.snapshot_id).await?;
+        let table = DocumentTable::from_snapshot(&snapshot)?;
+        let mut res: BTreeMap<_, _> = table
+            .documents(path)
+            .map(|(name, document)| (name.clone(), document.clone()))
+            .collect();
+        for (_, name, document) in
+            self.change_set.document_changes().filter(|(changed, ..)| *changed == path)
+        {
+            match document {
+                Some(document) => res.insert(name.clone(), document.clone()),
+                None => res.remove(name),
+            };
+        }
+        Ok(res)
+    }
+
+    /// The content of the document `name` of the group at `path`
+    pub async fn get_document(&self, path: &Path, name: &str) -> RepositoryResult<Bytes> {
+        let document =
+            self.list_documents(path).await?.remove(name).ok_or_else(|| {
+                RepositoryError::DocumentNotFound {
+                    path: path.clone(),
+                    name: name.to_string(),
+                }
+            })?;
+        Ok(self.storage.fetch_chunk(&document.id, &ByteRange::ALL).await?)
+    }
+
     // Record the write, referenceing or delete of a chunk
     //
     // Caller has to write the chunk before calling this.
@@ -433,15 +1704,175 @@ impl Repository {
         coord: ChunkIndices,
         data: Option<ChunkPayload>,
     ) -> RepositoryResult<()> {
-        self.get_array(&path)
-            .await
-            .map(|node: NodeSnapshot| self.change_set.set_chunk_ref(node.id, coord, data))
+        self.authorize(Operation::Write(&path))?;
+        let node = self.get_array(&path).await?;
+        if let Some(ChunkPayload::Virtual(VirtualChunkRef {
+            length,
+            slice: Some(slice),
+            ..
+        })) = &data
+        {
+            slice.validate(*length).map_err(VirtualReferenceError::InvalidSlice)?;
+        }
+        if let (NodeData::Array(meta, _), Some(payload)) = (&node.node_data, &data) {
+            let found = match payload {
+                ChunkPayload::Inline(bytes) => Some(bytes.len() as u64),
+                // the length of transformed chunks is not the chunk size
+                ChunkPayload::Ref(ChunkRef { id, .. })
+                    if self
+                        .session_chunk_transformers(id)
+                        .await
+                        .is_some_and(|ids| !ids.is_empty()) =>
+                {
+                    None
+                }
+                ChunkPayload::Ref(ChunkRef { length, .. }) => Some(*length),
+                ChunkPayload::Virtual(reference) => Some(reference.chunk_length()),
+            };
+            match (meta.uncompressed_chunk_size_bytes(), found) {
+                (Some(expected), Some(found)) if expected != found => {
+                    return Err(RepositoryError::InvalidChunkSize {
+                        path,
+                        coords: coord,
+                        expected,
+                        found,
+                    })
+                }
+                _ => {}
+            }
+            if meta.is_uncompressed_vlen() {
+                let elements = meta.chunk_elements();
+                let checked = match (payload, found) {
+                    (ChunkPayload::Inline(bytes), _) => {
+                        validate_vlen(bytes, elements, meta.data_type == DataType::String)
+                            .map(|_| ())
+                    }
+                    // only inline chunks are read, the others must at least fit the lengths
+                    (_, Some(found)) if found < min_vlen_chunk_size(elements) => {
+                        Err(VlenError::Truncated(found as usize))
+                    }
+                    _ => Ok(()),
+                };
+                checked.map_err(|source| RepositoryError::InvalidVlenChunk {
+                    path: path.clone(),
+                    coords: coord.clone(),
+                    source,
+                })?;
+            }
+        }
+        if let Some(Some(ChunkPayload::Ref(ChunkRef { id, .. }))) =
+            self.change_set.get_chunk_ref(&node.id, &coord)
+        {
+            let rewritten = match &data {
+                Some(ChunkPayload::Ref(ChunkRef { id: new_id, .. })) => new_id != id,
+                _ => true,
+            };
+            if rewritten && self.written_chunks.lock().await.contains_key(id) {
+                self.superseded_chunks.insert(id.clone());
+            }
+        }
+        self.change_set.set_chunk_ref(node.id, coord, data);
+        Ok(())
     }
 
     pub async fn get_node(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
+        self.authorize(Operation::Read(path))?;
         get_node(self.storage.as_ref(), &self.change_set, self.snapshot_id(), path).await
     }
 
+    /// The user attributes of the node at `path`
+    ///
+    /// Nodes listed or fetched with [`Repository::get_node`] only point to attributes stored
+    /// outside the snapshot, this loads them.
+    pub async fn get_user_attributes(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<Option<UserAttributes>> {
+        let node = self.get_node(path).await?;
+        load_user_attributes(self.storage.as_ref(), node.user_attributes).await
+    }
+
+    /// The user attributes of the nodes at `paths`, in the same order
+    ///
+    /// Every attributes file the nodes point to is fetched once, concurrently.
+    pub async fn get_attributes_bulk(
+        &self,
+        paths: impl IntoIterator<Item = &Path>,
+    ) -> RepositoryResult<Vec<Option<UserAttributes>>> {
+        let mut atts = Vec::new();
+        for path in paths {
+            atts.push(self.get_node(path).await?.user_attributes);
+        }
+        let ids: HashSet<&AttributesId> = atts
+            .iter()
+            .filter_map(|atts| match atts {
+                Some(UserAttributesSnapshot::Ref(atts_ref)) => Some(&atts_ref.object_id),
+                _ => None,
+            })
+            .collect();
+        let tables: HashMap<&AttributesId, Arc<AttributesTable>> =
+            futures::stream::iter(ids)
+                .map(|id| async move {
+                    self.storage.fetch_attributes(id).await.map(|table| (id, table))
+                })
+                .buffer_unordered(self.tunables.get().attributes_fetch_concurrency.max(1))
+                .try_collect()
+                .await?;
+        atts.iter()
+            .map(|atts| match atts {
+                None => Ok(None),
+                Some(UserAttributesSnapshot::Inline(atts)) => Ok(Some(atts.clone())),
+                Some(UserAttributesSnapshot::Ref(UserAttributesRef {
+                    object_id,
+                    location,
+                })) => tables
+                    .get(object_id)
+                    .and_then(|table| table.get(*location))
+                    .cloned()
+                    .map(Some)
+                    .ok_or_else(|| {
+                        IcechunkFormatError::UserAttributesNotFound {
+                            id: object_id.clone(),
+                            location: *location,
+                        }
+                        .into()
+                    }),
+            })
+            .collect()
+    }
+
+    /// The xarray conventions of the array at `path`, read from its user attributes
+    pub async fn array_conventions(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<ArrayConventions> {
+        let atts = self.get_user_attributes(path).await?;
+        ArrayConventions::parse(path, atts.as_ref())
+            .map_err(RepositoryError::InvalidConventions)
+    }
+
+    /// Replace the convention attributes of the array at `path`, keeping the others
+    pub async fn set_array_conventions(
+        &mut self,
+        path: Path,
+        conventions: &ArrayConventions,
+    ) -> RepositoryResult<()> {
+        self.get_array(&path).await?;
+        let atts = self.get_user_attributes(&path).await?;
+        self.set_user_attributes(path, Some(conventions.apply(atts))).await
+    }
+
+    pub async fn get_custom_node(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
+        match self.get_node(path).await {
+            res @ Ok(NodeSnapshot { node_data: NodeData::Custom(..), .. }) => res,
+            Ok(node @ NodeSnapshot { .. }) => Err(RepositoryError::NotACustomNode {
+                node,
+                message: "getting a custom node".to_string(),
+            }),
+            other => other,
+        }
+    }
+
     pub async fn get_array(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
         match self.get_node(path).await {
             res @ Ok(NodeSnapshot { node_data: NodeData::Array(..), .. }) => res,
@@ -473,7 +1904,7 @@ impl Repository {
         // TODO: it's ugly to have to do this destructuring even if we could be calling `get_array`
         // get_array should return the array data, not a node
         match node.node_data {
-            NodeData::Group => Err(RepositoryError::NotAnArray {
+            NodeData::Group | NodeData::Custom(_) => Err(RepositoryError::NotAnArray {
                 node,
                 message: "getting chunk reference".to_string(),
             }),
@@ -497,20 +1928,114 @@ impl Repository {
         }
     }
 
-    /// Get a future that reads the the payload of a chunk from object store
-    ///
-    /// This function doesn't return [`Bytes`] directly to avoid locking the ref to self longer
-    /// than needed. We want the bytes to be pulled from object store without holding a ref to the
-    /// [`Repository`], that way, writes can happen concurrently.
-    ///
-    /// The result of calling this function is None, if the chunk reference is not present in the
-    /// repository, or a [`Future`] that will fetch the bytes, possibly failing.
+    /// Whether the array has the chunk, from the manifests, without fetching it
+    pub async fn chunk_exists(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<bool> {
+        Ok(self.get_chunk_ref(path, coords).await?.is_some())
+    }
+
+    /// The chunks of the array at `path` with coordinates in `region`, in coordinate order
     ///
-    /// Example usage:
-    /// ```ignore
-    /// get_chunk(
-    ///     ds.get_chunk_reader(
-    ///         &path,
+    /// Only the manifests with extents overlapping the region are fetched, and their chunks
+    /// are selected with [`Storage::fetch_manifest_region`].
+    pub async fn chunk_refs_in_region(
+        &self,
+        path: &Path,
+        region: &ManifestExtents,
+    ) -> RepositoryResult<BTreeMap<ChunkIndices, ChunkPayload>> {
+        let node = self.get_node(path).await?;
+        let NodeData::Array(_, manifest_refs) = &node.node_data else {
+            return Err(RepositoryError::NotAnArray {
+                node,
+                message: "getting chunk references in a region".to_string(),
+            });
+        };
+        let mut res = BTreeMap::new();
+        for manifest_ref in
+            manifest_refs.iter().filter(|manifest| manifest.extents.overlaps(region))
+        {
+            res.extend(
+                self.storage
+                    .fetch_manifest_region(&manifest_ref.object_id, &node.id, region)
+                    .await?,
+            );
+        }
+        for (coord, payload) in self.change_set.array_chunks_iterator(&node.id, path) {
+            if !region.contains(coord) {
+                continue;
+            }
+            match payload {
+                Some(payload) => res.insert(coord.clone(), payload.clone()),
+                None => res.remove(coord),
+            };
+        }
+        Ok(res)
+    }
+
+    /// Whether the array has each of the chunks, in order
+    ///
+    /// Every manifest is fetched at most once, so resuming jobs can check all the chunks
+    /// they have to produce.
+    pub async fn chunks_exist(
+        &self,
+        path: &Path,
+        coords: &[ChunkIndices],
+    ) -> RepositoryResult<Vec<bool>> {
+        let node = self.get_node(path).await?;
+        let NodeData::Array(_, manifest_refs) = &node.node_data else {
+            return Err(RepositoryError::NotAnArray {
+                node,
+                message: "checking chunks".to_string(),
+            });
+        };
+        let mut manifests: HashMap<&ManifestId, Arc<Manifest>> = HashMap::new();
+        let mut res = Vec::with_capacity(coords.len());
+        for coord in coords {
+            if let Some(session_chunk) = self.change_set.get_chunk_ref(&node.id, coord) {
+                res.push(session_chunk.is_some());
+                continue;
+            }
+            let mut exists = false;
+            for manifest_ref in
+                manifest_refs.iter().filter(|manifest| manifest.extents.contains(coord))
+            {
+                let manifest = match manifests.entry(&manifest_ref.object_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(
+                        self.storage.fetch_manifests(&manifest_ref.object_id).await?,
+                    ),
+                };
+                match manifest.get_chunk_payload(&node.id, coord.clone()) {
+                    Ok(_) => {
+                        exists = true;
+                        break;
+                    }
+                    Err(IcechunkFormatError::ChunkCoordinatesNotFound { .. }) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            res.push(exists);
+        }
+        Ok(res)
+    }
+
+    /// Get a future that reads the the payload of a chunk from object store
+    ///
+    /// This function doesn't return [`Bytes`] directly to avoid locking the ref to self longer
+    /// than needed. We want the bytes to be pulled from object store without holding a ref to the
+    /// [`Repository`], that way, writes can happen concurrently.
+    ///
+    /// The result of calling this function is None, if the chunk reference is not present in the
+    /// repository, or a [`Future`] that will fetch the bytes, possibly failing.
+    ///
+    /// Example usage:
+    /// ```ignore
+    /// get_chunk(
+    ///     ds.get_chunk_reader(
+    ///         &path,
     ///         &ChunkIndices(vec![0, 0, 0]),
     ///         &ByteRange::ALL,
     ///     )
@@ -530,13 +2055,48 @@ impl Repository {
         Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
     > {
         match self.get_chunk_ref(path, coords).await? {
-            Some(ChunkPayload::Ref(ChunkRef { id, .. })) => {
+            Some(ChunkPayload::Ref(ChunkRef { id, offset, length })) => {
                 let storage = Arc::clone(&self.storage);
-                let byte_range = byte_range.clone();
+                let write_buffer = self.write_buffer.clone();
+                let chunk_packer = Arc::clone(&self.chunk_packer);
+                let codec_workers = self.codec_workers.clone();
+                let (transformer_ids, placement) =
+                    self.chunk_record(path, coords, &id).await?;
+                let transformers = self.resolve_transformers(&transformer_ids).await?;
+                // transformed chunks are fetched whole, the range applies to the decoded bytes
+                let (fetch_range, decoded_range) = if transformers.is_empty() {
+                    (chunk_ref_byte_range(byte_range, offset, length), ByteRange::ALL)
+                } else {
+                    (
+                        chunk_ref_byte_range(&ByteRange::ALL, offset, length),
+                        byte_range.clone(),
+                    )
+                };
                 Ok(Some(
                     async move {
-                        // TODO: we don't have a way to distinguish if we want to pass a range or not
-                        storage.fetch_chunk(&id, &byte_range).await.map_err(|e| e.into())
+                        let bytes = if let Some(bytes) = chunk_packer.get(&id).await {
+                            fetch_range.slice(bytes)
+                        } else if let Some(bytes) = match write_buffer {
+                            Some(buffer) => buffer.get(&id).await?,
+                            None => None,
+                        } {
+                            fetch_range.slice(bytes)
+                        } else {
+                            // TODO: we don't have a way to distinguish if we want to pass a range or not
+                            match &placement {
+                                Some(shard) => {
+                                    storage
+                                        .fetch_chunk_from(shard, &id, &fetch_range)
+                                        .await?
+                                }
+                                None => storage.fetch_chunk(&id, &fetch_range).await?,
+                            }
+                        };
+                        if transformers.is_empty() {
+                            return Ok(bytes);
+                        }
+                        let decoded = codec_workers.decode(&transformers, bytes).await?;
+                        Ok(decoded_range.slice(decoded))
                     }
                     .boxed(),
                 ))
@@ -544,13 +2104,12 @@ impl Repository {
             Some(ChunkPayload::Inline(bytes)) => {
                 Ok(Some(ready(Ok(byte_range.slice(bytes))).boxed()))
             }
-            Some(ChunkPayload::Virtual(VirtualChunkRef { location, offset, length })) => {
-                let byte_range = construct_valid_byte_range(byte_range, offset, length);
+            Some(ChunkPayload::Virtual(reference)) => {
+                let byte_range = byte_range.clone();
                 let resolver = Arc::clone(&self.virtual_resolver);
                 Ok(Some(
                     async move {
-                        resolver
-                            .fetch_chunk(&location, &byte_range)
+                        fetch_virtual_chunk(resolver.as_ref(), &reference, &byte_range)
                             .await
                             .map_err(|e| e.into())
                     }
@@ -561,6 +2120,92 @@ impl Repository {
         }
     }
 
+    /// Get a presigned URL that can be used to fetch `byte_range` of the chunk during `ttl`.
+    ///
+    /// This allows serving chunks to clients without proxying the bytes. Returns `None` if
+    /// the chunk doesn't exist. Presigning needs support from the [`Storage`] implementation,
+    /// and it's not supported for virtual chunks, in both cases it fails with
+    /// [`StorageError::PresignNotSupported`].
+    pub async fn presign_chunk(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        byte_range: &ByteRange,
+        ttl: Duration,
+    ) -> RepositoryResult<Option<PresignedChunk>> {
+        match self.get_chunk_ref(path, coords).await? {
+            Some(ChunkPayload::Ref(ChunkRef { id, offset, length })) => {
+                // fail before uploading anything
+                if !self.storage.capabilities().presigning {
+                    return Err(StorageError::PresignNotSupported.into());
+                }
+                // the chunk must be in storage for the URL to work
+                if self.chunk_packer.is_open(&id).await {
+                    self.chunk_packer.seal_open().await;
+                }
+                upload_pack(
+                    self.storage.as_ref(),
+                    self.write_buffer.as_deref(),
+                    &self.chunk_packer,
+                    &id,
+                )
+                .await?;
+                if let Some(buffer) = &self.write_buffer {
+                    buffer.upload_chunk(self.storage.as_ref(), &id).await?;
+                }
+                let byte_range = chunk_ref_byte_range(byte_range, offset, length);
+                let url = self.storage.presign_read(&id, &byte_range, ttl).await?;
+                Ok(Some(PresignedChunk::Url(url)))
+            }
+            Some(ChunkPayload::Inline(bytes)) => {
+                Ok(Some(PresignedChunk::Inline(byte_range.slice(bytes))))
+            }
+            Some(ChunkPayload::Virtual(_)) => {
+                Err(StorageError::PresignNotSupported.into())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read `byte_range` of many chunks of the array at `path`, reporting the chunks that fail
+    /// instead of failing the whole read
+    ///
+    /// Up to `concurrency` chunks are read at a time. Only a missing array fails the call,
+    /// tools rendering the data show the failed chunks as gaps, and can read the
+    /// [`ChunkReadErrorKind::Throttled`] ones again.
+    pub async fn get_chunks_partial(
+        &self,
+        path: &Path,
+        coords: impl IntoIterator<Item = ChunkIndices>,
+        byte_range: &ByteRange,
+        concurrency: usize,
+    ) -> RepositoryResult<PartialChunks> {
+        self.get_array(path).await?;
+        let reads: Vec<_> = futures::stream::iter(coords)
+            .map(|coords| async move {
+                let read = match self.get_chunk_reader(path, &coords, byte_range).await {
+                    Ok(reader) => get_chunk(reader).await,
+                    Err(err) => Err(err),
+                };
+                (coords, read)
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await;
+        let mut res = PartialChunks::default();
+        for (coords, read) in reads {
+            match read {
+                Ok(bytes) => res.chunks.push((coords, bytes)),
+                Err(error) => res.errors.push(ChunkReadError {
+                    coords,
+                    kind: ChunkReadErrorKind::of(&error),
+                    error,
+                }),
+            }
+        }
+        Ok(res)
+    }
+
     /// Returns a function that can be used to asynchronously write chunk bytes to object store
     ///
     /// The reason to use this design, instead of simple pass the [`Bytes`] is to avoid holding a
@@ -574,19 +2219,190 @@ impl Repository {
     /// ```
     ///
     /// As shown, the result of the returned function must be awaited to finish the upload.
+    ///
+    /// The chunks are encoded with the transformers of the session, use
+    /// [`Repository::get_array_chunk_writer`] to apply the ones of the array.
     pub fn get_chunk_writer(
         &self,
     ) -> impl FnOnce(
         Bytes,
     ) -> Pin<
         Box<dyn Future<Output = RepositoryResult<ChunkPayload>> + Send>,
+    > {
+        self.chunk_writer(self.config.chunk_transformers.clone())
+    }
+
+    /// Like [`Repository::get_chunk_writer`], for the chunks of the array at `path`
+    ///
+    /// The chunks are encoded with the transformers of the array, see
+    /// [`Repository::set_array_chunk_transformers`].
+    pub async fn get_array_chunk_writer(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<
+        impl FnOnce(
+            Bytes,
+        )
+            -> Pin<Box<dyn Future<Output = RepositoryResult<ChunkPayload>> + Send>>,
+    > {
+        Ok(self.chunk_writer(self.array_chunk_transformers(path).await?))
+    }
+
+    /// The transformers applied to new chunks of the array at `path`
+    ///
+    /// Arrays without their own [`ZarrArrayMetadata::chunk_transformers`] use the ones of the
+    /// session.
+    pub async fn array_chunk_transformers(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<Vec<DynChunkTransformer>> {
+        let node = self.get_array(path).await?;
+        match &node.node_data {
+            NodeData::Array(
+                ZarrArrayMetadata { chunk_transformers: Some(ids), .. },
+                _,
+            ) => self.resolve_transformers(ids).await,
+            _ => Ok(self.config.chunk_transformers.clone()),
+        }
+    }
+
+    /// The transformers of the session with ids `ids`, or the dictionary transformers of
+    /// its dictionary compressors, see [`crate::chunk_dictionary`]
+    async fn resolve_transformers(
+        &self,
+        ids: &[String],
+    ) -> RepositoryResult<Vec<DynChunkTransformer>> {
+        self.dictionaries
+            .resolve(
+                self.storage.as_ref(),
+                &self.config.chunk_transformers,
+                &self.config.dictionary_compressors,
+                ids,
+            )
+            .await
+    }
+
+    /// Train a dictionary with `compressor` on up to `sample_chunks` chunks of the array at
+    /// `path`, and compress its future chunks with it
+    ///
+    /// The dictionary, of at most `max_size_bytes`, is stored right away, and its
+    /// transformer set as the only chunk transformer of the array, see
+    /// [`Repository::set_array_chunk_transformers`]. Returns the id of the transformer.
+    pub async fn train_chunk_dictionary(
+        &mut self,
+        path: &Path,
+        compressor: &str,
+        sample_chunks: usize,
+        max_size_bytes: usize,
+    ) -> RepositoryResult<String> {
+        let Some(compressor) = self
+            .config
+            .dictionary_compressors
+            .iter()
+            .find(|c| c.id() == compressor)
+            .cloned()
+        else {
+            return Err(
+                ChunkTransformError::UnknownTransformer(compressor.to_string()).into()
+            );
+        };
+        let coords: Vec<ChunkIndices> = self
+            .all_chunks()
+            .await?
+            .try_filter(|(chunk_path, _)| ready(chunk_path == path))
+            .map_ok(|(_, info)| info.coord)
+            .take(sample_chunks)
+            .try_collect()
+            .await?;
+        let mut samples = Vec::with_capacity(coords.len());
+        for coords in coords.iter() {
+            let reader = self.get_chunk_reader(path, coords, &ByteRange::ALL).await?;
+            samples.extend(get_chunk(reader).await?);
+        }
+        let dictionary =
+            compressor.train(&samples, max_size_bytes).map_err(|message| {
+                ChunkTransformError::Failed { id: compressor.id().to_string(), message }
+            })?;
+        let dictionary_id: ChunkId = self.sources.new_id();
+        let dictionary_id = dictionary_id.to_string();
+        write_dictionary(self.storage.as_ref(), &dictionary_id, dictionary).await?;
+        let id = dictionary_transformer_id(compressor.id(), &dictionary_id);
+        self.set_array_chunk_transformers(path, Some(vec![id.clone()])).await?;
+        Ok(id)
+    }
+
+    /// Encode the future chunks of the array at `path` with the session transformers with
+    /// ids `ids`, in order, or with all the session transformers if `None`
+    ///
+    /// The setting is recorded in the array metadata, chunks already written keep their
+    /// encoding. Fails with [`ChunkTransformError::UnknownTransformer`] if a transformer
+    /// is not configured in the session.
+    pub async fn set_array_chunk_transformers(
+        &mut self,
+        path: &Path,
+        ids: Option<Vec<String>>,
+    ) -> RepositoryResult<()> {
+        if let Some(ids) = &ids {
+            self.resolve_transformers(ids).await?;
+        }
+        let node = self.get_array(path).await?;
+        if let NodeData::Array(metadata, _) = node.node_data {
+            let metadata = ZarrArrayMetadata { chunk_transformers: ids, ..metadata };
+            self.update_array(path.clone(), metadata).await?;
+        }
+        Ok(())
+    }
+
+    fn chunk_writer(
+        &self,
+        transformers: Vec<DynChunkTransformer>,
+    ) -> impl FnOnce(
+        Bytes,
+    ) -> Pin<
+        Box<dyn Future<Output = RepositoryResult<ChunkPayload>> + Send>,
     > {
         let threshold = self.config.inline_chunk_threshold_bytes as usize;
         let storage = Arc::clone(&self.storage);
+        let written_chunks = Arc::clone(&self.written_chunks);
+        let write_buffer = self.write_buffer.clone();
+        let packing_threshold = self.config.chunk_packing_threshold_bytes;
+        let chunk_packer = Arc::clone(&self.chunk_packer);
+        let new_id = self.sources.new_id();
+        let codec_workers = self.codec_workers.clone();
         move |data: Bytes| {
             async move {
-                let payload = if data.len() > threshold {
-                    new_materialized_chunk(storage.as_ref(), data).await?
+                let data = codec_workers.encode(&transformers, data).await?;
+                // inline chunks have no id to find their transformers
+                let payload = if data.len() > threshold || !transformers.is_empty() {
+                    let payload = if (data.len() as u64) < packing_threshold {
+                        let (chunk_ref, sealed) = chunk_packer.append(&data).await;
+                        if let Some(pack_id) = sealed {
+                            upload_pack(
+                                storage.as_ref(),
+                                write_buffer.as_deref(),
+                                &chunk_packer,
+                                &pack_id,
+                            )
+                            .await?;
+                        }
+                        ChunkPayload::Ref(chunk_ref)
+                    } else {
+                        match write_buffer {
+                            Some(buffer) => {
+                                new_buffered_chunk(buffer.as_ref(), new_id, data).await?
+                            }
+                            None => {
+                                new_materialized_chunk(storage.as_ref(), new_id, data)
+                                    .await?
+                            }
+                        }
+                    };
+                    if let ChunkPayload::Ref(ChunkRef { id, .. }) = &payload {
+                        let ids =
+                            transformers.iter().map(|t| t.id().to_string()).collect();
+                        written_chunks.lock().await.insert(id.clone(), ids);
+                    }
+                    payload
                 } else {
                     new_inline_chunk(data)
                 };
@@ -604,6 +2420,7 @@ impl Repository {
             match t {
                 NodeType::Group => self.delete_group(p).await?,
                 NodeType::Array => self.delete_array(p).await?,
+                NodeType::Custom => self.delete_custom_node(p).await?,
             }
         }
         Ok(())
@@ -615,8 +2432,9 @@ impl Repository {
         manifests: &[ManifestRef],
         coords: &ChunkIndices,
     ) -> RepositoryResult<Option<ChunkPayload>> {
-        // FIXME: use manifest extents
-        for manifest in manifests {
+        for manifest in
+            manifests.iter().filter(|manifest| manifest.extents.contains(coords))
+        {
             let manifest_structure =
                 self.storage.fetch_manifests(&manifest.object_id).await?;
             match manifest_structure.get_chunk_payload(&node, coords.clone()) {
@@ -630,6 +2448,137 @@ impl Repository {
         Ok(None)
     }
 
+    /// The ids of the transformers applied to the chunk, stored in object `id`, and the
+    /// shard it was written to
+    async fn chunk_record(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        id: &ChunkId,
+    ) -> RepositoryResult<(Vec<String>, Option<String>)> {
+        let node = self.get_node(path).await?;
+        if self.change_set.get_chunk_ref(&node.id, coords).is_some() {
+            let transformers =
+                self.session_chunk_transformers(id).await.unwrap_or_default();
+            return Ok((transformers, self.storage.chunk_placement(id)));
+        }
+        if let NodeData::Array(_, manifests) = &node.node_data {
+            for manifest in
+                manifests.iter().filter(|manifest| manifest.extents.contains(coords))
+            {
+                let manifest = self.storage.fetch_manifests(&manifest.object_id).await?;
+                if manifest.get_chunk_payload(&node.id, coords.clone()).is_ok() {
+                    return Ok((
+                        manifest.chunk_transformers(&node.id, coords).to_vec(),
+                        manifest.chunk_placement(&node.id, coords).map(String::from),
+                    ));
+                }
+            }
+        }
+        Ok((Vec::new(), None))
+    }
+
+    /// The transformers of chunk `id`, if it was written or copied by this session
+    async fn session_chunk_transformers(&self, id: &ChunkId) -> Option<Vec<String>> {
+        let written = self.written_chunks.lock().await.get(id).cloned();
+        written.or_else(|| self.copied_chunks.get(id).cloned())
+    }
+
+    /// The transformers of every chunk written or copied by this session
+    async fn session_transformers(&self) -> HashMap<ChunkId, Vec<String>> {
+        let mut res = self.copied_chunks.clone();
+        res.extend(self.written_chunks.lock().await.clone());
+        res
+    }
+
+    /// Remember the transformers of a chunk reference set by the session but written
+    /// before it, so it's decoded and committed with them
+    async fn record_copied_chunk(&mut self, chunk: &TransformedPayload) {
+        if let ChunkPayload::Ref(ChunkRef { id, .. }) = &chunk.payload {
+            if chunk.is_transformed()
+                && !self.written_chunks.lock().await.contains_key(id)
+            {
+                self.copied_chunks.insert(id.clone(), chunk.transformers.clone());
+            }
+        }
+    }
+
+    /// The chunk at `coords` with the transformers it was encoded with
+    ///
+    /// This, and not [`Repository::get_chunk_ref`], is what must be copied to other arrays,
+    /// sessions or repositories, with [`Repository::set_transformed_chunk_ref`].
+    pub async fn get_transformed_chunk_ref(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<Option<TransformedPayload>> {
+        let Some(payload) = self.get_chunk_ref(path, coords).await? else {
+            return Ok(None);
+        };
+        let transformers = match &payload {
+            ChunkPayload::Ref(ChunkRef { id, .. }) => {
+                self.chunk_record(path, coords, id).await?.0
+            }
+            _ => Vec::new(),
+        };
+        Ok(Some(TransformedPayload::new(payload, transformers)))
+    }
+
+    /// [`Repository::set_chunk_ref`] for a chunk reference copied from another array or
+    /// session, it's decoded with its own transformers, not the ones of the array
+    pub async fn set_transformed_chunk_ref(
+        &mut self,
+        path: Path,
+        coord: ChunkIndices,
+        data: Option<TransformedPayload>,
+    ) -> RepositoryResult<()> {
+        self.authorize(Operation::Write(&path))?;
+        if let Some(chunk) = &data {
+            self.record_copied_chunk(chunk).await;
+        }
+        self.set_chunk_ref(path, coord, data.map(|chunk| chunk.payload)).await
+    }
+
+    /// The chunks of the array `node` with the transformers each one was encoded with
+    async fn transformed_chunks(
+        &self,
+        node: NodeSnapshot,
+    ) -> RepositoryResult<Vec<(ChunkIndices, TransformedPayload)>> {
+        let mut recorded = HashMap::new();
+        if let NodeData::Array(_, manifests) = &node.node_data {
+            for manifest in manifests {
+                let manifest = self.storage.fetch_manifests(&manifest.object_id).await?;
+                recorded.extend(
+                    manifest
+                        .transformers()
+                        .iter()
+                        .filter(|((id, _), _)| id == &node.id)
+                        .map(|((_, coord), ids)| (coord.clone(), ids.clone())),
+                );
+            }
+        }
+        let node_id = node.id.clone();
+        let chunks: Vec<ChunkInfo> =
+            verified_node_chunk_iterator(self.storage.as_ref(), &self.change_set, node)
+                .await
+                .try_collect()
+                .await?;
+        let mut res = Vec::with_capacity(chunks.len());
+        for ChunkInfo { coord, payload, .. } in chunks {
+            let transformers = match &payload {
+                ChunkPayload::Ref(ChunkRef { id, .. })
+                    if self.change_set.get_chunk_ref(&node_id, &coord).is_some() =>
+                {
+                    self.session_chunk_transformers(id).await.unwrap_or_default()
+                }
+                ChunkPayload::Ref(_) => recorded.remove(&coord).unwrap_or_default(),
+                _ => Vec::new(),
+            };
+            res.push((coord, TransformedPayload::new(payload, transformers)));
+        }
+        Ok(res)
+    }
+
     pub async fn list_nodes(
         &self,
     ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + '_> {
@@ -637,6 +2586,192 @@ impl Repository {
             .await
     }
 
+    /// The nodes selected by `listing`, including the uncommitted changes, in path order
+    ///
+    /// Nodes are produced as the iterator advances, only the nodes under the prefix are
+    /// visited.
+    pub async fn list_nodes_filtered(
+        &self,
+        listing: &NodeListing,
+    ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + '_> {
+        let prefix = listing.prefix.clone().unwrap_or_else(Path::root);
+        let after = listing
+            .page_token
+            .as_ref()
+            .map(|token| {
+                Path::try_from(token)
+                    .map_err(|_| RepositoryError::InvalidPageToken(token.clone()))
+            })
+            .transpose()?;
+        let start = match &after {
+            Some(after) if after >= &prefix => Bound::Excluded(after.clone()),
+            _ => Bound::Included(prefix.clone()),
+        };
+
+        let existing = {
+            let prefix = prefix.clone();
+            self.storage
+                .fetch_snapshot(&self.snapshot_id)
+                .await?
+                .iter_arc_from(start)
+                .take_while(move |node| node.path.starts_with(&prefix))
+                .filter_map(|node| self.change_set.update_existing_node(node, None))
+        };
+        let new = self
+            .change_set
+            .new_nodes_iterator(None)
+            .filter(|node| {
+                node.path.starts_with(&prefix)
+                    && after.as_ref().is_none_or(|after| &node.path > after)
+            })
+            .sorted_by(|a, b| a.path.cmp(&b.path));
+
+        let prefix_depth = prefix.ancestors().count();
+        let max_depth = listing.max_depth;
+        let kinds = listing.kinds.clone();
+        Ok(existing.merge_by(new, |a, b| a.path <= b.path).filter(move |node| {
+            max_depth
+                .is_none_or(|max| node.path.ancestors().count() - prefix_depth <= max)
+                && (kinds.is_empty() || kinds.contains(&node.node_type()))
+        }))
+    }
+
+    /// Up to `page_size` of the nodes selected by `listing`
+    pub async fn list_nodes_page(
+        &self,
+        listing: &NodeListing,
+        page_size: usize,
+    ) -> RepositoryResult<NodePage> {
+        let mut nodes: Vec<_> =
+            self.list_nodes_filtered(listing).await?.take(page_size + 1).collect();
+        let next_page_token = if nodes.len() > page_size {
+            nodes.truncate(page_size);
+            nodes.last().map(|node| node.path.to_string())
+        } else {
+            None
+        };
+        Ok(NodePage { nodes, next_page_token })
+    }
+
+    /// The chunks of the array at `path` written or deleted after snapshot `since`,
+    /// including the uncommitted changes
+    ///
+    /// A chunk was written after `since` if the snapshot that wrote it is not `since` or one
+    /// of its ancestors. Chunks written before their origin was recorded are always
+    /// reported as written.
+    pub async fn chunks_changed_since(
+        &self,
+        path: &Path,
+        since: &SnapshotId,
+    ) -> RepositoryResult<ChangedChunks> {
+        let since_snapshot = self.storage.fetch_snapshot(since).await?;
+        let history: HashSet<SnapshotId> = iter::once(since.clone())
+            .chain(Arc::clone(&since_snapshot).local_ancestry().map(|meta| meta.id))
+            .collect();
+        let node = self.get_array(path).await?;
+        let NodeData::Array(_, manifests) = &node.node_data else {
+            return Ok(ChangedChunks::default());
+        };
+
+        let mut res = ChangedChunks::default();
+        let mut current = HashSet::new();
+        for manifest_ref in manifests {
+            let manifest = self.storage.fetch_manifests(&manifest_ref.object_id).await?;
+            for (coord, _) in Arc::clone(&manifest).iter(node.id.clone()) {
+                let origin = manifest.chunk_origin(&node.id, &coord);
+                if !origin.is_some_and(|origin| history.contains(origin)) {
+                    res.written.insert(coord.clone());
+                }
+                current.insert(coord);
+            }
+        }
+        for (coord, payload) in self.change_set.array_chunks_iterator(&node.id, path) {
+            if payload.is_some() {
+                res.written.insert(coord.clone());
+                current.insert(coord.clone());
+            } else {
+                res.written.remove(coord);
+                current.remove(coord);
+            }
+        }
+
+        let old_node = since_snapshot.get_node(path).ok().filter(|old| old.id == node.id);
+        if let Some(NodeData::Array(_, old_manifests)) =
+            old_node.map(|old| &old.node_data)
+        {
+            for manifest_ref in old_manifests {
+                let manifest =
+                    self.storage.fetch_manifests(&manifest_ref.object_id).await?;
+                res.deleted.extend(
+                    manifest
+                        .iter(node.id.clone())
+                        .map(|(coord, _)| coord)
+                        .filter(|coord| !current.contains(coord)),
+                );
+            }
+        }
+        Ok(res)
+    }
+
+    /// A read only view of the snapshot this session started from
+    ///
+    /// The view doesn't include the uncommitted changes, it's meant to serve concurrent reads,
+    /// see [`SnapshotView`].
+    pub async fn snapshot_view(&self) -> RepositoryResult<SnapshotView> {
+        SnapshotView::with_virtual_resolver(
+            Arc::clone(&self.storage),
+            Arc::clone(&self.virtual_resolver),
+            &self.snapshot_id,
+        )
+        .await
+    }
+
+    /// The arrays that have a dimension called `dimension`
+    pub async fn list_arrays_with_dimension<'a>(
+        &'a self,
+        dimension: &'a str,
+    ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
+        Ok(self.list_nodes().await?.filter(move |node| match &node.node_data {
+            NodeData::Array(meta, _) => meta.dimension_index(dimension).is_some(),
+            _ => false,
+        }))
+    }
+
+    /// The coordinate arrays of the array at `path`
+    ///
+    /// Following the xarray conventions, the coordinate of a named dimension is a one
+    /// dimensional array with the same name, in the same group. Returns a map from dimension
+    /// name to coordinate array, dimensions without coordinates are not included.
+    pub async fn get_coordinates(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<BTreeMap<String, NodeSnapshot>> {
+        let node = self.get_array(path).await?;
+        let (NodeData::Array(meta, _), Some(group)) = (&node.node_data, path.parent())
+        else {
+            return Ok(BTreeMap::new());
+        };
+        let mut res = BTreeMap::new();
+        for dim in meta.dimension_names.iter().flatten().flatten() {
+            let Ok(coord_path) = group.child(dim) else { continue };
+            if &coord_path == path {
+                continue;
+            }
+            match self.get_node(&coord_path).await {
+                Ok(coord) => {
+                    if let NodeData::Array(meta, _) = &coord.node_data {
+                        if meta.is_coordinate_for(dim) {
+                            res.insert(dim.clone(), coord);
+                        }
+                    }
+                }
+                Err(RepositoryError::NodeNotFound { .. }) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(res)
+    }
+
     pub async fn all_chunks(
         &self,
     ) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + '_>
@@ -644,6 +2779,23 @@ impl Repository {
         all_chunks(self.storage.as_ref(), &self.change_set, self.snapshot_id()).await
     }
 
+    /// The root of the scratch area of the session
+    ///
+    /// Nodes created under it work like any other node, but they are never committed. They
+    /// stay in the session after a commit, for staging arrays across several commits, and
+    /// go away with the session or [`Repository::discard_scratch`]. Chunks written to them
+    /// are orphans once the session ends, garbage collection deletes them. Only new nodes can
+    /// go under the scratch path.
+    pub fn scratch_path() -> Path {
+        #[allow(clippy::expect_used)]
+        SCRATCH_PATH.try_into().expect("the scratch path is valid")
+    }
+
+    /// Drop the nodes of the scratch area, see [`Repository::scratch_path`]
+    pub fn discard_scratch(&mut self) -> ChangeSet {
+        self.change_set.split_off(&Self::scratch_path())
+    }
+
     /// Discard all uncommitted changes and return them as a `ChangeSet`
     pub fn discard_changes(&mut self) -> ChangeSet {
         std::mem::take(&mut self.change_set)
@@ -654,38 +2806,380 @@ impl Repository {
         self.change_set.merge(changes);
     }
 
-    /// After changes to the repository have been made, this generates and writes to `Storage` the updated datastructures.
-    ///
-    /// After calling this, changes are reset and the [`Repository`] can continue to be used for further
-    /// changes.
+    /// Merge the changes of `worker` in a coordinated `job`, see [`crate::claims`]
     ///
-    /// Returns the `ObjectId` of the new Snapshot file. It's the callers responsibility to commit
-    /// this id change.
-    pub async fn flush(
+    /// Fails, without merging anything, if the changes write chunks outside the claim of
+    /// the worker, or chunks already written by a previously merged change set.
+    pub async fn merge_claimed(
         &mut self,
-        message: &str,
-        properties: SnapshotProperties,
-    ) -> RepositoryResult<SnapshotId> {
-        let new_snapshot_id = flush(
-            self.storage.as_ref(),
-            &self.change_set,
-            self.snapshot_id(),
-            message,
+        job: &str,
+        worker: &str,
+        changes: ChangeSet,
+    ) -> RepositoryResult<()> {
+        let claim = list_claims(self.storage.as_ref(), job)
+            .await?
+            .into_iter()
+            .find(|claim| claim.worker == worker)
+            .ok_or_else(|| ClaimError::NoClaim {
+                job: job.to_string(),
+                worker: worker.to_string(),
+            })?;
+        let mut paths: HashMap<NodeId, Path> =
+            self.list_nodes().await?.map(|node| (node.id, node.path)).collect();
+        paths.extend(changes.new_arrays().map(|(path, id)| (id.clone(), path.clone())));
+        for (node_id, chunks) in changes.chunk_changes() {
+            // changes to deleted arrays are dropped on commit
+            let Some(path) = paths.get(node_id) else { continue };
+            for coords in chunks.keys() {
+                if !claim.contains(path, coords) {
+                    return Err(ClaimError::UnclaimedWrite {
+                        worker: worker.to_string(),
+                        path: path.clone(),
+                        coords: coords.clone(),
+                    }
+                    .into());
+                }
+                if self.change_set.get_chunk_ref(node_id, coords).is_some() {
+                    return Err(ClaimError::OverlappingWrite {
+                        path: path.clone(),
+                        coords: coords.clone(),
+                    }
+                    .into());
+                }
+            }
+        }
+        self.change_set.merge(changes);
+        Ok(())
+    }
+
+    /// Write the chunks of `writes`, uploading up to `config.concurrency` of them at a time
+    ///
+    /// The stream is only polled when there is room for a new upload, so a slow storage
+    /// slows down the producer, instead of buffering its chunks. Chunks are set in the
+    /// session in stream order, a later write of the same chunk wins. Uploads that fail with
+    /// a retryable error are retried, with exponential backoff. With a checkpoint
+    /// configured, the session is committed every `checkpoint.chunks` chunks, and at the end.
+    /// `progress` is called after every chunk set.
+    pub async fn ingest(
+        &mut self,
+        writes: impl Stream<Item = ChunkWrite>,
+        config: &IngestConfig,
+        mut progress: impl FnMut(&IngestProgress),
+    ) -> RepositoryResult<IngestProgress> {
+        let mut writes = pin!(writes.fuse());
+        let mut in_flight = FuturesOrdered::new();
+        let mut state = IngestProgress::default();
+        // the transformers of the arrays written, resolved once per array
+        let mut transformers: HashMap<Path, Vec<DynChunkTransformer>> = HashMap::new();
+        let upload = |repo: &Repository,
+                      write: ChunkWrite,
+                      attempt: u32,
+                      transformers: Vec<DynChunkTransformer>| {
+            let writer = repo.chunk_writer(transformers);
+            let delay = match attempt {
+                0 => None,
+                n => Some(
+                    config
+                        .runtime
+                        .sleep(config.retry_delay.saturating_mul(1 << (n - 1).min(16))),
+                ),
+            };
+            spawn(config.runtime.as_ref(), async move {
+                if let Some(delay) = delay {
+                    delay.await;
+                }
+                let res = writer(write.data.clone()).await;
+                (write, attempt, res)
+            })
+        };
+        loop {
+            while in_flight.len() < config.concurrency {
+                match writes.next().await {
+                    Some(write) => {
+                        let array_transformers = match transformers.get(&write.path) {
+                            Some(array_transformers) => array_transformers.clone(),
+                            None => {
+                                let resolved =
+                                    self.array_chunk_transformers(&write.path).await?;
+                                transformers.insert(write.path.clone(), resolved.clone());
+                                resolved
+                            }
+                        };
+                        in_flight.push_back(upload(self, write, 0, array_transformers))
+                    }
+                    None => break,
+                }
+            }
+            let Some((write, attempt, res)) = in_flight.next().await else { break };
+            let payload = match res {
+                Ok(payload) => payload,
+                Err(err) if err.is_retryable() && attempt < config.max_retries => {
+                    state.retries += 1;
+                    self.counters.retry();
+                    let array_transformers =
+                        transformers.get(&write.path).cloned().unwrap_or_default();
+                    in_flight.push_front(upload(
+                        self,
+                        write,
+                        attempt + 1,
+                        array_transformers,
+                    ));
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            state.chunks += 1;
+            state.bytes += write.data.len() as u64;
+            state.pending_chunks += 1;
+            self.set_chunk_ref(write.path, write.coords, Some(payload)).await?;
+            if let Some(checkpoint) = &config.checkpoint {
+                if state.pending_chunks >= checkpoint.chunks {
+                    self.ingest_checkpoint(checkpoint, &mut state).await?;
+                }
+            }
+            progress(&state);
+        }
+        if let Some(checkpoint) = &config.checkpoint {
+            if state.pending_chunks > 0 {
+                self.ingest_checkpoint(checkpoint, &mut state).await?;
+                progress(&state);
+            }
+        }
+        Ok(state)
+    }
+
+    async fn ingest_checkpoint(
+        &mut self,
+        checkpoint: &Checkpoint,
+        state: &mut IngestProgress,
+    ) -> RepositoryResult<()> {
+        let snapshot = self.commit(&checkpoint.branch, &checkpoint.message, None).await?;
+        state.checkpoint = Some(snapshot);
+        state.pending_chunks = 0;
+        Ok(())
+    }
+
+    /// Write the chunks of `writes` as they arrive, committing them to `config.branch` in
+    /// batches, see [`CoalescingConfig`]
+    ///
+    /// For writers that produce chunks slowly, like streaming sensor ingestion. The returned
+    /// stream must be polled for the chunks to be written, and yields every commit, the
+    /// interval is timed even if no chunk arrives. Chunks are uploaded one at a time, in
+    /// stream order. If a write or a commit fails, the stream ends with the error, and the
+    /// chunks not committed yet stay in the session.
+    pub fn ingest_streaming<'a>(
+        &'a mut self,
+        writes: impl Stream<Item = ChunkWrite> + 'a,
+        config: CoalescingConfig,
+    ) -> impl Stream<Item = RepositoryResult<StreamingCommit>> + 'a {
+        try_stream! {
+            let mut writes = pin!(writes.fuse());
+            let mut transformers: HashMap<Path, Vec<DynChunkTransformer>> = HashMap::new();
+            let (mut chunks, mut bytes) = (0, 0);
+            // started by the first chunk not committed yet
+            let mut timer: Option<BoxFuture<'static, ()>> = None;
+            loop {
+                let next = match timer.as_mut() {
+                    Some(timer) => match future::select(writes.next(), timer).await {
+                        future::Either::Left((write, _)) => Some(write),
+                        future::Either::Right(_) => None,
+                    },
+                    None => Some(writes.next().await),
+                };
+                let trigger = match next {
+                    None => CommitTrigger::Interval,
+                    Some(None) => CommitTrigger::EndOfStream,
+                    Some(Some(write)) => {
+                        let array_transformers = match transformers.get(&write.path) {
+                            Some(array_transformers) => array_transformers.clone(),
+                            None => {
+                                let resolved =
+                                    self.array_chunk_transformers(&write.path).await?;
+                                transformers.insert(write.path.clone(), resolved.clone());
+                                resolved
+                            }
+                        };
+                        let payload =
+                            self.chunk_writer(array_transformers)(write.data.clone()).await?;
+                        self.set_chunk_ref(write.path, write.coords, Some(payload)).await?;
+                        chunks += 1;
+                        bytes += write.data.len() as u64;
+                        if config.max_pending_bytes.is_some_and(|max| bytes >= max) {
+                            CommitTrigger::PendingBytes
+                        } else {
+                            if timer.is_none() {
+                                timer = config
+                                    .interval
+                                    .map(|interval| config.runtime.sleep(interval));
+                            }
+                            continue;
+                        }
+                    }
+                };
+                if chunks > 0 {
+                    let snapshot = self.commit(&config.branch, &config.message, None).await?;
+                    yield StreamingCommit { snapshot, chunks, bytes, trigger };
+                    (chunks, bytes) = (0, 0);
+                }
+                timer = None;
+                if trigger == CommitTrigger::EndOfStream {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// After changes to the repository have been made, this generates and writes to `Storage` the updated datastructures.
+    ///
+    /// After calling this, changes are reset and the [`Repository`] can continue to be used for further
+    /// changes.
+    ///
+    /// Returns the `ObjectId` of the new Snapshot file. It's the callers responsibility to commit
+    /// this id change.
+    pub async fn flush(
+        &mut self,
+        message: &str,
+        properties: SnapshotProperties,
+    ) -> RepositoryResult<SnapshotId> {
+        if let Err(err) = self.delete_superseded_chunks().await {
+            // the chunks are unreferenced either way, garbage collection deletes them later
+            self.emit(Event::ChunkCleanupFailed { message: err.to_string() });
+        }
+        self.chunk_packer.seal_open().await;
+        let packs = self.chunk_packer.sealed_packs().await;
+        let buffered = match &self.write_buffer {
+            // sealed packs go to the buffer, if there is one
+            Some(buffer) => (buffer.buffered_chunks().await + packs.len()) as u64,
+            None => packs.len() as u64,
+        };
+        let uploading = |done, bytes| {
+            Progress::new(Phase::UploadingChunks, done, Some(buffered)).with_bytes(bytes)
+        };
+        self.progress.on_progress(&uploading(0, 0));
+        let mut bytes = 0;
+        for (done, pack_id) in packs.into_iter().enumerate() {
+            let pack_bytes = upload_pack(
+                self.storage.as_ref(),
+                self.write_buffer.as_deref(),
+                &self.chunk_packer,
+                &pack_id,
+            )
+            .await?;
+            if self.write_buffer.is_none() {
+                bytes += pack_bytes;
+                self.progress.on_progress(&uploading(done as u64 + 1, bytes));
+            }
+        }
+        if let Some(buffer) = &self.write_buffer {
+            let uploaded = AtomicU64::new(0);
+            let uploaded_bytes = AtomicU64::new(0);
+            buffer
+                .upload_with_progress(self.storage.as_ref(), &|chunk_bytes| {
+                    let done = uploaded.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+                    let bytes = uploaded_bytes
+                        .fetch_add(chunk_bytes, atomic::Ordering::Relaxed)
+                        + chunk_bytes;
+                    self.progress.on_progress(&uploading(done, bytes));
+                })
+                .await?;
+        }
+
+        self.progress.on_progress(&Progress::new(Phase::WritingMetadata, 0, Some(1)));
+        let scratch = self.change_set.split_off(&Self::scratch_path());
+        let flushed = flush(
+            self.storage.as_ref(),
+            &self.change_set,
+            self.snapshot_id(),
+            message,
             properties,
+            &self.sources,
+            &self.config,
+            self.runtime.as_ref(),
+            &self.session_transformers().await,
         )
-        .await?;
+        .await;
+        let new_snapshot_id = match flushed {
+            Ok((id, split)) => {
+                if let Some(ManifestSplit { manifests, reason }) = split {
+                    self.emit(Event::ManifestSplit {
+                        snapshot: id.clone(),
+                        manifests,
+                        reason,
+                    });
+                }
+                id
+            }
+            Err(err) => {
+                self.change_set.merge(scratch);
+                return Err(err);
+            }
+        };
+        self.progress.on_progress(&Progress::new(Phase::WritingMetadata, 1, Some(1)));
 
         self.snapshot_id = new_snapshot_id.clone();
-        self.change_set = ChangeSet::default();
+        // the scratch area survives the commit, it's only dropped with the session, and its
+        // chunks keep their transformers
+        let scratch_chunks: HashSet<ChunkId> = scratch
+            .chunk_changes()
+            .flat_map(|(_, chunks)| chunks.values())
+            .filter_map(|payload| match payload {
+                Some(ChunkPayload::Ref(ChunkRef { id, .. })) => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+        self.change_set = scratch;
+        self.written_chunks.lock().await.retain(|id, _| scratch_chunks.contains(id));
+        self.copied_chunks.retain(|id, _| scratch_chunks.contains(id));
         Ok(new_snapshot_id)
     }
 
+    /// Delete the chunks written and then overwritten during this session.
+    ///
+    /// Chunks that are still referenced by the session changes, for example because the same
+    /// payload was set to multiple coordinates, are preserved. The chunks are forgotten even
+    /// if the deletion fails, see [`Event::ChunkCleanupFailed`].
+    async fn delete_superseded_chunks(&mut self) -> RepositoryResult<usize> {
+        let mut superseded = take(&mut self.superseded_chunks);
+        for (_, chunks) in self.change_set.chunk_changes() {
+            for payload in chunks.values() {
+                if let Some(ChunkPayload::Ref(ChunkRef { id, .. })) = payload {
+                    superseded.remove(id);
+                }
+            }
+        }
+        let mut not_packed = HashSet::new();
+        for id in superseded {
+            // packs that were not uploaded yet can be dropped
+            if !self.chunk_packer.remove(&id).await {
+                not_packed.insert(id);
+            }
+        }
+        superseded = not_packed;
+        if let Some(buffer) = &self.write_buffer {
+            // buffered chunks were never uploaded, there is nothing to delete
+            let mut uploaded = HashSet::new();
+            for id in superseded {
+                if !buffer.remove(&id).await? {
+                    uploaded.insert(id);
+                }
+            }
+            superseded = uploaded;
+        }
+        if superseded.is_empty() {
+            return Ok(0);
+        }
+        let deleted =
+            self.storage.delete_chunks(futures::stream::iter(superseded).boxed()).await?;
+        Ok(deleted)
+    }
+
     pub async fn commit(
         &mut self,
         update_branch_name: &str,
         message: &str,
         properties: Option<SnapshotProperties>,
     ) -> RepositoryResult<SnapshotId> {
+        self.authorize(Operation::UpdateRef(update_branch_name))?;
         let current = fetch_branch_tip(self.storage.as_ref(), update_branch_name).await;
 
         match current {
@@ -696,6 +3190,11 @@ impl Repository {
             Ok(ref_data) => {
                 // we can detect there will be a conflict before generating the new snapshot
                 if ref_data.snapshot != self.snapshot_id {
+                    self.emit(Event::Conflict {
+                        branch: update_branch_name.to_string(),
+                        expected_parent: Some(self.snapshot_id.clone()),
+                        actual_parent: Some(ref_data.snapshot.clone()),
+                    });
                     Err(RepositoryError::Conflict {
                         expected_parent: Some(self.snapshot_id.clone()),
                         actual_parent: Some(ref_data.snapshot.clone()),
@@ -707,33 +3206,465 @@ impl Repository {
         }
     }
 
-    async fn do_commit(
+    /// Commit, identifying the changes with a client supplied `idempotency_key`
+    ///
+    /// If a snapshot with the same key was already committed to the branch after the parent of
+    /// the session, that snapshot is returned and no new commit is created. This makes
+    /// the commit safe to retry when the outcome of a previous attempt is unknown, for example,
+    /// after a network failure. The retry can reuse the session, or use a new one with the
+    /// same parent and changes.
+    pub async fn commit_idempotent(
         &mut self,
         update_branch_name: &str,
         message: &str,
         properties: Option<SnapshotProperties>,
+        idempotency_key: &str,
     ) -> RepositoryResult<SnapshotId> {
-        let parent_snapshot = self.snapshot_id.clone();
-        let properties = properties.unwrap_or_default();
-        let new_snapshot = self.flush(message, properties).await?;
+        self.authorize(Operation::UpdateRef(update_branch_name))?;
+        let key = serde_json::Value::from(idempotency_key);
+        // a previous attempt could have flushed the session but failed to update the branch
+        let own_snapshot = self.storage.fetch_snapshot(&self.snapshot_id).await?;
+        let flushed = own_snapshot.properties.get(IDEMPOTENCY_KEY_PROPERTY) == Some(&key);
+        let parent = if flushed {
+            own_snapshot.local_ancestry().next().map(|parent| parent.id)
+        } else {
+            Some(self.snapshot_id.clone())
+        };
 
-        match update_branch(
+        match fetch_branch_tip(self.storage.as_ref(), update_branch_name).await {
+            Ok(ref_data) => {
+                if let Some(committed) = self
+                    .find_idempotency_key(&ref_data.snapshot, parent.as_ref(), &key)
+                    .await?
+                {
+                    self.snapshot_id = committed.clone();
+                    self.change_set = ChangeSet::default();
+                    self.superseded_chunks.clear();
+                    self.written_chunks.lock().await.clear();
+                    self.copied_chunks.clear();
+                    if let Some(buffer) = &self.write_buffer {
+                        buffer.clear().await?;
+                    }
+                    self.chunk_packer.clear().await;
+                    return Ok(committed);
+                }
+            }
+            Err(RefError::RefNotFound(_)) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        if flushed {
+            // the snapshot is already written, only the branch update is missing
+            return self
+                .publish_snapshot(update_branch_name, self.snapshot_id.clone(), parent)
+                .await;
+        }
+
+        let mut properties = properties.unwrap_or_default();
+        properties.insert(IDEMPOTENCY_KEY_PROPERTY.to_string(), key);
+        self.commit(update_branch_name, message, Some(properties)).await
+    }
+
+    /// Commit, recording where the data came from in a [`Lineage`] section of the snapshot
+    ///
+    /// Use [`crate::ops::lineage::trace_lineage`] to follow the recorded inputs.
+    pub async fn commit_with_lineage(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+        lineage: &Lineage,
+    ) -> RepositoryResult<SnapshotId> {
+        let mut properties = properties.unwrap_or_default();
+        properties.insert(LINEAGE_PROPERTY.to_string(), lineage.to_property());
+        self.commit(update_branch_name, message, Some(properties)).await
+    }
+
+    /// Replace the tip of `update_branch_name` with a snapshot that has the changes of the
+    /// session and `message`, and the same parents as the tip
+    ///
+    /// For fixes that shouldn't add a snapshot to the history, like a typo in the message or
+    /// the attributes. The session must be at the tip of the branch, and only change the
+    /// attributes and metadata of existing nodes, otherwise the amend fails with
+    /// [`RepositoryError::InvalidAmend`]. If the branch moved, it fails with a
+    /// [`RepositoryError::Conflict`]. With no `properties`, the ones of the tip are kept.
+    /// The properties Icechunk records, the ones starting with `icechunk.` like the
+    /// documents or the idempotency key, are always carried over from the tip.
+    ///
+    /// The replaced snapshot stays in storage until it's garbage collected, sessions started
+    /// from it can no longer commit to the branch.
+    pub async fn amend_commit(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> RepositoryResult<SnapshotId> {
+        self.authorize(Operation::UpdateRef(update_branch_name))?;
+        let tip = fetch_branch_tip(self.storage.as_ref(), update_branch_name).await?;
+        if tip.snapshot != self.snapshot_id {
+            self.emit(Event::Conflict {
+                branch: update_branch_name.to_string(),
+                expected_parent: Some(self.snapshot_id.clone()),
+                actual_parent: Some(tip.snapshot.clone()),
+            });
+            return Err(RepositoryError::Conflict {
+                expected_parent: Some(self.snapshot_id.clone()),
+                actual_parent: Some(tip.snapshot),
+            });
+        }
+        let mut changes = self.change_set.clone();
+        changes.split_off(&Self::scratch_path());
+        if !changes.is_metadata_only() {
+            return Err(RepositoryError::InvalidAmend(
+                "only attributes and metadata can change".to_string(),
+            ));
+        }
+        // flushing moves the session, it's restored if anything fails after it
+        let change_set = self.change_set.clone();
+        let snapshot_id = self.snapshot_id.clone();
+        let published = match self
+            .write_amended(
+                update_branch_name,
+                message,
+                properties,
+                &tip.snapshot,
+                changes,
+            )
+            .await
+        {
+            Ok(amended_id) => {
+                self.publish_snapshot(update_branch_name, amended_id, Some(tip.snapshot))
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        match published {
+            Ok(amended_id) => {
+                self.snapshot_id = amended_id.clone();
+                Ok(amended_id)
+            }
+            Err(err) => {
+                self.change_set = change_set;
+                self.snapshot_id = snapshot_id;
+                Err(err)
+            }
+        }
+    }
+
+    /// Write the snapshot and transaction log replacing `tip`, with the `changes` of the
+    /// session, see [`Repository::amend_commit`]
+    async fn write_amended(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+        tip: &SnapshotId,
+        changes: ChangeSet,
+    ) -> RepositoryResult<SnapshotId> {
+        let tip_snapshot = self.storage.fetch_snapshot(tip).await?;
+        let mut tx_log = self.storage.fetch_transaction_log(tip).await?.as_ref().clone();
+
+        let mut amended = if changes.is_empty() {
+            // a copy of the tip
+            let mut amended = tip_snapshot.with_history_of(&tip_snapshot);
+            amended.properties = amended_properties(properties, &tip_snapshot, &[]);
+            amended
+        } else {
+            // the flush recomputes the properties that depend on the changes
+            let properties =
+                amended_properties(properties, &tip_snapshot, &FLUSHED_PROPERTIES);
+            self.check_schema_constraints(update_branch_name).await?;
+            // the flushed snapshot is never referenced, snapshots are immutable so the
+            // amended one gets its own id
+            let flushed = self.flush(message, properties).await?;
+            tx_log.merge(
+                self.storage.fetch_transaction_log(&flushed).await?.as_ref().clone(),
+            );
+            self.storage.fetch_snapshot(&flushed).await?.with_history_of(&tip_snapshot)
+        };
+        amended.metadata.id = self.sources.new_id();
+        amended.metadata.message = message.to_string();
+        amended.metadata.written_at = self.sources.now();
+        let amended_id = amended.metadata.id.clone();
+        self.storage.write_snapshot(amended_id.clone(), Arc::new(amended)).await?;
+        self.storage.write_transaction_log(amended_id.clone(), Arc::new(tx_log)).await?;
+        Ok(amended_id)
+    }
+
+    /// The nodes of the current snapshot where the attribute `key` is `value`
+    ///
+    /// Uses the index of the snapshot if it has the key, otherwise the attributes of every
+    /// node are read. Uncommitted changes are not searched.
+    pub async fn find_by_attribute(
+        &self,
+        key: &str,
+        value: &serde_json::Value,
+    ) -> RepositoryResult<Vec<Path>> {
+        let snapshot = self.storage.fetch_snapshot(&self.snapshot_id).await?;
+        let indexed = AttributesIndex::from_snapshot(&snapshot)?
+            .and_then(|index| index.find(key, value));
+        Ok(indexed.unwrap_or_else(|| {
+            AttributesIndex::build(&snapshot, &[key.to_string()])
+                .find(key, value)
+                .unwrap_or_default()
+        }))
+    }
+
+    /// The lineage recorded by the current snapshot, if any
+    pub async fn lineage(&self) -> RepositoryResult<Option<Lineage>> {
+        Lineage::from_snapshot(
+            self.storage.fetch_snapshot(&self.snapshot_id).await?.as_ref(),
+        )
+    }
+
+    /// Find a snapshot with the idempotency key, between `tip` and `parent`, excluding `parent`
+    async fn find_idempotency_key(
+        &self,
+        tip: &SnapshotId,
+        parent: Option<&SnapshotId>,
+        key: &serde_json::Value,
+    ) -> RepositoryResult<Option<SnapshotId>> {
+        let tip_snapshot = self.storage.fetch_snapshot(tip).await?;
+        let expired = expired_snapshots(self.storage.as_ref()).await?;
+        let candidates = iter::once(tip.clone())
+            .chain(tip_snapshot.local_ancestry().map(|meta| meta.id))
+            .take_while(|id| Some(id) != parent)
+            .filter(|id| !expired.contains(id));
+        for id in candidates {
+            let snapshot = self.storage.fetch_snapshot(&id).await?;
+            if snapshot.properties.get(IDEMPOTENCY_KEY_PROPERTY) == Some(key) {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record the arrays deleted by the session in the trash, before committing
+    async fn trash_deleted_arrays(&self, branch: &str) -> RepositoryResult<()> {
+        if self.change_set.deleted_arrays().next().is_none() {
+            return Ok(());
+        }
+        let old = self.storage.fetch_snapshot(&self.snapshot_id).await?;
+        let now = self.sources.now();
+        for path in self.change_set.deleted_arrays() {
+            if let Ok(NodeSnapshot { node_data: NodeData::Array(..), .. }) =
+                old.get_node(path)
+            {
+                let item =
+                    TrashedItem::Array { branch: branch.to_string(), path: path.clone() };
+                put_in_trash(
+                    self.storage.as_ref(),
+                    item,
+                    self.snapshot_id.clone(),
+                    now,
+                    self.config.trash_retention,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_schema_constraints(&self, branch: &str) -> RepositoryResult<()> {
+        let rules: Vec<SchemaRule> = self
+            .config
+            .schema_constraints
+            .iter()
+            .filter(|c| c.applies_to(branch))
+            .map(|c| c.rule)
+            .collect();
+        if rules.is_empty() || self.change_set.is_empty() {
+            return Ok(());
+        }
+        let old = self.storage.fetch_snapshot(&self.snapshot_id).await?;
+        let nodes: Vec<NodeSnapshot> = self.list_nodes().await?.collect();
+        let mut violations = check_schema(&rules, old.iter(), nodes.iter().cloned());
+        if rules.contains(&SchemaRule::XarrayConventions) {
+            let mut arrays = Vec::new();
+            for node in nodes {
+                if let NodeData::Array(meta, _) = node.node_data {
+                    let atts =
+                        load_user_attributes(self.storage.as_ref(), node.user_attributes)
+                            .await?;
+                    arrays.push((node.path, meta.shape, atts));
+                }
+            }
+            violations.extend(
+                check_conventions(
+                    arrays.iter().map(|(path, shape, atts)| (path, shape, atts.as_ref())),
+                )
+                .into_iter()
+                .map(SchemaViolation::Conventions),
+            );
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(RepositoryError::SchemaViolations(violations))
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        self.events.on_event(&EventRecord::new(self.sources.now(), event));
+    }
+
+    async fn publish_snapshot(
+        &self,
+        update_branch_name: &str,
+        new_snapshot: SnapshotId,
+        parent_snapshot: Option<SnapshotId>,
+    ) -> RepositoryResult<SnapshotId> {
+        self.progress.on_progress(&Progress::new(Phase::UpdatingRef, 0, Some(1)));
+        let pending_hooks = record_pending_hooks(
+            self.storage.as_ref(),
+            &self.config.commit_hooks,
+            update_branch_name,
+            &new_snapshot,
+            parent_snapshot.as_ref(),
+            self.sources.now(),
+        )
+        .await?;
+        let updated = update_branch(
             self.storage.as_ref(),
             update_branch_name,
             new_snapshot.clone(),
-            Some(&parent_snapshot),
+            parent_snapshot.as_ref(),
             self.config.unsafe_overwrite_refs,
         )
-        .await
-        {
-            Ok(_) => Ok(new_snapshot),
+        .await;
+        if updated.is_err() && pending_hooks.is_some() {
+            discard_pending_hooks(self.storage.as_ref(), &new_snapshot).await?;
+        }
+        match updated {
+            Ok(_) => {
+                self.progress.on_progress(&Progress::new(Phase::UpdatingRef, 1, Some(1)));
+                if let Some(tips) = self.storage.branch_tip_cache() {
+                    tips.insert(update_branch_name, new_snapshot.clone());
+                }
+                self.emit(Event::Commit {
+                    branch: update_branch_name.to_string(),
+                    snapshot: new_snapshot.clone(),
+                    parent: parent_snapshot,
+                });
+                self.advertise_refs().await;
+                if let Some(pending) = pending_hooks {
+                    self.run_commit_hooks(pending).await?;
+                }
+                Ok(new_snapshot)
+            }
             Err(RefError::Conflict { expected_parent, actual_parent }) => {
+                self.emit(Event::Conflict {
+                    branch: update_branch_name.to_string(),
+                    expected_parent: expected_parent.clone(),
+                    actual_parent: actual_parent.clone(),
+                });
                 Err(RepositoryError::Conflict { expected_parent, actual_parent })
             }
             Err(err) => Err(err.into()),
         }
     }
 
+    async fn do_commit(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> RepositoryResult<SnapshotId> {
+        self.check_schema_constraints(update_branch_name).await?;
+        self.trash_deleted_arrays(update_branch_name).await?;
+        let parent_snapshot = self.snapshot_id.clone();
+        let properties = properties.unwrap_or_default();
+        let new_snapshot = self.flush(message, properties).await?;
+        self.publish_snapshot(update_branch_name, new_snapshot, Some(parent_snapshot))
+            .await
+    }
+
+    /// The first phase of a commit: write the snapshot of the session, without updating
+    /// `update_branch_name`
+    ///
+    /// Fails with a conflict if the branch already moved. The session continues from the
+    /// new snapshot, as after a commit. [`Repository::finalize_commit`] puts the snapshot in
+    /// the branch, until then nothing changes for readers, and an abandoned prepared
+    /// snapshot is deleted by garbage collection. See [`crate::ops::transaction`].
+    pub async fn prepare_commit(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> RepositoryResult<PreparedCommit> {
+        self.authorize(Operation::UpdateRef(update_branch_name))?;
+        let parent =
+            match fetch_branch_tip(self.storage.as_ref(), update_branch_name).await {
+                Ok(ref_data) if ref_data.snapshot != self.snapshot_id => {
+                    return Err(RepositoryError::Conflict {
+                        expected_parent: Some(self.snapshot_id.clone()),
+                        actual_parent: Some(ref_data.snapshot),
+                    });
+                }
+                Ok(ref_data) => Some(ref_data.snapshot),
+                Err(RefError::RefNotFound(_)) => None,
+                Err(err) => return Err(err.into()),
+            };
+        self.check_schema_constraints(update_branch_name).await?;
+        self.trash_deleted_arrays(update_branch_name).await?;
+        let snapshot = self.flush(message, properties.unwrap_or_default()).await?;
+        Ok(PreparedCommit { branch: update_branch_name.to_string(), parent, snapshot })
+    }
+
+    /// The second phase of a commit: update the branch of `prepared` to its snapshot
+    ///
+    /// Fails with a conflict if the branch moved since the commit was prepared. An error
+    /// from the commit hooks comes after the branch moved, the commit stays.
+    pub async fn finalize_commit(
+        &self,
+        prepared: &PreparedCommit,
+    ) -> RepositoryResult<SnapshotId> {
+        self.publish_snapshot(
+            &prepared.branch,
+            prepared.snapshot.clone(),
+            prepared.parent.clone(),
+        )
+        .await
+    }
+
+    /// Move the branch of a finalized commit back to its parent
+    ///
+    /// Fails with a conflict if the branch moved since the commit was finalized, the commit
+    /// hooks that already ran are not undone. A new branch is deleted.
+    pub async fn rollback_commit(
+        &self,
+        prepared: &PreparedCommit,
+    ) -> RepositoryResult<()> {
+        self.authorize(Operation::UpdateRef(&prepared.branch))?;
+        let tip = fetch_branch_tip(self.storage.as_ref(), &prepared.branch).await?;
+        if tip.snapshot != prepared.snapshot {
+            return Err(RepositoryError::Conflict {
+                expected_parent: Some(prepared.snapshot.clone()),
+                actual_parent: Some(tip.snapshot),
+            });
+        }
+        match &prepared.parent {
+            Some(parent) => {
+                update_branch(
+                    self.storage.as_ref(),
+                    &prepared.branch,
+                    parent.clone(),
+                    Some(&prepared.snapshot),
+                    self.config.unsafe_overwrite_refs,
+                )
+                .await?;
+                if let Some(tips) = self.storage.branch_tip_cache() {
+                    tips.insert(&prepared.branch, parent.clone());
+                }
+            }
+            None => {
+                delete_branch(self.storage.as_ref(), &prepared.branch).await?;
+            }
+        }
+        self.advertise_refs().await;
+        Ok(())
+    }
+
     /// Detect and optionally fix conflicts between the current [`ChangeSet`] (or session) and
     /// the tip of the branch.
     ///
@@ -809,29 +3740,17 @@ impl Repository {
             // nothing to do, commit should work without rebasing
             Ok(())
         } else {
-            let current_snapshot =
-                self.storage.fetch_snapshot(&ref_data.snapshot).await?;
-            // FIXME: this should be the whole ancestry not local
-            let anc = current_snapshot.local_ancestry().map(|meta| meta.id);
-            let new_commits = iter::once(ref_data.snapshot.clone())
-                .chain(anc.take_while(|snap_id| snap_id != &self.snapshot_id))
-                .collect::<Vec<_>>();
+            let new_commits = self.commits_since_base(&ref_data.snapshot).await?;
 
             // TODO: this clone is expensive
             // we currently need it to be able to process commits one by one without modifying the
             // changeset in case of failure
             // let mut changeset = self.change_set.clone();
 
-            // we need to reverse the iterator to process them in order of oldest first
-            for snap_id in new_commits.into_iter().rev() {
+            self.counters.conflict_retry();
+            for snap_id in new_commits {
                 let tx_log = self.storage.fetch_transaction_log(&snap_id).await?;
-                let repo = Repository {
-                    config: self.config().clone(),
-                    storage: self.storage.clone(),
-                    snapshot_id: snap_id.clone(),
-                    change_set: ChangeSet::default(),
-                    virtual_resolver: self.virtual_resolver.clone(),
-                };
+                let repo = self.read_only_at(&snap_id);
 
                 let change_set = take(&mut self.change_set);
                 // TODO: this should probably execute in a worker thread
@@ -854,9 +3773,254 @@ impl Repository {
         }
     }
 
-    pub fn changes(&self) -> &ChangeSet {
-        &self.change_set
-    }
+    /// The conflicts the session would have with the commits made to `branch` since its
+    /// base snapshot, oldest commit first
+    ///
+    /// Nothing is written and the session is not modified. Long running writers can check
+    /// periodically, and rebase before uploading more chunks, instead of finding the
+    /// conflicts when they commit. Only commits with conflicts are in the result. When it's
+    /// empty, the session can be rebased with no conflicts, and committed if the branch
+    /// didn't move.
+    pub async fn detect_conflicts(
+        &self,
+        branch: &str,
+    ) -> RepositoryResult<Vec<CommitConflicts>> {
+        let tip = fetch_branch_tip(self.storage.as_ref(), branch).await?.snapshot;
+        if tip == self.snapshot_id {
+            return Ok(Vec::new());
+        }
+        let mut res = Vec::new();
+        for snap_id in self.commits_since_base(&tip).await? {
+            let tx_log = self.storage.fetch_transaction_log(&snap_id).await?;
+            let repo = self.read_only_at(&snap_id);
+            let resolution = ConflictDetector
+                .solve(&tx_log, &repo, self.change_set.clone(), self)
+                .await?;
+            if let ConflictResolution::Unsolvable { reason, .. } = resolution {
+                res.push(CommitConflicts { snapshot: snap_id, conflicts: reason });
+            }
+        }
+        Ok(res)
+    }
+
+    /// The snapshots between `tip` and the base snapshot of the session, oldest first
+    ///
+    /// Fails if there are more than [`RepositoryConfig::max_history_depth`] of them.
+    async fn commits_since_base(
+        &self,
+        tip: &SnapshotId,
+    ) -> RepositoryResult<Vec<SnapshotId>> {
+        let tip_snapshot = self.storage.fetch_snapshot(tip).await?;
+        let expired = expired_snapshots(self.storage.as_ref()).await?;
+        // FIXME: this should be the whole ancestry not local
+        let anc = tip_snapshot.local_ancestry().map(|meta| meta.id);
+        let mut res = iter::once(tip.clone())
+            .chain(
+                anc.take_while(|snap_id| snap_id != &self.snapshot_id)
+                    .filter(|snap_id| !expired.contains(snap_id)),
+            )
+            .collect::<Vec<_>>();
+        let limit = self.config.max_history_depth;
+        if limit > 0 && res.len() > limit {
+            return Err(RepositoryError::HistoryTooDeep { snapshot: tip.clone(), limit });
+        }
+        res.reverse();
+        Ok(res)
+    }
+
+    /// A session at `snapshot`, with no changes, used to compare commits with this one
+    fn read_only_at(&self, snapshot: &SnapshotId) -> Repository {
+        Repository {
+            config: self.config().clone(),
+            storage: self.storage.clone(),
+            backend: self.backend.clone(),
+            counters: Arc::clone(&self.counters),
+            snapshot_id: snapshot.clone(),
+            change_set: ChangeSet::default(),
+            virtual_resolver: self.virtual_resolver.clone(),
+            written_chunks: Default::default(),
+            copied_chunks: Default::default(),
+            superseded_chunks: Default::default(),
+            write_buffer: None,
+            chunk_packer: Arc::new(ChunkPacker::new(self.config.chunk_pack_size_bytes)),
+            codec_workers: self.codec_workers.clone(),
+            runtime: Arc::clone(&self.runtime),
+            dictionaries: Arc::clone(&self.dictionaries),
+            node_kinds: self.node_kinds.clone(),
+            sources: self.sources.clone(),
+            authorizer: Arc::clone(&self.authorizer),
+            progress: default_progress_observer(),
+            events: default_event_observer(),
+            tunables: self.tunables.clone(),
+        }
+    }
+
+    /// Commit to `update_branch_name` in turn with the other queued committers
+    ///
+    /// Takes a ticket in the commit queue of the branch, see [`crate::commit_queue`], and
+    /// waits, checking with backoff, until the tickets ahead of it leave or expire. Then the
+    /// session is rebased on the branch tip with `solver`, and committed. The ticket is
+    /// renewed while waiting and committing, and released whatever the outcome. Commits that
+    /// don't go through the queue are not ordered, they can still conflict with queued ones.
+    pub async fn commit_queued(
+        &mut self,
+        update_branch_name: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+        solver: &dyn ConflictSolver,
+        config: &CommitQueueConfig,
+    ) -> RepositoryResult<SnapshotId> {
+        self.authorize(Operation::UpdateRef(update_branch_name))?;
+        let storage = Arc::clone(&self.storage);
+        let mut ticket = enqueue(
+            storage.as_ref(),
+            update_branch_name,
+            self.sources.now(),
+            config.lease,
+        )
+        .await?;
+        let res =
+            self.commit_in_turn(&mut ticket, message, properties, solver, config).await;
+        let left = leave_queue(storage.as_ref(), &ticket).await;
+        let snapshot = res?;
+        left?;
+        Ok(snapshot)
+    }
+
+    async fn commit_in_turn(
+        &mut self,
+        ticket: &mut QueueTicket,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+        solver: &dyn ConflictSolver,
+        config: &CommitQueueConfig,
+    ) -> RepositoryResult<SnapshotId> {
+        let storage = Arc::clone(&self.storage);
+        let mut delay = config.poll_interval;
+        while !has_turn(storage.as_ref(), ticket, self.sources.now()).await? {
+            let now = self.sources.now();
+            if config.timeout.is_some_and(|timeout| now - ticket.enqueued_at >= timeout) {
+                return Err(RepositoryError::CommitQueueTimeout(ticket.branch.clone()));
+            }
+            if ticket.expires_at - now < config.lease / 2 {
+                renew_ticket(storage.as_ref(), ticket, now, config.lease).await?;
+            }
+            config.runtime.sleep(delay).await;
+            delay = delay.saturating_mul(2).min(config.max_poll_interval);
+        }
+
+        let interval = (config.lease / 3).to_std().unwrap_or(config.poll_interval);
+        let mut renewed = ticket.clone();
+        let sources = self.sources.clone();
+        let renewals = pin!(async move {
+            loop {
+                config.runtime.sleep(interval).await;
+                // a failed renewal only risks losing the turn if the next ones fail too
+                let _ = renew_ticket(
+                    storage.as_ref(),
+                    &mut renewed,
+                    sources.now(),
+                    config.lease,
+                )
+                .await;
+            }
+        });
+        let branch = ticket.branch.clone();
+        let commit = pin!(async {
+            match self.rebase(solver, &branch).await {
+                Ok(()) | Err(RepositoryError::Ref(RefError::RefNotFound(_))) => {}
+                Err(err) => return Err(err),
+            }
+            self.commit(&branch, message, properties).await
+        });
+        match future::select(commit, renewals).await {
+            future::Either::Left((res, _)) => res,
+            future::Either::Right(((), commit)) => commit.await,
+        }
+    }
+
+    /// Summarize the changes in the current session that would be included in a commit
+    pub async fn status(&self) -> RepositoryResult<SessionStatus> {
+        let change_set = &self.change_set;
+        let paths: HashMap<NodeId, Path> =
+            self.list_nodes().await?.map(|node| (node.id, node.path)).collect();
+        let resolve = |ids: &mut dyn Iterator<Item = &NodeId>| {
+            ids.filter_map(|id| paths.get(id).cloned()).collect::<BTreeSet<_>>()
+        };
+
+        let mut res = SessionStatus {
+            new_groups: change_set.new_groups().map(|(path, _)| path.clone()).collect(),
+            new_arrays: change_set.new_arrays().map(|(path, _)| path.clone()).collect(),
+            updated_arrays: resolve(&mut change_set.zarr_updated_arrays()),
+            updated_attributes: resolve(&mut change_set.user_attributes_updated_nodes()),
+            deleted_groups: change_set.deleted_groups().cloned().collect(),
+            deleted_arrays: change_set.deleted_arrays().cloned().collect(),
+            ..Default::default()
+        };
+
+        for (node_id, chunks) in change_set.chunk_changes() {
+            let Some(path) = paths.get(node_id) else {
+                // chunks of deleted arrays won't be committed
+                continue;
+            };
+            for payload in chunks.values() {
+                let counter = match payload {
+                    Some(ChunkPayload::Inline(bytes)) => {
+                        res.inline_chunk_bytes += bytes.len() as u64;
+                        &mut res.written_chunks
+                    }
+                    Some(ChunkPayload::Ref(ChunkRef { length, .. })) => {
+                        res.uploaded_chunk_bytes += length;
+                        &mut res.written_chunks
+                    }
+                    Some(ChunkPayload::Virtual(reference)) => {
+                        res.virtual_chunk_bytes += reference.chunk_length();
+                        &mut res.written_chunks
+                    }
+                    None => &mut res.deleted_chunks,
+                };
+                *counter.entry(path.clone()).or_default() += 1;
+            }
+        }
+        Ok(res)
+    }
+
+    /// Validate that the current session could be committed to `update_branch_name`,
+    /// without writing anything to storage.
+    ///
+    /// Fails in the same situations in which [`Repository::commit`] would fail before
+    /// writing a new snapshot, like [`RepositoryError::NoChangesToCommit`] if the session has
+    /// no changes or [`RepositoryError::Conflict`] if the branch tip is no longer the parent
+    /// of the session. Otherwise returns the [`Repository::status`] of the session.
+    ///
+    /// Another session can still commit before this one, so committing may fail even after a
+    /// successful dry run.
+    pub async fn commit_dry_run(
+        &self,
+        update_branch_name: &str,
+    ) -> RepositoryResult<SessionStatus> {
+        let mut committed = self.change_set.clone();
+        committed.split_off(&Self::scratch_path());
+        if committed.is_empty() {
+            return Err(RepositoryError::NoChangesToCommit);
+        }
+        match fetch_branch_tip(self.storage.as_ref(), update_branch_name).await {
+            Err(RefError::RefNotFound(_)) => {}
+            Err(err) => return Err(err.into()),
+            Ok(ref_data) if ref_data.snapshot != self.snapshot_id => {
+                return Err(RepositoryError::Conflict {
+                    expected_parent: Some(self.snapshot_id.clone()),
+                    actual_parent: Some(ref_data.snapshot),
+                })
+            }
+            Ok(_) => {}
+        }
+        self.status().await
+    }
+
+    pub fn changes(&self) -> &ChangeSet {
+        &self.change_set
+    }
 
     pub fn change_set_bytes(&self) -> RepositoryResult<Vec<u8>> {
         self.change_set.export_to_bytes()
@@ -867,6 +4031,7 @@ impl Repository {
     }
 
     pub async fn new_branch(&self, branch_name: &str) -> RepositoryResult<BranchVersion> {
+        self.authorize(Operation::UpdateRef(branch_name))?;
         // TODO: The parent snapshot should exist?
         let version = match update_branch(
             self.storage.as_ref(),
@@ -883,15 +4048,106 @@ impl Repository {
             }
             Err(err) => Err(err.into()),
         }?;
+        self.advertise_refs().await;
+        Ok(version)
+    }
+
+    /// The tip of the branch with its version token, for use with
+    /// [`Repository::update_branch_if_version`]
+    pub async fn branch_tip_versioned(
+        &self,
+        branch_name: &str,
+    ) -> RepositoryResult<(BranchVersion, SnapshotId)> {
+        let (version, data) =
+            fetch_branch_tip_versioned(self.storage.as_ref(), branch_name).await?;
+        Ok((version, data.snapshot))
+    }
 
+    /// Point the branch to `snapshot_id` only if it's still at version `expected`, see
+    /// [`crate::refs::update_branch_if_version`]
+    pub async fn update_branch_if_version(
+        &self,
+        branch_name: &str,
+        snapshot_id: &SnapshotId,
+        expected: Option<&BranchVersion>,
+    ) -> RepositoryResult<BranchVersion> {
+        self.authorize(Operation::UpdateRef(branch_name))?;
+        let version = update_branch_if_version(
+            self.storage.as_ref(),
+            branch_name,
+            snapshot_id.clone(),
+            expected,
+        )
+        .await?;
+        self.advertise_refs().await;
         Ok(version)
     }
 
+    /// Delete a branch, it can be restored with [`crate::trash::restore_branch`] until the
+    /// trash retention expires
+    pub async fn delete_branch(&self, branch_name: &str) -> RepositoryResult<TrashEntry> {
+        self.authorize(Operation::UpdateRef(branch_name))?;
+        let entry = trash_branch(
+            self.storage.as_ref(),
+            branch_name,
+            self.sources.now(),
+            self.config.trash_retention,
+        )
+        .await?;
+        self.advertise_refs().await;
+        Ok(entry)
+    }
+
+    /// Add back to the session an array deleted by a previous commit, with its attributes and
+    /// chunks
+    ///
+    /// The entry is removed from the trash, the array must be committed to be kept.
+    pub async fn restore_array(
+        &mut self,
+        trash_entry_id: &str,
+    ) -> RepositoryResult<Path> {
+        let entry = fetch_trash_entry(self.storage.as_ref(), trash_entry_id).await?;
+        let TrashedItem::Array { path, .. } = entry.item else {
+            return Err(RefError::InvalidRefType(trash_entry_id.to_string()).into());
+        };
+        let snapshot = self.storage.fetch_snapshot(&entry.snapshot).await?;
+        let node = snapshot.get_node(&path)?.clone();
+        let NodeData::Array(meta, manifests) = node.node_data else {
+            return Err(RepositoryError::NotAnArray {
+                node,
+                message: "restoring from the trash".to_string(),
+            });
+        };
+        self.add_array(path.clone(), meta).await?;
+        let atts =
+            load_user_attributes(self.storage.as_ref(), node.user_attributes.clone())
+                .await?;
+        if atts.is_some() {
+            self.set_user_attributes(path.clone(), atts).await?;
+        }
+        for manifest_ref in manifests {
+            let manifest = self.storage.fetch_manifests(&manifest_ref.object_id).await?;
+            for ((node_id, coords), payload) in manifest.chunks() {
+                if node_id == &node.id {
+                    self.set_chunk_ref(
+                        path.clone(),
+                        coords.clone(),
+                        Some(payload.clone()),
+                    )
+                    .await?;
+                }
+            }
+        }
+        delete_trash_entry(self.storage.as_ref(), trash_entry_id).await?;
+        Ok(path)
+    }
+
     pub async fn tag(
         &self,
         tag_name: &str,
         snapshot_id: &SnapshotId,
     ) -> RepositoryResult<()> {
+        self.authorize(Operation::UpdateRef(tag_name))?;
         create_tag(
             self.storage.as_ref(),
             tag_name,
@@ -899,8 +4155,102 @@ impl Repository {
             self.config.unsafe_overwrite_refs,
         )
         .await?;
+        self.advertise_refs().await;
+        Ok(())
+    }
+
+    /// Failed hooks are reported as [`Event::HookFailed`], they stay pending
+    async fn run_commit_hooks(&self, pending: PendingHooks) -> RepositoryResult<()> {
+        let failures =
+            run_hooks(self.storage.as_ref(), &self.config.commit_hooks, pending).await?;
+        for HookFailure { hook, snapshot, message } in failures {
+            self.emit(Event::HookFailed { hook, snapshot, message });
+        }
         Ok(())
     }
+
+    /// Run the commit hooks of the session left pending by failed or interrupted commits,
+    /// see [`crate::commit_hooks::run_pending_hooks`]
+    pub async fn run_pending_hooks(&self) -> RepositoryResult<Vec<HookFailure>> {
+        Ok(run_pending_hooks(
+            self.storage.as_ref(),
+            &self.config.commit_hooks,
+            self.sources.now(),
+            PENDING_HOOKS_GRACE,
+        )
+        .await?)
+    }
+
+    /// Rewrite the ref advertisement if it's enabled
+    ///
+    /// The refs were already updated when this runs, so a failure doesn't fail the operation,
+    /// it's reported as [`Event::AdvertisementFailed`] and the advertisement is only behind
+    /// until the next successful write.
+    async fn advertise_refs(&self) {
+        if self.config.advertise_refs {
+            if let Err(err) = advertise_refs(self.storage.as_ref()).await {
+                self.emit(Event::AdvertisementFailed { message: err.to_string() });
+            }
+        }
+    }
+
+    /// The snapshot each tag or branch in `names` points to, see
+    /// [`crate::refs::resolve_refs`]
+    pub async fn resolve_refs(
+        &self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> RepositoryResult<HashMap<String, SnapshotId>> {
+        Ok(resolve_refs(self.storage.as_ref(), names).await?)
+    }
+
+    /// Protect the snapshot of the session from garbage collection and retention for `ttl`
+    ///
+    /// The pin can be renewed with [`crate::pins::renew_pin`], and should be released with
+    /// [`crate::pins::release_pin`] once the snapshot is no longer needed.
+    pub async fn pin(
+        &self,
+        holder: &str,
+        ttl: TimeDelta,
+    ) -> RepositoryResult<SnapshotPin> {
+        Ok(pin_snapshot(
+            self.storage.as_ref(),
+            self.snapshot_id(),
+            holder,
+            self.sources.now(),
+            ttl,
+        )
+        .await?)
+    }
+
+    /// Expire the snapshots the retention rules of the repository config don't keep
+    ///
+    /// See [`crate::ops::retention::apply_retention`], the expired snapshots are deleted by
+    /// the next garbage collection.
+    pub async fn apply_retention(&self) -> RepositoryResult<RetentionSummary> {
+        Ok(apply_retention(
+            self.storage.as_ref(),
+            &self.config.retention_rules,
+            self.sources.now(),
+        )
+        .await?)
+    }
+
+    /// Analyze the snapshot the session started from, see
+    /// [`crate::ops::health::health_report`]
+    pub async fn health_report(
+        &self,
+        config: &HealthConfig,
+    ) -> RepositoryResult<HealthReport> {
+        Ok(health_report(self.storage.as_ref(), self.snapshot_id(), config).await?)
+    }
+
+    /// The decoded objects stored with id `id`, see [`crate::ops::inspect`]
+    pub async fn inspect_object(
+        &self,
+        id: &str,
+    ) -> RepositoryResult<Vec<InspectedObject>> {
+        inspect_object(self.storage.as_ref(), id).await
+    }
 }
 
 impl From<Repository> for ChangeSet {
@@ -911,17 +4261,103 @@ impl From<Repository> for ChangeSet {
 
 async fn new_materialized_chunk(
     storage: &(dyn Storage + Send + Sync),
+    new_id: ChunkId,
     data: Bytes,
 ) -> RepositoryResult<ChunkPayload> {
-    let new_id = ObjectId::random();
     storage.write_chunk(new_id.clone(), data.clone()).await?;
     Ok(ChunkPayload::Ref(ChunkRef { id: new_id, offset: 0, length: data.len() as u64 }))
 }
 
+/// Upload the pack `id`, if it was not uploaded already, to the write buffer if the session
+/// has one, or to `storage`
+async fn upload_pack(
+    storage: &(dyn Storage + Send + Sync),
+    write_buffer: Option<&ChunkWriteBuffer>,
+    chunk_packer: &ChunkPacker,
+    id: &ChunkId,
+) -> RepositoryResult<u64> {
+    match chunk_packer.get(id).await {
+        Some(bytes) => {
+            let len = bytes.len() as u64;
+            match write_buffer {
+                Some(buffer) => buffer.insert(id.clone(), bytes).await?,
+                None => storage.write_chunk(id.clone(), bytes).await?,
+            }
+            chunk_packer.remove(id).await;
+            Ok(len)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Check the root hash of the snapshot, if `options` expects one
+async fn verify_root_hash(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+    options: &OpenOptions,
+) -> RepositoryResult<()> {
+    let Some((expected, algorithm)) = options.root_hash else { return Ok(()) };
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let actual = snapshot_root_hash(storage, &snapshot, algorithm).await?;
+    if actual != expected {
+        return Err(RepositoryError::RootHashMismatch {
+            snapshot: snapshot_id.clone(),
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// The byte range to fetch from the chunk object for `request`, chunk objects can have many
+/// chunks when they are packed
+pub(crate) fn chunk_ref_byte_range(
+    request: &ByteRange,
+    offset: u64,
+    length: u64,
+) -> ByteRange {
+    if length == 0 {
+        request.clone()
+    } else {
+        construct_valid_byte_range(request, offset, length)
+    }
+}
+
+async fn new_buffered_chunk(
+    buffer: &ChunkWriteBuffer,
+    new_id: ChunkId,
+    data: Bytes,
+) -> RepositoryResult<ChunkPayload> {
+    let length = data.len() as u64;
+    buffer.insert(new_id.clone(), data).await?;
+    Ok(ChunkPayload::Ref(ChunkRef { id: new_id, offset: 0, length }))
+}
+
 fn new_inline_chunk(data: Bytes) -> ChunkPayload {
     ChunkPayload::Inline(data)
 }
 
+/// The properties of the amended snapshot of `tip`, `properties` or the ones of `tip`
+///
+/// The reserved properties of `tip`, see [`RESERVED_PROPERTY_PREFIX`], are kept, except
+/// the `recomputed` ones.
+fn amended_properties(
+    properties: Option<SnapshotProperties>,
+    tip: &Snapshot,
+    recomputed: &[&str],
+) -> SnapshotProperties {
+    let Some(mut properties) = properties else {
+        let mut properties = tip.properties.clone();
+        properties.retain(|key, _| !recomputed.contains(&key.as_str()));
+        return properties;
+    };
+    let reserved = tip.properties.iter().filter(|(key, _)| {
+        key.starts_with(RESERVED_PROPERTY_PREFIX) && !recomputed.contains(&key.as_str())
+    });
+    properties.extend(reserved.map(|(key, value)| (key.clone(), value.clone())));
+    properties
+}
+
 pub async fn get_chunk(
     reader: Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
 ) -> RepositoryResult<Option<Bytes>> {
@@ -965,6 +4401,83 @@ async fn updated_nodes<'a>(
         .chain(change_set.new_nodes_iterator(manifest_id)))
 }
 
+/// Resolve `atts`, fetching the attributes file if they are not inline
+pub async fn load_user_attributes(
+    storage: &(dyn Storage + Send + Sync),
+    atts: Option<UserAttributesSnapshot>,
+) -> RepositoryResult<Option<UserAttributes>> {
+    match atts {
+        None => Ok(None),
+        Some(UserAttributesSnapshot::Inline(atts)) => Ok(Some(atts)),
+        Some(UserAttributesSnapshot::Ref(UserAttributesRef { object_id, location })) => {
+            let table = storage.fetch_attributes(&object_id).await?;
+            match table.get(location) {
+                Some(atts) => Ok(Some(atts.clone())),
+                None => Err(IcechunkFormatError::UserAttributesNotFound {
+                    id: object_id,
+                    location,
+                }
+                .into()),
+            }
+        }
+    }
+}
+
+/// Move the inline user attributes of `nodes` larger than `threshold_bytes` to a new
+/// attributes file, and list the attributes files the nodes point to
+fn split_user_attributes(
+    pending: &mut PendingMetadata,
+    sources: &Sources,
+    old_snapshot: &Snapshot,
+    nodes: impl Iterator<Item = NodeSnapshot>,
+    threshold_bytes: u64,
+) -> (Vec<NodeSnapshot>, Vec<AttributeFileInfo>) {
+    let mut nodes: Vec<_> = nodes.collect();
+    let new_id: AttributesId = sources.new_id();
+    let mut split = Vec::new();
+    if threshold_bytes > 0 {
+        for node in nodes.iter_mut() {
+            let Some(UserAttributesSnapshot::Inline(atts)) = &node.user_attributes else {
+                continue;
+            };
+            if atts.to_bytes().len() as u64 > threshold_bytes {
+                let location = split.len() as TableOffset;
+                split.push(atts.clone());
+                node.user_attributes =
+                    Some(UserAttributesSnapshot::Ref(UserAttributesRef {
+                        object_id: new_id.clone(),
+                        location,
+                    }));
+            }
+        }
+    }
+
+    let mut files: Vec<AttributeFileInfo> = Vec::new();
+    if !split.is_empty() {
+        let table = AttributesTable::new(split);
+        files.push(AttributeFileInfo {
+            id: new_id.clone(),
+            format_version: table.icechunk_attributes_format_version,
+        });
+        pending.attributes.push((new_id, Arc::new(table)));
+    }
+    let referenced: HashSet<_> = nodes
+        .iter()
+        .filter_map(|node| match &node.user_attributes {
+            Some(UserAttributesSnapshot::Ref(atts)) => Some(&atts.object_id),
+            _ => None,
+        })
+        .collect();
+    files.extend(
+        old_snapshot
+            .attribute_files
+            .iter()
+            .filter(|file| referenced.contains(&file.id))
+            .cloned(),
+    );
+    (nodes, files)
+}
+
 async fn get_node<'a>(
     storage: &(dyn Storage + Send + Sync),
     change_set: &'a ChangeSet,
@@ -1001,17 +4514,13 @@ async fn get_existing_node<'a>(
     path: &Path,
 ) -> RepositoryResult<NodeSnapshot> {
     // An existing node is one that is present in a Snapshot file on storage
-    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
-
-    let node = snapshot.get_node(path).map_err(|err| match err {
-        // A missing node here is not really a format error, so we need to
-        // generate the correct error for repositories
-        IcechunkFormatError::NodeNotFound { path } => RepositoryError::NodeNotFound {
-            path,
-            message: "existing node not found".to_string(),
-        },
-        err => RepositoryError::FormatError(err),
-    })?;
+    let node =
+        storage.fetch_snapshot_node(snapshot_id, path).await?.ok_or_else(|| {
+            RepositoryError::NodeNotFound {
+                path: path.clone(),
+                message: "existing node not found".to_string(),
+            }
+        })?;
     let session_atts = change_set
         .get_user_attributes(&node.id)
         .cloned()
@@ -1020,6 +4529,11 @@ async fn get_existing_node<'a>(
         user_attributes: session_atts.unwrap_or_else(|| node.user_attributes.clone()),
         ..node.clone()
     };
+    if let Some(session_data) = change_set.get_updated_custom_node(&node.id).cloned() {
+        if let NodeData::Custom(_) = res.node_data {
+            return Ok(NodeSnapshot { node_data: NodeData::Custom(session_data), ..res });
+        }
+    }
     if let Some(session_meta) = change_set.get_updated_zarr_metadata(&node.id).cloned() {
         if let NodeData::Array(_, manifests) = res.node_data {
             Ok(NodeSnapshot {
@@ -1034,103 +4548,712 @@ async fn get_existing_node<'a>(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn flush(
     storage: &(dyn Storage + Send + Sync),
     change_set: &ChangeSet,
     parent_id: &SnapshotId,
     message: &str,
     properties: SnapshotProperties,
-) -> RepositoryResult<SnapshotId> {
+    sources: &Sources,
+    config: &RepositoryConfig,
+    runtime: &dyn Runtime,
+    written_chunks: &HashMap<ChunkId, Vec<String>>,
+) -> RepositoryResult<(SnapshotId, Option<ManifestSplit>)> {
     if change_set.is_empty() {
         return Err(RepositoryError::NoChangesToCommit);
     }
 
-    let chunks = all_chunks(storage, change_set, parent_id)
+    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
+    let new_snapshot_id: SnapshotId = sources.new_id();
+    let written =
+        |id: &ChunkId| written_chunks.get(id).filter(|ids| !ids.is_empty()).cloned();
+    let mut partitions = flush_partitions(
+        storage,
+        change_set,
+        &old_snapshot,
+        parent_id,
+        &new_snapshot_id,
+        sources,
+        config,
+        written,
+    )
+    .await?;
+
+    // the partitions are uploaded while the chunks of the other arrays are collected
+    let partition_writes = take(&mut partitions.pending).write(storage);
+    let (new_manifest, ()) = future::try_join(
+        flush_manifest(
+            storage,
+            change_set,
+            &old_snapshot,
+            parent_id,
+            &new_snapshot_id,
+            &partitions,
+            config.manifest_format_version(),
+            written,
+        ),
+        partition_writes,
+    )
+    .await?;
+    let written_manifest = if new_manifest.len() > 0 {
+        write_new_manifest(
+            storage,
+            sources,
+            runtime,
+            new_manifest,
+            config.manifest_split_threshold_bytes,
+        )
         .await?
-        .map_ok(|(_path, chunk_info)| chunk_info);
-
-    let new_manifest = Arc::new(Manifest::from_stream(chunks).await?);
-    let new_manifest_id = if new_manifest.len() > 0 {
-        let id = ObjectId::random();
-        storage.write_manifests(id.clone(), Arc::clone(&new_manifest)).await?;
-        Some(id)
     } else {
-        None
+        WrittenManifest::default()
+    };
+    // a single manifest has the chunks of all the arrays
+    let new_manifest_id = match written_manifest.files.as_slice() {
+        [file] if written_manifest.split.is_none() => Some(file.id.clone()),
+        _ => None,
     };
 
     let all_nodes =
-        updated_nodes(storage, change_set, parent_id, new_manifest_id.as_ref()).await?;
+        updated_nodes(storage, change_set, parent_id, new_manifest_id.as_ref())
+            .await?
+            .map(|node| {
+                let refs = partitions.refs.get(&node.id).cloned().or_else(|| {
+                    written_manifest.split.as_ref().map(|split| {
+                        split.refs.get(&node.id).cloned().unwrap_or_default()
+                    })
+                });
+                match (refs, node.node_data) {
+                    (Some(refs), NodeData::Array(meta, _)) => {
+                        NodeSnapshot { node_data: NodeData::Array(meta, refs), ..node }
+                    }
+                    (_, node_data) => NodeSnapshot { node_data, ..node },
+                }
+            });
+    let mut pending = PendingMetadata::default();
+    let (all_nodes, attribute_files) = split_user_attributes(
+        &mut pending,
+        sources,
+        &old_snapshot,
+        all_nodes,
+        config.attributes_split_threshold_bytes,
+    );
+    // before the content hash, it reads the new manifest
+    pending.write(storage).await?;
 
-    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
     let mut new_snapshot = Snapshot::from_iter(
         old_snapshot.as_ref(),
         Some(properties),
-        new_manifest_id
-            .as_ref()
-            .map(|mid| {
-                vec![ManifestFileInfo {
-                    id: mid.clone(),
-                    format_version: new_manifest.icechunk_manifest_format_version,
-                }]
-            })
-            .unwrap_or_default(),
-        vec![],
+        written_manifest.files.into_iter().chain(partitions.files).collect(),
+        attribute_files,
         all_nodes,
     );
+    new_snapshot.metadata.id = new_snapshot_id;
     new_snapshot.metadata.message = message.to_string();
-    new_snapshot.metadata.written_at = Utc::now();
+    new_snapshot.metadata.written_at = sources.now();
+    new_snapshot.started_at = new_snapshot.metadata.written_at;
+    if config.record_content_hash {
+        let hash =
+            snapshot_content_hash(storage, &new_snapshot, config.hash_algorithm).await?;
+        new_snapshot.properties.insert(
+            CONTENT_HASH_PROPERTY.to_string(),
+            serde_json::Value::from(hash.to_string()),
+        );
+        new_snapshot.properties.insert(
+            CONTENT_HASH_ALGORITHM_PROPERTY.to_string(),
+            serde_json::Value::from(config.hash_algorithm.to_string()),
+        );
+    }
+    if !config.indexed_attributes.is_empty() {
+        // an unreadable parent index is rebuilt
+        let parent_index = AttributesIndex::from_snapshot(&old_snapshot).ok().flatten();
+        let index = AttributesIndex::update(
+            parent_index,
+            &new_snapshot,
+            change_set,
+            &config.indexed_attributes,
+        );
+        new_snapshot
+            .properties
+            .insert(ATTRIBUTES_INDEX_PROPERTY.to_string(), index.to_property());
+    }
+    let documents =
+        DocumentTable::from_snapshot(&old_snapshot)?.update(change_set, &new_snapshot);
+    if !documents.is_empty() {
+        new_snapshot
+            .properties
+            .insert(DOCUMENTS_PROPERTY.to_string(), documents.to_property());
+    }
 
     let new_snapshot = Arc::new(new_snapshot);
     // FIXME: this should execute in a non-blocking context
     let tx_log =
         TransactionLog::new(change_set, old_snapshot.iter(), new_snapshot.iter());
     let new_snapshot_id = &new_snapshot.metadata.id;
-    storage.write_snapshot(new_snapshot_id.clone(), Arc::clone(&new_snapshot)).await?;
+    storage
+        .write_snapshot_if_absent(new_snapshot_id.clone(), Arc::clone(&new_snapshot))
+        .await?;
     storage.write_transaction_log(new_snapshot_id.clone(), Arc::new(tx_log)).await?;
 
-    Ok(new_snapshot_id.clone())
+    let split = written_manifest
+        .split
+        .map(|split| ManifestSplit { manifests: split.manifests, reason: split.reason });
+    Ok((new_snapshot_id.clone(), split))
 }
 
-/// Warning: The presence of a single error may mean multiple missing items
-async fn updated_chunk_iterator<'a>(
-    storage: &'a (dyn Storage + Send + Sync),
-    change_set: &'a ChangeSet,
-    snapshot_id: &'a SnapshotId,
-) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + 'a> {
-    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
-    let nodes = futures::stream::iter(snapshot.iter_arc());
-    let res = nodes.then(move |node| async move {
-        let path = node.path.clone();
-        node_chunk_iterator(storage, change_set, snapshot_id, &node.path)
-            .await
-            .map_ok(move |ci| (path.clone(), ci))
-    });
-    Ok(res.flatten())
+/// The manifest of the chunks of the arrays that are not partitioned, as written
+#[derive(Debug, Default)]
+struct WrittenManifest {
+    files: Vec<ManifestFileInfo>,
+    /// `None` if it was written as a single manifest
+    split: Option<SplitManifest>,
 }
 
-/// Warning: The presence of a single error may mean multiple missing items
-async fn node_chunk_iterator<'a>(
-    storage: &'a (dyn Storage + Send + Sync),
-    change_set: &'a ChangeSet,
-    snapshot_id: &SnapshotId,
-    path: &Path,
-) -> impl Stream<Item = RepositoryResult<ChunkInfo>> + 'a {
-    match get_node(storage, change_set, snapshot_id, path).await {
-        Ok(node) => futures::future::Either::Left(
-            verified_node_chunk_iterator(storage, change_set, node).await,
-        ),
-        Err(_) => futures::future::Either::Right(futures::stream::empty()),
-    }
+#[derive(Debug)]
+struct SplitManifest {
+    /// The manifests with chunks of each array
+    refs: HashMap<NodeId, Vec<ManifestRef>>,
+    manifests: usize,
+    reason: String,
 }
 
-/// Warning: The presence of a single error may mean multiple missing items
-async fn verified_node_chunk_iterator<'a>(
-    storage: &'a (dyn Storage + Send + Sync),
-    change_set: &'a ChangeSet,
+/// Why a flush wrote its manifest in several pieces, reported as [`Event::ManifestSplit`]
+#[derive(Debug)]
+struct ManifestSplit {
+    manifests: usize,
+    reason: String,
+}
+
+/// The write of a piece of a manifest, with its id, the times it was split and the
+/// attempts to write it
+type PieceWrite<'a> =
+    BoxFuture<'a, (ManifestId, Arc<Manifest>, PieceState, StorageResult<()>)>;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PieceState {
+    splits: u32,
+    attempts: u32,
+}
+
+/// Pieces of a manifest are split in two at most this many times when the backend rejects
+/// them for their size
+const MAX_MANIFEST_SPLITS: u32 = 10;
+/// Attempts to write a piece of a manifest that fails with a retryable error
+const MANIFEST_WRITE_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, it doubles on each retry
+const MANIFEST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Write `manifest`, split in pieces of about `threshold_bytes` if it's larger, zero
+/// disables it
+///
+/// The size is the [`Manifest::estimated_size`]. Pieces the backend rejects for their size,
+/// see [`StorageErrorKind::TooLarge`], are split in two and written again, under new ids,
+/// up to [`MAX_MANIFEST_SPLITS`] times and until they have a single chunk. Pieces that fail
+/// with retryable errors are written again as they are, other errors fail the write.
+async fn write_new_manifest(
+    storage: &(dyn Storage + Send + Sync),
+    sources: &Sources,
+    runtime: &dyn Runtime,
+    manifest: Arc<Manifest>,
+    threshold_bytes: u64,
+) -> RepositoryResult<WrittenManifest> {
+    let mut reason = None;
+    let mut queue = vec![(Arc::clone(&manifest), PieceState::default())];
+    if threshold_bytes > 0 {
+        let size = manifest.estimated_size();
+        let pieces = manifest.split(size.div_ceil(threshold_bytes) as usize);
+        if pieces.len() > 1 {
+            reason = Some(format!(
+                "the manifest of about {size} bytes is larger than {threshold_bytes} bytes"
+            ));
+            queue = pieces
+                .into_iter()
+                .map(|piece| (Arc::new(piece), PieceState::default()))
+                .collect();
+        }
+    }
+
+    let mut written = Vec::new();
+    while !queue.is_empty() {
+        let mut writes: Vec<PieceWrite<'_>> = Vec::new();
+        for (piece, state) in queue.drain(..) {
+            let id: ManifestId = sources.new_id();
+            let delay = match state.attempts {
+                0 => None,
+                n => {
+                    Some(runtime.sleep(MANIFEST_RETRY_DELAY.saturating_mul(1 << (n - 1))))
+                }
+            };
+            writes.push(
+                async move {
+                    if let Some(delay) = delay {
+                        delay.await;
+                    }
+                    // ids are random, in the unlikely case of a collision we must not
+                    // overwrite
+                    let res = storage
+                        .write_manifests_if_absent(id.clone(), Arc::clone(&piece))
+                        .await;
+                    (id, piece, state, res)
+                }
+                .boxed(),
+            );
+        }
+        let results: Vec<_> = futures::stream::iter(writes)
+            .buffer_unordered(METADATA_WRITE_CONCURRENCY)
+            .collect()
+            .await;
+        for (id, piece, state, res) in results {
+            match res {
+                Ok(()) => written.push((id, piece)),
+                Err(err)
+                    if err.kind() == StorageErrorKind::TooLarge
+                        && piece.len() > 1
+                        && state.splits < MAX_MANIFEST_SPLITS =>
+                {
+                    reason.get_or_insert_with(|| {
+                        format!("writing the manifest failed: {err}")
+                    });
+                    let state = PieceState { splits: state.splits + 1, attempts: 0 };
+                    queue.extend(
+                        piece.split(2).into_iter().map(|half| (Arc::new(half), state)),
+                    );
+                }
+                Err(err)
+                    if err.is_retryable()
+                        && state.attempts + 1 < MANIFEST_WRITE_ATTEMPTS =>
+                {
+                    let state = PieceState { attempts: state.attempts + 1, ..state };
+                    queue.push((piece, state));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    let files = written
+        .iter()
+        .map(|(id, piece)| ManifestFileInfo {
+            id: id.clone(),
+            format_version: piece.icechunk_manifest_format_version,
+        })
+        .collect();
+    let Some(reason) = reason else {
+        return Ok(WrittenManifest { files, split: None });
+    };
+    // in chunk order, so lookups try the pieces in order
+    written.sort_by(|(_, a), (_, b)| {
+        a.chunks().keys().next().cmp(&b.chunks().keys().next())
+    });
+    let mut refs: HashMap<NodeId, Vec<ManifestRef>> = HashMap::new();
+    for (id, piece) in written.iter() {
+        for (node, extents) in piece.node_extents() {
+            refs.entry(node)
+                .or_default()
+                .push(ManifestRef { object_id: id.clone(), extents });
+        }
+    }
+    let split = SplitManifest { refs, manifests: written.len(), reason };
+    Ok(WrittenManifest { files, split: Some(split) })
+}
+
+/// The manifest of the chunks of the arrays that are not partitioned
+#[allow(clippy::too_many_arguments)]
+async fn flush_manifest(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    old_snapshot: &Snapshot,
+    parent_id: &SnapshotId,
+    new_snapshot_id: &SnapshotId,
+    partitions: &PartitionedManifests,
+    format_version: IcechunkFormatVersion,
+    written: impl Fn(&ChunkId) -> Option<Vec<String>> + Copy,
+) -> RepositoryResult<Arc<Manifest>> {
+    // the chunks of partitioned arrays are only in their partitions
+    let chunks = all_chunks_except(storage, change_set, parent_id, |node| {
+        partitions.refs.contains_key(node)
+    })
+    .await?
+    .map_ok(|(_path, chunk_info)| chunk_info);
+    let new_manifest = Manifest::from_stream(chunks).await?;
+    let old_manifests: Vec<&ManifestFileInfo> = old_snapshot
+        .manifest_files
+        .iter()
+        .filter(|file| !partitions.replaced.contains(&file.id))
+        .collect();
+    let origins = chunk_origins(
+        storage,
+        change_set,
+        &old_manifests,
+        &new_manifest,
+        new_snapshot_id,
+    )
+    .await?;
+    let transformers = chunk_transformers_record(
+        storage,
+        change_set,
+        &old_manifests,
+        &new_manifest,
+        written,
+    )
+    .await?;
+    let placements =
+        chunk_placements_record(storage, change_set, &old_manifests, &new_manifest)
+            .await?;
+    Ok(Arc::new(
+        new_manifest
+            .with_origins(origins)
+            .with_transformers(transformers)
+            .with_placements(placements)
+            .with_format_version(format_version),
+    ))
+}
+
+/// The manifests and attributes files of a new snapshot, not written yet
+#[derive(Debug, Default)]
+struct PendingMetadata {
+    manifests: Vec<(ManifestId, Arc<Manifest>)>,
+    attributes: Vec<(AttributesId, Arc<AttributesTable>)>,
+}
+
+impl PendingMetadata {
+    /// Write all the files, [`METADATA_WRITE_CONCURRENCY`] at a time
+    async fn write(self, storage: &(dyn Storage + Send + Sync)) -> RepositoryResult<()> {
+        let mut writes: Vec<Pin<Box<dyn Future<Output = StorageResult<()>> + Send>>> =
+            Vec::new();
+        for (id, manifest) in self.manifests {
+            // ids are random, in the unlikely case of a collision we must not overwrite
+            writes.push(storage.write_manifests_if_absent(id, manifest));
+        }
+        for (id, table) in self.attributes {
+            writes.push(storage.write_attributes(id, table));
+        }
+        futures::stream::iter(writes)
+            .buffer_unordered(METADATA_WRITE_CONCURRENCY)
+            .try_collect::<()>()
+            .await?;
+        Ok(())
+    }
+}
+
+/// The manifests of the partitioned arrays, see [`ManifestPartitioning`]
+#[derive(Debug, Default)]
+struct PartitionedManifests {
+    refs: HashMap<NodeId, Vec<ManifestRef>>,
+    /// All the manifests the partitioned arrays point to, old and new
+    files: Vec<ManifestFileInfo>,
+    /// The partition manifests of the parent, they only have chunks of partitioned arrays
+    replaced: HashSet<ManifestId>,
+    /// The new partitions
+    pending: PendingMetadata,
+}
+
+/// Write the partitions of `config.manifest_partitions` with chunk changes, with the chunks
+/// of the parent and the change set, and keep the manifest of the parent for the rest
+#[allow(clippy::too_many_arguments)]
+async fn flush_partitions(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    old_snapshot: &Snapshot,
+    parent_id: &SnapshotId,
+    new_snapshot_id: &SnapshotId,
+    sources: &Sources,
+    config: &RepositoryConfig,
+    written: impl Fn(&ChunkId) -> Option<Vec<String>>,
+) -> RepositoryResult<PartitionedManifests> {
+    let mut res = PartitionedManifests::default();
+    for (path, partitioning) in config.manifest_partitions.iter() {
+        let node = match get_node(storage, change_set, parent_id, path).await {
+            Ok(node) => node,
+            Err(RepositoryError::NodeNotFound { .. }) => continue,
+            Err(err) => return Err(err),
+        };
+        let NodeData::Array(meta, old_refs) = node.node_data else { continue };
+        let ndim = meta.shape.len();
+        if partitioning.dimension >= ndim {
+            continue;
+        }
+        let changed: HashSet<u64> = change_set
+            .array_chunks_iterator(&node.id, &node.path)
+            .filter_map(|(coords, _)| partitioning.partition(coords))
+            .collect();
+
+        let mut refs = BTreeMap::new();
+        let mut chunks = BTreeMap::new();
+        let mut origins = BTreeMap::new();
+        let mut transformers = BTreeMap::new();
+        let mut placements = BTreeMap::new();
+        for manifest_ref in old_refs {
+            let partition = manifest_ref
+                .extents
+                .0
+                .first()
+                .and_then(|from| partitioning.partition(from))
+                .filter(|partition| {
+                    manifest_ref.extents == partitioning.extents(ndim, *partition)
+                });
+            if partition.is_some() {
+                res.replaced.insert(manifest_ref.object_id.clone());
+            }
+            match partition.filter(|partition| !changed.contains(partition)) {
+                Some(partition) => {
+                    if let Some(file) = old_snapshot
+                        .manifest_files
+                        .iter()
+                        .find(|file| file.id == manifest_ref.object_id)
+                    {
+                        res.files.push(file.clone());
+                    }
+                    refs.insert(partition, manifest_ref);
+                }
+                None => {
+                    let manifest =
+                        storage.fetch_manifests(&manifest_ref.object_id).await?;
+                    origins.extend(
+                        manifest
+                            .origins()
+                            .iter()
+                            .filter(|((id, _), _)| id == &node.id)
+                            .map(|(key, origin)| (key.clone(), origin.clone())),
+                    );
+                    transformers.extend(
+                        manifest
+                            .transformers()
+                            .iter()
+                            .filter(|((id, _), _)| id == &node.id)
+                            .map(|(key, ids)| (key.clone(), ids.clone())),
+                    );
+                    placements.extend(
+                        manifest
+                            .placements()
+                            .iter()
+                            .filter(|((id, _), _)| id == &node.id)
+                            .map(|(key, shard)| (key.clone(), shard.clone())),
+                    );
+                    chunks.extend(manifest.iter(node.id.clone()));
+                }
+            }
+        }
+        for (coords, payload) in change_set.array_chunks_iterator(&node.id, &node.path) {
+            let key = (node.id.clone(), coords.clone());
+            transformers.remove(&key);
+            placements.remove(&key);
+            match payload {
+                Some(payload) => {
+                    if let ChunkPayload::Ref(ChunkRef { id, .. }) = payload {
+                        if let Some(ids) = written(id) {
+                            transformers.insert(key.clone(), ids);
+                        }
+                        if let Some(shard) = storage.chunk_placement(id) {
+                            placements.insert(key.clone(), shard);
+                        }
+                    }
+                    origins.insert(key, new_snapshot_id.clone());
+                    chunks.insert(coords.clone(), payload.clone());
+                }
+                None => {
+                    origins.remove(&key);
+                    chunks.remove(coords);
+                }
+            }
+        }
+
+        let mut partitions: BTreeMap<u64, BTreeMap<_, _>> = BTreeMap::new();
+        for (coords, payload) in chunks {
+            if let Some(partition) = partitioning.partition(&coords) {
+                partitions
+                    .entry(partition)
+                    .or_default()
+                    .insert((node.id.clone(), coords), payload);
+            }
+        }
+        for (partition, chunks) in partitions {
+            let manifest = Manifest::new(chunks)
+                .with_origins(
+                    origins
+                        .iter()
+                        .filter(|((_, coords), _)| {
+                            partitioning.partition(coords) == Some(partition)
+                        })
+                        .map(|(key, origin)| (key.clone(), origin.clone()))
+                        .collect(),
+                )
+                .with_transformers(
+                    transformers
+                        .iter()
+                        .filter(|((_, coords), _)| {
+                            partitioning.partition(coords) == Some(partition)
+                        })
+                        .map(|(key, ids)| (key.clone(), ids.clone()))
+                        .collect(),
+                )
+                .with_placements(
+                    placements
+                        .iter()
+                        .filter(|((_, coords), _)| {
+                            partitioning.partition(coords) == Some(partition)
+                        })
+                        .map(|(key, shard)| (key.clone(), shard.clone()))
+                        .collect(),
+                )
+                .with_format_version(config.manifest_format_version());
+            let id: ManifestId = sources.new_id();
+            res.files.push(ManifestFileInfo {
+                id: id.clone(),
+                format_version: manifest.icechunk_manifest_format_version,
+            });
+            res.pending.manifests.push((id.clone(), Arc::new(manifest)));
+            refs.insert(
+                partition,
+                ManifestRef {
+                    object_id: id,
+                    extents: partitioning.extents(ndim, partition),
+                },
+            );
+        }
+        res.refs.insert(node.id, refs.into_values().collect());
+    }
+    Ok(res)
+}
+
+/// The snapshot that wrote each chunk of `manifest`: `new_snapshot` for chunks written by
+/// the change set, and the recorded origin for the rest
+async fn chunk_origins(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    old_manifests: &[&ManifestFileInfo],
+    manifest: &Manifest,
+    new_snapshot: &SnapshotId,
+) -> RepositoryResult<BTreeMap<(NodeId, ChunkIndices), SnapshotId>> {
+    let mut old_origins = BTreeMap::new();
+    for file in old_manifests {
+        old_origins.extend(storage.fetch_manifests(&file.id).await?.origins().clone());
+    }
+    Ok(manifest
+        .chunks()
+        .keys()
+        .filter_map(|key @ (node, coord)| {
+            if change_set.get_chunk_ref(node, coord).is_some() {
+                Some((key.clone(), new_snapshot.clone()))
+            } else {
+                old_origins.remove(key).map(|origin| (key.clone(), origin))
+            }
+        })
+        .collect())
+}
+
+/// The transformers of each chunk of `manifest`: given by `written` for chunks stored by
+/// the change set, and the recorded ones for the rest
+async fn chunk_transformers_record(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    old_manifests: &[&ManifestFileInfo],
+    manifest: &Manifest,
+    written: impl Fn(&ChunkId) -> Option<Vec<String>>,
+) -> RepositoryResult<BTreeMap<(NodeId, ChunkIndices), Vec<String>>> {
+    let mut old_transformers = BTreeMap::new();
+    for file in old_manifests {
+        old_transformers
+            .extend(storage.fetch_manifests(&file.id).await?.transformers().clone());
+    }
+    Ok(manifest
+        .chunks()
+        .iter()
+        .filter_map(|(key @ (node, coord), payload)| {
+            if change_set.get_chunk_ref(node, coord).is_some() {
+                match payload {
+                    ChunkPayload::Ref(ChunkRef { id, .. }) => {
+                        written(id).map(|ids| (key.clone(), ids))
+                    }
+                    _ => None,
+                }
+            } else {
+                old_transformers.remove(key).map(|ids| (key.clone(), ids))
+            }
+        })
+        .collect())
+}
+
+/// The shard of each chunk of `manifest`: given by the storage for chunks stored by the
+/// change set, and the recorded one for the rest
+async fn chunk_placements_record(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    old_manifests: &[&ManifestFileInfo],
+    manifest: &Manifest,
+) -> RepositoryResult<BTreeMap<(NodeId, ChunkIndices), String>> {
+    let mut old_placements = BTreeMap::new();
+    for file in old_manifests {
+        old_placements
+            .extend(storage.fetch_manifests(&file.id).await?.placements().clone());
+    }
+    Ok(manifest
+        .chunks()
+        .iter()
+        .filter_map(|(key @ (node, coord), payload)| {
+            if change_set.get_chunk_ref(node, coord).is_some() {
+                match payload {
+                    ChunkPayload::Ref(ChunkRef { id, .. }) => {
+                        storage.chunk_placement(id).map(|shard| (key.clone(), shard))
+                    }
+                    _ => None,
+                }
+            } else {
+                old_placements.remove(key).map(|shard| (key.clone(), shard))
+            }
+        })
+        .collect())
+}
+
+fn in_region(region: &[Range<u64>], coords: &ChunkIndices) -> bool {
+    coords.0.len() == region.len()
+        && coords.0.iter().zip(region).all(|(coord, range)| range.contains(coord))
+}
+
+/// Warning: The presence of a single error may mean multiple missing items
+async fn updated_chunk_iterator<'a>(
+    storage: &'a (dyn Storage + Send + Sync),
+    change_set: &'a ChangeSet,
+    snapshot_id: &'a SnapshotId,
+    except: impl Fn(&NodeId) -> bool + Send + 'a,
+) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + 'a> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let nodes =
+        futures::stream::iter(snapshot.iter_arc().filter(move |node| !except(&node.id)));
+    let res = nodes.then(move |node| async move {
+        let path = node.path.clone();
+        node_chunk_iterator(storage, change_set, snapshot_id, &node.path)
+            .await
+            .map_ok(move |ci| (path.clone(), ci))
+    });
+    Ok(res.flatten())
+}
+
+/// Warning: The presence of a single error may mean multiple missing items
+async fn node_chunk_iterator<'a>(
+    storage: &'a (dyn Storage + Send + Sync),
+    change_set: &'a ChangeSet,
+    snapshot_id: &SnapshotId,
+    path: &Path,
+) -> impl Stream<Item = RepositoryResult<ChunkInfo>> + 'a {
+    match get_node(storage, change_set, snapshot_id, path).await {
+        Ok(node) => futures::future::Either::Left(
+            verified_node_chunk_iterator(storage, change_set, node).await,
+        ),
+        Err(_) => futures::future::Either::Right(futures::stream::empty()),
+    }
+}
+
+/// Warning: The presence of a single error may mean multiple missing items
+async fn verified_node_chunk_iterator<'a>(
+    storage: &'a (dyn Storage + Send + Sync),
+    change_set: &'a ChangeSet,
     node: NodeSnapshot,
 ) -> impl Stream<Item = RepositoryResult<ChunkInfo>> + 'a {
     match node.node_data {
-        NodeData::Group => futures::future::Either::Left(futures::stream::empty()),
+        NodeData::Group | NodeData::Custom(_) => {
+            futures::future::Either::Left(futures::stream::empty())
+        }
         NodeData::Array(_, manifests) => {
             let new_chunk_indices: Box<HashSet<&ChunkIndices>> = Box::new(
                 change_set
@@ -1202,15 +5325,29 @@ async fn verified_node_chunk_iterator<'a>(
     }
 }
 
-async fn all_chunks<'a>(
+pub(crate) async fn all_chunks<'a>(
     storage: &'a (dyn Storage + Send + Sync),
     change_set: &'a ChangeSet,
     snapshot_id: &'a SnapshotId,
+) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + 'a> {
+    all_chunks_except(storage, change_set, snapshot_id, |_| false).await
+}
+
+/// The chunks of [`all_chunks`], without the ones of the nodes `except` returns true for
+async fn all_chunks_except<'a>(
+    storage: &'a (dyn Storage + Send + Sync),
+    change_set: &'a ChangeSet,
+    snapshot_id: &'a SnapshotId,
+    except: impl Fn(&NodeId) -> bool + Copy + Send + 'a,
 ) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + 'a> {
     let existing_array_chunks =
-        updated_chunk_iterator(storage, change_set, snapshot_id).await?;
-    let new_array_chunks =
-        futures::stream::iter(change_set.new_arrays_chunk_iterator().map(Ok));
+        updated_chunk_iterator(storage, change_set, snapshot_id, except).await?;
+    let new_array_chunks = futures::stream::iter(
+        change_set
+            .new_arrays_chunk_iterator()
+            .filter(move |(_, chunk)| !except(&chunk.node))
+            .map(Ok),
+    );
     Ok(existing_array_chunks.chain(new_array_chunks))
 }
 
@@ -1231,18 +5368,23 @@ mod tests {
 
     use std::{error::Error, num::NonZeroU64};
 
+    use chrono::DateTime;
+
     use crate::{
         conflicts::{
             basic_solver::{BasicConflictSolver, VersionSelection},
             detector::ConflictDetector,
         },
-        format::manifest::ChunkInfo,
+        format::{manifest::ChunkInfo, ObjectId},
         metadata::{
             ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, StorageTransformer,
         },
         refs::{fetch_ref, Ref},
-        storage::{logging::LoggingStorage, ObjectStorage},
+        storage::{
+            faulty::FaultyStorage, logging::LoggingStorage, GuardedStorage, ObjectStorage,
+        },
         strategies::*,
+        test_utils,
     };
 
     use super::*;
@@ -1400,6 +5542,8 @@ mod tests {
                 Some("y".to_string()),
                 Some("t".to_string()),
             ]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
         let manifest_ref = ManifestRef {
             object_id: manifest_id.clone(),
@@ -1468,6 +5612,8 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let new_array_path: Path = "/group/array2".to_string().try_into().unwrap();
@@ -1595,6 +5741,8 @@ mod tests {
                 Some("y".to_string()),
                 Some("t".to_string()),
             ]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let node_id1 = NodeId::random();
@@ -1675,6 +5823,192 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_deterministic_sources() -> Result<(), Box<dyn Error>> {
+        async fn build(
+        ) -> Result<(Arc<dyn Storage + Send + Sync>, SnapshotId), Box<dyn Error>>
+        {
+            let storage: Arc<dyn Storage + Send + Sync> =
+                Arc::new(ObjectStorage::new_in_memory_store(None));
+            let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+            let mut ds = Repository::init_with_sources(
+                Arc::clone(&storage),
+                false,
+                Sources::deterministic(7, start),
+            )
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+            let path: Path = "/array".try_into()?;
+            ds.add_group(Path::root()).await?;
+            ds.add_array(
+                path.clone(),
+                ZarrArrayMetadata {
+                    shape: vec![2],
+                    data_type: DataType::UInt8,
+                    chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                    chunk_key_encoding: ChunkKeyEncoding::Slash,
+                    fill_value: FillValue::UInt8(0),
+                    codecs: vec![],
+                    storage_transformers: None,
+                    dimension_names: None,
+                    chunk_transformers: None,
+                    grid_origin: None,
+                },
+            )
+            .await?;
+            let payload = ds.get_chunk_writer()(Bytes::from_static(b"x")).await?;
+            ds.set_chunk_ref(path, ChunkIndices(vec![0]), Some(payload)).await?;
+            let id = ds.commit(Ref::DEFAULT_BRANCH, "commit", None).await?;
+            Ok((storage, id))
+        }
+
+        let (storage1, id1) = build().await?;
+        let (storage2, id2) = build().await?;
+        assert_eq!(id1, id2);
+        let snapshot1 = storage1.fetch_snapshot(&id1).await?;
+        assert_eq!(snapshot1, storage2.fetch_snapshot(&id2).await?);
+        assert_eq!(
+            snapshot1.metadata.written_at,
+            DateTime::from_timestamp(1_700_000_001, 0).unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&backend), false).await?.build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        ds.commit("main", "first", None).await?;
+        ds.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("hi".into())),
+        )
+        .await?;
+        ds.commit("main", "second", None).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let storage = Arc::new(MemCachingStorage::new(logging_c, 4, 4, 0, 4, 0));
+        let ds = Repository::from_branch_tip(storage, "main").await?.build();
+        ds.prefetch(10).await?;
+        let fetched = logging.fetch_operations();
+        // the snapshot, its two parents and the manifest
+        assert_eq!(fetched.len(), 4);
+
+        assert_eq!(ds.ancestry().await?.count().await, 3);
+        ds.get_node(&path).await?;
+        assert!(ds.get_chunk_ref(&path, &ChunkIndices(vec![0])).await?.is_some());
+        assert_eq!(logging.fetch_operations(), fetched);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_user_attributes() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&backend), false)
+            .await?
+            .with_attributes_split_threshold_bytes(100)
+            .build();
+        let big: Path = "/big".try_into()?;
+        let small_atts = UserAttributes::try_new(br#"{"title":"small"}"#)?;
+        let colormap: Vec<_> = (0..256).map(|i| vec![i, i, i]).collect();
+        let big_atts = UserAttributes::try_new(
+            serde_json::json!({ "colormap": colormap }).to_string().as_bytes(),
+        )?;
+        ds.add_group(Path::root()).await?;
+        ds.set_user_attributes(Path::root(), Some(small_atts.clone())).await?;
+        ds.add_group(big.clone()).await?;
+        ds.set_user_attributes(big.clone(), Some(big_atts.clone())).await?;
+        let first = ds.commit("main", "first", None).await?;
+
+        let snapshot = backend.fetch_snapshot(&first).await?;
+        assert_eq!(snapshot.attribute_files.len(), 1);
+        assert!(matches!(
+            snapshot.get_node(&big)?.user_attributes,
+            Some(UserAttributesSnapshot::Ref(_))
+        ));
+        assert_eq!(
+            snapshot.get_node(&Path::root())?.user_attributes,
+            Some(UserAttributesSnapshot::Inline(small_atts.clone()))
+        );
+
+        // listing doesn't load the attributes file
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut ds = Repository::from_branch_tip(logging_c, "main")
+            .await?
+            .with_attributes_split_threshold_bytes(100)
+            .build();
+        assert_eq!(ds.list_nodes().await?.count(), 2);
+        let fetched_attributes = || {
+            logging
+                .fetch_operations()
+                .iter()
+                .filter(|(op, _)| op == "fetch_attributes")
+                .count()
+        };
+        assert_eq!(fetched_attributes(), 0);
+        assert_eq!(ds.get_user_attributes(&big).await?, Some(big_atts.clone()));
+        assert_eq!(fetched_attributes(), 1);
+
+        // unchanged attributes keep pointing to their file
+        ds.set_user_attributes(Path::root(), None).await?;
+        let second = ds.commit("main", "second", None).await?;
+        let snapshot = backend.fetch_snapshot(&second).await?;
+        assert_eq!(snapshot.attribute_files.len(), 1);
+        assert_eq!(ds.get_user_attributes(&big).await?, Some(big_atts.clone()));
+        assert_eq!(ds.get_user_attributes(&Path::root()).await?, None);
+
+        // a bulk fetch reads each attributes file once
+        let other: Path = "/other".try_into()?;
+        ds.add_group(other.clone()).await?;
+        ds.set_user_attributes(other.clone(), Some(big_atts.clone())).await?;
+        ds.commit("main", "other", None).await?;
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let bulk = Repository::from_branch_tip(logging_c, "main").await?.build();
+        assert_eq!(
+            bulk.get_attributes_bulk([&big, &Path::root(), &other, &big]).await?,
+            vec![
+                Some(big_atts.clone()),
+                None,
+                Some(big_atts.clone()),
+                Some(big_atts.clone())
+            ]
+        );
+        assert_eq!(
+            logging
+                .fetch_operations()
+                .iter()
+                .filter(|(op, _)| op == "fetch_attributes")
+                .count(),
+            2
+        );
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_repository_with_updates_and_writes() -> Result<(), Box<dyn Error>> {
         let backend: Arc<dyn Storage + Send + Sync> =
@@ -1724,6 +6058,8 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let new_array_path: Path = "/group/array1".try_into().unwrap();
@@ -1901,34 +6237,1385 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_delete_children() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+    async fn test_delete_children() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.add_group("/a".try_into().unwrap()).await?;
+        ds.add_group("/b".try_into().unwrap()).await?;
+        ds.add_group("/b/bb".try_into().unwrap()).await?;
+        ds.delete_group("/b".try_into().unwrap()).await?;
+        assert!(ds.get_group(&"/b".try_into().unwrap()).await.is_err());
+        assert!(ds.get_group(&"/b/bb".try_into().unwrap()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_children_of_old_nodes() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.add_group("/a".try_into().unwrap()).await?;
+        ds.add_group("/b".try_into().unwrap()).await?;
+        ds.add_group("/b/bb".try_into().unwrap()).await?;
+        ds.commit("main", "commit", None).await?;
+
+        ds.delete_group("/b".try_into().unwrap()).await?;
+        assert!(ds.get_group(&"/b".try_into().unwrap()).await.is_err());
+        assert!(ds.get_group(&"/b/bb".try_into().unwrap()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_branch_tolerating_staleness() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(MemCachingStorage::new(Arc::clone(&backend), 2, 2, 0, 2, 0));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let root = ds.commit("main", "root", None).await?;
+
+        // another process commits, without going through the cache
+        let mut other = Repository::update(Arc::clone(&backend), root.clone()).build();
+        other.add_group("/other".try_into()?).await?;
+        let tip = other.commit("main", "other", None).await?;
+
+        let stale = OpenOptions::new().tolerate_staleness(Duration::from_secs(3600));
+        let open = |options: OpenOptions| {
+            let storage = Arc::clone(&storage);
+            async move {
+                Repository::open_branch(storage, "main", &options)
+                    .await
+                    .map(|builder| builder.build().snapshot_id().clone())
+            }
+        };
+        assert_eq!(open(stale).await?, root);
+        assert_eq!(open(OpenOptions::new()).await?, tip);
+        // an empty window always fetches the ref, and refreshes the cache
+        let mut other = Repository::update(Arc::clone(&backend), tip).build();
+        other.add_group("/another".try_into()?).await?;
+        let tip = other.commit("main", "another", None).await?;
+        assert_eq!(
+            open(OpenOptions::new().tolerate_staleness(Duration::ZERO)).await?,
+            tip
+        );
+        assert_eq!(open(stale).await?, tip);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scratch_nodes_are_not_committed() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        ds.add_group(Path::root()).await?;
+        ds.commit("main", "root", None).await?;
+
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let staging: Path = format!("{SCRATCH_PATH}/staging").as_str().try_into()?;
+        let output: Path = "/output".try_into()?;
+        ds.add_group(Repository::scratch_path()).await?;
+        ds.add_array(staging.clone(), zarr_meta).await?;
+        let payload = ds.get_chunk_writer()("staged".into()).await?;
+        ds.set_chunk_ref(staging.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        assert_eq!(ds.copy_array(&staging, output.clone(), None).await?, 1);
+        let snapshot = ds.commit("main", "output", None).await?;
+
+        let committed = Repository::update(Arc::clone(&storage), snapshot).build();
+        assert!(committed.get_array(&output).await.is_ok());
+        assert!(committed.get_node(&Repository::scratch_path()).await.is_err());
+        assert!(committed.get_node(&staging).await.is_err());
+        let chunk = committed.get_chunk_ref(&output, &ChunkIndices(vec![0])).await?;
+        assert!(matches!(chunk, Some(ChunkPayload::Ref(_))));
+
+        // the scratch area survives the commit, but can't be committed alone
+        assert!(ds.get_chunk_ref(&staging, &ChunkIndices(vec![0])).await?.is_some());
+        assert!(matches!(
+            ds.commit("main", "scratch", None).await,
+            Err(RepositoryError::NoChangesToCommit)
+        ));
+        assert!(ds.get_array(&staging).await.is_ok());
+        assert!(!ds.discard_scratch().is_empty());
+        assert!(ds.get_node(&staging).await.is_err());
+        assert!(!ds.has_uncommitted_changes());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transformed_scratch_chunks_survive_commits(
+    ) -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_chunk_transformer(crate::test_utils::XorTransformer(42))
+            .build();
+        let staging: Path = format!("{SCRATCH_PATH}/staging").as_str().try_into()?;
+        let output: Path = "/output".try_into()?;
+        ds.add_group(Repository::scratch_path()).await?;
+        ds.add_array(staging.clone(), basic_meta()).await?;
+        ds.add_array(output.clone(), basic_meta()).await?;
+        for path in [&staging, &output] {
+            let payload = ds.get_chunk_writer()("hello".into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        }
+        ds.commit("main", "output", None).await?;
+
+        for path in [&staging, &output] {
+            let chunk = get_chunk(
+                ds.get_chunk_reader(path, &ChunkIndices(vec![0]), &ByteRange::ALL)
+                    .await?,
+            )
+            .await?;
+            assert_eq!(chunk, Some(Bytes::from_static(b"hello")), "{path}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_array() -> Result<(), Box<dyn Error>> {
+        let in_mem_storage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage: Arc<dyn Storage + Send + Sync> = in_mem_storage.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![4, 4],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap(); 2]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let src: Path = "/src".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(src.clone(), zarr_meta.clone()).await?;
+        let atts = UserAttributes::try_new(br#"{"units": "m"}"#)?;
+        ds.set_user_attributes(src.clone(), Some(atts.clone())).await?;
+        for (x, y) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let payload = ds.get_chunk_writer()(format!("{x}{y}").into()).await?;
+            ds.set_chunk_ref(src.clone(), ChunkIndices(vec![x, y]), Some(payload))
+                .await?;
+        }
+        ds.commit("main", "src", None).await?;
+
+        let full: Path = "/full".try_into()?;
+        let row: Path = "/row".try_into()?;
+        assert_eq!(ds.copy_array(&src, full.clone(), None).await?, 4);
+        assert_eq!(ds.copy_array(&src, row.clone(), Some(&[1..2, 0..2])).await?, 2);
+        assert!(matches!(
+            ds.copy_array(&src, "/bad".try_into()?, Some(&[0..1, 0..1, 0..1])).await,
+            Err(RepositoryError::InvalidChunkRegion { dimensions: 2, .. })
+        ));
+        assert!(matches!(
+            ds.copy_array(&src, full.clone(), None).await,
+            Err(RepositoryError::AlreadyExists { .. })
+        ));
+        // rewriting the source doesn't change the copies
+        let payload = ds.get_chunk_writer()("new".into()).await?;
+        ds.set_chunk_ref(src.clone(), ChunkIndices(vec![1, 0]), Some(payload)).await?;
+        ds.commit("main", "copies", None).await?;
+
+        for (path, coords, expected) in [
+            (&full, vec![0, 1], Some("01")),
+            (&full, vec![1, 0], Some("10")),
+            (&row, vec![1, 0], Some("10")),
+            (&row, vec![0, 0], None),
+            (&src, vec![1, 0], Some("new")),
+        ] {
+            let chunk = get_chunk(
+                ds.get_chunk_reader(path, &ChunkIndices(coords), &ByteRange::ALL).await?,
+            )
+            .await?;
+            assert_eq!(chunk, expected.map(Bytes::from));
+        }
+        assert_eq!(ds.get_user_attributes(&row).await?, Some(atts));
+        assert_eq!(
+            ds.get_chunk_ref(&full, &ChunkIndices(vec![0, 0])).await?,
+            ds.get_chunk_ref(&src, &ChunkIndices(vec![0, 0])).await?
+        );
+        // only the rewritten chunk and the metadata of the commit were written
+        let new_chunks = in_mem_storage
+            .all_keys()
+            .await?
+            .into_iter()
+            .filter(|key| key.contains("chunk"))
+            .count();
+        assert_eq!(new_chunks, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_transformed_array() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_chunk_transformer(crate::test_utils::XorTransformer(42))
+            .build();
+        let (src, committed, uncommitted): (Path, Path, Path) =
+            ("/src".try_into()?, "/committed".try_into()?, "/uncommitted".try_into()?);
+        ds.add_array(src.clone(), basic_meta()).await?;
+        for i in 0..2u64 {
+            let payload = ds.get_chunk_writer()(format!("hello{i}").into()).await?;
+            ds.set_chunk_ref(src.clone(), ChunkIndices(vec![i]), Some(payload)).await?;
+        }
+        ds.commit("main", "src", None).await?;
+        // a chunk of the session and one of the commit
+        let payload = ds.get_chunk_writer()("hello1".into()).await?;
+        ds.set_chunk_ref(src.clone(), ChunkIndices(vec![1]), Some(payload)).await?;
+        ds.copy_array(&src, uncommitted.clone(), None).await?;
+
+        async fn read(
+            ds: &Repository,
+            path: &Path,
+            i: u64,
+        ) -> RepositoryResult<Option<Bytes>> {
+            get_chunk(
+                ds.get_chunk_reader(path, &ChunkIndices(vec![i]), &ByteRange::ALL)
+                    .await?,
+            )
+            .await
+        }
+        for i in 0..2 {
+            assert_eq!(
+                read(&ds, &uncommitted, i).await?,
+                Some(format!("hello{i}").into())
+            );
+        }
+        ds.commit("main", "copy", None).await?;
+        ds.copy_array(&src, committed.clone(), None).await?;
+        for path in [&uncommitted, &committed] {
+            for i in 0..2 {
+                assert_eq!(read(&ds, path, i).await?, Some(format!("hello{i}").into()));
+            }
+        }
+        ds.commit("main", "copy again", None).await?;
+        let ds = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_chunk_transformer(crate::test_utils::XorTransformer(42))
+            .build();
+        for i in 0..2 {
+            assert_eq!(read(&ds, &committed, i).await?, Some(format!("hello{i}").into()));
+            assert_eq!(
+                ds.get_transformed_chunk_ref(&committed, &ChunkIndices(vec![i]))
+                    .await?
+                    .map(|chunk| chunk.transformers),
+                Some(vec!["xor".to_string()])
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_superseded_chunks_are_deleted() -> Result<(), Box<dyn Error>> {
+        let in_mem_storage =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let storage: Arc<dyn Storage + Send + Sync> = in_mem_storage.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![5],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), zarr_meta).await?;
+
+        let count_chunks = || async {
+            in_mem_storage
+                .all_keys()
+                .await
+                .unwrap()
+                .iter()
+                .filter(|key| key.contains("chunk"))
+                .count()
+        };
+
+        // rewrite the same chunk a few times
+        for data in ["a", "b", "c"] {
+            let payload = ds.get_chunk_writer()(data.into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        }
+        // a payload set to two different coordinates, and then overwritten in one of them
+        let shared = ds.get_chunk_writer()("shared".into()).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![1]), Some(shared.clone()))
+            .await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), Some(shared)).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), None).await?;
+        assert_eq!(count_chunks().await, 4);
+
+        ds.commit("main", "commit", None).await?;
+        assert_eq!(count_chunks().await, 2);
+        assert_eq!(
+            get_chunk(
+                ds.get_chunk_reader(&path, &ChunkIndices(vec![0]), &ByteRange::ALL)
+                    .await?
+            )
+            .await?,
+            Some("c".into())
+        );
+        assert_eq!(
+            get_chunk(
+                ds.get_chunk_reader(&path, &ChunkIndices(vec![1]), &ByteRange::ALL)
+                    .await?
+            )
+            .await?,
+            Some("shared".into())
+        );
+
+        // chunks from previous commits are never deleted
+        let payload = ds.get_chunk_writer()("d".into()).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        ds.commit("main", "commit", None).await?;
+        assert_eq!(count_chunks().await, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_superseded_chunk_deletion_is_best_effort() -> Result<(), Box<dyn Error>>
+    {
+        #[derive(Debug, Default)]
+        struct Recorder(std::sync::Mutex<Vec<Event>>);
+        impl crate::events::EventObserver for Recorder {
+            fn on_event(&self, record: &EventRecord) {
+                self.0.lock().unwrap().push(record.event.clone());
+            }
+        }
+
+        let faulty = Arc::new(FaultyStorage::new(Arc::new(
+            ObjectStorage::new_in_memory_store(None),
+        )));
+        let storage: Arc<dyn Storage + Send + Sync> = faulty.clone();
+        let recorder = Arc::new(Recorder::default());
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .with_event_observer(recorder.clone())
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), basic_meta()).await?;
+        for data in ["a", "b"] {
+            let payload = ds.get_chunk_writer()(data.into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        }
+
+        faulty.fail("delete_objects", 0, 1);
+        let snapshot = ds.commit("main", "commit", None).await?;
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, snapshot);
+        assert_eq!(
+            get_chunk(
+                ds.get_chunk_reader(&path, &ChunkIndices(vec![0]), &ByteRange::ALL)
+                    .await?
+            )
+            .await?,
+            Some("b".into())
+        );
+        let events = recorder.0.lock().unwrap().clone();
+        assert!(matches!(events[0], Event::ChunkCleanupFailed { .. }), "{events:?}");
+        assert!(matches!(events[1], Event::Commit { .. }), "{events:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_array_metadata_is_type_checked() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let bytes_codec = |endian: &str| Codec {
+            name: "bytes".to_string(),
+            configuration: Some(HashMap::from([("endian".to_string(), endian.into())])),
+        };
+        let meta = ZarrArrayMetadata {
+            shape: vec![4],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![bytes_codec("little")],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into()?;
+
+        let bad_fill =
+            ZarrArrayMetadata { fill_value: FillValue::Float32(0.0), ..meta.clone() };
+        assert!(matches!(
+            ds.add_array(path.clone(), bad_fill).await,
+            Err(RepositoryError::FormatError(
+                IcechunkFormatError::FillValueMismatch { .. }
+            ))
+        ));
+        let bad_endian =
+            ZarrArrayMetadata { codecs: vec![bytes_codec("middle")], ..meta.clone() };
+        assert!(matches!(
+            ds.add_array(path.clone(), bad_endian).await,
+            Err(RepositoryError::FormatError(IcechunkFormatError::InvalidEndianness(_)))
+        ));
+
+        ds.add_array(path.clone(), meta.clone()).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from(vec![0; 7])).await?;
+        assert!(matches!(
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await,
+            Err(RepositoryError::InvalidChunkSize { expected: 8, found: 7, .. })
+        ));
+        let payload = ds.get_chunk_writer()(Bytes::from(vec![0; 8])).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+
+        // compressed chunks can have any size
+        let compressed = ZarrArrayMetadata {
+            codecs: vec![
+                bytes_codec("big"),
+                Codec { name: "zstd".to_string(), configuration: None },
+            ],
+            ..meta
+        };
+        ds.update_array(path.clone(), compressed).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from(vec![0; 3])).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![1]), Some(payload)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_by_dimension() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let meta = |dims: &[&str]| ZarrArrayMetadata {
+            shape: vec![10; dims.len()],
+            data_type: DataType::Float64,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(10).unwrap(); dims.len()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Float64(0.0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: Some(dims.iter().map(|dim| Some(dim.to_string())).collect()),
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        ds.add_group(Path::root()).await?;
+        ds.add_group("/other".try_into()?).await?;
+        let temp: Path = "/temperature".try_into()?;
+        ds.add_array(temp.clone(), meta(&["time", "lat", "lon"])).await?;
+        ds.add_array("/time".try_into()?, meta(&["time"])).await?;
+        // not a coordinate, it has more than one dimension
+        ds.add_array("/lat".try_into()?, meta(&["lat", "lon"])).await?;
+        // not a coordinate, it's in another group
+        ds.add_array("/other/lon".try_into()?, meta(&["lon"])).await?;
+
+        let mut with_time: Vec<_> = ds
+            .list_arrays_with_dimension("time")
+            .await?
+            .map(|node| node.path.to_string())
+            .collect();
+        with_time.sort();
+        assert_eq!(with_time, vec!["/temperature", "/time"]);
+        assert_eq!(ds.list_arrays_with_dimension("depth").await?.count(), 0);
+
+        let coords = ds.get_coordinates(&temp).await?;
+        assert_eq!(coords.keys().collect::<Vec<_>>(), vec!["time"]);
+        assert_eq!(coords["time"].path.to_string(), "/time");
+        assert!(ds.get_coordinates(&"/time".try_into()?).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_packing() -> Result<(), Box<dyn Error>> {
+        let in_mem_storage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage: Arc<dyn Storage + Send + Sync> = in_mem_storage.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(1)
+            .with_chunk_packing(5, 10)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![6],
+            data_type: DataType::UInt8,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), zarr_meta).await?;
+
+        let count_chunks = || async {
+            in_mem_storage
+                .all_keys()
+                .await
+                .unwrap()
+                .iter()
+                .filter(|key| key.contains("chunk"))
+                .count()
+        };
+        async fn read(ds: &Repository, path: &Path, idx: u64) -> Option<Bytes> {
+            let coords = ChunkIndices(vec![idx]);
+            let reader = ds.get_chunk_reader(path, &coords, &ByteRange::ALL).await;
+            get_chunk(reader.unwrap()).await.unwrap()
+        }
+
+        let data = ["abcd", "efgh", "ijkl", "mn", "not packed", "o"];
+        for (idx, data) in data.into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(data.into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx as u64]), Some(payload))
+                .await?;
+        }
+        // one full pack and the big chunk were uploaded
+        assert_eq!(count_chunks().await, 2);
+        assert_eq!(read(&ds, &path, 1).await, Some("efgh".into()));
+        assert_eq!(read(&ds, &path, 3).await, Some("mn".into()));
+
+        ds.commit("main", "commit", None).await?;
+        assert_eq!(count_chunks().await, 3);
+        for (idx, expected) in data.into_iter().enumerate() {
+            assert_eq!(read(&ds, &path, idx as u64).await, Some(expected.into()));
+        }
+        let coords = ChunkIndices(vec![2]);
+        assert_eq!(
+            get_chunk(
+                ds.get_chunk_reader(&path, &coords, &ByteRange::from_offset(2)).await?
+            )
+            .await?,
+            Some("kl".into())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_write_buffer() -> Result<(), Box<dyn Error>> {
+        let in_mem_storage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage: Arc<dyn Storage + Send + Sync> = in_mem_storage.clone();
+        let spill_dir = tempfile::tempdir()?;
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .with_chunk_write_buffer(spill_dir.path(), 4)
+            .build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![3],
+            data_type: DataType::UInt8,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), zarr_meta).await?;
+
+        let count_chunks = || async {
+            in_mem_storage
+                .all_keys()
+                .await
+                .unwrap()
+                .iter()
+                .filter(|key| key.contains("chunk"))
+                .count()
+        };
+        async fn read(ds: &Repository, path: &Path, idx: u64) -> Option<Bytes> {
+            let coords = ChunkIndices(vec![idx]);
+            let reader = ds.get_chunk_reader(path, &coords, &ByteRange::ALL).await;
+            get_chunk(reader.unwrap()).await.unwrap()
+        }
+
+        for (idx, data) in ["abc", "defgh", "ijk"].into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(data.into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx as u64]), Some(payload))
+                .await?;
+        }
+        // an overwritten chunk is never uploaded
+        let payload = ds.get_chunk_writer()("lmn".into()).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), Some(payload)).await?;
+
+        // nothing is uploaded until commit, but chunks can be read
+        assert_eq!(count_chunks().await, 0);
+        assert_eq!(std::fs::read_dir(spill_dir.path())?.count(), 3);
+        assert_eq!(read(&ds, &path, 1).await, Some("defgh".into()));
+        assert_eq!(read(&ds, &path, 2).await, Some("lmn".into()));
+
+        ds.commit("main", "commit", None).await?;
+        assert_eq!(count_chunks().await, 3);
+        assert_eq!(std::fs::read_dir(spill_dir.path())?.count(), 0);
+        assert_eq!(read(&ds, &path, 0).await, Some("abc".into()));
+        assert_eq!(read(&ds, &path, 2).await, Some("lmn".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_presign_chunk() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(5)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), basic_meta()).await?;
+        for (idx, data) in ["small", "not so small"].into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(data.into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx as u64]), Some(payload))
+                .await?;
+        }
+
+        let ttl = Duration::from_secs(60);
+        assert_eq!(
+            ds.presign_chunk(
+                &path,
+                &ChunkIndices(vec![0]),
+                &ByteRange::bounded(1, 3),
+                ttl
+            )
+            .await?,
+            Some(PresignedChunk::Inline("ma".into()))
+        );
+        // the in memory storage cannot generate URLs
+        assert!(matches!(
+            ds.presign_chunk(&path, &ChunkIndices(vec![1]), &ByteRange::ALL, ttl).await,
+            Err(RepositoryError::StorageError(StorageError::PresignNotSupported))
+        ));
+        assert_eq!(
+            ds.presign_chunk(&path, &ChunkIndices(vec![2]), &ByteRange::ALL, ttl).await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunks_exist() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&backend), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), basic_meta()).await?;
+        for idx in 0..3 {
+            let payload = ds.get_chunk_writer()(Bytes::from_static(b"data")).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx]), Some(payload))
+                .await?;
+        }
+        ds.commit("main", "chunks", None).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut ds = Repository::from_branch_tip(logging_c, "main").await?.build();
+        // the session changes are taken into account
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![1]), None).await?;
+        ds.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![3]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+
+        assert!(ds.chunk_exists(&path, &ChunkIndices(vec![0])).await?);
+        assert!(!ds.chunk_exists(&path, &ChunkIndices(vec![1])).await?);
+        let coords: Vec<_> = (0..5).map(|idx| ChunkIndices(vec![idx])).collect();
+        assert_eq!(
+            ds.chunks_exist(&path, &coords).await?,
+            vec![true, false, true, true, false]
+        );
+        let operations = logging.fetch_operations();
+        assert!(operations.iter().all(|(op, _)| op != "fetch_chunk"));
+        assert_eq!(
+            operations.iter().filter(|(op, _)| op == "fetch_manifests").count(),
+            2
+        );
+        assert!(matches!(
+            ds.chunks_exist(&Path::root(), &coords).await,
+            Err(RepositoryError::NodeNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_refs_in_region() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&backend), false)
+            .await?
+            .with_columnar_manifests(true)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_array(path.clone(), basic_meta()).await?;
+        for idx in 0..5 {
+            ds.set_chunk_ref(
+                path.clone(),
+                ChunkIndices(vec![idx]),
+                Some(ChunkPayload::Inline(Bytes::from(vec![idx as u8]))),
+            )
+            .await?;
+        }
+        let snapshot = ds.commit("main", "chunks", None).await?;
+        let manifest = &backend.fetch_snapshot(&snapshot).await?.manifest_files[0];
+        assert_eq!(manifest.format_version, COLUMNAR_MANIFEST_FORMAT);
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut ds = Repository::from_branch_tip(logging_c, "main").await?.build();
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), None).await?;
+        ds.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![3]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+
+        let region = ManifestExtents::new(ChunkIndices(vec![1]), ChunkIndices(vec![4]));
+        let chunks = ds.chunk_refs_in_region(&path, &region).await?;
+        assert_eq!(
+            chunks,
+            BTreeMap::from([
+                (ChunkIndices(vec![1]), ChunkPayload::Inline(Bytes::from(vec![1]))),
+                (ChunkIndices(vec![3]), ChunkPayload::Inline("new".into())),
+            ])
+        );
+        // the chunks are selected from the columns, the manifest is not built
+        let operations = logging.fetch_operations();
+        assert!(operations.iter().all(|(op, _)| op != "fetch_manifests"));
+        assert!(operations.iter().any(|(op, _)| op == "fetch_manifest_region"));
+        let outside = ManifestExtents::new(ChunkIndices(vec![7]), ChunkIndices(vec![9]));
+        assert_eq!(ds.chunk_refs_in_region(&path, &outside).await?, BTreeMap::new());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_and_open_or_create() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        assert!(!Repository::exists(storage.as_ref()).await?);
+
+        // concurrent creations, only one succeeds
+        let results = futures::future::join_all(
+            (0..5).map(|_| Repository::create(Arc::clone(&storage))),
+        )
+        .await;
+        let created: Vec<_> =
+            results.iter().filter_map(|res| res.as_ref().ok()).collect();
+        assert_eq!(created.len(), 1);
+        assert!(results
+            .iter()
+            .all(|res| res.is_ok()
+                || matches!(res, Err(RepositoryError::AlreadyInitialized))));
+        let snapshot = created[0].clone().build().snapshot_id().clone();
+        assert!(Repository::exists(storage.as_ref()).await?);
+
+        let opened = Repository::open_or_create(Arc::clone(&storage)).await?.build();
+        assert_eq!(opened.snapshot_id(), &snapshot);
+
+        let other: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let results = futures::future::join_all(
+            (0..5).map(|_| Repository::open_or_create(Arc::clone(&other))),
+        )
+        .await;
+        let snapshots: HashSet<_> = results
+            .into_iter()
+            .map(|res| res.map(|builder| builder.build().snapshot_id().clone()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(snapshots.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_from_url() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let url = url::Url::from_directory_path(dir.path()).unwrap().to_string();
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_local_store(dir.path())?);
+        let mut ds = Repository::init(storage, false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let snapshot = ds.commit(Ref::DEFAULT_BRANCH, "first", None).await?;
+
+        let ds = Repository::from_url(&url, None).await?.build();
+        assert_eq!(ds.snapshot_id(), &snapshot);
+        assert!(ds.get_node(&Path::root()).await.is_ok());
+
+        assert!(matches!(
+            Repository::from_url("ftp://host/repo", None).await,
+            Err(RepositoryError::InvalidUrl(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_idempotent() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let base = ds.snapshot_id().clone();
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit_idempotent("main", "first", None, "job-1").await?;
+
+        // a retry in a new session with the same changes finds the original commit
+        let mut retry = Repository::update(Arc::clone(&storage), base).build();
+        retry.add_group(Path::root()).await?;
+        assert_eq!(retry.commit_idempotent("main", "first", None, "job-1").await?, first);
+        assert_eq!(retry.snapshot_id(), &first);
+        assert!(!retry.has_uncommitted_changes());
+        // retrying in the same session also works
+        assert_eq!(ds.commit_idempotent("main", "first", None, "job-1").await?, first);
+        assert_eq!(ds.ancestry().await?.count().await, 2);
+
+        // a previous attempt flushed but didn't update the branch
+        ds.add_group("/a".try_into()?).await?;
+        let properties = SnapshotProperties::from_iter([(
+            IDEMPOTENCY_KEY_PROPERTY.to_string(),
+            serde_json::Value::from("job-2"),
+        )]);
+        let flushed = ds.flush("second", properties).await?;
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, first);
+        assert_eq!(ds.commit_idempotent("main", "second", None, "job-2").await?, flushed);
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, flushed);
+
+        // a new key creates a new commit
+        ds.add_group("/b".try_into()?).await?;
+        let third = ds.commit_idempotent("main", "third", None, "job-3").await?;
+        assert_ne!(third, flushed);
+        assert_eq!(ds.ancestry().await?.count().await, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_amend_commit() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let base = ds.snapshot_id().clone();
+        ds.add_group(Path::root()).await?;
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"title":"tpyo"}"#)?),
+        )
+        .await?;
+        let first = ds.commit("main", "frist", None).await?;
+        let mut stale = Repository::update(Arc::clone(&storage), first).build();
+
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"title":"typo"}"#)?),
+        )
+        .await?;
+        let amended = ds.amend_commit("main", "first", None).await?;
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, amended);
+        let ancestry: Vec<_> = ds.ancestry().await?.try_collect().await?;
+        assert_eq!(
+            ancestry.iter().map(|meta| &meta.id).collect::<Vec<_>>(),
+            vec![&amended, &base]
+        );
+        assert_eq!(ancestry[0].message, "first");
+        assert_eq!(
+            ds.get_user_attributes(&Path::root()).await?,
+            Some(UserAttributes::try_new(br#"{"title":"typo"}"#)?)
+        );
+        // the log has the changes of both, for rebases of sessions started at the parent
+        let tx_log = storage.fetch_transaction_log(&amended).await?;
+        assert_eq!(tx_log.new_groups.len(), 1);
+        assert_eq!(tx_log.updated_user_attributes.len(), 1);
+
+        // only the message
+        let renamed = ds.amend_commit("main", "the first", None).await?;
+        assert_eq!(ds.ancestry().await?.count().await, 2);
+        assert!(ds.get_user_attributes(&Path::root()).await?.is_some());
+
+        assert!(matches!(
+            stale.amend_commit("main", "late", None).await,
+            Err(RepositoryError::Conflict { .. })
+        ));
+        ds.add_group("/new".try_into()?).await?;
+        assert!(matches!(
+            ds.amend_commit("main", "more", None).await,
+            Err(RepositoryError::InvalidAmend(_))
+        ));
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, renamed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_amend_commit_keeps_reserved_properties() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.put_document(Path::root(), "README", "text/plain", Bytes::from("hello"))
+            .await?;
+        ds.commit_idempotent("main", "frist", None, "key").await?;
+        let props = |value: &str| {
+            SnapshotProperties::from_iter([("team".to_string(), value.into())])
+        };
+
+        // only the message and properties
+        let amended = ds.amend_commit("main", "first", Some(props("a"))).await?;
+        // and with attributes, the documents are computed again by the flush
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"title":"typo"}"#)?),
+        )
+        .await?;
+        let reamended = ds.amend_commit("main", "first", Some(props("b"))).await?;
+
+        for (id, team) in [(amended, "a"), (reamended, "b")] {
+            let snapshot = storage.fetch_snapshot(&id).await?;
+            assert_eq!(snapshot.properties.get("team"), Some(&team.into()));
+            assert_eq!(
+                snapshot.properties.get(IDEMPOTENCY_KEY_PROPERTY),
+                Some(&"key".into())
+            );
+            let session = Repository::update(Arc::clone(&storage), id).build();
+            assert_eq!(
+                session.get_document(&Path::root(), "README").await?,
+                Bytes::from("hello")
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_amend_commit_guarded() -> Result<(), Box<dyn Error>> {
+        // snapshots can't be overwritten, amending must write a new one
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(GuardedStorage::new(test_utils::new_in_memory_storage()));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit("main", "frist", None).await?;
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"title":"typo"}"#)?),
+        )
+        .await?;
+        let amended = ds.amend_commit("main", "first", None).await?;
+        assert_ne!(amended, first);
+        assert_eq!(ds.snapshot_id(), &amended);
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, amended);
+        assert_eq!(ds.ancestry().await?.count().await, 2);
+        assert!(ds.get_user_attributes(&Path::root()).await?.is_some());
+
+        // a conflict leaves the session as it was
+        let mut stale = Repository::update(Arc::clone(&storage), amended.clone()).build();
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"title":"fixed"}"#)?),
+        )
+        .await?;
+        stale.add_group("/other".try_into()?).await?;
+        let tip = stale.commit("main", "other", None).await?;
+        assert!(matches!(
+            ds.amend_commit("main", "late", None).await,
+            Err(RepositoryError::Conflict { .. })
+        ));
+        assert_eq!(ds.snapshot_id(), &amended);
+        assert!(ds.has_uncommitted_changes());
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, tip);
+
+        // so do failures after the changes were flushed, writing the amended snapshot or
+        // moving the branch
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&storage)));
+        let mut ds = Repository::from_branch_tip(faulty.clone(), "main").await?.build();
+        let fixed = UserAttributes::try_new(br#"{"title":"fixed"}"#)?;
+        ds.set_user_attributes(Path::root(), Some(fixed.clone())).await?;
+        for operation in ["write_snapshot", "write_ref"] {
+            faulty.fail(operation, 0, 1);
+            assert!(ds.amend_commit("main", "other", None).await.is_err());
+            assert_eq!(ds.snapshot_id(), &tip);
+            assert!(ds.has_uncommitted_changes());
+            assert_eq!(ds.get_user_attributes(&Path::root()).await?, Some(fixed.clone()));
+            assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, tip);
+        }
+        let amended = ds.amend_commit("main", "other", None).await?;
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, amended);
+        let reopened =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert_eq!(reopened.get_user_attributes(&Path::root()).await?, Some(fixed));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ingest() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![10],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+
+        let pulled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let writes = futures::stream::iter(0..10u64).map(|c| {
+            pulled.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            ChunkWrite::new(
+                path.clone(),
+                ChunkIndices(vec![c]),
+                Bytes::from(vec![c as u8]),
+            )
+        });
+        let config = IngestConfig::default()
+            .with_concurrency(3)
+            .with_checkpoint("main", 4, "ingest");
+        let mut reports = Vec::new();
+        let progress = ds
+            .ingest(writes, &config, |progress| {
+                // the stream is not read ahead of the uploads
+                let pulled = pulled.load(std::sync::atomic::Ordering::Relaxed);
+                assert!(pulled - progress.chunks < 3);
+                reports.push(progress.clone());
+            })
+            .await?;
+        assert_eq!(progress.chunks, 10);
+        assert_eq!(progress.bytes, 10);
+        assert_eq!(progress.pending_chunks, 0);
+        assert_eq!(reports.len(), 11);
+        assert_eq!(reports[3].pending_chunks, 0);
+        assert_eq!(reports[4].pending_chunks, 1);
+
+        let tip = fetch_branch_tip(storage.as_ref(), "main").await?.snapshot;
+        assert_eq!(progress.checkpoint, Some(tip.clone()));
+        // init, and three checkpoints: after 4, 8 and 10 chunks
+        assert_eq!(ds.ancestry().await?.count().await, 4);
+        let view = SnapshotView::open(Arc::clone(&storage), &tip).await?;
+        for c in 0..10u64 {
+            assert_eq!(
+                view.get_chunk(&path, &ChunkIndices(vec![c]), &ByteRange::ALL).await?,
+                Some(Bytes::from(vec![c as u8]))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ingest_streaming() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/sensor".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![10],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        ds.commit("main", "create", None).await?;
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let write = |c: u64| {
+            ChunkWrite::new(
+                path.clone(),
+                ChunkIndices(vec![c]),
+                Bytes::from(vec![c as u8]),
+            )
+        };
+        let config = CoalescingConfig::new("main", "stream")
+            .with_interval(Some(Duration::from_millis(50)))
+            .with_max_pending_bytes(Some(4));
+        let mut commits = pin!(ds.ingest_streaming(rx, config));
+
+        // a single chunk is committed when the interval elapses
+        tx.unbounded_send(write(0))?;
+        let commit = commits.next().await.unwrap()?;
+        assert_eq!(
+            (commit.chunks, commit.bytes, commit.trigger),
+            (1, 1, CommitTrigger::Interval)
+        );
+        // the chunks ready are written before the interval is checked
+        for c in 1..=5 {
+            tx.unbounded_send(write(c))?;
+        }
+        let commit = commits.next().await.unwrap()?;
+        assert_eq!(
+            (commit.chunks, commit.bytes, commit.trigger),
+            (4, 4, CommitTrigger::PendingBytes)
+        );
+        drop(tx);
+        let last = commits.next().await.unwrap()?;
+        assert_eq!((last.chunks, last.trigger), (1, CommitTrigger::EndOfStream));
+        assert!(commits.next().await.is_none());
+
+        let tip = fetch_branch_tip(storage.as_ref(), "main").await?.snapshot;
+        assert_eq!(last.snapshot, tip);
+        let view = SnapshotView::open(Arc::clone(&storage), &tip).await?;
+        for c in 0..=5u64 {
+            assert_eq!(
+                view.get_chunk(&path, &ChunkIndices(vec![c]), &ByteRange::ALL).await?,
+                Some(Bytes::from(vec![c as u8]))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunks_changed_since() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let initial = ds.snapshot_id().clone();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![8],
+                data_type: DataType::Int32,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::Int32(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        let chunk =
+            |c: u64| (ChunkIndices(vec![c]), Some(ChunkPayload::Inline("x".into())));
+        for c in 0..3 {
+            let (coords, payload) = chunk(c);
+            ds.set_chunk_ref(path.clone(), coords, payload).await?;
+        }
+        let first = ds.commit("main", "first", None).await?;
+
+        let (coords, payload) = chunk(1);
+        ds.set_chunk_ref(path.clone(), coords, payload).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), None).await?;
+        let second = ds.commit("main", "second", None).await?;
+        let (coords, payload) = chunk(3);
+        ds.set_chunk_ref(path.clone(), coords, payload).await?;
+
+        let coords = |cs: &[u64]| cs.iter().map(|c| ChunkIndices(vec![*c])).collect();
+        assert_eq!(
+            ds.chunks_changed_since(&path, &first).await?,
+            ChangedChunks { written: coords(&[1, 3]), deleted: coords(&[2]) }
+        );
+        assert_eq!(
+            ds.chunks_changed_since(&path, &second).await?,
+            ChangedChunks { written: coords(&[3]), deleted: coords(&[]) }
+        );
+        assert_eq!(
+            ds.chunks_changed_since(&path, &initial).await?,
+            ChangedChunks { written: coords(&[0, 1, 3]), deleted: coords(&[]) }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_nodes_filtered() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        for path in ["/", "/a", "/a/b", "/a/b/c", "/a-z"] {
+            ds.add_group(path.try_into()?).await?;
+        }
+        ds.add_array(
+            "/a/arr".try_into()?,
+            ZarrArrayMetadata {
+                shape: vec![1],
+                data_type: DataType::Int32,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::Int32(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        ds.commit("main", "tree", None).await?;
+        ds.add_group("/a/new".try_into()?).await?;
+        ds.delete_group("/a/b/c".try_into()?).await?;
+
+        let paths = |nodes: Vec<NodeSnapshot>| {
+            nodes.into_iter().map(|node| node.path.to_string()).collect_vec()
+        };
+        let listing = NodeListing::default().with_prefix("/a".try_into()?);
+        let children = listing.clone().with_max_depth(1);
+        assert_eq!(
+            paths(ds.list_nodes_filtered(&children).await?.collect()),
+            vec!["/a", "/a/arr", "/a/b", "/a/new"]
+        );
+        let arrays = listing.with_kind(NodeType::Array);
+        assert_eq!(
+            paths(ds.list_nodes_filtered(&arrays).await?.collect()),
+            vec!["/a/arr"]
+        );
+
+        let mut pages = vec![];
+        let mut listing = NodeListing::default();
+        loop {
+            let page = ds.list_nodes_page(&listing, 2).await?;
+            pages.push(paths(page.nodes));
+            match page.next_page_token {
+                Some(token) => listing = listing.with_page_token(token),
+                None => break,
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![vec!["/", "/a"], vec!["/a/arr", "/a/b"], vec!["/a/new", "/a-z"]]
+        );
+        assert!(matches!(
+            ds.list_nodes_page(&NodeListing::default().with_page_token("bad"), 2).await,
+            Err(RepositoryError::InvalidPageToken(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_status_and_dry_run() -> Result<(), Box<dyn Error>> {
+        let in_mem_storage =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let storage: Arc<dyn Storage + Send + Sync> = in_mem_storage.clone();
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
-        ds.add_group(Path::root()).await?;
-        ds.add_group("/a".try_into().unwrap()).await?;
-        ds.add_group("/b".try_into().unwrap()).await?;
-        ds.add_group("/b/bb".try_into().unwrap()).await?;
-        ds.delete_group("/b".try_into().unwrap()).await?;
-        assert!(ds.get_group(&"/b".try_into().unwrap()).await.is_err());
-        assert!(ds.get_group(&"/b/bb".try_into().unwrap()).await.is_err());
-        Ok(())
-    }
+        assert!(ds.status().await?.is_empty());
+        assert!(matches!(
+            ds.commit_dry_run("main").await,
+            Err(RepositoryError::NoChangesToCommit)
+        ));
 
-    #[tokio::test]
-    async fn test_delete_children_of_old_nodes() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
-            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
-        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![5],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into()?;
         ds.add_group(Path::root()).await?;
-        ds.add_group("/a".try_into().unwrap()).await?;
-        ds.add_group("/b".try_into().unwrap()).await?;
-        ds.add_group("/b/bb".try_into().unwrap()).await?;
-        ds.commit("main", "commit", None).await?;
+        ds.add_array(path.clone(), zarr_meta.clone()).await?;
+        ds.commit("main", "first", None).await?;
 
-        ds.delete_group("/b".try_into().unwrap()).await?;
-        assert!(ds.get_group(&"/b".try_into().unwrap()).await.is_err());
-        assert!(ds.get_group(&"/b/bb".try_into().unwrap()).await.is_err());
+        ds.update_array(path.clone(), zarr_meta).await?;
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"foo":42}"#).unwrap()),
+        )
+        .await?;
+        ds.add_group("/group".try_into()?).await?;
+        ds.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![1]), None).await?;
+
+        let status = ds.status().await?;
+        assert_eq!(
+            status,
+            SessionStatus {
+                new_groups: BTreeSet::from(["/group".try_into()?]),
+                updated_arrays: BTreeSet::from([path.clone()]),
+                updated_attributes: BTreeSet::from([Path::root()]),
+                written_chunks: BTreeMap::from([(path.clone(), 1)]),
+                deleted_chunks: BTreeMap::from([(path.clone(), 1)]),
+                inline_chunk_bytes: 5,
+                ..Default::default()
+            }
+        );
+
+        let snapshots = || async {
+            in_mem_storage
+                .all_keys()
+                .await
+                .unwrap()
+                .iter()
+                .filter(|key| key.contains("snapshot"))
+                .count()
+        };
+        let before = snapshots().await;
+        assert_eq!(ds.commit_dry_run("main").await?, status);
+        assert_eq!(ds.commit_dry_run("new-branch").await?, status);
+        assert_eq!(snapshots().await, before);
+
+        // another session moves the branch tip
+        let mut other =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        other.add_group("/other".try_into()?).await?;
+        other.commit("main", "other", None).await?;
+        assert!(matches!(
+            ds.commit_dry_run("main").await,
+            Err(RepositoryError::Conflict { .. })
+        ));
         Ok(())
     }
 
@@ -1970,6 +7657,8 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let a1path: Path = "/array1".try_into()?;
@@ -2039,7 +7728,7 @@ mod tests {
             NodeData::Array(_, manifests) => {
                 manifests.first().as_ref().unwrap().object_id.clone()
             }
-            NodeData::Group => panic!("must be an array"),
+            _ => panic!("must be an array"),
         };
         let manifest = storage.fetch_manifests(&manifest_id).await?;
         let initial_size = manifest.len();
@@ -2062,7 +7751,7 @@ mod tests {
             NodeData::Array(_, manifests) => {
                 manifests.first().as_ref().unwrap().object_id.clone()
             }
-            NodeData::Group => panic!("must be an array"),
+            _ => panic!("must be an array"),
         };
         let manifest = storage.fetch_manifests(&manifest_id).await?;
         let size_after_delete = manifest.len();
@@ -2098,7 +7787,7 @@ mod tests {
             NodeData::Array(_, manifests) => {
                 manifests.first().as_ref().unwrap().object_id.clone()
             }
-            NodeData::Group => panic!("must be an array"),
+            _ => panic!("must be an array"),
         };
         let manifest = storage.fetch_manifests(&manifest_id).await?;
         let size_after_chunk_delete = manifest.len();
@@ -2107,6 +7796,320 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_flush_writes_metadata_before_snapshot() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&backend)));
+        let path: Path = "/temperature".try_into()?;
+        let partitioning = ManifestPartitioning::new(0, NonZeroU32::new(2).unwrap());
+        let mut ds = Repository::init(
+            Arc::clone(&faulty) as Arc<dyn Storage + Send + Sync>,
+            false,
+        )
+        .await?
+        .with_manifest_partitioning(path.clone(), partitioning)
+        .with_attributes_split_threshold_bytes(1)
+        .build();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), test_utils::array_metadata(vec![8])).await?;
+        ds.set_user_attributes(
+            path.clone(),
+            Some(UserAttributes::try_new(br#"{"a":1}"#)?),
+        )
+        .await?;
+        for t in 0..8 {
+            let payload = ChunkPayload::Inline(Bytes::from(vec![t as u8]));
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![t]), Some(payload)).await?;
+        }
+        let snapshots = backend.list_snapshots().await?.count().await;
+
+        // one of the four partitions fails, the snapshot is not written
+        faulty.fail("write_manifests_if_absent", 2, 1);
+        assert!(ds.commit("main", "partitions", None).await.is_err());
+        assert_eq!(backend.list_snapshots().await?.count().await, snapshots);
+
+        let id = ds.commit("main", "partitions", None).await?;
+        assert_eq!(backend.fetch_snapshot(&id).await?.manifest_files.len(), 4);
+        assert_eq!(backend.list_objects("attributes/").await?.count().await, 1);
+        let reader = Repository::update(backend, id).build();
+        assert_eq!(
+            reader.get_user_attributes(&path).await?,
+            Some(UserAttributes::try_new(br#"{"a":1}"#)?)
+        );
+        for t in 0..8 {
+            assert_eq!(
+                reader.get_chunk_ref(&path, &ChunkIndices(vec![t])).await?,
+                Some(ChunkPayload::Inline(Bytes::from(vec![t as u8])))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_chunks_partial() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&backend)));
+        let path: Path = "/temperature".try_into()?;
+        let mut ds = Repository::init(
+            Arc::clone(&faulty) as Arc<dyn Storage + Send + Sync>,
+            false,
+        )
+        .await?
+        .with_inline_threshold_bytes(0)
+        .build();
+        ds.add_array(path.clone(), test_utils::array_metadata(vec![4])).await?;
+        let mut ids = Vec::new();
+        for t in [0, 1, 3] {
+            let payload = ds.get_chunk_writer()(Bytes::from(vec![t as u8; 4])).await?;
+            if let ChunkPayload::Ref(chunk_ref) = &payload {
+                ids.push(chunk_ref.id.clone());
+            }
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![t]), Some(payload)).await?;
+        }
+        ds.commit("main", "chunks", None).await?;
+        backend
+            .delete_chunks(futures::stream::iter(vec![ids[1].clone()]).boxed())
+            .await?;
+
+        // chunk 3 is the third chunk fetched
+        faulty.fail("fetch_chunk", 2, 1);
+        let coords = (0..4).map(|t| ChunkIndices(vec![t]));
+        let read = ds.get_chunks_partial(&path, coords, &ByteRange::ALL, 1).await?;
+        assert!(!read.is_complete());
+        assert_eq!(
+            read.chunks,
+            vec![
+                (ChunkIndices(vec![0]), Some(Bytes::from(vec![0; 4]))),
+                (ChunkIndices(vec![2]), None)
+            ]
+        );
+        let errors: Vec<_> = read.errors.iter().map(|e| (&e.coords, e.kind)).collect();
+        assert_eq!(
+            errors,
+            vec![
+                (&ChunkIndices(vec![1]), ChunkReadErrorKind::Missing),
+                (&ChunkIndices(vec![3]), ChunkReadErrorKind::Other)
+            ]
+        );
+        assert_eq!(
+            ChunkReadErrorKind::of(
+                &StorageError::Shared(Arc::new(StorageError::ChunkCorrupted(
+                    "chunk".to_string(),
+                    1
+                )))
+                .into()
+            ),
+            ChunkReadErrorKind::Corrupted
+        );
+
+        let read =
+            ds.get_chunks_partial(&path, [ChunkIndices(vec![3])], &ByteRange::ALL, 4);
+        assert_eq!(
+            read.await?.chunks,
+            vec![(ChunkIndices(vec![3]), Some(Bytes::from(vec![3; 4])))]
+        );
+        assert!(ds
+            .get_chunks_partial(&"/missing".try_into()?, [], &ByteRange::ALL, 4)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest_split() -> Result<(), Box<dyn Error>> {
+        #[derive(Debug, Default)]
+        struct Recorder(std::sync::Mutex<Vec<Event>>);
+        impl crate::events::EventObserver for Recorder {
+            fn on_event(&self, record: &EventRecord) {
+                self.0.lock().unwrap().push(record.event.clone());
+            }
+        }
+
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage + Send + Sync> = faulty.clone();
+        let recorder = Arc::new(Recorder::default());
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_event_observer(recorder.clone())
+            .build();
+        let (wide, empty): (Path, Path) = ("/wide".try_into()?, "/empty".try_into()?);
+        ds.add_group(Path::root()).await?;
+        ds.add_array(wide.clone(), basic_meta()).await?;
+        ds.add_array(empty.clone(), basic_meta()).await?;
+        let payload = |i: u64| ChunkPayload::Inline(Bytes::from(vec![i as u8; 10]));
+        for i in 0..4 {
+            ds.set_chunk_ref(wide.clone(), ChunkIndices(vec![i]), Some(payload(i)))
+                .await?;
+        }
+        let check_chunks = |snapshot: SnapshotId| {
+            let storage = Arc::clone(&storage);
+            let wide = wide.clone();
+            async move {
+                let ds = Repository::update(storage, snapshot).build();
+                for i in 0..4 {
+                    let coords = ChunkIndices(vec![i]);
+                    assert_eq!(
+                        ds.get_chunk_ref(&wide, &coords).await.unwrap(),
+                        Some(payload(i))
+                    );
+                }
+            }
+        };
+
+        // the backend rejects the manifest for its size, its halves are written instead
+        faulty.reject_too_large("write_manifests_if_absent", 0, 1);
+        let first = ds.commit("main", "first", None).await?;
+        assert_eq!(backend.fetch_snapshot(&first).await?.manifest_files.len(), 2);
+        check_chunks(first.clone()).await;
+        let NodeData::Array(_, refs) = ds.get_node(&empty).await?.node_data else {
+            panic!("must be an array")
+        };
+        assert!(refs.is_empty());
+
+        // manifests larger than the threshold are split before writing
+        let mut ds = Repository::update(Arc::clone(&storage), first.clone())
+            .with_event_observer(recorder.clone())
+            .with_manifest_split_threshold_bytes(1)
+            .build();
+        ds.set_chunk_ref(wide.clone(), ChunkIndices(vec![0]), Some(payload(0))).await?;
+        let second = ds.commit("main", "second", None).await?;
+        assert_eq!(backend.fetch_snapshot(&second).await?.manifest_files.len(), 4);
+        check_chunks(second.clone()).await;
+
+        let splits: Vec<_> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::ManifestSplit { snapshot, manifests, .. } => {
+                    Some((snapshot.clone(), *manifests))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(splits, vec![(first.clone(), 2), (second.clone(), 4)]);
+        let manifest = backend
+            .fetch_manifests(&backend.fetch_snapshot(&first).await?.manifest_files[0].id)
+            .await?;
+        assert!(
+            rmp_serde::to_vec(manifest.as_ref())?.len() as u64
+                <= manifest.estimated_size()
+        );
+
+        // transient failures are retried, the manifest is not split
+        faulty.fail("write_manifests_if_absent", 0, 2);
+        let mut ds = Repository::update(Arc::clone(&storage), second).build();
+        ds.set_chunk_ref(wide.clone(), ChunkIndices(vec![1]), Some(payload(1))).await?;
+        let third = ds.commit("main", "third", None).await?;
+        assert_eq!(backend.fetch_snapshot(&third).await?.manifest_files.len(), 1);
+        check_chunks(third).await;
+
+        // a manifest of a single chunk is not split
+        faulty.reject_too_large("write_manifests_if_absent", 0, 1);
+        let mut ds = Repository::update(Arc::clone(&storage), first).build();
+        ds.delete_array(wide.clone()).await?;
+        ds.set_chunk_ref(empty.clone(), ChunkIndices(vec![0]), Some(payload(0))).await?;
+        assert!(ds.commit("main", "fourth", None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest_splits_are_bounded() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let faulty = Arc::new(FaultyStorage::new(Arc::clone(&backend)));
+        let mut ds = Repository::init(faulty.clone(), false).await?.build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), test_utils::array_metadata(vec![2048])).await?;
+        for i in 0..2048 {
+            let payload = ChunkPayload::Inline(Bytes::from(vec![1]));
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![i]), Some(payload)).await?;
+        }
+
+        // every write is rejected, the pieces are halved ten times, down to two chunks
+        faulty.reject_too_large("write_manifests_if_absent", 0, usize::MAX);
+        assert!(ds.commit("main", "too large", None).await.is_err());
+        assert_eq!(faulty.injected_failures(), (1 << (MAX_MANIFEST_SPLITS + 1)) - 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest_partitions() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let (path, other): (Path, Path) =
+            ("/temperature".try_into()?, "/other".try_into()?);
+        let partitioning = ManifestPartitioning::new(0, NonZeroU32::new(4).unwrap());
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_manifest_partitioning(path.clone(), partitioning)
+            .build();
+        ds.add_group(Path::root()).await?;
+        for array in [&path, &other] {
+            let zarr_meta = ZarrArrayMetadata {
+                shape: vec![12, 1],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap(); 2]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            };
+            ds.add_array(array.clone(), zarr_meta).await?;
+        }
+        let chunk = |t: u64| ChunkIndices(vec![t, 0]);
+        let payload = |t: u64| ChunkPayload::Inline(Bytes::from(vec![t as u8]));
+        for t in 0..6 {
+            ds.set_chunk_ref(path.clone(), chunk(t), Some(payload(t))).await?;
+        }
+        ds.set_chunk_ref(other.clone(), chunk(0), Some(payload(0))).await?;
+        let first = ds.commit("main", "first", None).await?;
+
+        let manifests = |node: NodeSnapshot| match node.node_data {
+            NodeData::Array(_, manifests) => manifests,
+            _ => panic!("must be an array"),
+        };
+        let refs = manifests(ds.get_node(&path).await?);
+        assert_eq!(
+            refs.iter().map(|r| r.extents.clone()).collect::<Vec<_>>(),
+            vec![partitioning.extents(2, 0), partitioning.extents(2, 1)]
+        );
+        assert_eq!(manifests(ds.get_node(&other).await?).len(), 1);
+        assert_eq!(storage.fetch_snapshot(&first).await?.manifest_files.len(), 3);
+
+        // appends only rewrite the last partition, and roll over to a new one
+        for t in 6..9 {
+            ds.set_chunk_ref(path.clone(), chunk(t), Some(payload(t))).await?;
+        }
+        let second = ds.commit("main", "append", None).await?;
+        let appended = manifests(ds.get_node(&path).await?);
+        assert_eq!(appended.len(), 3);
+        assert_eq!(appended[0], refs[0]);
+        assert_ne!(appended[1].object_id, refs[1].object_id);
+        assert_eq!(storage.fetch_manifests(&appended[1].object_id).await?.len(), 4);
+        assert_eq!(storage.fetch_manifests(&appended[2].object_id).await?.len(), 1);
+
+        // reading a recent chunk fetches the snapshot and its partition
+        let ds = Repository::update(Arc::clone(&storage), second).build();
+        assert_eq!(ds.get_chunk_ref(&path, &chunk(8)).await?, Some(payload(8)));
+        assert_eq!(ds.metrics().metadata_fetches, 2);
+        for t in 0..9 {
+            assert_eq!(ds.get_chunk_ref(&path, &chunk(t)).await?, Some(payload(t)));
+        }
+        assert_eq!(ds.get_chunk_ref(&path, &chunk(9)).await?, None);
+        assert_eq!(ds.get_chunk_ref(&other, &chunk(0)).await?, Some(payload(0)));
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_all_chunks_iterator() -> Result<(), Box<dyn Error>> {
         let storage: Arc<dyn Storage + Send + Sync> =
@@ -2127,6 +8130,8 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -2217,6 +8222,8 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let new_array_path: Path = "/array1".try_into().unwrap();
@@ -2330,6 +8337,8 @@ mod tests {
             codecs: vec![],
             storage_transformers: None,
             dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
         }
     }
 
@@ -2409,6 +8418,156 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test()]
+    async fn test_detect_conflicts_before_commit() -> Result<(), Box<dyn Error>> {
+        let (mut repo1, mut repo2) = get_repos_for_conflict().await?;
+        let path: Path = "/foo/bar/some-array".try_into().unwrap();
+        repo2.update_array(path.clone(), basic_meta()).await?;
+        assert_eq!(repo2.detect_conflicts("main").await?, vec![]);
+
+        repo1.add_group("/unrelated".try_into().unwrap()).await?;
+        repo1.commit(Ref::DEFAULT_BRANCH, "unrelated", None).await?;
+        assert_eq!(repo2.detect_conflicts("main").await?, vec![]);
+        repo1.update_array(path.clone(), basic_meta()).await?;
+        let conflicting = repo1.commit(Ref::DEFAULT_BRANCH, "update array", None).await?;
+
+        let before = repo2.snapshot_id().clone();
+        assert_eq!(
+            repo2.detect_conflicts("main").await?,
+            vec![CommitConflicts {
+                snapshot: conflicting,
+                conflicts: vec![Conflict::ZarrMetadataDoubleUpdate(path)],
+            }]
+        );
+        // the session is untouched
+        assert_eq!(repo2.snapshot_id(), &before);
+        assert!(repo2.has_uncommitted_changes());
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct TableSchema;
+
+    impl NodeKindSchema for TableSchema {
+        fn validate(&self, metadata: &[u8]) -> Result<(), String> {
+            let json: serde_json::Value =
+                serde_json::from_slice(metadata).map_err(|e| e.to_string())?;
+            if json.get("columns").is_some_and(|c| c.is_array()) {
+                Ok(())
+            } else {
+                Err("missing columns".to_string())
+            }
+        }
+    }
+
+    fn table(columns: &str) -> CustomNodeData {
+        CustomNodeData {
+            kind: "table".to_string(),
+            metadata: format!(r#"{{"columns":{columns}}}"#).into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_nodes() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_node_kind("table", Arc::new(TableSchema))
+            .build();
+        ds.add_group(Path::root()).await?;
+        let path: Path = "/table".try_into()?;
+
+        assert!(matches!(
+            ds.add_custom_node(
+                path.clone(),
+                CustomNodeData { kind: "mesh".to_string(), metadata: Bytes::new() }
+            )
+            .await,
+            Err(RepositoryError::UnknownNodeKind(kind)) if kind == "mesh"
+        ));
+        assert!(matches!(
+            ds.add_custom_node(path.clone(), CustomNodeData { metadata: "{}".into(), ..table("[]") }).await,
+            Err(RepositoryError::InvalidCustomNode { message, .. }) if message == "missing columns"
+        ));
+
+        ds.add_custom_node(path.clone(), table(r#"["a"]"#)).await?;
+        ds.add_array("/table/a".try_into()?, basic_meta()).await?;
+        assert_eq!(
+            ds.get_custom_node(&path).await?.node_data,
+            NodeData::Custom(table(r#"["a"]"#))
+        );
+        assert!(matches!(
+            ds.get_group(&path).await,
+            Err(RepositoryError::NotAGroup { .. })
+        ));
+        ds.commit("main", "add table", None).await?;
+
+        let mut ds = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_node_kind("table", Arc::new(TableSchema))
+            .build();
+        assert_eq!(
+            ds.get_custom_node(&path).await?.node_data,
+            NodeData::Custom(table(r#"["a"]"#))
+        );
+        ds.update_custom_node(path.clone(), table(r#"["a","b"]"#)).await?;
+        assert_eq!(
+            ds.get_custom_node(&path).await?.node_data,
+            NodeData::Custom(table(r#"["a","b"]"#))
+        );
+        ds.commit("main", "update table", None).await?;
+        assert_eq!(
+            ds.list_nodes()
+                .await?
+                .filter(|node| node.node_type() == NodeType::Custom)
+                .count(),
+            1
+        );
+
+        ds.delete_custom_node(path.clone()).await?;
+        assert!(matches!(
+            ds.get_node(&"/table/a".try_into()?).await,
+            Err(RepositoryError::NodeNotFound { .. })
+        ));
+        ds.commit("main", "delete table", None).await?;
+        assert_eq!(ds.list_nodes().await?.count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test()]
+    /// Test conflict detection
+    ///
+    /// This session: update custom node
+    /// Previous commit: update same custom node
+    async fn test_conflict_detection_double_custom_node_edit(
+    ) -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo1 = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_node_kind("table", Arc::new(TableSchema))
+            .build();
+        let path: Path = "/table".try_into()?;
+        repo1.add_custom_node(path.clone(), table("[]")).await?;
+        repo1.commit(Ref::DEFAULT_BRANCH, "add table", None).await?;
+
+        let mut repo2 = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_node_kind("table", Arc::new(TableSchema))
+            .build();
+        repo1.update_custom_node(path.clone(), table(r#"["a"]"#)).await?;
+        repo1.commit(Ref::DEFAULT_BRANCH, "update table", None).await?;
+
+        repo2.update_custom_node(path.clone(), table(r#"["b"]"#)).await?;
+        repo2.commit("main", "update table again", None).await.unwrap_err();
+        assert_has_conflict(
+            &Conflict::CustomNodeDoubleUpdate(path),
+            repo2.rebase(&ConflictDetector, "main").await,
+        );
+        Ok(())
+    }
+
     #[tokio::test()]
     /// Test conflict detection
     ///
@@ -2595,6 +8754,45 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test()]
+    async fn test_max_history_depth() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            crate::test_utils::new_in_memory_storage();
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_max_history_depth(2)
+            .build();
+        let mut behind = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_max_history_depth(2)
+            .build();
+        let base = behind.snapshot_id().clone();
+        for i in 0..3 {
+            repo.add_group(Path::try_from(format!("/group-{i}").as_str())?).await?;
+            repo.commit("main", "commit", None).await?;
+        }
+
+        let ancestry: Vec<_> = repo.ancestry().await?.collect().await;
+        assert_eq!(ancestry.len(), 4);
+        assert!(ancestry[..3].iter().all(|res| res.is_ok()));
+        assert!(matches!(
+            ancestry[3],
+            Err(RepositoryError::HistoryTooDeep { limit: 2, .. })
+        ));
+
+        behind.add_group("/other".try_into().unwrap()).await?;
+        assert!(matches!(
+            behind.rebase(&ConflictDetector, "main").await,
+            Err(RepositoryError::HistoryTooDeep { limit: 2, .. })
+        ));
+        let mut deeper = Repository::update(Arc::clone(&storage), base)
+            .with_max_history_depth(3)
+            .build();
+        deeper.add_group("/other".try_into().unwrap()).await?;
+        deeper.rebase(&ConflictDetector, "main").await?;
+        Ok(())
+    }
+
     #[tokio::test()]
     async fn test_rebase_without_fast_forward() -> Result<(), Box<dyn Error>> {
         let storage: Arc<dyn Storage + Send + Sync> =
@@ -2611,6 +8809,8 @@ mod tests {
             codecs: vec![],
             storage_transformers: None,
             dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -2689,6 +8889,8 @@ mod tests {
             codecs: vec![],
             storage_transformers: None,
             dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
         };
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -3034,7 +9236,7 @@ mod tests {
 
         let path: Path = "/foo/bar/some-array".try_into().unwrap();
         // write chunks with repo 1
-        for coord in [0u32, 1, 2] {
+        for coord in [0u64, 1, 2] {
             repo1
                 .set_chunk_ref(
                     path.clone(),
@@ -3052,7 +9254,7 @@ mod tests {
         }
 
         // write the same chunks with repo 2
-        for coord in [0u32, 1, 2] {
+        for coord in [0u64, 1, 2] {
             repo2
                 .set_chunk_ref(
                     path.clone(),
@@ -0,0 +1,127 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use futures::{
+    channel::oneshot,
+    future::{BoxFuture, RemoteHandle},
+    FutureExt,
+};
+
+/// Runs the background and blocking tasks of Icechunk
+///
+/// Icechunk futures can be polled by any executor, but some components need to run tasks
+/// in the background, or do blocking IO. They do it through a [`Runtime`], by default,
+/// [`TokioRuntime`]. Users of other executors, like `smol` or `async-std`, can implement
+/// this trait and pass it to those components.
+///
+/// The storage backends based on `object_store` and the AWS SDK still need a tokio runtime:
+/// their clients use it internally.
+pub trait Runtime: Debug + Send + Sync {
+    /// Run `task` in the background, until it completes
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    /// Run `task` in a thread where blocking is allowed
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>);
+
+    /// A future that completes after `duration`
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+pub type DynRuntime = Arc<dyn Runtime>;
+
+/// Runs tasks in the tokio runtime of the calling thread
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        tokio::spawn(task);
+    }
+
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+pub fn default_runtime() -> DynRuntime {
+    Arc::new(TokioRuntime)
+}
+
+/// Run `task` in the background, and return a handle to await its result
+///
+/// Dropping the handle cancels the task.
+pub fn spawn<T: Send + 'static>(
+    runtime: &dyn Runtime,
+    task: impl std::future::Future<Output = T> + Send + 'static,
+) -> RemoteHandle<T> {
+    let (task, handle) = task.remote_handle();
+    runtime.spawn(task.boxed());
+    handle
+}
+
+/// Run the blocking function `f` and await its result
+pub async fn spawn_blocking<T: Send + 'static>(
+    runtime: &dyn Runtime,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, oneshot::Canceled> {
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn_blocking(Box::new(move || {
+        // the receiver may be gone if the caller is no longer interested
+        let _ = tx.send(f());
+    }));
+    rx.await
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+pub(crate) mod tests {
+    use std::thread;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// A runtime that runs every task in a new thread, without tokio
+    #[derive(Debug)]
+    pub(crate) struct ThreadRuntime;
+
+    impl Runtime for ThreadRuntime {
+        fn spawn(&self, task: BoxFuture<'static, ()>) {
+            thread::spawn(move || futures::executor::block_on(task));
+        }
+
+        fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+            thread::spawn(task);
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || {
+                thread::sleep(duration);
+                let _ = tx.send(());
+            });
+            rx.map(|_| ()).boxed()
+        }
+    }
+
+    #[test]
+    fn test_tasks_run_without_tokio() {
+        futures::executor::block_on(async {
+            let handle = spawn(&ThreadRuntime, async { 21 * 2 });
+            assert_eq!(handle.await, 42);
+            let res = spawn_blocking(&ThreadRuntime, || "blocking").await;
+            assert_eq!(res, Ok("blocking"));
+            ThreadRuntime.sleep(Duration::from_millis(1)).await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_tokio_runtime() {
+        let runtime = default_runtime();
+        assert_eq!(spawn(runtime.as_ref(), async { 1 }).await, 1);
+        assert_eq!(spawn_blocking(runtime.as_ref(), || 2).await, Ok(2));
+    }
+}
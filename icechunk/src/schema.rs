@@ -0,0 +1,194 @@
+//! Constraints on how the hierarchy of a repository can change, checked on commit
+use std::collections::HashMap;
+
+use crate::{
+    conventions::ConventionViolation,
+    format::{
+        snapshot::{NodeData, NodeSnapshot},
+        Path,
+    },
+    metadata::{ArrayShape, DataType},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaRule {
+    /// The data type of an existing array cannot change
+    ImmutableDataTypes,
+    /// The dimensions of an existing array cannot shrink, or change in number
+    GrowOnlyDimensions,
+    /// Existing arrays cannot be deleted
+    NoArrayDeletions,
+    /// Array attributes follow the xarray conventions, see
+    /// [`crate::conventions::check_conventions`]. The attributes of every array are
+    /// checked, loading the ones stored outside the snapshot.
+    XarrayConventions,
+}
+
+/// A [`SchemaRule`] enforced on commits to one branch, or to all of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaConstraint {
+    pub rule: SchemaRule,
+    pub branch: Option<String>,
+}
+
+impl SchemaConstraint {
+    pub fn all_branches(rule: SchemaRule) -> Self {
+        Self { rule, branch: None }
+    }
+
+    pub fn on_branch(rule: SchemaRule, branch: impl Into<String>) -> Self {
+        Self { rule, branch: Some(branch.into()) }
+    }
+
+    pub fn applies_to(&self, branch: &str) -> bool {
+        self.branch.as_deref().is_none_or(|b| b == branch)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaViolation {
+    DataTypeChanged { path: Path, from: DataType, to: DataType },
+    DimensionsShrunk { path: Path, from: ArrayShape, to: ArrayShape },
+    ArrayDeleted(Path),
+    Conventions(ConventionViolation),
+}
+
+/// Find the changes from the `old` to the `new` hierarchy that break the `rules`
+pub fn check_schema<'a>(
+    rules: &[SchemaRule],
+    old: impl Iterator<Item = &'a NodeSnapshot>,
+    new: impl Iterator<Item = NodeSnapshot>,
+) -> Vec<SchemaViolation> {
+    let mut new: HashMap<Path, NodeSnapshot> =
+        new.map(|node| (node.path.clone(), node)).collect();
+    let mut res = Vec::new();
+    for old_node in old {
+        let NodeData::Array(old_meta, _) = &old_node.node_data else {
+            continue;
+        };
+        let path = &old_node.path;
+        match new.remove(path).map(|node| node.node_data) {
+            Some(NodeData::Array(new_meta, _)) => {
+                if rules.contains(&SchemaRule::ImmutableDataTypes)
+                    && new_meta.data_type != old_meta.data_type
+                {
+                    res.push(SchemaViolation::DataTypeChanged {
+                        path: path.clone(),
+                        from: old_meta.data_type.clone(),
+                        to: new_meta.data_type,
+                    });
+                }
+                let shrunk = new_meta.shape.len() != old_meta.shape.len()
+                    || new_meta
+                        .shape
+                        .iter()
+                        .zip(old_meta.shape.iter())
+                        .any(|(n, o)| n < o);
+                if rules.contains(&SchemaRule::GrowOnlyDimensions) && shrunk {
+                    res.push(SchemaViolation::DimensionsShrunk {
+                        path: path.clone(),
+                        from: old_meta.shape.clone(),
+                        to: new_meta.shape,
+                    });
+                }
+            }
+            _ => {
+                if rules.contains(&SchemaRule::NoArrayDeletions) {
+                    res.push(SchemaViolation::ArrayDeleted(path.clone()));
+                }
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, FillValue},
+        repository::{RepositoryError, ZarrArrayMetadata},
+        ObjectStorage, Repository, Storage,
+    };
+
+    fn array_metadata(shape: ArrayShape, data_type: DataType) -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap(); shape.len()]),
+            shape,
+            fill_value: if data_type == DataType::Int32 {
+                FillValue::Int32(0)
+            } else {
+                FillValue::Float64(0.0)
+            },
+            data_type,
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schema_constraints() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_schema_constraint(SchemaConstraint::all_branches(
+                SchemaRule::ImmutableDataTypes,
+            ))
+            .with_schema_constraint(SchemaConstraint::all_branches(
+                SchemaRule::GrowOnlyDimensions,
+            ))
+            .with_schema_constraint(SchemaConstraint::on_branch(
+                SchemaRule::NoArrayDeletions,
+                "main",
+            ))
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), array_metadata(vec![10, 10], DataType::Int32)).await?;
+        ds.commit("main", "first", None).await?;
+
+        // growing is allowed
+        ds.update_array(path.clone(), array_metadata(vec![20, 10], DataType::Int32))
+            .await?;
+        ds.commit("main", "grow", None).await?;
+
+        ds.update_array(path.clone(), array_metadata(vec![20, 5], DataType::Float64))
+            .await?;
+        let res = ds.commit("main", "shrink", None).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::SchemaViolations(violations)) if violations == vec![
+                SchemaViolation::DataTypeChanged {
+                    path: path.clone(),
+                    from: DataType::Int32,
+                    to: DataType::Float64,
+                },
+                SchemaViolation::DimensionsShrunk {
+                    path: path.clone(),
+                    from: vec![20, 10],
+                    to: vec![20, 5],
+                },
+            ]
+        ));
+
+        ds.delete_array(path.clone()).await?;
+        let res = ds.commit("main", "delete", None).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::SchemaViolations(violations))
+                if violations == vec![SchemaViolation::ArrayDeleted(path.clone())]
+        ));
+        // deletions are only forbidden on main
+        ds.new_branch("dev").await?;
+        ds.commit("dev", "delete", None).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+//! HTTP transport for [`RepoServer`]
+//!
+//! Requests authenticate with an `Authorization: Bearer <token>` header, bodies are read
+//! after it and only up to [`RepoServer::max_request_size`]. Routes:
+//! - `POST /sessions`, with a json body `{"branch": ...}`
+//! - `GET /sessions/{id}/nodes`
+//! - `GET` and `PUT /sessions/{id}/chunks/{array path}?coords=0,1`, chunk bytes as the body
+//! - `POST /sessions/{id}/commit`, with a json body `{"message": ...}`
+//! - `DELETE /sessions/{id}`
+//!
+//! Array paths are percent-decoded. Failures of the repository are answered with a generic
+//! `500` response, their details are only logged, they can name keys of the bucket.
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use hyper::{
+    body::HttpBody,
+    header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Server, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+
+use super::{RepoServer, Request, Response, ServerError, ServerResult};
+use crate::{
+    format::{ChunkIndices, Path},
+    repository::RepositoryError,
+};
+
+#[derive(Deserialize)]
+struct OpenSessionBody {
+    branch: String,
+}
+
+#[derive(Deserialize)]
+struct CommitBody {
+    message: String,
+}
+
+/// Serve `server` on `addr` until the returned future is dropped
+pub async fn serve(server: Arc<RepoServer>, addr: SocketAddr) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let server = Arc::clone(&server);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let server = Arc::clone(&server);
+                async move { Ok::<_, Infallible>(handle(&server, req).await) }
+            }))
+        }
+    });
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle(server: &RepoServer, req: hyper::Request<Body>) -> hyper::Response<Body> {
+    match route(server, req).await {
+        Ok(Response::Chunk(Some(bytes))) => hyper::Response::new(Body::from(bytes)),
+        Ok(Response::Chunk(None)) => status(StatusCode::NOT_FOUND, "chunk not found"),
+        Ok(res) => match serde_json::to_vec(&res) {
+            Ok(json) => hyper::Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(json))
+                .unwrap_or_default(),
+            Err(err) => internal_error(&err),
+        },
+        Err(ServerError::Repository(err))
+            if !matches!(err, RepositoryError::Unauthorized(_)) =>
+        {
+            internal_error(&err)
+        }
+        Err(err) => {
+            let code = match err {
+                ServerError::Unauthorized => StatusCode::UNAUTHORIZED,
+                ServerError::Forbidden { .. }
+                | ServerError::Repository(RepositoryError::Unauthorized(_)) => {
+                    StatusCode::FORBIDDEN
+                }
+                ServerError::SessionNotFound(_) => StatusCode::NOT_FOUND,
+                ServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+                ServerError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                ServerError::TooManySessions { .. } => StatusCode::TOO_MANY_REQUESTS,
+                ServerError::Repository(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            status(code, &err.to_string())
+        }
+    }
+}
+
+fn internal_error(err: &dyn std::error::Error) -> hyper::Response<Body> {
+    tracing::error!(error = %err, "request failed");
+    status(StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+}
+
+fn status(code: StatusCode, message: &str) -> hyper::Response<Body> {
+    let mut res = hyper::Response::new(Body::from(message.to_string()));
+    *res.status_mut() = code;
+    res
+}
+
+async fn route(server: &RepoServer, req: hyper::Request<Body>) -> ServerResult<Response> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(ServerError::Unauthorized)?
+        .to_string();
+    // before reading the body, unknown clients can't make the server buffer anything
+    server.authenticate(&token)?;
+    let method = req.method().clone();
+    let uri_path = req.uri().path().to_string();
+    let query = req.uri().query().unwrap_or_default().to_string();
+    let body = read_body(req, server.max_request_size()).await?;
+
+    let segments: Vec<&str> = uri_path.trim_matches('/').splitn(4, '/').collect();
+    let request = match (&method, segments.as_slice()) {
+        (&Method::POST, ["sessions"]) => {
+            let OpenSessionBody { branch } = parse_json(&body)?;
+            Request::OpenSession { branch }
+        }
+        (&Method::DELETE, ["sessions", session]) => {
+            Request::CloseSession { session: session.to_string() }
+        }
+        (&Method::GET, ["sessions", session, "nodes"]) => {
+            Request::ListNodes { session: session.to_string() }
+        }
+        (&Method::POST, ["sessions", session, "commit"]) => {
+            let CommitBody { message } = parse_json(&body)?;
+            Request::Commit { session: session.to_string(), message }
+        }
+        (&Method::GET, ["sessions", session, "chunks", path]) => Request::GetChunk {
+            session: session.to_string(),
+            path: parse_path(path)?,
+            coords: parse_coords(&query)?,
+        },
+        (&Method::PUT, ["sessions", session, "chunks", path]) => Request::SetChunk {
+            session: session.to_string(),
+            path: parse_path(path)?,
+            coords: parse_coords(&query)?,
+            data: body,
+        },
+        _ => {
+            return Err(ServerError::BadRequest(format!(
+                "no route for {method} {uri_path}"
+            )))
+        }
+    };
+    server.handle(&token, request).await
+}
+
+/// The body of `req`, failing as soon as it's known to be larger than `limit`
+async fn read_body(req: hyper::Request<Body>, limit: u64) -> ServerResult<Bytes> {
+    let too_large = ServerError::PayloadTooLarge { limit };
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(too_large);
+    }
+    let mut body = req.into_body();
+    let mut res = BytesMut::new();
+    while let Some(data) = body.data().await {
+        let data = data.map_err(|err| ServerError::BadRequest(err.to_string()))?;
+        if (res.len() + data.len()) as u64 > limit {
+            return Err(too_large);
+        }
+        res.extend_from_slice(&data);
+    }
+    Ok(res.freeze())
+}
+
+fn parse_json<'a, T: Deserialize<'a>>(body: &'a [u8]) -> ServerResult<T> {
+    serde_json::from_slice(body).map_err(|err| ServerError::BadRequest(err.to_string()))
+}
+
+fn parse_path(path: &str) -> ServerResult<Path> {
+    let invalid = || ServerError::BadRequest(format!("invalid array path `{path}`"));
+    let decoded = percent_decode_str(path).decode_utf8().map_err(|_| invalid())?;
+    Path::normalized(&decoded).map_err(|_| invalid())
+}
+
+fn parse_coords(query: &str) -> ServerResult<ChunkIndices> {
+    let coords = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("coords="))
+        .ok_or_else(|| ServerError::BadRequest("missing coords".to_string()))?;
+    let coords = percent_decode_str(coords).decode_utf8_lossy();
+    coords
+        .split(',')
+        .map(|c| c.parse())
+        .collect::<Result<_, _>>()
+        .map(ChunkIndices)
+        .map_err(|_| ServerError::BadRequest(format!("invalid coords `{coords}`")))
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use hyper::body::to_bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        server::{AccessPolicy, Permission},
+        ObjectStorage, Repository, Storage,
+    };
+
+    async fn new_server() -> Result<RepoServer, Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            "/array".try_into()?,
+            ZarrArrayMetadata {
+                shape: vec![2],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        ds.commit("main", "create", None).await?;
+        Ok(RepoServer::new(
+            storage,
+            AccessPolicy::default().with_user("token", "writer", Permission::Write),
+        )
+        .with_max_request_size(32))
+    }
+
+    fn request(
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: impl Into<Body>,
+    ) -> hyper::Request<Body> {
+        let mut builder = hyper::Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        builder.body(body.into()).unwrap()
+    }
+
+    async fn body(res: hyper::Response<Body>) -> Result<Bytes, Box<dyn Error>> {
+        Ok(to_bytes(res.into_body()).await?)
+    }
+
+    #[tokio::test]
+    async fn test_http_rejects_before_reading() -> Result<(), Box<dyn Error>> {
+        let server = new_server().await?;
+        let open = r#"{"branch": "main"}"#;
+        let res = handle(&server, request(Method::POST, "/sessions", None, open)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res =
+            handle(&server, request(Method::POST, "/sessions", Some("bad"), open)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // a body that never ends is not read without a valid token
+        let (_sender, endless) = Body::channel();
+        let res =
+            handle(&server, request(Method::POST, "/sessions", Some("bad"), endless))
+                .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let mut declared = request(Method::POST, "/sessions", Some("token"), open);
+        declared.headers_mut().insert(CONTENT_LENGTH, "1000000".parse()?);
+        assert_eq!(
+            handle(&server, declared).await.status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        let res = handle(
+            &server,
+            request(Method::POST, "/sessions", Some("token"), vec![b' '; 33]),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_round_trip() -> Result<(), Box<dyn Error>> {
+        let server = new_server().await?;
+        let res = handle(
+            &server,
+            request(Method::POST, "/sessions", Some("token"), r#"{"branch":"main"}"#),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let Response::SessionOpened { session, .. } =
+            serde_json::from_slice(&body(res).await?)?
+        else {
+            panic!("session not opened");
+        };
+
+        let chunk = format!("/sessions/{session}/chunks/array?coords=1");
+        let res = handle(&server, request(Method::GET, &chunk, Some("token"), "")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res =
+            handle(&server, request(Method::PUT, &chunk, Some("token"), "hello")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = handle(&server, request(Method::GET, &chunk, Some("token"), "")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await?, Bytes::from_static(b"hello"));
+        // paths and coordinates are percent-decoded
+        let encoded = format!("/sessions/{session}/chunks/%61rray?coords=%31");
+        let res =
+            handle(&server, request(Method::GET, &encoded, Some("token"), "")).await;
+        assert_eq!(body(res).await?, Bytes::from_static(b"hello"));
+
+        let commit = format!("/sessions/{session}/commit");
+        let res = handle(
+            &server,
+            request(Method::POST, &commit, Some("token"), r#"{"message":"w"}"#),
+        )
+        .await;
+        assert!(matches!(
+            serde_json::from_slice(&body(res).await?)?,
+            Response::Committed(_)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_hides_repository_errors() -> Result<(), Box<dyn Error>> {
+        let server = new_server().await?;
+        let open = r#"{"branch": "missing"}"#;
+        let res =
+            handle(&server, request(Method::POST, "/sessions", Some("token"), open))
+                .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body(res).await?, Bytes::from_static(b"internal server error"));
+
+        let server = new_server().await?.with_max_sessions_per_user(1);
+        let open = r#"{"branch": "main"}"#;
+        let res =
+            handle(&server, request(Method::POST, "/sessions", Some("token"), open))
+                .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res =
+            handle(&server, request(Method::POST, "/sessions", Some("token"), open))
+                .await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
+}
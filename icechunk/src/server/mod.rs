@@ -0,0 +1,466 @@
+//! A server exposing repository sessions to remote clients
+//!
+//! [`RepoServer`] multiplexes the sessions of many users over one repository. Every request
+//! carries a token, that the [`AccessPolicy`] maps to a user and their [`Permission`]. Users
+//! can only use the sessions they opened, sessions idle for longer than the session timeout
+//! are closed, and each user can only have so many open sessions. The HTTP transport is
+//! behind the `server` feature.
+#[cfg(feature = "server")]
+pub mod http;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    authorization::DynAuthorizer,
+    format::{snapshot::NodeSnapshot, ByteRange, ChunkIndices, Path, SnapshotId},
+    repository::{get_chunk, RepositoryError},
+    Repository, Storage,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Permission {
+    Read,
+    /// Includes [`Permission::Read`]
+    Write,
+}
+
+/// The users allowed to use the server, by access token
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    users: HashMap<String, (String, Permission)>,
+    authorizers: HashMap<String, DynAuthorizer>,
+}
+
+impl AccessPolicy {
+    pub fn with_user(
+        mut self,
+        token: impl Into<String>,
+        user: impl Into<String>,
+        permission: Permission,
+    ) -> Self {
+        self.users.insert(token.into(), (user.into(), permission));
+        self
+    }
+
+    /// Consult `authorizer` in the sessions of `user`, to restrict the paths and refs
+    /// they can use
+    pub fn with_authorizer(
+        mut self,
+        user: impl Into<String>,
+        authorizer: DynAuthorizer,
+    ) -> Self {
+        self.authorizers.insert(user.into(), authorizer);
+        self
+    }
+
+    fn authenticate(&self, token: &str) -> ServerResult<(&str, Permission)> {
+        self.users
+            .get(token)
+            .map(|(user, permission)| (user.as_str(), *permission))
+            .ok_or(ServerError::Unauthorized)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("invalid access token")]
+    Unauthorized,
+    #[error("user `{user}` is not allowed to {action}")]
+    Forbidden { user: String, action: String },
+    #[error("session `{0}` not found")]
+    SessionNotFound(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("request body larger than {limit} bytes")]
+    PayloadTooLarge { limit: u64 },
+    #[error("user `{user}` already has {limit} open sessions")]
+    TooManySessions { user: String, limit: usize },
+    #[error("repository error `{0}`")]
+    Repository(#[from] RepositoryError),
+}
+
+pub type ServerResult<A> = Result<A, ServerError>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    OpenSession { branch: String },
+    ListNodes { session: String },
+    GetChunk { session: String, path: Path, coords: ChunkIndices },
+    SetChunk { session: String, path: Path, coords: ChunkIndices, data: Bytes },
+    Commit { session: String, message: String },
+    CloseSession { session: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    SessionOpened { session: String, snapshot: SnapshotId },
+    Nodes(Vec<NodeSnapshot>),
+    Chunk(Option<Bytes>),
+    Committed(SnapshotId),
+    Done,
+}
+
+#[derive(Debug)]
+struct Session {
+    user: String,
+    branch: String,
+    repository: Arc<tokio::sync::Mutex<Repository>>,
+    last_used: DateTime<Utc>,
+}
+
+/// Sessions not used for this long are closed
+pub const DEFAULT_SESSION_TIMEOUT: TimeDelta = TimeDelta::hours(1);
+
+/// The largest request body accepted, chunks are usually much smaller
+pub const DEFAULT_MAX_REQUEST_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The sessions a user can have open at once, each holds a repository in memory
+pub const DEFAULT_MAX_SESSIONS_PER_USER: usize = 16;
+
+#[derive(Debug)]
+pub struct RepoServer {
+    storage: Arc<dyn Storage + Send + Sync>,
+    policy: AccessPolicy,
+    sessions: Mutex<HashMap<String, Session>>,
+    session_timeout: TimeDelta,
+    max_request_size: u64,
+    max_sessions_per_user: usize,
+}
+
+impl RepoServer {
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>, policy: AccessPolicy) -> Self {
+        Self {
+            storage,
+            policy,
+            sessions: Mutex::new(HashMap::new()),
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            max_sessions_per_user: DEFAULT_MAX_SESSIONS_PER_USER,
+        }
+    }
+
+    pub fn with_session_timeout(mut self, timeout: TimeDelta) -> Self {
+        self.session_timeout = timeout;
+        self
+    }
+
+    pub fn with_max_request_size(mut self, bytes: u64) -> Self {
+        self.max_request_size = bytes;
+        self
+    }
+
+    pub fn with_max_sessions_per_user(mut self, sessions: usize) -> Self {
+        self.max_sessions_per_user = sessions;
+        self
+    }
+
+    pub fn max_request_size(&self) -> u64 {
+        self.max_request_size
+    }
+
+    /// Fails if `token` is not one of the users of the policy
+    pub fn authenticate(&self, token: &str) -> ServerResult<()> {
+        self.policy.authenticate(token).map(|_| ())
+    }
+
+    /// Close the sessions not used since `session_timeout` before `now`, returns how many
+    ///
+    /// [`RepoServer::handle`] calls it on every request, the uncommitted changes of the
+    /// closed sessions are lost.
+    pub fn evict_idle_sessions(&self, now: DateTime<Utc>) -> usize {
+        let mut sessions = self.lock_sessions();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.last_used + self.session_timeout > now);
+        before - sessions.len()
+    }
+
+    pub async fn handle(&self, token: &str, request: Request) -> ServerResult<Response> {
+        let (user, permission) = self.policy.authenticate(token)?;
+        let now = Utc::now();
+        self.evict_idle_sessions(now);
+        let required = match request {
+            Request::SetChunk { .. } | Request::Commit { .. } => Permission::Write,
+            _ => Permission::Read,
+        };
+        if permission < required {
+            return Err(ServerError::Forbidden {
+                user: user.to_string(),
+                action: "write".to_string(),
+            });
+        }
+
+        match request {
+            Request::OpenSession { branch } => {
+                let mut builder =
+                    Repository::from_branch_tip(Arc::clone(&self.storage), &branch)
+                        .await?;
+                if let Some(authorizer) = self.policy.authorizers.get(user) {
+                    builder.with_authorizer(Arc::clone(authorizer));
+                }
+                let repository = builder.build();
+                let snapshot = repository.snapshot_id().clone();
+                let session = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+                let mut sessions = self.lock_sessions();
+                let open = sessions.values().filter(|s| s.user == user).count();
+                if open >= self.max_sessions_per_user {
+                    return Err(ServerError::TooManySessions {
+                        user: user.to_string(),
+                        limit: self.max_sessions_per_user,
+                    });
+                }
+                sessions.insert(
+                    session.clone(),
+                    Session {
+                        user: user.to_string(),
+                        branch,
+                        repository: Arc::new(tokio::sync::Mutex::new(repository)),
+                        last_used: now,
+                    },
+                );
+                Ok(Response::SessionOpened { session, snapshot })
+            }
+            Request::ListNodes { session } => {
+                let (repository, _) = self.session(user, &session, now)?;
+                let repository = repository.lock().await;
+                let nodes = repository.list_nodes().await?.collect();
+                Ok(Response::Nodes(nodes))
+            }
+            Request::GetChunk { session, path, coords } => {
+                let (repository, _) = self.session(user, &session, now)?;
+                let reader = repository
+                    .lock()
+                    .await
+                    .get_chunk_reader(&path, &coords, &ByteRange::ALL)
+                    .await?;
+                Ok(Response::Chunk(get_chunk(reader).await?))
+            }
+            Request::SetChunk { session, path, coords, data } => {
+                let (repository, _) = self.session(user, &session, now)?;
+                let mut repository = repository.lock().await;
+                let payload =
+                    repository.get_array_chunk_writer(&path).await?(data).await?;
+                repository.set_chunk_ref(path, coords, Some(payload)).await?;
+                Ok(Response::Done)
+            }
+            Request::Commit { session, message } => {
+                let (repository, branch) = self.session(user, &session, now)?;
+                let snapshot =
+                    repository.lock().await.commit(&branch, &message, None).await?;
+                Ok(Response::Committed(snapshot))
+            }
+            Request::CloseSession { session } => {
+                self.session(user, &session, now)?;
+                self.lock_sessions().remove(&session);
+                Ok(Response::Done)
+            }
+        }
+    }
+
+    fn lock_sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The repository and branch of a session opened by `user`, used at `now`
+    fn session(
+        &self,
+        user: &str,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> ServerResult<(Arc<tokio::sync::Mutex<Repository>>, String)> {
+        let mut sessions = self.lock_sessions();
+        match sessions.get_mut(id) {
+            // other users' sessions look the same as missing ones
+            Some(session) if session.user == user => {
+                session.last_used = now;
+                Ok((Arc::clone(&session.repository), session.branch.clone()))
+            }
+            _ => Err(ServerError::SessionNotFound(id.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::ZarrArrayMetadata,
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_repo_server() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let path: Path = "/array".try_into()?;
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        ds.commit("main", "create", None).await?;
+
+        let server = RepoServer::new(
+            Arc::clone(&storage),
+            AccessPolicy::default()
+                .with_user("writer-token", "writer", Permission::Write)
+                .with_user("reader-token", "reader", Permission::Read),
+        );
+        let open = |token| {
+            server.handle(token, Request::OpenSession { branch: "main".to_string() })
+        };
+        let Response::SessionOpened { session: writer, .. } =
+            open("writer-token").await?
+        else {
+            panic!("session not opened");
+        };
+        let Response::SessionOpened { session: reader, .. } =
+            open("reader-token").await?
+        else {
+            panic!("session not opened");
+        };
+        assert!(matches!(open("bad-token").await, Err(ServerError::Unauthorized)));
+
+        let set = |session: &str, token| {
+            server.handle(
+                token,
+                Request::SetChunk {
+                    session: session.to_string(),
+                    path: path.clone(),
+                    coords: ChunkIndices(vec![0]),
+                    data: Bytes::from_static(b"hello"),
+                },
+            )
+        };
+        assert!(matches!(
+            set(&reader, "reader-token").await,
+            Err(ServerError::Forbidden { user, .. }) if user == "reader"
+        ));
+        assert!(matches!(
+            set(&reader, "writer-token").await,
+            Err(ServerError::SessionNotFound(_))
+        ));
+        set(&writer, "writer-token").await?;
+        let snapshot = match server
+            .handle(
+                "writer-token",
+                Request::Commit { session: writer.clone(), message: "write".to_string() },
+            )
+            .await?
+        {
+            Response::Committed(snapshot) => snapshot,
+            res => panic!("unexpected response {res:?}"),
+        };
+
+        // sessions are isolated, the reader sees the new chunk after reopening
+        let get = |session: &str| {
+            server.handle(
+                "reader-token",
+                Request::GetChunk {
+                    session: session.to_string(),
+                    path: path.clone(),
+                    coords: ChunkIndices(vec![0]),
+                },
+            )
+        };
+        assert_eq!(get(&reader).await?, Response::Chunk(None));
+        server
+            .handle("reader-token", Request::CloseSession { session: reader.clone() })
+            .await?;
+        assert!(matches!(get(&reader).await, Err(ServerError::SessionNotFound(_))));
+        let Response::SessionOpened { session: reader, snapshot: opened } =
+            open("reader-token").await?
+        else {
+            panic!("session not opened");
+        };
+        assert_eq!(opened, snapshot);
+        assert_eq!(
+            get(&reader).await?,
+            Response::Chunk(Some(Bytes::from_static(b"hello")))
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_idle_sessions_expire() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        Repository::init(Arc::clone(&storage), false).await?;
+        let timeout = TimeDelta::minutes(5);
+        let server = RepoServer::new(
+            storage,
+            AccessPolicy::default().with_user("token", "user", Permission::Read),
+        )
+        .with_session_timeout(timeout);
+        let Response::SessionOpened { session, .. } = server
+            .handle("token", Request::OpenSession { branch: "main".to_string() })
+            .await?
+        else {
+            panic!("session not opened");
+        };
+        let now = Utc::now();
+        assert_eq!(server.evict_idle_sessions(now), 0);
+        assert_eq!(server.evict_idle_sessions(now + timeout + TimeDelta::seconds(1)), 1);
+        assert!(matches!(
+            server.handle("token", Request::ListNodes { session }).await,
+            Err(ServerError::SessionNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sessions_per_user_are_capped() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        Repository::init(Arc::clone(&storage), false).await?;
+        let server = RepoServer::new(
+            storage,
+            AccessPolicy::default()
+                .with_user("token", "user", Permission::Read)
+                .with_user("other-token", "other", Permission::Read),
+        )
+        .with_max_sessions_per_user(2);
+        let open = |token| {
+            server.handle(token, Request::OpenSession { branch: "main".to_string() })
+        };
+        let Response::SessionOpened { session, .. } = open("token").await? else {
+            panic!("session not opened");
+        };
+        open("token").await?;
+        assert!(matches!(
+            open("token").await,
+            Err(ServerError::TooManySessions { user, limit: 2 }) if user == "user"
+        ));
+        // the cap is per user, and closing a session makes room
+        open("other-token").await?;
+        server.handle("token", Request::CloseSession { session }).await?;
+        open("token").await?;
+        Ok(())
+    }
+}
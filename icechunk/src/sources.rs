@@ -0,0 +1,147 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::{rngs::StdRng, thread_rng, RngCore, SeedableRng};
+
+use crate::format::{FileTypeTag, ObjectId};
+
+/// The source of the timestamps written to snapshots
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The source of the random bytes used to create new ids
+pub trait IdSource: Debug + Send + Sync {
+    fn fill(&self, buf: &mut [u8]);
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that starts at a fixed time, and moves `step` forward every time it's read
+#[derive(Debug)]
+pub struct VirtualClock {
+    now: Mutex<DateTime<Utc>>,
+    step: TimeDelta,
+}
+
+impl VirtualClock {
+    pub fn new(start: DateTime<Utc>, step: TimeDelta) -> Self {
+        Self { now: Mutex::new(start), step }
+    }
+
+    pub fn advance(&self, delta: TimeDelta) {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner) += delta;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> DateTime<Utc> {
+        let mut now = self.now.lock().unwrap_or_else(PoisonError::into_inner);
+        let res = *now;
+        *now += self.step;
+        res
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomIds;
+
+impl IdSource for RandomIds {
+    fn fill(&self, buf: &mut [u8]) {
+        thread_rng().fill_bytes(buf)
+    }
+}
+
+/// Generates the same sequence of ids for the same seed
+#[derive(Debug)]
+pub struct SeededIds {
+    rng: Mutex<StdRng>,
+}
+
+impl SeededIds {
+    pub fn new(seed: u64) -> Self {
+        Self { rng: Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+}
+
+impl IdSource for SeededIds {
+    fn fill(&self, buf: &mut [u8]) {
+        self.rng.lock().unwrap_or_else(PoisonError::into_inner).fill_bytes(buf)
+    }
+}
+
+/// The clock and id source used by a repository
+///
+/// By default, the system clock and random ids. Tests can use [`Sources::deterministic`]
+/// to get reproducible snapshots, as long as operations run in the same order.
+#[derive(Debug, Clone)]
+pub struct Sources {
+    pub clock: Arc<dyn Clock>,
+    pub ids: Arc<dyn IdSource>,
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Self { clock: Arc::new(SystemClock), ids: Arc::new(RandomIds) }
+    }
+}
+
+impl Sources {
+    /// A [`VirtualClock`] starting at `start`, moving one second per read, and
+    /// [`SeededIds`]
+    pub fn deterministic(seed: u64, start: DateTime<Utc>) -> Self {
+        Self {
+            clock: Arc::new(VirtualClock::new(start, TimeDelta::seconds(1))),
+            ids: Arc::new(SeededIds::new(seed)),
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    pub fn new_id<const SIZE: usize, T: FileTypeTag>(&self) -> ObjectId<SIZE, T> {
+        let mut buf = [0; SIZE];
+        self.ids.fill(&mut buf);
+        ObjectId::new(buf)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::format::SnapshotId;
+
+    #[test]
+    fn test_deterministic_sources() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let sources = Sources::deterministic(42, start);
+        let other = Sources::deterministic(42, start);
+        let ids: Vec<SnapshotId> = (0..3).map(|_| sources.new_id()).collect();
+        let other_ids: Vec<SnapshotId> = (0..3).map(|_| other.new_id()).collect();
+        assert_eq!(ids, other_ids);
+        assert_ne!(ids[0], ids[1]);
+        let different: SnapshotId = Sources::deterministic(43, start).new_id();
+        assert_ne!(different, ids[0]);
+
+        assert_eq!(sources.now(), start);
+        assert_eq!(sources.now(), start + TimeDelta::seconds(1));
+        let clock = VirtualClock::new(start, TimeDelta::zero());
+        clock.advance(TimeDelta::hours(1));
+        assert_eq!(clock.now(), start + TimeDelta::hours(1));
+        assert_eq!(clock.now(), start + TimeDelta::hours(1));
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    fmt::Debug,
+    mem,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+    sources::{Clock, SystemClock},
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageResult,
+};
+
+/// One read of an object, reported to an [`AccessLogger`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRecord {
+    /// Who read the object, as set with [`AuditedStorage::new`]
+    pub principal: String,
+    pub at: DateTime<Utc>,
+    pub kind: ObjectKind,
+    /// The object id, or the ref key for refs
+    pub id: String,
+}
+
+/// Receives the reads of an [`AuditedStorage`], in batches
+///
+/// Called from the task that filled the batch, so it should hand the records over, to a
+/// channel or a buffered writer, instead of doing slow work in place.
+pub trait AccessLogger: Debug + Send + Sync {
+    fn log(&self, records: Vec<AccessRecord>);
+}
+
+/// A [`Storage`] that reports which objects were read, by whom and when
+///
+/// Reads of snapshots, manifests, chunks, transaction logs and refs are recorded, whether
+/// they succeed or not, and presigned URLs are recorded as reads of the chunk. Records are
+/// buffered and sent to the logger `batch_size` at a time; the rest is sent by
+/// [`AuditedStorage::flush`], or when the storage is dropped. Writes, listings and deletes
+/// are not recorded.
+#[derive(Debug)]
+pub struct AuditedStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    logger: Arc<dyn AccessLogger>,
+    principal: String,
+    clock: Arc<dyn Clock>,
+    batch_size: usize,
+    batch: Mutex<Vec<AccessRecord>>,
+}
+
+impl AuditedStorage {
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        logger: Arc<dyn AccessLogger>,
+        principal: impl Into<String>,
+    ) -> Self {
+        Self {
+            backend,
+            logger,
+            principal: principal.into(),
+            clock: Arc::new(SystemClock),
+            batch_size: 100,
+            batch: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Use `clock` for the time of the records, instead of the system time
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Send the buffered records to the logger
+    pub fn flush(&self) {
+        let records = mem::take(&mut *self.lock_batch());
+        if !records.is_empty() {
+            self.logger.log(records);
+        }
+    }
+
+    fn lock_batch(&self) -> std::sync::MutexGuard<'_, Vec<AccessRecord>> {
+        self.batch.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record(&self, kind: ObjectKind, id: String) {
+        let record = AccessRecord {
+            principal: self.principal.clone(),
+            at: self.clock.now(),
+            kind,
+            id,
+        };
+        let full = {
+            let mut batch = self.lock_batch();
+            batch.push(record);
+            (batch.len() >= self.batch_size).then(|| mem::take(&mut *batch))
+        };
+        // the logger is called without holding the lock, so reads don't wait for it
+        if let Some(records) = full {
+            self.logger.log(records);
+        }
+    }
+}
+
+impl Drop for AuditedStorage {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl private::Sealed for AuditedStorage {}
+
+#[async_trait]
+impl Storage for AuditedStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.record(ObjectKind::Snapshot, id.to_string());
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.record(ObjectKind::Manifest, id.to_string());
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.record(ObjectKind::Manifest, id.to_string());
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.record(ObjectKind::Snapshot, id.to_string());
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.record(ObjectKind::Manifest, id.to_string());
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.record(ObjectKind::Chunk, id.to_string());
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.record(ObjectKind::Chunk, id.to_string());
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.record(ObjectKind::Chunk, id.to_string());
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.record(ObjectKind::TransactionLog, id.to_string());
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.record(ObjectKind::Ref, ref_key.to_string());
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.record(ObjectKind::Snapshot, id.to_string());
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use chrono::TimeDelta;
+    use itertools::Itertools;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, sources::VirtualClock, ObjectStorage, Repository};
+
+    #[derive(Debug, Default)]
+    struct Batches(Mutex<Vec<Vec<AccessRecord>>>);
+
+    impl AccessLogger for Batches {
+        fn log(&self, records: Vec<AccessRecord>) {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner).push(records);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_records_are_batched() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&backend), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let snapshot = ds.commit("main", "commit", None).await?;
+
+        let batches = Arc::new(Batches::default());
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")?.to_utc();
+        let storage = AuditedStorage::new(backend, batches.clone(), "alice")
+            .with_batch_size(2)
+            .with_clock(Arc::new(VirtualClock::new(start, TimeDelta::seconds(1))));
+        storage.fetch_snapshot(&snapshot).await?;
+        assert!(storage.fetch_manifests(&ManifestId::random()).await.is_err());
+        storage.fetch_transaction_log(&snapshot).await?;
+        assert_eq!(batches.0.lock().unwrap().len(), 1);
+        drop(storage);
+
+        let records = batches.0.lock().unwrap().iter().flatten().cloned().collect_vec();
+        let snapshot_id = snapshot.to_string();
+        assert_eq!(
+            records.iter().map(|r| (r.kind, r.id == snapshot_id)).collect_vec(),
+            vec![
+                (ObjectKind::Snapshot, true),
+                (ObjectKind::Manifest, false),
+                (ObjectKind::TransactionLog, true),
+            ]
+        );
+        assert!(records.iter().all(|r| r.principal == "alice"));
+        assert_eq!(records[2].at, start + TimeDelta::seconds(2));
+        // the last, incomplete, batch was sent on drop
+        assert_eq!(batches.0.lock().unwrap().len(), 2);
+        Ok(())
+    }
+}
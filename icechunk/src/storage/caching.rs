@@ -1,9 +1,16 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::stream::BoxStream;
 use quick_cache::sync::Cache;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     format::{
@@ -11,19 +18,137 @@ use crate::{
         transaction_log::TransactionLog, AttributesId, ByteRange, ChunkId, ManifestId,
         SnapshotId,
     },
+    memory::{BudgetedCache, MemoryBudget},
     private,
+    refs::BranchTipCache,
+    tunables::TunablesHandle,
 };
 
-use super::{ListInfo, Storage, StorageError, StorageResult};
+use super::{
+    Conditional, ListInfo, ObjectKind, PresignedUrl, Storage, StorageCapabilities,
+    StorageError, StorageResult,
+};
+
+/// Lookups served from a cache, and from the backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// The fraction of lookups served from the cache, `None` before any lookup
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CachingStats {
+    pub snapshots: CacheStats,
+    pub manifests: CacheStats,
+    pub chunks: CacheStats,
+}
+
+impl CachingStats {
+    /// The lookups made after `earlier` was taken
+    pub fn since(&self, earlier: &CachingStats) -> CachingStats {
+        let since = |now: CacheStats, then: CacheStats| CacheStats {
+            hits: now.hits.saturating_sub(then.hits),
+            misses: now.misses.saturating_sub(then.misses),
+        };
+        CachingStats {
+            snapshots: since(self.snapshots, earlier.snapshots),
+            manifests: since(self.manifests, earlier.manifests),
+            chunks: since(self.chunks, earlier.chunks),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    lookups: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Counters {
+    fn lookup(&self) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> CacheStats {
+        let misses = self.misses.load(Ordering::Relaxed);
+        let hits = self.lookups.load(Ordering::Relaxed).saturating_sub(misses);
+        CacheStats { hits, misses }
+    }
+}
+
+/// The number of objects of each kind kept by a [`MemCachingStorage`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub num_snapshots: u16,
+    pub num_manifests: u16,
+    pub num_transactions: u16,
+    pub num_attributes: u16,
+    pub num_chunks: u16,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            num_snapshots: 2,
+            num_manifests: 2,
+            num_transactions: 0,
+            num_attributes: 2,
+            num_chunks: 0,
+        }
+    }
+}
+
+/// The caches limited by number of objects
+#[derive(Debug)]
+struct CountCaches {
+    config: CacheConfig,
+    snapshots: Cache<SnapshotId, Arc<Snapshot>>,
+    manifests: Cache<ManifestId, Arc<Manifest>>,
+    transactions: Cache<SnapshotId, Arc<TransactionLog>>,
+    attributes: Cache<AttributesId, Arc<AttributesTable>>,
+    chunks: Cache<(ChunkId, ByteRange), Bytes>,
+}
+
+impl CountCaches {
+    fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            snapshots: Cache::new(config.num_snapshots as usize),
+            manifests: Cache::new(config.num_manifests as usize),
+            transactions: Cache::new(config.num_transactions as usize),
+            attributes: Cache::new(config.num_attributes as usize),
+            chunks: Cache::new(config.num_chunks as usize),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct MemCachingStorage {
     backend: Arc<dyn Storage + Send + Sync>,
-    snapshot_cache: Cache<SnapshotId, Arc<Snapshot>>,
-    manifest_cache: Cache<ManifestId, Arc<Manifest>>,
-    transactions_cache: Cache<SnapshotId, Arc<TransactionLog>>,
-    attributes_cache: Cache<AttributesId, Arc<AttributesTable>>,
-    chunk_cache: Cache<(ChunkId, ByteRange), Bytes>,
+    caches: RwLock<Arc<CountCaches>>,
+    /// Resizes `caches`, see [`MemCachingStorage::with_tunables`]
+    tunables: Option<TunablesHandle>,
+    applied_version: AtomicU64,
+    /// Replace the count limited caches when a [`MemoryBudget`] is used
+    budgeted_manifests: Option<Arc<BudgetedCache<ManifestId, Arc<Manifest>>>>,
+    budgeted_chunks: Option<Arc<BudgetedCache<(ChunkId, ByteRange), Bytes>>>,
+    snapshot_counters: Counters,
+    manifest_counters: Counters,
+    chunk_counters: Counters,
+    branch_tips: BranchTipCache,
 }
 
 impl MemCachingStorage {
@@ -35,13 +160,126 @@ impl MemCachingStorage {
         num_attributes: u16,
         num_chunks: u16,
     ) -> Self {
+        let config = CacheConfig {
+            num_snapshots,
+            num_manifests,
+            num_transactions,
+            num_attributes,
+            num_chunks,
+        };
         MemCachingStorage {
             backend,
-            snapshot_cache: Cache::new(num_snapshots as usize),
-            manifest_cache: Cache::new(num_manifests as usize),
-            transactions_cache: Cache::new(num_transactions as usize),
-            attributes_cache: Cache::new(num_attributes as usize),
-            chunk_cache: Cache::new(num_chunks as usize),
+            caches: RwLock::new(Arc::new(CountCaches::new(config))),
+            tunables: None,
+            applied_version: AtomicU64::new(0),
+            budgeted_manifests: None,
+            budgeted_chunks: None,
+            snapshot_counters: Counters::default(),
+            manifest_counters: Counters::default(),
+            chunk_counters: Counters::default(),
+            branch_tips: BranchTipCache::default(),
+        }
+    }
+
+    pub fn from_config(
+        backend: Arc<dyn Storage + Send + Sync>,
+        config: &CacheConfig,
+    ) -> Self {
+        Self::new(
+            backend,
+            config.num_snapshots,
+            config.num_manifests,
+            config.num_transactions,
+            config.num_attributes,
+            config.num_chunks,
+        )
+    }
+
+    /// Cache manifests and chunks with the memory in `budget`, instead of by count
+    ///
+    /// The caches are emptied when other users of the budget need memory.
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.budgeted_manifests =
+            Some(BudgetedCache::new(budget.pool("manifest_cache", None), 64));
+        self.budgeted_chunks =
+            Some(BudgetedCache::new(budget.pool("chunk_cache", None), 1024));
+        self
+    }
+
+    /// Follow the capacities in [`crate::tunables::Tunables::cache`], from now on
+    ///
+    /// A cache is emptied when its capacity changes, the objects in use stay valid. Caches
+    /// limited by a [`MemoryBudget`] don't change.
+    pub fn with_tunables(mut self, tunables: TunablesHandle) -> Self {
+        self.applied_version = AtomicU64::new(tunables.version().wrapping_sub(1));
+        self.tunables = Some(tunables);
+        self
+    }
+
+    /// The capacities of the caches, after applying the latest tunables
+    pub fn cache_config(&self) -> CacheConfig {
+        self.caches().config
+    }
+
+    fn caches(&self) -> Arc<CountCaches> {
+        if let Some(tunables) = &self.tunables {
+            let version = tunables.version();
+            if self.applied_version.swap(version, Ordering::AcqRel) != version {
+                let config = tunables.get().cache;
+                let mut caches =
+                    self.caches.write().unwrap_or_else(PoisonError::into_inner);
+                if caches.config != config {
+                    *caches = Arc::new(CountCaches::new(config));
+                }
+            }
+        }
+        Arc::clone(&self.caches.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// The hits and misses of the caches since the storage was created
+    pub fn stats(&self) -> CachingStats {
+        CachingStats {
+            snapshots: self.snapshot_counters.stats(),
+            manifests: self.manifest_counters.stats(),
+            chunks: self.chunk_counters.stats(),
+        }
+    }
+
+    fn cache_manifest(&self, id: ManifestId, manifest: Arc<Manifest>) {
+        match &self.budgeted_manifests {
+            Some(cache) => cache.insert(id, manifest),
+            None => self.caches().manifests.insert(id, manifest),
+        }
+    }
+
+    /// The chunk from the cache, or from the backend, reading from `shard` if it's known
+    async fn fetch_cached_chunk(
+        &self,
+        shard: Option<&str>,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let key = (id.clone(), range.clone());
+        self.chunk_counters.lookup();
+        let fetch = async {
+            self.chunk_counters.miss();
+            match shard {
+                Some(shard) => self.backend.fetch_chunk_from(shard, id, range).await,
+                None => self.backend.fetch_chunk(id, range).await,
+            }
+        };
+        if let Some(cache) = &self.budgeted_chunks {
+            return cache.get_or_insert_async(&key, fetch).await;
+        }
+        let caches = self.caches();
+        let cached = caches.chunks.get_value_or_guard_async(&key).await;
+        match cached {
+            Ok(bytes) => Ok(bytes),
+            Err(guard) => {
+                let bytes = fetch.await?;
+                let _fail_is_ok = guard.insert(bytes.clone());
+                Ok(bytes)
+            }
         }
     }
 }
@@ -54,9 +292,13 @@ impl Storage for MemCachingStorage {
         &self,
         id: &SnapshotId,
     ) -> Result<Arc<Snapshot>, StorageError> {
-        match self.snapshot_cache.get_value_or_guard_async(id).await {
+        self.snapshot_counters.lookup();
+        let caches = self.caches();
+        let cached = caches.snapshots.get_value_or_guard_async(id).await;
+        match cached {
             Ok(snapshot) => Ok(snapshot),
             Err(guard) => {
+                self.snapshot_counters.miss();
                 let snapshot = self.backend.fetch_snapshot(id).await?;
                 let _fail_is_ok = guard.insert(Arc::clone(&snapshot));
                 Ok(snapshot)
@@ -68,7 +310,9 @@ impl Storage for MemCachingStorage {
         &self,
         id: &AttributesId,
     ) -> Result<Arc<AttributesTable>, StorageError> {
-        match self.attributes_cache.get_value_or_guard_async(id).await {
+        let caches = self.caches();
+        let cached = caches.attributes.get_value_or_guard_async(id).await;
+        match cached {
             Ok(table) => Ok(table),
             Err(guard) => {
                 let table = self.backend.fetch_attributes(id).await?;
@@ -82,21 +326,50 @@ impl Storage for MemCachingStorage {
         &self,
         id: &ManifestId,
     ) -> Result<Arc<Manifest>, StorageError> {
-        match self.manifest_cache.get_value_or_guard_async(id).await {
+        self.manifest_counters.lookup();
+        let fetch = async {
+            self.manifest_counters.miss();
+            self.backend.fetch_manifests(id).await
+        };
+        if let Some(cache) = &self.budgeted_manifests {
+            return cache.get_or_insert_async(id, fetch).await;
+        }
+        let caches = self.caches();
+        let cached = caches.manifests.get_value_or_guard_async(id).await;
+        match cached {
             Ok(manifest) => Ok(manifest),
             Err(guard) => {
-                let manifest = self.backend.fetch_manifests(id).await?;
+                let manifest = fetch.await?;
                 let _fail_is_ok = guard.insert(Arc::clone(&manifest));
                 Ok(manifest)
             }
         }
     }
 
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        // revalidation is for the backend, the cache only has the latest fetch
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
     async fn fetch_transaction_log(
         &self,
         id: &SnapshotId,
     ) -> StorageResult<Arc<TransactionLog>> {
-        match self.transactions_cache.get_value_or_guard_async(id).await {
+        let caches = self.caches();
+        let cached = caches.transactions.get_value_or_guard_async(id).await;
+        match cached {
             Ok(log) => Ok(log),
             Err(guard) => {
                 let log = self.backend.fetch_transaction_log(id).await?;
@@ -111,15 +384,25 @@ impl Storage for MemCachingStorage {
         id: &ChunkId,
         range: &ByteRange,
     ) -> Result<Bytes, StorageError> {
-        let key = (id.clone(), range.clone());
-        match self.chunk_cache.get_value_or_guard_async(&key).await {
-            Ok(bytes) => Ok(bytes),
-            Err(guard) => {
-                let bytes = self.backend.fetch_chunk(id, range).await?;
-                let _fail_is_ok = guard.insert(bytes.clone());
-                Ok(bytes)
-            }
-        }
+        self.fetch_cached_chunk(None, id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.fetch_cached_chunk(Some(shard), id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
     }
 
     async fn write_snapshot(
@@ -128,7 +411,17 @@ impl Storage for MemCachingStorage {
         snapshot: Arc<Snapshot>,
     ) -> Result<(), StorageError> {
         self.backend.write_snapshot(id.clone(), Arc::clone(&snapshot)).await?;
-        self.snapshot_cache.insert(id, snapshot);
+        self.caches().snapshots.insert(id, snapshot);
+        Ok(())
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id.clone(), Arc::clone(&snapshot)).await?;
+        self.caches().snapshots.insert(id, snapshot);
         Ok(())
     }
 
@@ -138,7 +431,7 @@ impl Storage for MemCachingStorage {
         table: Arc<AttributesTable>,
     ) -> Result<(), StorageError> {
         self.backend.write_attributes(id.clone(), Arc::clone(&table)).await?;
-        self.attributes_cache.insert(id, table);
+        self.caches().attributes.insert(id, table);
         Ok(())
     }
 
@@ -148,7 +441,17 @@ impl Storage for MemCachingStorage {
         manifest: Arc<Manifest>,
     ) -> Result<(), StorageError> {
         self.backend.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
-        self.manifest_cache.insert(id, manifest);
+        self.cache_manifest(id, manifest);
+        Ok(())
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id.clone(), Arc::clone(&manifest)).await?;
+        self.cache_manifest(id, manifest);
         Ok(())
     }
 
@@ -158,7 +461,7 @@ impl Storage for MemCachingStorage {
         log: Arc<TransactionLog>,
     ) -> StorageResult<()> {
         self.backend.write_transaction_log(id.clone(), Arc::clone(&log)).await?;
-        self.transactions_cache.insert(id, log);
+        self.caches().transactions.insert(id, log);
         Ok(())
     }
 
@@ -206,6 +509,51 @@ impl Storage for MemCachingStorage {
     ) -> StorageResult<usize> {
         self.backend.delete_objects(prefix, ids).await
     }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        Some(self.stats())
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        Some(&self.branch_tips)
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
 }
 
 #[cfg(test)]
@@ -282,6 +630,7 @@ mod test {
             logging.fetch_operations(),
             vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
         );
+        assert_eq!(caching.stats().manifests, CacheStats { hits: 4, misses: 1 });
         Ok(())
     }
 
@@ -337,4 +686,30 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_caching_storage_with_memory_budget(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let small = ChunkId::random();
+        let large = ChunkId::random();
+        backend.write_chunk(small.clone(), Bytes::from(vec![0; 10])).await?;
+        backend.write_chunk(large.clone(), Bytes::from(vec![0; 200])).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let budget = MemoryBudget::new(100);
+        let caching =
+            MemCachingStorage::new(logging_c, 0, 0, 0, 0, 0).with_memory_budget(&budget);
+        for _ in 0..3 {
+            caching.fetch_chunk(&small, &ByteRange::ALL).await?;
+            // too large for the budget, never cached
+            caching.fetch_chunk(&large, &ByteRange::ALL).await?;
+        }
+        assert_eq!(logging.fetch_operations().len(), 4);
+        assert_eq!(budget.usage().pools["chunk_cache"], 10);
+        assert_eq!(budget.used_bytes(), 10);
+        Ok(())
+    }
 }
@@ -0,0 +1,479 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    future::{self, BoxFuture, Either},
+    stream::BoxStream,
+    FutureExt,
+};
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+    runtime::{default_runtime, DynRuntime},
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageErrorKind, StorageResult,
+};
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+/// A [`Storage`] that reads objects from a chain of read-only mirrors before the origin
+///
+/// Snapshots, manifests, chunks, attributes and transaction logs are immutable, so any copy
+/// of them is good: reads try the mirrors in the order they were passed, a local cache
+/// directory first, then a regional mirror, for example. When a mirror doesn't have the
+/// object, is unavailable or throttled, or doesn't answer within `mirror_timeout`, the
+/// next one is tried, and finally the origin, whose error is returned if it fails too.
+/// Other errors of a mirror, like denied permissions, are returned: they need fixing, and
+/// hiding them would silently move all the reads to the origin. Unlike
+/// [`super::ReplicatedStorage`], nothing is copied to the mirrors, they are filled by other
+/// means.
+///
+/// Writes, refs, listings and deletes only use the origin.
+#[derive(Debug)]
+pub struct FallbackStorage {
+    mirrors: Vec<DynStorage>,
+    origin: DynStorage,
+    /// Reads served by each mirror, and by the origin last
+    served: Vec<AtomicU64>,
+    mirror_timeout: Duration,
+    runtime: DynRuntime,
+}
+
+impl FallbackStorage {
+    pub fn new(mirrors: Vec<DynStorage>, origin: DynStorage) -> Self {
+        let served = (0..=mirrors.len()).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            mirrors,
+            origin,
+            served,
+            mirror_timeout: Duration::from_secs(10),
+            runtime: default_runtime(),
+        }
+    }
+
+    /// Give up on a mirror that didn't answer a read after `timeout`, 10 seconds by default
+    pub fn with_mirror_timeout(mut self, timeout: Duration) -> Self {
+        self.mirror_timeout = timeout;
+        self
+    }
+
+    /// Use `runtime` to time the mirrors out, instead of tokio
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// The reads served by each mirror, in order, and by the origin last
+    pub fn served(&self) -> Vec<u64> {
+        self.served.iter().map(|count| count.load(Ordering::Relaxed)).collect()
+    }
+
+    async fn read<T>(
+        &self,
+        op: impl Fn(DynStorage) -> BoxFuture<'static, StorageResult<T>>,
+    ) -> StorageResult<T> {
+        for (index, mirror) in self.mirrors.iter().enumerate() {
+            let timeout = self.runtime.sleep(self.mirror_timeout);
+            match future::select(op(Arc::clone(mirror)), timeout).await {
+                Either::Left((Ok(res), _)) => {
+                    self.served[index].fetch_add(1, Ordering::Relaxed);
+                    return Ok(res);
+                }
+                // a missing object, or an unavailable mirror, the next one may have it
+                Either::Left((Err(err), _))
+                    if matches!(
+                        err.kind(),
+                        StorageErrorKind::NotFound
+                            | StorageErrorKind::Throttled
+                            | StorageErrorKind::Transient
+                    ) => {}
+                Either::Left((Err(err), _)) => return Err(err),
+                Either::Right(_) => {}
+            }
+        }
+        let res = op(Arc::clone(&self.origin)).await?;
+        self.served[self.mirrors.len()].fetch_add(1, Ordering::Relaxed);
+        Ok(res)
+    }
+}
+
+impl private::Sealed for FallbackStorage {}
+
+#[async_trait]
+impl Storage for FallbackStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_snapshot(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        let (id, path) = (id.clone(), path.clone());
+        self.read(|s| {
+            let (id, path) = (id.clone(), path.clone());
+            async move { s.fetch_snapshot_node(&id, &path).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let (id, range) = (id.clone(), range.clone());
+        self.read(|s| {
+            let (id, range) = (id.clone(), range.clone());
+            async move { s.fetch_snapshot_segments(&id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_attributes(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_manifests(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let (id, node, extents) = (id.clone(), node.clone(), extents.clone());
+        self.read(|s| {
+            let (id, node, extents) = (id.clone(), node.clone(), extents.clone());
+            async move { s.fetch_manifest_region(&id, &node, &extents).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        let id = id.clone();
+        let etag = etag.map(str::to_string);
+        self.read(|s| {
+            let id = id.clone();
+            let etag = etag.clone();
+            async move { s.fetch_snapshot_if_modified(&id, etag.as_deref()).await }
+                .boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        let id = id.clone();
+        let etag = etag.map(str::to_string);
+        self.read(|s| {
+            let id = id.clone();
+            let etag = etag.clone();
+            async move { s.fetch_manifests_if_modified(&id, etag.as_deref()).await }
+                .boxed()
+        })
+        .await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let (id, range) = (id.clone(), range.clone());
+        self.read(|s| {
+            let (id, range) = (id.clone(), range.clone());
+            async move { s.fetch_chunk(&id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        // storages that don't have the shard ignore it
+        let (shard, id, range) = (shard.to_string(), id.clone(), range.clone());
+        self.read(|s| {
+            let (shard, id, range) = (shard.clone(), id.clone(), range.clone());
+            async move { s.fetch_chunk_from(&shard, &id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        // the mirrors may not be reachable by whoever gets the URL
+        self.origin.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_transaction_log(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.origin.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.origin.write_snapshot_segments(id, bytes).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.origin.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.origin.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.origin.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.origin.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.origin.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.origin.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.origin.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.origin.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.origin.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.origin.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.origin.list_objects(prefix).await
+    }
+
+    /// Objects are only deleted from the origin, mirrors expire them on their own
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.origin.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.origin.copy_object(kind, from_id, to_id).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.origin.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.origin.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.origin.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.origin.branch_tip_cache()
+    }
+
+    // only reads go to the mirrors
+    fn capabilities(&self) -> StorageCapabilities {
+        self.origin.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        storage::{faulty::FaultyStorage, read_after_write::ReadAfterWriteStorage},
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_reads_fall_back_in_order() -> Result<(), Box<dyn Error>> {
+        let local: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mirror_backend: DynStorage =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mirror = Arc::new(FaultyStorage::new(Arc::clone(&mirror_backend)));
+        let origin: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = FallbackStorage::new(
+            vec![Arc::clone(&local), mirror.clone()],
+            Arc::clone(&origin),
+        );
+
+        let (cached, mirrored) = (ChunkId::random(), ChunkId::random());
+        local.write_chunk(cached.clone(), Bytes::from_static(b"local")).await?;
+        mirror_backend
+            .write_chunk(mirrored.clone(), Bytes::from_static(b"mirror"))
+            .await?;
+        origin.write_chunk(mirrored.clone(), Bytes::from_static(b"mirror")).await?;
+        // writes only go to the origin
+        let fresh = ChunkId::random();
+        storage.write_chunk(fresh.clone(), Bytes::from_static(b"origin")).await?;
+        assert!(local.fetch_chunk(&fresh, &ByteRange::ALL).await.is_err());
+
+        assert_eq!(storage.fetch_chunk(&cached, &ByteRange::ALL).await?, "local");
+        assert_eq!(storage.fetch_chunk(&mirrored, &ByteRange::ALL).await?, "mirror");
+        assert_eq!(storage.fetch_chunk(&fresh, &ByteRange::ALL).await?, "origin");
+        assert_eq!(storage.served(), vec![1, 1, 1]);
+
+        // an unavailable mirror is skipped
+        mirror.fail("fetch_chunk", 0, 1);
+        assert_eq!(storage.fetch_chunk(&mirrored, &ByteRange::ALL).await?, "mirror");
+        assert_eq!(storage.served(), vec![1, 1, 2]);
+
+        // the error of the origin is returned
+        let missing = ChunkId::random();
+        let err = storage.fetch_chunk(&missing, &ByteRange::ALL).await.unwrap_err();
+        assert!(err.is_not_found());
+        assert_eq!(storage.served(), vec![1, 1, 2]);
+
+        // a mirror rejecting the request is not skipped
+        mirror.reject_too_large("fetch_chunk", 0, 1);
+        let err = storage.fetch_chunk(&mirrored, &ByteRange::ALL).await.unwrap_err();
+        assert_eq!(err.kind(), StorageErrorKind::TooLarge);
+        assert_eq!(storage.served(), vec![1, 1, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_mirrors_time_out() -> Result<(), Box<dyn Error>> {
+        // a mirror waiting an hour for the missing object to show up
+        let empty: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let slow: DynStorage = Arc::new(
+            ReadAfterWriteStorage::new(empty).with_retries(1, Duration::from_secs(3600)),
+        );
+        let origin: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = FallbackStorage::new(vec![slow], Arc::clone(&origin))
+            .with_mirror_timeout(Duration::from_millis(20));
+
+        let id = ChunkId::random();
+        origin.write_chunk(id.clone(), Bytes::from_static(b"origin")).await?;
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, "origin");
+        assert_eq!(storage.served(), vec![0, 1]);
+        Ok(())
+    }
+}
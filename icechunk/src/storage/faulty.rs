@@ -0,0 +1,390 @@
+//! A storage that fails on demand, for testing how callers handle storage errors
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult,
+};
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+/// The failures of one operation, named after the [`Storage`] method
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Fault {
+    /// Calls that succeed before the failures start
+    skip: usize,
+    /// Calls that fail after the skipped ones
+    times: usize,
+    /// Fail as if the backend rejected the object for its size
+    too_large: bool,
+}
+
+#[derive(Debug)]
+pub struct FaultyStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    faults: Mutex<HashMap<String, Fault>>,
+    injected: AtomicUsize,
+}
+
+impl FaultyStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            backend,
+            faults: Mutex::new(HashMap::new()),
+            injected: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fail the next `times` calls to `operation`, like `"write_ref"` or `"fetch_chunk"`
+    pub fn with_failures(self, operation: &str, times: usize) -> Self {
+        self.fail(operation, 0, times);
+        self
+    }
+
+    /// Let `skip` calls to `operation` succeed, then fail the next `times` ones
+    ///
+    /// Replaces the faults set before for the operation, it can be called while the
+    /// storage is in use.
+    pub fn fail(&self, operation: &str, skip: usize, times: usize) {
+        self.faults
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(operation.to_string(), Fault { skip, times, too_large: false });
+    }
+
+    /// Like [`FaultyStorage::fail`], with errors of kind [`super::StorageErrorKind::TooLarge`]
+    pub fn reject_too_large(&self, operation: &str, skip: usize, times: usize) {
+        self.faults
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(operation.to_string(), Fault { skip, times, too_large: true });
+    }
+
+    /// The number of calls that failed on purpose
+    pub fn injected_failures(&self) -> usize {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    fn check(&self, operation: &str) -> StorageResult<()> {
+        let mut faults = self.faults.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(fault) = faults.get_mut(operation) else { return Ok(()) };
+        if fault.skip > 0 {
+            fault.skip -= 1;
+            return Ok(());
+        }
+        if fault.times == 0 {
+            return Ok(());
+        }
+        fault.times -= 1;
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        // the message of the object_store client error, the kind is parsed from it
+        let status = if fault.too_large { "Client error with status 413: " } else { "" };
+        Err(StorageError::ObjectStore(::object_store::Error::Generic {
+            store: "faulty",
+            source: format!("{status}injected failure of {operation}").into(),
+        }))
+    }
+}
+
+impl private::Sealed for FaultyStorage {}
+
+#[async_trait]
+impl Storage for FaultyStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.check("fetch_snapshot")?;
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.check("fetch_attributes")?;
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.check("fetch_manifests")?;
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.check("fetch_manifest_region")?;
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.check("fetch_snapshot_if_modified")?;
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.check("fetch_manifests_if_modified")?;
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.check("fetch_chunk")?;
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.check("fetch_chunk_from")?;
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.check("presign_read")?;
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.check("fetch_transaction_log")?;
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.check("write_snapshot")?;
+        self.backend.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.check("write_snapshot_if_absent")?;
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.check("write_attributes")?;
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.check("write_manifests")?;
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.check("write_manifests_if_absent")?;
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.check("write_chunk")?;
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.check("write_transaction_log")?;
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.check("get_ref")?;
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.check("ref_names")?;
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.check("ref_versions")?;
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.check("write_ref")?;
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.check("list_objects")?;
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.check("delete_objects")?;
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.check("copy_object")?;
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.check("fetch_snapshot")?;
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.check("fetch_snapshot_segments")?;
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.check("write_snapshot_segments")?;
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        refs::{fetch_branch_tip, RefError},
+        repository::RepositoryError,
+        test_utils,
+    };
+
+    #[tokio::test]
+    async fn test_injected_failures() -> Result<(), Box<dyn Error>> {
+        let storage = Arc::new(FaultyStorage::new(test_utils::new_in_memory_storage()));
+        let mut ds = test_utils::new_repository(storage.clone()).await?;
+        ds.add_group(Path::root()).await?;
+        storage.fail("write_ref", 0, 1);
+        assert!(matches!(
+            ds.commit("main", "root", None).await,
+            Err(RepositoryError::Ref(RefError::Storage(StorageError::ObjectStore(_))))
+        ));
+        assert_eq!(storage.injected_failures(), 1);
+
+        // failures start after the skipped calls, and stop when used up
+        storage.fail("get_ref", 1, 1);
+        assert!(fetch_branch_tip(storage.as_ref(), "main").await.is_ok());
+        assert!(fetch_branch_tip(storage.as_ref(), "main").await.is_err());
+        assert!(fetch_branch_tip(storage.as_ref(), "main").await.is_ok());
+        assert_eq!(storage.injected_failures(), 2);
+        Ok(())
+    }
+}
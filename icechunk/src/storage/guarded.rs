@@ -0,0 +1,420 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    ops::gc::{pointed_snapshots, GCError},
+    private,
+    refs::BranchTipCache,
+    refs::RefError,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult, CHUNK_PREFIX, MANIFEST_PREFIX,
+    SNAPSHOT_PREFIX,
+};
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+/// A [`Storage`] that refuses the writes and deletes that would corrupt a repository
+///
+/// Snapshots, manifests and chunks are immutable: writing one with the id of an existing
+/// object fails with [`StorageError::ObjectOverwrite`], except for chunks written again
+/// with the same bytes. Deleting one that is still reachable from a ref, the trash or a
+/// pin fails with [`StorageError::ReferencedObjectDeletion`], and nothing is deleted.
+///
+/// The checks cost an extra request per chunk write and a walk of the repository per
+/// delete. Chunk writes are checked before the upload, so concurrent writers of the same
+/// chunk id can still race. Attributes, transaction logs and refs are not guarded.
+#[derive(Debug)]
+pub struct GuardedStorage {
+    backend: DynStorage,
+}
+
+impl GuardedStorage {
+    pub fn new(backend: DynStorage) -> Self {
+        Self { backend }
+    }
+
+    async fn exists(&self, kind: ObjectKind, id: &str) -> StorageResult<bool> {
+        let mut objects = self.backend.list_objects_of_kind(kind, id).await?;
+        while let Some(info) = objects.try_next().await? {
+            if info.id == id {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The ids of the objects of `kind` that garbage collection would keep
+    async fn referenced(&self, kind: ObjectKind) -> StorageResult<HashSet<String>> {
+        let storage = self.backend.as_ref();
+        let extra_roots = HashSet::new();
+        let snapshots: HashSet<SnapshotId> =
+            pointed_snapshots(storage, &extra_roots, Utc::now())
+                .await
+                .map_err(gc_storage_error)?
+                .try_collect()
+                .await
+                .map_err(gc_storage_error)?;
+        if kind == ObjectKind::Snapshot {
+            return Ok(snapshots.iter().map(|id| id.to_string()).collect());
+        }
+
+        let mut manifests = HashSet::new();
+        for id in snapshots.iter() {
+            let snapshot = storage.fetch_snapshot(id).await?;
+            manifests.extend(snapshot.manifest_files.iter().map(|mf| mf.id.clone()));
+        }
+        if kind == ObjectKind::Manifest {
+            return Ok(manifests.iter().map(|id| id.to_string()).collect());
+        }
+
+        let mut chunks = HashSet::new();
+        for id in manifests.iter() {
+            let manifest = storage.fetch_manifests(id).await?;
+            chunks.extend(manifest.chunks().values().filter_map(
+                |payload| match payload {
+                    ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.to_string()),
+                    _ => None,
+                },
+            ));
+        }
+        Ok(chunks)
+    }
+}
+
+fn overwrite_error(err: StorageError, kind: ObjectKind, id: String) -> StorageError {
+    match err {
+        StorageError::ObjectAlreadyExists(_) => StorageError::ObjectOverwrite(kind, id),
+        err => err,
+    }
+}
+
+fn gc_storage_error(err: GCError) -> StorageError {
+    match err {
+        GCError::Storage(err) | GCError::Ref(RefError::Storage(err)) => err,
+        err => StorageError::Other(err.to_string()),
+    }
+}
+
+impl private::Sealed for GuardedStorage {}
+
+#[async_trait]
+impl Storage for GuardedStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let key = id.to_string();
+        self.backend
+            .write_snapshot_if_absent(id, table)
+            .await
+            .map_err(|err| overwrite_error(err, ObjectKind::Snapshot, key))
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let key = id.to_string();
+        self.backend
+            .write_manifests_if_absent(id, table)
+            .await
+            .map_err(|err| overwrite_error(err, ObjectKind::Manifest, key))
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        let key = id.to_string();
+        if !self.exists(ObjectKind::Chunk, &key).await? {
+            return self.backend.write_chunk(id, bytes).await;
+        }
+        // a retried upload that already succeeded writes the same bytes again
+        if self.backend.fetch_chunk(&id, &ByteRange::ALL).await? == bytes {
+            Ok(())
+        } else {
+            Err(StorageError::ObjectOverwrite(ObjectKind::Chunk, key))
+        }
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        let kind = match prefix {
+            SNAPSHOT_PREFIX => ObjectKind::Snapshot,
+            MANIFEST_PREFIX => ObjectKind::Manifest,
+            CHUNK_PREFIX => ObjectKind::Chunk,
+            _ => return self.backend.delete_objects(prefix, ids).await,
+        };
+        let ids: Vec<String> = ids.collect().await;
+        let referenced = self.referenced(kind).await?;
+        if let Some(id) = ids.iter().find(|id| referenced.contains(*id)) {
+            return Err(StorageError::ReferencedObjectDeletion(kind, id.clone()));
+        }
+        self.backend.delete_objects(prefix, stream::iter(ids).boxed()).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        if matches!(kind, ObjectKind::Snapshot | ObjectKind::Manifest | ObjectKind::Chunk)
+            && self.exists(kind, to_id).await?
+        {
+            return Err(StorageError::ObjectOverwrite(kind, to_id.to_string()));
+        }
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::{format::Path, ObjectStorage, Repository};
+
+    #[tokio::test]
+    async fn test_guarded_storage() -> Result<(), Box<dyn Error>> {
+        let backend: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage: DynStorage = Arc::new(GuardedStorage::new(Arc::clone(&backend)));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        repo.add_group(Path::root()).await?;
+        let snapshot_id = repo.commit("main", "group", None).await?;
+
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+        assert!(matches!(
+            storage.write_snapshot(snapshot_id.clone(), snapshot).await,
+            Err(StorageError::ObjectOverwrite(ObjectKind::Snapshot, id))
+                if id == snapshot_id.to_string()
+        ));
+        assert!(matches!(
+            storage.delete_snapshots(stream::iter([snapshot_id.clone()]).boxed()).await,
+            Err(StorageError::ReferencedObjectDeletion(ObjectKind::Snapshot, _))
+        ));
+        assert!(storage.fetch_snapshot(&snapshot_id).await.is_ok());
+
+        let chunk = ChunkId::random();
+        storage.write_chunk(chunk.clone(), Bytes::from_static(b"hello")).await?;
+        storage.write_chunk(chunk.clone(), Bytes::from_static(b"hello")).await?;
+        assert!(matches!(
+            storage.write_chunk(chunk.clone(), Bytes::from_static(b"other")).await,
+            Err(StorageError::ObjectOverwrite(ObjectKind::Chunk, _))
+        ));
+        assert!(matches!(
+            storage
+                .copy_object(
+                    ObjectKind::Chunk,
+                    &ChunkId::random().to_string(),
+                    &chunk.to_string()
+                )
+                .await,
+            Err(StorageError::ObjectOverwrite(ObjectKind::Chunk, _))
+        ));
+        // the chunk isn't referenced by any snapshot
+        assert_eq!(
+            storage.delete_chunks(stream::iter([chunk.clone()]).boxed()).await?,
+            1
+        );
+        assert!(backend.fetch_chunk(&chunk, &ByteRange::ALL).await.is_err());
+        Ok(())
+    }
+}
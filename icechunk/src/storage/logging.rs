@@ -1,34 +1,85 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::stream::BoxStream;
 
-use super::{ListInfo, Storage, StorageError, StorageResult};
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult,
+};
 use crate::{
     format::{
-        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
     },
     private,
+    refs::BranchTipCache,
 };
 
 #[derive(Debug)]
 pub struct LoggingStorage {
     backend: Arc<dyn Storage + Send + Sync>,
     fetch_log: Mutex<Vec<(String, Vec<u8>)>>,
+    lagging_reads: usize,
+    reads: Mutex<HashMap<Vec<u8>, usize>>,
+    truncated_chunk_writes: Mutex<usize>,
 }
 
-#[cfg(test)]
 impl LoggingStorage {
     pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
-        Self { backend, fetch_log: Mutex::new(Vec::new()) }
+        Self {
+            backend,
+            fetch_log: Mutex::new(Vec::new()),
+            lagging_reads: 0,
+            reads: Mutex::new(HashMap::new()),
+            truncated_chunk_writes: Mutex::new(0),
+        }
+    }
+
+    /// Simulate a faulty network: the first `writes` chunk uploads lose their last byte
+    #[allow(clippy::expect_used)]
+    pub fn with_truncated_chunk_writes(self, writes: usize) -> Self {
+        *self.truncated_chunk_writes.lock().expect("poison lock") = writes;
+        self
+    }
+
+    /// Simulate an eventually consistent store: the first `reads` fetches of each object fail
+    /// as if it didn't exist
+    pub fn with_lagging_reads(mut self, reads: usize) -> Self {
+        self.lagging_reads = reads;
+        self
     }
 
     #[allow(clippy::expect_used)] // this implementation is intended for tests only
     pub fn fetch_operations(&self) -> Vec<(String, Vec<u8>)> {
         self.fetch_log.lock().expect("poison lock").clone()
     }
+
+    #[allow(clippy::expect_used)]
+    fn log(&self, operation: &str, id: &[u8]) -> StorageResult<()> {
+        self.fetch_log
+            .lock()
+            .expect("poison lock")
+            .push((operation.to_string(), id.to_vec()));
+        let mut reads = self.reads.lock().expect("poison lock");
+        let seen = reads.entry(id.to_vec()).or_default();
+        *seen += 1;
+        if *seen <= self.lagging_reads {
+            return Err(StorageError::ObjectStore(::object_store::Error::NotFound {
+                path: format!("{id:?}"),
+                source: "not visible yet".into(),
+            }));
+        }
+        Ok(())
+    }
 }
 
 impl private::Sealed for LoggingStorage {}
@@ -40,10 +91,7 @@ impl Storage for LoggingStorage {
         &self,
         id: &SnapshotId,
     ) -> Result<Arc<Snapshot>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_snapshot".to_string(), id.0.to_vec()));
+        self.log("fetch_snapshot", &id.0)?;
         self.backend.fetch_snapshot(id).await
     }
 
@@ -51,10 +99,7 @@ impl Storage for LoggingStorage {
         &self,
         id: &SnapshotId,
     ) -> StorageResult<Arc<crate::format::transaction_log::TransactionLog>> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_transaction_log".to_string(), id.0.to_vec()));
+        self.log("fetch_transaction_log", &id.0)?;
         self.backend.fetch_transaction_log(id).await
     }
 
@@ -62,10 +107,7 @@ impl Storage for LoggingStorage {
         &self,
         id: &AttributesId,
     ) -> Result<Arc<AttributesTable>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_attributes".to_string(), id.0.to_vec()));
+        self.log("fetch_attributes", &id.0)?;
         self.backend.fetch_attributes(id).await
     }
 
@@ -73,25 +115,66 @@ impl Storage for LoggingStorage {
         &self,
         id: &ManifestId,
     ) -> Result<Arc<Manifest>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_manifests".to_string(), id.0.to_vec()));
+        self.log("fetch_manifests", &id.0)?;
         self.backend.fetch_manifests(id).await
     }
 
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.log("fetch_manifest_region", &id.0)?;
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.log("fetch_snapshot_if_modified", &id.0)?;
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.log("fetch_manifests_if_modified", &id.0)?;
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
     async fn fetch_chunk(
         &self,
         id: &ChunkId,
         range: &ByteRange,
     ) -> Result<Bytes, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_chunk".to_string(), id.0.to_vec()));
+        self.log("fetch_chunk", &id.0)?;
         self.backend.fetch_chunk(id, range).await
     }
 
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> Result<Bytes, StorageError> {
+        self.log("fetch_chunk_from", &id.0)?;
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
     async fn write_snapshot(
         &self,
         id: SnapshotId,
@@ -100,6 +183,14 @@ impl Storage for LoggingStorage {
         self.backend.write_snapshot(id, table).await
     }
 
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
     async fn write_transaction_log(
         &self,
         id: SnapshotId,
@@ -124,7 +215,24 @@ impl Storage for LoggingStorage {
         self.backend.write_manifests(id, table).await
     }
 
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
     async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> Result<(), StorageError> {
+        let bytes = {
+            let mut truncated = self.truncated_chunk_writes.lock().expect("poison lock");
+            if *truncated > 0 && !bytes.is_empty() {
+                *truncated -= 1;
+                bytes.slice(..bytes.len() - 1)
+            } else {
+                bytes
+            }
+        };
         self.backend.write_chunk(id, bytes).await
     }
 
@@ -166,4 +274,59 @@ impl Storage for LoggingStorage {
     ) -> StorageResult<usize> {
         self.backend.delete_objects(prefix, ids).await
     }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.log("fetch_snapshot_node", &id.0)?;
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.log("fetch_snapshot_segments", &id.0)?;
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
 }
@@ -1,40 +1,79 @@
 use aws_sdk_s3::{
     config::http::HttpResponse,
+    error::ProvideErrorMetadata,
     error::SdkError,
     operation::{
-        delete_objects::DeleteObjectsError, get_object::GetObjectError,
-        list_objects_v2::ListObjectsV2Error, put_object::PutObjectError,
+        copy_object::CopyObjectError, delete_objects::DeleteObjectsError,
+        get_object::GetObjectError, list_objects_v2::ListObjectsV2Error,
+        put_object::PutObjectError,
     },
+    presigning::PresigningConfigError,
     primitives::ByteStreamError,
 };
 use chrono::{DateTime, Utc};
 use core::fmt;
 use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
-use std::{ffi::OsString, sync::Arc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{ffi::OsString, future::ready, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use thiserror::Error;
 
+pub mod audit;
 pub mod caching;
+pub mod fallback;
+pub mod guarded;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "test_utils"))]
+pub mod faulty;
+#[cfg(any(test, feature = "test_utils"))]
 pub mod logging;
+pub mod obfuscated;
 
 pub mod object_store;
+pub mod prioritized;
+pub mod read_after_write;
+pub mod recording;
+pub mod replicated;
+pub mod revalidating;
 pub mod s3;
+pub mod segmented;
+pub mod sharded;
+pub mod single_flight;
+pub mod tiered;
+pub mod verifying;
 pub mod virtual_ref;
 
-pub use caching::MemCachingStorage;
-pub use object_store::ObjectStorage;
+pub use audit::{AccessLogger, AccessRecord, AuditedStorage};
+pub use caching::{CacheConfig, CacheStats, CachingStats, MemCachingStorage};
+pub use fallback::FallbackStorage;
+pub use guarded::GuardedStorage;
+pub use obfuscated::ObfuscatedStorage;
+pub use object_store::{Durability, ObjectStorage};
+pub use prioritized::{PrioritizedStorage, RequestPriority, RequestScheduler};
+pub use read_after_write::ReadAfterWriteStorage;
+pub use recording::{RecordingStorage, ReplayStorage, StorageTrace};
+pub use replicated::ReplicatedStorage;
+pub use revalidating::{RevalidatingStorage, RevalidationStats};
+pub use segmented::SegmentedSnapshotStorage;
+pub use sharded::{ChunkPlacement, HashPlacement, ShardedStorage};
+pub use single_flight::SingleFlightStorage;
+pub use tiered::TieredStorage;
+pub use verifying::{ChunkVerification, ChunkVerifyingStorage};
 
 use crate::{
     format::{
-        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        transaction_log::TransactionLog, AttributesId, ByteRange, ChunkId, ManifestId,
-        SnapshotId,
+        attributes::AttributesTable,
+        framing::{frame, unframe, FrameKind, FramingError},
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, IcechunkFormatVersion,
+        ManifestId, NodeId, Path, SnapshotId,
     },
     private,
+    refs::BranchTipCache,
 };
 
 #[derive(Debug, Error)]
@@ -51,33 +90,291 @@ pub enum StorageError {
     S3ListObjectError(#[from] SdkError<ListObjectsV2Error, HttpResponse>),
     #[error("error deleting objects in object store {0}")]
     S3DeleteObjectError(#[from] SdkError<DeleteObjectsError, HttpResponse>),
+    #[error("error copying object in object store {0}")]
+    S3CopyObjectError(#[from] SdkError<CopyObjectError, HttpResponse>),
     #[error("error streaming bytes from object store {0}")]
     S3StreamError(#[from] ByteStreamError),
     #[error("messagepack decode error: {0}")]
     MsgPackDecodeError(#[from] rmp_serde::decode::Error),
     #[error("messagepack encode error: {0}")]
     MsgPackEncodeError(#[from] rmp_serde::encode::Error),
+    #[error("invalid metadata object: {0}")]
+    Framing(#[from] FramingError),
     #[error("cannot overwrite ref: {0}")]
     RefAlreadyExists(String),
     #[error("ref not found: {0}")]
     RefNotFound(String),
+    #[error("object already exists: {0}")]
+    ObjectAlreadyExists(String),
+    #[error("object {0} is archived, it must be restored before it can be read")]
+    ObjectArchived(String),
+    #[error("invalid presigning configuration {0}")]
+    S3PresigningConfigError(#[from] PresigningConfigError),
+    #[error("this storage doesn't support presigned URLs")]
+    PresignNotSupported,
+    #[error("this storage doesn't support segmented snapshots")]
+    SegmentsNotSupported,
+    #[error("this storage can't list keys")]
+    ListingNotSupported,
+    #[error("{0} is still not readable after writing it")]
+    NotVisible(String),
+    #[error("chunk {0} doesn't match the data written after {1} uploads")]
+    ChunkCorrupted(String, u32),
+    #[error("{0:?} object {1} already exists, objects are immutable")]
+    ObjectOverwrite(ObjectKind, String),
+    #[error("{0:?} object {1} is still referenced and cannot be deleted")]
+    ReferencedObjectDeletion(ObjectKind, String),
+    #[error("cannot decrypt {0}, it was not written with this obfuscation key")]
+    NotDecryptable(String),
+    #[error("local filesystem error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("request `{0}` is not in the replayed trace")]
+    NotRecorded(String),
     #[error("unknown storage error: {0}")]
     Other(String),
+    #[error("{0}")]
+    Shared(Arc<StorageError>),
+}
+
+/// A classification of storage errors, independent of the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StorageErrorKind {
+    /// The object or ref doesn't exist
+    NotFound,
+    /// The credentials are missing or don't grant access
+    PermissionDenied,
+    /// A conditional write failed, for example, because the object already exists
+    PreconditionFailed,
+    /// The backend asked the client to slow down
+    Throttled,
+    /// A network or server failure, that may not happen again
+    Transient,
+    /// The object is larger than the backend accepts
+    TooLarge,
+    Other,
+}
+
+impl StorageError {
+    pub fn kind(&self) -> StorageErrorKind {
+        use StorageErrorKind::*;
+        match self {
+            StorageError::ObjectStore(err) => match err {
+                ::object_store::Error::NotFound { .. } => NotFound,
+                ::object_store::Error::AlreadyExists { .. }
+                | ::object_store::Error::Precondition { .. }
+                | ::object_store::Error::NotModified { .. } => PreconditionFailed,
+                ::object_store::Error::PermissionDenied { .. }
+                | ::object_store::Error::Unauthenticated { .. } => PermissionDenied,
+                ::object_store::Error::Generic { source, .. } => {
+                    generic_error_kind(source.as_ref())
+                }
+                _ => Other,
+            },
+            StorageError::S3GetObjectError(err) => sdk_error_kind(err),
+            StorageError::S3PutObjectError(err) => sdk_error_kind(err),
+            StorageError::S3ListObjectError(err) => sdk_error_kind(err),
+            StorageError::S3DeleteObjectError(err) => sdk_error_kind(err),
+            StorageError::S3CopyObjectError(err) => sdk_error_kind(err),
+            StorageError::S3StreamError(_) | StorageError::NotVisible(_) => Transient,
+            StorageError::RefAlreadyExists(_)
+            | StorageError::ObjectAlreadyExists(_)
+            | StorageError::ObjectOverwrite(..) => PreconditionFailed,
+            StorageError::RefNotFound(_) => NotFound,
+            StorageError::Shared(err) => err.kind(),
+            _ => Other,
+        }
+    }
+
+    /// The operation that failed may succeed if it's retried
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.kind(), StorageErrorKind::Throttled | StorageErrorKind::Transient)
+    }
+
+    /// The error happened because the requested object doesn't exist
+    pub(crate) fn is_not_found(&self) -> bool {
+        self.kind() == StorageErrorKind::NotFound
+    }
+}
+
+/// The kind of an `object_store` error without a variant of its own
+///
+/// Network failures and error responses reach us as `Generic` errors, and the errors of
+/// `object_store` HTTP clients are private types, so only their messages tell them apart.
+/// Responses with a client error status are not transient, everything else, network
+/// failures, timeouts and server errors, is.
+fn generic_error_kind(
+    source: &(dyn std::error::Error + Send + Sync),
+) -> StorageErrorKind {
+    let mut message = source.to_string();
+    let mut cause = source.source();
+    while let Some(err) = cause {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        cause = err.source();
+    }
+    if message.contains("Client error with status 413")
+        || message.contains("EntityTooLarge")
+    {
+        StorageErrorKind::TooLarge
+    } else if message.contains("Client error with status 429") {
+        StorageErrorKind::Throttled
+    } else if message.contains("Client error with status") {
+        StorageErrorKind::Other
+    } else {
+        StorageErrorKind::Transient
+    }
+}
+
+fn sdk_error_kind<E: ProvideErrorMetadata>(
+    err: &SdkError<E, HttpResponse>,
+) -> StorageErrorKind {
+    use StorageErrorKind::*;
+    match err {
+        SdkError::TimeoutError(_)
+        | SdkError::DispatchFailure(_)
+        | SdkError::ResponseError(_) => Transient,
+        SdkError::ServiceError(service_err) => {
+            let code = service_err.err().code().unwrap_or("");
+            match (code, service_err.raw().status().as_u16()) {
+                ("SlowDown" | "Throttling" | "ThrottlingException", _) | (_, 429) => {
+                    Throttled
+                }
+                ("NoSuchKey", _) | (_, 404) => NotFound,
+                ("EntityTooLarge", _) | (_, 413) => TooLarge,
+                (_, 401 | 403) => PermissionDenied,
+                ("PreconditionFailed" | "ConditionalRequestConflict", _)
+                | (_, 409 | 412) => PreconditionFailed,
+                (_, 500..=599) => Transient,
+                _ => Other,
+            }
+        }
+        _ => Other,
+    }
 }
 
 pub type StorageResult<A> = Result<A, StorageError>;
 
+/// Serialize a metadata object, in a checksummed frame if `framed`
+pub(crate) fn serialize_metadata<T: Serialize>(
+    kind: FrameKind,
+    format_version: IcechunkFormatVersion,
+    object: &T,
+    framed: bool,
+) -> StorageResult<Vec<u8>> {
+    let bytes = rmp_serde::to_vec(object)?;
+    Ok(if framed { frame(kind, format_version, &bytes) } else { bytes })
+}
+
+/// Deserialize a metadata object, framed or not
+pub(crate) fn deserialize_metadata<T: DeserializeOwned>(
+    kind: FrameKind,
+    bytes: &[u8],
+) -> StorageResult<T> {
+    // TODO: optimize using from_read
+    Ok(rmp_serde::from_slice(unframe(kind, bytes)?)?)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ListInfo<Id> {
     pub id: Id,
     pub created_at: DateTime<Utc>,
+    pub size_bytes: u64,
+}
+
+/// The types of objects Icechunk stores
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectKind {
+    Snapshot,
+    Manifest,
+    Chunk,
+    TransactionLog,
+    Ref,
+}
+
+impl ObjectKind {
+    pub(crate) fn prefix(&self) -> &'static str {
+        match self {
+            ObjectKind::Snapshot => SNAPSHOT_PREFIX,
+            ObjectKind::Manifest => MANIFEST_PREFIX,
+            ObjectKind::Chunk => CHUNK_PREFIX,
+            ObjectKind::TransactionLog => TRANSACTION_PREFIX,
+            ObjectKind::Ref => REF_PREFIX,
+        }
+    }
+}
+
+/// A URL that grants temporary read access to an object, without credentials
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresignedUrl {
+    pub url: String,
+    /// Headers that must be sent with the request, for example, the byte range
+    pub headers: Vec<(String, String)>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The optional features a [`Storage`] supports, see [`Storage::capabilities`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct StorageCapabilities {
+    /// Refs written with `overwrite_refs = false` are only created if they don't exist
+    pub conditional_writes: bool,
+    /// [`Storage::copy_object`] copies without downloading the object
+    pub server_side_copy: bool,
+    /// [`Storage::delete_objects`] deletes many objects per request
+    pub batch_delete: bool,
+    /// [`Storage::presign_read`] is supported
+    pub presigning: bool,
+    /// [`Storage::ref_names`] and [`Storage::ref_versions`] are supported
+    pub listing: bool,
+}
+
+impl StorageCapabilities {
+    pub fn all() -> Self {
+        Self {
+            conditional_writes: true,
+            server_side_copy: true,
+            batch_delete: true,
+            presigning: true,
+            listing: true,
+        }
+    }
+
+    /// The features supported by both `self` and `other`
+    pub fn intersection(self, other: Self) -> Self {
+        Self {
+            conditional_writes: self.conditional_writes && other.conditional_writes,
+            server_side_copy: self.server_side_copy && other.server_side_copy,
+            batch_delete: self.batch_delete && other.batch_delete,
+            presigning: self.presigning && other.presigning,
+            listing: self.listing && other.listing,
+        }
+    }
+}
+
+/// The version tag a storage gives an object, see [`Storage::fetch_snapshot_if_modified`]
+pub type ETag = String;
+
+/// The result of fetching an object only if it changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conditional<T> {
+    /// The object still has the ETag passed
+    NotModified,
+    /// The object, with its current ETag if the storage reports them
+    Modified(T, Option<ETag>),
 }
 
 const SNAPSHOT_PREFIX: &str = "snapshots/";
 const MANIFEST_PREFIX: &str = "manifests/";
-// const ATTRIBUTES_PREFIX: &str = "attributes/";
-const CHUNK_PREFIX: &str = "chunks/";
-const REF_PREFIX: &str = "refs";
+pub(crate) const ATTRIBUTES_PREFIX: &str = "attributes/";
+pub(crate) const CHUNK_PREFIX: &str = "chunks/";
+pub(crate) const REF_PREFIX: &str = "refs";
 const TRANSACTION_PREFIX: &str = "transactions/";
+const SNAPSHOT_SEGMENTS_PREFIX: &str = "segments/";
+
+/// Maximum number of objects in a single delete request, this is the S3 limit
+const DELETE_BATCH_SIZE: usize = 1_000;
+/// How many delete requests are sent in parallel
+const DELETE_CONCURRENCY: usize = 10;
 
 /// Fetch and write the parquet files that represent the repository in object store
 ///
@@ -92,16 +389,120 @@ pub trait Storage: fmt::Debug + private::Sealed {
     ) -> StorageResult<Arc<AttributesTable>>; // FIXME: format flags
     async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>>; // FIXME: format flags
     async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes>; // FIXME: format flags
+
+    /// The chunks of `node` in manifest `id` with coordinates in `extents`, in coordinate
+    /// order
+    ///
+    /// Storages that decode manifests themselves select the chunks from the columns of the
+    /// manifest, see [`crate::format::manifest_columns::ColumnarManifest::select`], without
+    /// building the whole manifest. The default implementation selects them from
+    /// [`Storage::fetch_manifests`], so caching storages use their cached manifest. Storages
+    /// that wrap another one return the selection of their backend.
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let manifest = self.fetch_manifests(id).await?;
+        Ok(manifest
+            .chunks_in_region(node, extents)
+            .map(|(coord, payload)| (coord.clone(), payload.clone()))
+            .collect())
+    }
+
+    /// Generate a URL that can be used to fetch `range` of the chunk for the next `ttl`
+    ///
+    /// Not every storage supports this, the default implementation fails with
+    /// [`StorageError::PresignNotSupported`].
+    async fn presign_read(
+        &self,
+        _id: &ChunkId,
+        _range: &ByteRange,
+        _ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        Err(StorageError::PresignNotSupported)
+    }
+
+    /// Fetch the chunk from the shard recorded for it, see [`Storage::chunk_placement`]
+    ///
+    /// The default implementation ignores the shard.
+    async fn fetch_chunk_from(
+        &self,
+        _shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.fetch_chunk(id, range).await
+    }
+
     async fn fetch_transaction_log(
         &self,
         id: &SnapshotId,
     ) -> StorageResult<Arc<TransactionLog>>; // FIXME: format flags
 
+    /// Fetch the snapshot, unless its ETag is still `etag`
+    ///
+    /// Revalidating a cached object this way downloads nothing if it didn't change. The
+    /// default implementation fetches the object every time, and reports no ETag.
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        _etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        Ok(Conditional::Modified(self.fetch_snapshot(id).await?, None))
+    }
+
+    /// Fetch the manifest, unless its ETag is still `etag`, see
+    /// [`Storage::fetch_snapshot_if_modified`]
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        _etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        Ok(Conditional::Modified(self.fetch_manifests(id).await?, None))
+    }
+
+    /// The node at `path` of the snapshot, `None` if there is no such node
+    ///
+    /// The default implementation fetches the whole snapshot, see
+    /// [`SegmentedSnapshotStorage`] for one that doesn't. Storages that wrap another one
+    /// without caching snapshots return the node of their backend.
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        Ok(self.fetch_snapshot(id).await?.get_node(path).ok().cloned())
+    }
+
+    /// Fetch `range` of the segmented layout of the snapshot, see
+    /// [`crate::format::snapshot_segments`]
+    ///
+    /// The default implementation fails with [`StorageError::SegmentsNotSupported`].
+    async fn fetch_snapshot_segments(
+        &self,
+        _id: &SnapshotId,
+        _range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        Err(StorageError::SegmentsNotSupported)
+    }
+
     async fn write_snapshot(
         &self,
         id: SnapshotId,
         table: Arc<Snapshot>,
     ) -> StorageResult<()>;
+    /// Write the segmented layout of the snapshot, after the snapshot itself
+    ///
+    /// The default implementation fails with [`StorageError::SegmentsNotSupported`].
+    async fn write_snapshot_segments(
+        &self,
+        _id: SnapshotId,
+        _bytes: Bytes,
+    ) -> StorageResult<()> {
+        Err(StorageError::SegmentsNotSupported)
+    }
     async fn write_attributes(
         &self,
         id: AttributesId,
@@ -112,6 +513,24 @@ pub trait Storage: fmt::Debug + private::Sealed {
         id: ManifestId,
         table: Arc<Manifest>,
     ) -> StorageResult<()>;
+
+    /// Write the snapshot only if there is no object with the same id
+    ///
+    /// Fails with [`StorageError::ObjectAlreadyExists`] otherwise. A retried upload that
+    /// gets this error can fetch the existing object and compare it with its own.
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()>;
+    /// Write the manifest only if there is no object with the same id
+    ///
+    /// Fails with [`StorageError::ObjectAlreadyExists`] otherwise.
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()>;
     async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()>;
     async fn write_transaction_log(
         &self,
@@ -144,6 +563,71 @@ pub trait Storage: fmt::Debug + private::Sealed {
         ids: BoxStream<'_, String>,
     ) -> StorageResult<usize>;
 
+    /// Copy an object to a new id, without downloading its bytes to the client
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()>;
+
+    /// The shard a chunk with this id is written to, `None` if the storage is not sharded
+    ///
+    /// The repository records it in the manifest, so chunks are fetched from the right
+    /// shard after the shards or their placement change. Storages that wrap another one
+    /// return the placement of their backend.
+    fn chunk_placement(&self, _id: &ChunkId) -> Option<String> {
+        None
+    }
+
+    /// The absolute URL of the object of chunk `id`, stored in `shard` if one was recorded
+    /// for it, for virtual references from other repositories
+    ///
+    /// `None` if the objects of the storage have no stable URL, like in memory ones. Storages
+    /// that wrap another one return the URL of their backend.
+    fn chunk_url(&self, _shard: Option<&str>, _id: &ChunkId) -> Option<String> {
+        None
+    }
+
+    /// The hits and misses of the caches of this storage, `None` if it doesn't cache
+    ///
+    /// Storages that wrap another one return the stats of their backend.
+    fn cache_stats(&self) -> Option<CachingStats> {
+        None
+    }
+
+    /// The branch tips resolved through this storage, `None` if it doesn't cache them
+    ///
+    /// Storages that wrap another one return the cache of their backend.
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        None
+    }
+
+    /// The optional features this storage supports
+    ///
+    /// Higher layers use it to pick a strategy up front instead of failing over on errors.
+    /// The default reports nothing as supported. Storages that wrap another one return the
+    /// capabilities of their backend, or of all their backends if they write to several.
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities::default()
+    }
+
+    /// List all objects of a kind physically present in storage
+    ///
+    /// Only objects whose id starts with `id_prefix` are returned.
+    async fn list_objects_of_kind<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id_prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        let id_prefix = id_prefix.to_string();
+        Ok(self
+            .list_objects(kind.prefix())
+            .await?
+            .try_filter(move |info| ready(info.id.starts_with(id_prefix.as_str())))
+            .boxed())
+    }
+
     async fn list_chunks(
         &self,
     ) -> StorageResult<BoxStream<StorageResult<ListInfo<ChunkId>>>> {
@@ -162,6 +646,12 @@ pub trait Storage: fmt::Debug + private::Sealed {
         Ok(translate_list_infos(self.list_objects(SNAPSHOT_PREFIX).await?))
     }
 
+    async fn list_transaction_logs(
+        &self,
+    ) -> StorageResult<BoxStream<StorageResult<ListInfo<SnapshotId>>>> {
+        Ok(translate_list_infos(self.list_objects(TRANSACTION_PREFIX).await?))
+    }
+
     async fn delete_chunks(
         &self,
         chunks: BoxStream<'_, ChunkId>,
@@ -191,8 +681,7 @@ where
     Id: for<'b> TryFrom<&'b str>,
 {
     let id = Id::try_from(item.id.as_str()).ok()?;
-    let created_at = item.created_at;
-    Some(ListInfo { created_at, id })
+    Some(ListInfo { id, created_at: item.created_at, size_bytes: item.size_bytes })
 }
 
 fn translate_list_infos<'a, Id>(
@@ -204,3 +693,286 @@ where
     // FIXME: flag error, don't skip
     s.try_filter_map(|info| async move { Ok(convert_list_item(info)) }).boxed()
 }
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use aws_sdk_s3::{
+        error::ErrorMetadata, operation::get_object::GetObjectError, primitives::SdkBody,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn service_error(code: &str, status: u16) -> StorageError {
+        let err = GetObjectError::generic(ErrorMetadata::builder().code(code).build());
+        let raw = HttpResponse::new(status.try_into().unwrap(), SdkBody::empty());
+        StorageError::S3GetObjectError(SdkError::service_error(err, raw))
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let cases = [
+            (service_error("NoSuchKey", 404), StorageErrorKind::NotFound, false),
+            (
+                service_error("AccessDenied", 403),
+                StorageErrorKind::PermissionDenied,
+                false,
+            ),
+            (
+                service_error("PreconditionFailed", 412),
+                StorageErrorKind::PreconditionFailed,
+                false,
+            ),
+            (service_error("SlowDown", 503), StorageErrorKind::Throttled, true),
+            (service_error("InternalError", 500), StorageErrorKind::Transient, true),
+            (
+                StorageError::S3GetObjectError(SdkError::timeout_error("timeout")),
+                StorageErrorKind::Transient,
+                true,
+            ),
+            (
+                StorageError::ObjectStore(::object_store::Error::NotFound {
+                    path: "foo".to_string(),
+                    source: "missing".into(),
+                }),
+                StorageErrorKind::NotFound,
+                false,
+            ),
+            (
+                StorageError::RefAlreadyExists("main".to_string()),
+                StorageErrorKind::PreconditionFailed,
+                false,
+            ),
+            (
+                StorageError::ObjectStore(::object_store::Error::Generic {
+                    store: "S3",
+                    source: "Server error, body contains Error, with status 503".into(),
+                }),
+                StorageErrorKind::Transient,
+                true,
+            ),
+            (
+                StorageError::ObjectStore(::object_store::Error::Generic {
+                    store: "S3",
+                    source: "error sending request for url".into(),
+                }),
+                StorageErrorKind::Transient,
+                true,
+            ),
+            (
+                StorageError::ObjectStore(::object_store::Error::Generic {
+                    store: "S3",
+                    source: "Client error with status 400 Bad Request: No Body".into(),
+                }),
+                StorageErrorKind::Other,
+                false,
+            ),
+            (service_error("EntityTooLarge", 400), StorageErrorKind::TooLarge, false),
+            (
+                StorageError::ObjectStore(::object_store::Error::Generic {
+                    store: "S3",
+                    source: "Client error with status 413 Payload Too Large: No Body"
+                        .into(),
+                }),
+                StorageErrorKind::TooLarge,
+                false,
+            ),
+            (StorageError::Other("oops".to_string()), StorageErrorKind::Other, false),
+        ];
+        for (err, kind, retryable) in cases {
+            assert_eq!(err.kind(), kind, "{err}");
+            assert_eq!(err.is_retryable(), retryable, "{err}");
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let local: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let expected = StorageCapabilities {
+            conditional_writes: true,
+            server_side_copy: true,
+            batch_delete: false,
+            presigning: false,
+            listing: true,
+        };
+        assert_eq!(local.capabilities(), expected);
+        let logging = logging::LoggingStorage::new(Arc::clone(&local));
+        assert_eq!(logging.capabilities(), expected);
+
+        let unlisted =
+            StorageCapabilities { listing: false, ..StorageCapabilities::all() };
+        assert_eq!(
+            unlisted.intersection(expected),
+            StorageCapabilities { listing: false, ..expected }
+        );
+        assert_eq!(
+            StorageCapabilities::all().intersection(StorageCapabilities::default()),
+            StorageCapabilities::default()
+        );
+    }
+
+    type DynStorage = Arc<dyn Storage + Send + Sync>;
+    type Wrapper = fn(DynStorage) -> DynStorage;
+
+    /// Every storage that wraps another one and should forward all reads to it
+    fn pass_through_wrappers() -> Vec<(&'static str, Wrapper)> {
+        vec![
+            ("audit", |s| {
+                let logger =
+                    Arc::new(crate::events::JsonEventLogger::new(std::io::sink()));
+                Arc::new(AuditedStorage::new(s, logger, "reader"))
+            }),
+            ("caching", |s| Arc::new(MemCachingStorage::new(s, 1, 1, 1, 1, 1))),
+            ("fallback", |s| Arc::new(FallbackStorage::new(vec![Arc::clone(&s)], s))),
+            ("faulty", |s| Arc::new(faulty::FaultyStorage::new(s))),
+            ("guarded", |s| Arc::new(GuardedStorage::new(s))),
+            ("logging", |s| Arc::new(logging::LoggingStorage::new(s))),
+            ("read_after_write", |s| Arc::new(ReadAfterWriteStorage::new(s))),
+            ("replicated", |s| {
+                Arc::new(ReplicatedStorage::new(Arc::clone(&s), vec![s], Duration::ZERO))
+            }),
+            ("revalidating", |s| {
+                Arc::new(RevalidatingStorage::new(s, 1, 1, Duration::from_secs(1)))
+            }),
+            ("single_flight", |s| Arc::new(SingleFlightStorage::new(s))),
+            ("tiered", |s| {
+                Arc::new(TieredStorage::new(
+                    s,
+                    Arc::new(ObjectStorage::new_in_memory_store(None)),
+                ))
+            }),
+            ("verifying", |s| {
+                Arc::new(ChunkVerifyingStorage::new(s, ChunkVerification::ReadBack))
+            }),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_wrappers_forward_chunk_shards() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let id = ChunkId::random();
+        for (name, wrap) in pass_through_wrappers() {
+            let recording = Arc::new(RecordingStorage::new(Arc::new(
+                ObjectStorage::new_in_memory_store(None),
+            )));
+            recording.write_chunk(id.clone(), Bytes::from_static(b"chunk")).await?;
+            let storage = wrap(recording.clone());
+            assert_eq!(
+                storage.fetch_chunk_from("shard-a", &id, &ByteRange::ALL).await?,
+                Bytes::from_static(b"chunk"),
+                "{name}"
+            );
+            let reads: Vec<String> = recording
+                .trace()
+                .calls
+                .into_iter()
+                .map(|call| call.request)
+                .filter(|request| request.starts_with("fetch_chunk"))
+                .collect();
+            assert!(!reads.is_empty(), "{name}");
+            assert!(
+                reads
+                    .iter()
+                    .all(|request| request.starts_with("fetch_chunk_from shard-a ")),
+                "{name}: {reads:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrappers_forward_conditional_fetches(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let snapshot = Arc::new(Snapshot::empty());
+        let snapshot_id = snapshot.metadata.id.clone();
+        let manifest_id = ManifestId::random();
+        for (name, wrap) in pass_through_wrappers() {
+            let recording = Arc::new(RecordingStorage::new(Arc::new(
+                ObjectStorage::new_in_memory_store(None),
+            )));
+            recording.write_snapshot(snapshot_id.clone(), Arc::clone(&snapshot)).await?;
+            recording
+                .write_manifests(manifest_id.clone(), Arc::new(Manifest::default()))
+                .await?;
+            let storage = wrap(recording.clone());
+            assert!(
+                matches!(
+                    storage
+                        .fetch_snapshot_if_modified(&snapshot_id, Some("etag"))
+                        .await?,
+                    Conditional::Modified(..)
+                ),
+                "{name}"
+            );
+            assert!(
+                matches!(
+                    storage
+                        .fetch_manifests_if_modified(&manifest_id, Some("etag"))
+                        .await?,
+                    Conditional::Modified(..)
+                ),
+                "{name}"
+            );
+            let requests: Vec<String> =
+                recording.trace().calls.into_iter().map(|call| call.request).collect();
+            for op in ["fetch_snapshot", "fetch_manifests"] {
+                let reads: Vec<&String> = requests
+                    .iter()
+                    .filter(|request| request.starts_with(&format!("{op} ")))
+                    .collect();
+                assert!(reads.is_empty(), "{name} lost the etag: {reads:?}");
+                assert!(
+                    requests.iter().any(|request| {
+                        request.starts_with(&format!("{op}_if_modified "))
+                            && request.ends_with("Some(\"etag\")")
+                    }),
+                    "{name}: {requests:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrappers_forward_manifest_regions(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let node = NodeId::random();
+        let manifest = Arc::new(Manifest::new(BTreeMap::from([(
+            (node.clone(), ChunkIndices(vec![1])),
+            ChunkPayload::Inline(Bytes::from_static(b"chunk")),
+        )])));
+        let manifest_id = ManifestId::random();
+        let extents = ManifestExtents::new(ChunkIndices(vec![0]), ChunkIndices(vec![2]));
+        for (name, wrap) in pass_through_wrappers() {
+            let recording = Arc::new(RecordingStorage::new(Arc::new(
+                ObjectStorage::new_in_memory_store(None),
+            )));
+            recording.write_manifests(manifest_id.clone(), Arc::clone(&manifest)).await?;
+            let storage = wrap(recording.clone());
+            assert_eq!(
+                storage.fetch_manifest_region(&manifest_id, &node, &extents).await?,
+                vec![(
+                    ChunkIndices(vec![1]),
+                    ChunkPayload::Inline(Bytes::from_static(b"chunk"))
+                )],
+                "{name}"
+            );
+            let requests: Vec<String> =
+                recording.trace().calls.into_iter().map(|call| call.request).collect();
+            // caches select the chunks from the manifest they cache
+            let op = match name {
+                "caching" | "revalidating" | "single_flight" => "fetch_manifests",
+                _ => "fetch_manifest_region",
+            };
+            assert!(
+                requests.iter().any(|request| request.starts_with(op)),
+                "{name}: {requests:?}"
+            );
+        }
+        Ok(())
+    }
+}
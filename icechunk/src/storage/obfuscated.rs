@@ -0,0 +1,691 @@
+//! A [`Storage`] that encrypts the names of refs and nodes
+//!
+//! For deployments where the operators of the bucket must not learn the structure of the
+//! datasets, [`ObfuscatedStorage`] encrypts with a key the names of branches, tags and every
+//! other ref, the content of refs, and the paths of the nodes in snapshots. Everything stays
+//! queryable through the API with the key: the encryption is deterministic, so the same name
+//! is always stored under the same key, and lookups by name or path work without listing.
+//!
+//! Paths are encrypted whole, not by component, so snapshots don't reveal the hierarchy,
+//! only the number of nodes. Snapshot properties are encrypted together, as one value: some
+//! of the properties Icechunk records are keyed by node path, like the
+//! [`crate::ops::attributes_index`] and the [`crate::ops::documents`]. Attributes, manifests
+//! and transaction logs only reference nodes by id, and are written as they are, like commit
+//! messages. Attribute values and chunks are not encrypted.
+//!
+//! Names are sealed with a synthetic IV construction over HMAC-SHA256: the IV is a MAC of
+//! the name, and the name is encrypted with a keystream derived from the IV, so decrypting
+//! also authenticates it. Both keys are derived from the user key with HMAC-SHA256, and the
+//! keystream is made of HMAC-SHA256 blocks of the IV and a counter.
+//!
+//! This is not a standard construction like AES-SIV (RFC 5297), and it hasn't been
+//! reviewed: its security rests only on HMAC-SHA256 being a pseudorandom function. Like
+//! any deterministic encryption it leaks equality: the same name seals to the same
+//! ciphertext, in this repository and in every other one with the same key. It also leaks
+//! lengths, a sealed name is 16 bytes longer than the name. Only the contents of names are
+//! hidden, not which names repeat or how long they are.
+use std::{fmt, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot, SnapshotProperties},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        PathError, SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult, REF_PREFIX,
+};
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+type HmacSha256 = Hmac<Sha256>;
+
+const SIV_LENGTH: usize = 16;
+/// The only property of stored snapshots, their properties, encrypted
+const SEALED_PROPERTIES_PROPERTY: &str = "icechunk.sealed_properties";
+
+/// Deterministic authenticated encryption of names and ref contents
+struct NameCipher {
+    siv_key: [u8; 32],
+    stream_key: [u8; 32],
+}
+
+impl fmt::Debug for NameCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the keys
+        f.write_str("NameCipher")
+    }
+}
+
+fn mac(key: &[u8]) -> HmacSha256 {
+    #[allow(clippy::expect_used)]
+    HmacSha256::new_from_slice(key).expect("HMAC can take keys of any size")
+}
+
+impl NameCipher {
+    fn new(key: &[u8]) -> Self {
+        let derive = |label: &[u8]| {
+            let mut mac = mac(key);
+            mac.update(label);
+            mac.finalize().into_bytes().into()
+        };
+        Self {
+            siv_key: derive(b"icechunk obfuscation siv"),
+            stream_key: derive(b"icechunk obfuscation stream"),
+        }
+    }
+
+    fn siv(&self, plain: &[u8]) -> HmacSha256 {
+        let mut mac = mac(&self.siv_key);
+        mac.update(plain);
+        mac
+    }
+
+    fn apply_keystream(&self, siv: &[u8], data: &mut [u8]) {
+        for (counter, block) in data.chunks_mut(32).enumerate() {
+            let mut mac = mac(&self.stream_key);
+            mac.update(siv);
+            mac.update(&(counter as u64).to_be_bytes());
+            let pad = mac.finalize().into_bytes();
+            block.iter_mut().zip(pad.iter()).for_each(|(byte, pad)| *byte ^= pad);
+        }
+    }
+
+    fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let mut res = Vec::with_capacity(SIV_LENGTH + plain.len());
+        res.extend_from_slice(&self.siv(plain).finalize().into_bytes()[..SIV_LENGTH]);
+        res.extend_from_slice(plain);
+        let (siv, data) = res.split_at_mut(SIV_LENGTH);
+        self.apply_keystream(siv, data);
+        res
+    }
+
+    /// `None` if `sealed` was not sealed with this key
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SIV_LENGTH {
+            return None;
+        }
+        let (siv, data) = sealed.split_at(SIV_LENGTH);
+        let mut plain = data.to_vec();
+        self.apply_keystream(siv, &mut plain);
+        self.siv(&plain).verify_truncated_left(siv).ok()?;
+        Some(plain)
+    }
+
+    fn seal_name(&self, name: &str) -> String {
+        base32::encode(base32::Alphabet::Crockford, &self.seal(name.as_bytes()))
+    }
+
+    fn open_name(&self, sealed: &str) -> Option<String> {
+        let bytes = base32::decode(base32::Alphabet::Crockford, sealed)?;
+        String::from_utf8(self.open(&bytes)?).ok()
+    }
+}
+
+/// A [`Storage`] that encrypts ref names, ref contents, node paths and snapshot properties
+/// with a key
+///
+/// See the [module documentation](self). Caches must wrap this storage, so they hold the
+/// names in clear, and [`crate::storage::SegmentedSnapshotStorage`] must be its backend,
+/// so segments are built from the encrypted snapshot; segments written through this
+/// storage fail with [`StorageError::SegmentsNotSupported`]. Reading a repository with
+/// another key, or one written without obfuscation, fails with
+/// [`StorageError::NotDecryptable`].
+#[derive(Debug)]
+pub struct ObfuscatedStorage {
+    backend: DynStorage,
+    cipher: NameCipher,
+}
+
+impl ObfuscatedStorage {
+    pub fn new(backend: DynStorage, key: &[u8]) -> Self {
+        Self { backend, cipher: NameCipher::new(key) }
+    }
+
+    /// The key of the ref in the backend, only the ref name, before the first `/`, is
+    /// encrypted, so the versions of a ref stay together
+    fn seal_ref_key(&self, ref_key: &str) -> String {
+        match ref_key.split_once('/') {
+            Some((name, version)) => {
+                format!("{}/{}", self.cipher.seal_name(name), version)
+            }
+            None => self.cipher.seal_name(ref_key),
+        }
+    }
+
+    fn seal_path(&self, path: &Path) -> Result<Path, PathError> {
+        Path::new(
+            format!("/{}", self.cipher.seal_name(path.to_string().as_str())).as_str(),
+        )
+    }
+
+    fn open_path(&self, sealed: &Path) -> Option<Path> {
+        let name = sealed.to_string();
+        Path::new(self.cipher.open_name(&name[1..])?.as_str()).ok()
+    }
+
+    fn seal_properties(&self, properties: &SnapshotProperties) -> SnapshotProperties {
+        if properties.is_empty() {
+            return SnapshotProperties::new();
+        }
+        // serializing a map of JSON values can't fail
+        let plain = serde_json::to_vec(properties).unwrap_or_default();
+        let sealed =
+            base32::encode(base32::Alphabet::Crockford, &self.cipher.seal(&plain));
+        SnapshotProperties::from_iter([(
+            SEALED_PROPERTIES_PROPERTY.to_string(),
+            sealed.into(),
+        )])
+    }
+
+    fn open_properties(&self, sealed: &SnapshotProperties) -> Option<SnapshotProperties> {
+        if sealed.is_empty() {
+            return Some(SnapshotProperties::new());
+        }
+        let value = sealed.get(SEALED_PROPERTIES_PROPERTY)?.as_str()?;
+        if sealed.len() != 1 {
+            return None;
+        }
+        let bytes = base32::decode(base32::Alphabet::Crockford, value)?;
+        serde_json::from_slice(&self.cipher.open(&bytes)?).ok()
+    }
+
+    fn seal_snapshot(&self, snapshot: &Snapshot) -> Result<Arc<Snapshot>, PathError> {
+        let nodes = snapshot
+            .iter()
+            .map(|node| {
+                Ok(NodeSnapshot { path: self.seal_path(&node.path)?, ..node.clone() })
+            })
+            .collect::<Result<Vec<_>, PathError>>()?;
+        let mut sealed = snapshot.with_nodes(nodes);
+        sealed.properties = self.seal_properties(&snapshot.properties);
+        Ok(Arc::new(sealed))
+    }
+
+    fn open_snapshot(&self, snapshot: &Snapshot) -> Option<Arc<Snapshot>> {
+        let nodes = snapshot
+            .iter()
+            .map(|node| {
+                Some(NodeSnapshot { path: self.open_path(&node.path)?, ..node.clone() })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let mut opened = snapshot.with_nodes(nodes);
+        opened.properties = self.open_properties(&snapshot.properties)?;
+        Some(Arc::new(opened))
+    }
+}
+
+fn not_obfuscable(id: &SnapshotId, err: PathError) -> StorageError {
+    StorageError::Other(format!("cannot obfuscate the paths of snapshot {id}: {err}"))
+}
+
+fn not_decryptable(id: &SnapshotId) -> StorageError {
+    StorageError::NotDecryptable(format!("snapshot {id}"))
+}
+
+impl private::Sealed for ObfuscatedStorage {}
+
+#[async_trait]
+impl Storage for ObfuscatedStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let snapshot = self.backend.fetch_snapshot(id).await?;
+        self.open_snapshot(snapshot.as_ref()).ok_or_else(|| not_decryptable(id))
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        match self.backend.fetch_snapshot_if_modified(id, etag).await? {
+            Conditional::NotModified => Ok(Conditional::NotModified),
+            Conditional::Modified(snapshot, etag) => {
+                let snapshot = self
+                    .open_snapshot(snapshot.as_ref())
+                    .ok_or_else(|| not_decryptable(id))?;
+                Ok(Conditional::Modified(snapshot, etag))
+            }
+        }
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        // a path too long to obfuscate is not in the snapshot
+        let Ok(sealed) = self.seal_path(path) else { return Ok(None) };
+        Ok(self
+            .backend
+            .fetch_snapshot_node(id, &sealed)
+            .await?
+            .map(|node| NodeSnapshot { path: path.clone(), ..node }))
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let sealed =
+            self.seal_snapshot(table.as_ref()).map_err(|err| not_obfuscable(&id, err))?;
+        self.backend.write_snapshot(id, sealed).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let sealed =
+            self.seal_snapshot(table.as_ref()).map_err(|err| not_obfuscable(&id, err))?;
+        self.backend.write_snapshot_if_absent(id, sealed).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        let bytes = match self.backend.get_ref(&self.seal_ref_key(ref_key)).await {
+            Ok(bytes) => bytes,
+            Err(StorageError::RefNotFound(_)) => {
+                return Err(StorageError::RefNotFound(ref_key.to_string()))
+            }
+            Err(err) => return Err(err),
+        };
+        self.cipher
+            .open(&bytes)
+            .map(Bytes::from)
+            .ok_or_else(|| StorageError::NotDecryptable(ref_key.to_string()))
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        let mut res = Vec::new();
+        for name in self.backend.ref_names().await? {
+            match self.cipher.open_name(&name) {
+                Some(name) => res.push(name),
+                None => return Err(StorageError::NotDecryptable(name)),
+            }
+        }
+        Ok(res)
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        // versions are ordered names, they stay in clear
+        self.backend.ref_versions(&self.cipher.seal_name(ref_name)).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let sealed = Bytes::from(self.cipher.seal(&bytes));
+        match self
+            .backend
+            .write_ref(&self.seal_ref_key(ref_key), overwrite_refs, sealed)
+            .await
+        {
+            Err(StorageError::RefAlreadyExists(_)) => {
+                Err(StorageError::RefAlreadyExists(ref_key.to_string()))
+            }
+            res => res,
+        }
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        if prefix == REF_PREFIX {
+            let ids = ids.map(|ref_key| self.seal_ref_key(&ref_key));
+            self.backend.delete_objects(prefix, ids.boxed()).await
+        } else {
+            self.backend.delete_objects(prefix, ids).await
+        }
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    // segments written here would have their paths in clear
+    async fn fetch_snapshot_segments(
+        &self,
+        _id: &SnapshotId,
+        _range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        Err(StorageError::SegmentsNotSupported)
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        _id: SnapshotId,
+        _bytes: Bytes,
+    ) -> StorageResult<()> {
+        Err(StorageError::SegmentsNotSupported)
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashSet, error::Error};
+
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::UserAttributes,
+        refs::{fetch_branch_tip, list_refs, Ref},
+        ObjectStorage, Repository,
+    };
+
+    #[test]
+    fn test_names_roundtrip() {
+        let cipher = NameCipher::new(b"secret");
+        let sealed = cipher.seal_name("branch.main");
+        assert_eq!(sealed, cipher.seal_name("branch.main"));
+        assert_ne!(sealed, cipher.seal_name("branch.dev"));
+        assert_eq!(cipher.open_name(&sealed).as_deref(), Some("branch.main"));
+        assert_eq!(NameCipher::new(b"other").open_name(&sealed), None);
+        assert_eq!(cipher.open_name("branch.main"), None);
+    }
+
+    #[test]
+    fn test_known_answers() {
+        // computed independently from the construction in the module documentation
+        let cipher = NameCipher::new(b"0123456789abcdef0123456789abcdef");
+        let cases = [
+            ("", "MSDMZKQM809B8SBK77ECTD120R"),
+            ("branch.main", "GCPEFS770B2KS5DJ1GAA7P421X4RX36ZFP7VYXC6N6W0"),
+            // longer than one keystream block
+            (
+                "/patients/smith/visits/2024-03-01/blood-pressure",
+                "JEAV56V35B67AHY5E4G6VG7RYYB3XRQJ099220P27NB3W2FYB5GF1JYE62HENT0GSCHBPDMPEQQQKVYPV91DRRFCXT0A2M5QX2BXBM0",
+            ),
+        ];
+        for (name, sealed) in cases {
+            assert_eq!(cipher.seal_name(name), sealed);
+            assert_eq!(cipher.open_name(sealed).as_deref(), Some(name));
+            assert_eq!(cipher.seal(name.as_bytes()).len(), SIV_LENGTH + name.len());
+        }
+    }
+
+    #[test]
+    fn test_tampered_names_dont_open() {
+        let cipher = NameCipher::new(b"secret");
+        let sealed = cipher.seal(b"/patients/smith/visits/2024-03-01/blood-pressure");
+        for index in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert_eq!(cipher.open(&tampered), None, "byte {index}");
+        }
+        assert_eq!(cipher.open(&sealed[..sealed.len() - 1]), None);
+        assert_eq!(cipher.open(&sealed[..SIV_LENGTH - 1]), None);
+        assert!(cipher.open(&sealed).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_obfuscated_repository() -> Result<(), Box<dyn Error>> {
+        let backend: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage: DynStorage =
+            Arc::new(ObfuscatedStorage::new(Arc::clone(&backend), b"secret"));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.add_group("/patients".try_into()?).await?;
+        ds.add_group("/patients/smith".try_into()?).await?;
+        let snapshot = ds.commit("main", "first", None).await?;
+        ds.new_branch("trial-42").await?;
+        ds.tag("v1", &snapshot).await?;
+
+        // queryable with the key
+        let refs: HashSet<_> = list_refs(storage.as_ref()).await?.into_iter().collect();
+        assert_eq!(
+            refs,
+            HashSet::from([
+                Ref::Branch("main".to_string()),
+                Ref::Branch("trial-42".to_string()),
+                Ref::Tag("v1".to_string()),
+            ])
+        );
+        assert_eq!(
+            fetch_branch_tip(storage.as_ref(), "trial-42").await?.snapshot,
+            snapshot
+        );
+        let ds = Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        let paths: Vec<_> =
+            ds.list_nodes().await?.map(|node| node.path.to_string()).collect();
+        assert_eq!(paths, ["/", "/patients", "/patients/smith"]);
+        assert!(ds.get_node(&"/patients/smith".try_into()?).await.is_ok());
+
+        // the bucket reveals no names
+        for name in backend.ref_names().await? {
+            assert!(
+                !name.contains("main") && !name.contains("trial") && !name.contains("v1")
+            );
+            let versions: Vec<_> =
+                backend.ref_versions(&name).await?.try_collect().await?;
+            for version in versions {
+                let content = backend.get_ref(&format!("{}/{}", name, version)).await?;
+                assert!(
+                    !String::from_utf8_lossy(&content).contains(&snapshot.to_string())
+                );
+            }
+        }
+        let stored = backend.fetch_snapshot(&snapshot).await?;
+        assert_eq!(stored.len(), 3);
+        assert!(stored.iter().all(|node| !node.path.to_string().contains("patients")
+            && node.path.parent() == Some(Path::root())));
+
+        // another key can't read it
+        let other = ObfuscatedStorage::new(Arc::clone(&backend), b"other");
+        assert!(matches!(other.ref_names().await, Err(StorageError::NotDecryptable(_))));
+        assert!(matches!(
+            other.fetch_snapshot(&snapshot).await,
+            Err(StorageError::NotDecryptable(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_objects_have_no_paths() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let backend: DynStorage = Arc::new(ObjectStorage::new_local_store(dir.path())?);
+        let storage: DynStorage =
+            Arc::new(ObfuscatedStorage::new(Arc::clone(&backend), b"secret"));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_indexed_attribute("kind")
+            .build();
+        let smith: Path = "/patients/smith".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_group("/patients".try_into()?).await?;
+        ds.add_group(smith.clone()).await?;
+        ds.set_user_attributes(
+            smith.clone(),
+            Some(UserAttributes::try_new(br#"{"kind":"record"}"#)?),
+        )
+        .await?;
+        ds.put_document(smith.clone(), "consent", "text/plain", Bytes::from("signed"))
+            .await?;
+        let properties = SnapshotProperties::from_iter([(
+            "reviewed".to_string(),
+            "/patients/smith".into(),
+        )]);
+        let snapshot = ds.commit("main", "first", Some(properties)).await?;
+
+        // the properties, keyed by path, read back with the key
+        let ds = Repository::update(Arc::clone(&storage), snapshot.clone()).build();
+        assert_eq!(ds.find_by_attribute("kind", &"record".into()).await?, vec![smith]);
+        assert_eq!(
+            ds.get_document(&"/patients/smith".try_into()?, "consent").await?,
+            Bytes::from("signed")
+        );
+        let stored = backend.fetch_snapshot(&snapshot).await?;
+        assert_eq!(
+            stored.properties.keys().collect::<Vec<_>>(),
+            vec![SEALED_PROPERTIES_PROPERTY]
+        );
+
+        let mut files = vec![dir.path().to_path_buf()];
+        while let Some(file) = files.pop() {
+            if file.is_dir() {
+                files
+                    .extend(std::fs::read_dir(&file)?.map(|entry| entry.unwrap().path()));
+                continue;
+            }
+            let content = std::fs::read(&file)?;
+            let relative = file.strip_prefix(dir.path())?.display().to_string();
+            for name in ["patients", "smith"] {
+                assert!(!relative.contains(name), "{relative}");
+                assert!(
+                    !content.windows(name.len()).any(|window| window == name.as_bytes()),
+                    "{name} in {relative}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
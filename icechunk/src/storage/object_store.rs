@@ -1,10 +1,17 @@
 use crate::{
     format::{
-        attributes::AttributesTable, format_constants, manifest::Manifest,
-        snapshot::Snapshot, transaction_log::TransactionLog, AttributesId, ByteRange,
-        ChunkId, FileTypeTag, ManifestId, ObjectId, SnapshotId,
+        attributes::AttributesTable,
+        format_constants,
+        framing::{unframe, FrameKind},
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        manifest_columns::ColumnarManifest,
+        snapshot::Snapshot,
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, FileTypeTag, ManifestId, NodeId,
+        ObjectId, SnapshotId,
     },
     private,
+    template::{default_branch_in, SETTINGS_KEY},
 };
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -29,8 +36,10 @@ use std::{
 };
 
 use super::{
-    ListInfo, Storage, StorageError, StorageResult, CHUNK_PREFIX, MANIFEST_PREFIX,
-    REF_PREFIX, SNAPSHOT_PREFIX, TRANSACTION_PREFIX,
+    deserialize_metadata, serialize_metadata, Conditional, ETag, ListInfo, ObjectKind,
+    Storage, StorageCapabilities, StorageError, StorageResult, ATTRIBUTES_PREFIX,
+    CHUNK_PREFIX, DELETE_BATCH_SIZE, DELETE_CONCURRENCY, MANIFEST_PREFIX, REF_PREFIX,
+    SNAPSHOT_PREFIX, SNAPSHOT_SEGMENTS_PREFIX, TRANSACTION_PREFIX,
 };
 
 // Get Range is object_store specific, keep it with this module
@@ -47,6 +56,23 @@ impl From<&ByteRange> for Option<GetRange> {
     }
 }
 
+/// How the local filesystem store makes its writes durable, each level includes the
+/// previous ones
+///
+/// Objects and refs are always written to a temporary file first, and moved in place once
+/// complete, so readers never see partial writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Durability {
+    /// Leave flushing to the operating system, recent writes can be lost on power failure
+    #[default]
+    None,
+    /// Flush every object and ref to disk before the write returns
+    SyncFiles,
+    /// Also flush the object directories before every ref update, and the ref directory
+    /// after it, so after a power failure refs never point to lost objects
+    SyncDirectories,
+}
+
 #[derive(Debug)]
 pub struct ObjectStorage {
     store: Arc<dyn ObjectStore>,
@@ -57,6 +83,10 @@ pub struct ObjectStorage {
 
     supports_create_if_not_exists: bool,
     supports_metadata: bool,
+    framed_metadata: bool,
+    /// Set only for the local filesystem store, to find the files to sync
+    local: Option<Arc<LocalFileSystem>>,
+    durability: Durability,
 }
 
 impl ObjectStorage {
@@ -73,6 +103,9 @@ impl ObjectStorage {
             artificially_sort_refs_in_mem: false,
             supports_create_if_not_exists: true,
             supports_metadata: true,
+            framed_metadata: false,
+            local: None,
+            durability: Durability::None,
         }
     }
 
@@ -84,14 +117,102 @@ impl ObjectStorage {
         let prefix = prefix.display().to_string();
         let store = Arc::new(LocalFileSystem::new_with_prefix(prefix.clone())?);
         Ok(ObjectStorage {
-            store,
+            store: Arc::clone(&store) as Arc<dyn ObjectStore>,
             prefix: "".to_string(),
             artificially_sort_refs_in_mem: true,
             supports_create_if_not_exists: true,
             supports_metadata: false,
+            framed_metadata: false,
+            local: Some(store),
+            durability: Durability::None,
         })
     }
 
+    /// A storage over any `object_store` implementation, with its objects under `prefix`
+    ///
+    /// This is how backends without a constructor of their own are used, for example, the
+    /// services of OpenDAL, through its `object_store_opendal` adapter. The store is
+    /// assumed to support conditional writes and object attributes, see
+    /// [`ObjectStorage::with_conditional_writes`] and
+    /// [`ObjectStorage::with_object_attributes`]. Refs are sorted in memory, stores don't
+    /// all list keys in order.
+    pub fn new_with_store(
+        store: Arc<dyn ObjectStore>,
+        prefix: impl Into<String>,
+    ) -> ObjectStorage {
+        ObjectStorage {
+            store,
+            prefix: prefix.into(),
+            artificially_sort_refs_in_mem: true,
+            supports_create_if_not_exists: true,
+            supports_metadata: true,
+            framed_metadata: false,
+            local: None,
+            durability: Durability::None,
+        }
+    }
+
+    /// Whether the store can write an object only if it doesn't exist
+    ///
+    /// Without them, refs are overwritten, and concurrent commits to a branch can lose
+    /// each other's updates.
+    pub fn with_conditional_writes(mut self, supported: bool) -> Self {
+        self.supports_create_if_not_exists = supported;
+        self
+    }
+
+    /// Whether the store can save attributes, like the content type, with the objects
+    pub fn with_object_attributes(mut self, supported: bool) -> Self {
+        self.supports_metadata = supported;
+        self
+    }
+
+    /// Flush writes to disk as `durability` requires, only the local filesystem store
+    /// supports it
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Flush the file or directory at `path` to disk, if the durability level asks for it
+    async fn sync(&self, path: &ObjectPath, level: Durability) -> StorageResult<()> {
+        let Some(local) = self.local.as_ref().filter(|_| self.durability >= level) else {
+            return Ok(());
+        };
+        let path = local.path_to_filesystem(path)?;
+        tokio::task::spawn_blocking(move || match std::fs::File::open(&path) {
+            Ok(file) => file.sync_all(),
+            // directories are created with the first object in them
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        })
+        .await
+        .map_err(|err| StorageError::Other(err.to_string()))??;
+        Ok(())
+    }
+
+    async fn sync_object_dirs(&self) -> StorageResult<()> {
+        for prefix in [
+            SNAPSHOT_PREFIX,
+            MANIFEST_PREFIX,
+            ATTRIBUTES_PREFIX,
+            TRANSACTION_PREFIX,
+            CHUNK_PREFIX,
+        ] {
+            let dir = ObjectPath::from(format!("{}/{}", self.prefix, prefix));
+            self.sync(&dir, Durability::SyncDirectories).await?;
+        }
+        Ok(())
+    }
+
+    /// Write snapshots, manifests and transaction logs in checksummed frames
+    ///
+    /// Framed and unframed objects can always be read.
+    pub fn with_framed_metadata(mut self, framed: bool) -> Self {
+        self.framed_metadata = framed;
+        self
+    }
+
     /// Return all keys in the store
     ///
     /// Intended for testing and debugging purposes only.
@@ -104,6 +225,36 @@ impl ObjectStorage {
             .await?)
     }
 
+    /// List the repositories stored directly under the prefix of this storage
+    ///
+    /// Returns the prefixes of the repositories, relative to the prefix of this storage. A
+    /// prefix holds a repository if it has a default branch, the one of its settings, see
+    /// [`crate::template::default_branch`].
+    pub async fn list_repositories(&self) -> StorageResult<Vec<String>> {
+        let root = ObjectPath::from(self.prefix.as_str());
+        let children = self.store.list_with_delimiter(Some(&root)).await?.common_prefixes;
+        let mut res = Vec::new();
+        for child in children {
+            let refs = child.child(REF_PREFIX);
+            let settings = ObjectPath::from(format!("{refs}/{SETTINGS_KEY}"));
+            let settings = match self.store.get(&settings).await {
+                Ok(settings) => Some(settings.bytes().await?),
+                Err(::object_store::Error::NotFound { .. }) => None,
+                Err(err) => return Err(err.into()),
+            };
+            let default_branch =
+                default_branch_in(settings.as_deref()).map_err(StorageError::Other)?;
+            let branch = refs.child(format!("branch.{default_branch}").as_str());
+            let versions = self.store.list_with_delimiter(Some(&branch)).await?;
+            if !versions.objects.is_empty() {
+                if let Some(name) = self.drop_prefix(&root, &child) {
+                    res.push(name.to_string());
+                }
+            }
+        }
+        Ok(res)
+    }
+
     fn get_path_str(&self, file_prefix: &str, id: &str) -> ObjectPath {
         let path = format!("{}/{}/{}", self.prefix, file_prefix, id);
         ObjectPath::from(path)
@@ -122,10 +273,18 @@ impl ObjectStorage {
         self.get_path(SNAPSHOT_PREFIX, id)
     }
 
+    fn get_snapshot_segments_path(&self, id: &SnapshotId) -> ObjectPath {
+        self.get_path(SNAPSHOT_SEGMENTS_PREFIX, id)
+    }
+
     fn get_manifest_path(&self, id: &ManifestId) -> ObjectPath {
         self.get_path(MANIFEST_PREFIX, id)
     }
 
+    fn get_attributes_path(&self, id: &AttributesId) -> ObjectPath {
+        self.get_path(ATTRIBUTES_PREFIX, id)
+    }
+
     fn get_transaction_path(&self, id: &SnapshotId) -> ObjectPath {
         self.get_path(TRANSACTION_PREFIX, id)
     }
@@ -134,6 +293,24 @@ impl ObjectStorage {
         self.get_path(CHUNK_PREFIX, id)
     }
 
+    /// The object and its ETag, `None` if its ETag is still `etag`
+    async fn get_if_modified(
+        &self,
+        path: &ObjectPath,
+        etag: Option<&str>,
+    ) -> StorageResult<Option<(Bytes, Option<ETag>)>> {
+        let options =
+            GetOptions { if_none_match: etag.map(str::to_string), ..Default::default() };
+        match self.store.get_opts(path, options).await {
+            Ok(res) => {
+                let etag = res.meta.e_tag.clone();
+                Ok(Some((res.bytes().await?, etag)))
+            }
+            Err(::object_store::Error::NotModified { .. }) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     fn drop_prefix(&self, prefix: &ObjectPath, path: &ObjectPath) -> Option<ObjectPath> {
         path.prefix_match(&ObjectPath::from(format!("{}", prefix))).map(|it| it.collect())
     }
@@ -160,15 +337,122 @@ impl ObjectStorage {
             .boxed()
     }
 
+    async fn put_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+        only_if_absent: bool,
+    ) -> StorageResult<()> {
+        let path = self.get_snapshot_path(&id);
+        let bytes = serialize_metadata(
+            FrameKind::Snapshot,
+            snapshot.icechunk_snapshot_format_version,
+            snapshot.as_ref(),
+            self.framed_metadata,
+        )?;
+        let attributes = if self.supports_metadata {
+            Attributes::from_iter(vec![
+                (
+                    Attribute::ContentType,
+                    AttributeValue::from(
+                        format_constants::LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE,
+                    ),
+                ),
+                (
+                    Attribute::Metadata(std::borrow::Cow::Borrowed(
+                        format_constants::LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY,
+                    )),
+                    AttributeValue::from(
+                        snapshot.icechunk_snapshot_format_version.to_string(),
+                    ),
+                ),
+            ])
+        } else {
+            Attributes::new()
+        };
+        self.put_metadata(&path, bytes, attributes, only_if_absent).await
+    }
+
+    async fn put_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+        only_if_absent: bool,
+    ) -> StorageResult<()> {
+        let path = self.get_manifest_path(&id);
+        let bytes = serialize_metadata(
+            FrameKind::Manifest,
+            manifest.icechunk_manifest_format_version,
+            manifest.as_ref(),
+            self.framed_metadata,
+        )?;
+        let attributes = if self.supports_metadata {
+            Attributes::from_iter(vec![
+                (
+                    Attribute::ContentType,
+                    AttributeValue::from(
+                        format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE,
+                    ),
+                ),
+                (
+                    Attribute::Metadata(std::borrow::Cow::Borrowed(
+                        format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
+                    )),
+                    AttributeValue::from(
+                        manifest.icechunk_manifest_format_version.to_string(),
+                    ),
+                ),
+            ])
+        } else {
+            Attributes::new()
+        };
+        self.put_metadata(&path, bytes, attributes, only_if_absent).await
+    }
+
+    async fn put_metadata(
+        &self,
+        path: &ObjectPath,
+        bytes: Vec<u8>,
+        attributes: Attributes,
+        only_if_absent: bool,
+    ) -> StorageResult<()> {
+        let mode = if only_if_absent && self.supports_create_if_not_exists {
+            PutMode::Create
+        } else {
+            if only_if_absent && self.store.head(path).await.is_ok() {
+                // racy, but the best we can do without conditional writes
+                return Err(StorageError::ObjectAlreadyExists(path.to_string()));
+            }
+            PutMode::Overwrite
+        };
+        let options = PutOptions { mode, attributes, ..PutOptions::default() };
+        // FIXME: use multipart
+        self.store.put_opts(path, bytes.into(), options).await.map_err(|e| match e {
+            object_store::Error::AlreadyExists { path, .. } => {
+                StorageError::ObjectAlreadyExists(path)
+            }
+            _ => e.into(),
+        })?;
+        self.sync(path, Durability::SyncFiles).await
+    }
+
     async fn delete_batch(
         &self,
         prefix: &str,
         batch: Vec<String>,
     ) -> StorageResult<usize> {
         let keys = batch.iter().map(|id| Ok(self.get_path_str(prefix, id)));
-        let results = self.store.delete_stream(stream::iter(keys).boxed());
-        // FIXME: flag errors instead of skipping them
-        Ok(results.filter(|res| ready(res.is_ok())).count().await)
+        let mut results = self.store.delete_stream(stream::iter(keys).boxed());
+        let mut deleted = 0;
+        while let Some(res) = results.next().await {
+            match res {
+                Ok(_) => deleted += 1,
+                // deleting an object that doesn't exist is not an error
+                Err(object_store::Error::NotFound { .. }) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(deleted)
     }
 }
 
@@ -182,16 +466,46 @@ impl Storage for ObjectStorage {
     ) -> Result<Arc<Snapshot>, StorageError> {
         let path = self.get_snapshot_path(id);
         let bytes = self.store.get(&path).await?.bytes().await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+        Ok(Arc::new(deserialize_metadata(FrameKind::Snapshot, &bytes)?))
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        let path = self.get_snapshot_path(id);
+        Ok(match self.get_if_modified(&path, etag).await? {
+            Some((bytes, etag)) => Conditional::Modified(
+                Arc::new(deserialize_metadata(FrameKind::Snapshot, &bytes)?),
+                etag,
+            ),
+            None => Conditional::NotModified,
+        })
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        let path = self.get_manifest_path(id);
+        Ok(match self.get_if_modified(&path, etag).await? {
+            Some((bytes, etag)) => Conditional::Modified(
+                Arc::new(deserialize_metadata(FrameKind::Manifest, &bytes)?),
+                etag,
+            ),
+            None => Conditional::NotModified,
+        })
     }
 
     async fn fetch_attributes(
         &self,
-        _id: &AttributesId,
+        id: &AttributesId,
     ) -> Result<Arc<AttributesTable>, StorageError> {
-        todo!();
+        let path = self.get_attributes_path(id);
+        let bytes = self.store.get(&path).await?.bytes().await?;
+        Ok(Arc::new(deserialize_metadata(FrameKind::Attributes, &bytes)?))
     }
 
     async fn fetch_manifests(
@@ -200,9 +514,20 @@ impl Storage for ObjectStorage {
     ) -> Result<Arc<Manifest>, StorageError> {
         let path = self.get_manifest_path(id);
         let bytes = self.store.get(&path).await?.bytes().await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+        Ok(Arc::new(deserialize_metadata(FrameKind::Manifest, &bytes)?))
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let path = self.get_manifest_path(id);
+        let bytes = self.store.get(&path).await?.bytes().await?;
+        let columns =
+            ColumnarManifest::from_slice(unframe(FrameKind::Manifest, &bytes)?)?;
+        Ok(columns.select(node, extents))
     }
 
     async fn fetch_transaction_log(
@@ -211,9 +536,7 @@ impl Storage for ObjectStorage {
     ) -> StorageResult<Arc<TransactionLog>> {
         let path = self.get_transaction_path(id);
         let bytes = self.store.get(&path).await?.bytes().await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+        Ok(Arc::new(deserialize_metadata(FrameKind::TransactionLog, &bytes)?))
     }
 
     async fn write_snapshot(
@@ -221,73 +544,66 @@ impl Storage for ObjectStorage {
         id: SnapshotId,
         snapshot: Arc<Snapshot>,
     ) -> Result<(), StorageError> {
-        let path = self.get_snapshot_path(&id);
-        let bytes = rmp_serde::to_vec(snapshot.as_ref())?;
+        self.put_snapshot(id, snapshot, false).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.put_snapshot(id, snapshot, true).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> Result<(), StorageError> {
+        let path = self.get_attributes_path(&id);
+        let bytes = serialize_metadata(
+            FrameKind::Attributes,
+            table.icechunk_attributes_format_version,
+            table.as_ref(),
+            self.framed_metadata,
+        )?;
         let attributes = if self.supports_metadata {
             Attributes::from_iter(vec![
                 (
                     Attribute::ContentType,
                     AttributeValue::from(
-                        format_constants::LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE,
+                        format_constants::LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE,
                     ),
                 ),
                 (
                     Attribute::Metadata(std::borrow::Cow::Borrowed(
-                        format_constants::LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY,
+                        format_constants::LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY,
                     )),
                     AttributeValue::from(
-                        snapshot.icechunk_snapshot_format_version.to_string(),
+                        table.icechunk_attributes_format_version.to_string(),
                     ),
                 ),
             ])
         } else {
             Attributes::new()
         };
-        let options = PutOptions { attributes, ..PutOptions::default() };
-        // FIXME: use multipart
-        self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+        self.put_metadata(&path, bytes, attributes, false).await
     }
 
-    async fn write_attributes(
+    async fn write_manifests(
         &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
     ) -> Result<(), StorageError> {
-        todo!()
+        self.put_manifests(id, manifest, false).await
     }
 
-    async fn write_manifests(
+    async fn write_manifests_if_absent(
         &self,
         id: ManifestId,
         manifest: Arc<Manifest>,
-    ) -> Result<(), StorageError> {
-        let path = self.get_manifest_path(&id);
-        let bytes = rmp_serde::to_vec(manifest.as_ref())?;
-        let attributes = if self.supports_metadata {
-            Attributes::from_iter(vec![
-                (
-                    Attribute::ContentType,
-                    AttributeValue::from(
-                        format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE,
-                    ),
-                ),
-                (
-                    Attribute::Metadata(std::borrow::Cow::Borrowed(
-                        format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
-                    )),
-                    AttributeValue::from(
-                        manifest.icechunk_manifest_format_version.to_string(),
-                    ),
-                ),
-            ])
-        } else {
-            Attributes::new()
-        };
-        let options = PutOptions { attributes, ..PutOptions::default() };
-        // FIXME: use multipart
-        self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+    ) -> StorageResult<()> {
+        self.put_manifests(id, manifest, true).await
     }
 
     async fn write_transaction_log(
@@ -296,7 +612,12 @@ impl Storage for ObjectStorage {
         log: Arc<TransactionLog>,
     ) -> StorageResult<()> {
         let path = self.get_transaction_path(&id);
-        let bytes = rmp_serde::to_vec(log.as_ref())?;
+        let bytes = serialize_metadata(
+            FrameKind::TransactionLog,
+            log.icechunk_transaction_log_format_version,
+            log.as_ref(),
+            self.framed_metadata,
+        )?;
         let attributes = if self.supports_metadata {
             Attributes::from_iter(vec![
                 (
@@ -320,7 +641,28 @@ impl Storage for ObjectStorage {
         let options = PutOptions { attributes, ..PutOptions::default() };
         // FIXME: use multipart
         self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+        self.sync(&path, Durability::SyncFiles).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let path = self.get_snapshot_segments_path(id);
+        let options =
+            GetOptions { range: Option::<GetRange>::from(range), ..Default::default() };
+        Ok(self.store.get_opts(&path, options).await?.bytes().await?)
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let path = self.get_snapshot_segments_path(&id);
+        self.store.put(&path, bytes.into()).await?;
+        self.sync(&path, Durability::SyncFiles).await
     }
 
     async fn fetch_chunk(
@@ -348,7 +690,7 @@ impl Storage for ObjectStorage {
         let mut write = object_store::WriteMultipart::new(upload);
         write.write(&bytes);
         write.finish().await?;
-        Ok(())
+        self.sync(&path, Durability::SyncFiles).await
     }
 
     async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
@@ -412,16 +754,21 @@ impl Storage for ObjectStorage {
         };
         let opts = PutOptions { mode, ..PutOptions::default() };
 
-        self.store
-            .put_opts(&key, PutPayload::from_bytes(bytes), opts)
-            .await
-            .map_err(|e| match e {
+        // the objects the ref points to must be durable before the ref is
+        self.sync_object_dirs().await?;
+        self.store.put_opts(&key, PutPayload::from_bytes(bytes), opts).await.map_err(
+            |e| match e {
                 object_store::Error::AlreadyExists { path, .. } => {
                     StorageError::RefAlreadyExists(path)
                 }
                 _ => e.into(),
-            })
-            .map(|_| ())
+            },
+        )?;
+        self.sync(&key, Durability::SyncFiles).await?;
+        // a new branch also adds its directory to the refs directory
+        let dir = ref_key.rsplit_once('/').map(|(dir, _)| dir).unwrap_or_default();
+        self.sync(&self.ref_key(dir), Durability::SyncDirectories).await?;
+        self.sync(&self.ref_key(""), Durability::SyncDirectories).await
     }
 
     async fn list_objects<'a>(
@@ -444,23 +791,259 @@ impl Storage for ObjectStorage {
         ids: BoxStream<'_, String>,
     ) -> StorageResult<usize> {
         let deleted = AtomicUsize::new(0);
-        ids.chunks(1_000)
-            // FIXME: configurable concurrency
-            .for_each_concurrent(10, |batch| {
+        ids.chunks(DELETE_BATCH_SIZE)
+            .map(Ok::<_, StorageError>)
+            .try_for_each_concurrent(DELETE_CONCURRENCY, |batch| {
                 let deleted = &deleted;
                 async move {
-                    // FIXME: handle error instead of skipping
-                    let new_deletes = self.delete_batch(prefix, batch).await.unwrap_or(0);
+                    let new_deletes = self.delete_batch(prefix, batch).await?;
                     deleted.fetch_add(new_deletes, Ordering::Release);
+                    Ok(())
                 }
             })
-            .await;
+            .await?;
         Ok(deleted.into_inner())
     }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        let from = self.get_path_str(kind.prefix(), from_id);
+        let to = self.get_path_str(kind.prefix(), to_id);
+        self.store.copy(&from, &to).await?;
+        self.sync(&to, Durability::SyncFiles).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities {
+            conditional_writes: self.supports_create_if_not_exists,
+            server_side_copy: true,
+            // the in-memory and local stores delete objects one by one
+            batch_delete: false,
+            presigning: false,
+            listing: true,
+        }
+    }
+
+    fn chunk_url(&self, _shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        // only the local store has URLs, in memory objects can't be referenced
+        let local = self.local.as_ref()?;
+        let path = local.path_to_filesystem(&self.get_chunk_path(id)).ok()?;
+        url::Url::from_file_path(path).ok().map(String::from)
+    }
 }
 
 fn object_to_list_info(object: &ObjectMeta) -> Option<ListInfo<String>> {
     let created_at = object.last_modified;
     let id = object.location.filename()?.to_string();
-    Some(ListInfo { id, created_at })
+    let size_bytes = object.size as u64;
+    Some(ListInfo { id, created_at, size_bytes })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::framing::FramingError, template::RepositoryTemplate};
+
+    #[tokio::test]
+    async fn test_delete_many_objects() -> Result<(), Box<dyn Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let ids: Vec<_> = (0..2_500).map(|_| ChunkId::random()).collect();
+        for id in ids.iter() {
+            storage.write_chunk(id.clone(), Bytes::from_static(b"chunk")).await?;
+        }
+
+        let to_delete = stream::iter(ids.clone()).boxed();
+        assert_eq!(storage.delete_chunks(to_delete).await?, 2_500);
+        assert_eq!(storage.list_chunks().await?.count().await, 0);
+
+        // deleting objects that don't exist is not an error
+        storage.delete_chunks(stream::iter(ids).boxed()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_objects_of_kind() -> Result<(), Box<dyn Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let ids: Vec<_> = (0..10).map(|_| ChunkId::random()).collect();
+        for (size, id) in ids.iter().enumerate() {
+            storage.write_chunk(id.clone(), Bytes::from(vec![0; size])).await?;
+        }
+        storage.write_snapshot(SnapshotId::random(), Arc::new(Snapshot::empty())).await?;
+
+        let mut listed: Vec<_> = storage
+            .list_objects_of_kind(ObjectKind::Chunk, "")
+            .await?
+            .map_ok(|info| (info.id, info.size_bytes))
+            .try_collect()
+            .await?;
+        listed.sort();
+        let mut expected: Vec<_> = ids
+            .iter()
+            .enumerate()
+            .map(|(size, id)| (id.to_string(), size as u64))
+            .collect();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        let id_prefix = &ids[0].to_string()[0..4];
+        let listed: Vec<_> = storage
+            .list_objects_of_kind(ObjectKind::Chunk, id_prefix)
+            .await?
+            .try_collect()
+            .await?;
+        assert!(!listed.is_empty());
+        assert!(listed.iter().all(|info| info.id.starts_with(id_prefix)));
+
+        assert_eq!(
+            storage.list_objects_of_kind(ObjectKind::Snapshot, "").await?.count().await,
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_object() -> Result<(), Box<dyn Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let (from, to) = (ChunkId::random(), ChunkId::random());
+        storage.write_chunk(from.clone(), Bytes::from_static(b"chunk")).await?;
+        storage
+            .copy_object(ObjectKind::Chunk, &from.to_string(), &to.to_string())
+            .await?;
+        assert_eq!(storage.fetch_chunk(&to, &ByteRange::ALL).await?, "chunk");
+        assert_eq!(storage.fetch_chunk(&from, &ByteRange::ALL).await?, "chunk");
+
+        let missing = ChunkId::random().to_string();
+        let res = storage.copy_object(ObjectKind::Chunk, &missing, &missing).await;
+        assert!(res.is_err_and(|err| err.is_not_found()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_if_absent() -> Result<(), Box<dyn Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None);
+        let id = SnapshotId::random();
+        let snapshot = Arc::new(Snapshot::empty());
+        storage.write_snapshot_if_absent(id.clone(), Arc::clone(&snapshot)).await?;
+        assert!(matches!(
+            storage
+                .write_snapshot_if_absent(id.clone(), Arc::new(Snapshot::empty()))
+                .await,
+            Err(StorageError::ObjectAlreadyExists(_))
+        ));
+        assert_eq!(storage.fetch_snapshot(&id).await?, snapshot);
+
+        let id = ManifestId::random();
+        storage.write_manifests_if_absent(id.clone(), Arc::default()).await?;
+        assert!(matches!(
+            storage.write_manifests_if_absent(id, Arc::default()).await,
+            Err(StorageError::ObjectAlreadyExists(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_durable_local_writes() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(
+            ObjectStorage::new_local_store(dir.path())?
+                .with_durability(Durability::SyncDirectories),
+        );
+        // the first ref update happens before any chunk directory exists
+        let mut repo =
+            crate::Repository::init(Arc::clone(&storage), false).await?.build();
+        repo.add_group(crate::format::Path::root()).await?;
+        storage.write_chunk(ChunkId::random(), Bytes::from_static(b"chunk")).await?;
+        let snapshot = repo.commit("main", "durable", None).await?;
+
+        let reopened = ObjectStorage::new_local_store(dir.path())?;
+        let tip = crate::refs::fetch_branch_tip(&reopened, "main").await?;
+        assert_eq!(tip.snapshot, snapshot);
+        assert!(reopened.fetch_snapshot(&snapshot).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_framed_metadata() -> Result<(), Box<dyn Error>> {
+        let storage = ObjectStorage::new_in_memory_store(None).with_framed_metadata(true);
+        let id = SnapshotId::random();
+        let snapshot = Arc::new(Snapshot::empty());
+        storage.write_snapshot(id.clone(), Arc::clone(&snapshot)).await?;
+        let path = storage.get_snapshot_path(&id);
+        let bytes = storage.store.get(&path).await?.bytes().await?;
+        assert!(crate::format::framing::is_framed(&bytes));
+        assert_eq!(storage.fetch_snapshot(&id).await?, snapshot);
+
+        // framing doesn't need to be enabled to read framed objects
+        let plain = ObjectStorage::new_with_store(Arc::clone(&storage.store), "");
+        assert_eq!(plain.fetch_snapshot(&id).await?, snapshot);
+
+        let truncated = bytes.slice(..bytes.len() - 1);
+        storage.store.put(&path, truncated.into()).await?;
+        assert!(matches!(
+            storage.fetch_snapshot(&id).await,
+            Err(StorageError::Framing(FramingError::Truncated {
+                kind: FrameKind::Snapshot,
+                ..
+            }))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_over_any_store() -> Result<(), Box<dyn Error>> {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(
+            ObjectStorage::new_with_store(Arc::clone(&store), "repo")
+                .with_object_attributes(false),
+        );
+        let mut ds = crate::Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(crate::format::Path::root()).await?;
+        let snapshot = ds.commit("main", "root", None).await?;
+
+        // the objects are under the prefix, and the repository can be opened again
+        let keys: Vec<String> =
+            store.list(None).map_ok(|obj| obj.location.to_string()).try_collect().await?;
+        assert!(keys.iter().all(|key| key.starts_with("repo/")));
+        let reopened: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_with_store(store, "repo"));
+        assert_eq!(
+            crate::refs::fetch_branch_tip(reopened.as_ref(), "main").await?.snapshot,
+            snapshot
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_repositories() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        for name in ["repo-a", "nested", "repo-b", "repo-trunk"] {
+            let storage: Arc<dyn Storage + Send + Sync> =
+                Arc::new(ObjectStorage::new_local_store(&dir.path().join(name))?);
+            if name == "nested" {
+                // not a repository, it only has chunks
+                storage.write_chunk(ChunkId::random(), Bytes::from_static(b"x")).await?;
+            } else if name == "repo-trunk" {
+                // without a main branch
+                let template = RepositoryTemplate::new().with_default_branch("trunk");
+                crate::Repository::init_with_template(storage, false, &template).await?;
+            } else {
+                crate::Repository::init(storage, false).await?;
+            }
+        }
+
+        let storage = ObjectStorage::new_local_store(dir.path())?;
+        let mut repos = storage.list_repositories().await?;
+        repos.sort();
+        assert_eq!(repos, vec!["repo-a", "repo-b", "repo-trunk"]);
+        Ok(())
+    }
 }
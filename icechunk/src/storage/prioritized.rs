@@ -0,0 +1,560 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use tokio::sync::oneshot;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+    tunables::TunablesHandle,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageResult,
+};
+
+/// How urgent a storage request is, requests waiting for a [`RequestScheduler`] slot are
+/// sent most urgent first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequestPriority {
+    /// Somebody is waiting for the result, like a dashboard
+    Interactive = 0,
+    Normal = 1,
+    /// Prefetch, garbage collection, tiering and other maintenance
+    Background = 2,
+}
+
+impl RequestPriority {
+    const ALL: [RequestPriority; 3] = [
+        RequestPriority::Interactive,
+        RequestPriority::Normal,
+        RequestPriority::Background,
+    ];
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    running: usize,
+    /// Waiting requests, by priority, oldest first
+    waiting: [VecDeque<oneshot::Sender<RequestPermit>>; 3],
+}
+
+impl SchedulerState {
+    /// The most urgent waiting request
+    fn pop_next(&mut self) -> Option<(RequestPriority, oneshot::Sender<RequestPermit>)> {
+        RequestPriority::ALL.into_iter().find_map(|priority| {
+            self.waiting[priority as usize].pop_front().map(|sender| (priority, sender))
+        })
+    }
+}
+
+/// Limits the storage requests in progress in the process, and hands the free slots to the
+/// most urgent waiting requests
+///
+/// Requests of the same priority are served in order. Priorities are strict: background
+/// requests only get a slot when no interactive or normal request is waiting, so they
+/// stall while interactive traffic saturates the storage.
+#[derive(Debug)]
+pub struct RequestScheduler {
+    max_concurrent: AtomicUsize,
+    /// Updates `max_concurrent`, see [`RequestScheduler::with_tunables`]
+    tunables: Option<TunablesHandle>,
+    applied_version: AtomicU64,
+    state: Mutex<SchedulerState>,
+    /// Requests that got a slot, by priority
+    served: [AtomicU64; 3],
+}
+
+/// A slot of the [`RequestScheduler`], freed on drop
+#[derive(Debug)]
+pub struct RequestPermit {
+    scheduler: Option<Arc<RequestScheduler>>,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release();
+        }
+    }
+}
+
+impl RequestScheduler {
+    /// A scheduler for at most `max_concurrent` requests at a time, at least one
+    pub fn new(max_concurrent: usize) -> Arc<Self> {
+        Self::build(max_concurrent, None)
+    }
+
+    /// A scheduler that follows [`crate::tunables::Tunables::max_concurrent_requests`],
+    /// with `max_concurrent` while it's not set
+    ///
+    /// A new limit applies from the next request acquired or released. When it goes down,
+    /// the requests in progress finish first.
+    pub fn with_tunables(max_concurrent: usize, tunables: TunablesHandle) -> Arc<Self> {
+        Self::build(max_concurrent, Some(tunables))
+    }
+
+    fn build(max_concurrent: usize, tunables: Option<TunablesHandle>) -> Arc<Self> {
+        let scheduler = Self {
+            max_concurrent: AtomicUsize::new(max_concurrent.max(1)),
+            applied_version: AtomicU64::new(u64::MAX),
+            tunables,
+            state: Mutex::new(SchedulerState::default()),
+            served: Default::default(),
+        };
+        scheduler.limit();
+        Arc::new(scheduler)
+    }
+
+    /// The maximum number of requests in progress, after applying the latest tunables
+    pub fn limit(&self) -> usize {
+        if let Some(tunables) = &self.tunables {
+            let version = tunables.version();
+            if self.applied_version.swap(version, Ordering::AcqRel) != version {
+                if let Some(max) = tunables.get().max_concurrent_requests {
+                    self.max_concurrent.store(max.max(1), Ordering::Release);
+                }
+            }
+        }
+        self.max_concurrent.load(Ordering::Acquire)
+    }
+
+    /// Wait for a free slot, after the waiting requests with the same or higher priority
+    pub async fn acquire(self: &Arc<Self>, priority: RequestPriority) -> RequestPermit {
+        let limit = self.limit();
+        let receiver = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            if state.running < limit && state.waiting.iter().all(VecDeque::is_empty) {
+                state.running += 1;
+                self.served[priority as usize].fetch_add(1, Ordering::Relaxed);
+                return RequestPermit { scheduler: Some(Arc::clone(self)) };
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiting[priority as usize].push_back(sender);
+            // slots added by raising the limit go to the most urgent requests
+            while state.running < limit {
+                let Some((priority, sender)) = state.pop_next() else { break };
+                match sender.send(RequestPermit { scheduler: Some(Arc::clone(self)) }) {
+                    Ok(()) => {
+                        state.running += 1;
+                        self.served[priority as usize].fetch_add(1, Ordering::Relaxed);
+                    }
+                    // the state is locked, the permit must not release its slot
+                    Err(mut unsent) => unsent.scheduler = None,
+                }
+            }
+            receiver
+        };
+        // the sender is only dropped after sending, a permit in a dropped receiver frees
+        // its slot
+        receiver.await.unwrap_or(RequestPermit { scheduler: None })
+    }
+
+    /// Requests waiting for a slot
+    pub fn queued(&self) -> usize {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.waiting.iter().map(VecDeque::len).sum()
+    }
+
+    /// Requests that got a slot with `priority`
+    pub fn served(&self, priority: RequestPriority) -> u64 {
+        self.served[priority as usize].load(Ordering::Relaxed)
+    }
+
+    /// Hand the slot of a dropped permit to the most urgent waiting request
+    fn release(self: Arc<Self>) {
+        let limit = self.limit();
+        let mut permit = RequestPermit { scheduler: Some(Arc::clone(&self)) };
+        loop {
+            let next = {
+                let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+                // the limit went down, this slot goes away
+                let next = if state.running > limit { None } else { state.pop_next() };
+                if next.is_none() {
+                    state.running -= 1;
+                    // the slot is free, the permit must not release it again
+                    permit.scheduler = None;
+                }
+                next
+            };
+            let Some((priority, sender)) = next else { return };
+            match sender.send(permit) {
+                Ok(()) => {
+                    self.served[priority as usize].fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                // the request was cancelled while waiting
+                Err(unsent) => permit = unsent,
+            }
+        }
+    }
+}
+
+/// A [`Storage`] whose requests take a slot of a shared [`RequestScheduler`] first, with
+/// a priority
+///
+/// Handles for each kind of work share the scheduler, see
+/// [`PrioritizedStorage::with_priority`]: the storage of the interactive sessions gets
+/// [`RequestPriority::Interactive`], the one passed to garbage collection or tiering
+/// [`RequestPriority::Background`], so the maintenance doesn't slow down the readers.
+/// Listings only hold the slot until the stream is returned.
+#[derive(Debug, Clone)]
+pub struct PrioritizedStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    scheduler: Arc<RequestScheduler>,
+    priority: RequestPriority,
+}
+
+impl PrioritizedStorage {
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        scheduler: Arc<RequestScheduler>,
+        priority: RequestPriority,
+    ) -> Self {
+        Self { backend, scheduler, priority }
+    }
+
+    /// A handle to the same backend and scheduler, sending its requests with `priority`
+    pub fn with_priority(&self, priority: RequestPriority) -> Self {
+        Self { priority, ..self.clone() }
+    }
+
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+
+    pub fn scheduler(&self) -> &Arc<RequestScheduler> {
+        &self.scheduler
+    }
+
+    async fn permit(&self) -> RequestPermit {
+        self.scheduler.acquire(self.priority).await
+    }
+}
+
+impl private::Sealed for PrioritizedStorage {}
+
+#[async_trait]
+impl Storage for PrioritizedStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let _permit = self.permit().await;
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let _permit = self.permit().await;
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let _permit = self.permit().await;
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        let _permit = self.permit().await;
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        let _permit = self.permit().await;
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        let _permit = self.permit().await;
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        let _permit = self.permit().await;
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        let _permit = self.permit().await;
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        let _permit = self.permit().await;
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        let _permit = self.permit().await;
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::ObjectStorage;
+
+    async fn wait_queued(scheduler: &RequestScheduler, queued: usize) {
+        while scheduler.queued() < queued {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interactive_requests_go_first() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let scheduler = RequestScheduler::new(1);
+        let background = PrioritizedStorage::new(
+            Arc::clone(&backend),
+            Arc::clone(&scheduler),
+            RequestPriority::Background,
+        );
+        let interactive = background.with_priority(RequestPriority::Interactive);
+        let id = ChunkId::random();
+        interactive.write_chunk(id.clone(), Bytes::from_static(b"data")).await?;
+
+        let busy = scheduler.acquire(RequestPriority::Background).await;
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let fetch = |storage: PrioritizedStorage, name: &'static str| {
+            let (order, id) = (Arc::clone(&order), id.clone());
+            tokio::spawn(async move {
+                storage.fetch_chunk(&id, &ByteRange::ALL).await.unwrap();
+                order.lock().unwrap().push(name);
+            })
+        };
+        let prefetch = fetch(background.clone(), "prefetch");
+        wait_queued(&scheduler, 1).await;
+        let gc = fetch(background.clone(), "gc");
+        wait_queued(&scheduler, 2).await;
+        let dashboard = fetch(interactive.clone(), "dashboard");
+        wait_queued(&scheduler, 3).await;
+        // a cancelled request doesn't keep its slot
+        let cancelled = fetch(interactive.clone(), "cancelled");
+        wait_queued(&scheduler, 4).await;
+        cancelled.abort();
+        assert!(cancelled.await.unwrap_err().is_cancelled());
+
+        drop(busy);
+        for task in [prefetch, gc, dashboard] {
+            task.await?;
+        }
+        assert_eq!(*order.lock().unwrap(), vec!["dashboard", "prefetch", "gc"]);
+        assert_eq!(scheduler.served(RequestPriority::Interactive), 2);
+        assert_eq!(scheduler.served(RequestPriority::Background), 3);
+        assert_eq!(scheduler.queued(), 0);
+        // all slots are free again
+        let _permit = scheduler.acquire(RequestPriority::Background).await;
+        Ok(())
+    }
+}
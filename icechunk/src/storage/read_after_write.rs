@@ -0,0 +1,493 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt};
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+    runtime::{default_runtime, DynRuntime},
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult,
+};
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+/// A [`Storage`] for backends that don't guarantee read-after-write consistency
+///
+/// Snapshots, manifests, attributes, transaction logs and chunks are immutable, and only
+/// read once something references them, so a read that doesn't find one is retried, up to
+/// `max_retries` times, waiting `retry_delay` and then doubling it. Writes of snapshots,
+/// manifests, attributes and transaction logs read the object back, with the same retries,
+/// before completing. This way a commit doesn't move a ref to a snapshot other clients
+/// can't read yet, at the cost of one extra read per metadata object written. If the
+/// object is still missing, the write fails with [`StorageError::NotVisible`].
+///
+/// Refs are not retried: a missing ref is a normal answer, and ref updates rely on the
+/// conditional writes of the backend.
+#[derive(Debug)]
+pub struct ReadAfterWriteStorage {
+    backend: DynStorage,
+    max_retries: u32,
+    retry_delay: Duration,
+    runtime: DynRuntime,
+}
+
+impl ReadAfterWriteStorage {
+    pub fn new(backend: DynStorage) -> Self {
+        Self {
+            backend,
+            max_retries: 5,
+            retry_delay: Duration::from_millis(50),
+            runtime: default_runtime(),
+        }
+    }
+
+    pub fn with_retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Use `runtime` to wait between retries, instead of tokio
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    async fn read<T>(
+        &self,
+        op: impl Fn(DynStorage) -> BoxFuture<'static, StorageResult<T>>,
+    ) -> StorageResult<T> {
+        let mut delay = self.retry_delay;
+        for _ in 0..self.max_retries {
+            match op(Arc::clone(&self.backend)).await {
+                Err(err) if err.is_not_found() => {
+                    self.runtime.sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                }
+                res => return res,
+            }
+        }
+        op(Arc::clone(&self.backend)).await
+    }
+
+    async fn verify<T>(
+        &self,
+        description: String,
+        op: impl Fn(DynStorage) -> BoxFuture<'static, StorageResult<T>>,
+    ) -> StorageResult<()> {
+        match self.read(op).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.is_not_found() => Err(StorageError::NotVisible(description)),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn verify_snapshot(&self, id: SnapshotId) -> StorageResult<()> {
+        self.verify(format!("snapshot {id}"), |s| {
+            let id = id.clone();
+            async move { s.fetch_snapshot(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn verify_manifest(&self, id: ManifestId) -> StorageResult<()> {
+        self.verify(format!("manifest {id}"), |s| {
+            let id = id.clone();
+            async move { s.fetch_manifests(&id).await }.boxed()
+        })
+        .await
+    }
+}
+
+impl private::Sealed for ReadAfterWriteStorage {}
+
+#[async_trait]
+impl Storage for ReadAfterWriteStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_snapshot(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_attributes(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_manifests(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let (id, node, extents) = (id.clone(), node.clone(), extents.clone());
+        self.read(|s| {
+            let (id, node, extents) = (id.clone(), node.clone(), extents.clone());
+            async move { s.fetch_manifest_region(&id, &node, &extents).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        let id = id.clone();
+        let etag = etag.map(str::to_string);
+        self.read(|s| {
+            let id = id.clone();
+            let etag = etag.clone();
+            async move { s.fetch_snapshot_if_modified(&id, etag.as_deref()).await }
+                .boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        let id = id.clone();
+        let etag = etag.map(str::to_string);
+        self.read(|s| {
+            let id = id.clone();
+            let etag = etag.clone();
+            async move { s.fetch_manifests_if_modified(&id, etag.as_deref()).await }
+                .boxed()
+        })
+        .await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let id = id.clone();
+        let range = range.clone();
+        self.read(|s| {
+            let id = id.clone();
+            let range = range.clone();
+            async move { s.fetch_chunk(&id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let shard = shard.to_string();
+        let id = id.clone();
+        let range = range.clone();
+        self.read(|s| {
+            let shard = shard.clone();
+            let id = id.clone();
+            let range = range.clone();
+            async move { s.fetch_chunk_from(&shard, &id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_transaction_log(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id.clone(), table).await?;
+        self.verify_snapshot(id).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id.clone(), table).await?;
+        self.verify_snapshot(id).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id.clone(), table).await?;
+        self.verify(format!("attributes {id}"), |s| {
+            let id = id.clone();
+            async move { s.fetch_attributes(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id.clone(), table).await?;
+        self.verify_manifest(id).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id.clone(), table).await?;
+        self.verify_manifest(id).await
+    }
+
+    /// Chunks are not read back, the snapshots that reference them are only published
+    /// after the verified metadata writes, and chunk reads are retried
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id.clone(), log).await?;
+        self.verify(format!("transaction log {id}"), |s| {
+            let id = id.clone();
+            async move { s.fetch_transaction_log(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        repository::{
+            get_chunk, ChunkIndices, ChunkKeyEncoding, ChunkShape, DataType, FillValue,
+            Path, ZarrArrayMetadata,
+        },
+        storage::logging::LoggingStorage,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_reads_and_writes_wait_for_visibility() -> Result<(), Box<dyn Error>> {
+        let backend = Arc::new(
+            LoggingStorage::new(Arc::new(ObjectStorage::new_in_memory_store(None)))
+                .with_lagging_reads(2),
+        );
+        let storage: DynStorage = Arc::new(
+            ReadAfterWriteStorage::new(backend.clone())
+                .with_retries(3, Duration::from_millis(1)),
+        );
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        ds.add_group(Path::root()).await?;
+        let path: Path = "/array".try_into()?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![1],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        let snapshot = ds.commit("main", "commit", None).await?;
+
+        // the snapshot was read back before the branch was updated
+        let snapshot_reads = backend
+            .fetch_operations()
+            .into_iter()
+            .filter(|(op, id)| op == "fetch_snapshot" && id == &snapshot.0.to_vec())
+            .count();
+        assert!(snapshot_reads >= 3);
+
+        let ds = Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        let chunk = get_chunk(
+            ds.get_chunk_reader(&path, &ChunkIndices(vec![0]), &ByteRange::ALL).await?,
+        )
+        .await?;
+        assert_eq!(chunk, Some(Bytes::from_static(b"hello")));
+
+        // the backend alone misses fresh objects
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"world")).await?;
+        assert!(backend.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, "world");
+
+        let impatient = ReadAfterWriteStorage::new(Arc::new(
+            LoggingStorage::new(Arc::new(ObjectStorage::new_in_memory_store(None)))
+                .with_lagging_reads(5),
+        ))
+        .with_retries(1, Duration::from_millis(1));
+        let id = SnapshotId::random();
+        assert!(matches!(
+            impatient.write_snapshot(id, Arc::new(Snapshot::empty())).await,
+            Err(StorageError::NotVisible(_))
+        ));
+        Ok(())
+    }
+}
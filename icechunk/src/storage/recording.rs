@@ -0,0 +1,825 @@
+//! Record the traffic of a storage to a file, and replay it later without the storage
+//!
+//! [`RecordingStorage`] sends every request to its backend and keeps the request with the
+//! full response, or the error, in a [`StorageTrace`]. A [`ReplayStorage`] answers from the
+//! trace only, so the same reads produce the same results on any machine, which makes a
+//! trace saved with [`StorageTrace::save`] a reproducible attachment for a bug report about
+//! inconsistent reads.
+//!
+//! Requests are matched by operation and arguments, the responses to the same request are
+//! replayed in the order they were recorded. Replay doesn't write anything, writes only
+//! get their recorded result, and sessions that create new ids don't replay. Errors keep
+//! their kind and message, not their source. Listings are collected when they are
+//! requested, an error in the middle of a listing fails the request.
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path as FilePath,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ETag, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageErrorKind, StorageResult,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum RecordedError {
+    RefNotFound(String),
+    RefAlreadyExists(String),
+    ObjectAlreadyExists(String),
+    NotFound(String),
+    Other(String),
+}
+
+impl From<&StorageError> for RecordedError {
+    fn from(err: &StorageError) -> Self {
+        match err {
+            StorageError::Shared(err) => err.as_ref().into(),
+            StorageError::RefNotFound(name) => Self::RefNotFound(name.clone()),
+            StorageError::RefAlreadyExists(name) => Self::RefAlreadyExists(name.clone()),
+            StorageError::ObjectAlreadyExists(id) => {
+                Self::ObjectAlreadyExists(id.clone())
+            }
+            err if err.kind() == StorageErrorKind::NotFound => {
+                Self::NotFound(err.to_string())
+            }
+            err => Self::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<RecordedError> for StorageError {
+    fn from(err: RecordedError) -> Self {
+        match err {
+            RecordedError::RefNotFound(name) => StorageError::RefNotFound(name),
+            RecordedError::RefAlreadyExists(name) => StorageError::RefAlreadyExists(name),
+            RecordedError::ObjectAlreadyExists(id) => {
+                StorageError::ObjectAlreadyExists(id)
+            }
+            RecordedError::NotFound(message) => {
+                StorageError::ObjectStore(::object_store::Error::NotFound {
+                    path: message,
+                    source: "recorded as not found".into(),
+                })
+            }
+            RecordedError::Other(message) => StorageError::Other(message),
+        }
+    }
+}
+
+/// A storage request and what it got
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedCall {
+    /// The operation and its arguments, like `fetch_chunk <id> <range>`
+    pub request: String,
+    /// The response serialized with messagepack
+    response: Result<Bytes, RecordedError>,
+}
+
+impl RecordedCall {
+    pub fn is_error(&self) -> bool {
+        self.response.is_err()
+    }
+}
+
+/// The requests recorded by a [`RecordingStorage`], in the order they completed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StorageTrace {
+    pub calls: Vec<RecordedCall>,
+}
+
+impl StorageTrace {
+    pub fn to_bytes(&self) -> StorageResult<Vec<u8>> {
+        Ok(rmp_serde::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> StorageResult<Self> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<FilePath>) -> StorageResult<()> {
+        Ok(std::fs::write(path, self.to_bytes()?)?)
+    }
+
+    pub fn load(path: impl AsRef<FilePath>) -> StorageResult<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+type RecordedListInfo = (String, DateTime<Utc>, u64);
+type RecordedConditional<T> = Option<(T, Option<ETag>)>;
+
+/// A [`Storage`] that records its requests and responses, see the
+/// [module documentation](self)
+#[derive(Debug)]
+pub struct RecordingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    trace: Mutex<StorageTrace>,
+}
+
+impl RecordingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { backend, trace: Mutex::new(StorageTrace::default()) }
+    }
+
+    /// The requests recorded so far
+    pub fn trace(&self) -> StorageTrace {
+        self.trace.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Save the requests recorded so far to the file at `path`
+    pub fn save(&self, path: impl AsRef<FilePath>) -> StorageResult<()> {
+        self.trace().save(path)
+    }
+
+    fn record<T: Serialize>(
+        &self,
+        request: String,
+        result: Result<&T, &StorageError>,
+    ) -> StorageResult<()> {
+        let response = match result {
+            Ok(value) => Ok(Bytes::from(rmp_serde::to_vec(value)?)),
+            Err(err) => Err(err.into()),
+        };
+        self.trace
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .calls
+            .push(RecordedCall { request, response });
+        Ok(())
+    }
+
+    /// Record the response of an object the backend returns shared
+    fn record_arc<T: Serialize>(
+        &self,
+        request: String,
+        result: StorageResult<Arc<T>>,
+    ) -> StorageResult<Arc<T>> {
+        self.record(request, result.as_ref().map(|value| value.as_ref()))?;
+        result
+    }
+
+    fn record_conditional<T: Serialize>(
+        &self,
+        request: String,
+        result: StorageResult<Conditional<Arc<T>>>,
+    ) -> StorageResult<Conditional<Arc<T>>> {
+        let recorded = result.as_ref().map(|conditional| match conditional {
+            Conditional::NotModified => None,
+            Conditional::Modified(value, etag) => Some((value.as_ref(), etag.clone())),
+        });
+        self.record(request, recorded.as_ref().map_err(|err| *err))?;
+        result
+    }
+
+    fn record_value<T: Serialize>(
+        &self,
+        request: String,
+        result: StorageResult<T>,
+    ) -> StorageResult<T> {
+        self.record(request, result.as_ref())?;
+        result
+    }
+}
+
+impl private::Sealed for RecordingStorage {}
+
+#[async_trait]
+impl Storage for RecordingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let result = self.backend.fetch_snapshot(id).await;
+        self.record_arc(format!("fetch_snapshot {id}"), result)
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        let result = self.backend.fetch_snapshot_if_modified(id, etag).await;
+        self.record_conditional(
+            format!("fetch_snapshot_if_modified {id} {etag:?}"),
+            result,
+        )
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        let result = self.backend.fetch_snapshot_node(id, path).await;
+        self.record_value(format!("fetch_snapshot_node {id} {path}"), result)
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let result = self.backend.fetch_snapshot_segments(id, range).await;
+        self.record_value(format!("fetch_snapshot_segments {id} {range:?}"), result)
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let result = self.backend.fetch_attributes(id).await;
+        self.record_arc(format!("fetch_attributes {id}"), result)
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let result = self.backend.fetch_manifests(id).await;
+        self.record_arc(format!("fetch_manifests {id}"), result)
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let result = self.backend.fetch_manifest_region(id, node, extents).await;
+        self.record_value(
+            format!("fetch_manifest_region {id} {node} {extents:?}"),
+            result,
+        )
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        let result = self.backend.fetch_manifests_if_modified(id, etag).await;
+        self.record_conditional(
+            format!("fetch_manifests_if_modified {id} {etag:?}"),
+            result,
+        )
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let result = self.backend.fetch_chunk(id, range).await;
+        self.record_value(format!("fetch_chunk {id} {range:?}"), result)
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let result = self.backend.fetch_chunk_from(shard, id, range).await;
+        self.record_value(format!("fetch_chunk_from {shard} {id} {range:?}"), result)
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        let result = self.backend.presign_read(id, range, ttl).await;
+        self.record_value(format!("presign_read {id} {range:?} {ttl:?}"), result)
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        let result = self.backend.fetch_transaction_log(id).await;
+        self.record_arc(format!("fetch_transaction_log {id}"), result)
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let request = format!("write_snapshot {id}");
+        let result = self.backend.write_snapshot(id, table).await;
+        self.record_value(request, result)
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let request = format!("write_snapshot_segments {id}");
+        let result = self.backend.write_snapshot_segments(id, bytes).await;
+        self.record_value(request, result)
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let request = format!("write_snapshot_if_absent {id}");
+        let result = self.backend.write_snapshot_if_absent(id, table).await;
+        self.record_value(request, result)
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        let request = format!("write_attributes {id}");
+        let result = self.backend.write_attributes(id, table).await;
+        self.record_value(request, result)
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let request = format!("write_manifests {id}");
+        let result = self.backend.write_manifests(id, table).await;
+        self.record_value(request, result)
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let request = format!("write_manifests_if_absent {id}");
+        let result = self.backend.write_manifests_if_absent(id, table).await;
+        self.record_value(request, result)
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        let request = format!("write_chunk {id}");
+        let result = self.backend.write_chunk(id, bytes).await;
+        self.record_value(request, result)
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        let request = format!("write_transaction_log {id}");
+        let result = self.backend.write_transaction_log(id, log).await;
+        self.record_value(request, result)
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        let result = self.backend.get_ref(ref_key).await;
+        self.record_value(format!("get_ref {ref_key}"), result)
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        let result = self.backend.ref_names().await;
+        self.record_value("ref_names".to_string(), result)
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        let result = match self.backend.ref_versions(ref_name).await {
+            Ok(versions) => versions.try_collect::<Vec<_>>().await,
+            Err(err) => Err(err),
+        };
+        let versions = self.record_value(format!("ref_versions {ref_name}"), result)?;
+        Ok(stream::iter(versions.into_iter().map(Ok)).boxed())
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let result = self.backend.write_ref(ref_key, overwrite_refs, bytes).await;
+        self.record_value(format!("write_ref {ref_key} {overwrite_refs}"), result)
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        let result = match self.backend.list_objects(prefix).await {
+            Ok(objects) => {
+                objects
+                    .map_ok(|info| (info.id, info.created_at, info.size_bytes))
+                    .try_collect::<Vec<RecordedListInfo>>()
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        let objects = self.record_value(format!("list_objects {prefix}"), result)?;
+        Ok(stream::iter(objects.into_iter().map(list_info)).boxed())
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        let ids: Vec<String> = ids.collect().await;
+        let request = format!("delete_objects {prefix} {}", ids.join(","));
+        let result = self.backend.delete_objects(prefix, stream::iter(ids).boxed()).await;
+        self.record_value(request, result)
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        let result = self.backend.copy_object(kind, from_id, to_id).await;
+        self.record_value(format!("copy_object {kind:?} {from_id} {to_id}"), result)
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+fn list_info(
+    (id, created_at, size_bytes): RecordedListInfo,
+) -> StorageResult<ListInfo<String>> {
+    Ok(ListInfo { id, created_at, size_bytes })
+}
+
+/// A [`Storage`] that answers with the responses of a [`StorageTrace`], see the
+/// [module documentation](self)
+///
+/// Requests that are not in the trace, or were already answered as many times as they
+/// were recorded, fail with [`StorageError::NotRecorded`].
+#[derive(Debug)]
+pub struct ReplayStorage {
+    responses: Mutex<HashMap<String, VecDeque<Result<Bytes, RecordedError>>>>,
+}
+
+impl ReplayStorage {
+    pub fn new(trace: StorageTrace) -> Self {
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+        for call in trace.calls {
+            responses.entry(call.request).or_default().push_back(call.response);
+        }
+        Self { responses: Mutex::new(responses) }
+    }
+
+    /// Replay the trace saved at `path`
+    pub fn load(path: impl AsRef<FilePath>) -> StorageResult<Self> {
+        Ok(Self::new(StorageTrace::load(path)?))
+    }
+
+    /// Recorded responses that were not replayed yet
+    pub fn remaining(&self) -> usize {
+        let responses = self.responses.lock().unwrap_or_else(PoisonError::into_inner);
+        responses.values().map(VecDeque::len).sum()
+    }
+
+    fn replay<T: DeserializeOwned>(&self, request: String) -> StorageResult<T> {
+        let response = self
+            .responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&request)
+            .and_then(VecDeque::pop_front);
+        match response {
+            Some(Ok(bytes)) => Ok(rmp_serde::from_slice(&bytes)?),
+            Some(Err(err)) => Err(err.into()),
+            None => Err(StorageError::NotRecorded(request)),
+        }
+    }
+
+    fn replay_conditional<T: DeserializeOwned>(
+        &self,
+        request: String,
+    ) -> StorageResult<Conditional<Arc<T>>> {
+        Ok(match self.replay::<RecordedConditional<T>>(request)? {
+            None => Conditional::NotModified,
+            Some((value, etag)) => Conditional::Modified(Arc::new(value), etag),
+        })
+    }
+}
+
+impl private::Sealed for ReplayStorage {}
+
+#[async_trait]
+impl Storage for ReplayStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.replay(format!("fetch_snapshot {id}")).map(Arc::new)
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.replay_conditional(format!("fetch_snapshot_if_modified {id} {etag:?}"))
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.replay(format!("fetch_snapshot_node {id} {path}"))
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.replay(format!("fetch_snapshot_segments {id} {range:?}"))
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.replay(format!("fetch_attributes {id}")).map(Arc::new)
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.replay(format!("fetch_manifests {id}")).map(Arc::new)
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.replay(format!("fetch_manifest_region {id} {node} {extents:?}"))
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.replay_conditional(format!("fetch_manifests_if_modified {id} {etag:?}"))
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.replay(format!("fetch_chunk {id} {range:?}"))
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.replay(format!("fetch_chunk_from {shard} {id} {range:?}"))
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.replay(format!("presign_read {id} {range:?} {ttl:?}"))
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.replay(format!("fetch_transaction_log {id}")).map(Arc::new)
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        _table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_snapshot {id}"))
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        _bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_snapshot_segments {id}"))
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        _table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_snapshot_if_absent {id}"))
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        _table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_attributes {id}"))
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        _table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_manifests {id}"))
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        _table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_manifests_if_absent {id}"))
+    }
+
+    async fn write_chunk(&self, id: ChunkId, _bytes: Bytes) -> StorageResult<()> {
+        self.replay(format!("write_chunk {id}"))
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        _log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_transaction_log {id}"))
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.replay(format!("get_ref {ref_key}"))
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.replay("ref_names".to_string())
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        let versions: Vec<String> = self.replay(format!("ref_versions {ref_name}"))?;
+        Ok(stream::iter(versions.into_iter().map(Ok)).boxed())
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        _bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.replay(format!("write_ref {ref_key} {overwrite_refs}"))
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        let objects: Vec<RecordedListInfo> =
+            self.replay(format!("list_objects {prefix}"))?;
+        Ok(stream::iter(objects.into_iter().map(list_info)).boxed())
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        let ids: Vec<String> = ids.collect().await;
+        self.replay(format!("delete_objects {prefix} {}", ids.join(",")))
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.replay(format!("copy_object {kind:?} {from_id} {to_id}"))
+    }
+
+    // every operation is answered from the recording, including the unsupported ones
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities::all()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::ChunkIndices,
+        repository::get_chunk,
+        test_utils::{array_metadata, new_in_memory_storage},
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn test_record_and_replay() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> = new_in_memory_storage();
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        repo.add_array(path.clone(), array_metadata(vec![2])).await?;
+        let payload = repo.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        repo.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        repo.commit("main", "data", None).await?;
+
+        let read = |storage: Arc<dyn Storage + Send + Sync>| {
+            let path = path.clone();
+            async move {
+                let repo = Repository::from_branch_tip(storage, "main").await?.build();
+                let coords = ChunkIndices(vec![0]);
+                let chunk = get_chunk(
+                    repo.get_chunk_reader(&path, &coords, &ByteRange::ALL).await?,
+                )
+                .await?;
+                let missing = repo.get_node(&"/missing".try_into()?).await.is_err();
+                Ok::<_, Box<dyn Error>>((chunk, missing))
+            }
+        };
+        let recording = Arc::new(RecordingStorage::new(Arc::clone(&storage)));
+        let recorded =
+            read(Arc::clone(&recording) as Arc<dyn Storage + Send + Sync>).await?;
+        assert_eq!(recorded, (Some(Bytes::from_static(b"hello")), true));
+        let missing_ref = recording.get_ref("branch.missing/ZZZZZZZZ.json").await;
+        assert!(matches!(missing_ref, Err(StorageError::RefNotFound(_))));
+        assert!(recording
+            .trace()
+            .calls
+            .iter()
+            .any(|call| call.request.starts_with("fetch_chunk")));
+
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("trace.msgpack");
+        recording.save(&file)?;
+        let replay = Arc::new(ReplayStorage::load(&file)?);
+        let replayed =
+            read(Arc::clone(&replay) as Arc<dyn Storage + Send + Sync>).await?;
+        assert_eq!(replayed, recorded);
+        assert!(matches!(
+            replay.get_ref("branch.missing/ZZZZZZZZ.json").await,
+            Err(StorageError::RefNotFound(_))
+        ));
+        assert_eq!(replay.remaining(), 0);
+        // every response was consumed, the same request again is not in the trace
+        assert!(matches!(
+            replay.get_ref("branch.missing/ZZZZZZZZ.json").await,
+            Err(StorageError::NotRecorded(request)) if request.starts_with("get_ref")
+        ));
+        Ok(())
+    }
+}
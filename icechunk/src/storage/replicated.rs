@@ -0,0 +1,597 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    future::{BoxFuture, RemoteHandle},
+    stream::{BoxStream, FuturesUnordered},
+    FutureExt, StreamExt,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::Snapshot,
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+    runtime::{default_runtime, spawn, DynRuntime},
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult,
+};
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+#[derive(Debug)]
+struct Replica {
+    storage: DynStorage,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+/// A [`Storage`] that writes to a primary backend and copies every object to replicas
+///
+/// Writes complete when the primary has the object, copies to the replicas happen in the
+/// background. Reads of snapshots, manifests, chunks, attributes and transaction logs go
+/// to the replicas first, in the order they were passed, falling back to the next replica,
+/// and finally to the primary, when the object is not found or there is an error. A replica
+/// that fails with something other than a missing object is skipped for an
+/// `unhealthy_period`.
+///
+/// Refs are only read from and written to the primary, to maintain the consistency
+/// guarantees of commits.
+#[derive(Debug)]
+pub struct ReplicatedStorage {
+    primary: DynStorage,
+    replicas: Vec<Replica>,
+    unhealthy_period: Duration,
+    pending: Mutex<FuturesUnordered<RemoteHandle<StorageResult<()>>>>,
+    replication_errors: Mutex<Vec<StorageError>>,
+    runtime: DynRuntime,
+}
+
+impl ReplicatedStorage {
+    pub fn new(
+        primary: DynStorage,
+        replicas: Vec<DynStorage>,
+        unhealthy_period: Duration,
+    ) -> Self {
+        Self {
+            primary,
+            replicas: replicas
+                .into_iter()
+                .map(|storage| Replica { storage, unhealthy_until: Mutex::new(None) })
+                .collect(),
+            unhealthy_period,
+            pending: Mutex::new(FuturesUnordered::new()),
+            replication_errors: Mutex::new(Vec::new()),
+            runtime: default_runtime(),
+        }
+    }
+
+    /// Use `runtime` to run the copies to replicas, instead of tokio
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Wait until all pending copies to replicas finish.
+    ///
+    /// Returns the errors of the copies that failed since the last call.
+    pub async fn wait_for_replication(&self) -> Vec<StorageError> {
+        let mut pending = self.pending.lock().await;
+        while let Some(res) = pending.next().await {
+            self.record_replication_result(res).await;
+        }
+        std::mem::take(&mut *self.replication_errors.lock().await)
+    }
+
+    async fn record_replication_result(&self, res: StorageResult<()>) {
+        if let Err(err) = res {
+            self.replication_errors.lock().await.push(err);
+        }
+    }
+
+    async fn replicate(
+        &self,
+        op: impl Fn(DynStorage) -> BoxFuture<'static, StorageResult<()>>,
+    ) {
+        let mut pending = self.pending.lock().await;
+        // collect the copies already finished, so the set doesn't grow forever
+        while let Some(Some(res)) = pending.next().now_or_never() {
+            self.record_replication_result(res).await;
+        }
+        for replica in self.replicas.iter() {
+            pending.push(spawn(self.runtime.as_ref(), op(Arc::clone(&replica.storage))));
+        }
+    }
+
+    async fn read<T>(
+        &self,
+        op: impl Fn(DynStorage) -> BoxFuture<'static, StorageResult<T>>,
+    ) -> StorageResult<T> {
+        for replica in self.replicas.iter() {
+            {
+                let unhealthy_until = replica.unhealthy_until.lock().await;
+                if unhealthy_until.is_some_and(|until| until > Instant::now()) {
+                    continue;
+                }
+            }
+            match op(Arc::clone(&replica.storage)).await {
+                Ok(res) => return Ok(res),
+                // the object may not be replicated yet
+                Err(err) if err.is_not_found() => {}
+                Err(_) => {
+                    *replica.unhealthy_until.lock().await =
+                        Some(Instant::now() + self.unhealthy_period);
+                }
+            }
+        }
+        op(Arc::clone(&self.primary)).await
+    }
+}
+
+impl private::Sealed for ReplicatedStorage {}
+
+#[async_trait]
+impl Storage for ReplicatedStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_snapshot(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_attributes(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_manifests(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let (id, node, extents) = (id.clone(), node.clone(), extents.clone());
+        self.read(|s| {
+            let (id, node, extents) = (id.clone(), node.clone(), extents.clone());
+            async move { s.fetch_manifest_region(&id, &node, &extents).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        let id = id.clone();
+        let etag = etag.map(str::to_string);
+        self.read(|s| {
+            let id = id.clone();
+            let etag = etag.clone();
+            async move { s.fetch_snapshot_if_modified(&id, etag.as_deref()).await }
+                .boxed()
+        })
+        .await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        let id = id.clone();
+        let etag = etag.map(str::to_string);
+        self.read(|s| {
+            let id = id.clone();
+            let etag = etag.clone();
+            async move { s.fetch_manifests_if_modified(&id, etag.as_deref()).await }
+                .boxed()
+        })
+        .await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let id = id.clone();
+        let range = range.clone();
+        self.read(|s| {
+            let id = id.clone();
+            let range = range.clone();
+            async move { s.fetch_chunk(&id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let shard = shard.to_string();
+        let id = id.clone();
+        let range = range.clone();
+        self.read(|s| {
+            let shard = shard.clone();
+            let id = id.clone();
+            let range = range.clone();
+            async move { s.fetch_chunk_from(&shard, &id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        // we don't know if the object was already copied, so we can only presign from the
+        // primary
+        self.primary.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        let id = id.clone();
+        self.read(|s| {
+            let id = id.clone();
+            async move { s.fetch_transaction_log(&id).await }.boxed()
+        })
+        .await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.primary.write_snapshot(id.clone(), Arc::clone(&table)).await?;
+        self.replicate(|s| {
+            let (id, table) = (id.clone(), Arc::clone(&table));
+            async move { s.write_snapshot(id, table).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Only the primary checks the object is absent, replicas are copies of the primary
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.primary.write_snapshot_if_absent(id.clone(), Arc::clone(&table)).await?;
+        self.replicate(|s| {
+            let (id, table) = (id.clone(), Arc::clone(&table));
+            async move { s.write_snapshot(id, table).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.primary.write_attributes(id.clone(), Arc::clone(&table)).await?;
+        self.replicate(|s| {
+            let (id, table) = (id.clone(), Arc::clone(&table));
+            async move { s.write_attributes(id, table).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.primary.write_manifests(id.clone(), Arc::clone(&table)).await?;
+        self.replicate(|s| {
+            let (id, table) = (id.clone(), Arc::clone(&table));
+            async move { s.write_manifests(id, table).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Only the primary checks the object is absent, replicas are copies of the primary
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.primary.write_manifests_if_absent(id.clone(), Arc::clone(&table)).await?;
+        self.replicate(|s| {
+            let (id, table) = (id.clone(), Arc::clone(&table));
+            async move { s.write_manifests(id, table).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.primary.write_chunk(id.clone(), bytes.clone()).await?;
+        self.replicate(|s| {
+            let (id, bytes) = (id.clone(), bytes.clone());
+            async move { s.write_chunk(id, bytes).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.primary.write_transaction_log(id.clone(), Arc::clone(&log)).await?;
+        self.replicate(|s| {
+            let (id, log) = (id.clone(), Arc::clone(&log));
+            async move { s.write_transaction_log(id, log).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.primary.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.primary.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.primary.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.primary.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.primary.list_objects(prefix).await
+    }
+
+    /// Objects are deleted from the primary and every replica
+    ///
+    /// Returns the number of objects deleted from the primary, failures to delete from
+    /// replicas are reported by [`ReplicatedStorage::wait_for_replication`].
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        let ids: Vec<String> = ids.collect().await;
+        let deleted = self
+            .primary
+            .delete_objects(prefix, futures::stream::iter(ids.clone()).boxed())
+            .await?;
+        for replica in self.replicas.iter() {
+            let res = replica
+                .storage
+                .delete_objects(prefix, futures::stream::iter(ids.clone()).boxed())
+                .await;
+            if let Err(err) = res {
+                self.replication_errors.lock().await.push(err);
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.primary.copy_object(kind, from_id, to_id).await?;
+        let (from_id, to_id) = (from_id.to_string(), to_id.to_string());
+        self.replicate(|s| {
+            let (from_id, to_id) = (from_id.clone(), to_id.clone());
+            async move { s.copy_object(kind, &from_id, &to_id).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let (id, range) = (id.clone(), range.clone());
+        self.read(|s| {
+            let (id, range) = (id.clone(), range.clone());
+            async move { s.fetch_snapshot_segments(&id, &range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.primary.write_snapshot_segments(id.clone(), bytes.clone()).await?;
+        self.replicate(|s| {
+            let (id, bytes) = (id.clone(), bytes.clone());
+            async move { s.write_snapshot_segments(id, bytes).await }.boxed()
+        })
+        .await;
+        Ok(())
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.primary.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.primary.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.primary.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.primary.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.replicas.iter().fold(self.primary.capabilities(), |caps, replica| {
+            caps.intersection(replica.storage.capabilities())
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        runtime::tests::ThreadRuntime, storage::logging::LoggingStorage, ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_writes_are_replicated() -> Result<(), Box<dyn Error>> {
+        let primary: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let replica: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = ReplicatedStorage::new(
+            Arc::clone(&primary),
+            vec![Arc::clone(&replica)],
+            Duration::from_secs(60),
+        );
+
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        assert_eq!(primary.fetch_chunk(&id, &ByteRange::ALL).await?, "hello");
+        assert!(storage.wait_for_replication().await.is_empty());
+        assert_eq!(replica.fetch_chunk(&id, &ByteRange::ALL).await?, "hello");
+
+        let deleted = storage
+            .delete_chunks(futures::stream::iter(vec![id.clone()]).boxed())
+            .await?;
+        assert_eq!(deleted, 1);
+        assert!(primary.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+        assert!(replica.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replication_without_tokio() -> Result<(), Box<dyn Error>> {
+        futures::executor::block_on(async {
+            let primary: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+            let replica: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+            let storage = ReplicatedStorage::new(
+                Arc::clone(&primary),
+                vec![Arc::clone(&replica)],
+                Duration::from_secs(60),
+            )
+            .with_runtime(Arc::new(ThreadRuntime));
+
+            let id = SnapshotId::random();
+            let snapshot = Arc::new(Snapshot::empty());
+            storage.write_snapshot(id.clone(), Arc::clone(&snapshot)).await?;
+            assert!(storage.wait_for_replication().await.is_empty());
+            assert_eq!(replica.fetch_snapshot(&id).await?, snapshot);
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_reads_prefer_replicas_with_failover() -> Result<(), Box<dyn Error>> {
+        let primary = Arc::new(LoggingStorage::new(Arc::new(
+            ObjectStorage::new_in_memory_store(None),
+        )));
+        let replica = Arc::new(LoggingStorage::new(Arc::new(
+            ObjectStorage::new_in_memory_store(None),
+        )));
+        let storage = ReplicatedStorage::new(
+            primary.clone(),
+            vec![replica.clone()],
+            Duration::from_secs(60),
+        );
+
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        storage.wait_for_replication().await;
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, "hello");
+        assert_eq!(replica.fetch_operations().len(), 1);
+        assert!(primary.fetch_operations().is_empty());
+
+        // an object missing in the replica is read from the primary
+        let missing = ChunkId::random();
+        primary.write_chunk(missing.clone(), Bytes::from_static(b"world")).await?;
+        assert_eq!(storage.fetch_chunk(&missing, &ByteRange::ALL).await?, "world");
+        assert_eq!(replica.fetch_operations().len(), 2);
+        assert_eq!(primary.fetch_operations().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refs_only_use_primary() -> Result<(), Box<dyn Error>> {
+        let primary: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let replica: DynStorage = Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = ReplicatedStorage::new(
+            Arc::clone(&primary),
+            vec![Arc::clone(&replica)],
+            Duration::from_secs(60),
+        );
+        storage.write_ref("branch.main/0", false, Bytes::from_static(b"{}")).await?;
+        storage.wait_for_replication().await;
+        assert_eq!(storage.get_ref("branch.main/0").await?, "{}");
+        assert!(replica.ref_names().await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use quick_cache::sync::Cache;
+use serde::Serialize;
+
+use crate::{
+    format::{
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        transaction_log::TransactionLog, AttributesId, ByteRange, ChunkId, ManifestId,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ETag, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult,
+};
+
+/// The lookups of a [`RevalidatingStorage`] since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RevalidationStats {
+    /// Served from the cache without a request
+    pub hits: u64,
+    /// Revalidated with a conditional request, the object didn't change
+    pub not_modified: u64,
+    /// Downloaded, because they were not cached, or changed
+    pub downloads: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Validated<V> {
+    value: V,
+    etag: Option<ETag>,
+    validated_at: Instant,
+}
+
+#[derive(Debug)]
+struct ValidatedCache<K, V> {
+    cache: Cache<K, Validated<V>>,
+    max_age: Duration,
+    hits: AtomicU64,
+    not_modified: AtomicU64,
+    downloads: AtomicU64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ValidatedCache<K, V> {
+    fn new(capacity: u16, max_age: Duration) -> Self {
+        Self {
+            cache: Cache::new(capacity as usize),
+            max_age,
+            hits: AtomicU64::new(0),
+            not_modified: AtomicU64::new(0),
+            downloads: AtomicU64::new(0),
+        }
+    }
+
+    async fn get<Fut>(
+        &self,
+        key: &K,
+        fetch: impl FnOnce(Option<ETag>) -> Fut,
+    ) -> StorageResult<V>
+    where
+        Fut: Future<Output = StorageResult<Conditional<V>>>,
+    {
+        let cached = self.cache.get(key);
+        if let Some(entry) = cached.as_ref() {
+            if entry.validated_at.elapsed() < self.max_age {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(entry.value.clone());
+            }
+        }
+        let etag = cached.as_ref().and_then(|entry| entry.etag.clone());
+        let entry = match (fetch(etag).await?, cached) {
+            (Conditional::NotModified, Some(entry)) => {
+                self.not_modified.fetch_add(1, Ordering::Relaxed);
+                Validated { validated_at: Instant::now(), ..entry }
+            }
+            (Conditional::NotModified, None) => {
+                return Err(StorageError::Other(
+                    "storage reported an unchanged object that is not cached".to_string(),
+                ))
+            }
+            (Conditional::Modified(value, etag), _) => {
+                self.downloads.fetch_add(1, Ordering::Relaxed);
+                Validated { value, etag, validated_at: Instant::now() }
+            }
+        };
+        self.cache.insert(key.clone(), entry.clone());
+        Ok(entry.value)
+    }
+
+    fn stats(&self) -> RevalidationStats {
+        RevalidationStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            not_modified: self.not_modified.load(Ordering::Relaxed),
+            downloads: self.downloads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A [`Storage`] that caches snapshots and manifests for `max_age`, and then revalidates
+/// them with their ETag
+///
+/// For long lived services that must notice objects replaced in the bucket, by a restore
+/// from a backup for example, without downloading large manifests again every time. The
+/// backend must support conditional fetches, like [`super::ObjectStorage`] and
+/// [`super::s3::S3Storage`]; other storages download the object on every revalidation.
+#[derive(Debug)]
+pub struct RevalidatingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    snapshots: ValidatedCache<SnapshotId, Arc<Snapshot>>,
+    manifests: ValidatedCache<ManifestId, Arc<Manifest>>,
+}
+
+impl RevalidatingStorage {
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        num_snapshots: u16,
+        num_manifests: u16,
+        max_age: Duration,
+    ) -> Self {
+        Self {
+            backend,
+            snapshots: ValidatedCache::new(num_snapshots, max_age),
+            manifests: ValidatedCache::new(num_manifests, max_age),
+        }
+    }
+
+    pub fn snapshot_stats(&self) -> RevalidationStats {
+        self.snapshots.stats()
+    }
+
+    pub fn manifest_stats(&self) -> RevalidationStats {
+        self.manifests.stats()
+    }
+}
+
+impl private::Sealed for RevalidatingStorage {}
+
+#[async_trait]
+impl Storage for RevalidatingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.snapshots
+            .get(id, |etag| async move {
+                self.backend.fetch_snapshot_if_modified(id, etag.as_deref()).await
+            })
+            .await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.manifests
+            .get(id, |etag| async move {
+                self.backend.fetch_manifests_if_modified(id, etag.as_deref()).await
+            })
+            .await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, test_utils};
+
+    #[tokio::test]
+    async fn test_revalidating_storage() -> Result<(), Box<dyn Error>> {
+        let backend = test_utils::new_in_memory_storage();
+        let mut ds = test_utils::new_repository(Arc::clone(&backend)).await?;
+        ds.add_group(Path::root()).await?;
+        let id = ds.commit("main", "root", None).await?;
+
+        // the backend skips the download when the ETag didn't change
+        let Conditional::Modified(snapshot, Some(etag)) =
+            backend.fetch_snapshot_if_modified(&id, None).await?
+        else {
+            panic!("the in memory store reports ETags")
+        };
+        assert_eq!(snapshot.metadata.id, id);
+        assert_eq!(
+            backend.fetch_snapshot_if_modified(&id, Some(&etag)).await?,
+            Conditional::NotModified
+        );
+
+        let storage =
+            RevalidatingStorage::new(Arc::clone(&backend), 2, 2, Duration::ZERO);
+        for _ in 0..3 {
+            assert_eq!(storage.fetch_snapshot(&id).await?.metadata.id, id);
+        }
+        assert_eq!(
+            storage.snapshot_stats(),
+            RevalidationStats { hits: 0, not_modified: 2, downloads: 1 }
+        );
+
+        let storage = RevalidatingStorage::new(backend, 2, 2, Duration::from_secs(3600));
+        for _ in 0..3 {
+            storage.fetch_snapshot(&id).await?;
+        }
+        assert_eq!(
+            storage.snapshot_stats(),
+            RevalidationStats { hits: 2, not_modified: 0, downloads: 1 }
+        );
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
 use std::{
+    collections::BTreeMap,
+    fmt::Debug,
     future::ready,
     ops::Range,
     path::{Path, PathBuf},
@@ -6,6 +8,7 @@ use std::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 use async_stream::try_stream;
@@ -13,14 +16,21 @@ use async_trait::async_trait;
 use aws_config::{meta::region::RegionProviderChain, AppName, BehaviorVersion};
 use aws_credential_types::Credentials;
 use aws_sdk_s3::{
-    config::{Builder, Region},
-    error::ProvideErrorMetadata,
+    config::{
+        http::HttpResponse, interceptors::BeforeTransmitInterceptorContextMut, Builder,
+        ConfigBag, Intercept, Region, RuntimeComponents,
+    },
+    error::{ProvideErrorMetadata, SdkError},
+    operation::{get_object::GetObjectError, put_object::PutObjectError},
+    presigning::PresigningConfig,
     primitives::ByteStream,
-    types::{Delete, Object, ObjectIdentifier},
+    types::{Delete, Object, ObjectIdentifier, StorageClass},
     Client,
 };
 use aws_smithy_types_convert::{date_time::DateTimeExt, stream::PaginationStreamExt};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use bytes::Bytes;
+use chrono::{TimeDelta, Utc};
 use futures::{
     stream::{self, BoxStream},
     StreamExt, TryStreamExt,
@@ -29,18 +39,27 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     format::{
-        attributes::AttributesTable, format_constants, manifest::Manifest,
-        snapshot::Snapshot, transaction_log::TransactionLog, AttributesId, ByteRange,
-        ChunkId, FileTypeTag, ManifestId, SnapshotId,
+        attributes::AttributesTable,
+        format_constants,
+        framing::{unframe, FrameKind},
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        manifest_columns::ColumnarManifest,
+        snapshot::Snapshot,
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, FileTypeTag, ManifestId, NodeId,
+        SnapshotId,
     },
     private,
+    template::{default_branch_in, SETTINGS_KEY},
     zarr::ObjectId,
     Storage, StorageError,
 };
 
 use super::{
-    ListInfo, StorageResult, CHUNK_PREFIX, MANIFEST_PREFIX, REF_PREFIX, SNAPSHOT_PREFIX,
-    TRANSACTION_PREFIX,
+    deserialize_metadata, serialize_metadata, Conditional, ETag, ListInfo, ObjectKind,
+    PresignedUrl, StorageCapabilities, StorageResult, ATTRIBUTES_PREFIX, CHUNK_PREFIX,
+    DELETE_BATCH_SIZE, DELETE_CONCURRENCY, MANIFEST_PREFIX, REF_PREFIX, SNAPSHOT_PREFIX,
+    SNAPSHOT_SEGMENTS_PREFIX, TRANSACTION_PREFIX,
 };
 
 #[derive(Debug)]
@@ -48,6 +67,9 @@ pub struct S3Storage {
     client: Arc<Client>,
     prefix: String,
     bucket: String,
+    storage_classes: S3StorageClasses,
+    framed_metadata: bool,
+    checksum_chunks: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -69,15 +91,112 @@ pub enum S3Credentials {
     Static(StaticS3Credentials),
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum S3StorageClass {
+    #[serde(rename = "STANDARD")]
+    Standard,
+    #[serde(rename = "STANDARD_IA")]
+    StandardIa,
+    #[serde(rename = "GLACIER_IR")]
+    GlacierIr,
+}
+
+impl From<S3StorageClass> for StorageClass {
+    fn from(value: S3StorageClass) -> Self {
+        match value {
+            S3StorageClass::Standard => StorageClass::Standard,
+            S3StorageClass::StandardIa => StorageClass::StandardIa,
+            S3StorageClass::GlacierIr => StorageClass::GlacierIr,
+        }
+    }
+}
+
+/// The storage class used to write each type of object, `None` uses the bucket default
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct S3StorageClasses {
+    pub chunks: Option<S3StorageClass>,
+    pub manifests: Option<S3StorageClass>,
+    pub snapshots: Option<S3StorageClass>,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct S3Config {
     pub region: Option<String>,
     pub endpoint: Option<String>,
     pub credentials: S3Credentials,
     pub allow_http: bool,
+    #[serde(default)]
+    pub storage_classes: S3StorageClasses,
+    /// Write snapshots, manifests and transaction logs in checksummed frames
+    #[serde(default)]
+    pub framed_metadata: bool,
+    /// Send the CRC-32 of every chunk uploaded, for S3 to validate it
+    #[serde(default)]
+    pub checksum_chunks: bool,
+    /// Headers sent with every request, like `x-amz-request-payer` or the ones a proxy
+    /// requires
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// The bucket is a requester-pays one, requests are billed to the account of the
+    /// credentials
+    #[serde(default)]
+    pub requester_pays: bool,
+}
+
+/// Adds headers to every request sent to S3, for headers that change per request, like
+/// tracing ones
+///
+/// Headers are added before the request is signed, they are covered by the signature.
+pub trait RequestHook: Debug + Send + Sync {
+    /// The headers to add to the request, with its HTTP `method` and `uri`
+    fn headers(&self, method: &str, uri: &str) -> Vec<(String, String)>;
+}
+
+pub type DynRequestHook = Arc<dyn RequestHook>;
+
+#[derive(Debug)]
+struct RequestHookInterceptor(DynRequestHook);
+
+impl Intercept for RequestHookInterceptor {
+    fn name(&self) -> &'static str {
+        "IcechunkRequestHook"
+    }
+
+    fn modify_before_signing(
+        &self,
+        context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let request = context.request_mut();
+        for (name, value) in self.0.headers(request.method(), request.uri()) {
+            request.headers_mut().try_insert(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+const REQUEST_PAYER_HEADER: &str = "x-amz-request-payer";
+
+/// The [`S3Config::headers`], the same for every request
+#[derive(Debug)]
+struct StaticHeaders(BTreeMap<String, String>);
+
+impl RequestHook for StaticHeaders {
+    fn headers(&self, _method: &str, _uri: &str) -> Vec<(String, String)> {
+        self.0.iter().map(|(name, value)| (name.clone(), value.clone())).collect()
+    }
 }
 
 pub async fn mk_client(config: Option<&S3Config>) -> Client {
+    mk_client_with_hook(config, None).await
+}
+
+/// A client that adds the headers of `hook` to every request, see [`RequestHook`]
+pub async fn mk_client_with_hook(
+    config: Option<&S3Config>,
+    hook: Option<DynRequestHook>,
+) -> Client {
     let region = config
         .and_then(|c| c.region.as_ref())
         .map(|r| RegionProviderChain::first_try(Some(Region::new(r.clone()))))
@@ -117,6 +236,18 @@ pub async fn mk_client(config: Option<&S3Config>) -> Client {
         s3_builder = s3_builder.force_path_style(true);
     }
 
+    let mut headers = config.map(|c| c.headers.clone()).unwrap_or_default();
+    if config.is_some_and(|c| c.requester_pays) {
+        headers.insert(REQUEST_PAYER_HEADER.to_string(), "requester".to_string());
+    }
+    if !headers.is_empty() {
+        s3_builder = s3_builder
+            .interceptor(RequestHookInterceptor(Arc::new(StaticHeaders(headers))));
+    }
+    if let Some(hook) = hook {
+        s3_builder = s3_builder.interceptor(RequestHookInterceptor(hook));
+    }
+
     let config = s3_builder.build();
 
     Client::from_conf(config)
@@ -128,8 +259,90 @@ impl S3Storage {
         prefix: impl Into<String>,
         config: Option<&S3Config>,
     ) -> Result<S3Storage, StorageError> {
-        let client = Arc::new(mk_client(config).await);
-        Ok(S3Storage { client, prefix: prefix.into(), bucket: bucket_name.into() })
+        Self::new_s3_store_with_hook(bucket_name, prefix, config, None).await
+    }
+
+    /// A storage whose requests carry the headers of `hook`, see [`RequestHook`]
+    pub async fn new_s3_store_with_hook(
+        bucket_name: impl Into<String>,
+        prefix: impl Into<String>,
+        config: Option<&S3Config>,
+        hook: Option<DynRequestHook>,
+    ) -> Result<S3Storage, StorageError> {
+        let client = Arc::new(mk_client_with_hook(config, hook).await);
+        let storage_classes =
+            config.map(|c| c.storage_classes.clone()).unwrap_or_default();
+        Ok(S3Storage {
+            client,
+            prefix: prefix.into(),
+            bucket: bucket_name.into(),
+            storage_classes,
+            framed_metadata: config.is_some_and(|c| c.framed_metadata),
+            checksum_chunks: config.is_some_and(|c| c.checksum_chunks),
+        })
+    }
+
+    /// List the repositories stored directly under the prefix of this storage
+    ///
+    /// Returns the prefixes of the repositories, relative to the prefix of this storage. A
+    /// prefix holds a repository if it has a default branch, the one of its settings, see
+    /// [`crate::template::default_branch`].
+    pub async fn list_repositories(&self) -> StorageResult<Vec<String>> {
+        let root = if self.prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.prefix.trim_end_matches('/'))
+        };
+        let mut paginator = self
+            .client
+            .list_objects_v2()
+            .bucket(self.bucket.clone())
+            .prefix(root.clone())
+            .delimiter("/")
+            .into_paginator()
+            .send();
+
+        let mut res = Vec::new();
+        while let Some(page) = paginator.try_next().await? {
+            for child in page.common_prefixes().iter().filter_map(|p| p.prefix()) {
+                let settings = self
+                    .client
+                    .get_object()
+                    .bucket(self.bucket.clone())
+                    .key(format!("{child}{REF_PREFIX}/{SETTINGS_KEY}"))
+                    .send()
+                    .await;
+                let settings = match settings {
+                    Ok(settings) => Some(settings.body.collect().await?.into_bytes()),
+                    Err(err)
+                        if err.as_service_error().is_some_and(|e| e.is_no_such_key()) =>
+                    {
+                        None
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let default_branch = default_branch_in(settings.as_deref())
+                    .map_err(StorageError::Other)?;
+                let branch = format!("{child}{REF_PREFIX}/branch.{default_branch}/");
+                let versions = self
+                    .client
+                    .list_objects_v2()
+                    .bucket(self.bucket.clone())
+                    .prefix(branch)
+                    .max_keys(1)
+                    .send()
+                    .await?;
+                if versions.key_count().unwrap_or(0) > 0 {
+                    if let Some(name) = child
+                        .strip_prefix(root.as_str())
+                        .map(|name| name.trim_end_matches('/'))
+                    {
+                        res.push(name.to_string());
+                    }
+                }
+            }
+        }
+        Ok(res)
     }
 
     fn get_path_str(&self, file_prefix: &str, id: &str) -> StorageResult<String> {
@@ -150,6 +363,10 @@ impl S3Storage {
         self.get_path(SNAPSHOT_PREFIX, id)
     }
 
+    fn get_snapshot_segments_path(&self, id: &SnapshotId) -> StorageResult<String> {
+        self.get_path(SNAPSHOT_SEGMENTS_PREFIX, id)
+    }
+
     fn get_manifest_path(&self, id: &ManifestId) -> StorageResult<String> {
         self.get_path(MANIFEST_PREFIX, id)
     }
@@ -158,6 +375,10 @@ impl S3Storage {
         self.get_path(CHUNK_PREFIX, id)
     }
 
+    fn get_attributes_path(&self, id: &AttributesId) -> StorageResult<String> {
+        self.get_path(ATTRIBUTES_PREFIX, id)
+    }
+
     fn get_transaction_path(&self, id: &SnapshotId) -> StorageResult<String> {
         self.get_path(TRANSACTION_PREFIX, id)
     }
@@ -168,17 +389,43 @@ impl S3Storage {
     }
 
     async fn get_object(&self, key: &str) -> StorageResult<Bytes> {
-        Ok(self
+        let res = self
             .client
             .get_object()
             .bucket(self.bucket.clone())
             .key(key)
             .send()
-            .await?
-            .body
-            .collect()
-            .await?
-            .into_bytes())
+            .await
+            .map_err(|err| get_object_error(key, err))?;
+        Ok(res.body.collect().await?.into_bytes())
+    }
+
+    /// The object and its ETag, `None` if its ETag is still `etag`
+    async fn get_object_if_modified(
+        &self,
+        key: &str,
+        etag: Option<&str>,
+    ) -> StorageResult<Option<(Bytes, Option<ETag>)>> {
+        let res = self
+            .client
+            .get_object()
+            .bucket(self.bucket.clone())
+            .key(key)
+            .set_if_none_match(etag.map(str::to_string))
+            .send()
+            .await;
+        match res {
+            Ok(res) => {
+                let etag = res.e_tag().map(str::to_string);
+                Ok(Some((res.body.collect().await?.into_bytes(), etag)))
+            }
+            Err(err)
+                if err.raw_response().is_some_and(|res| res.status().as_u16() == 304) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(get_object_error(key, err)),
+        }
     }
 
     async fn get_object_range(
@@ -192,32 +439,145 @@ impl S3Storage {
             b = b.range(header)
         };
 
-        Ok(b.send().await?.body.collect().await?.into_bytes())
+        let res = b.send().await.map_err(|err| get_object_error(key, err))?;
+        Ok(res.body.collect().await?.into_bytes())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn put_object<
         I: IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
     >(
         &self,
         key: &str,
         content_type: Option<impl Into<String>>,
+        storage_class: Option<S3StorageClass>,
+        only_if_absent: bool,
         metadata: I,
+        checksum_crc32: Option<String>,
         bytes: impl Into<ByteStream>,
     ) -> StorageResult<()> {
         let mut b = self.client.put_object().bucket(self.bucket.clone()).key(key);
 
+        if let Some(checksum) = checksum_crc32 {
+            // S3 rejects the upload if the bytes it receives don't match
+            b = b.checksum_crc32(checksum);
+        }
+
         if let Some(ct) = content_type {
             b = b.content_type(ct)
         };
 
+        if let Some(class) = storage_class {
+            b = b.storage_class(class.into())
+        };
+
+        if only_if_absent {
+            b = b.if_none_match("*")
+        }
+
         for (k, v) in metadata {
             b = b.metadata(k, v);
         }
 
-        b.body(bytes.into()).send().await?;
+        match b.body(bytes.into()).send().await {
+            Ok(_) => Ok(()),
+            Err(err) if only_if_absent && is_precondition_failure(&err) => {
+                Err(StorageError::ObjectAlreadyExists(key.to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Copy an object to another [`S3Storage`], possibly in a different bucket or prefix
+    ///
+    /// The copy happens server side, both storages must be accessible with the credentials
+    /// of this one.
+    pub async fn copy_object_to(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        destination: &S3Storage,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        let from = self.get_path_str(kind.prefix(), from_id)?;
+        let to = destination.get_path_str(kind.prefix(), to_id)?;
+        let mut b = self
+            .client
+            .copy_object()
+            .copy_source(format!("{}/{}", self.bucket, from))
+            .bucket(destination.bucket.clone())
+            .key(to);
+        let class = match kind {
+            ObjectKind::Chunk => destination.storage_classes.chunks,
+            ObjectKind::Manifest => destination.storage_classes.manifests,
+            ObjectKind::Snapshot => destination.storage_classes.snapshots,
+            ObjectKind::TransactionLog | ObjectKind::Ref => None,
+        };
+        if let Some(class) = class {
+            b = b.storage_class(class.into())
+        };
+        b.send().await?;
         Ok(())
     }
 
+    async fn put_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+        only_if_absent: bool,
+    ) -> StorageResult<()> {
+        let key = self.get_snapshot_path(&id)?;
+        let bytes = serialize_metadata(
+            FrameKind::Snapshot,
+            snapshot.icechunk_snapshot_format_version,
+            snapshot.as_ref(),
+            self.framed_metadata,
+        )?;
+        let metadata = [(
+            format_constants::LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY,
+            snapshot.icechunk_snapshot_format_version.to_string(),
+        )];
+        self.put_object(
+            key.as_str(),
+            Some(format_constants::LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE),
+            self.storage_classes.snapshots,
+            only_if_absent,
+            metadata,
+            None,
+            bytes,
+        )
+        .await
+    }
+
+    async fn put_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+        only_if_absent: bool,
+    ) -> StorageResult<()> {
+        let key = self.get_manifest_path(&id)?;
+        let bytes = serialize_metadata(
+            FrameKind::Manifest,
+            manifest.icechunk_manifest_format_version,
+            manifest.as_ref(),
+            self.framed_metadata,
+        )?;
+        let metadata = [(
+            format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
+            manifest.icechunk_manifest_format_version.to_string(),
+        )];
+        self.put_object(
+            key.as_str(),
+            Some(format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE),
+            self.storage_classes.manifests,
+            only_if_absent,
+            metadata,
+            None,
+            bytes,
+        )
+        .await
+    }
+
     async fn delete_batch(
         &self,
         prefix: &str,
@@ -246,10 +606,35 @@ impl S3Storage {
             .send()
             .await?;
 
+        if let Some(err) = res.errors().first() {
+            return Err(StorageError::Other(format!(
+                "error deleting object {}: {}",
+                err.key().unwrap_or_default(),
+                err.message().unwrap_or_default()
+            )));
+        }
         Ok(res.deleted().len())
     }
 }
 
+/// Objects in archival storage classes need to be restored before they can be read
+fn get_object_error(
+    key: &str,
+    err: SdkError<GetObjectError, HttpResponse>,
+) -> StorageError {
+    match err.as_service_error() {
+        Some(GetObjectError::InvalidObjectState(_)) => {
+            StorageError::ObjectArchived(key.to_string())
+        }
+        _ => err.into(),
+    }
+}
+
+fn is_precondition_failure(err: &SdkError<PutObjectError, HttpResponse>) -> bool {
+    let code = err.as_service_error().and_then(|e| e.code()).unwrap_or("");
+    code.contains("PreconditionFailed") || code.contains("ConditionalRequestConflict")
+}
+
 pub fn range_to_header(range: &ByteRange) -> Option<String> {
     match range {
         ByteRange::Bounded(Range { start, end }) => {
@@ -268,24 +653,65 @@ impl Storage for S3Storage {
     async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
         let key = self.get_snapshot_path(id)?;
         let bytes = self.get_object(key.as_str()).await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+        Ok(Arc::new(deserialize_metadata(FrameKind::Snapshot, &bytes)?))
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        let key = self.get_snapshot_path(id)?;
+        Ok(match self.get_object_if_modified(key.as_str(), etag).await? {
+            Some((bytes, etag)) => Conditional::Modified(
+                Arc::new(deserialize_metadata(FrameKind::Snapshot, &bytes)?),
+                etag,
+            ),
+            None => Conditional::NotModified,
+        })
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        let key = self.get_manifest_path(id)?;
+        Ok(match self.get_object_if_modified(key.as_str(), etag).await? {
+            Some((bytes, etag)) => Conditional::Modified(
+                Arc::new(deserialize_metadata(FrameKind::Manifest, &bytes)?),
+                etag,
+            ),
+            None => Conditional::NotModified,
+        })
     }
 
     async fn fetch_attributes(
         &self,
-        _id: &AttributesId,
+        id: &AttributesId,
     ) -> StorageResult<Arc<AttributesTable>> {
-        todo!()
+        let key = self.get_attributes_path(id)?;
+        let bytes = self.get_object(key.as_str()).await?;
+        Ok(Arc::new(deserialize_metadata(FrameKind::Attributes, &bytes)?))
     }
 
     async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
         let key = self.get_manifest_path(id)?;
         let bytes = self.get_object(key.as_str()).await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+        Ok(Arc::new(deserialize_metadata(FrameKind::Manifest, &bytes)?))
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let key = self.get_manifest_path(id)?;
+        let bytes = self.get_object(key.as_str()).await?;
+        let columns =
+            ColumnarManifest::from_slice(unframe(FrameKind::Manifest, &bytes)?)?;
+        Ok(columns.select(node, extents))
     }
 
     async fn fetch_transaction_log(
@@ -294,9 +720,35 @@ impl Storage for S3Storage {
     ) -> StorageResult<Arc<TransactionLog>> {
         let key = self.get_transaction_path(id)?;
         let bytes = self.get_object(key.as_str()).await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+        Ok(Arc::new(deserialize_metadata(FrameKind::TransactionLog, &bytes)?))
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let key = self.get_snapshot_segments_path(id)?;
+        self.get_object_range(key.as_str(), range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let key = self.get_snapshot_segments_path(&id)?;
+        let metadata: [(String, String); 0] = [];
+        self.put_object(
+            key.as_str(),
+            None::<String>,
+            self.storage_classes.snapshots,
+            false,
+            metadata,
+            None,
+            bytes,
+        )
+        .await
     }
 
     async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
@@ -305,52 +757,87 @@ impl Storage for S3Storage {
         Ok(bytes)
     }
 
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        let key = self.get_chunk_path(id)?;
+        // this validates the ttl is not longer than S3 maximum presigning period
+        let config = PresigningConfig::expires_in(ttl)?;
+        let expires_at = Utc::now()
+            + TimeDelta::from_std(ttl).map_err(|e| StorageError::Other(e.to_string()))?;
+        let mut b = self.client.get_object().bucket(self.bucket.clone()).key(key);
+        if let Some(header) = range_to_header(range) {
+            b = b.range(header)
+        };
+        let req = b.presigned(config).await?;
+        Ok(PresignedUrl {
+            url: req.uri().to_string(),
+            headers: req.headers().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            expires_at,
+        })
+    }
+
     async fn write_snapshot(
         &self,
         id: SnapshotId,
         snapshot: Arc<Snapshot>,
     ) -> StorageResult<()> {
-        let key = self.get_snapshot_path(&id)?;
-        let bytes = rmp_serde::to_vec(snapshot.as_ref())?;
+        self.put_snapshot(id, snapshot, false).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.put_snapshot(id, snapshot, true).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        let key = self.get_attributes_path(&id)?;
+        let bytes = serialize_metadata(
+            FrameKind::Attributes,
+            table.icechunk_attributes_format_version,
+            table.as_ref(),
+            self.framed_metadata,
+        )?;
         let metadata = [(
-            format_constants::LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY,
-            snapshot.icechunk_snapshot_format_version.to_string(),
+            format_constants::LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY,
+            table.icechunk_attributes_format_version.to_string(),
         )];
         self.put_object(
             key.as_str(),
-            Some(format_constants::LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE),
+            Some(format_constants::LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE),
+            self.storage_classes.snapshots,
+            false,
             metadata,
+            None,
             bytes,
         )
         .await
     }
 
-    async fn write_attributes(
+    async fn write_manifests(
         &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
-    ) -> StorageResult<()> {
-        todo!()
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> Result<(), StorageError> {
+        self.put_manifests(id, manifest, false).await
     }
 
-    async fn write_manifests(
+    async fn write_manifests_if_absent(
         &self,
         id: ManifestId,
         manifest: Arc<Manifest>,
-    ) -> Result<(), StorageError> {
-        let key = self.get_manifest_path(&id)?;
-        let bytes = rmp_serde::to_vec(manifest.as_ref())?;
-        let metadata = [(
-            format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
-            manifest.icechunk_manifest_format_version.to_string(),
-        )];
-        self.put_object(
-            key.as_str(),
-            Some(format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE),
-            metadata,
-            bytes,
-        )
-        .await
+    ) -> StorageResult<()> {
+        self.put_manifests(id, manifest, true).await
     }
 
     async fn write_transaction_log(
@@ -359,7 +846,12 @@ impl Storage for S3Storage {
         log: Arc<TransactionLog>,
     ) -> StorageResult<()> {
         let key = self.get_transaction_path(&id)?;
-        let bytes = rmp_serde::to_vec(log.as_ref())?;
+        let bytes = serialize_metadata(
+            FrameKind::TransactionLog,
+            log.icechunk_transaction_log_format_version,
+            log.as_ref(),
+            self.framed_metadata,
+        )?;
         let metadata = [(
             format_constants::LATEST_ICECHUNK_TRANSACTION_LOG_VERSION_METADATA_KEY,
             log.icechunk_transaction_log_format_version.to_string(),
@@ -367,7 +859,10 @@ impl Storage for S3Storage {
         self.put_object(
             key.as_str(),
             Some(format_constants::LATEST_ICECHUNK_TRANSACTION_LOG_CONTENT_TYPE),
+            None,
+            false,
             metadata,
+            None,
             bytes,
         )
         .await
@@ -381,7 +876,19 @@ impl Storage for S3Storage {
         let key = self.get_chunk_path(&id)?;
         //FIXME: use multipart upload
         let metadata: [(String, String); 0] = [];
-        self.put_object(key.as_str(), None::<String>, metadata, bytes).await
+        let checksum = self
+            .checksum_chunks
+            .then(|| STANDARD.encode(crc32fast::hash(&bytes).to_be_bytes()));
+        self.put_object(
+            key.as_str(),
+            None::<String>,
+            self.storage_classes.chunks,
+            false,
+            metadata,
+            checksum,
+            bytes,
+        )
+        .await
     }
 
     async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
@@ -482,10 +989,7 @@ impl Storage for S3Storage {
         match res {
             Ok(_) => Ok(()),
             Err(err) => {
-                let code = err.as_service_error().and_then(|e| e.code()).unwrap_or("");
-                if code.contains("PreconditionFailed")
-                    || code.contains("ConditionalRequestConflict")
-                {
+                if is_precondition_failure(&err) {
                     Err(StorageError::RefAlreadyExists(key))
                 } else {
                     Err(err.into())
@@ -526,19 +1030,37 @@ impl Storage for S3Storage {
         ids: BoxStream<'_, String>,
     ) -> StorageResult<usize> {
         let deleted = AtomicUsize::new(0);
-        ids.chunks(1_000)
-            // FIXME: configurable concurrency
-            .for_each_concurrent(10, |batch| {
+        ids.chunks(DELETE_BATCH_SIZE)
+            .map(Ok::<_, StorageError>)
+            .try_for_each_concurrent(DELETE_CONCURRENCY, |batch| {
                 let deleted = &deleted;
                 async move {
-                    // FIXME: handle error instead of skipping
-                    let new_deletes = self.delete_batch(prefix, batch).await.unwrap_or(0);
+                    let new_deletes = self.delete_batch(prefix, batch).await?;
                     deleted.fetch_add(new_deletes, Ordering::Release);
+                    Ok(())
                 }
             })
-            .await;
+            .await?;
         Ok(deleted.into_inner())
     }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.copy_object_to(kind, from_id, self, to_id).await
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        StorageCapabilities::all()
+    }
+
+    fn chunk_url(&self, _shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        let key = self.get_chunk_path(id).ok()?;
+        Some(format!("s3://{}/{}", self.bucket, key.trim_start_matches('/')))
+    }
 }
 
 fn object_to_list_info(object: &Object) -> Option<ListInfo<String>> {
@@ -546,5 +1068,99 @@ fn object_to_list_info(object: &Object) -> Option<ListInfo<String>> {
     let last_modified = object.last_modified()?;
     let created_at = last_modified.to_chrono_utc().ok()?;
     let id = Path::new(key).file_name().and_then(|s| s.to_str())?.to_string();
-    Some(ListInfo { id, created_at })
+    let size_bytes = object.size().unwrap_or(0) as u64;
+    Some(ListInfo { id, created_at, size_bytes })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct TraceHeader;
+
+    impl RequestHook for TraceHeader {
+        fn headers(&self, method: &str, _uri: &str) -> Vec<(String, String)> {
+            vec![("x-trace-method".to_string(), method.to_string())]
+        }
+    }
+
+    /// Answer one request with a `NoSuchKey` error, returning the request head
+    async fn serve_no_such_key(listener: TcpListener) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            head.extend_from_slice(&buf[..n]);
+        }
+        let body = "<Error><Code>NoSuchKey</Code></Error>";
+        let response = format!(
+            "HTTP/1.1 404 Not Found\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(head).unwrap().to_lowercase()
+    }
+
+    #[tokio::test]
+    async fn test_request_headers() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(format!("http://{}", listener.local_addr()?)),
+            credentials: S3Credentials::Anonymous,
+            allow_http: true,
+            headers: BTreeMap::from([(
+                "x-proxy-token".to_string(),
+                "secret".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_no_such_key(listener));
+        let storage = S3Storage::new_s3_store_with_hook(
+            "bucket",
+            "prefix",
+            Some(&config),
+            Some(Arc::new(TraceHeader)),
+        )
+        .await?;
+        assert!(matches!(
+            storage.get_ref("branch.main/ZZZZZZZZ.json").await,
+            Err(StorageError::RefNotFound(_))
+        ));
+        let head = server.await?;
+        assert!(head.contains("x-proxy-token: secret\r\n"));
+        assert!(head.contains("x-trace-method: get\r\n"));
+        assert!(!head.contains(REQUEST_PAYER_HEADER));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requester_pays() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(format!("http://{}", listener.local_addr()?)),
+            credentials: S3Credentials::Anonymous,
+            allow_http: true,
+            requester_pays: true,
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve_no_such_key(listener));
+        let storage = S3Storage::new_s3_store("bucket", "prefix", Some(&config)).await?;
+        assert!(storage.get_ref("branch.main/ZZZZZZZZ.json").await.is_err());
+        assert!(server.await?.contains("x-amz-request-payer: requester\r\n"));
+        Ok(())
+    }
 }
@@ -0,0 +1,426 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use quick_cache::sync::Cache;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        snapshot_segments::{
+            decode_index, decode_index_length, decode_segment, encode_segments,
+            SegmentIndex, INDEX_LENGTH_BYTES,
+        },
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult, SNAPSHOT_SEGMENTS_PREFIX,
+};
+
+/// A [`Storage`] that resolves single nodes of a snapshot without loading it whole
+///
+/// Every snapshot written through it is also written in the segmented layout of
+/// [`crate::format::snapshot_segments`], and [`Storage::fetch_snapshot_node`] only reads
+/// the index and one segment of it. Opening a repository with hundreds of thousands of
+/// nodes, and reading a few arrays, doesn't fetch the list of all its nodes. Snapshots that
+/// have no segmented layout, because they were written without this storage, are fetched
+/// whole from the backend.
+///
+/// Put it outside of any caching storage, so snapshots without a layout are cached.
+#[derive(Debug)]
+pub struct SegmentedSnapshotStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    nodes_per_segment: usize,
+    /// `None` for snapshots without a segmented layout
+    indexes: Cache<SnapshotId, Option<Arc<SegmentIndex>>>,
+    segments: Cache<(SnapshotId, usize), Arc<Vec<NodeSnapshot>>>,
+}
+
+impl SegmentedSnapshotStorage {
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        nodes_per_segment: usize,
+        num_segments: u16,
+    ) -> Self {
+        Self {
+            backend,
+            nodes_per_segment,
+            indexes: Cache::new(num_segments as usize),
+            segments: Cache::new(num_segments as usize),
+        }
+    }
+
+    async fn index(&self, id: &SnapshotId) -> StorageResult<Option<Arc<SegmentIndex>>> {
+        if let Some(index) = self.indexes.get(id) {
+            return Ok(index);
+        }
+        let header = self
+            .backend
+            .fetch_snapshot_segments(id, &ByteRange::to_offset(INDEX_LENGTH_BYTES))
+            .await;
+        let index = match header {
+            Ok(header) => {
+                let length = decode_index_length(&header).ok_or_else(|| {
+                    StorageError::Other(format!("invalid segmented snapshot {id}"))
+                })?;
+                let range =
+                    ByteRange::from_offset_with_length(INDEX_LENGTH_BYTES, length);
+                let bytes = self.backend.fetch_snapshot_segments(id, &range).await?;
+                Some(Arc::new(decode_index(&bytes)?))
+            }
+            Err(err) if err.is_not_found() => None,
+            Err(StorageError::SegmentsNotSupported) => None,
+            Err(err) => return Err(err),
+        };
+        self.indexes.insert(id.clone(), index.clone());
+        Ok(index)
+    }
+
+    async fn segment(
+        &self,
+        id: &SnapshotId,
+        index: &SegmentIndex,
+        segment: usize,
+    ) -> StorageResult<Arc<Vec<NodeSnapshot>>> {
+        let key = (id.clone(), segment);
+        if let Some(nodes) = self.segments.get(&key) {
+            return Ok(nodes);
+        }
+        let range = index.segment_range(segment).ok_or_else(|| {
+            StorageError::Other(format!("segment {segment} not found in snapshot {id}"))
+        })?;
+        let bytes = self.backend.fetch_snapshot_segments(id, &range).await?;
+        let nodes = Arc::new(decode_segment(&bytes)?);
+        self.segments.insert(key, Arc::clone(&nodes));
+        Ok(nodes)
+    }
+
+    async fn write_segments(
+        &self,
+        id: SnapshotId,
+        table: &Snapshot,
+    ) -> StorageResult<()> {
+        let bytes = encode_segments(table, self.nodes_per_segment)?;
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+}
+
+impl private::Sealed for SegmentedSnapshotStorage {}
+
+#[async_trait]
+impl Storage for SegmentedSnapshotStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        let Some(index) = self.index(id).await? else {
+            return self.backend.fetch_snapshot_node(id, path).await;
+        };
+        let Some(segment) = index.find(path) else { return Ok(None) };
+        let nodes = self.segment(id, &index, segment).await?;
+        Ok(nodes
+            .binary_search_by(|node| node.path.cmp(path))
+            .ok()
+            .map(|found| nodes[found].clone()))
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        // the layout of a snapshot that failed to write must not replace an existing one
+        self.backend.write_snapshot(id.clone(), Arc::clone(&table)).await?;
+        self.write_segments(id, &table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id.clone(), Arc::clone(&table)).await?;
+        self.write_segments(id, &table).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+
+    /// Also deletes the segmented layouts of the snapshots
+    async fn delete_snapshots(
+        &self,
+        snapshots: BoxStream<'_, SnapshotId>,
+    ) -> StorageResult<usize> {
+        let ids = snapshots.collect::<Vec<_>>().await;
+        for id in ids.iter() {
+            self.indexes.remove(id);
+        }
+        let keys = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        self.backend
+            .delete_objects(SNAPSHOT_SEGMENTS_PREFIX, futures::stream::iter(keys).boxed())
+            .await?;
+        self.backend.delete_snapshots(futures::stream::iter(ids).boxed()).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        repository::RepositoryError, storage::logging::LoggingStorage, test_utils,
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn test_segmented_snapshots() -> Result<(), Box<dyn Error>> {
+        let backend = test_utils::new_in_memory_storage();
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(SegmentedSnapshotStorage::new(logging.clone(), 2, 10));
+        let mut ds = test_utils::new_repository(Arc::clone(&storage)).await?;
+        ds.add_group(Path::root()).await?;
+        for name in ["a", "b", "c", "d", "e"] {
+            ds.add_group(Path::root().child(name)?).await?;
+        }
+        let array: Path = "/c/array".try_into()?;
+        ds.add_array(array.clone(), test_utils::array_metadata(vec![4])).await?;
+        let id = ds.commit("main", "nodes", None).await?;
+
+        // nodes are resolved reading only the index and their segment
+        let before = logging.fetch_operations().len();
+        let ds = Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        let node = ds.get_node(&array).await?;
+        let snapshot = backend.fetch_snapshot(&id).await?;
+        assert_eq!(&node, snapshot.get_node(&array)?);
+        ds.get_node(&"/a".try_into()?).await?;
+        assert!(matches!(
+            ds.get_node(&"/z".try_into()?).await,
+            Err(RepositoryError::NodeNotFound { .. })
+        ));
+        let operations = logging.fetch_operations()[before..]
+            .iter()
+            .map(|(op, _)| op.clone())
+            .collect::<Vec<_>>();
+        assert!(!operations.contains(&"fetch_snapshot".to_string()), "{operations:?}");
+        // the index, its length, and two segments, the missing node has no segment
+        assert_eq!(
+            operations.iter().filter(|op| *op == "fetch_snapshot_segments").count(),
+            4
+        );
+
+        // snapshots written without the layout are resolved by the backend, whole
+        let before = logging.fetch_operations().len();
+        let mut plain =
+            Repository::from_branch_tip(Arc::clone(&backend), "main").await?.build();
+        plain.add_group("/f".try_into()?).await?;
+        plain.commit("main", "plain", None).await?;
+        let ds = Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert_eq!(ds.get_node(&array).await?.id, node.id);
+        assert!(logging.fetch_operations()[before..]
+            .iter()
+            .any(|(op, _)| op == "fetch_snapshot_node"));
+        Ok(())
+    }
+}
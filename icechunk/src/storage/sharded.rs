@@ -0,0 +1,476 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageResult, CHUNK_PREFIX,
+};
+
+/// Chooses the shard of [`ShardedStorage`] a new chunk is written to
+pub trait ChunkPlacement: Debug + Send + Sync {
+    /// The index in `shards`, the names of the shards, of the shard for the chunk `id`
+    fn place(&self, id: &ChunkId, shards: &[String]) -> usize;
+}
+
+/// Spreads the chunks evenly over the shards, by the hash of their id
+///
+/// It uses rendezvous hashing: adding a shard only moves the chunks that now hash to it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashPlacement;
+
+impl ChunkPlacement for HashPlacement {
+    fn place(&self, id: &ChunkId, shards: &[String]) -> usize {
+        shards
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, name)| fnv1a(name.as_bytes().iter().chain(id.0.iter())))
+            .map(|(index, _)| index)
+            .unwrap_or_default()
+    }
+}
+
+/// A hash that doesn't depend on the process or the Rust version, placements must not
+/// change when the library is upgraded
+fn fnv1a<'a>(bytes: impl Iterator<Item = &'a u8>) -> u64 {
+    bytes.fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A [`Storage`] that spreads chunks over many backends, usually buckets, to go over the
+/// request rate of a single bucket
+///
+/// Snapshots, manifests, refs and the other metadata are stored in the primary backend.
+/// The [`ChunkPlacement`] chooses the shard of each new chunk, and the repository records
+/// it in the manifest, see [`Storage::chunk_placement`]. Chunks read without their
+/// placement, by other readers or through a wrapper storage, are looked up in the shard
+/// the current placement would choose, and then in the others.
+#[derive(Debug)]
+pub struct ShardedStorage {
+    primary: Arc<dyn Storage + Send + Sync>,
+    names: Vec<String>,
+    shards: Vec<Arc<dyn Storage + Send + Sync>>,
+    placement: Arc<dyn ChunkPlacement>,
+}
+
+impl ShardedStorage {
+    /// `shards` are named, their name is recorded with the chunks, so it must not change
+    pub fn new(
+        primary: Arc<dyn Storage + Send + Sync>,
+        shards: Vec<(String, Arc<dyn Storage + Send + Sync>)>,
+    ) -> Self {
+        let (names, shards) = shards.into_iter().unzip();
+        Self { primary, names, shards, placement: Arc::new(HashPlacement) }
+    }
+
+    pub fn with_placement(mut self, placement: Arc<dyn ChunkPlacement>) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    fn shard_index(&self, id: &ChunkId) -> Option<usize> {
+        if self.shards.is_empty() {
+            None
+        } else {
+            Some(self.placement.place(id, &self.names).min(self.shards.len() - 1))
+        }
+    }
+
+    fn chunk_shard(&self, id: &ChunkId) -> &Arc<dyn Storage + Send + Sync> {
+        self.shard_index(id).map_or(&self.primary, |index| &self.shards[index])
+    }
+
+    /// The shards to look for an existing chunk in, the placed one first
+    fn chunk_lookup_order(&self, id: &ChunkId) -> Vec<&Arc<dyn Storage + Send + Sync>> {
+        let placed = self.shard_index(id);
+        placed
+            .map(|index| &self.shards[index])
+            .into_iter()
+            .chain(
+                self.shards
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| Some(*index) != placed)
+                    .map(|(_, shard)| shard),
+            )
+            .collect()
+    }
+
+    fn is_chunk_prefix(prefix: &str) -> bool {
+        prefix == CHUNK_PREFIX
+    }
+}
+
+impl private::Sealed for ShardedStorage {}
+
+#[async_trait]
+impl Storage for ShardedStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.primary.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.primary.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.primary.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.primary.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.primary.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.primary.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let shards = self.chunk_lookup_order(id);
+        let Some((last, rest)) = shards.split_last() else {
+            return self.primary.fetch_chunk(id, range).await;
+        };
+        for shard in rest {
+            match shard.fetch_chunk(id, range).await {
+                Err(err) if err.is_not_found() => {}
+                res => return res,
+            }
+        }
+        last.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        match self.names.iter().position(|name| name == shard) {
+            Some(index) => self.shards[index].fetch_chunk(id, range).await,
+            // the shard was removed, its chunks may have been moved
+            None => self.fetch_chunk(id, range).await,
+        }
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        // presigning doesn't check the object exists, so we use the placed shard
+        self.chunk_shard(id).presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.primary.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.primary.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.primary.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.primary.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.primary.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.primary.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.chunk_shard(&id).write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.primary.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.primary.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.primary.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.primary.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.primary.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    /// Chunks are listed from every shard
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        if !Self::is_chunk_prefix(prefix) || self.shards.is_empty() {
+            return self.primary.list_objects(prefix).await;
+        }
+        let mut listings = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            listings.push(shard.list_objects(prefix).await?);
+        }
+        Ok(futures::stream::iter(listings).flatten().boxed())
+    }
+
+    /// Chunks are deleted from every shard
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        if !Self::is_chunk_prefix(prefix) || self.shards.is_empty() {
+            return self.primary.delete_objects(prefix, ids).await;
+        }
+        let ids: Vec<String> = ids.collect().await;
+        let mut deleted = 0;
+        for shard in self.shards.iter() {
+            let count = shard
+                .delete_objects(prefix, futures::stream::iter(ids.clone()).boxed())
+                .await?;
+            // every chunk lives in a single shard, but backends can count deletes of
+            // missing objects, so adding the counts could report more than requested
+            deleted = count.max(deleted);
+        }
+        Ok(deleted)
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        let id = match ChunkId::try_from(from_id) {
+            Ok(id) if kind == ObjectKind::Chunk && !self.shards.is_empty() => id,
+            _ => return self.primary.copy_object(kind, from_id, to_id).await,
+        };
+        // the copy stays in the shard of the original chunk
+        let shards = self.chunk_lookup_order(&id);
+        let Some((last, rest)) = shards.split_last() else {
+            return self.primary.copy_object(kind, from_id, to_id).await;
+        };
+        for shard in rest {
+            match shard.copy_object(kind, from_id, to_id).await {
+                Err(err) if err.is_not_found() => {}
+                res => return res,
+            }
+        }
+        last.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.primary.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.primary.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.primary.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.shard_index(id).map(|index| self.names[index].clone())
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        // chunks of removed shards are looked up where they would be placed now
+        let storage = shard
+            .and_then(|shard| self.names.iter().position(|name| name == shard))
+            .map_or_else(|| self.chunk_shard(id), |index| &self.shards[index]);
+        storage.chunk_url(None, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.primary.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.primary.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.shards.iter().fold(self.primary.capabilities(), |caps, shard| {
+            caps.intersection(shard.capabilities())
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::manifest::ChunkPayload,
+        format::{ChunkIndices, Path},
+        repository::get_chunk,
+        test_utils, Repository,
+    };
+
+    #[derive(Debug)]
+    struct Fixed(usize);
+
+    impl ChunkPlacement for Fixed {
+        fn place(&self, _id: &ChunkId, _shards: &[String]) -> usize {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sharded_storage() -> Result<(), Box<dyn Error>> {
+        let primary = test_utils::new_in_memory_storage();
+        let shards: Vec<(String, Arc<dyn Storage + Send + Sync>)> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| (name.to_string(), test_utils::new_in_memory_storage()))
+            .collect();
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ShardedStorage::new(Arc::clone(&primary), shards.clone()));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), test_utils::array_metadata(vec![30])).await?;
+        for i in 0..30 {
+            let payload = ds.get_chunk_writer()(Bytes::from(vec![i as u8])).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![i]), Some(payload)).await?;
+        }
+        let snapshot = ds.commit("main", "sharded", None).await?;
+
+        // the chunks are spread over the shards, and the metadata stays in the primary
+        let mut counts = Vec::new();
+        for (_, shard) in shards.iter() {
+            counts.push(shard.list_chunks().await?.try_collect::<Vec<_>>().await?.len());
+        }
+        assert_eq!(counts.iter().sum::<usize>(), 30);
+        assert!(counts.iter().all(|count| *count > 0));
+        assert_eq!(primary.list_chunks().await?.try_collect::<Vec<_>>().await?.len(), 0);
+        assert_eq!(storage.list_chunks().await?.try_collect::<Vec<_>>().await?.len(), 30);
+        let manifest_id = &primary.fetch_snapshot(&snapshot).await?.manifest_files[0].id;
+        assert_eq!(primary.fetch_manifests(manifest_id).await?.placements().len(), 30);
+
+        // chunks are found after a shard is added and the placement changes
+        let mut grown = shards;
+        grown.push(("d".to_string(), test_utils::new_in_memory_storage()));
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(
+            ShardedStorage::new(primary, grown).with_placement(Arc::new(Fixed(3))),
+        );
+        let ds = Repository::update(Arc::clone(&storage), snapshot).build();
+        for i in 0..30 {
+            let coords = ChunkIndices(vec![i]);
+            let reader = ds.get_chunk_reader(&path, &coords, &ByteRange::ALL).await?;
+            assert_eq!(get_chunk(reader).await?, Some(Bytes::from(vec![i as u8])));
+            let Some(ChunkPayload::Ref(chunk_ref)) =
+                ds.get_chunk_ref(&path, &coords).await?
+            else {
+                panic!("the chunk is stored")
+            };
+            assert_eq!(storage.chunk_placement(&chunk_ref.id), Some("d".to_string()));
+            storage.fetch_chunk(&chunk_ref.id, &ByteRange::ALL).await?;
+        }
+        Ok(())
+    }
+}
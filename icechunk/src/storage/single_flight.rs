@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    future::{BoxFuture, Shared},
+    stream::BoxStream,
+    FutureExt, TryFutureExt,
+};
+
+use crate::{
+    format::{
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        transaction_log::TransactionLog, AttributesId, ByteRange, ChunkId, ManifestId,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult,
+};
+
+type Flight<V> = Shared<BoxFuture<'static, Result<V, Arc<StorageError>>>>;
+
+/// The requests in progress for one kind of object
+#[derive(Debug)]
+struct Flights<K, V: Clone> {
+    in_flight: Mutex<HashMap<K, Flight<V>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone + Send + Sync + 'static> Flights<K, V> {
+    fn new() -> Self {
+        Self { in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    async fn run(
+        &self,
+        key: K,
+        fetch: impl FnOnce() -> BoxFuture<'static, StorageResult<V>>,
+    ) -> StorageResult<V> {
+        let flight = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.clone())
+            .or_insert_with(|| fetch().map_err(Arc::new).boxed().shared())
+            .clone();
+        let res = flight.clone().await;
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+        // a new request for the key may have started already
+        if in_flight.get(&key).is_some_and(|current| current.ptr_eq(&flight)) {
+            in_flight.remove(&key);
+        }
+        res.map_err(StorageError::Shared)
+    }
+}
+
+/// A [`Storage`] that coalesces concurrent identical fetches into a single request
+///
+/// While a snapshot, manifest, chunk range or transaction log is being fetched, other
+/// requests for the same object wait for the same result, instead of sending their own. Once
+/// the request finishes, nothing is kept: this doesn't replace [`super::MemCachingStorage`],
+/// it prevents many readers of a fresh snapshot from fetching the same objects before they
+/// are cached. Errors are shared too, as [`StorageError::Shared`].
+#[derive(Debug)]
+pub struct SingleFlightStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    snapshots: Flights<SnapshotId, Arc<Snapshot>>,
+    manifests: Flights<ManifestId, Arc<Manifest>>,
+    chunks: Flights<(ChunkId, ByteRange), Bytes>,
+    transaction_logs: Flights<SnapshotId, Arc<TransactionLog>>,
+}
+
+impl SingleFlightStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            backend,
+            snapshots: Flights::new(),
+            manifests: Flights::new(),
+            chunks: Flights::new(),
+            transaction_logs: Flights::new(),
+        }
+    }
+}
+
+impl private::Sealed for SingleFlightStorage {}
+
+#[async_trait]
+impl Storage for SingleFlightStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let backend = Arc::clone(&self.backend);
+        let key = id.clone();
+        self.snapshots
+            .run(id.clone(), || async move { backend.fetch_snapshot(&key).await }.boxed())
+            .await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let backend = Arc::clone(&self.backend);
+        let key = id.clone();
+        self.manifests
+            .run(id.clone(), || {
+                async move { backend.fetch_manifests(&key).await }.boxed()
+            })
+            .await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        // callers with different etags expect different answers, they are not merged
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let backend = Arc::clone(&self.backend);
+        let (key, key_range) = (id.clone(), range.clone());
+        self.chunks
+            .run((id.clone(), range.clone()), || {
+                async move { backend.fetch_chunk(&key, &key_range).await }.boxed()
+            })
+            .await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        let backend = Arc::clone(&self.backend);
+        let (shard, key, key_range) = (shard.to_string(), id.clone(), range.clone());
+        self.chunks
+            .run((id.clone(), range.clone()), || {
+                async move { backend.fetch_chunk_from(&shard, &key, &key_range).await }
+                    .boxed()
+            })
+            .await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        let backend = Arc::clone(&self.backend);
+        let key = id.clone();
+        self.transaction_logs
+            .run(id.clone(), || {
+                async move { backend.fetch_transaction_log(&key).await }.boxed()
+            })
+            .await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::join_all;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_concurrent_fetches_are_coalesced() {
+        let flights: Flights<&str, Bytes> = Flights::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let fetch = |key: &'static str| {
+            let calls = Arc::clone(&calls);
+            move || {
+                async move {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    if key == "missing" {
+                        Err(StorageError::RefNotFound(key.to_string()))
+                    } else {
+                        Ok(Bytes::from_static(key.as_bytes()))
+                    }
+                }
+                .boxed()
+            }
+        };
+
+        let results =
+            join_all((0..10).map(|_| flights.run("chunk", fetch("chunk")))).await;
+        assert!(results.iter().all(|res| res.as_ref().unwrap() == "chunk"));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+
+        // once finished, the result is not kept
+        flights.run("chunk", fetch("chunk")).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        let results =
+            join_all((0..3).map(|_| flights.run("missing", fetch("missing")))).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        for res in results {
+            assert!(res.unwrap_err().is_not_found());
+        }
+    }
+}
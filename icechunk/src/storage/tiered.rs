@@ -0,0 +1,349 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageResult,
+};
+
+/// A [`Storage`] that keeps chunks in two backends, a hot one for recent data and a cold one
+/// for historical data
+///
+/// New chunks, and every other object, are always written to the hot backend. Chunks are
+/// moved to the cold backend by [`crate::ops::tiering::migrate_cold_chunks`]. Chunk ids
+/// don't depend on where the chunk is stored, so moving chunks doesn't require rewriting
+/// manifests: chunks missing from the hot backend are read from the cold one.
+#[derive(Debug)]
+pub struct TieredStorage {
+    hot: Arc<dyn Storage + Send + Sync>,
+    cold: Arc<dyn Storage + Send + Sync>,
+}
+
+impl TieredStorage {
+    pub fn new(
+        hot: Arc<dyn Storage + Send + Sync>,
+        cold: Arc<dyn Storage + Send + Sync>,
+    ) -> Self {
+        Self { hot, cold }
+    }
+
+    pub fn hot(&self) -> &Arc<dyn Storage + Send + Sync> {
+        &self.hot
+    }
+
+    pub fn cold(&self) -> &Arc<dyn Storage + Send + Sync> {
+        &self.cold
+    }
+}
+
+impl private::Sealed for TieredStorage {}
+
+#[async_trait]
+impl Storage for TieredStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.hot.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.hot.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.hot.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.hot.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.hot.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.hot.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        match self.hot.fetch_chunk(id, range).await {
+            Err(err) if err.is_not_found() => self.cold.fetch_chunk(id, range).await,
+            res => res,
+        }
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        // the shard is the one of the hot storage, where placements come from
+        match self.hot.fetch_chunk_from(shard, id, range).await {
+            Err(err) if err.is_not_found() => self.cold.fetch_chunk(id, range).await,
+            res => res,
+        }
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        // presigning doesn't check the object exists, so we can't know which tier to use
+        self.hot.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.hot.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.hot.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.hot.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.hot.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.hot.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.hot.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.hot.write_chunk(id, bytes).await
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.hot.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.hot.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.hot.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.hot.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.hot.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    /// Objects are listed from both tiers
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        let hot = self.hot.list_objects(prefix).await?;
+        let cold = self.cold.list_objects(prefix).await?;
+        Ok(hot.chain(cold).boxed())
+    }
+
+    /// Objects are deleted from both tiers
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        let ids: Vec<String> = ids.collect().await;
+        let hot = self
+            .hot
+            .delete_objects(prefix, futures::stream::iter(ids.clone()).boxed())
+            .await?;
+        let cold =
+            self.cold.delete_objects(prefix, futures::stream::iter(ids).boxed()).await?;
+        // every object lives in a single tier, but backends can count deletes of missing
+        // objects, so adding the counts could report more objects than requested
+        Ok(hot.max(cold))
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        // the copy stays in the tier of the original object
+        match self.hot.copy_object(kind, from_id, to_id).await {
+            Err(err) if kind == ObjectKind::Chunk && err.is_not_found() => {
+                self.cold.copy_object(kind, from_id, to_id).await
+            }
+            res => res,
+        }
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.hot.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.hot.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.hot.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.hot.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, _shard: Option<&str>, _id: &ChunkId) -> Option<String> {
+        // chunks move to the cold backend, they have no stable location
+        None
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.hot.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.hot.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.hot.capabilities().intersection(self.cold.capabilities())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::ObjectStorage;
+
+    #[tokio::test]
+    async fn test_chunks_are_read_from_both_tiers() -> Result<(), Box<dyn Error>> {
+        let hot: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let cold: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let storage = TieredStorage::new(Arc::clone(&hot), Arc::clone(&cold));
+
+        let new_chunk = ChunkId::random();
+        storage.write_chunk(new_chunk.clone(), Bytes::from_static(b"new")).await?;
+        assert_eq!(hot.fetch_chunk(&new_chunk, &ByteRange::ALL).await?, "new");
+
+        let old_chunk = ChunkId::random();
+        cold.write_chunk(old_chunk.clone(), Bytes::from_static(b"old")).await?;
+        assert_eq!(storage.fetch_chunk(&old_chunk, &ByteRange::ALL).await?, "old");
+        assert!(storage.fetch_chunk(&ChunkId::random(), &ByteRange::ALL).await.is_err());
+
+        let copy = ChunkId::random();
+        storage
+            .copy_object(ObjectKind::Chunk, &old_chunk.to_string(), &copy.to_string())
+            .await?;
+        assert_eq!(cold.fetch_chunk(&copy, &ByteRange::ALL).await?, "old");
+        storage.delete_chunks(futures::stream::iter(vec![copy]).boxed()).await?;
+
+        let mut listed: Vec<_> =
+            storage.list_chunks().await?.map_ok(|info| info.id).try_collect().await?;
+        listed.sort();
+        let mut expected = vec![new_chunk.clone(), old_chunk.clone()];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        let deleted = storage
+            .delete_chunks(futures::stream::iter(vec![new_chunk, old_chunk]).boxed())
+            .await?;
+        assert_eq!(deleted, 2);
+        Ok(())
+    }
+}
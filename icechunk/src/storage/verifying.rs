@@ -0,0 +1,341 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+
+use crate::{
+    format::{
+        attributes::AttributesTable,
+        manifest::{ChunkPayload, Manifest, ManifestExtents},
+        snapshot::{NodeSnapshot, Snapshot},
+        transaction_log::TransactionLog,
+        AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, NodeId, Path,
+        SnapshotId,
+    },
+    private,
+    refs::BranchTipCache,
+};
+
+use super::{
+    CachingStats, Conditional, ListInfo, ObjectKind, PresignedUrl, Storage,
+    StorageCapabilities, StorageError, StorageResult,
+};
+
+type DynStorage = Arc<dyn Storage + Send + Sync>;
+
+/// How [`ChunkVerifyingStorage`] checks an uploaded chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkVerification {
+    /// Read the whole chunk back and compare it with the bytes written
+    ReadBack,
+    /// Read back only the last `bytes` bytes of the chunk, this catches truncated uploads
+    /// for a fraction of the cost
+    Sample { bytes: u64 },
+}
+
+/// A [`Storage`] that reads back every chunk it writes, and uploads it again if it doesn't
+/// match
+///
+/// A chunk that doesn't match after `max_uploads` uploads fails the write with
+/// [`StorageError::ChunkCorrupted`], so the commit never references it. Metadata objects
+/// are written as they are, see [`crate::storage::ReadAfterWriteStorage`] to read them
+/// back. With S3, [`crate::storage::s3::S3Config::checksum_chunks`] also has the service
+/// validate the checksum of every chunk upload.
+#[derive(Debug)]
+pub struct ChunkVerifyingStorage {
+    backend: DynStorage,
+    verification: ChunkVerification,
+    max_uploads: u32,
+}
+
+impl ChunkVerifyingStorage {
+    pub fn new(backend: DynStorage, verification: ChunkVerification) -> Self {
+        Self { backend, verification, max_uploads: 3 }
+    }
+
+    pub fn with_max_uploads(mut self, max_uploads: u32) -> Self {
+        self.max_uploads = max_uploads.max(1);
+        self
+    }
+
+    async fn is_stored(&self, id: &ChunkId, bytes: &Bytes) -> StorageResult<bool> {
+        let (range, expected) = match self.verification {
+            ChunkVerification::ReadBack => (ByteRange::ALL, bytes.clone()),
+            ChunkVerification::Sample { bytes: sample } => {
+                let sample = sample.min(bytes.len() as u64);
+                (ByteRange::Last(sample), bytes.slice(bytes.len() - sample as usize..))
+            }
+        };
+        match self.backend.fetch_chunk(id, &range).await {
+            Ok(stored) => Ok(stored == expected),
+            // the upload didn't leave a readable object
+            Err(err) if err.is_not_found() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl private::Sealed for ChunkVerifyingStorage {}
+
+#[async_trait]
+impl Storage for ChunkVerifyingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.backend.fetch_snapshot(id).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.backend.fetch_attributes(id).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.backend.fetch_manifests(id).await
+    }
+
+    async fn fetch_manifest_region(
+        &self,
+        id: &ManifestId,
+        node: &NodeId,
+        extents: &ManifestExtents,
+    ) -> StorageResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        self.backend.fetch_manifest_region(id, node, extents).await
+    }
+
+    async fn fetch_snapshot_if_modified(
+        &self,
+        id: &SnapshotId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Snapshot>>> {
+        self.backend.fetch_snapshot_if_modified(id, etag).await
+    }
+
+    async fn fetch_manifests_if_modified(
+        &self,
+        id: &ManifestId,
+        etag: Option<&str>,
+    ) -> StorageResult<Conditional<Arc<Manifest>>> {
+        self.backend.fetch_manifests_if_modified(id, etag).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk(id, range).await
+    }
+
+    async fn fetch_chunk_from(
+        &self,
+        shard: &str,
+        id: &ChunkId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_chunk_from(shard, id, range).await
+    }
+
+    async fn presign_read(
+        &self,
+        id: &ChunkId,
+        range: &ByteRange,
+        ttl: Duration,
+    ) -> StorageResult<PresignedUrl> {
+        self.backend.presign_read(id, range, ttl).await
+    }
+
+    async fn fetch_transaction_log(
+        &self,
+        id: &SnapshotId,
+    ) -> StorageResult<Arc<TransactionLog>> {
+        self.backend.fetch_transaction_log(id).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot(id, table).await
+    }
+
+    async fn write_snapshot_if_absent(
+        &self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_if_absent(id, table).await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.backend.write_attributes(id, table).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests(id, table).await
+    }
+
+    async fn write_manifests_if_absent(
+        &self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.backend.write_manifests_if_absent(id, table).await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        for _ in 0..self.max_uploads {
+            self.backend.write_chunk(id.clone(), bytes.clone()).await?;
+            if self.is_stored(&id, &bytes).await? {
+                return Ok(());
+            }
+        }
+        Err(StorageError::ChunkCorrupted(id.to_string(), self.max_uploads))
+    }
+
+    async fn write_transaction_log(
+        &self,
+        id: SnapshotId,
+        log: Arc<TransactionLog>,
+    ) -> StorageResult<()> {
+        self.backend.write_transaction_log(id, log).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: BoxStream<'_, String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    async fn copy_object(
+        &self,
+        kind: ObjectKind,
+        from_id: &str,
+        to_id: &str,
+    ) -> StorageResult<()> {
+        self.backend.copy_object(kind, from_id, to_id).await
+    }
+
+    async fn fetch_snapshot_node(
+        &self,
+        id: &SnapshotId,
+        path: &Path,
+    ) -> StorageResult<Option<NodeSnapshot>> {
+        self.backend.fetch_snapshot_node(id, path).await
+    }
+
+    async fn fetch_snapshot_segments(
+        &self,
+        id: &SnapshotId,
+        range: &ByteRange,
+    ) -> StorageResult<Bytes> {
+        self.backend.fetch_snapshot_segments(id, range).await
+    }
+
+    async fn write_snapshot_segments(
+        &self,
+        id: SnapshotId,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_snapshot_segments(id, bytes).await
+    }
+
+    fn chunk_placement(&self, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_placement(id)
+    }
+
+    fn chunk_url(&self, shard: Option<&str>, id: &ChunkId) -> Option<String> {
+        self.backend.chunk_url(shard, id)
+    }
+
+    fn cache_stats(&self) -> Option<CachingStats> {
+        self.backend.cache_stats()
+    }
+
+    fn branch_tip_cache(&self) -> Option<&BranchTipCache> {
+        self.backend.branch_tip_cache()
+    }
+
+    fn capabilities(&self) -> StorageCapabilities {
+        self.backend.capabilities()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{storage::logging::LoggingStorage, ObjectStorage};
+
+    #[tokio::test]
+    async fn test_corrupted_chunks_are_uploaded_again() -> Result<(), Box<dyn Error>> {
+        for verification in
+            [ChunkVerification::ReadBack, ChunkVerification::Sample { bytes: 2 }]
+        {
+            let backend = Arc::new(
+                LoggingStorage::new(Arc::new(ObjectStorage::new_in_memory_store(None)))
+                    .with_truncated_chunk_writes(2),
+            );
+            let storage = ChunkVerifyingStorage::new(backend.clone(), verification);
+            let id = ChunkId::random();
+            storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+            assert_eq!(backend.fetch_chunk(&id, &ByteRange::ALL).await?, "hello");
+
+            let backend = Arc::new(
+                LoggingStorage::new(Arc::new(ObjectStorage::new_in_memory_store(None)))
+                    .with_truncated_chunk_writes(3),
+            );
+            let storage = ChunkVerifyingStorage::new(backend, verification);
+            assert!(matches!(
+                storage
+                    .write_chunk(ChunkId::random(), Bytes::from_static(b"hello"))
+                    .await,
+                Err(StorageError::ChunkCorrupted(_, 3))
+            ));
+        }
+        Ok(())
+    }
+}
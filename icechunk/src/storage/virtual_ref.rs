@@ -1,4 +1,6 @@
-use crate::format::manifest::{VirtualChunkLocation, VirtualReferenceError};
+use crate::format::manifest::{
+    VirtualChunkLocation, VirtualChunkRef, VirtualReferenceError,
+};
 use crate::format::ByteRange;
 use crate::private;
 use async_trait::async_trait;
@@ -26,28 +28,99 @@ pub trait VirtualChunkResolver: Debug + private::Sealed {
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ObjectStoreVirtualChunkResolverConfig {
     S3(S3Config),
+    /// Read the chunks under each route prefix with the credentials and settings of the
+    /// route, and the other chunks with `default`
+    S3Routes {
+        default: Option<S3Config>,
+        routes: Vec<VirtualChunkRoute>,
+    },
+}
+
+/// The S3 settings for the virtual chunks under `prefix`, like `s3://bucket/` or
+/// `s3://bucket/some/prefix`
+///
+/// Prefixes match whole path components, and the longest matching prefix wins.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VirtualChunkRoute {
+    pub prefix: String,
+    pub config: S3Config,
+}
+
+impl VirtualChunkRoute {
+    pub fn new(prefix: impl Into<String>, config: S3Config) -> Self {
+        Self { prefix: prefix.into(), config }
+    }
+
+    pub fn matches(&self, location: &str) -> bool {
+        let prefix = self.prefix.as_str();
+        match location.strip_prefix(prefix) {
+            Some(rest) => {
+                prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+            }
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ObjectStoreVirtualChunkResolver {
     s3: OnceCell<Client>,
     config: Box<Option<ObjectStoreVirtualChunkResolverConfig>>,
+    /// A client for each route, in the order of the config
+    route_clients: Vec<OnceCell<Client>>,
 }
 
 impl ObjectStoreVirtualChunkResolver {
     pub fn new(config: Option<ObjectStoreVirtualChunkResolverConfig>) -> Self {
-        Self { s3: Default::default(), config: Box::new(config) }
+        let routes = match &config {
+            Some(ObjectStoreVirtualChunkResolverConfig::S3Routes { routes, .. }) => {
+                routes.len()
+            }
+            _ => 0,
+        };
+        Self {
+            s3: Default::default(),
+            config: Box::new(config),
+            route_clients: (0..routes).map(|_| OnceCell::new()).collect(),
+        }
+    }
+
+    fn routes(&self) -> &[VirtualChunkRoute] {
+        match self.config.as_ref() {
+            Some(ObjectStoreVirtualChunkResolverConfig::S3Routes { routes, .. }) => {
+                routes
+            }
+            _ => &[],
+        }
     }
 
-    async fn s3(&self) -> &Client {
+    /// The index of the route that reads `location`, `None` for the default settings
+    pub fn route_for(&self, location: &str) -> Option<usize> {
+        self.routes()
+            .iter()
+            .enumerate()
+            .filter(|(_, route)| route.matches(location))
+            .max_by_key(|(_, route)| route.prefix.len())
+            .map(|(index, _)| index)
+    }
+
+    async fn s3(&self, location: &str) -> &Client {
+        if let Some(index) = self.route_for(location) {
+            let config = &self.routes()[index].config;
+            return self.route_clients[index]
+                .get_or_init(|| async move { mk_client(Some(config)).await })
+                .await;
+        }
         let config = self.config.clone();
         self.s3
             .get_or_init(|| async move {
                 match config.as_ref() {
-                    Some(ObjectStoreVirtualChunkResolverConfig::S3(config)) => {
-                        mk_client(Some(config)).await
-                    }
-                    None => mk_client(None).await,
+                    Some(ObjectStoreVirtualChunkResolverConfig::S3(config))
+                    | Some(ObjectStoreVirtualChunkResolverConfig::S3Routes {
+                        default: Some(config),
+                        ..
+                    }) => mk_client(Some(config)).await,
+                    _ => mk_client(None).await,
                 }
             })
             .await
@@ -88,7 +161,7 @@ impl ObjectStoreVirtualChunkResolver {
 
         let key = url.path();
         let key = key.strip_prefix('/').unwrap_or(key);
-        let mut b = self.s3().await.get_object().bucket(bucket_name).key(key);
+        let mut b = self.s3(url.as_str()).await.get_object().bucket(bucket_name).key(key);
 
         if let Some(header) = range_to_header(range) {
             b = b.range(header)
@@ -133,6 +206,38 @@ pub fn construct_valid_byte_range(
     }
 }
 
+/// Fetch `range` of the chunk `reference` points to, gathering its region if it's a slice
+pub async fn fetch_virtual_chunk(
+    resolver: &(dyn VirtualChunkResolver + Send + Sync),
+    reference: &VirtualChunkRef,
+    range: &ByteRange,
+) -> Result<Bytes, VirtualReferenceError> {
+    match &reference.slice {
+        None => {
+            let range =
+                construct_valid_byte_range(range, reference.offset, reference.length);
+            resolver.fetch_chunk(&reference.location, &range).await
+        }
+        Some(slice) => {
+            slice
+                .validate(reference.length)
+                .map_err(VirtualReferenceError::InvalidSlice)?;
+            let span = slice.byte_span();
+            let bytes = resolver
+                .fetch_chunk(
+                    &reference.location,
+                    &ByteRange::Bounded(
+                        reference.offset + span.start..reference.offset + span.end,
+                    ),
+                )
+                .await?;
+            let chunk =
+                slice.extract(&bytes).map_err(VirtualReferenceError::InvalidSlice)?;
+            Ok(range.slice(chunk))
+        }
+    }
+}
+
 impl private::Sealed for ObjectStoreVirtualChunkResolver {}
 
 #[async_trait]
@@ -176,6 +281,34 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_virtual_chunk_routes() -> Result<(), Box<dyn std::error::Error>> {
+        let partner =
+            S3Config { region: Some("eu-west-1".to_string()), ..Default::default() };
+        let config = ObjectStoreVirtualChunkResolverConfig::S3Routes {
+            default: None,
+            routes: vec![
+                VirtualChunkRoute::new("s3://archive", S3Config::default()),
+                VirtualChunkRoute::new("s3://archive/partner/", partner),
+            ],
+        };
+        let json = serde_json::to_string(&config)?;
+        assert_eq!(
+            serde_json::from_str::<ObjectStoreVirtualChunkResolverConfig>(&json)?,
+            config
+        );
+
+        let resolver = ObjectStoreVirtualChunkResolver::new(Some(config));
+        assert_eq!(resolver.route_for("s3://archive/data/c0"), Some(0));
+        // the longest prefix wins
+        assert_eq!(resolver.route_for("s3://archive/partner/c0"), Some(1));
+        assert_eq!(resolver.route_for("s3://archive/partner-2/c0"), Some(0));
+        // prefixes match whole components
+        assert_eq!(resolver.route_for("s3://archive-2/c0"), None);
+        assert_eq!(resolver.route_for("s3://other/c0"), None);
+        Ok(())
+    }
+
     #[proptest]
     fn test_properties_construct_valid_byte_range(
         #[strategy(0..10u64)] offset: u64,
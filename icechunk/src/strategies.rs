@@ -106,6 +106,8 @@ prop_compose! {
             codecs,
             storage_transformers,
             dimension_names: shape_and_dim.dimension_names,
+            chunk_transformers: None,
+            grid_origin: None,
         }
     }
 }
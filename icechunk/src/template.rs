@@ -0,0 +1,245 @@
+//! What a new repository starts with
+//!
+//! A [`RepositoryTemplate`] sets the default branch, the message and properties of the
+//! first commit, and the groups and arrays it seeds, see
+//! [`crate::Repository::init_with_template`].
+//! A default branch other than [`Ref::DEFAULT_BRANCH`] is recorded in the settings of the
+//! repository, a namespace of the refs, so [`crate::Repository::exists`] and the
+//! constructors that open the default branch find it.
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{
+        snapshot::{NodeData, NodeSnapshot, Snapshot, SnapshotProperties},
+        Path,
+    },
+    refs::{Ref, RefResult},
+    repository::{RepositoryError, RepositoryResult, ZarrArrayMetadata},
+    sources::Sources,
+    Storage, StorageError,
+};
+
+pub(crate) const SETTINGS_KEY: &str = "settings.repo/ref.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepositorySettings {
+    pub default_branch: String,
+}
+
+/// The settings of the repository, `None` if it was created with the default ones
+pub async fn fetch_settings(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Option<RepositorySettings>> {
+    match storage.get_ref(SETTINGS_KEY).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(data.as_ref())?)),
+        Err(StorageError::RefNotFound(..)) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub(crate) async fn write_settings(
+    storage: &(dyn Storage + Send + Sync),
+    settings: &RepositorySettings,
+    overwrite: bool,
+) -> RefResult<()> {
+    let content = serde_json::to_vec(settings)?;
+    storage.write_ref(SETTINGS_KEY, overwrite, Bytes::from(content)).await?;
+    Ok(())
+}
+
+/// The branch the repository opens by default
+pub async fn default_branch(storage: &(dyn Storage + Send + Sync)) -> RefResult<String> {
+    Ok(fetch_settings(storage)
+        .await?
+        .map_or_else(|| Ref::DEFAULT_BRANCH.to_string(), |s| s.default_branch))
+}
+
+/// The default branch of a repository, from the content of its settings ref, if it has one
+pub(crate) fn default_branch_in(settings: Option<&[u8]>) -> Result<String, String> {
+    match settings {
+        None => Ok(Ref::DEFAULT_BRANCH.to_string()),
+        Some(data) => serde_json::from_slice::<RepositorySettings>(data)
+            .map(|settings| settings.default_branch)
+            .map_err(|err| format!("invalid repository settings: {err}")),
+    }
+}
+
+/// A new repository, by default an empty one with a [`Ref::DEFAULT_BRANCH`] branch
+///
+/// The parents of the seeded nodes that are not seeded are created as groups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepositoryTemplate {
+    default_branch: String,
+    message: String,
+    properties: Option<SnapshotProperties>,
+    /// The seeded nodes, `None` for groups
+    nodes: BTreeMap<Path, Option<ZarrArrayMetadata>>,
+}
+
+impl Default for RepositoryTemplate {
+    fn default() -> Self {
+        Self {
+            default_branch: Ref::DEFAULT_BRANCH.to_string(),
+            message: Snapshot::INITIAL_COMMIT_MESSAGE.to_string(),
+            properties: None,
+            nodes: BTreeMap::new(),
+        }
+    }
+}
+
+impl RepositoryTemplate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_default_branch(mut self, branch: impl Into<String>) -> Self {
+        self.default_branch = branch.into();
+        self
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = message.into();
+        self
+    }
+
+    pub fn with_properties(mut self, properties: SnapshotProperties) -> Self {
+        self.properties = Some(properties);
+        self
+    }
+
+    pub fn with_group(mut self, path: Path) -> Self {
+        self.nodes.insert(path, None);
+        self
+    }
+
+    pub fn with_array(mut self, path: Path, metadata: ZarrArrayMetadata) -> Self {
+        self.nodes.insert(path, Some(metadata));
+        self
+    }
+
+    pub fn default_branch(&self) -> &str {
+        &self.default_branch
+    }
+
+    /// The first snapshot of the repository, with ids and timestamps from `sources`
+    pub(crate) fn initial_snapshot(
+        &self,
+        sources: &Sources,
+    ) -> RepositoryResult<Snapshot> {
+        if self.default_branch.is_empty() || self.default_branch.contains('/') {
+            return Err(RepositoryError::InvalidTemplate(format!(
+                "invalid default branch name `{}`",
+                self.default_branch
+            )));
+        }
+        let mut nodes = BTreeMap::new();
+        for (path, metadata) in self.nodes.iter() {
+            let node_data = match metadata {
+                Some(metadata) => {
+                    metadata.validate().map_err(|err| {
+                        RepositoryError::InvalidTemplate(format!("array `{path}`: {err}"))
+                    })?;
+                    NodeData::Array(metadata.clone(), vec![])
+                }
+                None => NodeData::Group,
+            };
+            nodes.insert(path.clone(), node_data);
+            for parent in path.ancestors().skip(1) {
+                match self.nodes.get(&parent) {
+                    Some(Some(_)) => {
+                        return Err(RepositoryError::InvalidTemplate(format!(
+                            "`{path}` is inside array `{parent}`"
+                        )))
+                    }
+                    Some(None) => {}
+                    None => {
+                        nodes.insert(parent, NodeData::Group);
+                    }
+                }
+            }
+        }
+        let nodes = nodes.into_iter().map(|(path, node_data)| NodeSnapshot {
+            id: sources.new_id(),
+            path,
+            user_attributes: None,
+            node_data,
+        });
+        let mut snapshot =
+            Snapshot::initial(self.message.clone(), self.properties.clone(), nodes);
+        snapshot.metadata.id = sources.new_id();
+        snapshot.metadata.written_at = sources.now();
+        snapshot.started_at = snapshot.metadata.written_at;
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        refs::list_refs,
+        test_utils::{array_metadata, new_in_memory_storage},
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn test_repository_template() -> Result<(), Box<dyn Error>> {
+        let storage = new_in_memory_storage();
+        let properties: SnapshotProperties =
+            [("team".to_string(), "ocean".into())].into_iter().collect();
+        let array: Path = "/climate/sst".try_into()?;
+        let template = RepositoryTemplate::new()
+            .with_default_branch("production")
+            .with_message("Created from the team template")
+            .with_properties(properties.clone())
+            .with_group("/raw".try_into()?)
+            .with_array(array.clone(), array_metadata(vec![10]));
+        Repository::init_with_template(Arc::clone(&storage), false, &template).await?;
+
+        assert!(Repository::exists(storage.as_ref()).await?);
+        assert_eq!(default_branch(storage.as_ref()).await?, "production");
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("production".to_string())]
+        );
+        let ds = Repository::open_or_create(Arc::clone(&storage)).await?.build();
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(snapshot.metadata.message, "Created from the team template");
+        assert_eq!(snapshot.properties, properties);
+        let paths: Vec<String> =
+            ds.list_nodes().await?.map(|node| node.path.to_string()).collect();
+        assert_eq!(paths, ["/", "/climate", "/climate/sst", "/raw"]);
+        assert!(ds.get_array(&array).await.is_ok());
+
+        // the template is checked before anything is written
+        let other = new_in_memory_storage();
+        let inside_array = RepositoryTemplate::new()
+            .with_array(array.clone(), array_metadata(vec![10]))
+            .with_group("/climate/sst/bad".try_into()?);
+        assert!(matches!(
+            Repository::init_with_template(Arc::clone(&other), false, &inside_array)
+                .await,
+            Err(RepositoryError::InvalidTemplate(_))
+        ));
+        let bad_branch = RepositoryTemplate::new().with_default_branch("a/b");
+        assert!(matches!(
+            Repository::init_with_template(Arc::clone(&other), false, &bad_branch).await,
+            Err(RepositoryError::InvalidTemplate(_))
+        ));
+        assert!(!Repository::exists(other.as_ref()).await?);
+        assert!(matches!(
+            Repository::init_with_template(storage, false, &RepositoryTemplate::new())
+                .await,
+            Err(RepositoryError::AlreadyInitialized)
+        ));
+        Ok(())
+    }
+}
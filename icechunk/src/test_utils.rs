@@ -0,0 +1,62 @@
+//! Helpers for the tests of crates built on icechunk, enabled by the `test_utils` feature
+//!
+//! Repositories run on an in-memory storage, no object store is needed. Wrap it in a
+//! [`LoggingStorage`] to check the requests made, or in a [`FaultyStorage`] to make some of
+//! them fail.
+use std::{num::NonZeroU64, sync::Arc};
+
+use bytes::Bytes;
+
+pub use crate::storage::{faulty::FaultyStorage, logging::LoggingStorage};
+use crate::{
+    chunk_transformer::ChunkTransformer,
+    metadata::{ArrayShape, ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+    repository::{RepositoryResult, ZarrArrayMetadata},
+    ObjectStorage, Repository, Storage,
+};
+
+pub fn new_in_memory_storage() -> Arc<dyn Storage + Send + Sync> {
+    Arc::new(ObjectStorage::new_in_memory_store(None))
+}
+
+/// A new repository in `storage`, with the default configuration
+pub async fn new_repository(
+    storage: Arc<dyn Storage + Send + Sync>,
+) -> RepositoryResult<Repository> {
+    Ok(Repository::init(storage, false).await?.build())
+}
+
+/// The metadata of a `uint8` array of `shape` with one element chunks
+pub fn array_metadata(shape: ArrayShape) -> ZarrArrayMetadata {
+    ZarrArrayMetadata {
+        chunk_shape: ChunkShape(vec![NonZeroU64::MIN; shape.len()]),
+        shape,
+        data_type: DataType::UInt8,
+        chunk_key_encoding: ChunkKeyEncoding::Slash,
+        fill_value: FillValue::UInt8(0),
+        codecs: vec![],
+        storage_transformers: None,
+        dimension_names: None,
+        chunk_transformers: None,
+        grid_origin: None,
+    }
+}
+
+/// A [`ChunkTransformer`] with id `xor` that xors every byte with the key, to check that
+/// transformed chunks are decoded
+#[derive(Debug)]
+pub struct XorTransformer(pub u8);
+
+impl ChunkTransformer for XorTransformer {
+    fn id(&self) -> &str {
+        "xor"
+    }
+
+    fn encode(&self, data: Bytes) -> Result<Bytes, String> {
+        Ok(data.iter().map(|b| b ^ self.0).collect())
+    }
+
+    fn decode(&self, data: Bytes) -> Result<Bytes, String> {
+        self.encode(data)
+    }
+}
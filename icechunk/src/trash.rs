@@ -0,0 +1,251 @@
+//! Recoverable deletes
+//!
+//! Deleted branches, and arrays deleted by commits, are recorded in a trash namespace of the
+//! refs. Trashed snapshots are kept by garbage collection, until the entry is restored, or
+//! purged after its retention window.
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, StreamExt};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{Path, SnapshotId},
+    refs::{
+        delete_branch, fetch_branch_tip, update_branch, BranchVersion, RefError,
+        RefResult,
+    },
+    storage::REF_PREFIX,
+    Storage, StorageError,
+};
+
+pub(crate) const TRASH_REF_PREFIX: &str = "trash.";
+const TRASH_KEY_NAME: &str = "ref.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrashedItem {
+    Branch(String),
+    /// An array deleted by a commit to `branch`
+    Array {
+        branch: String,
+        path: Path,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: String,
+    pub item: TrashedItem,
+    /// The tip of the deleted branch, or the last snapshot that had the deleted array
+    pub snapshot: SnapshotId,
+    pub deleted_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl TrashEntry {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn trash_key(id: &str) -> String {
+    format!("{}{}/{}", TRASH_REF_PREFIX, id, TRASH_KEY_NAME)
+}
+
+pub(crate) async fn put_in_trash(
+    storage: &(dyn Storage + Send + Sync),
+    item: TrashedItem,
+    snapshot: SnapshotId,
+    deleted_at: DateTime<Utc>,
+    retention: TimeDelta,
+) -> RefResult<TrashEntry> {
+    // ids sort by deletion time
+    let id = format!(
+        "{}-{}",
+        deleted_at.format("%Y%m%dT%H%M%S%3f"),
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+    );
+    let entry =
+        TrashEntry { id, item, snapshot, deleted_at, expires_at: deleted_at + retention };
+    let content = serde_json::to_vec(&entry)?;
+    storage.write_ref(trash_key(&entry.id).as_str(), false, Bytes::from(content)).await?;
+    Ok(entry)
+}
+
+/// Delete a branch, keeping it in the trash for `retention`
+pub async fn trash_branch(
+    storage: &(dyn Storage + Send + Sync),
+    name: &str,
+    now: DateTime<Utc>,
+    retention: TimeDelta,
+) -> RefResult<TrashEntry> {
+    let tip = fetch_branch_tip(storage, name).await?;
+    let entry = put_in_trash(
+        storage,
+        TrashedItem::Branch(name.to_string()),
+        tip.snapshot,
+        now,
+        retention,
+    )
+    .await?;
+    delete_branch(storage, name).await?;
+    Ok(entry)
+}
+
+pub async fn fetch_trash_entry(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+) -> RefResult<TrashEntry> {
+    match storage.get_ref(trash_key(id).as_str()).await {
+        Ok(data) => Ok(serde_json::from_slice(data.as_ref())?),
+        Err(StorageError::RefNotFound(..)) => Err(RefError::RefNotFound(id.to_string())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// All entries in the trash, including the expired ones that were not purged yet
+pub async fn list_trash(
+    storage: &(dyn Storage + Send + Sync),
+) -> RefResult<Vec<TrashEntry>> {
+    let mut res = Vec::new();
+    for name in storage.ref_names().await? {
+        if let Some(id) = name.strip_prefix(TRASH_REF_PREFIX) {
+            res.push(fetch_trash_entry(storage, id).await?);
+        }
+    }
+    Ok(res)
+}
+
+/// Remove an entry from the trash, its snapshot can then be garbage collected
+pub async fn delete_trash_entry(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+) -> RefResult<()> {
+    storage.delete_objects(REF_PREFIX, stream::iter([trash_key(id)]).boxed()).await?;
+    Ok(())
+}
+
+/// Recreate a trashed branch, pointing to the same snapshot it had when it was deleted
+///
+/// Fails with a conflict if a branch with the same name was created since.
+pub async fn restore_branch(
+    storage: &(dyn Storage + Send + Sync),
+    id: &str,
+) -> RefResult<BranchVersion> {
+    let entry = fetch_trash_entry(storage, id).await?;
+    let TrashedItem::Branch(name) = &entry.item else {
+        return Err(RefError::InvalidRefType(id.to_string()));
+    };
+    let version =
+        update_branch(storage, name, entry.snapshot.clone(), None, false).await?;
+    delete_trash_entry(storage, id).await?;
+    Ok(version)
+}
+
+/// Delete the trash entries expired at `now`, returning how many were deleted
+pub async fn purge_trash(
+    storage: &(dyn Storage + Send + Sync),
+    now: DateTime<Utc>,
+) -> RefResult<usize> {
+    let expired: Vec<String> = list_trash(storage)
+        .await?
+        .into_iter()
+        .filter(|entry| entry.is_expired(now))
+        .map(|entry| trash_key(&entry.id))
+        .collect();
+    Ok(storage.delete_objects(REF_PREFIX, stream::iter(expired).boxed()).await?)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkIndices},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        refs::{list_refs, Ref},
+        repository::{get_chunk, ZarrArrayMetadata},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_trash_and_restore() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_trash_retention(TimeDelta::days(1))
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![1],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        let with_array = ds.commit("main", "add array", None).await?;
+        ds.new_branch("dev").await?;
+
+        // branches
+        let entry = ds.delete_branch("dev").await?;
+        assert_eq!(entry.item, TrashedItem::Branch("dev".to_string()));
+        assert_eq!(entry.snapshot, with_array);
+        assert_eq!(
+            list_refs(storage.as_ref()).await?,
+            vec![Ref::Branch("main".to_string())]
+        );
+        assert_eq!(list_trash(storage.as_ref()).await?, vec![entry.clone()]);
+        restore_branch(storage.as_ref(), &entry.id).await?;
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "dev").await?.snapshot, with_array);
+        assert_eq!(list_trash(storage.as_ref()).await?, vec![]);
+
+        // arrays
+        ds.delete_array(path.clone()).await?;
+        ds.commit("main", "delete array", None).await?;
+        let entries = list_trash(storage.as_ref()).await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].item,
+            TrashedItem::Array { branch: "main".to_string(), path: path.clone() }
+        );
+        assert_eq!(entries[0].snapshot, with_array);
+        assert_eq!(ds.restore_array(&entries[0].id).await?, path);
+        ds.commit("main", "restore array", None).await?;
+        let chunk = get_chunk(
+            ds.get_chunk_reader(&path, &ChunkIndices(vec![0]), &ByteRange::ALL).await?,
+        )
+        .await?;
+        assert_eq!(chunk, Some(Bytes::from_static(b"hello")));
+
+        // purging
+        let entry = ds.delete_branch("dev").await?;
+        assert_eq!(purge_trash(storage.as_ref(), entry.deleted_at).await?, 0);
+        assert_eq!(
+            purge_trash(storage.as_ref(), entry.deleted_at + TimeDelta::days(1)).await?,
+            1
+        );
+        assert!(matches!(
+            restore_branch(storage.as_ref(), &entry.id).await,
+            Err(RefError::RefNotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Settings that can change while the repository is in use
+//!
+//! A [`TunablesHandle`] is shared by the components that follow it:
+//! [`crate::RepositoryBuilder::with_tunables`] for the sessions,
+//! [`crate::storage::MemCachingStorage::with_tunables`] for the cache capacities and
+//! [`crate::storage::RequestScheduler::with_tunables`] for the request concurrency. Services
+//! call [`TunablesHandle::update`], or [`TunablesHandle::reload`] from a config file, to
+//! adjust to their load without reopening sessions. The components read the new values
+//! on their next operation, requests in progress keep the values they started with.
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, ConfigResult},
+    storage::CacheConfig,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tunables {
+    /// Capacities of the caches of a [`crate::storage::MemCachingStorage`], resizing them
+    /// empties them
+    pub cache: CacheConfig,
+    /// Files fetched at a time by [`crate::Repository::prefetch`]
+    pub prefetch_concurrency: usize,
+    /// Attributes files fetched at a time by [`crate::Repository::get_attributes_bulk`]
+    pub attributes_fetch_concurrency: usize,
+    /// Storage requests in progress at a time in a [`crate::storage::RequestScheduler`],
+    /// `None` keeps the limit it was created with
+    pub max_concurrent_requests: Option<usize>,
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Self {
+            cache: CacheConfig::default(),
+            prefetch_concurrency: 10,
+            attributes_fetch_concurrency: 10,
+            max_concurrent_requests: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    current: RwLock<Arc<Tunables>>,
+    /// Incremented on every update, so followers know when to apply them again
+    version: AtomicU64,
+}
+
+/// A shared, atomically updated, [`Tunables`], see the [module documentation](self)
+#[derive(Debug, Clone, Default)]
+pub struct TunablesHandle(Arc<Shared>);
+
+impl TunablesHandle {
+    pub fn new(tunables: Tunables) -> Self {
+        Self(Arc::new(Shared {
+            current: RwLock::new(Arc::new(tunables)),
+            version: AtomicU64::new(0),
+        }))
+    }
+
+    /// The current values
+    pub fn get(&self) -> Arc<Tunables> {
+        Arc::clone(&self.0.current.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Changes with every update
+    pub fn version(&self) -> u64 {
+        self.0.version.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, tunables: Tunables) {
+        self.update(|current| *current = tunables);
+    }
+
+    /// Change some of the values, the others are kept
+    pub fn update(&self, f: impl FnOnce(&mut Tunables)) {
+        let mut current = self.0.current.write().unwrap_or_else(PoisonError::into_inner);
+        let mut tunables = current.as_ref().clone();
+        f(&mut tunables);
+        *current = Arc::new(tunables);
+        self.0.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Replace the values with the ones in the JSON file at `path`, see [`crate::config`]
+    ///
+    /// The values are kept if the file is invalid.
+    pub fn reload(&self, path: &Path) -> ConfigResult<()> {
+        self.set(config::from_file(path)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, time::Duration};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkId},
+        storage::{
+            logging::LoggingStorage, MemCachingStorage, ObjectStorage, RequestPriority,
+            RequestScheduler,
+        },
+        Storage,
+    };
+
+    #[tokio::test]
+    async fn test_live_tunables() -> Result<(), Box<dyn Error>> {
+        let handle = TunablesHandle::new(Tunables {
+            cache: CacheConfig { num_chunks: 0, ..CacheConfig::default() },
+            max_concurrent_requests: Some(1),
+            ..Tunables::default()
+        });
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let logging = Arc::new(LoggingStorage::new(backend));
+        let caching = MemCachingStorage::from_config(
+            Arc::clone(&logging) as Arc<dyn Storage + Send + Sync>,
+            &CacheConfig::default(),
+        )
+        .with_tunables(handle.clone());
+        let id = ChunkId::random();
+        caching.write_chunk(id.clone(), Bytes::from_static(b"chunk")).await?;
+        let fetches = || {
+            logging
+                .fetch_operations()
+                .iter()
+                .filter(|(op, _)| op == "fetch_chunk")
+                .count()
+        };
+        caching.fetch_chunk(&id, &ByteRange::ALL).await?;
+        caching.fetch_chunk(&id, &ByteRange::ALL).await?;
+        assert_eq!(fetches(), 2);
+
+        // the storage grows its chunk cache without being recreated
+        handle.update(|tunables| tunables.cache.num_chunks = 10);
+        caching.fetch_chunk(&id, &ByteRange::ALL).await?;
+        caching.fetch_chunk(&id, &ByteRange::ALL).await?;
+        assert_eq!(fetches(), 3);
+
+        let scheduler = RequestScheduler::with_tunables(8, handle.clone());
+        let first = scheduler.acquire(RequestPriority::Normal).await;
+        let second = tokio::spawn({
+            let scheduler = Arc::clone(&scheduler);
+            async move { scheduler.acquire(RequestPriority::Normal).await }
+        });
+        while scheduler.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+        // raising the limit lets the waiting request in, with the next acquire
+        handle.update(|tunables| tunables.max_concurrent_requests = Some(2));
+        let third = tokio::spawn({
+            let scheduler = Arc::clone(&scheduler);
+            async move { scheduler.acquire(RequestPriority::Normal).await }
+        });
+        let second = tokio::time::timeout(Duration::from_secs(5), second).await??;
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_secs(5), third).await??;
+        assert_eq!(scheduler.queued(), 0);
+        drop((second, third));
+
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("tunables.json");
+        std::fs::write(&file, r#"{"prefetch_concurrency": 2}"#)?;
+        handle.reload(&file)?;
+        assert_eq!(handle.get().prefetch_concurrency, 2);
+        assert_eq!(handle.get().cache, CacheConfig::default());
+        std::fs::write(&file, r#"{"prefetch_concurency": 4}"#)?;
+        assert!(handle.reload(&file).is_err());
+        assert_eq!(handle.get().prefetch_concurrency, 2);
+        Ok(())
+    }
+}
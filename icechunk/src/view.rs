@@ -0,0 +1,579 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    future::RemoteHandle,
+    stream, Stream, StreamExt,
+};
+use itertools::{EitherOrBoth, Itertools};
+use quick_cache::sync::Cache;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, ChunkRef, Manifest},
+        snapshot::{NodeData, NodeSnapshot, Snapshot},
+        ByteRange, ChunkIndices, IcechunkFormatError, ManifestId, Path, SnapshotId,
+    },
+    refs::fetch_branch_tip,
+    repository::{chunk_ref_byte_range, RepositoryError, RepositoryResult},
+    runtime::{default_runtime, spawn, DynRuntime},
+    storage::virtual_ref::{
+        fetch_virtual_chunk, ObjectStoreVirtualChunkResolver, VirtualChunkResolver,
+    },
+    Storage,
+};
+
+const DEFAULT_MANIFEST_CACHE_SIZE: usize = 16;
+
+#[derive(Debug)]
+struct ViewState {
+    storage: Arc<dyn Storage + Send + Sync>,
+    virtual_resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
+    snapshot: Arc<Snapshot>,
+    manifest_cache: Cache<ManifestId, Arc<Manifest>>,
+}
+
+/// How a chunk of an array compares in two snapshots, see [`SnapshotView::compare_chunks`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkPair {
+    /// Set in both, to the same reference or the same bytes
+    Same {
+        coords: ChunkIndices,
+    },
+    Different {
+        coords: ChunkIndices,
+        left: Bytes,
+        right: Bytes,
+    },
+    LeftOnly {
+        coords: ChunkIndices,
+        left: Bytes,
+    },
+    RightOnly {
+        coords: ChunkIndices,
+        right: Bytes,
+    },
+}
+
+impl ChunkPair {
+    pub fn coords(&self) -> &ChunkIndices {
+        match self {
+            ChunkPair::Same { coords }
+            | ChunkPair::Different { coords, .. }
+            | ChunkPair::LeftOnly { coords, .. }
+            | ChunkPair::RightOnly { coords, .. } => coords,
+        }
+    }
+}
+
+/// A read only handle to the contents of a snapshot
+///
+/// Unlike a [`crate::Repository`], a view has no uncommitted changes, and all its methods
+/// take `&self`, so it can serve concurrent reads. Cloning is cheap, clones share the
+/// snapshot, and a cache of the manifests read.
+#[derive(Debug, Clone)]
+pub struct SnapshotView {
+    state: Arc<ViewState>,
+}
+
+impl SnapshotView {
+    /// Create a view of `snapshot_id`, fetching the snapshot from `storage`
+    pub async fn open(
+        storage: Arc<dyn Storage + Send + Sync>,
+        snapshot_id: &SnapshotId,
+    ) -> RepositoryResult<Self> {
+        let resolver = Arc::new(ObjectStoreVirtualChunkResolver::new(None));
+        Self::with_virtual_resolver(storage, resolver, snapshot_id).await
+    }
+
+    /// Create a view of the current tip of `branch_name`
+    pub async fn open_branch(
+        storage: Arc<dyn Storage + Send + Sync>,
+        branch_name: &str,
+    ) -> RepositoryResult<Self> {
+        let tip = fetch_branch_tip(storage.as_ref(), branch_name).await?;
+        Self::open(storage, &tip.snapshot).await
+    }
+
+    pub(crate) async fn with_virtual_resolver(
+        storage: Arc<dyn Storage + Send + Sync>,
+        virtual_resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
+        snapshot_id: &SnapshotId,
+    ) -> RepositoryResult<Self> {
+        let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+        Ok(Self {
+            state: Arc::new(ViewState {
+                storage,
+                virtual_resolver,
+                snapshot,
+                manifest_cache: Cache::new(DEFAULT_MANIFEST_CACHE_SIZE),
+            }),
+        })
+    }
+
+    pub fn snapshot_id(&self) -> &SnapshotId {
+        &self.state.snapshot.metadata.id
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.state.snapshot
+    }
+
+    pub fn get_node(&self, path: &Path) -> RepositoryResult<&NodeSnapshot> {
+        self.state.snapshot.get_node(path).map_err(|err| match err {
+            IcechunkFormatError::NodeNotFound { path } => RepositoryError::NodeNotFound {
+                path,
+                message: "getting node from view".to_string(),
+            },
+            err => RepositoryError::FormatError(err),
+        })
+    }
+
+    pub fn list_nodes(&self) -> impl Iterator<Item = &NodeSnapshot> + '_ {
+        self.state.snapshot.iter()
+    }
+
+    pub async fn get_chunk_ref(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<Option<ChunkPayload>> {
+        let node = self.get_node(path)?;
+        let NodeData::Array(_, manifests) = &node.node_data else {
+            return Err(RepositoryError::NotAnArray {
+                node: node.clone(),
+                message: "getting chunk reference from view".to_string(),
+            });
+        };
+        for manifest in manifests {
+            let manifest = self.fetch_manifest(&manifest.object_id).await?;
+            match manifest.get_chunk_payload(&node.id, coords.clone()) {
+                Ok(payload) => return Ok(Some(payload.clone())),
+                Err(IcechunkFormatError::ChunkCoordinatesNotFound { .. }) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read `byte_range` of a chunk, `None` if the chunk is not set
+    pub async fn get_chunk(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        byte_range: &ByteRange,
+    ) -> RepositoryResult<Option<Bytes>> {
+        match self.get_chunk_ref(path, coords).await? {
+            Some(payload) => Ok(Some(self.fetch_payload(payload, byte_range).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Pair the chunks of the array at `path` in this view, the left, and `other`, the right
+    ///
+    /// Chunks set in neither snapshot are skipped, the others come in coordinate order. An
+    /// array missing from one of the snapshots has no chunks there. Chunks with different
+    /// references are fetched from both sides and compared byte by byte, chunks with the
+    /// same reference are not fetched.
+    pub async fn compare_chunks<'a>(
+        &'a self,
+        other: &'a SnapshotView,
+        path: &Path,
+    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<ChunkPair>> + 'a> {
+        let (left, right) =
+            match (self.chunk_payloads(path).await, other.chunk_payloads(path).await) {
+                (Err(RepositoryError::NodeNotFound { .. }), Ok(right)) => {
+                    (BTreeMap::new(), right)
+                }
+                (Ok(left), Err(RepositoryError::NodeNotFound { .. })) => {
+                    (left, BTreeMap::new())
+                }
+                (left, right) => (left?, right?),
+            };
+        let pairs = left
+            .into_iter()
+            .merge_join_by(right, |(left, _), (right, _)| left.cmp(right))
+            .collect_vec();
+        Ok(stream::iter(pairs).then(move |pair| async move {
+            let all = &ByteRange::ALL;
+            match pair {
+                EitherOrBoth::Both((coords, left), (_, right)) if left == right => {
+                    Ok(ChunkPair::Same { coords })
+                }
+                EitherOrBoth::Both((coords, left), (_, right)) => {
+                    let left = self.fetch_payload(left, all).await?;
+                    let right = other.fetch_payload(right, all).await?;
+                    if left == right {
+                        Ok(ChunkPair::Same { coords })
+                    } else {
+                        Ok(ChunkPair::Different { coords, left, right })
+                    }
+                }
+                EitherOrBoth::Left((coords, left)) => Ok(ChunkPair::LeftOnly {
+                    coords,
+                    left: self.fetch_payload(left, all).await?,
+                }),
+                EitherOrBoth::Right((coords, right)) => Ok(ChunkPair::RightOnly {
+                    coords,
+                    right: other.fetch_payload(right, all).await?,
+                }),
+            }
+        }))
+    }
+
+    async fn chunk_payloads(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<BTreeMap<ChunkIndices, ChunkPayload>> {
+        let node = self.get_node(path)?;
+        let NodeData::Array(_, manifests) = &node.node_data else {
+            return Err(RepositoryError::NotAnArray {
+                node: node.clone(),
+                message: "comparing chunks".to_string(),
+            });
+        };
+        let mut res = BTreeMap::new();
+        // later manifests don't override earlier ones, as in `get_chunk_ref`
+        for manifest in manifests.iter().rev() {
+            let manifest = self.fetch_manifest(&manifest.object_id).await?;
+            res.extend(manifest.iter(node.id.clone()));
+        }
+        Ok(res)
+    }
+
+    async fn fetch_payload(
+        &self,
+        payload: ChunkPayload,
+        byte_range: &ByteRange,
+    ) -> RepositoryResult<Bytes> {
+        match payload {
+            ChunkPayload::Ref(ChunkRef { id, offset, length }) => {
+                let byte_range = chunk_ref_byte_range(byte_range, offset, length);
+                Ok(self.state.storage.fetch_chunk(&id, &byte_range).await?)
+            }
+            ChunkPayload::Inline(bytes) => Ok(byte_range.slice(bytes)),
+            ChunkPayload::Virtual(reference) => Ok(fetch_virtual_chunk(
+                self.state.virtual_resolver.as_ref(),
+                &reference,
+                byte_range,
+            )
+            .await?),
+        }
+    }
+
+    async fn fetch_manifest(&self, id: &ManifestId) -> RepositoryResult<Arc<Manifest>> {
+        match self.state.manifest_cache.get_value_or_guard_async(id).await {
+            Ok(manifest) => Ok(manifest),
+            Err(guard) => {
+                let manifest = self.state.storage.fetch_manifests(id).await?;
+                let _fail_is_ok = guard.insert(Arc::clone(&manifest));
+                Ok(manifest)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RefreshState {
+    storage: Arc<dyn Storage + Send + Sync>,
+    branch_name: String,
+    current: RwLock<SnapshotView>,
+    subscribers: Mutex<Vec<UnboundedSender<SnapshotView>>>,
+}
+
+impl RefreshState {
+    async fn refresh(&self) -> RepositoryResult<bool> {
+        let tip = fetch_branch_tip(self.storage.as_ref(), &self.branch_name).await?;
+        if self.current.read().await.snapshot_id() == &tip.snapshot {
+            return Ok(false);
+        }
+        let view = SnapshotView::open(Arc::clone(&self.storage), &tip.snapshot).await?;
+        *self.current.write().await = view.clone();
+        self.subscribers
+            .lock()
+            .await
+            .retain(|subscriber| subscriber.unbounded_send(view.clone()).is_ok());
+        Ok(true)
+    }
+}
+
+/// A [`SnapshotView`] of the tip of a branch, that follows the branch as it moves
+///
+/// Every `interval`, a background task fetches the branch ref, and if it points to a new
+/// snapshot, atomically replaces the current view. Reads in progress keep using the view
+/// they started with. Failed refreshes are retried on the next interval. Dropping the
+/// [`RefreshingView`] stops the background task.
+#[derive(Debug)]
+pub struct RefreshingView {
+    state: Arc<RefreshState>,
+    _task: RemoteHandle<()>,
+}
+
+impl RefreshingView {
+    pub async fn open(
+        storage: Arc<dyn Storage + Send + Sync>,
+        branch_name: &str,
+        interval: Duration,
+    ) -> RepositoryResult<Self> {
+        Self::open_with_runtime(storage, branch_name, interval, default_runtime()).await
+    }
+
+    /// Like [`RefreshingView::open`], but running the background task in `runtime`
+    pub async fn open_with_runtime(
+        storage: Arc<dyn Storage + Send + Sync>,
+        branch_name: &str,
+        interval: Duration,
+        runtime: DynRuntime,
+    ) -> RepositoryResult<Self> {
+        let tip = fetch_branch_tip(storage.as_ref(), branch_name).await?;
+        let view = SnapshotView::open(Arc::clone(&storage), &tip.snapshot).await?;
+        let state = Arc::new(RefreshState {
+            storage,
+            branch_name: branch_name.to_string(),
+            current: RwLock::new(view),
+            subscribers: Mutex::new(Vec::new()),
+        });
+        let task_state = Arc::clone(&state);
+        let task_runtime = Arc::clone(&runtime);
+        let task = spawn(runtime.as_ref(), async move {
+            loop {
+                task_runtime.sleep(interval).await;
+                // errors are transient for our purposes, we try again after `interval`
+                let _ = task_state.refresh().await;
+            }
+        });
+        Ok(Self { state, _task: task })
+    }
+
+    /// The view of the latest snapshot seen
+    pub async fn current(&self) -> SnapshotView {
+        self.state.current.read().await.clone()
+    }
+
+    /// Check the branch now, without waiting for the interval
+    ///
+    /// Returns `true` if the branch had moved.
+    pub async fn refresh(&self) -> RepositoryResult<bool> {
+        self.state.refresh().await
+    }
+
+    /// A stream of the new views, one for every time the branch is seen moving
+    pub async fn subscribe(&self) -> impl Stream<Item = SnapshotView> {
+        let (tx, rx): (_, UnboundedReceiver<SnapshotView>) = unbounded();
+        self.state.subscribers.lock().await.push(tx);
+        rx
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use futures::{StreamExt, TryStreamExt};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        repository::{get_chunk, ZarrArrayMetadata},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_snapshot_view() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .build();
+        let path: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![3],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                chunk_transformers: None,
+                grid_origin: None,
+            },
+        )
+        .await?;
+        for (idx, data) in [(0, "hello"), (1, "a")] {
+            let payload = ds.get_chunk_writer()(data.into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx]), Some(payload))
+                .await?;
+        }
+        let snapshot_id = ds.commit("main", "commit", None).await?;
+
+        let view = ds.snapshot_view().await?;
+        assert_eq!(view.snapshot_id(), &snapshot_id);
+
+        // the view doesn't see uncommitted changes
+        let payload = ds.get_chunk_writer()("bye".into()).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        assert_eq!(
+            get_chunk(
+                ds.get_chunk_reader(&path, &ChunkIndices(vec![0]), &ByteRange::ALL)
+                    .await?
+            )
+            .await?,
+            Some(Bytes::from("bye"))
+        );
+        assert_eq!(
+            view.get_chunk(&path, &ChunkIndices(vec![0]), &ByteRange::ALL).await?,
+            Some(Bytes::from("hello"))
+        );
+
+        let view = SnapshotView::open(storage, &snapshot_id).await?;
+        assert_eq!(view.list_nodes().count(), 2);
+        let reads = (0..3).map(|idx| {
+            let view = view.clone();
+            let path = path.clone();
+            tokio::spawn(async move {
+                view.get_chunk(&path, &ChunkIndices(vec![idx]), &ByteRange::ALL).await
+            })
+        });
+        let mut res = Vec::new();
+        for read in reads {
+            res.push(read.await??);
+        }
+        assert_eq!(res, vec![Some(Bytes::from("hello")), Some(Bytes::from("a")), None]);
+        assert_eq!(
+            view.get_chunk(&path, &ChunkIndices(vec![0]), &ByteRange::bounded(1, 3))
+                .await?,
+            Some(Bytes::from("el"))
+        );
+        assert!(matches!(
+            view.get_node(&"/missing".try_into()?),
+            Err(RepositoryError::NodeNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compare_chunks() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into()?;
+        let meta = ZarrArrayMetadata {
+            shape: vec![4],
+            data_type: DataType::UInt8,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), meta.clone()).await?;
+        for (idx, data) in [(0, "a"), (1, "b"), (2, "c")] {
+            let payload = ds.get_chunk_writer()(data.into()).await?;
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![idx]), Some(payload))
+                .await?;
+        }
+        ds.commit("main", "production", None).await?;
+        ds.new_branch("staging").await?;
+
+        let mut staging = Repository::from_branch_tip(Arc::clone(&storage), "staging")
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        // the same bytes, in a new chunk object
+        for (idx, data) in [(0, "a"), (1, "x"), (3, "d")] {
+            let payload = staging.get_chunk_writer()(data.into()).await?;
+            staging
+                .set_chunk_ref(path.clone(), ChunkIndices(vec![idx]), Some(payload))
+                .await?;
+        }
+        staging.set_chunk_ref(path.clone(), ChunkIndices(vec![2]), None).await?;
+        let new_path: Path = "/new".try_into()?;
+        staging.add_array(new_path.clone(), meta).await?;
+        staging
+            .set_chunk_ref(
+                new_path.clone(),
+                ChunkIndices(vec![0]),
+                Some(ChunkPayload::Inline("n".into())),
+            )
+            .await?;
+        staging.commit("staging", "staging", None).await?;
+
+        let production = SnapshotView::open_branch(Arc::clone(&storage), "main").await?;
+        let staging = SnapshotView::open_branch(Arc::clone(&storage), "staging").await?;
+        let pairs: Vec<_> =
+            production.compare_chunks(&staging, &path).await?.try_collect().await?;
+        assert_eq!(
+            pairs,
+            vec![
+                ChunkPair::Same { coords: ChunkIndices(vec![0]) },
+                ChunkPair::Different {
+                    coords: ChunkIndices(vec![1]),
+                    left: Bytes::from("b"),
+                    right: Bytes::from("x")
+                },
+                ChunkPair::LeftOnly {
+                    coords: ChunkIndices(vec![2]),
+                    left: Bytes::from("c")
+                },
+                ChunkPair::RightOnly {
+                    coords: ChunkIndices(vec![3]),
+                    right: Bytes::from("d")
+                },
+            ]
+        );
+        let pairs: Vec<_> =
+            production.compare_chunks(&staging, &new_path).await?.try_collect().await?;
+        assert_eq!(
+            pairs,
+            vec![ChunkPair::RightOnly {
+                coords: ChunkIndices(vec![0]),
+                right: Bytes::from("n")
+            }]
+        );
+        assert!(matches!(
+            production.compare_chunks(&staging, &"/missing".try_into()?).await.err(),
+            Some(RepositoryError::NodeNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refreshing_view() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let initial = ds.snapshot_id().clone();
+
+        let view =
+            RefreshingView::open(Arc::clone(&storage), "main", Duration::from_millis(10))
+                .await?;
+        assert_eq!(view.current().await.snapshot_id(), &initial);
+        assert!(!view.refresh().await?);
+        let mut updates = view.subscribe().await;
+
+        ds.add_group(Path::root()).await?;
+        let snapshot_id = ds.commit("main", "commit", None).await?;
+        let update =
+            tokio::time::timeout(Duration::from_secs(5), updates.next()).await?.unwrap();
+        assert_eq!(update.snapshot_id(), &snapshot_id);
+        assert_eq!(view.current().await.snapshot_id(), &snapshot_id);
+        assert_eq!(view.current().await.list_nodes().count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path as StdPath, PathBuf},
+};
+
+use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::{
+    format::ChunkId,
+    memory::{MemoryBudget, MemoryPool},
+    runtime::{default_runtime, spawn_blocking, DynRuntime},
+    Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum WriteBufferError {
+    #[error("error accessing the spill directory {0}")]
+    Io(#[from] io::Error),
+    #[error("error uploading buffered chunk {0}")]
+    Storage(#[from] StorageError),
+}
+
+pub type WriteBufferResult<A> = Result<A, WriteBufferError>;
+
+const UPLOAD_CONCURRENCY: usize = 10;
+
+#[derive(Debug)]
+enum BufferedChunk {
+    Memory(Bytes),
+    Spilled(PathBuf),
+}
+
+#[derive(Debug, Default)]
+struct BufferState {
+    chunks: HashMap<ChunkId, BufferedChunk>,
+    memory_bytes: u64,
+}
+
+/// Holds the chunks written by a session until they are uploaded during commit
+///
+/// Chunks are kept in memory until they add up to `memory_limit_bytes`, after that, new
+/// chunks are written to a file in `spill_dir`, and read back at upload time. This allows
+/// writing, in a single commit, more data than fits in memory. Spilled files are deleted
+/// after upload, or when the buffer is dropped.
+#[derive(Debug)]
+pub struct ChunkWriteBuffer {
+    spill_dir: PathBuf,
+    memory_limit_bytes: u64,
+    state: Mutex<BufferState>,
+    runtime: DynRuntime,
+    memory_pool: Option<MemoryPool>,
+}
+
+impl ChunkWriteBuffer {
+    pub fn new(spill_dir: impl Into<PathBuf>, memory_limit_bytes: u64) -> Self {
+        Self {
+            spill_dir: spill_dir.into(),
+            memory_limit_bytes,
+            state: Mutex::new(BufferState::default()),
+            runtime: default_runtime(),
+            memory_pool: None,
+        }
+    }
+
+    /// Account the chunks kept in memory in `budget`, chunks are spilled when the budget
+    /// is exhausted, even if `memory_limit_bytes` is not reached
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.memory_pool =
+            Some(budget.pool("write_buffer", Some(self.memory_limit_bytes)));
+        self
+    }
+
+    /// Use `runtime` for the blocking file operations, instead of tokio
+    pub fn with_runtime(mut self, runtime: DynRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub async fn insert(&self, id: ChunkId, bytes: Bytes) -> WriteBufferResult<()> {
+        let mut state = self.state.lock().await;
+        let size = bytes.len() as u64;
+        let fits = state.memory_bytes + size <= self.memory_limit_bytes
+            && self.memory_pool.as_ref().is_none_or(|pool| pool.try_reserve(size));
+        let chunk = if fits {
+            state.memory_bytes += size;
+            BufferedChunk::Memory(bytes)
+        } else {
+            let path = self.spill_dir.join(id.to_string());
+            let dir = self.spill_dir.clone();
+            let file = path.clone();
+            spawn_blocking(self.runtime.as_ref(), move || {
+                fs::create_dir_all(dir)?;
+                fs::write(file, bytes)
+            })
+            .await
+            .map_err(io::Error::other)??;
+            BufferedChunk::Spilled(path)
+        };
+        if let Some(old) = state.chunks.insert(id, chunk) {
+            self.discard(&mut state, old).await?;
+        }
+        Ok(())
+    }
+
+    /// Get the bytes of a buffered chunk, `None` if the chunk is not in the buffer
+    pub async fn get(&self, id: &ChunkId) -> WriteBufferResult<Option<Bytes>> {
+        let path = match self.state.lock().await.chunks.get(id) {
+            None => return Ok(None),
+            Some(BufferedChunk::Memory(bytes)) => return Ok(Some(bytes.clone())),
+            Some(BufferedChunk::Spilled(path)) => path.clone(),
+        };
+        let bytes = spawn_blocking(self.runtime.as_ref(), move || fs::read(path))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(Some(Bytes::from(bytes)))
+    }
+
+    /// Remove a chunk from the buffer, returns `false` if the chunk was not buffered
+    pub async fn remove(&self, id: &ChunkId) -> WriteBufferResult<bool> {
+        let mut state = self.state.lock().await;
+        match state.chunks.remove(id) {
+            Some(chunk) => {
+                self.discard(&mut state, chunk).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Remove every chunk from the buffer, without uploading them
+    pub async fn clear(&self) -> WriteBufferResult<()> {
+        let mut state = self.state.lock().await;
+        let chunks: Vec<_> = state.chunks.drain().map(|(_, chunk)| chunk).collect();
+        for chunk in chunks {
+            self.discard(&mut state, chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Upload one buffered chunk to `storage`, returns `false` if the chunk was not buffered
+    pub async fn upload_chunk(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        id: &ChunkId,
+    ) -> WriteBufferResult<bool> {
+        match self.get(id).await? {
+            Some(bytes) => {
+                storage.write_chunk(id.clone(), bytes).await?;
+                self.remove(id).await
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Upload every buffered chunk to `storage`, returns the number of chunks uploaded
+    ///
+    /// Chunks are removed from the buffer only after they are uploaded, so a failed upload
+    /// can be retried.
+    pub async fn upload(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+    ) -> WriteBufferResult<usize> {
+        self.upload_with_progress(storage, &|_| {}).await
+    }
+
+    /// Like [`ChunkWriteBuffer::upload`], calling `on_uploaded` with the size of each chunk
+    /// once it's uploaded
+    pub async fn upload_with_progress(
+        &self,
+        storage: &(dyn Storage + Send + Sync),
+        on_uploaded: &(dyn Fn(u64) + Send + Sync),
+    ) -> WriteBufferResult<usize> {
+        let ids: Vec<_> = self.state.lock().await.chunks.keys().cloned().collect();
+        let uploaded: Vec<bool> = stream::iter(ids)
+            .map(|id| async move {
+                let Some(bytes) = self.get(&id).await? else {
+                    return Ok::<_, WriteBufferError>(false);
+                };
+                let len = bytes.len() as u64;
+                storage.write_chunk(id.clone(), bytes).await?;
+                let uploaded = self.remove(&id).await?;
+                on_uploaded(len);
+                Ok(uploaded)
+            })
+            .buffer_unordered(UPLOAD_CONCURRENCY)
+            .try_collect()
+            .await?;
+        Ok(uploaded.into_iter().filter(|uploaded| *uploaded).count())
+    }
+
+    pub async fn buffered_chunks(&self) -> usize {
+        self.state.lock().await.chunks.len()
+    }
+
+    pub async fn spilled_chunks(&self) -> usize {
+        self.state
+            .lock()
+            .await
+            .chunks
+            .values()
+            .filter(|chunk| matches!(chunk, BufferedChunk::Spilled(_)))
+            .count()
+    }
+
+    async fn discard(
+        &self,
+        state: &mut BufferState,
+        chunk: BufferedChunk,
+    ) -> io::Result<()> {
+        match chunk {
+            BufferedChunk::Memory(bytes) => {
+                state.memory_bytes -= bytes.len() as u64;
+                if let Some(pool) = &self.memory_pool {
+                    pool.release(bytes.len() as u64);
+                }
+                Ok(())
+            }
+            BufferedChunk::Spilled(path) => {
+                spawn_blocking(self.runtime.as_ref(), move || remove_spilled(&path))
+                    .await
+                    .map_err(io::Error::other)?
+            }
+        }
+    }
+}
+
+impl Drop for ChunkWriteBuffer {
+    fn drop(&mut self) {
+        for chunk in self.state.get_mut().chunks.values() {
+            if let BufferedChunk::Spilled(path) = chunk {
+                // best effort, the files are in a temporary directory anyway
+                let _ = remove_spilled(path);
+            }
+        }
+    }
+}
+
+fn remove_spilled(path: &StdPath) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::ByteRange, runtime::tests::ThreadRuntime, ObjectStorage};
+
+    #[tokio::test]
+    async fn test_chunks_spill_to_disk() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let buffer = ChunkWriteBuffer::new(dir.path(), 10);
+        let ids: Vec<_> = (0..4).map(|_| ChunkId::random()).collect();
+        for id in ids.iter() {
+            buffer.insert(id.clone(), Bytes::from_static(b"12345")).await?;
+        }
+        assert_eq!(buffer.buffered_chunks().await, 4);
+        assert_eq!(buffer.spilled_chunks().await, 2);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+        for id in ids.iter() {
+            assert_eq!(buffer.get(id).await?, Some(Bytes::from_static(b"12345")));
+        }
+
+        assert!(buffer.remove(&ids[3]).await?);
+        assert!(!buffer.remove(&ids[3]).await?);
+        assert_eq!(buffer.get(&ids[3]).await?, None);
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        assert_eq!(buffer.upload(storage.as_ref()).await?, 3);
+        assert_eq!(buffer.buffered_chunks().await, 0);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+        for id in ids[0..3].iter() {
+            assert_eq!(storage.fetch_chunk(id, &ByteRange::ALL).await?, "12345");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spilled_files_are_deleted_on_drop() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let buffer = ChunkWriteBuffer::new(dir.path(), 0);
+        buffer.insert(ChunkId::random(), Bytes::from_static(b"chunk")).await?;
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        drop(buffer);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_without_tokio() -> Result<(), Box<dyn Error>> {
+        futures::executor::block_on(async {
+            let dir = tempfile::tempdir()?;
+            let buffer = ChunkWriteBuffer::new(dir.path(), 0)
+                .with_runtime(Arc::new(ThreadRuntime));
+            let id = ChunkId::random();
+            buffer.insert(id.clone(), Bytes::from_static(b"chunk")).await?;
+            assert_eq!(buffer.spilled_chunks().await, 1);
+            assert_eq!(buffer.get(&id).await?, Some(Bytes::from_static(b"chunk")));
+            assert!(buffer.remove(&id).await?);
+            assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_spill_when_budget_is_exhausted() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let budget = MemoryBudget::new(8);
+        let buffer = ChunkWriteBuffer::new(dir.path(), 100).with_memory_budget(&budget);
+        let first = ChunkId::random();
+        buffer.insert(first.clone(), Bytes::from_static(b"12345")).await?;
+        buffer.insert(ChunkId::random(), Bytes::from_static(b"12345")).await?;
+        assert_eq!(buffer.spilled_chunks().await, 1);
+        assert_eq!(budget.usage().pools["write_buffer"], 5);
+        buffer.remove(&first).await?;
+        assert_eq!(budget.used_bytes(), 0);
+        Ok(())
+    }
+}
@@ -22,27 +22,32 @@ use tokio::sync::RwLock;
 
 use crate::{
     change_set::ChangeSet,
+    config::{self, ConfigResult},
     format::{
-        manifest::VirtualChunkRef,
-        snapshot::{NodeData, UserAttributesSnapshot},
-        ByteRange, ChunkOffset, IcechunkFormatError, SnapshotId,
+        hashing::HashAlgorithm, manifest::VirtualChunkRef, snapshot::NodeData, ByteRange,
+        ChunkOffset, IcechunkFormatError, SnapshotId,
     },
     refs::{update_branch, BranchVersion, Ref, RefError},
     repository::{
-        get_chunk, raise_if_invalid_snapshot_id, ArrayShape, ChunkIndices,
-        ChunkKeyEncoding, ChunkPayload, ChunkShape, Codec, DataType, DimensionNames,
-        FillValue, Path, RepositoryError, RepositoryResult, StorageTransformer,
-        UserAttributes, ZarrArrayMetadata,
+        get_chunk, load_user_attributes, raise_if_invalid_snapshot_id, ArrayShape,
+        ChunkIndices, ChunkKeyEncoding, ChunkPayload, ChunkShape, Codec, DataType,
+        DimensionNames, FillValue, Path, RepositoryError, RepositoryResult,
+        StorageTransformer, UserAttributes, ZarrArrayMetadata,
     },
     storage::{
         s3::{S3Config, S3Storage},
         virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+        CacheConfig,
     },
-    ObjectStorage, Repository, RepositoryBuilder, SnapshotMetadata, Storage,
+    MemCachingStorage, ObjectStorage, Repository, RepositoryBuilder, SnapshotMetadata,
+    Storage,
 };
 
 pub use crate::format::ObjectId;
 
+/// The S3 compatible endpoint of Google Cloud Storage
+pub const GCS_S3_ENDPOINT: &str = "https://storage.googleapis.com";
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(tag = "type")]
 #[non_exhaustive]
@@ -63,6 +68,60 @@ pub enum StorageConfig {
 }
 
 impl StorageConfig {
+    /// Parse a storage URL: `s3://bucket/prefix`, `gs://bucket/prefix`, `file:///path` or
+    /// `memory://prefix`
+    ///
+    /// `s3_config` is used only for S3 and GCS URLs, by default, credentials and region are
+    /// read from the environment. GCS buckets are accessed through their S3 compatible API,
+    /// at [`GCS_S3_ENDPOINT`] unless `s3_config` sets another endpoint, with HMAC keys as
+    /// credentials.
+    pub fn from_url(url: &str, s3_config: Option<S3Config>) -> Result<Self, String> {
+        let parsed = url::Url::parse(url)
+            .map_err(|e| format!("invalid storage URL `{url}`: {e}"))?;
+        let path = parsed.path().trim_matches('/').to_string();
+        let bucket = || {
+            parsed
+                .host_str()
+                .filter(|bucket| !bucket.is_empty())
+                .map(String::from)
+                .ok_or_else(|| format!("missing bucket in storage URL `{url}`"))
+        };
+        match parsed.scheme() {
+            "s3" => Ok(StorageConfig::S3ObjectStore {
+                bucket: bucket()?,
+                prefix: path,
+                config: s3_config,
+            }),
+            "gs" => {
+                let mut config = s3_config.unwrap_or_default();
+                config.endpoint.get_or_insert_with(|| GCS_S3_ENDPOINT.to_string());
+                // GCS ignores the region, but the client needs one
+                config.region.get_or_insert_with(|| "auto".to_string());
+                Ok(StorageConfig::S3ObjectStore {
+                    bucket: bucket()?,
+                    prefix: path,
+                    config: Some(config),
+                })
+            }
+            "file" => {
+                let root = parsed
+                    .to_file_path()
+                    .map_err(|_| format!("invalid local path in storage URL `{url}`"))?;
+                Ok(StorageConfig::LocalFileSystem { root })
+            }
+            "memory" => {
+                let prefix = [parsed.host_str().unwrap_or_default(), path.as_str()]
+                    .into_iter()
+                    .filter(|part| !part.is_empty())
+                    .join("/");
+                Ok(StorageConfig::InMemory { prefix: Some(prefix) })
+            }
+            scheme => Err(format!(
+                "unsupported storage URL scheme `{scheme}`, use one of s3, gs, file or memory"
+            )),
+        }
+    }
+
     pub async fn make_storage(&self) -> Result<Arc<dyn Storage + Send + Sync>, String> {
         match self {
             StorageConfig::InMemory { prefix } => {
@@ -110,6 +169,8 @@ pub struct RepositoryConfig {
     pub unsafe_overwrite_refs: Option<bool>,
     pub change_set_bytes: Option<Vec<u8>>,
     pub virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+    /// Record the content hash of new snapshots, with this algorithm
+    pub content_hash: Option<HashAlgorithm>,
 }
 
 impl RepositoryConfig {
@@ -117,6 +178,17 @@ impl RepositoryConfig {
         Self::default()
     }
 
+    /// Load the config from a JSON file, see [`crate::config`]
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> ConfigResult<Self> {
+        config::from_file(path.as_ref())
+    }
+
+    /// Load the config from the `ICECHUNK_REPOSITORY__` environment variables, the same
+    /// that set the repository in [`ConsolidatedStore::from_env`]
+    pub fn from_env() -> ConfigResult<Self> {
+        config::from_env(format!("{}REPOSITORY__", config::ENV_PREFIX).as_str())
+    }
+
     pub fn existing(version: VersionInfo) -> Self {
         Self { version: Some(version), ..Self::default() }
     }
@@ -149,6 +221,11 @@ impl RepositoryConfig {
         self
     }
 
+    pub fn with_content_hash(mut self, algorithm: HashAlgorithm) -> Self {
+        self.content_hash = Some(algorithm);
+        self
+    }
+
     pub async fn make_repository(
         &self,
         storage: Arc<dyn Storage + Send + Sync>,
@@ -191,6 +268,9 @@ impl RepositoryConfig {
         if let Some(config) = &self.virtual_ref_config {
             builder.with_virtual_ref_config(config.clone());
         }
+        if let Some(algorithm) = self.content_hash {
+            builder.with_record_content_hash(true).with_hash_algorithm(algorithm);
+        }
         if let Some(change_set_bytes) = &self.change_set_bytes {
             let change_set = ChangeSet::import_from_bytes(change_set_bytes)
                 .map_err(|err| format!("Error parsing change set: {err}"))?;
@@ -204,13 +284,15 @@ impl RepositoryConfig {
 
 #[skip_serializing_none]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct StoreOptions {
     pub get_partial_values_concurrency: u16,
+    pub cache: CacheConfig,
 }
 
 impl Default for StoreOptions {
     fn default() -> Self {
-        Self { get_partial_values_concurrency: 10 }
+        Self { get_partial_values_concurrency: 10, cache: CacheConfig::default() }
     }
 }
 
@@ -223,6 +305,18 @@ pub struct ConsolidatedStore {
 }
 
 impl ConsolidatedStore {
+    /// Load the storage, repository and store options from a JSON file, see
+    /// [`crate::config`]
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> ConfigResult<Self> {
+        config::from_file(path.as_ref())
+    }
+
+    /// Load the storage, repository and store options from the `ICECHUNK_` environment
+    /// variables, like `ICECHUNK_STORAGE__TYPE=s3`, see [`crate::config`]
+    pub fn from_env() -> ConfigResult<Self> {
+        config::from_env(config::ENV_PREFIX)
+    }
+
     pub fn with_version(mut self, version: VersionInfo) -> Self {
         self.repository.version = Some(version);
         self
@@ -252,6 +346,17 @@ pub enum ListDirItem {
     Prefix(String),
 }
 
+impl StoreError {
+    /// The operation that failed may succeed if it's retried as is
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StoreError::RepositoryError(err) => err.is_retryable(),
+            StoreError::RefError(err) => err.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
 pub type StoreResult<A> = Result<A, StoreError>;
 
 #[derive(Debug, Clone, PartialEq, Eq, Error)]
@@ -329,7 +434,12 @@ impl Store {
         consolidated: &ConsolidatedStore,
         mode: AccessMode,
     ) -> Result<Self, String> {
-        let storage = consolidated.storage.make_cached_storage().await?;
+        let options = consolidated.config.clone().unwrap_or_default();
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(MemCachingStorage::from_config(
+                consolidated.storage.make_storage().await?,
+                &options.cache,
+            ));
         let (repository, branch) =
             consolidated.repository.make_repository(storage).await?;
         Ok(Self::from_repository(repository, mode, branch, consolidated.config.clone()))
@@ -521,6 +631,28 @@ impl Store {
         Ok(result)
     }
 
+    /// Commit, making it safe to retry with the same `idempotency_key`
+    ///
+    /// See [`Repository::commit_idempotent`].
+    pub async fn commit_idempotent(
+        &self,
+        message: &str,
+        idempotency_key: &str,
+    ) -> StoreResult<SnapshotId> {
+        let Some(branch) = &self.current_branch else {
+            return Err(StoreError::NotOnBranch);
+        };
+
+        let result = self
+            .repository
+            .write()
+            .await
+            .deref_mut()
+            .commit_idempotent(branch, message, None, idempotency_key)
+            .await?;
+        Ok(result)
+    }
+
     /// Tag the given snapshot with a specified tag
     pub async fn tag(&self, tag: &str, snapshot_id: &SnapshotId) -> StoreResult<()> {
         self.repository.write().await.deref_mut().tag(tag, snapshot_id).await?;
@@ -660,13 +792,18 @@ impl Store {
             Key::Chunk { node_path, coords } => {
                 match locked_repo {
                     Some(repo) => {
-                        let writer = repo.get_chunk_writer();
+                        let writer = repo.get_array_chunk_writer(&node_path).await?;
                         let payload = writer(value).await?;
                         repo.set_chunk_ref(node_path, coords, Some(payload)).await?
                     }
                     None => {
                         // we only lock the repository to get the writer
-                        let writer = self.repository.read().await.get_chunk_writer();
+                        let writer = self
+                            .repository
+                            .read()
+                            .await
+                            .get_array_chunk_writer(&node_path)
+                            .await?;
                         // then we can write the bytes without holding the lock
                         let payload = writer(value).await?;
                         // and finally we lock for write and update the reference
@@ -749,6 +886,9 @@ impl Store {
                     NodeData::Group => {
                         Ok(guard.deref_mut().delete_group(node_path).await?)
                     }
+                    NodeData::Custom(_) => {
+                        Ok(guard.deref_mut().delete_custom_node(node_path).await?)
+                    }
                 }
             }
             Key::Chunk { node_path, coords } => {
@@ -947,10 +1087,17 @@ async fn set_array_meta(
     array_meta: ArrayMetadata,
     repo: &mut Repository,
 ) -> Result<(), StoreError> {
-    if repo.get_array(&path).await.is_ok() {
+    if let Ok(node) = repo.get_array(&path).await {
         // TODO: we don't necessarily need to update both
         repo.set_user_attributes(path.clone(), array_meta.attributes).await?;
-        repo.update_array(path, array_meta.zarr_metadata).await?;
+        // zarr metadata doesn't have the chunk transformers or the grid origin, the array
+        // keeps its own
+        let mut zarr_metadata = array_meta.zarr_metadata;
+        if let NodeData::Array(old, _) = node.node_data {
+            zarr_metadata.chunk_transformers = old.chunk_transformers;
+            zarr_metadata.grid_origin = old.grid_origin;
+        }
+        repo.update_array(path, zarr_metadata).await?;
         Ok(())
     } else {
         repo.add_array(path.clone(), array_meta.zarr_metadata).await?;
@@ -986,15 +1133,11 @@ async fn get_metadata(
     let node = repo.get_node(path).await.map_err(|_| {
         StoreError::NotFound(KeyNotFoundError::NodeNotFound { path: path.clone() })
     })?;
-    let user_attributes = match node.user_attributes {
-        None => None,
-        Some(UserAttributesSnapshot::Inline(atts)) => Some(atts),
-        // FIXME: implement
-        #[allow(clippy::unimplemented)]
-        Some(UserAttributesSnapshot::Ref(_)) => unimplemented!(),
-    };
+    let user_attributes =
+        load_user_attributes(repo.storage().as_ref(), node.user_attributes).await?;
     let full_metadata = match node.node_data {
-        NodeData::Group => {
+        // Zarr sees custom nodes as groups, so they can have children
+        NodeData::Group | NodeData::Custom(_) => {
             Ok::<Bytes, StoreError>(GroupMetadata::new(user_attributes).to_bytes())
         }
         NodeData::Array(zarr_metadata, _) => {
@@ -1056,7 +1199,7 @@ async fn exists(key: &str, repo: &Repository) -> StoreResult<bool> {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum Key {
+pub(crate) enum Key {
     Metadata { node_path: Path },
     Chunk { node_path: Path, coords: ChunkIndices },
     ZarrV2(String),
@@ -1091,20 +1234,19 @@ impl Key {
                 let path = path.strip_suffix('/').unwrap_or(path);
                 if coords.is_empty() {
                     Ok(Key::Chunk {
-                        node_path: format!("/{path}").try_into().map_err(|_| {
+                        node_path: Path::normalized(path).map_err(|_| {
                             StoreError::InvalidKey { key: key.to_string() }
                         })?,
                         coords: ChunkIndices(vec![]),
                     })
                 } else {
-                    let absolute = format!("/{path}")
-                        .try_into()
+                    let absolute = Path::normalized(path)
                         .map_err(|_| StoreError::InvalidKey { key: key.to_string() })?;
                     coords
                         .strip_prefix('/')
                         .ok_or(StoreError::InvalidKey { key: key.to_string() })?
                         .split('/')
-                        .map(|s| s.parse::<u32>())
+                        .map(|s| s.parse::<u64>())
                         .collect::<Result<Vec<_>, _>>()
                         .map(|coords| Key::Chunk {
                             node_path: absolute,
@@ -1122,8 +1264,7 @@ impl Key {
         } else if let Some(path) = key.strip_suffix(Key::METADATA_SUFFIX) {
             // we need to be careful indexing into utf8 strings
             Ok(Key::Metadata {
-                node_path: format!("/{path}")
-                    .try_into()
+                node_path: Path::normalized(path)
                     .map_err(|_| StoreError::InvalidKey { key: key.to_string() })?,
             })
         } else {
@@ -1213,6 +1354,8 @@ impl TryFrom<ZarrArrayMetadataSerialzer> for ZarrArrayMetadata {
                 codecs,
                 storage_transformers,
                 dimension_names,
+                chunk_transformers: None,
+                grid_origin: None,
             })
         }
     }
@@ -1229,6 +1372,9 @@ impl From<ZarrArrayMetadata> for ZarrArrayMetadataSerialzer {
             codecs,
             storage_transformers,
             dimension_names,
+            // icechunk settings are not part of the zarr metadata
+            chunk_transformers: _,
+            grid_origin: _,
         } = value;
         {
             fn fill_value_to_json(f: FillValue) -> serde_json::Value {
@@ -1282,7 +1428,12 @@ impl From<ZarrArrayMetadata> for ZarrArrayMetadataSerialzer {
                 }
             }
 
-            let fill_value = fill_value_to_json(fill_value);
+            let fill_value = match (&data_type, fill_value) {
+                (DataType::DateTime64(_), FillValue::Int64(FillValue::NAT)) => {
+                    FillValue::NAT_STR.into()
+                }
+                (_, fill_value) => fill_value_to_json(fill_value),
+            };
             ZarrArrayMetadataSerialzer {
                 shape,
                 data_type,
@@ -1709,6 +1860,8 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            chunk_transformers: None,
+            grid_origin: None,
         };
         let zarr_meta = ArrayMetadata::new(None, zarr_meta);
 
@@ -1718,7 +1871,21 @@ mod tests {
             )
             .unwrap(),
             zarr_meta,
+        );
+
+        // datetime with NaT roundtrip
+        let meta = serde_json::from_str::<ArrayMetadata>(
+            r#"{"zarr_format":3,"node_type":"array","shape":[2],"data_type":"datetime64[ns]","chunk_grid":{"name":"regular","configuration":{"chunk_shape":[1]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":"NaT","codecs":[{"name":"bytes","configuration":{"endian":"little"}}]}"#,
         )
+        .unwrap();
+        assert_eq!(
+            meta.zarr_metadata.data_type,
+            DataType::DateTime64(crate::metadata::TimeUnit::Nanosecond)
+        );
+        assert_eq!(meta.zarr_metadata.fill_value, FillValue::Int64(FillValue::NAT));
+        let json = serde_json::to_value(&meta).unwrap();
+        assert_eq!(json["fill_value"], "NaT");
+        assert_eq!(json["data_type"], "datetime64[ns]");
     }
 
     #[tokio::test]
@@ -2106,6 +2273,59 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_scalar_array() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let mut store = Store::from_repository(
+            ds,
+            AccessMode::ReadWrite,
+            Some("main".to_string()),
+            None,
+        );
+        store
+            .borrow_mut()
+            .set(
+                "zarr.json",
+                Bytes::copy_from_slice(br#"{"zarr_format":3, "node_type":"group"}"#),
+            )
+            .await?;
+        let zarr_meta = Bytes::copy_from_slice(br#"{"zarr_format":3,"node_type":"array","shape":[],"data_type":"float64","chunk_grid":{"name":"regular","configuration":{"chunk_shape":[]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":0.0,"codecs":[{"name":"bytes","configuration":{"endian":"little"}}]}"#);
+        store.set("scalar/zarr.json", zarr_meta).await?;
+        let data = Bytes::copy_from_slice(&42.0f64.to_le_bytes());
+        store.set("scalar/c", data.clone()).await?;
+        assert_eq!(store.get("scalar/c", &ByteRange::ALL).await?, data);
+        assert_eq!(
+            all_keys(&store).await?,
+            vec![
+                "scalar/c".to_string(),
+                "scalar/zarr.json".to_string(),
+                "zarr.json".to_string()
+            ]
+        );
+        let mut dir = store.list_dir("scalar/").await?.try_collect::<Vec<_>>().await?;
+        dir.sort();
+        assert_eq!(dir, vec!["c".to_string(), "zarr.json".to_string()]);
+        store.commit("scalar").await?;
+        let store = Store::from_repository(
+            Repository::from_branch_tip(storage, "main").await?.build(),
+            AccessMode::ReadOnly,
+            None,
+            None,
+        );
+        assert_eq!(store.get("scalar/c", &ByteRange::ALL).await?, data);
+        let stored: serde_json::Value = serde_json::from_slice(
+            &store.get("scalar/zarr.json", &ByteRange::ALL).await?,
+        )?;
+        assert_eq!(stored["shape"], serde_json::json!([]));
+        assert_eq!(
+            stored["chunk_grid"]["configuration"]["chunk_shape"],
+            serde_json::json!([])
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_dir() -> Result<(), Box<dyn std::error::Error>> {
         let storage: Arc<dyn Storage + Send + Sync> =
@@ -2622,6 +2842,53 @@ mod tests {
         readable_store.get("zarr.json", &ByteRange::ALL).await.unwrap();
     }
 
+    #[test]
+    fn test_storage_config_from_url() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            StorageConfig::from_url("s3://bucket/some/prefix/", None)?,
+            StorageConfig::S3ObjectStore {
+                bucket: "bucket".to_string(),
+                prefix: "some/prefix".to_string(),
+                config: None,
+            }
+        );
+        assert_eq!(
+            StorageConfig::from_url("file:///tmp/repo", None)?,
+            StorageConfig::LocalFileSystem { root: PathBuf::from("/tmp/repo") }
+        );
+        assert_eq!(
+            StorageConfig::from_url("memory://some/prefix", None)?,
+            StorageConfig::InMemory { prefix: Some("some/prefix".to_string()) }
+        );
+        assert_eq!(
+            StorageConfig::from_url("gs://bucket/prefix", None)?,
+            StorageConfig::S3ObjectStore {
+                bucket: "bucket".to_string(),
+                prefix: "prefix".to_string(),
+                config: Some(S3Config {
+                    endpoint: Some(GCS_S3_ENDPOINT.to_string()),
+                    region: Some("auto".to_string()),
+                    ..Default::default()
+                }),
+            }
+        );
+        let proxy = S3Config {
+            endpoint: Some("http://localhost:4443".to_string()),
+            ..Default::default()
+        };
+        let config = StorageConfig::from_url("gs://bucket", Some(proxy))?;
+        assert!(matches!(
+            config,
+            StorageConfig::S3ObjectStore { config: Some(S3Config { endpoint: Some(e), .. }), .. }
+                if e == "http://localhost:4443"
+        ));
+        assert!(StorageConfig::from_url("s3:///prefix", None).is_err());
+        assert!(StorageConfig::from_url("gs:///prefix", None).is_err());
+        assert!(StorageConfig::from_url("az://container/prefix", None).is_err());
+        assert!(StorageConfig::from_url("not a url", None).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_store_config_deserialization() -> Result<(), Box<dyn std::error::Error>> {
         let expected = ConsolidatedStore {
@@ -2634,8 +2901,12 @@ mod tests {
                 unsafe_overwrite_refs: Some(true),
                 change_set_bytes: None,
                 virtual_ref_config: None,
+                content_hash: None,
             },
-            config: Some(StoreOptions { get_partial_values_concurrency: 100 }),
+            config: Some(StoreOptions {
+                get_partial_values_concurrency: 100,
+                cache: CacheConfig::default(),
+            }),
         };
 
         let json = r#"
@@ -2669,6 +2940,7 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    content_hash: None,
                 },
                 config: None,
                 ..expected.clone()
@@ -2690,6 +2962,7 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    content_hash: None,
                 },
                 config: None,
                 ..expected.clone()
@@ -2710,6 +2983,7 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    content_hash: None,
                 },
                 storage: StorageConfig::InMemory { prefix: Some("prefix".to_string()) },
                 config: None,
@@ -2730,6 +3004,7 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    content_hash: None,
                 },
                 storage: StorageConfig::InMemory { prefix: None },
                 config: None,
@@ -2750,6 +3025,7 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    content_hash: None,
                 },
                 storage: StorageConfig::S3ObjectStore {
                     bucket: String::from("test"),
@@ -2785,6 +3061,7 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    content_hash: None,
                 },
                 storage: StorageConfig::S3ObjectStore {
                     bucket: String::from("test"),
@@ -2798,6 +3075,7 @@ mod tests {
                             session_token: None,
                         }),
                         allow_http: true,
+                        ..Default::default()
                     })
                 },
                 config: None,
@@ -49,6 +49,8 @@ async fn test_concurrency() -> Result<(), Box<dyn std::error::Error>> {
             configuration: None,
         }]),
         dimension_names: Some(vec![Some("x".to_string()), Some("y".to_string())]),
+        chunk_transformers: None,
+        grid_origin: None,
     };
 
     let new_array_path: Path = "/array".try_into().unwrap();
@@ -67,14 +69,14 @@ async fn test_concurrency() -> Result<(), Box<dyn std::error::Error>> {
         for y in 0..N {
             let ds = Arc::clone(&ds);
             let barrier = Arc::clone(&barrier);
-            set.spawn(async move { read_task(ds, x as u32, y as u32, barrier).await });
+            set.spawn(async move { read_task(ds, x as u64, y as u64, barrier).await });
         }
     }
 
     for x in 0..N {
         for y in 0..N {
             let ds = Arc::clone(&ds);
-            set.spawn(async move { write_task(ds, x as u32, y as u32).await });
+            set.spawn(async move { write_task(ds, x as u64, y as u64).await });
         }
     }
 
@@ -83,7 +85,7 @@ async fn test_concurrency() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn write_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32) {
+async fn write_task(ds: Arc<RwLock<Repository>>, x: u64, y: u64) {
     let value = x as f64 * y as f64;
     let bytes = Bytes::copy_from_slice(&value.to_be_bytes());
 
@@ -110,7 +112,7 @@ async fn write_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32) {
         .expect("Failed to write chunk ref");
 }
 
-async fn read_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32, barrier: Arc<Barrier>) {
+async fn read_task(ds: Arc<RwLock<Repository>>, x: u64, y: u64, barrier: Arc<Barrier>) {
     let value = x as f64 * y as f64;
     let expected_bytes = Bytes::copy_from_slice(&value.to_be_bytes());
     barrier.wait().await;
@@ -146,8 +148,8 @@ async fn read_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32, barrier: Arc<Bar
 
 async fn list_task(ds: Arc<RwLock<Repository>>, barrier: Arc<Barrier>) {
     let mut expected_indices = HashSet::new();
-    for x in 0..(N as u32) {
-        for y in 0..(N as u32) {
+    for x in 0..(N as u64) {
+        for y in 0..(N as u64) {
             expected_indices.insert(ChunkIndices(vec![x, y]));
         }
     }
@@ -30,6 +30,7 @@ async fn mk_storage(
                     session_token: None,
                 }),
                 allow_http: true,
+                ..Default::default()
             }),
         )
         .await?,
@@ -53,8 +54,8 @@ async fn mk_repo(
 
 async fn write_chunks(
     mut repo: Repository,
-    xs: Range<u32>,
-    ys: Range<u32>,
+    xs: Range<u64>,
+    ys: Range<u64>,
 ) -> Result<Repository, Box<dyn std::error::Error + Send + Sync>> {
     for x in xs {
         for y in ys.clone() {
@@ -81,8 +82,8 @@ async fn write_chunks(
 async fn verify(
     repo: Repository,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    for x in 0..(SIZE / 2) as u32 {
-        for y in 0..(SIZE / 2) as u32 {
+    for x in 0..(SIZE / 2) as u64 {
+        for y in 0..(SIZE / 2) as u64 {
             let bytes = get_chunk(
                 repo.get_chunk_reader(
                     &"/array".try_into().unwrap(),
@@ -134,6 +135,8 @@ async fn test_distributed_writes() -> Result<(), Box<dyn std::error::Error + Sen
         codecs: vec![],
         storage_transformers: None,
         dimension_names: None,
+        chunk_transformers: None,
+        grid_origin: None,
     };
 
     let new_array_path: Path = "/array".try_into().unwrap();
@@ -147,7 +150,7 @@ async fn test_distributed_writes() -> Result<(), Box<dyn std::error::Error + Sen
     let mut set = JoinSet::new();
     #[allow(clippy::erasing_op, clippy::identity_op)]
     {
-        let size2 = SIZE as u32;
+        let size2 = SIZE as u64;
         let size24 = size2 / 4;
         let xrange1 = size24 * 0..size24 * 1;
         let xrange2 = size24 * 1..size24 * 2;
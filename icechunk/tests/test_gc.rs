@@ -26,6 +26,7 @@ fn minio_s3_config() -> S3Config {
             session_token: None,
         }),
         allow_http: true,
+        ..Default::default()
     }
 }
 
@@ -58,6 +59,8 @@ pub async fn test_gc() -> Result<(), Box<dyn std::error::Error>> {
         codecs: vec![],
         storage_transformers: None,
         dimension_names: None,
+        chunk_transformers: None,
+        grid_origin: None,
     };
 
     let array_path: Path = "/array".try_into().unwrap();
@@ -1,4 +1,4 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use bytes::Bytes;
 use chrono::Utc;
@@ -11,8 +11,11 @@ use icechunk::{
         create_tag, fetch_branch_tip, fetch_tag, list_refs, update_branch, Ref, RefError,
     },
     storage::{
-        s3::{S3Config, S3Credentials, S3Storage, StaticS3Credentials},
-        StorageResult,
+        s3::{
+            S3Config, S3Credentials, S3Storage, S3StorageClass, S3StorageClasses,
+            StaticS3Credentials,
+        },
+        ObjectKind, StorageResult,
     },
     Storage,
 };
@@ -31,6 +34,7 @@ async fn mk_storage() -> StorageResult<S3Storage> {
                 session_token: None,
             }),
             allow_http: true,
+            ..Default::default()
         }),
     )
     .await
@@ -188,3 +192,95 @@ pub async fn test_ref_names() -> Result<(), Box<dyn std::error::Error>> {
     );
     Ok(())
 }
+
+#[tokio::test]
+pub async fn test_presign_chunk_read() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage().await?;
+    let id = ChunkId::random();
+    let url = storage
+        .presign_read(&id, &ByteRange::bounded(10, 20), Duration::from_secs(60))
+        .await?;
+    assert!(url.url.contains(&id.to_string()));
+    assert!(url.url.contains("X-Amz-Signature="));
+    assert!(url.headers.contains(&("range".to_string(), "bytes=10-19".to_string())));
+    assert!(url.expires_at > Utc::now());
+
+    assert!(storage
+        .presign_read(&id, &ByteRange::ALL, Duration::from_secs(3600 * 24 * 30))
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[test]
+pub fn test_storage_classes_config() -> Result<(), Box<dyn std::error::Error>> {
+    let config: S3Config = serde_json::from_str(
+        r#"{"region": null, "endpoint": null, "credentials": {"type": "from_env"},
+            "allow_http": false,
+            "storage_classes": {"chunks": "GLACIER_IR", "manifests": "STANDARD_IA"}}"#,
+    )?;
+    assert_eq!(
+        config.storage_classes,
+        S3StorageClasses {
+            chunks: Some(S3StorageClass::GlacierIr),
+            manifests: Some(S3StorageClass::StandardIa),
+            snapshots: None,
+        }
+    );
+
+    // storage classes are optional
+    let config: S3Config = serde_json::from_str(
+        r#"{"region": null, "endpoint": null, "credentials": {"type": "from_env"},
+            "allow_http": false}"#,
+    )?;
+    assert_eq!(config.storage_classes, S3StorageClasses::default());
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_write_with_storage_class() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = S3Storage::new_s3_store(
+        "testbucket",
+        "test_s3_storage__".to_string() + Utc::now().to_rfc3339().as_str(),
+        Some(&S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some("http://localhost:9000".to_string()),
+            credentials: S3Credentials::Static(StaticS3Credentials {
+                access_key_id: "minio123".into(),
+                secret_access_key: "minio123".into(),
+                session_token: None,
+            }),
+            allow_http: true,
+            storage_classes: S3StorageClasses {
+                chunks: Some(S3StorageClass::Standard),
+                manifests: Some(S3StorageClass::Standard),
+                snapshots: Some(S3StorageClass::Standard),
+            },
+            framed_metadata: false,
+            checksum_chunks: true,
+            headers: Default::default(),
+            requester_pays: false,
+        }),
+    )
+    .await?;
+    let id = ChunkId::random();
+    storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+    assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, "hello");
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_copy_object() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage().await?;
+    let (from, to) = (ChunkId::random(), ChunkId::random());
+    storage.write_chunk(from.clone(), Bytes::from_static(b"hello")).await?;
+    storage.copy_object(ObjectKind::Chunk, &from.to_string(), &to.to_string()).await?;
+    assert_eq!(storage.fetch_chunk(&to, &ByteRange::ALL).await?, "hello");
+
+    let other = mk_storage().await?;
+    storage
+        .copy_object_to(ObjectKind::Chunk, &from.to_string(), &other, &to.to_string())
+        .await?;
+    assert_eq!(other.fetch_chunk(&to, &ByteRange::ALL).await?, "hello");
+    Ok(())
+}
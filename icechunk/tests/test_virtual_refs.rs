@@ -3,7 +3,7 @@
 mod tests {
     use icechunk::{
         format::{
-            manifest::{VirtualChunkLocation, VirtualChunkRef},
+            manifest::{ChunkSlice, VirtualChunkLocation, VirtualChunkRef},
             ByteRange, ChunkId, ChunkIndices, Path,
         },
         metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
@@ -36,6 +36,7 @@ mod tests {
                 session_token: None,
             }),
             allow_http: true,
+            ..Default::default()
         }
     }
 
@@ -45,6 +46,7 @@ mod tests {
             endpoint: None,
             credentials: S3Credentials::Anonymous,
             allow_http: false,
+            ..Default::default()
         }
     }
 
@@ -149,6 +151,8 @@ mod tests {
             codecs: vec![],
             storage_transformers: None,
             dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
         };
         let payload1 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -158,6 +162,7 @@ mod tests {
             ))?,
             offset: 0,
             length: 5,
+            slice: None,
         });
         let payload2 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -166,6 +171,7 @@ mod tests {
             ))?,
             offset: 1,
             length: 5,
+            slice: None,
         });
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -259,6 +265,8 @@ mod tests {
             codecs: vec![],
             storage_transformers: None,
             dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
         };
         let payload1 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -268,6 +276,7 @@ mod tests {
             ))?,
             offset: 0,
             length: 5,
+            slice: None,
         });
         let payload2 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -276,6 +285,7 @@ mod tests {
             ))?,
             offset: 1,
             length: 5,
+            slice: None,
         });
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -388,6 +398,7 @@ mod tests {
             ))?,
             offset: 0,
             length: 5,
+            slice: None,
         };
         let ref2 = VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -396,6 +407,7 @@ mod tests {
             ))?,
             offset: 1,
             length: 5,
+            slice: None,
         };
         store.set_virtual_ref("array/c/0/0/0", ref1).await?;
         store.set_virtual_ref("array/c/0/0/1", ref2).await?;
@@ -438,6 +450,7 @@ mod tests {
             )?,
             offset: 119339,
             length: 80,
+            slice: None,
         };
 
         store.set_virtual_ref("depth/c/0", ref2).await?;
@@ -453,4 +466,79 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repository_with_sliced_local_virtual_refs() -> Result<(), Box<dyn Error>>
+    {
+        // a file with a header, and a single 4x6 chunk of int8
+        let chunk_dir = TempDir::new()?;
+        let file = chunk_dir.path().join("giant").to_str().unwrap().to_owned();
+        let mut bytes = b"HEADER..".to_vec();
+        bytes.extend(0..24u8);
+        write_chunks_to_local_fs([(file.clone(), Bytes::from(bytes))].into_iter()).await;
+
+        let repo_dir = TempDir::new()?;
+        let mut ds = create_local_repository(repo_dir.path(), anon_s3_config()).await;
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![4, 6],
+            data_type: DataType::Int8,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(3).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            chunk_transformers: None,
+            grid_origin: None,
+        };
+        let path: Path = "/array".try_into().unwrap();
+        ds.add_array(path.clone(), zarr_meta).await?;
+        let location =
+            VirtualChunkLocation::from_absolute_path(&format!("file://{file}"))?;
+        let reference = |origin: Vec<u64>| VirtualChunkRef {
+            location: location.clone(),
+            offset: 8,
+            length: 24,
+            slice: Some(ChunkSlice {
+                source_shape: vec![4, 6],
+                origin,
+                shape: vec![2, 3],
+                element_size: 1,
+            }),
+        };
+        for (i, j) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let payload = ChunkPayload::Virtual(reference(vec![2 * i, 3 * j]));
+            ds.set_chunk_ref(path.clone(), ChunkIndices(vec![i, j]), Some(payload))
+                .await?;
+        }
+        assert!(ds
+            .set_chunk_ref(
+                path.clone(),
+                ChunkIndices(vec![1, 1]),
+                Some(ChunkPayload::Virtual(reference(vec![3, 3]))),
+            )
+            .await
+            .is_err());
+
+        for (i, j) in [(0u8, 0u8), (0, 1), (1, 0), (1, 1)] {
+            let expected: Vec<u8> = (2 * i..2 * i + 2)
+                .flat_map(|row| (3 * j..3 * j + 3).map(move |col| row * 6 + col))
+                .collect();
+            let coords = ChunkIndices(vec![i.into(), j.into()]);
+            let chunk =
+                get_chunk(ds.get_chunk_reader(&path, &coords, &ByteRange::ALL).await?)
+                    .await?;
+            assert_eq!(chunk, Some(Bytes::from(expected.clone())));
+            // ranges apply to the region
+            let chunk = get_chunk(
+                ds.get_chunk_reader(&path, &coords, &ByteRange::bounded(3, 5)).await?,
+            )
+            .await?;
+            assert_eq!(chunk, Some(Bytes::copy_from_slice(&expected[3..5])));
+        }
+        Ok(())
+    }
 }